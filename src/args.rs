@@ -1,37 +1,411 @@
 use std::{
     ffi::OsString,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
+use chrono::NaiveDate;
 use clap::{
-    Arg, ArgAction, ArgMatches, Command, CommandFactory, FromArgMatches, Parser, Subcommand,
+    Arg, ArgAction, ArgMatches, Args, Command, CommandFactory, FromArgMatches, Parser, Subcommand,
+    ValueEnum,
 };
 use error_stack::{Report, ResultExt};
 
 use crate::{
+    config::{SearchBoundary, SearchOptions},
     context::OverflowKeep,
+    dataset::{DatasetFormat, ScoreFilter},
     error::Error,
+    export::AssistantFormat,
+    file_options,
+    githooks::HookKind,
+    history::ExportFormat,
+    html,
     image::ImageData,
+    import::ImportSource,
+    ledger::Month,
     model::OutputFormat,
+    option_groups::nest_option_groups,
+    output::ResultFormat,
+    render::RenderMode,
+    structured::{self, DataFormat},
+    target_length::TargetLength,
     template::{OptionType, PromptOption, PromptTemplate},
+    usage::UsageGroup,
 };
 
 #[derive(Parser, Debug)]
 pub struct Cli {
     #[command(subcommand)]
-    command: MainCommand,
+    pub command: MainCommand,
+    #[command(flatten)]
+    pub search: SearchArgs,
+}
+
+/// Where to look for configuration files and templates. Every command accepts these.
+#[derive(Parser, Debug, Default)]
+pub struct SearchArgs {
+    /// Don't look for templates in `.promptbox` or `prompts` at the root of the git repository
+    #[arg(long, global = true)]
+    pub no_repo_discovery: bool,
+    /// Look for configuration files and templates starting from this directory instead of the
+    /// current directory
+    #[arg(long, global = true)]
+    pub config_dir: Option<PathBuf>,
+    /// Don't read configuration files above the root of the git repository or the home directory
+    #[arg(long, global = true, value_enum)]
+    pub stop_at: Option<SearchBoundary>,
+}
+
+impl SearchArgs {
+    /// Remove these arguments from the arguments of a run. The rest of the arguments are parsed
+    /// once the template has been found, which is too late for these.
+    pub fn take_from(args: &mut Vec<OsString>) -> Result<Self, clap::Error> {
+        let mut taken = vec![OsString::from("promptbox")];
+        let mut i = 0;
+        while i < args.len() {
+            let arg = args[i].to_string_lossy().into_owned();
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, _)) => (flag, true),
+                None => (arg.as_str(), false),
+            };
+            match flag {
+                "--" => break,
+                "--no-repo-discovery" => taken.push(args.remove(i)),
+                "--config-dir" | "--stop-at" => {
+                    taken.push(args.remove(i));
+                    if !inline_value && i < args.len() {
+                        taken.push(args.remove(i));
+                    }
+                }
+                _ => i += 1,
+            }
+        }
+
+        Self::try_parse_from(taken)
+    }
+
+    /// The search settings for reading the configuration. `--config-dir` is relative to
+    /// `base_dir`.
+    pub fn options(&self, base_dir: &Path) -> Result<SearchOptions, Report<Error>> {
+        let config_dir = self
+            .config_dir
+            .as_ref()
+            .map(|dir| {
+                std::fs::canonicalize(base_dir.join(dir))
+                    .change_context(Error::ArgParseFailure)
+                    .attach_printable_lazy(|| format!("--config-dir {}", dir.display()))
+            })
+            .transpose()?;
+
+        Ok(SearchOptions {
+            repo_discovery: !self.no_repo_discovery,
+            config_dir,
+            stop_at: self.stop_at,
+        })
+    }
 }
 
 #[derive(Subcommand, Debug)]
 pub enum MainCommand {
-    Run(GlobalRunArgs),
-    // List
-    // Show
+    Run(Box<GlobalRunArgs>),
+    /// List the available templates
+    List {
+        /// Output the list as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show a template's path, model settings, options, and text
+    Show {
+        /// The template to show
+        template: String,
+    },
+    /// Print a template's instructions, options, and model settings for a hosted assistant
+    Export {
+        /// The template to export
+        template: String,
+        /// The kind of assistant to export for
+        #[arg(long)]
+        format: AssistantFormat,
+    },
+    /// Open a template in $VISUAL or $EDITOR
+    Edit {
+        /// The template to edit
+        template: String,
+    },
+    /// Check every template for errors
+    Validate,
+    /// Check the configuration, template directories, tokenizer, and hosts for problems
+    Doctor {
+        /// Only check this host. Can be given multiple times.
+        #[arg(long = "host")]
+        hosts: Vec<String>,
+    },
+    /// Print the JSON Schema for template or configuration files
+    Schema {
+        /// The type of file to print the schema for
+        kind: SchemaKind,
+    },
+    /// View and export past runs. Without a subcommand, this lists recent runs.
+    #[command(args_conflicts_with_subcommands = true)]
+    History {
+        #[command(subcommand)]
+        command: Option<HistoryCommand>,
+
+        #[command(flatten)]
+        list: HistoryListArgs,
+    },
+    /// Build fine-tuning datasets from past runs
+    Dataset {
+        #[command(subcommand)]
+        command: DatasetCommand,
+    },
+    /// Install git hooks that write commit messages and pull request descriptions
+    Githooks {
+        #[command(subcommand)]
+        command: GithooksCommand,
+    },
+    /// Run several templates at once
+    Multi {
+        #[command(subcommand)]
+        command: MultiCommand,
+    },
+    /// Start an interactive conversation with a template. It takes the same arguments as `run`.
+    Chat {
+        /// The template to start the conversation with
+        template: String,
+    },
+    /// Manage the conversations saved with `run --session`
+    Session {
+        #[command(subcommand)]
+        command: SessionCommand,
+    },
+    /// Convert prompts from Fabric, AIChat, or llm into templates
+    Import {
+        /// The tool that the prompts come from
+        source: ImportSource,
+
+        /// The prompts to import: a Fabric patterns directory, an AIChat roles directory or
+        /// roles.yaml, or an llm templates directory or template file. This can also be the URL
+        /// of a git repository, such as the Fabric repository, which is cloned to read it.
+        path: String,
+
+        /// Where to write the templates. Defaults to the first template directory.
+        #[arg(long)]
+        dir: Option<PathBuf>,
+
+        /// Replace templates that already exist
+        #[arg(long)]
+        force: bool,
+    },
+    /// Inspect the cached responses
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+    /// Summarize the tokens used and their cost for each model, template, or provider
+    Usage {
+        /// Only count runs on or after this date, such as 2024-01-01
+        #[arg(long, value_name = "DATE")]
+        since: Option<NaiveDate>,
+
+        /// Only count runs from this month, such as 2025-01
+        #[arg(long, value_name = "YYYY-MM", conflicts_with = "since")]
+        month: Option<Month>,
+
+        /// Only count runs of this template
+        #[arg(long)]
+        template: Option<String>,
+
+        /// How to group the runs
+        #[arg(long, value_enum, default_value_t)]
+        by: UsageGroup,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SessionCommand {
+    /// List the saved sessions
+    List,
+    /// Print the messages in a session
+    Show {
+        /// The name of the session
+        name: String,
+    },
+    /// Delete a session
+    Clear {
+        /// The name of the session
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheCommand {
+    /// List the cached responses, newest first
+    Ls,
+    /// Print a cached response and the request details saved with it
+    Show {
+        /// The key of the entry, or the start of it as shown by `cache ls`
+        key: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum MultiCommand {
+    /// Run each template with the same arguments and input, and print the results together
+    Run {
+        /// The templates to run
+        #[arg(required = true)]
+        templates: Vec<String>,
+
+        /// Print a JSON object from template name to result instead of a section for each template
+        #[arg(long)]
+        json: bool,
+
+        /// The arguments to pass to every template, after `--`
+        #[arg(last = true)]
+        args: Vec<OsString>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum HistoryCommand {
+    /// List recent runs
+    List(HistoryListArgs),
+    /// Export one or more runs
+    Show {
+        /// The IDs of the runs to export
+        #[arg(required = true)]
+        ids: Vec<u64>,
+
+        /// The export format
+        #[arg(long, default_value = "markdown")]
+        format: ExportFormat,
+    },
+    /// Rate a run, for use with `dataset build --filter`
+    Rate {
+        /// The ID of the run
+        id: u64,
+        /// The score to give the run
+        score: f64,
+        /// The name of the score
+        #[arg(long, default_value = "rating")]
+        name: String,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct HistoryListArgs {
+    /// The number of runs to show
+    #[arg(long, short = 'n', default_value_t = 20)]
+    pub limit: usize,
+
+    /// Only show runs of this template
+    #[arg(long)]
+    pub template: Option<String>,
+
+    /// Only show runs whose prompt, system prompt, or response contains this text, ignoring case
+    #[arg(long)]
+    pub grep: Option<String>,
+
+    /// Print the full runs as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DatasetCommand {
+    /// Write prompt and response pairs from the history in a fine-tuning format
+    Build(DatasetBuildArgs),
+}
+
+#[derive(Parser, Debug)]
+pub struct DatasetBuildArgs {
+    /// Only use runs of this template
+    #[arg(long)]
+    pub template: Option<String>,
+
+    /// Only use runs with a score matching this condition, such as `judge>=4`.
+    /// Can be given multiple times.
+    #[arg(long)]
+    pub filter: Vec<ScoreFilter>,
+
+    /// The dataset format
+    #[arg(long, default_value = "openai-jsonl")]
+    pub format: DatasetFormat,
+
+    /// Remove runs with the same prompt, keeping the most recent one
+    #[arg(long)]
+    pub dedup: bool,
+
+    /// Write the training set to this file instead of stdout
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+
+    /// The fraction of examples to put in the validation set, between 0 and 1
+    #[arg(long, requires = "val_output", default_value_t = 0.0)]
+    pub val_split: f64,
+
+    /// Write the validation set to this file
+    #[arg(long)]
+    pub val_output: Option<PathBuf>,
+
+    /// The random seed used to choose the validation examples
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+
+    /// Score each run with this template before filtering. The template receives the run's
+    /// `prompt`, `response`, `system`, `template`, and `model`, and should reply with a number.
+    #[arg(long)]
+    pub judge: Option<String>,
+
+    /// The score name to store the judge's verdicts under, for use in `--filter`
+    #[arg(long, default_value = "judge")]
+    pub judge_score_name: String,
+
+    /// The number of runs to judge at once
+    #[arg(long, default_value_t = 4)]
+    pub concurrency: usize,
+
+    /// Judge every run again instead of reusing verdicts from earlier builds
+    #[arg(long, requires = "judge")]
+    pub rejudge: bool,
+
+    /// Let the judge template run shell commands with the `shell` function, if it also sets
+    /// `allow_shell`
+    #[arg(long, requires = "judge")]
+    pub allow_shell: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum GithooksCommand {
+    /// Install a hook into the current git repository
+    Install {
+        /// The hook to install
+        kind: HookKind,
+
+        /// Run this template instead of the bundled one. The template receives the diff in `extra`.
+        #[arg(long)]
+        template: Option<String>,
+
+        /// Replace an existing hook that was not installed by promptbox
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum SchemaKind {
+    /// Template files (`*.pb.toml`)
+    Template,
+    /// Configuration files (`promptbox.toml`)
+    Config,
 }
 
 #[derive(Parser, Debug, Default)]
 pub struct GlobalRunArgs {
-    /// The template to run
+    /// The template to run. If omitted on a terminal, choose one from a list.
+    #[arg(default_value = "", hide_default_value = true)]
     pub template: String,
 
     /// LM Studio host, if different from the default
@@ -58,6 +432,55 @@ pub struct GlobalRunArgs {
     #[arg(long, short = 't')]
     pub temperature: Option<f32>,
 
+    /// Override the top_p value passed to the model
+    #[arg(long)]
+    pub top_p: Option<f32>,
+
+    /// Override the top_k value passed to the model
+    #[arg(long)]
+    pub top_k: Option<u32>,
+
+    /// Override the maximum number of tokens to generate
+    #[arg(long)]
+    pub max_tokens: Option<u32>,
+
+    /// Stop generating at this sequence, in place of the template's stop sequences. Can be given
+    /// more than once.
+    #[arg(long, value_name = "SEQUENCE")]
+    pub stop: Vec<String>,
+
+    /// Ask the model to sample deterministically with this seed, so that the run can be
+    /// reproduced
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Override the frequency penalty passed to the model
+    #[arg(long)]
+    pub frequency_penalty: Option<f32>,
+
+    /// Override the presence penalty passed to the model
+    #[arg(long)]
+    pub presence_penalty: Option<f32>,
+
+    /// Make a token more or less likely, as TOKEN_ID=BIAS with a bias from -100 to 100. Can be
+    /// given more than once.
+    #[arg(long, value_name = "TOKEN_ID=BIAS", value_parser = parse_logit_bias)]
+    pub logit_bias: Vec<(String, f32)>,
+
+    /// An identifier for the person using PromptBox, sent to the host to help it detect abuse
+    #[arg(long)]
+    pub user: Option<String>,
+
+    /// Ask for a response of about this length, such as 300w for words or 2000c for characters,
+    /// in place of the template's `target_length`
+    #[arg(long, value_name = "LENGTH")]
+    pub target_length: Option<TargetLength>,
+
+    /// Ask the model to rewrite a response that is less than half or more than one and a half
+    /// times the target length
+    #[arg(long)]
+    pub enforce_length: bool,
+
     /// Prepend this text to the template
     #[arg(long = "pre")]
     pub prepend: Option<String>,
@@ -74,6 +497,10 @@ pub struct GlobalRunArgs {
     #[arg(long)]
     pub dry_run: bool,
 
+    /// Print each change made to the prompt while assembling it, with token counts, as JSON lines
+    #[arg(long)]
+    pub trace_assembly: bool,
+
     /// Print the prompt and the model parameters
     #[arg(long, short)]
     pub verbose: bool,
@@ -82,6 +509,139 @@ pub struct GlobalRunArgs {
     #[arg(long)]
     pub format: Option<OutputFormat>,
 
+    /// How to write the result. `json` and `jsonl` include the model, the token counts, and the
+    /// duration along with the response.
+    #[arg(long, value_enum, default_value_t)]
+    pub output_format: ResultFormat,
+
+    /// Write the result to this file instead of stdout. The path can use the template's options,
+    /// as in `notes/{{topic}}.md`.
+    #[arg(long, value_name = "PATH")]
+    pub output: Option<String>,
+
+    /// Copy the response to the clipboard, in addition to writing it
+    #[arg(long)]
+    pub copy: bool,
+
+    /// Ask for this many separate responses, and write them one after another. Responses aren't
+    /// streamed or cached, and this can't be used with a conversation.
+    #[arg(
+        long,
+        value_name = "COUNT",
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with_all = ["follow_up", "session"]
+    )]
+    pub n: Option<u32>,
+
+    /// Run this template to choose the best of the `--n` responses, and write only that one. The
+    /// template receives the `prompt`, `system`, and `responses`, and should reply with the number
+    /// of the best response.
+    #[arg(long, requires = "n")]
+    pub judge: Option<String>,
+
+    /// The model for the `--judge` template to use instead of its own
+    #[arg(long, requires = "judge")]
+    pub judge_model: Option<String>,
+
+    /// Add to the end of the `--output` file instead of replacing it
+    #[arg(long = "append", requires = "output")]
+    pub append_output: bool,
+
+    /// Render the response as Markdown. `auto` renders when writing text to a terminal.
+    #[arg(long, value_enum, default_value_t)]
+    pub render: RenderMode,
+
+    /// Don't write a newline after the response
+    #[arg(long)]
+    pub no_trailing_newline: bool,
+
+    /// Decode a base64 response and write the raw bytes
+    #[arg(long)]
+    pub raw_bytes: bool,
+
+    /// Don't ask for confirmation before sending large or expensive prompts
+    #[arg(long, short = 'y')]
+    pub yes: bool,
+
+    /// Send the prompt even if it would go over the budget
+    #[arg(long)]
+    pub force: bool,
+
+    /// Save images in the response to this directory and print their paths instead
+    #[arg(long)]
+    pub image_dir: Option<PathBuf>,
+
+    /// Give up if the model host doesn't send any data for this many seconds
+    #[arg(long)]
+    pub timeout: Option<u64>,
+
+    /// Give up if connecting to the model host takes longer than this many seconds
+    #[arg(long)]
+    pub connect_timeout: Option<u64>,
+
+    /// Don't ask the host to cache any part of the prompt
+    #[arg(long)]
+    pub no_prompt_cache: bool,
+
+    /// Don't save this run to the history
+    #[arg(long)]
+    pub no_history: bool,
+
+    /// Append the prompt and response to this file, instead of the `log_file` from the
+    /// configuration
+    #[arg(long, value_name = "PATH")]
+    pub log: Option<PathBuf>,
+
+    /// Print the tokens used and their cost to stderr
+    #[arg(long)]
+    pub usage: bool,
+
+    /// Print the time to the first token, the total time, and the tokens per second to stderr
+    #[arg(long)]
+    pub stats: bool,
+
+    /// Print the template and configuration files used for the run, with hashes of their
+    /// contents, to stderr
+    #[arg(long)]
+    pub provenance: bool,
+
+    /// Write each stage of the run, such as the rendered prompt, each part of the response, tool
+    /// calls, and usage, to this file as JSON lines, for programs that show the run's progress.
+    /// Use `-` for stderr.
+    #[arg(long, value_name = "PATH")]
+    pub events: Option<PathBuf>,
+
+    /// Always send the request, without reading or saving a cached response
+    #[arg(long)]
+    pub no_cache: bool,
+
+    /// Send the request even if there is a cached response, and cache the new response
+    #[arg(long, conflicts_with = "no_cache")]
+    pub refresh: bool,
+
+    /// Only use a cached response if it is newer than this, such as 30m, 12h, or 1d. Defaults to
+    /// the configured TTL.
+    #[arg(long, value_name = "AGE", value_parser = parse_max_age, conflicts_with = "no_cache")]
+    pub max_age: Option<Duration>,
+
+    /// Run the most recent run from the history again. Pass --model to use a different model.
+    #[arg(long)]
+    pub last: bool,
+
+    /// Send this message as a follow-up to the most recent run of the template, along with that
+    /// run's prompt and response. The template's options don't need to be given again.
+    #[arg(long = "continue", value_name = "MESSAGE", conflicts_with = "session")]
+    pub follow_up: Option<String>,
+
+    /// Continue the named conversation. Earlier messages in the session are sent with the prompt,
+    /// and the prompt and response are added to it.
+    #[arg(long)]
+    pub session: Option<String>,
+
+    /// Fail instead of warning when a configuration or template file contains unknown fields
+    #[arg(long)]
+    pub strict_config: bool,
+
     /// Set which side of the context to keep when overflowing.
     /// Defaults to keeping the start.
     #[arg(long)]
@@ -96,8 +656,31 @@ pub struct GlobalRunArgs {
     #[arg(long)]
     pub reserve_output_context: Option<usize>,
 
+    /// Read an image from stdin instead of text, such as from a screenshot tool. The MIME type is
+    /// detected from the data if it isn't given.
+    #[arg(long, value_name = "MIME", num_args = 0..=1, default_missing_value = "")]
+    pub image_stdin: Option<String>,
+
+    /// Parse the text piped in on stdin into the `stdin` variable, instead of adding it to the
+    /// end of the prompt
+    #[arg(long, value_enum)]
+    pub stdin_format: Option<DataFormat>,
+
+    /// Send images exactly as they are, without removing their metadata or shrinking them
+    #[arg(long)]
+    pub keep_metadata: bool,
+
+    /// Let the template run shell commands with the `shell` function or its tools. The template
+    /// must also set `allow_shell`.
+    #[arg(long)]
+    pub allow_shell: bool,
+
     /// Extra strings to add to the end of the prompt.
     pub extra_prompt: Vec<String>,
+
+    /// The text piped in on stdin, or the recorded stdin when running a run again
+    #[arg(skip)]
+    pub stdin: Option<String>,
 }
 
 pub enum FoundCommand {
@@ -105,39 +688,124 @@ pub enum FoundCommand {
         template: String,
         args: Vec<OsString>,
     },
+    /// A chat, with the arguments rewritten to look like a run of the template.
+    Chat {
+        template: String,
+        args: Vec<OsString>,
+    },
     Other(Cli),
 }
 
-pub fn parse_main_args(cmdline: Vec<OsString>) -> Result<FoundCommand, clap::Error> {
+pub fn parse_main_args(mut cmdline: Vec<OsString>) -> Result<FoundCommand, clap::Error> {
     let first_arg = cmdline
         .get(1)
-        .map(|s| s.to_string_lossy())
-        .unwrap_or_default();
-    let second_arg = cmdline
-        .get(2)
-        .map(|s| s.to_string_lossy())
+        .map(|s| s.to_string_lossy().into_owned())
         .unwrap_or_default();
-    if cmdline.len() >= 3
-        && first_arg == "run"
-        && !second_arg.is_empty()
-        && !second_arg.starts_with("-")
-    {
-        // This isn't great since it hardcodes looking for a specific format. Probably better to
-        // use a real parse with TrailingArgs.
-        Ok(FoundCommand::Run {
-            template: second_arg.to_string(),
+    let template_index = (first_arg == "run" || first_arg == "chat")
+        .then(|| template_position(&cmdline))
+        .flatten();
+
+    let Some(template_index) = template_index else {
+        return Cli::try_parse_from(cmdline).map(FoundCommand::Other);
+    };
+
+    // Move the template name to follow the command, since aliases and the template picker
+    // expect it there. It's the first positional argument, so this doesn't change how the
+    // rest are parsed.
+    let template = cmdline.remove(template_index);
+    cmdline.insert(2, template.clone());
+    let template = template.to_string_lossy().into_owned();
+    if first_arg == "chat" {
+        cmdline[1] = OsString::from("run");
+        Ok(FoundCommand::Chat {
+            template,
             args: cmdline,
         })
     } else {
-        Cli::try_parse_from(cmdline).map(FoundCommand::Other)
+        Ok(FoundCommand::Run {
+            template,
+            args: cmdline,
+        })
     }
 }
 
+/// Find the template name in the arguments of `run` or `chat`, skipping over the flags that come
+/// before it. Template options aren't known until the template is found, so they need to come
+/// after the template name.
+pub(crate) fn template_position(cmdline: &[OsString]) -> Option<usize> {
+    let mut i = 2;
+    while let Some(arg) = cmdline.get(i) {
+        let arg = arg.to_string_lossy();
+        if arg == "--" {
+            return (i + 1 < cmdline.len()).then_some(i + 1);
+        } else if !arg.starts_with('-') || arg == "-" {
+            return (!arg.is_empty()).then_some(i);
+        } else if !arg.contains('=') && find_run_flag(&arg, None).is_some_and(|f| f.takes_value) {
+            i += 1;
+        }
+        i += 1;
+    }
+
+    None
+}
+
+/// A flag of the `run` command
+pub(crate) struct RunFlag {
+    /// The name of the argument, which is the same for its long and short forms
+    pub id: String,
+    pub takes_value: bool,
+}
+
+/// Look up a flag such as `--model` or `-m` in the arguments of `run`, along with the options of
+/// the template when it is known. A value given with `=` is ignored.
+pub(crate) fn find_run_flag(arg: &str, template: Option<&PromptTemplate>) -> Option<RunFlag> {
+    let (arg, _) = arg.split_once('=').unwrap_or((arg, ""));
+    if let Some(long) = arg.strip_prefix("--") {
+        let template_flag = template.and_then(|template| {
+            if let Some(option) = template.options.get(long) {
+                let takes_value = option.array || option.option_type != OptionType::Bool;
+                Some((long.to_string(), takes_value))
+            } else {
+                let is_raw = template.options.iter().any(|(name, option)| {
+                    option.option_type == OptionType::Url && raw_arg_name(name) == long
+                });
+                is_raw.then(|| (long.to_string(), false))
+            }
+        });
+        if let Some((id, takes_value)) = template_flag {
+            return Some(RunFlag { id, takes_value });
+        }
+    }
+
+    let run_command = GlobalRunArgs::command().args(SearchArgs::command().get_arguments());
+    let found = if let Some(long) = arg.strip_prefix("--") {
+        run_command
+            .get_arguments()
+            .find(|a| a.get_long() == Some(long))
+    } else {
+        let short = arg.strip_prefix('-')?.chars().last()?;
+        run_command
+            .get_arguments()
+            .find(|a| a.get_short() == Some(short))
+    };
+    found.map(|a| RunFlag {
+        id: a.get_id().to_string(),
+        takes_value: a.get_action().takes_values(),
+    })
+}
+
 pub fn parse_template_args(
     cmdline: Vec<OsString>,
     base_dir: &Path,
+    template_name: &str,
     template: &PromptTemplate,
 ) -> Result<(GlobalRunArgs, serde_json::Value, Vec<ImageData>), Report<Error>> {
+    // A follow-up to an earlier run doesn't render the template, so its options aren't needed.
+    let continuing = cmdline.iter().any(|arg| {
+        let arg = arg.to_string_lossy();
+        arg == "--continue" || arg.starts_with("--continue=")
+    });
+
     let args = template
         .options
         .iter()
@@ -150,16 +818,39 @@ pub fn parse_template_args(
 
             let arg = Arg::new(name.to_string())
                 .long(name.to_string())
-                .required(
-                    option.option_type != OptionType::Bool
-                        && option.default.is_none()
-                        && !option.optional,
-                )
-                .help(&option.description)
+                .required(option.required() && !continuing)
+                .help(option_help(option))
                 .action(action);
 
+            let bounded = option.min.is_some() || option.max.is_some();
             let arg = match option.option_type {
-                OptionType::String => {
+                OptionType::String if option.pattern.is_some() => {
+                    let pattern = option.pattern.clone().unwrap_or_default();
+                    let regex = option
+                        .pattern_regex()
+                        .transpose()
+                        .change_context(Error::ParseTemplate)
+                        .attach_printable_lazy(|| format!("Invalid pattern for option {name}"))?
+                        .expect("pattern is set");
+                    arg.value_parser(move |value: &str| {
+                        if regex.is_match(value) {
+                            Ok(value.to_string())
+                        } else {
+                            Err(format!("must match the pattern `{pattern}`"))
+                        }
+                    })
+                }
+                OptionType::Number if bounded => {
+                    arg.value_parser(bounded_parser::<f32>(option.min, option.max))
+                }
+                OptionType::Integer if bounded => {
+                    arg.value_parser(bounded_parser::<i64>(option.min, option.max))
+                }
+                OptionType::String
+                | OptionType::Url
+                | OptionType::Json
+                | OptionType::Yaml
+                | OptionType::Csv => {
                     arg.value_parser(clap::builder::NonEmptyStringValueParser::new())
                 }
                 OptionType::Number => arg.value_parser(clap::value_parser!(f32)),
@@ -173,10 +864,31 @@ pub fn parse_template_args(
         })
         .collect::<Result<Vec<_>, Report<Error>>>()?;
 
+    // Each url option gets a flag to keep the page's HTML for just this run.
+    let raw_args = template
+        .options
+        .iter()
+        .filter(|(_, option)| option.option_type == OptionType::Url)
+        .map(|(name, _)| {
+            Arg::new(raw_arg_name(name))
+                .long(raw_arg_name(name))
+                .help(format!("Keep the original HTML of --{name}"))
+                .action(ArgAction::SetTrue)
+        });
+
     // Merge together the args from the global run options and from the template.
-    let run_command = Command::new("run")
+    let mut run_command = Command::new("run")
         .args(GlobalRunArgs::command().get_arguments())
-        .args(args);
+        .args(args)
+        .args(raw_args);
+
+    if !template.description.is_empty() {
+        run_command = run_command.about(template.description.clone());
+    }
+
+    if let Some(epilog) = template_help_epilog(template_name, template) {
+        run_command = run_command.after_help(epilog);
+    }
 
     let main_parsed = Command::new("promptbox")
         .subcommand(run_command)
@@ -222,19 +934,87 @@ pub fn parse_template_args(
                 }
             }
             OptionType::File => {
+                let values = if option.array {
+                    parsed
+                        .remove_many::<PathBuf>(name)
+                        .map(|values| values.collect::<Vec<_>>())
+                        .unwrap_or_default()
+                } else {
+                    parsed.remove_one::<PathBuf>(name).into_iter().collect()
+                };
+
+                // Directories and glob patterns expand to an array of files, even when the option
+                // takes a single value.
+                let mut expanded = false;
+                let mut paths = Vec::new();
+                for value in values {
+                    match file_options::expand(base_dir, &value, option)? {
+                        Some(files) => {
+                            expanded = true;
+                            paths.extend(files.into_iter().map(|file| (file, true)));
+                        }
+                        None => paths.push((value, false)),
+                    }
+                }
+                file_options::limit_files(name, option, &mut paths);
+
+                let vals = paths
+                    .iter()
+                    .map(|(path, from_expansion)| {
+                        file_options::read_file(base_dir, path, name, option, *from_expansion)
+                    })
+                    .filter_map(Result::transpose)
+                    .collect::<Result<Vec<_>, _>>()
+                    .change_context(Error::ArgParseFailure)?;
+                context[name] = if option.array || expanded {
+                    serde_json::Value::Array(vals)
+                } else {
+                    vals.into_iter().next().unwrap_or(serde_json::Value::Null)
+                };
+            }
+            OptionType::Json | OptionType::Yaml | OptionType::Csv => {
+                let values = if option.array {
+                    parsed
+                        .remove_many::<String>(name)
+                        .map(|values| values.collect::<Vec<_>>())
+                        .unwrap_or_default()
+                } else {
+                    parsed.remove_one::<String>(name).into_iter().collect()
+                };
+                let vals = values
+                    .iter()
+                    .map(|value| structured::parse_value(option.option_type, base_dir, value))
+                    .collect::<Result<Vec<_>, _>>()
+                    .attach_printable_lazy(|| format!("Reading --{name}"))?;
+
+                // The default is already structured, so it isn't parsed.
+                context[name] = match (option.array, vals.is_empty()) {
+                    (_, true) => option.default.clone().unwrap_or(if option.array {
+                        serde_json::Value::Array(vec![])
+                    } else {
+                        serde_json::Value::Null
+                    }),
+                    (true, false) => serde_json::Value::Array(vals),
+                    (false, false) => vals.into_iter().next().unwrap_or_default(),
+                };
+            }
+            OptionType::Url => {
+                let raw = option.raw
+                    || parsed
+                        .remove_one::<bool>(&raw_arg_name(name))
+                        .unwrap_or(false);
                 if option.array {
                     let vals = parsed
-                        .remove_many::<PathBuf>(&name)
+                        .remove_many::<String>(name)
                         .unwrap_or_default()
-                        .into_iter()
-                        .map(|path| create_file_object(base_dir, &path))
+                        .map(|url| create_url_object(&url, raw))
                         .collect::<Result<Vec<_>, _>>()
                         .change_context(Error::ArgParseFailure)?;
                     context[name] = serde_json::Value::Array(vals);
                 } else {
                     let val = parsed
-                        .remove_one::<PathBuf>(name)
-                        .map(|path| create_file_object(base_dir, &path))
+                        .remove_one::<String>(name)
+                        .map(|url| create_url_object(&url, raw))
                         .transpose()
                         .change_context(Error::ArgParseFailure)?;
                     context[name] = val.unwrap_or(serde_json::Value::Null);
@@ -243,12 +1023,94 @@ pub fn parse_template_args(
         }
     }
 
+    nest_option_groups(&mut context);
+
     let global_args =
         GlobalRunArgs::from_arg_matches_mut(&mut parsed).change_context(Error::ArgParseFailure)?;
 
     Ok((global_args, context, images))
 }
 
+fn option_help(option: &PromptOption) -> String {
+    match option.default.as_ref() {
+        Some(default) => {
+            let default = match default {
+                serde_json::Value::String(s) => s.clone(),
+                value => value.to_string(),
+            };
+
+            if option.description.is_empty() {
+                format!("[default: {default}]")
+            } else {
+                format!("{} [default: {default}]", option.description)
+            }
+        }
+        None => option.description.clone(),
+    }
+}
+
+/// Format the template's examples for the end of the help text.
+fn template_help_epilog(template_name: &str, template: &PromptTemplate) -> Option<String> {
+    if template.examples.is_empty() {
+        return None;
+    }
+
+    let examples = template
+        .examples
+        .iter()
+        .map(|example| {
+            let command = format!("  promptbox run {template_name} {}", example.args);
+            if example.description.is_empty() {
+                command
+            } else {
+                format!("  # {}\n{command}", example.description)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n");
+
+    Some(format!("Examples:\n{examples}"))
+}
+
+/// Parse an age like `90s`, `30m`, `12h`, `1d`, or `2w`. A number without a unit is in seconds.
+fn parse_max_age(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| format!("`{value}` should be a number followed by s, m, h, d, or w"))?;
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("Unknown unit `{unit}`. Use s, m, h, d, or w")),
+    };
+    Ok(Duration::from_secs(number * seconds))
+}
+
+/// Parse a logit bias like `50256=-100`.
+fn parse_logit_bias(value: &str) -> Result<(String, f32), String> {
+    let (token, bias) = value
+        .split_once('=')
+        .ok_or_else(|| format!("`{value}` should look like TOKEN_ID=BIAS"))?;
+    let token = token.trim();
+    if token.is_empty() || !token.chars().all(|c| c.is_ascii_digit()) {
+        return Err(format!("`{token}` should be a token ID"));
+    }
+    let bias = bias
+        .trim()
+        .parse::<f32>()
+        .ok()
+        .filter(|bias| (-100.0..=100.0).contains(bias))
+        .ok_or_else(|| format!("`{bias}` should be a number from -100 to 100"))?;
+    Ok((token.to_string(), bias))
+}
+
 fn read_image(base_dir: &Path, path: &Path) -> Result<ImageData, Report<Error>> {
     let path = base_dir
         .join(path)
@@ -258,20 +1120,66 @@ fn read_image(base_dir: &Path, path: &Path) -> Result<ImageData, Report<Error>>
     ImageData::new(&path).attach_printable_lazy(|| path.display().to_string())
 }
 
-fn create_file_object(
-    base_dir: &Path,
-    path: &Path,
-) -> Result<serde_json::Value, Report<std::io::Error>> {
-    let contents = std::fs::read_to_string(base_dir.join(path).canonicalize()?)
-        .attach_printable_lazy(|| format!("Could not read file: {}", path.display()))?;
-
-    let obj = serde_json::json!({
-        "filename": path.file_name().map(|s| s.to_string_lossy()).unwrap_or_default(),
-        "path": path.to_string_lossy().to_owned(),
-        "contents": contents
-    });
+/// Parse a number, and check that it's within an option's `min` and `max`.
+fn bounded_parser<T>(
+    min: Option<f64>,
+    max: Option<f64>,
+) -> impl Fn(&str) -> Result<T, String> + Clone + Send + Sync + 'static
+where
+    T: std::str::FromStr + Clone + Send + Sync + 'static,
+    T::Err: std::fmt::Display,
+{
+    move |value: &str| {
+        let parsed = value.parse::<T>().map_err(|e| e.to_string())?;
+        let number = value.parse::<f64>().map_err(|e| e.to_string())?;
+        if let Some(min) = min.filter(|min| number < *min) {
+            return Err(format!("must be at least {min}"));
+        }
+        if let Some(max) = max.filter(|max| number > *max) {
+            return Err(format!("must be at most {max}"));
+        }
+        Ok(parsed)
+    }
+}
+
+fn raw_arg_name(name: &str) -> String {
+    format!("{name}-raw")
+}
+
+/// Fetch a web page for a url option. HTML pages are reduced to their readable content as
+/// Markdown unless `raw` is set, and other text is used as is.
+fn create_url_object(url: &str, raw: bool) -> Result<serde_json::Value, Report<Error>> {
+    let response = ureq::get(url)
+        .timeout(Duration::from_secs(30))
+        .set(
+            "User-Agent",
+            concat!("promptbox/", env!("CARGO_PKG_VERSION")),
+        )
+        .call()
+        .map_err(|e| {
+            Report::new(Error::FetchUrl(url.to_string())).attach_printable(e.to_string())
+        })?;
+    let is_html = response.content_type().contains("html");
+    let body = response
+        .into_string()
+        .change_context_lazy(|| Error::FetchUrl(url.to_string()))?;
+
+    let (title, contents) = if is_html {
+        let contents = if raw {
+            body.clone()
+        } else {
+            html::to_markdown(&body)
+        };
+        (html::title(&body), contents)
+    } else {
+        (None, body)
+    };
 
-    Ok(obj)
+    Ok(serde_json::json!({
+        "url": url,
+        "title": title.unwrap_or_default(),
+        "contents": contents,
+    }))
 }
 
 fn add_val_to_context<T: Clone + Send + Sync + Into<serde_json::Value> + 'static>(
@@ -299,3 +1207,129 @@ fn add_val_to_context<T: Clone + Send + Sync + Into<serde_json::Value> + 'static
 
     context[name] = val;
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chat_looks_like_run() {
+        let cmdline = ["promptbox", "chat", "helper", "--topic", "rust"]
+            .map(OsString::from)
+            .to_vec();
+        let Ok(FoundCommand::Chat { template, args }) = parse_main_args(cmdline) else {
+            panic!("expected a chat");
+        };
+        assert_eq!(template, "helper");
+        assert_eq!(
+            args,
+            ["promptbox", "run", "helper", "--topic", "rust"].map(OsString::from)
+        );
+    }
+
+    #[test]
+    fn flags_before_template() {
+        let cmdline = [
+            "promptbox",
+            "run",
+            "--dry-run",
+            "-m",
+            "gpt-4o",
+            "--stop-at=git-root",
+            "simple",
+            "--topic",
+            "rust",
+        ]
+        .map(OsString::from)
+        .to_vec();
+        let Ok(FoundCommand::Run { template, args }) = parse_main_args(cmdline) else {
+            panic!("expected a run");
+        };
+        assert_eq!(template, "simple");
+        assert_eq!(
+            args,
+            [
+                "promptbox",
+                "run",
+                "simple",
+                "--dry-run",
+                "-m",
+                "gpt-4o",
+                "--stop-at=git-root",
+                "--topic",
+                "rust"
+            ]
+            .map(OsString::from)
+        );
+
+        let cmdline = ["promptbox", "run", "--dry-run"]
+            .map(OsString::from)
+            .to_vec();
+        assert!(matches!(
+            parse_main_args(cmdline),
+            Ok(FoundCommand::Other(_))
+        ));
+    }
+
+    #[test]
+    fn take_search_args() {
+        let mut args = [
+            "promptbox",
+            "run",
+            "helper",
+            "--stop-at=git-root",
+            "--topic",
+            "rust",
+            "--config-dir",
+            "../project",
+            "--no-repo-discovery",
+            "--",
+            "--stop-at",
+        ]
+        .map(OsString::from)
+        .to_vec();
+        let search = SearchArgs::take_from(&mut args).unwrap();
+        assert!(search.no_repo_discovery);
+        assert_eq!(search.config_dir, Some(PathBuf::from("../project")));
+        assert_eq!(search.stop_at, Some(SearchBoundary::GitRoot));
+        assert_eq!(
+            args,
+            [
+                "promptbox",
+                "run",
+                "helper",
+                "--topic",
+                "rust",
+                "--",
+                "--stop-at"
+            ]
+            .map(OsString::from)
+        );
+
+        let mut args = ["promptbox", "run", "helper", "--stop-at", "root"]
+            .map(OsString::from)
+            .to_vec();
+        assert!(SearchArgs::take_from(&mut args).is_err());
+    }
+
+    #[test]
+    fn max_age() {
+        assert_eq!(parse_max_age("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_max_age("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_max_age("1d"), Ok(Duration::from_secs(24 * 60 * 60)));
+        assert!(parse_max_age("1y").is_err());
+        assert!(parse_max_age("d").is_err());
+    }
+
+    #[test]
+    fn logit_bias() {
+        assert_eq!(
+            parse_logit_bias("50256=-100"),
+            Ok(("50256".to_string(), -100.0))
+        );
+        assert_eq!(parse_logit_bias(" 42 = 5.5"), Ok(("42".to_string(), 5.5)));
+        assert!(parse_logit_bias("50256").is_err());
+        assert!(parse_logit_bias("hello=5").is_err());
+        assert!(parse_logit_bias("42=150").is_err());
+    }
+}
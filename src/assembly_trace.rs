@@ -0,0 +1,224 @@
+//! Record each change made to the prompt while it is assembled, to help debug why the final prompt
+//! differs from the template.
+
+use std::sync::Arc;
+
+use error_stack::{Report, ResultExt};
+use serde::Serialize;
+
+use crate::{
+    error::Error,
+    segments::{PromptSegment, PromptSegments},
+    tokenizer::Tokenizer,
+};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AssemblyStepKind {
+    /// The template as loaded from the file
+    Template,
+    /// Text from `--pre` was added to the start of the template
+    Prepend,
+    /// Extra arguments and stdin were added to the end of the template
+    AppendExtra,
+    /// Extra arguments and stdin were placed in the `extra` template variable
+    SubstituteExtra,
+    /// Text from `--post` was added to the end of the template
+    Append,
+    /// The template was rendered with the arguments
+    Render,
+    /// The rendered prompt was trimmed to fit in the context
+    Truncate,
+    /// The instruction for the target length was added to the end of the prompt
+    TargetLength,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct AssemblyStep {
+    pub step: AssemblyStepKind,
+    pub detail: String,
+    pub tokens_before: usize,
+    pub tokens_after: usize,
+}
+
+/// The number of tokens in one segment of the final prompt
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SegmentTokens {
+    pub segment: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    pub tokens: usize,
+}
+
+/// Collects the steps applied to a prompt. When created with [AssemblyTrace::disabled], recording
+/// does nothing.
+#[derive(Debug, Default)]
+pub struct AssemblyTrace {
+    tokenizer: Option<Arc<Tokenizer>>,
+    pub steps: Vec<AssemblyStep>,
+    pub segments: Vec<SegmentTokens>,
+}
+
+impl AssemblyTrace {
+    pub fn new(tokenizer: Option<&str>) -> Result<Self, Report<Error>> {
+        let tokenizer = Tokenizer::get(tokenizer).change_context(Error::PreparePrompt)?;
+        Ok(Self {
+            tokenizer: Some(tokenizer),
+            steps: Vec::new(),
+            segments: Vec::new(),
+        })
+    }
+
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    fn count(tokenizer: &Tokenizer, text: &str) -> Result<usize, Report<Error>> {
+        if text.is_empty() {
+            return Ok(0);
+        }
+
+        let encoded = tokenizer
+            .encode(text)
+            .change_context(Error::PreparePrompt)?;
+        Ok(encoded.len())
+    }
+
+    /// Record a step which changed the prompt from `before` to `after`.
+    pub fn record(
+        &mut self,
+        step: AssemblyStepKind,
+        detail: impl Into<String>,
+        before: &str,
+        after: &str,
+    ) -> Result<(), Report<Error>> {
+        let Some(tokenizer) = self.tokenizer.as_ref() else {
+            return Ok(());
+        };
+
+        let tokens_before = Self::count(tokenizer, before)?;
+        let tokens_after = Self::count(tokenizer, after)?;
+        self.steps.push(AssemblyStep {
+            step,
+            detail: detail.into(),
+            tokens_before,
+            tokens_after,
+        });
+        Ok(())
+    }
+
+    /// Record a step which adds `value` to the prompt through a template variable, before the
+    /// template is rendered.
+    pub fn record_substitution(
+        &mut self,
+        step: AssemblyStepKind,
+        detail: impl Into<String>,
+        template: &str,
+        value: &str,
+    ) -> Result<(), Report<Error>> {
+        let Some(tokenizer) = self.tokenizer.as_ref() else {
+            return Ok(());
+        };
+
+        let tokens_before = Self::count(tokenizer, template)?;
+        let tokens_after = tokens_before + Self::count(tokenizer, value)?;
+        self.steps.push(AssemblyStep {
+            step,
+            detail: detail.into(),
+            tokens_before,
+            tokens_after,
+        });
+        Ok(())
+    }
+
+    /// Record the size of each segment of the final prompt.
+    pub fn record_segments(&mut self, segments: &PromptSegments) -> Result<(), Report<Error>> {
+        let Some(tokenizer) = self.tokenizer.as_ref() else {
+            return Ok(());
+        };
+
+        for segment in &segments.0 {
+            let file = match segment {
+                PromptSegment::Context { file, .. } => Some(file.clone()),
+                _ => None,
+            };
+            self.segments.push(SegmentTokens {
+                segment: segment.kind(),
+                file,
+                tokens: Self::count(tokenizer, segment.text())?,
+            });
+        }
+        Ok(())
+    }
+
+    /// Write each step, and then the size of each segment, to stderr as a line of JSON.
+    pub fn print(&self) {
+        for step in &self.steps {
+            eprintln!("{}", serde_json::to_string(step).unwrap_or_default());
+        }
+        for segment in &self.segments {
+            eprintln!("{}", serde_json::to_string(segment).unwrap_or_default());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::base_dir;
+
+    fn trace() -> AssemblyTrace {
+        let tokenizer = base_dir("whitespace_tokenizer.json");
+        AssemblyTrace::new(Some(&tokenizer.to_string_lossy())).unwrap()
+    }
+
+    #[test]
+    fn counts_tokens() {
+        let mut trace = trace();
+        trace
+            .record(AssemblyStepKind::Prepend, "--pre", "a b", "c d a b")
+            .unwrap();
+        trace
+            .record_substitution(AssemblyStepKind::SubstituteExtra, "", "c d a b", "e f g")
+            .unwrap();
+
+        assert_eq!(
+            trace.steps,
+            vec![
+                AssemblyStep {
+                    step: AssemblyStepKind::Prepend,
+                    detail: "--pre".to_string(),
+                    tokens_before: 2,
+                    tokens_after: 4,
+                },
+                AssemblyStep {
+                    step: AssemblyStepKind::SubstituteExtra,
+                    detail: String::new(),
+                    tokens_before: 4,
+                    tokens_after: 7,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn serialize() {
+        let mut trace = trace();
+        trace
+            .record(AssemblyStepKind::AppendExtra, "stdin", "", "a b")
+            .unwrap();
+        assert_eq!(
+            serde_json::to_string(&trace.steps[0]).unwrap(),
+            r##"{"step":"append_extra","detail":"stdin","tokens_before":0,"tokens_after":2}"##
+        );
+    }
+
+    #[test]
+    fn disabled() {
+        let mut trace = AssemblyTrace::disabled();
+        trace
+            .record(AssemblyStepKind::Append, "--post", "a", "a b")
+            .unwrap();
+        assert!(trace.steps.is_empty());
+    }
+}
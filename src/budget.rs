@@ -0,0 +1,220 @@
+//! Refuse to send prompts that would go over a spending limit.
+
+use error_stack::Report;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    confirm::{ask_terminal, RunEstimate},
+    error::Error,
+    ledger::{self, Ledger, Month},
+    option::update_if_none,
+    usage::PriceTable,
+};
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetAction {
+    /// Fail the run
+    #[default]
+    Refuse,
+    /// Ask whether to send the prompt anyway, and refuse if there is no terminal to ask on
+    Confirm,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, JsonSchema)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct BudgetInput {
+    /// The most that a single prompt may cost, in dollars.
+    pub per_run: Option<f64>,
+    /// The most that all runs in a calendar month may cost, in dollars.
+    pub monthly: Option<f64>,
+    /// Print a warning when the runs in a calendar month have cost more than this many dollars,
+    /// without refusing any runs.
+    pub monthly_warning: Option<f64>,
+    /// What to do when a run would go over the budget. Defaults to `refuse`.
+    pub on_exceed: Option<BudgetAction>,
+}
+
+impl BudgetInput {
+    /// For any members that are `None` in this `BudgetInput`, use the value from `other`
+    pub fn merge_defaults(&mut self, other: &BudgetInput) {
+        update_if_none(&mut self.per_run, &other.per_run);
+        update_if_none(&mut self.monthly, &other.monthly);
+        update_if_none(&mut self.monthly_warning, &other.monthly_warning);
+        update_if_none(&mut self.on_exceed, &other.on_exceed);
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BudgetPolicy {
+    pub per_run: Option<f64>,
+    pub monthly: Option<f64>,
+    pub monthly_warning: Option<f64>,
+    pub on_exceed: BudgetAction,
+}
+
+impl From<BudgetInput> for BudgetPolicy {
+    fn from(value: BudgetInput) -> Self {
+        Self {
+            per_run: value.per_run,
+            monthly: value.monthly,
+            monthly_warning: value.monthly_warning,
+            on_exceed: value.on_exceed.unwrap_or_default(),
+        }
+    }
+}
+
+impl BudgetPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.per_run.is_some() || self.monthly.is_some()
+    }
+
+    /// Describe how the run goes over the budget, or return `None` if it doesn't. `cost` is the
+    /// estimated cost of the run, and `spent` is what the month's runs have cost so far.
+    pub fn exceeded(&self, cost: Option<f64>, spent: f64) -> Option<String> {
+        if let Some((per_run, cost)) = self.per_run.zip(cost) {
+            if cost > per_run {
+                return Some(format!(
+                    "The prompt would cost ~${cost:.2}, over the limit of ${per_run:.2} per run"
+                ));
+            }
+        }
+
+        let monthly = self.monthly?;
+        let total = spent + cost.unwrap_or_default();
+        (total > monthly || spent >= monthly).then(|| {
+            format!("This month's runs have cost ${spent:.2}, and this run would go over the monthly limit of ${monthly:.2}")
+        })
+    }
+
+    /// Describe the monthly amount that a finished run went over, or return `None` if it didn't
+    /// cross one. `spent` is what the month's runs cost before this one.
+    pub fn crossed(&self, cost: f64, spent: f64) -> Option<String> {
+        let total = spent + cost;
+        [
+            (self.monthly, "monthly limit"),
+            (self.monthly_warning, "monthly warning"),
+        ]
+        .into_iter()
+        .find_map(|(amount, name)| {
+            let amount = amount?;
+            (spent <= amount && total > amount).then(|| {
+                format!(
+                    "This month's runs have now cost ${total:.2}, over the {name} of ${amount:.2}"
+                )
+            })
+        })
+    }
+}
+
+/// The cost of the runs in the ledger from the current month.
+pub fn spent_this_month(prices: &PriceTable) -> Result<f64, Report<Error>> {
+    let entries = Ledger::new(prices)?.month(Month::current())?;
+    Ok(ledger::total_cost(&entries))
+}
+
+/// Check the run against the budget, and fail if it would go over unless the user agrees to
+/// send it anyway.
+pub fn enforce_budget(
+    policy: &BudgetPolicy,
+    prices: &PriceTable,
+    estimate: &RunEstimate,
+) -> Result<(), Report<Error>> {
+    let spent = if policy.monthly.is_some() {
+        spent_this_month(prices)?
+    } else {
+        0.0
+    };
+
+    let Some(reason) = policy.exceeded(estimate.cost, spent) else {
+        return Ok(());
+    };
+
+    if policy.on_exceed == BudgetAction::Confirm {
+        eprintln!("{reason}");
+        if ask_terminal(&estimate.question())? == Some(true) {
+            return Ok(());
+        }
+    }
+
+    Err(Report::new(Error::OverBudget)
+        .attach_printable(reason)
+        .attach_printable("Pass --force to send it anyway"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_and_merge() {
+        let mut input: BudgetInput = toml::from_str("per_run = 0.5").unwrap();
+        let parent: BudgetInput = toml::from_str(
+            r##"
+            per_run = 2
+            monthly = 20
+            on_exceed = "confirm"
+            "##,
+        )
+        .unwrap();
+        input.merge_defaults(&parent);
+
+        let policy = BudgetPolicy::from(input);
+        assert_eq!(
+            policy,
+            BudgetPolicy {
+                per_run: Some(0.5),
+                monthly: Some(20.0),
+                monthly_warning: None,
+                on_exceed: BudgetAction::Confirm,
+            }
+        );
+        assert!(!BudgetPolicy::default().is_enabled());
+    }
+
+    #[test]
+    fn limits() {
+        let policy = BudgetPolicy {
+            per_run: Some(1.0),
+            monthly: Some(10.0),
+            monthly_warning: None,
+            on_exceed: BudgetAction::Refuse,
+        };
+
+        assert_eq!(policy.exceeded(Some(0.5), 5.0), None);
+        assert_eq!(policy.exceeded(None, 5.0), None);
+        assert!(policy
+            .exceeded(Some(1.5), 0.0)
+            .unwrap()
+            .contains("limit of $1.00 per run"));
+        assert!(policy
+            .exceeded(Some(0.5), 9.8)
+            .unwrap()
+            .contains("monthly limit of $10.00"));
+        // Once the month's budget is spent, runs with unknown costs are refused too.
+        assert!(policy.exceeded(None, 10.0).is_some());
+    }
+
+    #[test]
+    fn crossed() {
+        let policy = BudgetPolicy {
+            monthly: Some(10.0),
+            monthly_warning: Some(5.0),
+            ..Default::default()
+        };
+
+        assert_eq!(policy.crossed(0.5, 4.0), None);
+        assert!(policy
+            .crossed(0.5, 4.75)
+            .unwrap()
+            .contains("now cost $5.25, over the monthly warning of $5.00"));
+        // Only the run that goes over warns, and not every run after it.
+        assert_eq!(policy.crossed(0.5, 5.25), None);
+        assert!(policy
+            .crossed(2.0, 9.0)
+            .unwrap()
+            .contains("over the monthly limit of $10.00"));
+        assert_eq!(BudgetPolicy::default().crossed(100.0, 0.0), None);
+    }
+}
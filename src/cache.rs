@@ -23,6 +23,11 @@ impl Cache {
         Ok(Self { dir })
     }
 
+    #[cfg(test)]
+    pub fn at(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
     /// Read a file from the cache if it's not older than `max_stale`.
     pub fn read_cache<T: DeserializeOwned>(
         &self,
@@ -58,9 +63,32 @@ impl Cache {
             .attach_printable_lazy(|| format!("{}", path.display()))
     }
 
+    /// The names of the files in a directory of the cache
+    pub fn list(&self, dir: &str) -> Result<Vec<String>, Report<Error>> {
+        let path = self.dir.join(dir);
+        let entries = match std::fs::read_dir(&path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e)
+                    .change_context(Error::Cache)
+                    .attach_printable_lazy(|| path.display().to_string())
+            }
+        };
+
+        Ok(entries
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .collect())
+    }
+
     // Write a file to the cache as JSON
     pub fn write_cache(&self, filename: &str, data: impl Serialize) -> Result<(), Report<Error>> {
         let path = self.dir.join(filename);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .change_context(Error::Cache)
+                .attach_printable_lazy(|| format!("Creating directory {}", parent.display()))?;
+        }
         let file = std::fs::File::create(&path)
             .change_context(Error::Cache)
             .attach_printable_lazy(|| format!("Creating file {}", path.display()))?;
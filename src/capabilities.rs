@@ -0,0 +1,376 @@
+//! Check that a model can do what a template needs before sending the prompt to it.
+
+use std::{collections::HashMap, fmt::Display, str::FromStr};
+
+use error_stack::{Report, ResultExt};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::{
+    error::Error,
+    model::{ModelChainEntry, ModelChoice, ModelOptions},
+    option::update_if_none,
+};
+
+/// Something that a template needs from the model, such as `vision` or `context>=32000`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum Requirement {
+    /// The model accepts images.
+    Vision,
+    /// The model can be made to respond with JSON.
+    JsonMode,
+    /// The model can call tools.
+    Tools,
+    /// The model's context window holds at least this many tokens.
+    Context(usize),
+}
+
+impl FromStr for Requirement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "vision" => Ok(Self::Vision),
+            "json_mode" => Ok(Self::JsonMode),
+            "tools" => Ok(Self::Tools),
+            other => {
+                let size = other
+                    .strip_prefix("context")
+                    .and_then(|rest| rest.trim_start().strip_prefix(">="))
+                    .ok_or_else(|| {
+                        format!(
+                            "Unknown requirement `{other}`. Expected vision, json_mode, tools, or context>=N"
+                        )
+                    })?;
+                size.trim()
+                    .parse::<usize>()
+                    .map(Self::Context)
+                    .map_err(|_| format!("Requirement `{other}` should compare against a number"))
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for Requirement {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for Requirement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Vision => write!(f, "vision"),
+            Self::JsonMode => write!(f, "json_mode"),
+            Self::Tools => write!(f, "tools"),
+            Self::Context(size) => write!(f, "context>={size}"),
+        }
+    }
+}
+
+/// What a model can do. Capabilities which are `None` are not known.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, JsonSchema)]
+pub struct ModelCapabilitiesInput {
+    /// The model accepts images.
+    pub vision: Option<bool>,
+    /// The model can be made to respond with JSON.
+    pub json_mode: Option<bool>,
+    /// The model can call tools.
+    pub tools: Option<bool>,
+    /// The size of the model's context window, in tokens.
+    pub context: Option<usize>,
+}
+
+impl ModelCapabilitiesInput {
+    /// For any members that are `None` in this `ModelCapabilitiesInput`, use the value from `other`
+    pub fn merge_defaults(&mut self, other: &ModelCapabilitiesInput) {
+        update_if_none(&mut self.vision, &other.vision);
+        update_if_none(&mut self.json_mode, &other.json_mode);
+        update_if_none(&mut self.tools, &other.tools);
+        update_if_none(&mut self.context, &other.context);
+    }
+}
+
+const fn caps(
+    vision: bool,
+    json_mode: bool,
+    tools: bool,
+    context: usize,
+) -> ModelCapabilitiesInput {
+    ModelCapabilitiesInput {
+        vision: Some(vision),
+        json_mode: Some(json_mode),
+        tools: Some(tools),
+        context: Some(context),
+    }
+}
+
+/// Capabilities of well-known models. More specific patterns come first.
+const BUILTIN: &[(&str, ModelCapabilitiesInput)] = &[
+    ("gpt-4o*", caps(true, true, true, 128000)),
+    ("gpt-4-turbo*", caps(true, true, true, 128000)),
+    ("gpt-4-vision-preview", caps(true, false, false, 128000)),
+    ("gpt-4-1106-preview", caps(false, true, true, 128000)),
+    ("gpt-4-0125-preview", caps(false, true, true, 128000)),
+    ("gpt-4-32k*", caps(false, false, true, 32768)),
+    ("gpt-4*", caps(false, false, true, 8192)),
+    ("gpt-3.5-turbo-instruct*", caps(false, false, false, 4096)),
+    ("gpt-3.5-turbo*", caps(false, true, true, 16385)),
+    ("claude-3*", caps(true, false, true, 200000)),
+    (
+        "llava*",
+        ModelCapabilitiesInput {
+            vision: Some(true),
+            json_mode: None,
+            tools: None,
+            context: None,
+        },
+    ),
+];
+
+/// Check if a model name matches a pattern, which is either an exact name or a prefix ending in `*`.
+pub(crate) fn pattern_matches(pattern: &str, model: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => model.starts_with(prefix),
+        None => pattern == model,
+    }
+}
+
+/// The known capabilities of each model, from the configuration and the built-in list.
+#[derive(Debug, Clone, Default)]
+pub struct CapabilityRegistry {
+    /// Entries from the configuration, most specific first
+    configured: Vec<(String, ModelCapabilitiesInput)>,
+}
+
+impl From<HashMap<String, ModelCapabilitiesInput>> for CapabilityRegistry {
+    fn from(value: HashMap<String, ModelCapabilitiesInput>) -> Self {
+        let mut configured = value.into_iter().collect::<Vec<_>>();
+        // Exact names come before patterns, and longer patterns before shorter ones.
+        configured.sort_by(|(a, _), (b, _)| {
+            a.ends_with('*')
+                .cmp(&b.ends_with('*'))
+                .then(b.len().cmp(&a.len()))
+                .then(a.cmp(b))
+        });
+        Self { configured }
+    }
+}
+
+impl CapabilityRegistry {
+    /// Look up what a model can do. Each capability comes from the most specific entry that
+    /// sets it, and configured entries take precedence over the built-in ones.
+    pub fn lookup(&self, model: &str) -> ModelCapabilitiesInput {
+        let configured = self
+            .configured
+            .iter()
+            .map(|(pattern, caps)| (pattern.as_str(), caps));
+        let builtin = BUILTIN.iter().map(|(pattern, caps)| (*pattern, caps));
+
+        let mut result = ModelCapabilitiesInput::default();
+        for (_, caps) in configured
+            .chain(builtin)
+            .filter(|(pattern, _)| pattern_matches(pattern, model))
+        {
+            result.merge_defaults(caps);
+        }
+        result
+    }
+
+    /// Return the requirements that the model in `options` does not meet. Capabilities that
+    /// are not known count as unmet.
+    pub fn unmet_requirements(
+        &self,
+        options: &ModelOptions,
+        requires: &[Requirement],
+    ) -> Result<Vec<Requirement>, Report<Error>> {
+        let spec = options.full_model_spec();
+        let model = spec.model_name();
+        let caps = self.lookup(model);
+
+        let context = match caps.context {
+            Some(context) => Some(context),
+            None if requires
+                .iter()
+                .any(|r| matches!(r, Requirement::Context(_))) =>
+            {
+                options
+                    .api_host()?
+                    .model_context_limit(model)
+                    .change_context(Error::ContextLimit)?
+            }
+            None => None,
+        };
+
+        let unmet = requires
+            .iter()
+            .copied()
+            .filter(|requirement| match requirement {
+                Requirement::Vision => caps.vision != Some(true),
+                Requirement::JsonMode => caps.json_mode != Some(true),
+                Requirement::Tools => caps.tools != Some(true),
+                Requirement::Context(size) => context.map(|c| c < *size).unwrap_or(true),
+            })
+            .collect();
+        Ok(unmet)
+    }
+}
+
+fn join_requirements(requirements: &[Requirement]) -> String {
+    requirements
+        .iter()
+        .map(|r| r.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Remove the models that don't meet the requirements from the model chain, so that the prompt
+/// goes to the first model that can handle it. Returns a description of the change, if the first
+/// model was skipped.
+pub fn select_capable_models(
+    registry: &CapabilityRegistry,
+    model_options: &mut ModelOptions,
+    requires: &[Requirement],
+) -> Result<Option<String>, Report<Error>> {
+    if requires.is_empty() {
+        return Ok(None);
+    }
+
+    let entries = std::iter::once(ModelChainEntry {
+        model: model_options.model.clone(),
+        overrides: model_options.model_overrides.clone(),
+    })
+    .chain(model_options.fallback.iter().cloned());
+
+    let first_name = model_options.full_model_spec().model_name().to_string();
+    let mut capable = Vec::new();
+    let mut skipped = Vec::new();
+    for (entry, options) in entries.zip(model_options.model_chain()) {
+        let unmet = registry.unmet_requirements(&options, requires)?;
+        let name = options.full_model_spec().model_name().to_string();
+        if unmet.is_empty() {
+            capable.push((entry, name));
+        } else {
+            skipped.push((name, unmet));
+        }
+    }
+
+    if capable.is_empty() {
+        let mut err = Report::new(Error::MissingCapabilities);
+        for (name, unmet) in &skipped {
+            err = err.attach_printable(format!("{name} lacks {}", join_requirements(unmet)));
+        }
+        return Err(err.attach_printable(
+            "Declare the capabilities of other models in the `model_capabilities` section of the configuration",
+        ));
+    }
+
+    // The chain only changes visibly if the first model was skipped.
+    let message = (skipped.first().map(|(name, _)| name) == Some(&first_name)).then(|| {
+        let (name, unmet) = &skipped[0];
+        format!(
+            "{name} lacks {}, so using {} instead",
+            join_requirements(unmet),
+            capable[0].1
+        )
+    });
+
+    let chain = capable.into_iter().map(|(entry, _)| entry).collect();
+    model_options.set_model_choice(ModelChoice::Chain(chain));
+    Ok(message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::ModelSpec;
+
+    #[test]
+    fn parse_requirements() {
+        assert_eq!("vision".parse::<Requirement>(), Ok(Requirement::Vision));
+        assert_eq!(
+            "json_mode".parse::<Requirement>(),
+            Ok(Requirement::JsonMode)
+        );
+        assert_eq!(
+            "context>=32000".parse::<Requirement>(),
+            Ok(Requirement::Context(32000))
+        );
+        assert_eq!(
+            "context >= 8000".parse::<Requirement>(),
+            Ok(Requirement::Context(8000))
+        );
+        assert!("audio".parse::<Requirement>().is_err());
+        assert!("context>=lots".parse::<Requirement>().is_err());
+    }
+
+    #[test]
+    fn configured_capabilities_take_precedence() {
+        let registry = CapabilityRegistry::from(HashMap::from([
+            (
+                "gpt-4o-mini".to_string(),
+                ModelCapabilitiesInput {
+                    context: Some(64000),
+                    ..Default::default()
+                },
+            ),
+            (
+                "my-model*".to_string(),
+                ModelCapabilitiesInput {
+                    vision: Some(true),
+                    ..Default::default()
+                },
+            ),
+        ]));
+
+        let caps = registry.lookup("gpt-4o-mini");
+        assert_eq!(caps.context, Some(64000));
+        assert_eq!(caps.vision, Some(true));
+
+        assert_eq!(registry.lookup("my-model-7b").vision, Some(true));
+        assert_eq!(registry.lookup("gpt-4-0613").vision, Some(false));
+        assert_eq!(
+            registry.lookup("unknown"),
+            ModelCapabilitiesInput::default()
+        );
+    }
+
+    #[test]
+    fn falls_back_to_capable_model() {
+        let mut options = ModelOptions::default();
+        options.set_model_choice(ModelChoice::Chain(vec![
+            ModelChainEntry {
+                model: ModelSpec::Plain("gpt-3.5-turbo".to_string()),
+                overrides: Default::default(),
+            },
+            ModelChainEntry {
+                model: ModelSpec::Plain("gpt-4o".to_string()),
+                overrides: Default::default(),
+            },
+        ]));
+
+        let registry = CapabilityRegistry::default();
+        let message = select_capable_models(
+            &registry,
+            &mut options,
+            &[Requirement::Vision, Requirement::Context(32000)],
+        )
+        .unwrap()
+        .unwrap();
+        assert!(message.contains("gpt-3.5-turbo lacks vision, context>=32000"));
+        assert_eq!(options.full_model_spec().model_name(), "gpt-4o");
+        assert!(options.fallback.is_empty());
+    }
+
+    #[test]
+    fn no_capable_model() {
+        let mut options = ModelOptions::default();
+        let registry = CapabilityRegistry::default();
+        let err =
+            select_capable_models(&registry, &mut options, &[Requirement::Vision]).unwrap_err();
+        assert!(matches!(err.current_context(), Error::MissingCapabilities));
+    }
+}
@@ -0,0 +1,268 @@
+//! An interactive conversation that starts from a template. The template's system prompt is the
+//! system message and its prompt is the first message, and then each line typed is sent as the
+//! next message.
+
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use error_stack::{Report, ResultExt};
+
+use crate::{
+    config::Config,
+    context,
+    error::Error,
+    events::RunEvents,
+    generate_template_with_stdin,
+    hosts::{self, ModelInput},
+    interrupt, ledger,
+    model::ModelOptions,
+    moderation::ModerationStage,
+    output::{self, OutputOptions, ResultFormat},
+    prepare_run,
+    session::ChatMessage,
+    shutdown::CancellationToken,
+    transcript_log::{self, LogEntry},
+    usage::{Usage, UsageMeter},
+    GeneratedTemplate,
+};
+
+const PROMPT: &str = "> ";
+
+/// What the user typed at the prompt
+enum ChatInput {
+    Line(String),
+    /// Ctrl-C, which clears the line
+    Interrupted,
+    /// Ctrl-D or the end of the input, which ends the conversation
+    Eof,
+}
+
+/// Reads messages with line editing and a history of earlier messages.
+#[cfg(feature = "chat")]
+struct LineReader {
+    editor: rustyline::DefaultEditor,
+    history_path: PathBuf,
+}
+
+#[cfg(feature = "chat")]
+impl LineReader {
+    fn new() -> Result<Self, Report<Error>> {
+        let mut editor = rustyline::DefaultEditor::new().change_context(Error::Chat)?;
+        let history_path = crate::history::data_dir()?.join("chat_history.txt");
+        // There is no history file before the first chat.
+        editor.load_history(&history_path).ok();
+        Ok(Self {
+            editor,
+            history_path,
+        })
+    }
+
+    fn read(&mut self) -> Result<ChatInput, Report<Error>> {
+        use rustyline::error::ReadlineError;
+
+        match self.editor.readline(PROMPT) {
+            Ok(line) => {
+                if !line.trim().is_empty() {
+                    self.editor.add_history_entry(line.as_str()).ok();
+                }
+                Ok(ChatInput::Line(line))
+            }
+            Err(ReadlineError::Interrupted) => Ok(ChatInput::Interrupted),
+            Err(ReadlineError::Eof) => Ok(ChatInput::Eof),
+            Err(e) => Err(e).change_context(Error::Chat),
+        }
+    }
+
+    fn save_history(&mut self) {
+        if let Err(e) = self.editor.save_history(&self.history_path) {
+            eprintln!("Warning: Failed to save the chat history: {e}");
+        }
+    }
+}
+
+/// Reads plain lines from stdin, for builds without the `chat` feature.
+#[cfg(not(feature = "chat"))]
+struct LineReader;
+
+#[cfg(not(feature = "chat"))]
+impl LineReader {
+    fn new() -> Result<Self, Report<Error>> {
+        Ok(Self)
+    }
+
+    fn read(&mut self) -> Result<ChatInput, Report<Error>> {
+        use std::io::{BufRead, Write};
+
+        print!("{PROMPT}");
+        std::io::stdout().flush().change_context(Error::Chat)?;
+        let mut line = String::new();
+        let read = std::io::stdin()
+            .lock()
+            .read_line(&mut line)
+            .change_context(Error::Chat)?;
+        if read == 0 {
+            return Ok(ChatInput::Eof);
+        }
+        if interrupt::interrupted() {
+            interrupt::reset();
+            return Ok(ChatInput::Interrupted);
+        }
+        Ok(ChatInput::Line(
+            line.trim_end_matches(['\r', '\n']).to_string(),
+        ))
+    }
+
+    fn save_history(&mut self) {}
+}
+
+/// Send a message and stream the reply to stdout. Returns the reply, which is only partial if the
+/// user stopped it, and the options of the fallback model that sent it, if any.
+fn send_message(
+    model_options: &ModelOptions,
+    output_options: &OutputOptions,
+    input: &ModelInput,
+) -> Result<(String, Option<ModelOptions>), Report<Error>> {
+    let (message_tx, message_rx) = flume::bounded(32);
+    std::thread::scope(|scope| {
+        let print_thread = scope.spawn(|| {
+            input.cancel.cancel_on_error(|| {
+                output::write_output(output_options, message_rx, &mut std::io::stdout())
+            })
+        });
+        let result = input
+            .cancel
+            .cancel_on_error(|| hosts::send_model_request(model_options, input, message_tx));
+        let response = print_thread.join().unwrap();
+        let fallback = result?;
+        Ok((response.change_context(Error::WriteOutput)?, fallback))
+    })
+}
+
+fn log_message(
+    path: &Path,
+    template: &str,
+    model_options: &ModelOptions,
+    input: &ModelInput,
+    response: &str,
+) {
+    let model = model_options.full_model_spec();
+    let entry = LogEntry {
+        timestamp: chrono::Utc::now(),
+        template,
+        model: model.model_name(),
+        system: input.system,
+        history: input.history,
+        prompt: input.prompt,
+        response,
+        cached: false,
+    };
+    if let Err(e) = transcript_log::append(path, &entry) {
+        eprintln!("Warning: Failed to write the log file: {e:?}");
+    }
+}
+
+/// Run a conversation until the end of the input.
+pub fn run_chat(
+    config: Config,
+    base_dir: PathBuf,
+    template: String,
+    cmdline: Vec<OsString>,
+) -> Result<(), Report<Error>> {
+    let generated = generate_template_with_stdin(config, base_dir, template, cmdline, None)?;
+    if !prepare_run(&generated)? {
+        return Ok(());
+    }
+
+    let GeneratedTemplate {
+        template_name,
+        args,
+        model_options,
+        output_options,
+        prompt,
+        system,
+        mut images,
+        tools,
+        log_file,
+        budget,
+        moderation,
+        prices,
+        ..
+    } = generated;
+    let system = Some(system.as_str()).filter(|s| !s.is_empty());
+    // Replies are always written as text, since there's no single result to wrap in JSON.
+    let output_options = OutputOptions {
+        format: ResultFormat::Text,
+        ..output_options
+    };
+
+    let mut reader = LineReader::new()?;
+
+    interrupt::install_handler();
+    let mut conversation = Vec::new();
+    let mut next_message = Some(prompt).filter(|p| !p.trim().is_empty());
+    loop {
+        let message = match next_message.take() {
+            Some(message) => message,
+            None => match reader.read()? {
+                ChatInput::Line(line) if line.trim().is_empty() => continue,
+                ChatInput::Line(line) => line,
+                ChatInput::Interrupted => continue,
+                ChatInput::Eof => break,
+            },
+        };
+
+        // Replies are streamed as they arrive, so only the messages are moderated. A blocked
+        // message is reported like a failed request, and the conversation goes on.
+        if let Err(e) = moderation.check(ModerationStage::Input, &message, &model_options) {
+            eprintln!("{e:?}");
+            continue;
+        }
+
+        let history = context::fit_session_history(
+            &model_options,
+            &message,
+            system.unwrap_or_default(),
+            conversation.clone(),
+        )?;
+        let input = ModelInput {
+            prompt: &message,
+            system,
+            history: &history,
+            // Images only go with the first message.
+            images: std::mem::take(&mut images),
+            tools: &tools,
+            cancel: &CancellationToken::new(),
+            usage: &UsageMeter::new(),
+            events: RunEvents::none(),
+        };
+
+        match send_message(&model_options, &output_options, &input) {
+            Ok((response, fallback_options)) => {
+                let answered_options = fallback_options.as_ref().unwrap_or(&model_options);
+                if let Some(path) = log_file.as_deref() {
+                    log_message(path, &template_name, answered_options, &input, &response);
+                }
+                if let Some(usage) = Usage::measure(answered_options, &prices, &input, &response) {
+                    ledger::record_run(&template_name, answered_options, usage, &prices, &budget);
+                    if args.usage {
+                        eprintln!("Usage: {usage}");
+                    }
+                }
+                conversation.push(ChatMessage::user(message));
+                conversation.push(ChatMessage::assistant(response));
+            }
+            // Nothing is reading the replies anymore.
+            Err(e) if output::is_broken_pipe(&e) => break,
+            // Keep the conversation going, so that the message can be sent again.
+            Err(e) => eprintln!("{e:?}"),
+        }
+
+        // A stopped reply only ends that reply.
+        interrupt::reset();
+    }
+
+    reader.save_history();
+    Ok(())
+}
@@ -0,0 +1,625 @@
+//! Check responses for common problems, fixing them when possible and otherwise asking the model
+//! to try again.
+
+use error_stack::{Report, ResultExt};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::{
+    error::Error,
+    hosts::{self, ModelInput},
+    model::ModelOptions,
+    option::overwrite_from_option,
+    response_schema::ResponseSchema,
+    target_length::TargetLength,
+};
+
+const DEFAULT_MAX_REPAIRS: usize = 1;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    #[serde(alias = "en")]
+    English,
+    #[serde(alias = "es")]
+    Spanish,
+    #[serde(alias = "fr")]
+    French,
+    #[serde(alias = "de")]
+    German,
+    #[serde(alias = "it")]
+    Italian,
+    #[serde(alias = "pt")]
+    Portuguese,
+    #[serde(alias = "nl")]
+    Dutch,
+    #[serde(alias = "ru")]
+    Russian,
+    #[serde(alias = "el")]
+    Greek,
+    #[serde(alias = "ar")]
+    Arabic,
+    #[serde(alias = "he")]
+    Hebrew,
+    #[serde(alias = "hi")]
+    Hindi,
+    #[serde(alias = "th")]
+    Thai,
+    #[serde(alias = "zh")]
+    Chinese,
+    #[serde(alias = "ja")]
+    Japanese,
+    #[serde(alias = "ko")]
+    Korean,
+}
+
+impl Language {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::English => "English",
+            Self::Spanish => "Spanish",
+            Self::French => "French",
+            Self::German => "German",
+            Self::Italian => "Italian",
+            Self::Portuguese => "Portuguese",
+            Self::Dutch => "Dutch",
+            Self::Russian => "Russian",
+            Self::Greek => "Greek",
+            Self::Arabic => "Arabic",
+            Self::Hebrew => "Hebrew",
+            Self::Hindi => "Hindi",
+            Self::Thai => "Thai",
+            Self::Chinese => "Chinese",
+            Self::Japanese => "Japanese",
+            Self::Korean => "Korean",
+        }
+    }
+}
+
+impl std::fmt::Display for Language {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Common words in each language that uses the Latin alphabet, used to tell them apart.
+const STOPWORDS: &[(Language, &[&str])] = &[
+    (
+        Language::English,
+        &[
+            "the", "and", "is", "are", "of", "to", "that", "it", "with", "for", "this", "was",
+            "be", "on", "not", "you", "have", "by", "but", "from", "they", "which", "or", "will",
+            "can", "would", "there", "what", "has", "an",
+        ],
+    ),
+    (
+        Language::Spanish,
+        &[
+            "el", "los", "las", "que", "y", "un", "una", "es", "por", "con", "para", "del", "se",
+            "no", "su", "al", "lo", "como", "más", "pero", "sus", "le", "ya", "este", "está",
+            "son", "también", "muy", "hay", "esta",
+        ],
+    ),
+    (
+        Language::French,
+        &[
+            "le", "les", "des", "et", "est", "une", "du", "dans", "pour", "qui", "pas", "sur",
+            "au", "avec", "ce", "il", "sont", "ne", "plus", "par", "mais", "nous", "vous", "cette",
+            "aux", "été", "être", "ou", "leur", "elle",
+        ],
+    ),
+    (
+        Language::German,
+        &[
+            "der", "die", "das", "und", "ist", "nicht", "ein", "eine", "zu", "den", "von", "mit",
+            "sich", "des", "auf", "für", "im", "dem", "es", "auch", "als", "wird", "sind", "oder",
+            "aber", "werden", "bei", "einer", "noch", "ich",
+        ],
+    ),
+    (
+        Language::Italian,
+        &[
+            "il", "di", "che", "è", "per", "non", "sono", "della", "gli", "con", "si", "da",
+            "questo", "anche", "più", "ma", "nel", "alla", "dei", "ha", "una", "delle", "nella",
+            "essere", "questa", "molto", "ci", "sul", "tra", "io",
+        ],
+    ),
+    (
+        Language::Portuguese,
+        &[
+            "o", "os", "que", "é", "um", "uma", "para", "com", "não", "do", "da", "em", "no", "na",
+            "por", "mais", "dos", "das", "ao", "como", "mas", "foi", "são", "seu", "sua", "também",
+            "muito", "há", "isso", "você",
+        ],
+    ),
+    (
+        Language::Dutch,
+        &[
+            "de", "het", "een", "en", "van", "is", "dat", "niet", "op", "te", "zijn", "voor",
+            "met", "die", "er", "ook", "aan", "om", "maar", "als", "dan", "wordt", "bij", "door",
+            "nog", "naar", "wat", "ik", "je", "worden",
+        ],
+    ),
+];
+
+/// The writing system of a character, for the languages that have their own.
+fn script_language(c: char) -> Option<Language> {
+    let language = match c {
+        '\u{0400}'..='\u{04FF}' => Language::Russian,
+        '\u{0370}'..='\u{03FF}' => Language::Greek,
+        '\u{0600}'..='\u{06FF}' => Language::Arabic,
+        '\u{0590}'..='\u{05FF}' => Language::Hebrew,
+        '\u{0900}'..='\u{097F}' => Language::Hindi,
+        '\u{0E00}'..='\u{0E7F}' => Language::Thai,
+        '\u{3040}'..='\u{30FF}' => Language::Japanese,
+        '\u{4E00}'..='\u{9FFF}' => Language::Chinese,
+        '\u{1100}'..='\u{11FF}' | '\u{3130}'..='\u{318F}' | '\u{AC00}'..='\u{D7AF}' => {
+            Language::Korean
+        }
+        _ => return None,
+    };
+    Some(language)
+}
+
+/// Guess the language of some text, along with how sure the guess is, from 0 to 1. Returns `None`
+/// when there isn't enough text to be sure.
+pub fn detect_language(text: &str) -> Option<(Language, f32)> {
+    let mut letters = 0;
+    let mut script_counts: Vec<(Language, usize)> = Vec::new();
+    for c in text.chars().filter(|c| c.is_alphabetic()) {
+        letters += 1;
+        if let Some(language) = script_language(c) {
+            match script_counts.iter_mut().find(|(l, _)| *l == language) {
+                Some((_, count)) => *count += 1,
+                None => script_counts.push((language, 1)),
+            }
+        }
+    }
+
+    let script_letters = script_counts.iter().map(|(_, count)| count).sum::<usize>();
+    if script_letters * 2 > letters {
+        let count_of = |language| {
+            script_counts
+                .iter()
+                .find(|(l, _)| *l == language)
+                .map(|(_, count)| *count)
+                .unwrap_or(0)
+        };
+
+        // Japanese mixes kana with Chinese characters, so a modest share of kana means Japanese.
+        let kana = count_of(Language::Japanese);
+        let han = count_of(Language::Chinese);
+        if kana > 0 && kana * 10 >= kana + han {
+            return Some((Language::Japanese, (kana + han) as f32 / letters as f32));
+        }
+
+        return script_counts
+            .into_iter()
+            .filter(|(l, _)| *l != Language::Japanese)
+            .max_by_key(|(_, count)| *count)
+            .map(|(language, count)| (language, count as f32 / letters as f32));
+    }
+
+    let words = text
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect::<Vec<_>>();
+
+    let mut scores = STOPWORDS
+        .iter()
+        .map(|(language, stopwords)| {
+            let score = words
+                .iter()
+                .filter(|w| stopwords.contains(&w.as_str()))
+                .count();
+            (*language, score)
+        })
+        .collect::<Vec<_>>();
+    scores.sort_by_key(|(_, score)| std::cmp::Reverse(*score));
+
+    let (language, best) = scores[0];
+    let second = scores[1].1;
+    let enough = best >= 3 && best * 10 >= words.len();
+    if !enough || best <= second {
+        return None;
+    }
+
+    // Both how far ahead of the next language it is and how much evidence there is for it.
+    let margin = (best - second) as f32 / best as f32;
+    let evidence = (best as f32 / 10.0).min(1.0);
+    Some((language, margin * evidence))
+}
+
+#[derive(Deserialize, Debug, Clone, Default, JsonSchema)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct ResponseChecksInput {
+    /// Make sure that the response is valid JSON. Text around the JSON, such as code fences, is
+    /// removed.
+    pub json: Option<bool>,
+    /// Make sure that the response is written in this language. Accepts a language name such as
+    /// "english" or a two letter code such as "en".
+    pub language: Option<Language>,
+    /// Remove the first line of the response when it starts with one of these phrases, such as
+    /// "Sure, here is".
+    #[serde(default)]
+    pub banned_preambles: Vec<String>,
+    /// Ask the model to rewrite a response that is far from the template's `target_length`.
+    pub enforce_length: Option<bool>,
+    /// How many times to ask the model to fix a response that fails a check. Defaults to 1.
+    pub max_repairs: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseChecks {
+    pub json: bool,
+    /// The schema from the template's `response_schema`. Responses must be JSON that matches it.
+    pub schema: Option<ResponseSchema>,
+    pub language: Option<Language>,
+    pub banned_preambles: Vec<String>,
+    /// The target length, when it is enforced. Responses less than half or more than one and a
+    /// half times as long are sent back to the model.
+    pub length: Option<TargetLength>,
+    pub max_repairs: usize,
+}
+
+impl Default for ResponseChecks {
+    fn default() -> Self {
+        Self {
+            json: false,
+            schema: None,
+            language: None,
+            banned_preambles: Vec::new(),
+            length: None,
+            max_repairs: DEFAULT_MAX_REPAIRS,
+        }
+    }
+}
+
+impl From<&ResponseChecksInput> for ResponseChecks {
+    fn from(value: &ResponseChecksInput) -> Self {
+        let mut checks = Self {
+            json: value.json.unwrap_or(false),
+            language: value.language,
+            banned_preambles: value.banned_preambles.clone(),
+            ..Default::default()
+        };
+        overwrite_from_option(&mut checks.max_repairs, &value.max_repairs);
+        checks
+    }
+}
+
+/// Remove code fences and any other text around the JSON in the response.
+fn extract_json(response: &str) -> Result<&str, serde_json::Error> {
+    let trimmed = response.trim();
+    if serde_json::from_str::<serde_json::Value>(trimmed).is_ok() {
+        return Ok(trimmed);
+    }
+
+    let start = trimmed.find(['{', '[']).unwrap_or(0);
+    let end = trimmed
+        .rfind(['}', ']'])
+        .filter(|end| *end > start)
+        .map(|end| end + 1)
+        .unwrap_or(trimmed.len());
+    let candidate = &trimmed[start..end];
+    serde_json::from_str::<serde_json::Value>(candidate).map(|_| candidate)
+}
+
+impl ResponseChecks {
+    /// Returns true if there is nothing to check.
+    pub fn is_empty(&self) -> bool {
+        !self.json
+            && self.schema.is_none()
+            && self.language.is_none()
+            && self.banned_preambles.is_empty()
+            && self.length.is_none()
+    }
+
+    /// Remove lines at the start of the response which begin with a banned preamble.
+    fn strip_preambles<'a>(&self, mut response: &'a str) -> &'a str {
+        loop {
+            let trimmed = response.trim_start();
+            let (first_line, rest) = trimmed.split_once('\n').unwrap_or((trimmed, ""));
+            let first_line = first_line.to_lowercase();
+            let banned = self
+                .banned_preambles
+                .iter()
+                .any(|p| first_line.starts_with(&p.to_lowercase()));
+            if !banned {
+                return trimmed;
+            }
+
+            response = rest;
+        }
+    }
+
+    /// Check the response, fixing the problems that can be fixed without the model. Returns the
+    /// fixed response, or a description of the problem.
+    pub fn check(&self, response: &str) -> Result<String, String> {
+        let mut response = response;
+        if !self.banned_preambles.is_empty() {
+            response = self.strip_preambles(response);
+            if response.trim().is_empty() {
+                return Err("The response contained only a preamble and no answer".to_string());
+            }
+        }
+
+        if self.json || self.schema.is_some() {
+            response = extract_json(response)
+                .map_err(|e| format!("The response was not valid JSON ({e})"))?;
+        }
+
+        if let Some(schema) = &self.schema {
+            // extract_json already made sure that this parses.
+            let value = serde_json::from_str(response).unwrap_or_default();
+            if let Some(problem) = schema.validate(&value) {
+                return Err(format!("The response did not match the schema ({problem})"));
+            }
+        }
+
+        if let Some(expected) = self.language {
+            match detect_language(response) {
+                Some((found, _)) if found != expected => {
+                    return Err(format!(
+                        "The response was written in {found} instead of {expected}"
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(length) = &self.length {
+            length.check(response)?;
+        }
+
+        Ok(response.to_string())
+    }
+}
+
+fn repair_prompt(prompt: &str, response: &str, problem: &str) -> String {
+    format!(
+        "{prompt}\n\nYour previous response was:\n\n{response}\n\n{problem}. \
+        Write the whole response again with this problem fixed."
+    )
+}
+
+/// Check the response, asking the model to fix it if there is a problem that can't be fixed
+/// automatically.
+pub fn repair_response(
+    checks: &ResponseChecks,
+    model_options: &ModelOptions,
+    input: &ModelInput,
+    mut response: String,
+) -> Result<String, Report<Error>> {
+    let mut repairs = 0;
+    loop {
+        let problem = match checks.check(&response) {
+            Ok(response) => return Ok(response),
+            Err(problem) => problem,
+        };
+
+        if repairs >= checks.max_repairs {
+            return Err(Report::new(Error::ResponseCheckFailed)).attach_printable(problem);
+        }
+        repairs += 1;
+
+        eprintln!(
+            "{problem}. Asking {} to fix it",
+            model_options.full_model_spec().model_name()
+        );
+        let prompt = repair_prompt(input.prompt, &response, &problem);
+        let repair_input = ModelInput {
+            prompt: &prompt,
+            system: input.system,
+            history: input.history,
+            images: input.images.clone(),
+            tools: input.tools,
+            cancel: input.cancel,
+            usage: input.usage,
+            events: input.events,
+        };
+
+        let (tx, rx) = flume::unbounded();
+        hosts::send_model_request(model_options, &repair_input, tx)?;
+        response = rx.try_iter().collect();
+
+        if input.cancel.is_cancelled() {
+            return Ok(response);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn checks(toml: &str) -> ResponseChecks {
+        ResponseChecks::from(&toml::from_str::<ResponseChecksInput>(toml).unwrap())
+    }
+
+    #[test]
+    fn parse() {
+        let parsed = checks(
+            r##"
+            json = true
+            language = "de"
+            banned_preambles = ["Sure"]
+            max_repairs = 3
+            "##,
+        );
+        assert_eq!(
+            parsed,
+            ResponseChecks {
+                json: true,
+                schema: None,
+                language: Some(Language::German),
+                banned_preambles: vec!["Sure".to_string()],
+                length: None,
+                max_repairs: 3,
+            }
+        );
+
+        assert_eq!(checks(r#"language = "english""#).max_repairs, 1);
+        assert!(checks("").is_empty());
+        assert!(toml::from_str::<ResponseChecksInput>(r#"language = "klingon""#).is_err());
+    }
+
+    #[test]
+    fn json() {
+        let checks = checks("json = true");
+        assert_eq!(checks.check(r#" {"a": 1} "#).unwrap(), r#"{"a": 1}"#);
+        assert_eq!(
+            checks
+                .check("Here you go:\n```json\n[1, 2, 3]\n```\nLet me know!")
+                .unwrap(),
+            "[1, 2, 3]"
+        );
+
+        assert_eq!(
+            checks.check(r#"Sure: {"a": 1"#).unwrap_err(),
+            "The response was not valid JSON (EOF while parsing an object at line 1 column 7)"
+        );
+        assert!(checks.check("No JSON here").is_err());
+    }
+
+    #[test]
+    fn schema() {
+        let checks = ResponseChecks {
+            schema: Some(
+                ResponseSchema::new(serde_json::json!({
+                    "type": "object",
+                    "properties": { "count": { "type": "integer" } },
+                    "required": ["count"]
+                }))
+                .unwrap(),
+            ),
+            ..Default::default()
+        };
+        assert!(!checks.is_empty());
+        assert_eq!(
+            checks.check("```json\n{\"count\": 3}\n```").unwrap(),
+            "{\"count\": 3}"
+        );
+        assert_eq!(
+            checks.check(r#"{"count": "three"}"#).unwrap_err(),
+            r#"The response did not match the schema (/count: "three" is not of type "integer")"#
+        );
+        assert!(checks
+            .check("three")
+            .unwrap_err()
+            .starts_with("The response was not valid JSON"));
+    }
+
+    #[test]
+    fn preambles() {
+        let checks = checks(r#"banned_preambles = ["Sure", "Here is"]"#);
+        assert_eq!(
+            checks
+                .check("Sure! Happy to help.\nhere is the summary:\n\nThe summary.")
+                .unwrap(),
+            "The summary."
+        );
+        assert_eq!(
+            checks.check("The answer, sure enough, is 4").unwrap(),
+            "The answer, sure enough, is 4"
+        );
+        assert_eq!(
+            checks.check("Sure, I can do that.").unwrap_err(),
+            "The response contained only a preamble and no answer"
+        );
+    }
+
+    #[test]
+    fn preamble_before_json() {
+        let checks = checks(
+            r#"
+            json = true
+            banned_preambles = ["Certainly"]
+            "#,
+        );
+        assert_eq!(
+            checks
+                .check("Certainly! Here is the JSON:\n```\n{\"b\": 2}\n```")
+                .unwrap(),
+            "{\"b\": 2}"
+        );
+    }
+
+    #[test]
+    fn language() {
+        let checks = checks(r#"language = "english""#);
+        assert!(checks
+            .check("The weather is nice today, and it will be warm for the rest of the week.")
+            .is_ok());
+        assert_eq!(
+            checks
+                .check("Das Wetter ist heute schön, und es wird für den Rest der Woche warm.")
+                .unwrap_err(),
+            "The response was written in German instead of English"
+        );
+        // Too short to tell, so it passes.
+        assert!(checks.check("Danke").is_ok());
+    }
+
+    #[test]
+    fn detect() {
+        for (text, expected) in [
+            (
+                "El clima está muy agradable hoy y va a hacer calor por el resto de la semana.",
+                Language::Spanish,
+            ),
+            (
+                "Le temps est beau aujourd'hui et il va faire chaud pour le reste de la semaine.",
+                Language::French,
+            ),
+            (
+                "Het weer is vandaag mooi en het wordt de rest van de week warm.",
+                Language::Dutch,
+            ),
+            (
+                "Il tempo è bello oggi e farà caldo per il resto della settimana.",
+                Language::Italian,
+            ),
+            (
+                "O tempo está bom hoje e vai fazer calor para o resto da semana.",
+                Language::Portuguese,
+            ),
+            (
+                "Сегодня хорошая погода, и всю неделю будет тепло.",
+                Language::Russian,
+            ),
+            (
+                "今天天气很好，这周剩下的时间都会很暖和。",
+                Language::Chinese,
+            ),
+            (
+                "今日は天気が良くて、今週はずっと暖かいでしょう。",
+                Language::Japanese,
+            ),
+            (
+                "오늘은 날씨가 좋고 이번 주 내내 따뜻할 거예요.",
+                Language::Korean,
+            ),
+        ] {
+            let (found, confidence) = detect_language(text).unwrap();
+            assert_eq!(found, expected, "{text}");
+            assert!(
+                confidence > 0.0 && confidence <= 1.0,
+                "{text}: {confidence}"
+            );
+        }
+
+        assert_eq!(detect_language("12345 {} []"), None);
+    }
+
+    #[test]
+    fn repair_prompt_includes_problem() {
+        let prompt = repair_prompt("Say hi", "Sure!", "The response was not valid JSON");
+        assert_eq!(
+            prompt,
+            "Say hi\n\nYour previous response was:\n\nSure!\n\nThe response was not valid JSON. \
+            Write the whole response again with this problem fixed."
+        );
+    }
+}
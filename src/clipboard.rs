@@ -0,0 +1,87 @@
+//! Copy the response to the system clipboard.
+
+use error_stack::Report;
+#[cfg(feature = "clipboard")]
+use error_stack::ResultExt;
+
+use crate::error::Error;
+
+#[cfg(feature = "clipboard")]
+pub fn copy(text: &str) -> Result<(), Report<Error>> {
+    #[cfg(target_os = "linux")]
+    if let Some(result) = copy_with_tool(text) {
+        return result;
+    }
+
+    let mut clipboard = arboard::Clipboard::new().change_context(Error::Clipboard)?;
+    clipboard.set_text(text).change_context(Error::Clipboard)
+}
+
+/// On Linux, the clipboard contents are served by the program that set them, so they would
+/// disappear when PromptBox exits. These tools stay running in the background to serve the text
+/// until something else is copied.
+#[cfg(all(feature = "clipboard", target_os = "linux"))]
+fn copy_with_tool(text: &str) -> Option<Result<(), Report<Error>>> {
+    use std::{
+        io::Write,
+        process::{Command, Stdio},
+    };
+
+    let wayland = std::env::var_os("WAYLAND_DISPLAY").is_some();
+    let x11 = std::env::var_os("DISPLAY").is_some();
+    let tools: [(&str, &[&str], bool); 3] = [
+        ("wl-copy", &[], wayland),
+        ("xclip", &["-selection", "clipboard"], x11),
+        ("xsel", &["--clipboard", "--input"], x11),
+    ];
+
+    for (program, args, usable) in tools {
+        if !usable {
+            continue;
+        }
+
+        // The tool keeps running after this returns, so it can't hold on to PromptBox's output.
+        let child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                return Some(Err(Report::new(e)
+                    .change_context(Error::Clipboard)
+                    .attach_printable(format!("Running {program}"))))
+            }
+        };
+
+        let result = child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(text.as_bytes())
+            .and_then(|_| child.wait())
+            .change_context(Error::Clipboard)
+            .attach_printable_lazy(|| format!("Running {program}"))
+            .and_then(|status| {
+                if status.success() {
+                    Ok(())
+                } else {
+                    Err(Report::new(Error::Clipboard)
+                        .attach_printable(format!("{program} failed with {status}")))
+                }
+            });
+        return Some(result);
+    }
+
+    None
+}
+
+#[cfg(not(feature = "clipboard"))]
+pub fn copy(_text: &str) -> Result<(), Report<Error>> {
+    Err(Report::new(Error::Clipboard).attach_printable(
+        "This build of PromptBox can't use the clipboard. Rebuild it with the `clipboard` feature.",
+    ))
+}
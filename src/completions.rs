@@ -0,0 +1,275 @@
+//! Ask for several responses to the same prompt, for templates where it helps to see a few
+//! alternatives, such as brainstorming, or to have a judge choose the best of them.
+
+use std::{io::Write, time::Instant};
+
+use error_stack::{Report, ResultExt};
+
+use crate::{
+    checks, clipboard,
+    error::Error,
+    events::{RunEvent, RunEvents},
+    history::{History, HistoryEntry, Invocation, RunParameters, TokenCounts},
+    hosts::{self, ModelInput},
+    interrupt, ledger,
+    moderation::{ModerationResult, ModerationStage},
+    multi,
+    output::{self, CompletionsResult, ResultFormat},
+    progress::{RunStats, Spinner},
+    provenance,
+    shutdown::CancellationToken,
+    transcript_log::{self, LogEntry},
+    usage::{Usage, UsageMeter},
+    GeneratedTemplate,
+};
+
+/// Label each response with its number, like the sections of `promptbox multi`.
+fn format_responses(responses: &[String]) -> String {
+    let sections = responses
+        .iter()
+        .enumerate()
+        .map(|(i, response)| {
+            (
+                format!("{} of {}", i + 1, responses.len()),
+                response.clone(),
+            )
+        })
+        .collect::<Vec<_>>();
+    multi::format_sections(&sections)
+        .trim_end_matches('\n')
+        .to_string()
+}
+
+/// Send the prompt for `n` responses, and write them all once they have arrived, or only the best
+/// one when there is a judge. Each response that is written is saved to the history.
+pub fn send_completions(
+    generated: GeneratedTemplate,
+    invocation: Invocation,
+    mut output: impl Write,
+    n: usize,
+    moderation_results: Vec<ModerationResult>,
+    events: &RunEvents,
+) -> Result<(), Report<Error>> {
+    let GeneratedTemplate {
+        template_name,
+        args,
+        model_options,
+        output_options,
+        prompt,
+        system,
+        images,
+        tools,
+        session_history,
+        save_history,
+        log_file,
+        budget,
+        moderation,
+        prices,
+        checks,
+        post_process,
+        sources,
+        judge,
+        ..
+    } = generated;
+
+    interrupt::install_handler();
+
+    let cancel = CancellationToken::new();
+    let usage = UsageMeter::new();
+    let system = Some(system).filter(|s| !s.is_empty());
+    let input = ModelInput {
+        prompt: &prompt,
+        system: system.as_deref(),
+        history: &session_history,
+        images,
+        tools: &tools,
+        cancel: &cancel,
+        usage: &usage,
+        events,
+    };
+
+    let started = Instant::now();
+    let mut spinner = Spinner::start(format!(
+        "Waiting for {n} responses from {}",
+        model_options.full_model_spec().model_name()
+    ));
+    let result = cancel.cancel_on_error(|| -> Result<_, Report<Error>> {
+        let (responses, fallback) = hosts::send_completions_request(&model_options, &input, n)?;
+        let responses = responses
+            .into_iter()
+            .map(|response| {
+                let response = if checks.is_empty() {
+                    response
+                } else {
+                    checks::repair_response(&checks, &model_options, &input, response)?
+                };
+                let moderated =
+                    moderation.check(ModerationStage::Output, &response, &model_options)?;
+                Ok((post_process.apply(response)?, moderated))
+            })
+            .collect::<Result<Vec<_>, Report<Error>>>()?;
+        Ok((responses, fallback))
+    });
+    spinner.stop();
+    let (responses, fallback_options) = result?;
+    let (responses, response_moderation): (Vec<_>, Vec<_>) = responses.into_iter().unzip();
+    // The usage and history go under the model that answered, which is a fallback model when the
+    // earlier ones failed.
+    let answered_options = fallback_options.as_ref().unwrap_or(&model_options);
+    let model = answered_options.full_model_spec();
+
+    let chosen = match (&judge, args.judge.as_deref()) {
+        (Some(judge), Some(judge_name)) => {
+            let mut spinner = Spinner::start(format!("Waiting for {judge_name} to choose"));
+            let chosen = cancel.cancel_on_error(|| {
+                judge.choose(&template_name, model.model_name(), &input, &responses)
+            });
+            spinner.stop();
+            let chosen = chosen?;
+            if args.verbose {
+                eprintln!("{judge_name} chose response {} of {n}", chosen + 1);
+            }
+            Some(chosen)
+        }
+        _ => None,
+    };
+    let duration = started.elapsed();
+
+    let run_usage = Usage::measure(answered_options, &prices, &input, &responses.concat());
+    if let Some(run_usage) = run_usage {
+        ledger::record_run(
+            &template_name,
+            answered_options,
+            run_usage,
+            &prices,
+            &budget,
+        );
+        events.emit(RunEvent::Usage(run_usage));
+    }
+    if args.usage {
+        match run_usage {
+            Some(run_usage) => eprintln!("Usage: {run_usage}"),
+            None => eprintln!("Usage: unknown, since the tokenizer could not be loaded"),
+        }
+    }
+    if args.stats {
+        // Nothing is written until every response has arrived.
+        let stats = RunStats {
+            first_token: duration,
+            total: duration,
+            response_tokens: run_usage.map(|u| u.tokens.response),
+        };
+        eprintln!("Stats: {stats}");
+    }
+    if args.provenance {
+        eprint!("{}", provenance::footnotes(&sources));
+    }
+
+    // Only the chosen response is written and saved when there is a judge.
+    let (kept, formatted) = match chosen {
+        Some(i) => (i..i + 1, responses[i].clone()),
+        None => (0..responses.len(), format_responses(&responses)),
+    };
+    let written = if output_options.format == ResultFormat::Text {
+        output::write_output(&output_options, [formatted.clone()], &mut output).map(|_| ())
+    } else {
+        let filtered = responses
+            .iter()
+            .map(|response| output_options.stream_filters.apply(response))
+            .collect::<Vec<_>>();
+        let result = CompletionsResult {
+            response: chosen.map(|i| filtered[i].as_str()),
+            chosen: chosen.map(|i| i + 1),
+            responses: &filtered,
+            model: model.model_name(),
+            prompt_tokens: run_usage.map(|u| u.tokens.prompt),
+            completion_tokens: run_usage.map(|u| u.tokens.response),
+            duration_ms: duration.as_millis() as u64,
+        };
+        output::write_result(&output_options, &result, &mut output)
+    };
+    match written.change_context(Error::WriteOutput) {
+        Ok(()) => {}
+        // Nothing is reading the responses, but their usage was already recorded above.
+        Err(e) if output::is_broken_pipe(&e) => return Ok(()),
+        Err(e) => return Err(e),
+    }
+    // The responses aren't streamed, so they are reported as one chunk.
+    events.emit(RunEvent::Chunk { text: &formatted });
+    events.emit(RunEvent::Completed {
+        response: &formatted,
+        duration_ms: duration.as_millis() as u64,
+        cached: false,
+    });
+
+    // The responses have already been written, so failing to copy them isn't worth failing the
+    // run.
+    if args.copy && !formatted.is_empty() {
+        if let Err(e) = clipboard::copy(&formatted) {
+            eprintln!("Warning: {e:?}");
+        }
+    }
+
+    for i in kept {
+        let response = &responses[i];
+        if let Some(path) = log_file.as_deref() {
+            let entry = LogEntry {
+                timestamp: chrono::Utc::now(),
+                template: &template_name,
+                model: model.model_name(),
+                system: system.as_deref(),
+                history: &session_history,
+                prompt: &prompt,
+                response,
+                cached: false,
+            };
+            if let Err(e) = transcript_log::append(path, &entry) {
+                eprintln!("Warning: Failed to write the log file: {e:?}");
+            }
+        }
+
+        // Each response is its own run in the history, so that it can be scored or run again.
+        if save_history {
+            let mut entry = HistoryEntry::new(
+                &template_name,
+                model.model_name(),
+                system.as_deref(),
+                &prompt,
+                response,
+            );
+            entry.invocation = Some(invocation.clone());
+            entry.parameters = Some(RunParameters::from(answered_options));
+            entry.tokens = TokenCounts::count(
+                answered_options.tokenizer.as_deref(),
+                system.as_deref(),
+                &prompt,
+                response,
+            );
+            entry.duration_ms = Some(duration.as_millis() as u64);
+            entry.sources = sources.clone();
+            entry.moderation = moderation_results.clone();
+            entry.moderation.extend(response_moderation[i].clone());
+            History::new()?.add(entry)?;
+        }
+    }
+
+    if interrupt::interrupted() {
+        return Err(Report::new(Error::Interrupted));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn labeled_responses() {
+        let responses = ["First idea".to_string(), "Second idea\n".to_string()];
+        assert_eq!(
+            format_responses(&responses),
+            "== 1 of 2\nFirst idea\n\n== 2 of 2\nSecond idea"
+        );
+    }
+}
@@ -3,74 +3,325 @@ use std::{
     path::{Path, PathBuf},
 };
 
+use clap::ValueEnum;
 use error_stack::{Report, ResultExt};
+use schemars::JsonSchema;
 use serde::Deserialize;
 
 use crate::{
+    budget::{BudgetInput, BudgetPolicy},
+    capabilities::{CapabilityRegistry, ModelCapabilitiesInput},
+    confirm::{ConfirmPolicy, ConfirmPolicyInput},
     error::Error,
     global_config::global_config_dirs,
-    hosts::{HostDefinition, HostDefinitionInput},
+    hosts::{HostDefinition, HostDefinitionInput, HostRule, HostRuleInput, ProvidersInput},
+    image::{ImagePolicy, ImagePolicyInput},
     model::{ModelOptions, ModelOptionsInput},
-    option::overwrite_option_from_option,
-    template::ParsedTemplate,
+    moderation::ModerationInput,
+    option::{overwrite_option_from_option, update_if_none},
+    partials::Partials,
+    provenance::{SourceFile, SourceKind},
+    response_cache::{ResponseCacheInput, ResponseCachePolicy},
+    routing::RoutingPolicyInput,
+    template::{ParsedTemplate, TEMPLATE_EXTENSIONS},
+    template_alias::TemplateAlias,
+    unknown_fields::{from_toml_str, UnknownField},
+    usage::{ModelPriceInput, PriceTable},
 };
 
 fn default_template_dirs() -> Vec<PathBuf> {
     vec![PathBuf::from(".")]
 }
 
-#[derive(Deserialize, Debug, Default)]
+/// Directories at the root of a git repository which hold templates, so that they can be found
+/// from anywhere in the repository.
+const REPO_TEMPLATE_DIRS: [&str; 2] = [".promptbox", "prompts"];
+
+/// Extra template directories, separated like `PATH`, which are searched after the directories
+/// from the configuration files.
+const TEMPLATE_PATH_VAR: &str = "PROMPTBOX_TEMPLATE_PATH";
+
+/// The directories listed in `PROMPTBOX_TEMPLATE_PATH`
+fn env_template_path() -> Vec<PathBuf> {
+    std::env::var_os(TEMPLATE_PATH_VAR)
+        .map(|paths| std::env::split_paths(&paths).collect())
+        .unwrap_or_default()
+}
+
+/// The last directory to read when searching up through the parent directories
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, ValueEnum, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchBoundary {
+    /// The root of the git repository, or the home directory outside of a repository
+    GitRoot,
+    /// The home directory
+    Home,
+}
+
+/// Where to look for configuration files and templates, from the command line
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    /// Read the template directories at the root of the git repository. `--no-repo-discovery`
+    /// turns this off.
+    pub repo_discovery: bool,
+    /// Start looking for configuration files in this directory instead of the current directory,
+    /// for `--config-dir`
+    pub config_dir: Option<PathBuf>,
+    /// Stop looking through parent directories at the boundary, for `--stop-at`. This takes
+    /// precedence over the `stop_at` setting.
+    pub stop_at: Option<SearchBoundary>,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        Self {
+            repo_discovery: true,
+            config_dir: None,
+            stop_at: None,
+        }
+    }
+}
+
+/// The directories that the search uses besides the ones it walks through
+#[derive(Debug, Default)]
+struct SearchDirs {
+    home_dir: Option<PathBuf>,
+    global_dirs: Vec<PathBuf>,
+    /// Extra template directories, from `PROMPTBOX_TEMPLATE_PATH`
+    template_path: Vec<PathBuf>,
+}
+
+impl SearchDirs {
+    fn from_env() -> Self {
+        Self {
+            home_dir: etcetera::home_dir()
+                .ok()
+                .and_then(|dir| std::fs::canonicalize(dir).ok()),
+            global_dirs: global_config_dirs(),
+            template_path: env_template_path(),
+        }
+    }
+
+    /// The directory to stop searching at, if there is one
+    fn boundary(&self, stop_at: Option<SearchBoundary>, start_dir: &Path) -> Option<PathBuf> {
+        match stop_at? {
+            SearchBoundary::GitRoot => git_root(start_dir)
+                .map(Path::to_path_buf)
+                .or_else(|| self.home_dir.clone()),
+            SearchBoundary::Home => self.home_dir.clone(),
+        }
+    }
+}
+
+/// Find the root of the git repository which contains `dir`. `.git` is a file instead of a
+/// directory in worktrees and submodules, so either counts.
+fn git_root(dir: &Path) -> Option<&Path> {
+    dir.ancestors().find(|d| d.join(".git").exists())
+}
+
+#[derive(Deserialize, Debug, Default, JsonSchema)]
 pub struct ConfigInput {
     /// One or more globs that define where to look for templates.
     /// Defaults to ./promptbox, or ./ if the config file is in ./promptbox
     #[serde(default = "default_template_dirs")]
     pub templates: Vec<PathBuf>,
+    /// Extra template directories, such as a shared repository of templates outside the project.
+    /// These are searched after the other template directories, and after any listed in the
+    /// `PROMPTBOX_TEMPLATE_PATH` environment variable. Relative paths are relative to the
+    /// configuration file.
+    #[serde(default)]
+    pub template_path: Vec<PathBuf>,
+    /// A name for this file's template directories, so that a template can be run as
+    /// `namespace/name` when another directory has a template with the same name. Defaults to the
+    /// name of the directory.
+    pub namespace: Option<String>,
+    /// The namespaces declared for each template directory
+    #[serde(skip)]
+    pub namespaces: HashMap<PathBuf, String>,
     /// Stop recursing through parent directories if a config file is found with `top_level = true`
     #[serde(default)]
     pub top_level: bool,
+    /// Don't read configuration files above this directory: "git_root" for the root of the git
+    /// repository, or "home" for the home directory. This applies to the directories above the
+    /// file that sets it, or to every search when set in the global configuration.
+    pub stop_at: Option<SearchBoundary>,
     /// Do not use the global config if this is `false`.
     pub use_global_config: Option<bool>,
     /// Default model options to use for any prompts that don't override them.
     pub model: Option<ModelOptionsInput>,
-    /// Custom hosts that can serve model requests.
+    /// Settings for each model provider, and custom providers that can serve model requests.
+    pub providers: Option<ProvidersInput>,
+    /// Custom hosts that can serve model requests. The `providers` section is preferred.
     #[serde(default)]
     pub host: HashMap<String, HostDefinitionInput>,
     /// The default model host to use. If absent, ollama is the default.
     /// GPT 3.5/4 models will always use OpenAI as the default if not explicitly set otherwise.
     pub default_host: Option<String>,
+    /// Choose the host for models whose names match a regular expression, such as
+    /// `{ model = "^llama", host = "together" }`. The first matching rule is used, and these take
+    /// precedence over the built-in choices for OpenAI and Anthropic models.
+    #[serde(default)]
+    pub host_rules: Vec<HostRuleInput>,
+    /// Short names for templates along with arguments to use with them, such as
+    /// `cm = "commitmsg --style conventional"`, so that `promptbox run cm` runs `commitmsg` with
+    /// those arguments. Use an array instead of a string for arguments that contain spaces.
+    #[serde(default)]
+    pub template_aliases: HashMap<String, TemplateAlias>,
+    /// Choose a model based on the size of the prompt, for templates which enable it.
+    pub routing: Option<RoutingPolicyInput>,
+    /// Save each run so that it can be viewed with `promptbox history`. Defaults to true.
+    pub history: Option<bool>,
+    /// Ask before sending prompts that are large or expensive.
+    pub confirm: Option<ConfirmPolicyInput>,
+    /// Check prompts or responses with a moderation classifier. Templates can add checks, but
+    /// can't turn these off or change the classifier.
+    pub moderation: Option<ModerationInput>,
+    /// Refuse to send prompts that would cost more than a limit per run or per month. This is
+    /// usually set in the global configuration.
+    pub budget: Option<BudgetInput>,
+    /// Change images before they are sent, to protect privacy and limit their size.
+    pub images: Option<ImagePolicyInput>,
+    /// Reuse the responses to identical requests.
+    pub response_cache: Option<ResponseCacheInput>,
+    /// Append the prompt, system prompt, and response of each run to this file. Files ending in
+    /// `.jsonl` get one JSON object per run, and other files get Markdown.
+    pub log_file: Option<PathBuf>,
+    /// What each model can do, for templates which declare requirements. Keys are model names, or
+    /// prefixes of model names ending in `*`.
+    #[serde(default)]
+    pub model_capabilities: HashMap<String, ModelCapabilitiesInput>,
+    /// The price of each model, in dollars per million tokens, for reporting the cost of runs. Keys
+    /// are model names, or prefixes of model names ending in `*`.
+    #[serde(default)]
+    pub prices: HashMap<String, ModelPriceInput>,
+    /// Fields in the configuration files which were not recognized
+    #[serde(skip)]
+    pub unknown_fields: Vec<UnknownField>,
+    /// The configuration files that were read
+    #[serde(skip)]
+    pub sources: Vec<SourceFile>,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Config {
     pub template_dirs: Vec<PathBuf>,
+    /// The namespaces declared for template directories. Other directories use their names.
+    pub namespaces: HashMap<PathBuf, String>,
+    pub template_aliases: HashMap<String, TemplateAlias>,
     pub model: ModelOptions,
+    pub routing: RoutingPolicyInput,
+    /// Save each run to the history
+    pub history: bool,
+    pub confirm: ConfirmPolicy,
+    /// Moderation settings, before the template's settings are applied
+    pub moderation: ModerationInput,
+    pub budget: BudgetPolicy,
+    pub images: ImagePolicy,
+    pub response_cache: ResponseCachePolicy,
+    pub capabilities: CapabilityRegistry,
+    pub prices: PriceTable,
+    /// Append each run to this file
+    pub log_file: Option<PathBuf>,
+    /// Fields in the configuration files which were not recognized
+    pub unknown_fields: Vec<UnknownField>,
+    /// The configuration files that were read
+    pub sources: Vec<SourceFile>,
 }
 
 impl Config {
     /// Create a [Config], recursing from the directory given up through the parent directories.
+    #[cfg(test)]
     pub fn from_directory(start_dir: PathBuf) -> Result<Self, Report<Error>> {
-        let mut config = ConfigInput::default();
+        Self::search(start_dir, &SearchOptions::default())
+    }
+
+    /// Create a [Config], recursing from the directory given up through the parent directories,
+    /// with the search settings from the command line. The `config_dir` from `options` is used
+    /// instead of `start_dir` when it is given.
+    pub fn search(start_dir: PathBuf, options: &SearchOptions) -> Result<Self, Report<Error>> {
+        let start_dir = options.config_dir.clone().unwrap_or(start_dir);
+        Self::search_in(start_dir, options, &SearchDirs::from_env())
+    }
+
+    /// Create a [Config] from the directory and its parents, followed by the global directories.
+    /// With `repo_discovery`, the template directories at the root of the git repository are read
+    /// along with its config file. The directories in `template_path` and the `template_path`
+    /// setting are searched for templates last.
+    fn search_in(
+        start_dir: PathBuf,
+        options: &SearchOptions,
+        dirs: &SearchDirs,
+    ) -> Result<Self, Report<Error>> {
+        let mut config = ConfigInput {
+            stop_at: options.stop_at,
+            ..Default::default()
+        };
 
+        // The global configuration is read first so that its `stop_at` applies to the search, but
+        // it is merged last.
+        let global_configs = dirs
+            .global_dirs
+            .iter()
+            .filter_map(|dir| std::fs::canonicalize(dir).ok())
+            .map(|dir| Ok((ConfigInput::from_dir(&dir)?, dir)))
+            .collect::<Result<Vec<_>, Report<Error>>>()?;
+        let global_stop_at = global_configs
+            .iter()
+            .find_map(|(config, _)| config.as_ref()?.stop_at);
+
+        let repo_root = options
+            .repo_discovery
+            .then(|| git_root(&start_dir).map(Path::to_path_buf))
+            .flatten();
+        let search_root = start_dir.clone();
         let mut current_dir = start_dir;
         loop {
+            let mut top_level = false;
             if let Some(new_config) = ConfigInput::from_dir(&current_dir)? {
-                let top_level = new_config.top_level;
+                top_level = new_config.top_level;
                 config.merge(new_config);
-                if top_level {
-                    break;
+            }
+
+            if repo_root.as_ref() == Some(&current_dir) {
+                for new_config in ConfigInput::from_repo_root(&current_dir, &config.templates)? {
+                    top_level |= new_config.top_level;
+                    config.merge(new_config);
                 }
             }
 
-            if !current_dir.pop() {
+            let boundary = dirs.boundary(config.stop_at.or(global_stop_at), &search_root);
+            if top_level || boundary.as_ref() == Some(&current_dir) || !current_dir.pop() {
                 break;
             }
         }
 
         if config.use_global_config.unwrap_or(true) {
-            for global_config_dir in global_config_dirs() {
-                if let Some(new_config) = ConfigInput::from_dir(&global_config_dir)? {
-                    config.merge(new_config);
+            for (global_config, dir) in global_configs {
+                // Already read while searching up from the current directory
+                if config.templates.contains(&dir) {
+                    continue;
                 }
+
+                // Personal templates can be kept in the global directory without a config file.
+                let new_config = global_config.unwrap_or_else(|| ConfigInput {
+                    templates: vec![dir],
+                    ..Default::default()
+                });
+                config.merge(new_config);
+            }
+        }
+
+        // Only templates are read from these directories, not config files.
+        let extra_dirs = dirs
+            .template_path
+            .iter()
+            .chain(&config.template_path)
+            .filter_map(|dir| std::fs::canonicalize(dir).ok())
+            .filter(|dir| dir.is_dir())
+            .collect::<Vec<_>>();
+        for dir in extra_dirs {
+            if !config.templates.contains(&dir) {
+                config.templates.push(dir);
             }
         }
 
@@ -91,21 +342,107 @@ impl Config {
             }
         }
 
+        let host_rules = HostRule::compile(&input.host_rules, &hosts)?;
+        let mut model = ModelOptions::new(
+            input.model.unwrap_or_default(),
+            hosts,
+            input
+                .default_host
+                .unwrap_or_else(|| HostDefinition::default_host().to_string()),
+        );
+        model.host_rules = host_rules;
+
+        // Catch misspelled host names here instead of on the first request.
+        if !model.host.contains_key(&model.default_host) {
+            return Err(
+                Report::new(Error::UnknownModelHost(model.default_host.clone()))
+                    .attach_printable("Used by `default_host`")
+                    .change_context(Error::ParseConfig),
+            );
+        }
+        for options in model.model_chain() {
+            let host = options.host_name();
+            if !options.host.contains_key(&host) {
+                let model_name = options.full_model_spec().model_name().to_string();
+                return Err(Report::new(Error::UnknownModelHost(host))
+                    .attach_printable(format!("Used by the model `{model_name}`"))
+                    .change_context(Error::ParseConfig));
+            }
+        }
+
+        let images = input.images.unwrap_or_default();
+        if images.max_dimension == Some(0) {
+            return Err(Report::new(Error::ParseConfig)
+                .attach_printable("`images.max_dimension` must be greater than 0"));
+        }
+
         Ok(Self {
             template_dirs: input.templates,
-            model: ModelOptions::new(
-                input.model.unwrap_or_default(),
-                hosts,
-                input
-                    .default_host
-                    .unwrap_or_else(|| HostDefinition::default_host().to_string()),
-            ),
+            namespaces: input.namespaces,
+            template_aliases: input.template_aliases,
+            routing: input.routing.unwrap_or_default(),
+            unknown_fields: input.unknown_fields,
+            sources: input.sources,
+            history: input.history.unwrap_or(true),
+            confirm: input.confirm.unwrap_or_default().into(),
+            moderation: input.moderation.unwrap_or_default(),
+            budget: input.budget.unwrap_or_default().into(),
+            images: images.into(),
+            response_cache: input.response_cache.unwrap_or_default().into(),
+            capabilities: input.model_capabilities.into(),
+            prices: input.prices.into(),
+            log_file: input.log_file,
+            model,
         })
     }
 
+    /// The namespace of a template directory: the one declared in its config file, or else the
+    /// name of the directory. Directories named `promptbox`, `.promptbox`, or `prompts` are named
+    /// after their parent instead.
+    pub fn namespace(&self, template_dir: &Path) -> String {
+        if let Some(namespace) = self.namespaces.get(template_dir) {
+            return namespace.clone();
+        }
+
+        template_dir
+            .ancestors()
+            .filter_map(Path::file_name)
+            .map(|name| name.to_string_lossy())
+            .find(|name| name != "promptbox" && !REPO_TEMPLATE_DIRS.contains(&name.as_ref()))
+            .map(|name| name.into_owned())
+            .unwrap_or_default()
+    }
+
+    /// The paths where a template with this name could be, in order of precedence. A name like
+    /// `work/summarize` is looked for as a path in every template directory first, and then as
+    /// `summarize` in the template directories with the namespace `work`.
+    fn template_paths<'a>(&'a self, name: &'a str) -> impl Iterator<Item = PathBuf> + 'a {
+        let files = |dir: &'a PathBuf, name: &'a str| {
+            TEMPLATE_EXTENSIONS
+                .iter()
+                .map(move |extension| dir.join(format!("{name}{extension}")))
+        };
+
+        let by_path = self
+            .template_dirs
+            .iter()
+            .flat_map(move |dir| files(dir, name));
+
+        let by_namespace = name
+            .split_once('/')
+            .into_iter()
+            .flat_map(move |(namespace, name)| {
+                self.template_dirs
+                    .iter()
+                    .filter(move |dir| self.namespace(dir) == namespace)
+                    .flat_map(move |dir| files(dir, name))
+            });
+
+        by_path.chain(by_namespace)
+    }
+
     pub fn find_template(&self, name: &str) -> Result<ParsedTemplate, Report<Error>> {
-        for template_dir in &self.template_dirs {
-            let template_path = template_dir.join(format!("{}.pb.toml", name));
+        for template_path in self.template_paths(name) {
             match ParsedTemplate::from_file(name, &template_path) {
                 Ok(Some(template)) => return Ok(template),
                 // template was not found in this directory, but that's ok.
@@ -116,6 +453,17 @@ impl Config {
 
         Err(Report::from(Error::TemplateNotFound))
     }
+
+    /// The partials for templates to include, from the `partials` folder of each template
+    /// directory
+    pub fn partials(&self) -> Partials {
+        Partials::new(&self.template_dirs)
+    }
+
+    /// Find the file for the template with this name, without reading it.
+    pub fn find_template_file(&self, name: &str) -> Option<PathBuf> {
+        self.template_paths(name).find(|path| path.is_file())
+    }
 }
 
 impl ConfigInput {
@@ -142,18 +490,69 @@ impl ConfigInput {
             return Ok(None);
         };
 
-        let mut new_config: ConfigInput = toml::from_str(&contents)
-            .change_context(Error::ParseConfig)
-            .attach_printable_lazy(|| config_path.display().to_string())?;
+        let (mut new_config, unknown_fields) =
+            from_toml_str::<ConfigInput>(&contents, &config_path)
+                .change_context(Error::ParseConfig)
+                .attach_printable_lazy(|| config_path.display().to_string())?;
+        new_config.unknown_fields = unknown_fields;
+        new_config.sources = vec![SourceFile::new(SourceKind::Config, &config_path, &contents)];
+
+        if let Some(providers) = new_config.providers.take() {
+            providers
+                .validate(&config_path)
+                .change_context(Error::ParseConfig)?;
+            // Providers become hosts, which take precedence over `host` sections in the same file.
+            for (name, provider) in providers.into_hosts() {
+                match new_config.host.get_mut(&name) {
+                    Some(host) => host.merge_from_input(&provider),
+                    None => {
+                        new_config.host.insert(name, provider);
+                    }
+                }
+            }
+        }
 
         let base_dir = config_path.parent().expect("path had no directory");
         new_config.resolve_template_dirs(base_dir);
+        if let Some(model) = new_config.model.as_mut() {
+            model.resolve_tokenizer_path(base_dir);
+        }
+        for host in new_config.host.values_mut() {
+            host.resolve_ca_bundle_path(base_dir);
+        }
+        if let Some(log_file) = new_config.log_file.as_mut() {
+            if log_file.is_relative() {
+                *log_file = base_dir.join(&log_file);
+            }
+        }
         Ok(Some(new_config))
     }
 
+    /// Load the template directories at the root of a git repository, skipping any that are
+    /// already in `known_dirs`. Each directory can have its own config file, or just hold
+    /// templates.
+    fn from_repo_root(root: &Path, known_dirs: &[PathBuf]) -> Result<Vec<Self>, Report<Error>> {
+        REPO_TEMPLATE_DIRS
+            .iter()
+            .filter_map(|name| std::fs::canonicalize(root.join(name)).ok())
+            .filter(|dir| dir.is_dir() && !known_dirs.contains(dir))
+            .map(|dir| {
+                let config = Self::from_dir(&dir)?.unwrap_or_else(|| ConfigInput {
+                    templates: vec![dir],
+                    ..Default::default()
+                });
+                Ok(config)
+            })
+            .collect()
+    }
+
     /// Convert the template directory references to absolute paths
     fn resolve_template_dirs(&mut self, base_dir: &Path) {
-        for template in self.templates.iter_mut() {
+        for template in self
+            .templates
+            .iter_mut()
+            .chain(self.template_path.iter_mut())
+        {
             if template.is_relative() {
                 if let Ok(full_path) = std::fs::canonicalize(base_dir.join(&template)) {
                     *template = full_path;
@@ -164,9 +563,25 @@ impl ConfigInput {
 
     /// Merge in another ConfigInput, using only values which are not yet configured in `self`.
     fn merge(&mut self, other: ConfigInput) {
+        if let Some(namespace) = &other.namespace {
+            for dir in &other.templates {
+                self.namespaces
+                    .entry(dir.clone())
+                    .or_insert_with(|| namespace.clone());
+            }
+        }
         self.templates.extend(other.templates);
+        self.template_path.extend(other.template_path);
+        self.unknown_fields.extend(other.unknown_fields);
+        self.sources.extend(other.sources);
 
         overwrite_option_from_option(&mut self.use_global_config, &other.use_global_config);
+        update_if_none(&mut self.stop_at, &other.stop_at);
+        update_if_none(&mut self.history, &other.history);
+        update_if_none(&mut self.log_file, &other.log_file);
+        update_if_none(&mut self.default_host, &other.default_host);
+        // Rules from closer configuration files come first, so they take precedence.
+        self.host_rules.extend(other.host_rules);
 
         if let Some(other_model) = other.model {
             if let Some(model) = self.model.as_mut() {
@@ -176,6 +591,74 @@ impl ConfigInput {
             }
         }
 
+        if let Some(other_routing) = other.routing {
+            if let Some(routing) = self.routing.as_mut() {
+                routing.merge_defaults(&other_routing);
+            } else {
+                self.routing = Some(other_routing);
+            }
+        }
+
+        if let Some(other_confirm) = other.confirm {
+            if let Some(confirm) = self.confirm.as_mut() {
+                confirm.merge_defaults(&other_confirm);
+            } else {
+                self.confirm = Some(other_confirm);
+            }
+        }
+
+        if let Some(other_moderation) = other.moderation {
+            if let Some(moderation) = self.moderation.as_mut() {
+                moderation.merge_defaults(&other_moderation);
+            } else {
+                self.moderation = Some(other_moderation);
+            }
+        }
+
+        if let Some(other_budget) = other.budget {
+            if let Some(budget) = self.budget.as_mut() {
+                budget.merge_defaults(&other_budget);
+            } else {
+                self.budget = Some(other_budget);
+            }
+        }
+
+        if let Some(other_images) = other.images {
+            if let Some(images) = self.images.as_mut() {
+                images.merge_defaults(&other_images);
+            } else {
+                self.images = Some(other_images);
+            }
+        }
+
+        if let Some(other_cache) = other.response_cache {
+            if let Some(cache) = self.response_cache.as_mut() {
+                cache.merge_defaults(&other_cache);
+            } else {
+                self.response_cache = Some(other_cache);
+            }
+        }
+
+        for (key, other_caps) in other.model_capabilities {
+            if let Some(caps) = self.model_capabilities.get_mut(&key) {
+                caps.merge_defaults(&other_caps);
+            } else {
+                self.model_capabilities.insert(key, other_caps);
+            }
+        }
+
+        for (name, alias) in other.template_aliases {
+            self.template_aliases.entry(name).or_insert(alias);
+        }
+
+        for (key, other_price) in other.prices {
+            if let Some(price) = self.prices.get_mut(&key) {
+                price.merge_defaults(&other_price);
+            } else {
+                self.prices.insert(key, other_price);
+            }
+        }
+
         for (key, other_host) in other.host {
             if let Some(host) = self.host.get_mut(&key) {
                 host.merge_from_input(&other_host);
@@ -191,6 +674,13 @@ mod tests {
     use super::*;
     use crate::tests::{base_dir, BASE_DIR};
 
+    fn no_discovery() -> SearchOptions {
+        SearchOptions {
+            repo_discovery: false,
+            ..Default::default()
+        }
+    }
+
     #[test]
     fn config_in_subdir() {
         let config = Config::from_directory(base_dir("config_in_subdir")).expect("loading config");
@@ -213,6 +703,27 @@ mod tests {
         assert_eq!(config.template_dirs, expected_dirs);
     }
 
+    #[test]
+    fn nested_model_defaults() {
+        let parent = Config::from_directory(base_dir("nested_config")).expect("loading config");
+        assert_eq!(parent.model.model.model_name(), "llama3.1");
+        assert_eq!(parent.model.host_name(), "ollama");
+        assert_eq!(parent.model.temperature, 0.5);
+
+        // The project's settings take precedence, and the rest are inherited from the parent.
+        let project =
+            Config::from_directory(base_dir("nested_config/project")).expect("loading config");
+        assert_eq!(project.model.model.model_name(), "gpt-4o");
+        assert_eq!(project.model.host_name(), "openai");
+        assert_eq!(project.model.temperature, 0.8);
+        assert_eq!(project.model.top_p, Some(0.9));
+        assert_eq!(project.model.default_host, "together");
+        assert_eq!(
+            project.template_dirs,
+            vec![base_dir("nested_config/project"), base_dir("nested_config")]
+        );
+    }
+
     #[test]
     fn malformed() {
         let err = Config::from_directory(base_dir("malformed_config"))
@@ -220,6 +731,63 @@ mod tests {
         assert!(matches!(err.current_context(), Error::ParseConfig));
     }
 
+    #[test]
+    fn providers() {
+        let config = Config::from_directory(base_dir("providers_config")).expect("loading config");
+        let hosts = &config.model.host;
+        assert_eq!(hosts["ollama"].endpoint, "http://gpu-box:11434");
+        assert_eq!(hosts["vllm"].endpoint, "http://localhost:8000/v1");
+        assert_eq!(config.model.default_host, "vllm");
+    }
+
+    #[test]
+    fn invalid_providers() {
+        let err = Config::from_directory(base_dir("invalid_providers"))
+            .expect_err("loading config should fail");
+        assert!(matches!(err.current_context(), Error::ParseConfig));
+        assert!(format!("{err:?}").contains("`providers.custom[0].endpoint` should be an http"));
+    }
+
+    #[test]
+    fn unknown_default_host() {
+        let input = ConfigInput {
+            default_host: Some("olama".to_string()),
+            ..Default::default()
+        };
+        let err = Config::create_config(input).expect_err("creating config should fail");
+        assert!(format!("{err:?}").contains("Unknown model host olama"));
+    }
+
+    #[test]
+    fn zero_image_dimension() {
+        let input = ConfigInput {
+            images: Some(ImagePolicyInput {
+                max_dimension: Some(0),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let err = Config::create_config(input).expect_err("creating config should fail");
+        assert!(format!("{err:?}").contains("max_dimension"));
+    }
+
+    #[test]
+    fn host_rules() {
+        let input = toml::from_str::<ConfigInput>(
+            r##"
+            host_rules = [{ model = "^llama", host = "together" }]
+
+            [model]
+            model = "fast"
+            alias = { fast = "llama-3.1-8b" }
+            "##,
+        )
+        .unwrap();
+        let config = Config::create_config(input).expect("creating config");
+        assert_eq!(config.model.full_model_spec().model_name(), "llama-3.1-8b");
+        assert_eq!(config.model.host_name(), "together");
+    }
+
     #[test]
     fn stop_at_toplevel_setting() {
         let config = Config::from_directory(base_dir("toplevel_config")).expect("loading config");
@@ -242,6 +810,8 @@ mod tests {
                     api_key: Some("foo_key".to_string()),
                     protocol: Some(crate::hosts::HostProtocol::OpenAi),
                     limit_context_length: Some(true),
+                    send_app_id: None,
+                    ..Default::default()
                 },
             )]),
             ..Default::default()
@@ -281,6 +851,8 @@ mod tests {
                         api_key: Some("foo_key".to_string()),
                         protocol: Some(crate::hosts::HostProtocol::OpenAi),
                         limit_context_length: Some(true),
+                        send_app_id: None,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -319,6 +891,8 @@ mod tests {
                     api_key: Some("foo_key".to_string()),
                     protocol: Some(crate::hosts::HostProtocol::OpenAi),
                     limit_context_length: Some(true),
+                    send_app_id: None,
+                    ..Default::default()
                 },
             )]),
             ..Default::default()
@@ -342,4 +916,171 @@ mod tests {
 
         let _ = Config::create_config(input).unwrap_err();
     }
+
+    #[test]
+    fn templates_at_repo_root() {
+        let repo = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(repo.path()).unwrap();
+        std::fs::create_dir(root.join(".git")).unwrap();
+        std::fs::create_dir(root.join("prompts")).unwrap();
+        std::fs::create_dir(root.join(".promptbox")).unwrap();
+        std::fs::write(
+            root.join(".promptbox/promptbox.toml"),
+            "[model]\ntemperature = 0.3\n",
+        )
+        .unwrap();
+        let start_dir = root.join("services/api/src");
+        std::fs::create_dir_all(&start_dir).unwrap();
+
+        let config = Config::search_in(
+            start_dir.clone(),
+            &SearchOptions::default(),
+            &SearchDirs::default(),
+        )
+        .expect("loading config");
+        let repo_dirs = config
+            .template_dirs
+            .iter()
+            .filter(|dir| dir.starts_with(&root))
+            .collect::<Vec<_>>();
+        assert_eq!(repo_dirs, [&root.join(".promptbox"), &root.join("prompts")]);
+        assert_eq!(config.model.temperature, 0.3);
+
+        let config = Config::search_in(start_dir, &no_discovery(), &SearchDirs::default())
+            .expect("loading config");
+        assert!(!config
+            .template_dirs
+            .iter()
+            .any(|dir| dir.starts_with(&root)));
+    }
+
+    #[test]
+    fn global_dir() {
+        let project = tempfile::tempdir().unwrap();
+        let project_dir = std::fs::canonicalize(project.path()).unwrap();
+        std::fs::write(
+            project_dir.join("promptbox.toml"),
+            "top_level = true\n[model]\ntemperature = 0.7\n",
+        )
+        .unwrap();
+
+        let global = tempfile::tempdir().unwrap();
+        let global_dir = std::fs::canonicalize(global.path()).unwrap();
+        let dirs = SearchDirs {
+            global_dirs: vec![global_dir.clone()],
+            ..Default::default()
+        };
+        let config =
+            Config::search_in(project_dir.clone(), &no_discovery(), &dirs).expect("loading config");
+        // The global directory holds templates even without a config file.
+        assert_eq!(
+            config.template_dirs,
+            [project_dir.clone(), global_dir.clone()]
+        );
+
+        std::fs::write(
+            global_dir.join("promptbox.toml"),
+            "[model]\ntemperature = 0.1\ntop_p = 0.3\n",
+        )
+        .unwrap();
+        let config =
+            Config::search_in(project_dir.clone(), &no_discovery(), &dirs).expect("loading config");
+        assert_eq!(
+            config.template_dirs,
+            [project_dir.clone(), global_dir.clone()]
+        );
+        // The current directory's settings take precedence over the global ones.
+        assert_eq!(config.model.temperature, 0.7);
+        assert_eq!(config.model.top_p, Some(0.3));
+    }
+
+    #[test]
+    fn template_path() {
+        let root = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(root.path()).unwrap();
+        let project_dir = root.join("project");
+        let shared_dir = root.join("shared");
+        let team_dir = root.join("team");
+        for dir in [&project_dir, &shared_dir, &team_dir] {
+            std::fs::create_dir(dir).unwrap();
+        }
+        std::fs::write(
+            project_dir.join("promptbox.toml"),
+            "top_level = true\ntemplate_path = [\"../shared\", \"../missing\"]\n",
+        )
+        .unwrap();
+        // Config files in the extra directories are not read.
+        std::fs::write(
+            shared_dir.join("promptbox.toml"),
+            "[model]\ntemperature = 0.9\n",
+        )
+        .unwrap();
+
+        let dirs = SearchDirs {
+            template_path: vec![team_dir.clone(), project_dir.clone()],
+            ..Default::default()
+        };
+        let config =
+            Config::search_in(project_dir.clone(), &no_discovery(), &dirs).expect("loading config");
+        // The environment's directories come before the configuration's, and directories that are
+        // already searched or don't exist are skipped.
+        assert_eq!(config.template_dirs, [project_dir, team_dir, shared_dir]);
+        assert_ne!(config.model.temperature, 0.9);
+    }
+
+    #[test]
+    fn stop_at() {
+        let root = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(root.path()).unwrap();
+        let home_dir = root.join("home");
+        let repo_dir = home_dir.join("repo");
+        let start_dir = repo_dir.join("src");
+        let global_dir = root.join("global");
+        std::fs::create_dir_all(repo_dir.join(".git")).unwrap();
+        std::fs::create_dir(&start_dir).unwrap();
+        std::fs::create_dir(&global_dir).unwrap();
+        std::fs::write(root.join("promptbox.toml"), "[model]\ntop_p = 0.5\n").unwrap();
+        std::fs::write(
+            home_dir.join("promptbox.toml"),
+            "[model]\ntemperature = 0.5\n",
+        )
+        .unwrap();
+
+        let dirs = SearchDirs {
+            home_dir: Some(home_dir.clone()),
+            global_dirs: vec![global_dir.clone()],
+            ..Default::default()
+        };
+        let search = |stop_at| {
+            let options = SearchOptions {
+                stop_at,
+                ..no_discovery()
+            };
+            Config::search_in(start_dir.clone(), &options, &dirs).expect("loading config")
+        };
+        let config = search(None);
+        assert_eq!(config.model.temperature, 0.5);
+        assert_eq!(config.model.top_p, Some(0.5));
+
+        let config = search(Some(SearchBoundary::Home));
+        assert_eq!(config.model.temperature, 0.5);
+        assert_eq!(config.model.top_p, None);
+
+        let config = search(Some(SearchBoundary::GitRoot));
+        assert_eq!(config.model.temperature, 0.0);
+        assert_eq!(config.model.top_p, None);
+
+        // The setting can come from a configuration file, including the global one.
+        std::fs::write(
+            global_dir.join("promptbox.toml"),
+            "stop_at = \"git_root\"\n",
+        )
+        .unwrap();
+        let config = search(None);
+        assert_eq!(config.model.temperature, 0.0);
+
+        // The command line takes precedence.
+        let config = search(Some(SearchBoundary::Home));
+        assert_eq!(config.model.temperature, 0.5);
+    }
 }
@@ -0,0 +1,349 @@
+//! Ask before sending prompts that are large or expensive.
+
+use std::io::{BufRead, IsTerminal, Write};
+
+use error_stack::{Report, ResultExt};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::{
+    error::Error,
+    history::TokenCounts,
+    model::ModelOptions,
+    option::{overwrite_from_option, update_if_none},
+    session::ChatMessage,
+    tokenizer::Tokenizer,
+    usage::PriceTable,
+};
+
+const DEFAULT_MAX_TOKENS: usize = 50_000;
+
+#[derive(Deserialize, Debug, Clone, Default, JsonSchema)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct ConfirmPolicyInput {
+    /// Ask before sending prompts with at least this many tokens. Defaults to 50000.
+    pub max_tokens: Option<usize>,
+    /// Ask before sending prompts that are estimated to cost at least this many dollars, using the
+    /// prompt prices in the `prices` section.
+    pub max_cost: Option<f64>,
+}
+
+impl ConfirmPolicyInput {
+    /// For any members that are `None` in this `ConfirmPolicyInput`, use the value from `other`
+    pub fn merge_defaults(&mut self, other: &ConfirmPolicyInput) {
+        update_if_none(&mut self.max_tokens, &other.max_tokens);
+        update_if_none(&mut self.max_cost, &other.max_cost);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfirmPolicy {
+    pub max_tokens: usize,
+    pub max_cost: Option<f64>,
+}
+
+impl Default for ConfirmPolicy {
+    fn default() -> Self {
+        Self {
+            max_tokens: DEFAULT_MAX_TOKENS,
+            max_cost: None,
+        }
+    }
+}
+
+impl From<ConfirmPolicyInput> for ConfirmPolicy {
+    fn from(value: ConfirmPolicyInput) -> Self {
+        let mut policy = Self {
+            max_cost: value.max_cost,
+            ..Default::default()
+        };
+        overwrite_from_option(&mut policy.max_tokens, &value.max_tokens);
+        policy
+    }
+}
+
+/// The size and estimated cost of a request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunEstimate {
+    pub model: String,
+    /// The tokens sent to the model
+    pub tokens: usize,
+    /// The cost in dollars, if the price of the model is known
+    pub cost: Option<f64>,
+}
+
+impl RunEstimate {
+    /// Estimate a request with the prompt, the system prompt, and the earlier messages of a
+    /// session.
+    pub fn new(
+        prices: &PriceTable,
+        model_options: &ModelOptions,
+        prompt: &str,
+        system: &str,
+        history: &[ChatMessage],
+    ) -> Result<Self, Report<Error>> {
+        let tokenizer = Tokenizer::get(model_options.tokenizer.as_deref())
+            .change_context(Error::PreparePrompt)?;
+        let texts = history
+            .iter()
+            .map(|message| message.content.as_str())
+            .chain([prompt, system]);
+        let mut tokens = 0;
+        for text in texts {
+            if !text.is_empty() {
+                tokens += tokenizer
+                    .encode(text)
+                    .change_context(Error::PreparePrompt)?
+                    .len();
+            }
+        }
+
+        Ok(Self::from_tokens(prices, model_options, tokens))
+    }
+
+    /// Estimate a request that sends `tokens` tokens. When `max_tokens` is set, the response is
+    /// counted as that long, since that is the most that it can cost.
+    pub fn from_tokens(prices: &PriceTable, model_options: &ModelOptions, tokens: usize) -> Self {
+        let model = model_options.full_model_spec().model_name().to_string();
+        let counts = TokenCounts {
+            prompt: tokens,
+            response: model_options.max_tokens.unwrap_or_default() as usize,
+        };
+        let cost = prices.cost(&model, &counts);
+
+        Self {
+            model,
+            tokens,
+            cost,
+        }
+    }
+
+    /// The estimate for sending the same request `n` times, as with `--n`.
+    pub fn times(self, n: usize) -> Self {
+        Self {
+            tokens: self.tokens * n,
+            cost: self.cost.map(|cost| cost * n as f64),
+            ..self
+        }
+    }
+
+    /// Add another request, such as the one to the judge for `--judge`. The cost is only known if
+    /// it is known for both requests.
+    pub fn add(self, other: &RunEstimate) -> Self {
+        Self {
+            tokens: self.tokens + other.tokens,
+            cost: self.cost.zip(other.cost).map(|(a, b)| a + b),
+            ..self
+        }
+    }
+
+    /// Returns true if the request is large or expensive enough to ask about.
+    pub fn exceeds(&self, policy: &ConfirmPolicy) -> bool {
+        let too_expensive = policy
+            .max_cost
+            .zip(self.cost)
+            .is_some_and(|(max_cost, cost)| cost >= max_cost);
+        self.tokens >= policy.max_tokens || too_expensive
+    }
+
+    pub fn question(&self) -> String {
+        let tokens = if self.tokens >= 1000 {
+            format!("~{}k", (self.tokens as f64 / 1000.0).round())
+        } else {
+            self.tokens.to_string()
+        };
+        let cost = self
+            .cost
+            .map(|cost| format!(" (~${cost:.2})"))
+            .unwrap_or_default();
+
+        format!("Send {tokens} tokens{cost} to {}? [y/N] ", self.model)
+    }
+}
+
+/// Ask a yes or no question, defaulting to no.
+fn ask(question: &str, input: &mut impl BufRead, output: &mut impl Write) -> std::io::Result<bool> {
+    write!(output, "{question}")?;
+    output.flush()?;
+
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    let answer = answer.trim().to_lowercase();
+    Ok(answer == "y" || answer == "yes")
+}
+
+/// Ask the user to confirm the request if it exceeds the policy. Only asks when stderr is a
+/// terminal, so scripts are never blocked waiting for an answer.
+pub fn confirm_run(policy: &ConfirmPolicy, estimate: &RunEstimate) -> Result<(), Report<Error>> {
+    if !std::io::stderr().is_terminal() {
+        return Ok(());
+    }
+
+    if !estimate.exceeds(policy) {
+        return Ok(());
+    }
+
+    match ask_terminal(&estimate.question())? {
+        // There's no way to ask, so don't block the run.
+        None | Some(true) => Ok(()),
+        Some(false) => Err(Report::new(Error::RunCancelled)),
+    }
+}
+
+/// Ask a yes or no question on the terminal. Returns `None` if there is no terminal to ask on.
+pub fn ask_terminal(question: &str) -> Result<Option<bool>, Report<Error>> {
+    if !std::io::stderr().is_terminal() {
+        return Ok(None);
+    }
+
+    let answer = if std::io::stdin().is_terminal() {
+        ask(
+            question,
+            &mut std::io::stdin().lock(),
+            &mut std::io::stderr(),
+        )
+    } else {
+        // stdin is being used for input to the prompt, so read the answer from the terminal.
+        match std::fs::File::open("/dev/tty") {
+            Ok(tty) => ask(
+                question,
+                &mut std::io::BufReader::new(tty),
+                &mut std::io::stderr(),
+            ),
+            Err(_) => return Ok(None),
+        }
+    };
+
+    answer.change_context(Error::Io).map(Some)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn estimate(tokens: usize, cost: Option<f64>) -> RunEstimate {
+        RunEstimate {
+            model: "gpt-4o".to_string(),
+            tokens,
+            cost,
+        }
+    }
+
+    #[test]
+    fn parse_and_merge() {
+        let mut input: ConfirmPolicyInput = toml::from_str(
+            r##"
+            max_cost = 0.5
+            "##,
+        )
+        .unwrap();
+        let parent: ConfirmPolicyInput = toml::from_str(
+            r##"
+            max_tokens = 1000
+            max_cost = 10
+            "##,
+        )
+        .unwrap();
+        input.merge_defaults(&parent);
+
+        let policy = ConfirmPolicy::from(input);
+        assert_eq!(policy.max_tokens, 1000);
+        assert_eq!(policy.max_cost, Some(0.5));
+
+        assert_eq!(
+            ConfirmPolicy::from(ConfirmPolicyInput::default()),
+            ConfirmPolicy::default()
+        );
+    }
+
+    #[test]
+    fn thresholds() {
+        let policy = ConfirmPolicy {
+            max_tokens: 50_000,
+            max_cost: Some(1.0),
+        };
+
+        assert!(!estimate(1000, None).exceeds(&policy));
+        assert!(estimate(50_000, None).exceeds(&policy));
+        assert!(!estimate(1000, Some(0.5)).exceeds(&policy));
+        assert!(estimate(1000, Some(1.5)).exceeds(&policy));
+
+        let no_cost_limit = ConfirmPolicy {
+            max_cost: None,
+            ..policy
+        };
+        assert!(!estimate(1000, Some(100.0)).exceeds(&no_cost_limit));
+    }
+
+    #[test]
+    fn counts_history_and_response() {
+        let prices = PriceTable::from(std::collections::HashMap::from([(
+            "gpt-4o".to_string(),
+            crate::usage::ModelPriceInput {
+                input: Some(1_000_000.0),
+                output: Some(2_000_000.0),
+            },
+        )]));
+        let mut model_options = ModelOptions {
+            model: crate::model::ModelSpec::Plain("gpt-4o".to_string()),
+            tokenizer: Some(
+                crate::tests::base_dir("whitespace_tokenizer.json")
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+            ..Default::default()
+        };
+        let history = [ChatMessage::user("a b c"), ChatMessage::assistant("d e")];
+
+        let estimate = RunEstimate::new(&prices, &model_options, "f g", "h", &history).unwrap();
+        assert_eq!(estimate.tokens, 8);
+        assert_eq!(estimate.cost, Some(8.0));
+
+        // The response is counted at its longest when max_tokens is set.
+        model_options.max_tokens = Some(10);
+        let estimate = RunEstimate::new(&prices, &model_options, "f g", "h", &history).unwrap();
+        assert_eq!(estimate.tokens, 8);
+        assert_eq!(estimate.cost, Some(28.0));
+    }
+
+    #[test]
+    fn times_and_add() {
+        let estimate = estimate(1000, Some(0.5)).times(3);
+        assert_eq!(estimate, self::estimate(3000, Some(1.5)));
+        assert_eq!(
+            estimate.clone().add(&self::estimate(500, Some(0.25))),
+            self::estimate(3500, Some(1.75))
+        );
+        assert_eq!(
+            estimate.add(&self::estimate(500, None)),
+            self::estimate(3500, None)
+        );
+    }
+
+    #[test]
+    fn question() {
+        assert_eq!(
+            estimate(53_200, Some(0.798)).question(),
+            "Send ~53k tokens (~$0.80) to gpt-4o? [y/N] "
+        );
+        assert_eq!(
+            estimate(800, None).question(),
+            "Send 800 tokens to gpt-4o? [y/N] "
+        );
+    }
+
+    #[test]
+    fn answers() {
+        for (answer, expected) in [
+            ("y\n", true),
+            ("YES\n", true),
+            ("n\n", false),
+            ("\n", false),
+        ] {
+            let mut output = Vec::new();
+            let result = ask("Continue? ", &mut answer.as_bytes(), &mut output).unwrap();
+            assert_eq!(result, expected, "answer {answer:?}");
+            assert_eq!(output, b"Continue? ");
+        }
+    }
+}
@@ -1,41 +1,26 @@
-use std::{borrow::Cow, path::Path};
+use std::{borrow::Cow, collections::HashMap, path::Path, time::Duration};
 
 use clap::ValueEnum;
 use error_stack::{Report, ResultExt};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use tokenizers::Encoding;
-
-use crate::{model::ModelOptions, option::update_if_none, Error};
-
-struct Tokenizer(tokenizers::Tokenizer);
-
-impl Tokenizer {
-    fn new() -> Result<Self, Error> {
-        // This isn't accurate for everything but most models are using a similar config.
-        // Eventually it would be better to get the proper tokenizer for each model.
-        let tokenizer = tokenizers::Tokenizer::from_pretrained("TheBloke/Llama-2-70B-fp16", None)
-            .map_err(|e| Error::Tokenizer(e.to_string()))?;
-        Ok(Self(tokenizer))
-    }
-
-    fn encode(&self, input: &str) -> Result<Encoding, Error> {
-        self.0
-            .encode(input, false)
-            .map_err(|e| Error::Tokenizer(e.to_string()))
-    }
-
-    #[cfg(test)]
-    fn encode_batch<'s>(
-        &self,
-        input: Vec<impl Into<tokenizers::EncodeInput<'s>> + Send>,
-    ) -> Result<Vec<Encoding>, Error> {
-        self.0
-            .encode_batch(input, false)
-            .map_err(|e| Error::Tokenizer(e.to_string()))
-    }
-}
-
-#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, ValueEnum, PartialEq, Eq)]
+use tracing::{event, Level};
+
+use crate::{
+    cache::Cache,
+    model::ModelOptions,
+    option::update_if_none,
+    option_groups::update_option_value,
+    pin::{locate_pins, pin_sizes, Pin},
+    session::{self, ChatMessage},
+    template::RenderEnv,
+    tokenizer::{Encoding, Tokenizer},
+    Error,
+};
+
+#[derive(
+    Serialize, Deserialize, Default, Debug, Clone, Copy, ValueEnum, PartialEq, Eq, JsonSchema,
+)]
 #[serde(rename_all = "snake_case")]
 pub enum OverflowKeep {
     /// Keep the start of the content
@@ -45,7 +30,9 @@ pub enum OverflowKeep {
     End,
 }
 
-#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, ValueEnum, PartialEq, Eq)]
+#[derive(
+    Serialize, Deserialize, Default, Debug, Clone, Copy, ValueEnum, PartialEq, Eq, JsonSchema,
+)]
 #[serde(rename_all = "snake_case")]
 /// Control how array arguments are trimmed when reducing context overflow.
 pub enum ArrayTrimPriority {
@@ -74,6 +61,9 @@ pub struct ContextOptions {
     /// When trimming array arguments, whether to preserve the first arguments,
     /// the last arguments, or try to trim equally.
     pub array_priority: ArrayTrimPriority,
+    /// Options whose content is never trimmed. This is set from the options with `pin = true`.
+    #[serde(default)]
+    pub pinned_args: Vec<String>,
 }
 
 impl From<ContextOptionsInput> for ContextOptions {
@@ -84,6 +74,7 @@ impl From<ContextOptionsInput> for ContextOptions {
             trim_args: value.trim_args,
             array_priority: value.array_priority.unwrap_or_default(),
             reserve_output: value.reserve_output.unwrap_or(256),
+            pinned_args: vec![],
         }
     }
 }
@@ -96,11 +87,12 @@ impl Default for ContextOptions {
             trim_args: vec![],
             array_priority: ArrayTrimPriority::default(),
             reserve_output: 256,
+            pinned_args: vec![],
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, JsonSchema)]
 #[cfg_attr(test, derive(PartialEq, Eq))]
 pub struct ContextOptionsInput {
     /// Set a lower context size limit for a model.
@@ -132,7 +124,7 @@ impl ContextOptionsInput {
     }
 }
 
-fn truncate_at<'a>(
+pub(crate) fn truncate_at<'a>(
     limit: usize,
     keep: OverflowKeep,
     input: &'a str,
@@ -159,6 +151,7 @@ pub fn enforce_context_limit(
     model_options: &ModelOptions,
     template_path: &Path,
     template: &str,
+    env: &RenderEnv,
     mut template_args: tera::Context,
     rendered: String,
 ) -> Result<String, Report<Error>> {
@@ -170,7 +163,8 @@ pub fn enforce_context_limit(
         return Ok(rendered);
     };
 
-    let tokenizer = Tokenizer::new().change_context(Error::PreparePrompt)?;
+    let tokenizer =
+        Tokenizer::get(model_options.tokenizer.as_deref()).change_context(Error::PreparePrompt)?;
     let encoded = tokenizer
         .encode(&rendered)
         .change_context(Error::PreparePrompt)?;
@@ -179,15 +173,45 @@ pub fn enforce_context_limit(
         return Ok(rendered);
     }
 
+    let pins = locate_pins(
+        template,
+        &template_args,
+        &model_options.context.pinned_args,
+        &rendered,
+    );
+    let pin_sizes = pin_sizes(&pins, &rendered, |text| Ok(tokenizer.encode(text)?.len()))
+        .change_context(Error::PreparePrompt)?;
+    let pinned_tokens = pin_sizes.iter().map(|(_, size)| size).sum::<usize>();
+    if pinned_tokens > context_limit {
+        let mut report = Report::new(Error::PinnedContextTooLong {
+            pinned: pinned_tokens,
+            limit: context_limit,
+        });
+        for (label, size) in pin_sizes {
+            report = report.attach_printable(format!("{label}: {size} tokens"));
+        }
+        return Err(report);
+    }
+
     if model_options.context.trim_args.is_empty() {
         // trim from the entire context
-        let prompt = truncate_at(
-            context_limit,
-            model_options.context.keep,
-            &rendered,
-            &encoded,
-        )
-        .to_string();
+        let prompt = if pins.is_empty() {
+            truncate_at(
+                context_limit,
+                model_options.context.keep,
+                &rendered,
+                &encoded,
+            )
+            .to_string()
+        } else {
+            truncate_around_pins(
+                &tokenizer,
+                context_limit - pinned_tokens,
+                model_options.context.keep,
+                &rendered,
+                &pins,
+            )?
+        };
         Ok(prompt)
     } else {
         // trim from specific arguments and rerender
@@ -199,12 +223,176 @@ pub fn enforce_context_limit(
             &mut template_args,
         )?;
 
-        let prompt = crate::template::render_template(template_path, template, &template_args)?;
+        let prompt =
+            crate::template::render_template(template_path, template, &template_args, env)?;
 
         Ok(prompt)
     }
 }
 
+/// Drop the oldest messages from a session's history until they fit in the context along with the
+/// prompt and system prompt. Like [enforce_context_limit], this leaves room for `reserve_output`,
+/// which [ModelOptions::context_limit] has already subtracted.
+pub fn fit_session_history(
+    model_options: &ModelOptions,
+    prompt: &str,
+    system: &str,
+    history: Vec<ChatMessage>,
+) -> Result<Vec<ChatMessage>, Report<Error>> {
+    let context_limit = model_options
+        .context_limit()
+        .change_context(Error::PreparePrompt)?;
+
+    let Some(context_limit) = context_limit else {
+        return Ok(history);
+    };
+
+    let tokenizer =
+        Tokenizer::get(model_options.tokenizer.as_deref()).change_context(Error::PreparePrompt)?;
+    let count = |text: &str| Ok::<_, Error>(tokenizer.encode(text)?.len());
+    let used = count(prompt).change_context(Error::PreparePrompt)?
+        + count(system).change_context(Error::PreparePrompt)?;
+
+    let total = history.len();
+    let history = session::fit_to_budget(history, context_limit.saturating_sub(used), count)
+        .change_context(Error::PreparePrompt)?;
+    if history.len() < total {
+        event!(
+            Level::INFO,
+            dropped = total - history.len(),
+            "Dropped old session messages to fit the context"
+        );
+    }
+
+    Ok(history)
+}
+
+/// Truncate the parts of `rendered` outside of the pins so that they fit in `budget` tokens,
+/// leaving the pinned content as it is.
+fn truncate_around_pins(
+    tokenizer: &Tokenizer,
+    budget: usize,
+    keep: OverflowKeep,
+    rendered: &str,
+    pins: &[Pin],
+) -> Result<String, Report<Error>> {
+    // The prompt as alternating unpinned and pinned pieces
+    let mut pieces = Vec::with_capacity(pins.len() * 2 + 1);
+    let mut pos = 0;
+    for pin in pins {
+        pieces.push((false, &rendered[pos..pin.range.start]));
+        pieces.push((true, &rendered[pin.range.clone()]));
+        pos = pin.range.end;
+    }
+    pieces.push((false, &rendered[pos..]));
+
+    let mut output = vec![Cow::Borrowed(""); pieces.len()];
+    let mut remaining = budget;
+    let order: Box<dyn Iterator<Item = usize>> = match keep {
+        OverflowKeep::Start => Box::new(0..pieces.len()),
+        OverflowKeep::End => Box::new((0..pieces.len()).rev()),
+    };
+    for i in order {
+        let (pinned, text) = pieces[i];
+        if pinned {
+            output[i] = Cow::Borrowed(text);
+            continue;
+        }
+
+        let encoded = tokenizer
+            .encode(text)
+            .change_context(Error::PreparePrompt)?;
+        if encoded.len() <= remaining {
+            output[i] = Cow::Borrowed(text);
+            remaining -= encoded.len();
+            continue;
+        }
+
+        let truncated = if remaining > 0 {
+            truncate_at(remaining, keep, text, &encoded)
+        } else {
+            ""
+        };
+        remaining = 0;
+
+        // Keep the whitespace that separated this piece from the pinned content next to it.
+        output[i] = match keep {
+            OverflowKeep::Start => {
+                let space = &text[text.trim_end().len()..];
+                Cow::Owned(format!("{truncated}{space}"))
+            }
+            OverflowKeep::End => {
+                let space = &text[..text.len() - text.trim_start().len()];
+                Cow::Owned(format!("{space}{truncated}"))
+            }
+        };
+    }
+
+    Ok(output.concat())
+}
+
+const LEARNED_LIMITS_FILE: &str = "context_limits.json";
+
+/// Load the context limits learned from previous runs, by model name.
+pub fn load_learned_limits() -> HashMap<String, usize> {
+    let limits =
+        Cache::new().and_then(|cache| cache.read_cache(LEARNED_LIMITS_FILE, Duration::MAX));
+    match limits {
+        Ok(limits) => limits.unwrap_or_default(),
+        Err(e) => {
+            event!(Level::WARN, error = ?e, "Failed to read learned context limits");
+            HashMap::new()
+        }
+    }
+}
+
+/// Remember a context limit for a model, so that later runs don't send prompts that are too long.
+pub fn save_learned_limit(model: &str, limit: usize) -> Result<(), Report<Error>> {
+    let cache = Cache::new()?;
+    let mut limits: HashMap<String, usize> = cache
+        .read_cache(LEARNED_LIMITS_FILE, Duration::MAX)?
+        .unwrap_or_default();
+    limits.insert(model.to_string(), limit);
+    cache.write_cache(LEARNED_LIMITS_FILE, limits)
+}
+
+/// After the host rejected `prompt` as too long, set a smaller context limit for the model and
+/// render the prompt again to fit within it. Returns the new limit and prompt.
+pub fn shrink_after_context_error(
+    model_options: &mut ModelOptions,
+    reported_limit: Option<usize>,
+    template_path: &Path,
+    template: &str,
+    env: &RenderEnv,
+    template_args: &tera::Context,
+    prompt: &str,
+) -> Result<(usize, String), Report<Error>> {
+    let tokenizer =
+        Tokenizer::get(model_options.tokenizer.as_deref()).change_context(Error::PreparePrompt)?;
+    let sent = tokenizer
+        .encode(prompt)
+        .change_context(Error::PreparePrompt)?
+        .len()
+        + model_options.context.reserve_output;
+
+    // The local tokenizer counted fewer tokens than the host did, so even the limit that the host
+    // reported isn't enough. Leave some extra room below the size that failed.
+    let limit = reported_limit.unwrap_or(usize::MAX).min(sent) * 9 / 10;
+    let model = model_options.full_model_spec().model_name().to_string();
+    model_options.learned_context_limits.insert(model, limit);
+
+    let rendered = crate::template::render_template(template_path, template, template_args, env)?;
+    let prompt = enforce_context_limit(
+        model_options,
+        template_path,
+        template,
+        env,
+        template_args.clone(),
+        rendered,
+    )?;
+    Ok((limit, prompt))
+}
+
 fn trim_context_from_args(
     tokenizer: &Tokenizer,
     context_limit: usize,
@@ -219,16 +407,16 @@ fn trim_context_from_args(
             break;
         }
 
-        if let Some(mut value) = template_args.remove(arg.as_str()) {
-            let trimmed_amount = trim_arg(
-                tokenizer,
-                to_trim as usize,
-                context_options,
-                None,
-                &mut value,
-            )?;
+        if context_options.pinned_args.contains(arg) {
+            continue;
+        }
+
+        let trimmed_amount = update_option_value(template_args, arg, |value| {
+            trim_arg(tokenizer, to_trim as usize, context_options, None, value)
+        })
+        .transpose()?;
+        if let Some(trimmed_amount) = trimmed_amount {
             to_trim -= trimmed_amount as isize;
-            template_args.insert(arg.to_string(), &value);
         }
     }
 
@@ -338,12 +526,18 @@ fn trim_arg(
 mod test {
     use super::*;
 
+    // The token counts in these tests come from the llama2 tokenizer.
+    #[cfg(feature = "tokenizers")]
     const SAMPLE_TEXT_1: &str = "This is a test texting and it is full of sample text";
+    #[cfg(feature = "tokenizers")]
     const SAMPLE_TEXT_2: &str = "Another test text too!";
+    #[cfg(feature = "tokenizers")]
     const SAMPLE_TEXT_3: &str = "Testing testers test";
     // Calculated from the three texts together
+    #[cfg(feature = "tokenizers")]
     const TOTAL_TOKENS: usize = 23;
 
+    #[cfg(feature = "tokenizers")]
     mod enforce_context_limit {
         use std::path::PathBuf;
 
@@ -388,6 +582,7 @@ mod test {
                 &options,
                 &PathBuf::from("test"),
                 TEST_TEMPLATE,
+                &RenderEnv::default(),
                 context,
                 initial_render.clone(),
             )
@@ -406,6 +601,7 @@ mod test {
                 &options,
                 &PathBuf::from("test"),
                 TEST_TEMPLATE,
+                &RenderEnv::default(),
                 context,
                 initial_render.clone(),
             )
@@ -431,6 +627,7 @@ mod test {
                 &options,
                 &PathBuf::from("test"),
                 TEST_TEMPLATE,
+                &RenderEnv::default(),
                 context,
                 initial_render.clone(),
             )
@@ -440,6 +637,207 @@ mod test {
         }
     }
 
+    #[cfg(feature = "tokenizers")]
+    mod shrink_after_context_error {
+        use serde_json::json;
+
+        use super::*;
+        use crate::tests::base_dir;
+
+        fn shrink(reported_limit: Option<usize>) -> (ModelOptions, usize, String) {
+            let mut options = ModelOptions {
+                model: "gpt-3.5-turbo".to_string().into(),
+                tokenizer: Some(
+                    base_dir("whitespace_tokenizer.json")
+                        .to_string_lossy()
+                        .to_string(),
+                ),
+                context: ContextOptions {
+                    reserve_output: 0,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+
+            let text = (1..=100)
+                .map(|i| format!("word{i}"))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let context = tera::Context::from_value(json!({ "text": text })).unwrap();
+
+            let (limit, prompt) = shrink_after_context_error(
+                &mut options,
+                reported_limit,
+                Path::new("test"),
+                "{{text}}",
+                &RenderEnv::default(),
+                &context,
+                &text,
+            )
+            .unwrap();
+            (options, limit, prompt)
+        }
+
+        #[test]
+        fn tokenizer_undercounts() {
+            let (options, limit, prompt) = shrink(Some(4096));
+            assert_eq!(limit, 90);
+            assert_eq!(
+                options.learned_context_limits.get("gpt-3.5-turbo"),
+                Some(&90)
+            );
+            assert_eq!(prompt.split_whitespace().count(), 90);
+            assert!(prompt.ends_with("word90"));
+        }
+
+        #[test]
+        fn reported_limit_is_smaller() {
+            let (_, limit, prompt) = shrink(Some(50));
+            assert_eq!(limit, 45);
+            assert_eq!(prompt.split_whitespace().count(), 45);
+        }
+    }
+
+    #[cfg(feature = "tokenizers")]
+    mod fit_session_history {
+        use super::*;
+        use crate::tests::base_dir;
+
+        fn fit(reserve_output: usize) -> Vec<String> {
+            let options = ModelOptions {
+                model: "gpt-3.5-turbo".to_string().into(),
+                tokenizer: Some(
+                    base_dir("whitespace_tokenizer.json")
+                        .to_string_lossy()
+                        .to_string(),
+                ),
+                context: ContextOptions {
+                    limit: Some(10),
+                    reserve_output,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+
+            let history = vec![
+                ChatMessage::user("one two"),
+                ChatMessage::assistant("three"),
+                ChatMessage::user("four five six"),
+                ChatMessage::assistant("seven"),
+            ];
+            fit_session_history(&options, "a b", "", history)
+                .unwrap()
+                .into_iter()
+                .map(|m| m.content)
+                .collect()
+        }
+
+        #[test]
+        fn fits() {
+            assert_eq!(fit(0), ["one two", "three", "four five six", "seven"]);
+        }
+
+        #[test]
+        fn leaves_room_for_output() {
+            assert_eq!(fit(4), ["four five six", "seven"]);
+        }
+    }
+
+    mod pinned {
+        use serde_json::json;
+
+        use super::*;
+        use crate::tests::base_dir;
+
+        fn enforce(
+            template: &str,
+            args: serde_json::Value,
+            configure: impl FnOnce(&mut ContextOptions),
+        ) -> Result<String, Report<Error>> {
+            let mut context = ContextOptions {
+                limit: Some(5),
+                reserve_output: 0,
+                ..Default::default()
+            };
+            configure(&mut context);
+            let options = ModelOptions {
+                model: "gpt-3.5-turbo".to_string().into(),
+                tokenizer: Some(
+                    base_dir("whitespace_tokenizer.json")
+                        .to_string_lossy()
+                        .to_string(),
+                ),
+                context,
+                ..Default::default()
+            };
+
+            let args = tera::Context::from_value(args).unwrap();
+            let env = RenderEnv::default();
+            let rendered =
+                crate::template::render_template(Path::new("test"), template, &args, &env).unwrap();
+            enforce_context_limit(&options, Path::new("test"), template, &env, args, rendered)
+        }
+
+        #[test]
+        fn keep_start() {
+            let output = enforce(
+                "{{ notes }}\n{{ question }}",
+                json!({ "notes": "a b c d e f", "question": "q1 q2" }),
+                |context| context.pinned_args = vec!["question".to_string()],
+            )
+            .unwrap();
+            assert_eq!(output, "a b c\nq1 q2");
+        }
+
+        #[test]
+        fn keep_end() {
+            let output = enforce(
+                "{% pin %}q1 q2{% endpin %}\n{{ notes }}",
+                json!({ "notes": "a b c d e f" }),
+                |context| context.keep = OverflowKeep::End,
+            )
+            .unwrap();
+            assert_eq!(output, "q1 q2\nd e f");
+        }
+
+        #[test]
+        fn skip_pinned_trim_args() {
+            let output = enforce(
+                "{{ a }} {{ b }}",
+                json!({ "a": "1 2 3 4", "b": "5 6 7 8" }),
+                |context| {
+                    context.limit = Some(6);
+                    context.trim_args = vec!["a".to_string(), "b".to_string()];
+                    context.pinned_args = vec!["a".to_string()];
+                },
+            )
+            .unwrap();
+            assert_eq!(output, "1 2 3 4 5 6");
+        }
+
+        #[test]
+        fn pins_over_limit() {
+            let err = enforce(
+                "{% pin %}Keep all of this{% endpin %} {{ notes }}",
+                json!({ "notes": "a b c" }),
+                |context| context.pinned_args = vec!["notes".to_string()],
+            )
+            .unwrap_err();
+
+            assert!(matches!(
+                err.current_context(),
+                Error::PinnedContextTooLong {
+                    pinned: 7,
+                    limit: 5
+                }
+            ));
+            let message = format!("{err:?}");
+            assert!(message.contains("pin block 1: 4 tokens"), "{message}");
+            assert!(message.contains("option `notes`: 3 tokens"), "{message}");
+        }
+    }
+
+    #[cfg(feature = "tokenizers")]
     mod truncate_at {
         use super::*;
 
@@ -468,6 +866,7 @@ mod test {
         }
     }
 
+    #[cfg(feature = "tokenizers")]
     mod trim_context_from_args {
         use serde_json::json;
 
@@ -511,6 +910,7 @@ mod test {
                     trim_args: vec!["test".to_string()],
                     array_priority: ArrayTrimPriority::First,
                     reserve_output: 0,
+                    pinned_args: vec![],
                 },
                 &mut args,
             )
@@ -552,6 +952,7 @@ mod test {
                     trim_args: vec!["test".to_string()],
                     array_priority: ArrayTrimPriority::First,
                     reserve_output: 0,
+                    pinned_args: vec![],
                 },
                 &mut args,
             )
@@ -593,6 +994,7 @@ mod test {
                     trim_args: vec!["test".to_string()],
                     array_priority: ArrayTrimPriority::First,
                     reserve_output: 0,
+                    pinned_args: vec![],
                 },
                 &mut args,
             )
@@ -635,6 +1037,7 @@ mod test {
                     trim_args: vec!["test".to_string()],
                     array_priority: ArrayTrimPriority::First,
                     reserve_output: 0,
+                    pinned_args: vec![],
                 },
                 &mut args,
             )
@@ -678,6 +1081,7 @@ mod test {
                     trim_args: vec!["test".to_string()],
                     array_priority: ArrayTrimPriority::Last,
                     reserve_output: 0,
+                    pinned_args: vec![],
                 },
                 &mut args,
             )
@@ -720,6 +1124,7 @@ mod test {
                     trim_args: vec!["test".to_string()],
                     array_priority: ArrayTrimPriority::Equal,
                     reserve_output: 0,
+                    pinned_args: vec![],
                 },
                 &mut args,
             )
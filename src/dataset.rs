@@ -0,0 +1,314 @@
+//! Build fine-tuning datasets from the run history.
+
+use std::{collections::HashSet, path::Path, str::FromStr};
+
+use clap::ValueEnum;
+use error_stack::{Report, ResultExt};
+use serde_json::json;
+
+use crate::{
+    error::Error,
+    history::{openai_example, sharegpt_conversation, HistoryEntry},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DatasetFormat {
+    /// OpenAI chat fine-tuning, with a list of messages per line
+    OpenaiJsonl,
+    /// ShareGPT conversations, as used by many Hugging Face datasets
+    Sharegpt,
+    /// Alpaca instruction, input, and output
+    Alpaca,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// A condition on one of the scores of a history entry, such as `judge>=4`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScoreFilter {
+    name: String,
+    comparison: Comparison,
+    value: f64,
+}
+
+impl FromStr for ScoreFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Check the two-character operators first so that `>=` isn't read as `>`.
+        let operators = [
+            (">=", Comparison::Ge),
+            ("<=", Comparison::Le),
+            ("!=", Comparison::Ne),
+            ("==", Comparison::Eq),
+            (">", Comparison::Gt),
+            ("<", Comparison::Lt),
+            ("=", Comparison::Eq),
+        ];
+
+        let (name, comparison, value) = operators
+            .iter()
+            .find_map(|(op, comparison)| {
+                s.split_once(op)
+                    .map(|(name, value)| (name, *comparison, value))
+            })
+            .ok_or_else(|| format!("Filter `{s}` should look like `name>=value`"))?;
+
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(format!("Filter `{s}` is missing a score name"));
+        }
+
+        let value = value
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| format!("Filter `{s}` should compare against a number"))?;
+
+        Ok(Self {
+            name: name.to_string(),
+            comparison,
+            value,
+        })
+    }
+}
+
+impl ScoreFilter {
+    /// Check if an entry passes the filter. Entries without the score never pass.
+    pub fn matches(&self, entry: &HistoryEntry) -> bool {
+        let Some(score) = entry.scores.get(&self.name).copied() else {
+            return false;
+        };
+
+        match self.comparison {
+            Comparison::Eq => score == self.value,
+            Comparison::Ne => score != self.value,
+            Comparison::Lt => score < self.value,
+            Comparison::Le => score <= self.value,
+            Comparison::Gt => score > self.value,
+            Comparison::Ge => score >= self.value,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DatasetOptions<'a> {
+    /// Only use runs of this template
+    pub template: Option<&'a str>,
+    /// Only use runs that pass all of these filters
+    pub filters: &'a [ScoreFilter],
+    /// Remove runs with the same system prompt and prompt, keeping the most recent one
+    pub dedup: bool,
+    /// The fraction of the examples to put in the validation set
+    pub val_split: f64,
+    /// The seed used to shuffle the examples before splitting them
+    pub seed: u64,
+}
+
+/// The examples in each part of the dataset.
+#[derive(Debug, Default, PartialEq)]
+pub struct Dataset {
+    pub train: Vec<HistoryEntry>,
+    pub val: Vec<HistoryEntry>,
+}
+
+/// Select the history entries for a dataset and split them into training and validation sets.
+pub fn build(entries: Vec<HistoryEntry>, options: &DatasetOptions) -> Dataset {
+    let mut entries = entries
+        .into_iter()
+        .filter(|e| options.template.map(|t| t == e.template).unwrap_or(true))
+        .filter(|e| options.filters.iter().all(|f| f.matches(e)))
+        .collect::<Vec<_>>();
+
+    if options.dedup {
+        let mut seen = HashSet::new();
+        // Go through newest first so that the most recent run of each prompt is kept.
+        entries.reverse();
+        entries.retain(|e| seen.insert((e.system.clone(), e.prompt.clone())));
+        entries.reverse();
+    }
+
+    if options.val_split <= 0.0 {
+        return Dataset {
+            train: entries,
+            val: Vec::new(),
+        };
+    }
+
+    fastrand::Rng::with_seed(options.seed).shuffle(&mut entries);
+    let val_count = ((entries.len() as f64) * options.val_split.min(1.0)).round() as usize;
+    let train = entries.split_off(val_count);
+    Dataset {
+        train,
+        val: entries,
+    }
+}
+
+fn alpaca_example(entry: &HistoryEntry) -> serde_json::Value {
+    json!({
+        "instruction": entry.system.as_deref().unwrap_or_default(),
+        "input": entry.prompt,
+        "output": entry.response,
+    })
+}
+
+/// Format the entries as JSON lines.
+pub fn format_entries(entries: &[HistoryEntry], format: DatasetFormat) -> String {
+    entries
+        .iter()
+        .map(|entry| {
+            let value = match format {
+                DatasetFormat::OpenaiJsonl => openai_example(entry),
+                DatasetFormat::Sharegpt => sharegpt_conversation(entry),
+                DatasetFormat::Alpaca => alpaca_example(entry),
+            };
+            format!("{value}\n")
+        })
+        .collect()
+}
+
+/// Write the entries to `path`, or to stdout if there is no path.
+pub fn write_entries(
+    entries: &[HistoryEntry],
+    format: DatasetFormat,
+    path: Option<&Path>,
+) -> Result<(), Report<Error>> {
+    let contents = format_entries(entries, format);
+    match path {
+        Some(path) => std::fs::write(path, contents)
+            .change_context(Error::Io)
+            .attach_printable_lazy(|| path.display().to_string()),
+        None => {
+            print!("{contents}");
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::tests::history_entry;
+
+    fn entry(id: u64, template: &str, prompt: &str, judge: Option<f64>) -> HistoryEntry {
+        HistoryEntry {
+            template: template.to_string(),
+            prompt: prompt.to_string(),
+            scores: judge
+                .map(|j| BTreeMap::from([("judge".to_string(), j)]))
+                .unwrap_or_default(),
+            ..history_entry(id)
+        }
+    }
+
+    fn ids(entries: &[HistoryEntry]) -> Vec<u64> {
+        entries.iter().map(|e| e.id).collect()
+    }
+
+    #[test]
+    fn parse_filter() {
+        let filter: ScoreFilter = "judge>=4".parse().unwrap();
+        assert_eq!(
+            filter,
+            ScoreFilter {
+                name: "judge".to_string(),
+                comparison: Comparison::Ge,
+                value: 4.0
+            }
+        );
+
+        let filter: ScoreFilter = " rating = 2.5 ".parse().unwrap();
+        assert_eq!(filter.name, "rating");
+        assert_eq!(filter.comparison, Comparison::Eq);
+        assert_eq!(filter.value, 2.5);
+
+        "judge>=good".parse::<ScoreFilter>().unwrap_err();
+        ">=4".parse::<ScoreFilter>().unwrap_err();
+        "judge".parse::<ScoreFilter>().unwrap_err();
+    }
+
+    #[test]
+    fn filter_entries() {
+        let entries = vec![
+            entry(1, "summarize", "a", Some(5.0)),
+            entry(2, "summarize", "b", Some(3.0)),
+            entry(3, "summarize", "c", None),
+            entry(4, "other", "d", Some(5.0)),
+        ];
+
+        let filters = ["judge>=4".parse().unwrap()];
+        let dataset = build(
+            entries,
+            &DatasetOptions {
+                template: Some("summarize"),
+                filters: &filters,
+                ..Default::default()
+            },
+        );
+        assert_eq!(ids(&dataset.train), vec![1]);
+        assert!(dataset.val.is_empty());
+    }
+
+    #[test]
+    fn dedup_keeps_latest() {
+        let entries = vec![
+            entry(1, "summarize", "a", None),
+            entry(2, "summarize", "b", None),
+            entry(3, "summarize", "a", None),
+        ];
+
+        let dataset = build(
+            entries,
+            &DatasetOptions {
+                dedup: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(ids(&dataset.train), vec![2, 3]);
+    }
+
+    #[test]
+    fn split() {
+        let entries = (1..=10)
+            .map(|id| entry(id, "summarize", &id.to_string(), None))
+            .collect::<Vec<_>>();
+        let options = DatasetOptions {
+            val_split: 0.2,
+            seed: 7,
+            ..Default::default()
+        };
+
+        let dataset = build(entries.clone(), &options);
+        assert_eq!(dataset.train.len(), 8);
+        assert_eq!(dataset.val.len(), 2);
+
+        let mut all = ids(&dataset.train);
+        all.extend(ids(&dataset.val));
+        all.sort();
+        assert_eq!(all, (1..=10).collect::<Vec<_>>());
+
+        // The same seed gives the same split
+        assert_eq!(build(entries, &options), dataset);
+    }
+
+    #[test]
+    fn alpaca() {
+        let mut entry = entry(1, "summarize", "Summarize this", None);
+        entry.system = Some("Be brief".to_string());
+        assert_eq!(
+            format_entries(&[entry], DatasetFormat::Alpaca),
+            r##"{"input":"Summarize this","instruction":"Be brief","output":"response 1"}"##
+                .to_string()
+                + "\n"
+        );
+    }
+}
@@ -0,0 +1,184 @@
+//! Render dates in a template's time zone and locale, instead of the machine's, so that a template
+//! writes the same dates wherever it runs.
+
+use std::collections::HashMap;
+
+use chrono::{Locale, Utc};
+use chrono_tz::Tz;
+use error_stack::Report;
+use tera::{Tera, Value};
+
+use crate::error::Error;
+
+/// The time zone and locale from the template's `timezone` and `locale` variables
+#[derive(Debug, Clone, Default)]
+pub struct DateSettings {
+    timezone: Option<Tz>,
+    locale: Option<String>,
+}
+
+impl DateSettings {
+    /// Check the settings, so that mistakes in a template are found before it is rendered.
+    pub fn new(timezone: Option<&str>, locale: Option<&str>) -> Result<Self, Report<Error>> {
+        let timezone = timezone
+            .map(|timezone| {
+                timezone.parse::<Tz>().map_err(|_| {
+                    Report::new(Error::ParseTemplate)
+                        .attach_printable(format!("Unknown time zone {timezone}"))
+                })
+            })
+            .transpose()?;
+
+        if let Some(locale) = locale {
+            if Locale::try_from(locale).is_err() {
+                return Err(Report::new(Error::ParseTemplate)
+                    .attach_printable(format!("Unknown locale {locale}")));
+            }
+        }
+
+        Ok(Self {
+            timezone,
+            locale: locale.map(String::from),
+        })
+    }
+
+    pub fn from_context(context: &tera::Context) -> Result<Self, Report<Error>> {
+        let setting = |name| context.get(name).and_then(Value::as_str);
+        Self::new(setting("timezone"), setting("locale"))
+    }
+
+    /// Today's date, as YYYY-MM-DD
+    pub fn today(&self) -> String {
+        match self.timezone {
+            Some(timezone) => Utc::now().with_timezone(&timezone).date_naive(),
+            None => chrono::Local::now().date_naive(),
+        }
+        .to_string()
+    }
+
+    /// Replace the `date` filter and the `now` function with ones that use these settings.
+    pub fn register(&self, tera: &mut Tera) {
+        if self.timezone.is_none() && self.locale.is_none() {
+            return;
+        }
+
+        tera.register_filter(
+            "date",
+            DateFilter {
+                builtins: Tera::default(),
+                settings: self.clone(),
+            },
+        );
+        if let Some(timezone) = self.timezone {
+            tera.register_function("now", NowFunction(timezone));
+        }
+    }
+}
+
+/// Tera's `date` filter, with the template's settings as the defaults for its `timezone` and
+/// `locale` arguments
+struct DateFilter {
+    builtins: Tera,
+    settings: DateSettings,
+}
+
+impl tera::Filter for DateFilter {
+    fn filter(&self, value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let mut args = args.clone();
+        if let Some(timezone) = self.settings.timezone {
+            args.entry("timezone".to_string())
+                .or_insert_with(|| timezone.name().into());
+        }
+        if let Some(locale) = self.settings.locale.as_deref() {
+            args.entry("locale".to_string())
+                .or_insert_with(|| locale.into());
+        }
+
+        self.builtins.get_filter("date")?.filter(value, &args)
+    }
+}
+
+/// Tera's `now` function, giving the local time in the template's time zone
+struct NowFunction(Tz);
+
+impl tera::Function for NowFunction {
+    fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let flag = |name: &str| -> tera::Result<bool> {
+            match args.get(name) {
+                Some(value) => value.as_bool().ok_or_else(|| {
+                    tera::Error::msg(format!(
+                        "Function `now` received {name}={value} but `{name}` can only be a boolean"
+                    ))
+                }),
+                None => Ok(false),
+            }
+        };
+
+        let now = Utc::now();
+        if flag("timestamp")? {
+            return Ok(now.timestamp().into());
+        }
+
+        let now = if flag("utc")? {
+            now.to_rfc3339()
+        } else {
+            now.with_timezone(&self.0).to_rfc3339()
+        };
+        Ok(now.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn render(template: &str, timezone: Option<&str>, locale: Option<&str>) -> String {
+        let mut context = tera::Context::new();
+        context.insert("when", "2024-03-05T23:30:00Z");
+        let mut tera = Tera::default();
+        DateSettings::new(timezone, locale)
+            .unwrap()
+            .register(&mut tera);
+        tera.render_str(template, &context).unwrap()
+    }
+
+    #[test]
+    fn date_filter_defaults() {
+        let template = r#"{{ when | date(format="%A %e %B %H:%M") }}"#;
+        assert_eq!(render(template, None, None), "Tuesday  5 March 23:30");
+        assert_eq!(
+            render(template, Some("Asia/Tokyo"), None),
+            "Wednesday  6 March 08:30"
+        );
+        assert_eq!(
+            render(template, Some("Europe/Berlin"), Some("de_DE")),
+            "Mittwoch  6 März 00:30"
+        );
+
+        // Arguments given to the filter take precedence.
+        assert_eq!(
+            render(
+                r#"{{ when | date(format="%H:%M", timezone="UTC") }}"#,
+                Some("Asia/Tokyo"),
+                None
+            ),
+            "23:30"
+        );
+    }
+
+    #[test]
+    fn now_in_timezone() {
+        let now = render("{{ now() }}", Some("Asia/Kolkata"), None);
+        assert!(now.ends_with("+05:30"), "{now}");
+        let utc = render("{{ now(utc=true) }}", Some("Asia/Kolkata"), None);
+        assert!(utc.ends_with("+00:00"), "{utc}");
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let err = DateSettings::new(Some("Mars/Olympus_Mons"), None).unwrap_err();
+        assert!(format!("{err:?}").contains("Unknown time zone Mars/Olympus_Mons"));
+        let err = DateSettings::new(None, Some("xx_XX")).unwrap_err();
+        assert!(format!("{err:?}").contains("Unknown locale xx_XX"));
+    }
+}
@@ -0,0 +1,381 @@
+//! Check the configuration, template directories, tokenizer, and hosts for problems that would
+//! otherwise only show up when running a template.
+
+use std::{collections::BTreeSet, path::PathBuf, time::Duration};
+
+use error_stack::Report;
+
+use crate::{
+    config::{Config, SearchOptions},
+    hosts::{HostConnection, HostDefinition},
+    list::find_template_files,
+    model::ModelError,
+    tokenizer::{Tokenizer, DEFAULT_TOKENIZER},
+    validate::report_message,
+};
+
+/// How long to wait for each host to respond, unless the configuration sets a timeout.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Asks a host for the models that it serves
+type ListModels<'a> = dyn Fn(&HostConnection) -> Result<Vec<String>, Report<ModelError>> + 'a;
+
+/// How the checks reach outside of PromptBox, so that tests can check hosts without the network
+/// or the real environment variables.
+struct CheckEnv<'a> {
+    /// Look up an environment variable.
+    get_env: &'a dyn Fn(&str) -> Option<String>,
+    /// Ask a host for the models that it serves.
+    list_models: &'a ListModels<'a>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    Warning,
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub status: Status,
+    pub message: String,
+}
+
+impl Check {
+    fn ok(message: impl Into<String>) -> Self {
+        Self {
+            status: Status::Ok,
+            message: message.into(),
+        }
+    }
+
+    fn warning(message: impl Into<String>) -> Self {
+        Self {
+            status: Status::Warning,
+            message: message.into(),
+        }
+    }
+
+    fn failed(message: impl Into<String>) -> Self {
+        Self {
+            status: Status::Failed,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let tag = match self.status {
+            Status::Ok => "ok",
+            Status::Warning => "warn",
+            Status::Failed => "FAIL",
+        };
+        write!(f, "{tag:>4}  {}", self.message)
+    }
+}
+
+/// Describe why a request to a host failed, without the full response body.
+fn describe_host_error(err: &Report<ModelError>) -> String {
+    match err.current_context() {
+        ModelError::Model(401 | 403, _) => "the API key was rejected".to_string(),
+        ModelError::Model(code, _) => format!("the host returned error {code}"),
+        ModelError::Raw => match err.downcast_ref::<ureq::Error>() {
+            Some(e) => format!("could not connect: {e}"),
+            None => "could not connect".to_string(),
+        },
+        other => other.to_string(),
+    }
+}
+
+/// The hosts that are worth checking: those used by the configured models, those defined in the
+/// configuration, and built-in hosts that have an API key set.
+fn hosts_to_check(config: &Config, get_env: impl Fn(&str) -> Option<String>) -> Vec<String> {
+    let builtin = HostDefinition::builtin();
+    let mut names = config
+        .model
+        .model_chain()
+        .iter()
+        .map(|options| options.host_name())
+        .collect::<BTreeSet<_>>();
+
+    for (name, host) in &config.model.host {
+        let has_key = host.key_names().iter().any(|var| get_env(var).is_some());
+        if !builtin.contains_key(name) || has_key {
+            names.insert(name.clone());
+        }
+    }
+
+    names.into_iter().collect()
+}
+
+fn check_host(config: &Config, name: &str, env: &CheckEnv, checks: &mut Vec<Check>) {
+    let Some(host) = config.model.host.get(name) else {
+        checks.push(Check::failed(format!("Host {name} is not defined")));
+        return;
+    };
+
+    let key_names = host.key_names();
+    let missing = key_names
+        .iter()
+        .filter(|var| (env.get_env)(var).is_none())
+        .collect::<Vec<_>>();
+    for var in &missing {
+        let message = format!("Host {name}: the API key variable {var} is not set");
+        if missing.len() < key_names.len() {
+            // The other keys will be used instead.
+            checks.push(Check::warning(message));
+        } else {
+            checks.push(Check::failed(message));
+        }
+    }
+
+    let timeout = Some(config.model.timeout.unwrap_or(CHECK_TIMEOUT));
+    let connections = match host.connections(name, &config.model.agents, timeout, env.get_env) {
+        Ok(connections) => connections,
+        Err(e) => {
+            checks.push(Check::failed(format!("Host {name}: {e}")));
+            return;
+        }
+    };
+
+    // Models from the configuration which should be served by this host
+    let models = config
+        .model
+        .model_chain()
+        .into_iter()
+        .filter(|options| options.host_name() == name)
+        .map(|options| options.full_model_spec().model_name().to_string())
+        .collect::<BTreeSet<_>>();
+
+    for connection in connections {
+        let label = match &connection.key_name {
+            Some(key_name) => format!(
+                "Host {name} at {} with the key from {key_name}",
+                connection.endpoint
+            ),
+            None => format!("Host {name} at {}", connection.endpoint),
+        };
+
+        match (env.list_models)(&connection) {
+            Ok(available) => {
+                checks.push(Check::ok(format!(
+                    "{label}: {} models available",
+                    available.len()
+                )));
+
+                // Some hosts serve more models than they list, so this is only a warning.
+                if !available.is_empty() {
+                    for model in models.iter().filter(|m| !available.contains(m)) {
+                        checks.push(Check::warning(format!(
+                            "{label}: the model {model} is not listed"
+                        )));
+                    }
+                }
+            }
+            Err(e) => checks.push(Check::failed(format!(
+                "{label}: {}",
+                describe_host_error(&e)
+            ))),
+        }
+    }
+}
+
+/// Run all the checks. If `hosts` is not empty, only those hosts are checked.
+pub fn run_checks(base_dir: PathBuf, search: &SearchOptions, hosts: &[String]) -> Vec<Check> {
+    let env = CheckEnv {
+        get_env: &|var| std::env::var(var).ok(),
+        list_models: &|connection| connection.host.list_models(),
+    };
+    run_checks_with(base_dir, search, hosts, &env)
+}
+
+fn run_checks_with(
+    base_dir: PathBuf,
+    search: &SearchOptions,
+    hosts: &[String],
+    env: &CheckEnv,
+) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let config = match Config::search(base_dir, search) {
+        Ok(config) => config,
+        Err(e) => {
+            checks.push(Check::failed(format!(
+                "Could not load the configuration: {}",
+                report_message(&e, None)
+            )));
+            return checks;
+        }
+    };
+    checks.push(Check::ok("Loaded the configuration"));
+
+    for field in &config.unknown_fields {
+        checks.push(Check::warning(field.to_string()));
+    }
+
+    if config.template_dirs.is_empty() {
+        checks.push(Check::warning("No template directories are configured"));
+    }
+    for dir in &config.template_dirs {
+        if dir.is_dir() {
+            let count = find_template_files(dir).len();
+            checks.push(Check::ok(format!(
+                "Template directory {}: {count} templates",
+                dir.display()
+            )));
+        } else {
+            checks.push(Check::failed(format!(
+                "Template directory {} does not exist",
+                dir.display()
+            )));
+        }
+    }
+
+    let tokenizer = config.model.tokenizer.as_deref();
+    let tokenizer_name = tokenizer.unwrap_or(DEFAULT_TOKENIZER);
+    match Tokenizer::get(tokenizer) {
+        Ok(_) => checks.push(Check::ok(format!("Loaded the tokenizer {tokenizer_name}"))),
+        Err(e) => checks.push(Check::failed(format!(
+            "Could not load the tokenizer {tokenizer_name}: {e}"
+        ))),
+    }
+
+    let hosts = if hosts.is_empty() {
+        hosts_to_check(&config, env.get_env)
+    } else {
+        hosts.to_vec()
+    };
+    for host in &hosts {
+        check_host(&config, host, env, &mut checks);
+    }
+
+    checks
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_config(dir: &std::path::Path, config: &str) {
+        std::fs::write(
+            dir.join("promptbox.toml"),
+            format!("top_level = true\nuse_global_config = false\n{config}"),
+        )
+        .unwrap();
+    }
+
+    fn find<'a>(checks: &'a [Check], pattern: &str) -> &'a Check {
+        checks
+            .iter()
+            .find(|c| c.message.contains(pattern))
+            .unwrap_or_else(|| panic!("No check matching {pattern}: {checks:#?}"))
+    }
+
+    /// Run the checks with only `PROMPTBOX_DOCTOR_TEST_KEY` set, where every host serves
+    /// `models`, or can't be reached if it is `None`.
+    fn run(dir: &std::path::Path, hosts: &[String], models: Option<&[&str]>) -> Vec<Check> {
+        let env = CheckEnv {
+            get_env: &|var| (var == "PROMPTBOX_DOCTOR_TEST_KEY").then(|| "key".to_string()),
+            list_models: &|_| match models {
+                Some(models) => Ok(models.iter().map(|m| m.to_string()).collect()),
+                None => Err(Report::new(ModelError::Raw)),
+            },
+        };
+        run_checks_with(dir.to_path_buf(), &SearchOptions::default(), hosts, &env)
+    }
+
+    // Tokenizers always load without the `tokenizers` feature.
+    #[cfg(feature = "tokenizers")]
+    #[test]
+    fn finds_problems() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("templates")).unwrap();
+        std::fs::write(dir.path().join("templates/a.pb.toml"), "template = 'a'").unwrap();
+        write_config(
+            dir.path(),
+            r##"
+            templates = ["templates", "missing"]
+
+            [model]
+            model = { model = "some-model", host = "unreachable" }
+            tokenizer = "missing-tokenizer.json"
+            temprature = 1
+
+            [host.unreachable]
+            endpoint = "http://127.0.0.1:1"
+            protocol = "openai"
+            api_key = "PROMPTBOX_DOCTOR_TEST_UNSET_KEY"
+            "##,
+        );
+
+        let checks = run(dir.path(), &[], None);
+
+        assert_eq!(find(&checks, "Loaded the configuration").status, Status::Ok);
+        assert_eq!(find(&checks, "temprature").status, Status::Warning);
+        let templates = find(&checks, "templates: 1 templates");
+        assert_eq!(templates.status, Status::Ok);
+        assert_eq!(
+            find(&checks, "missing does not exist").status,
+            Status::Failed
+        );
+        assert_eq!(
+            find(&checks, "Could not load the tokenizer").status,
+            Status::Failed
+        );
+        assert_eq!(
+            find(&checks, "PROMPTBOX_DOCTOR_TEST_UNSET_KEY is not set").status,
+            Status::Failed
+        );
+        let host = find(&checks, "Host unreachable at http://127.0.0.1:1:");
+        assert_eq!(host.status, Status::Failed);
+        assert!(host.message.contains("could not connect"), "{host:?}");
+    }
+
+    #[test]
+    fn working_host() {
+        let endpoint = "http://gpu1:8080/v1";
+        let dir = tempfile::tempdir().unwrap();
+        write_config(
+            dir.path(),
+            &format!(
+                r##"
+                [model]
+                model = [
+                    {{ model = "some-model", host = "fake" }},
+                    {{ model = "missing-model", host = "fake" }},
+                ]
+
+                [host.fake]
+                endpoint = "{endpoint}"
+                protocol = "openai"
+                api_keys = ["PROMPTBOX_DOCTOR_TEST_KEY", "PROMPTBOX_DOCTOR_TEST_UNSET_KEY"]
+                "##
+            ),
+        );
+
+        let checks = run(
+            dir.path(),
+            &["fake".to_string()],
+            Some(&["some-model", "other-model"]),
+        );
+
+        let host = find(
+            &checks,
+            &format!("Host fake at {endpoint} with the key from PROMPTBOX_DOCTOR_TEST_KEY:"),
+        );
+        assert_eq!(host.status, Status::Ok);
+        assert!(host.message.ends_with("2 models available"));
+        // One of the keys is enough.
+        assert_eq!(
+            find(&checks, "PROMPTBOX_DOCTOR_TEST_UNSET_KEY is not set").status,
+            Status::Warning
+        );
+        assert_eq!(
+            find(&checks, "missing-model is not listed").status,
+            Status::Warning
+        );
+        assert!(!checks.iter().any(|c| c.message.contains("some-model")));
+    }
+}
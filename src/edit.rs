@@ -0,0 +1,123 @@
+//! Open a template in the user's editor.
+
+use std::path::PathBuf;
+
+use error_stack::{Report, ResultExt};
+
+use crate::{config::Config, error::Error, template::ParsedTemplate};
+
+/// Get the editor command and its arguments, preferring `$VISUAL` over `$EDITOR`.
+fn editor_command(visual: Option<String>, editor: Option<String>) -> Vec<String> {
+    [visual, editor]
+        .into_iter()
+        .flatten()
+        .map(|command| {
+            command
+                .split_whitespace()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+        })
+        .find(|command| !command.is_empty())
+        .unwrap_or_else(|| vec!["vi".to_string()])
+}
+
+/// Find the files that make up a template: the template file itself, and the template and system
+/// prompt files if they are separate.
+pub fn template_files(config: &Config, name: &str) -> Result<Vec<PathBuf>, Report<Error>> {
+    let source = config
+        .find_template_file(name)
+        .ok_or(Error::TemplateNotFound)?;
+
+    // The template may be broken, and still needs to be editable, so ignore any errors reading it.
+    let template = ParsedTemplate::from_file(name, &source).ok().flatten();
+
+    let mut files = vec![source.clone()];
+    if let Some(template) = template {
+        let other_files = std::iter::once(template.path)
+            .chain(template.system.map(|(path, _)| path))
+            .filter(|path| *path != source);
+        files.extend(other_files);
+    }
+
+    Ok(files)
+}
+
+/// Open the files for a template in the editor and wait for it to exit.
+pub fn edit_template(config: &Config, name: &str) -> Result<(), Report<Error>> {
+    let files = template_files(config, name)?;
+    let command = editor_command(std::env::var("VISUAL").ok(), std::env::var("EDITOR").ok());
+
+    let status = std::process::Command::new(&command[0])
+        .args(&command[1..])
+        .args(&files)
+        .status()
+        .change_context(Error::Io)
+        .attach_printable_lazy(|| format!("Running editor {}", command.join(" ")))?;
+
+    if !status.success() {
+        return Err(Report::new(Error::Io))
+            .attach_printable(format!("Editor {} exited with {status}", command.join(" ")));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::base_dir;
+
+    #[test]
+    fn choose_editor() {
+        assert_eq!(
+            editor_command(Some("code -w".to_string()), Some("vim".to_string())),
+            vec!["code", "-w"]
+        );
+        assert_eq!(
+            editor_command(Some(" ".to_string()), Some("vim".to_string())),
+            vec!["vim"]
+        );
+        assert_eq!(editor_command(None, None), vec!["vi"]);
+    }
+
+    #[test]
+    fn files() {
+        let config = Config::from_directory(base_dir("")).unwrap();
+
+        assert_eq!(
+            template_files(&config, "normal").unwrap(),
+            vec![base_dir("normal.pb.toml")]
+        );
+
+        assert_eq!(
+            template_files(&config, "template_path").unwrap(),
+            vec![
+                base_dir("template_path.pb.toml"),
+                base_dir("template_path.liquid")
+            ]
+        );
+
+        assert_eq!(
+            template_files(&config, "system_prompt_in_file").unwrap(),
+            vec![
+                base_dir("system_prompt_in_file.pb.toml"),
+                base_dir("system_prompt.liquid")
+            ]
+        );
+
+        let err = template_files(&config, "does_not_exist").unwrap_err();
+        assert!(matches!(err.current_context(), Error::TemplateNotFound));
+    }
+
+    #[test]
+    fn malformed_template() {
+        let config = Config::from_directory(base_dir("malformed_template")).unwrap();
+        assert!(config.find_template("malformed_template").is_err());
+
+        let files = template_files(&config, "malformed_template").unwrap();
+        assert_eq!(
+            files,
+            vec![base_dir("malformed_template/malformed_template.pb.toml")]
+        );
+    }
+}
@@ -1,19 +1,27 @@
 use thiserror::Error;
 
+use crate::moderation::ModerationStage;
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Error reading configuration file")]
     ParseConfig,
     #[error("Missing field {0}")]
     MissingField(&'static str),
+    #[error("Found {0} problems in the providers configuration")]
+    InvalidProviders(usize),
     #[error("Unknown model host {0}")]
     UnknownModelHost(String),
     #[error("Error reading template")]
     ParseTemplate,
     #[error("Template not found")]
     TemplateNotFound,
+    #[error("No template was given")]
+    MissingTemplateName,
     #[error("Template contents not found")]
     TemplateContentsNotFound,
+    #[error("Partial {0} not found")]
+    PartialNotFound(String),
     #[error("This template is missing template and template_path")]
     EmptyTemplate,
     #[error("Failed to parse arguments")]
@@ -24,14 +32,89 @@ pub enum Error {
     RunPrompt,
     #[error("Failed to calculate context limit")]
     ContextLimit,
+    #[error("Pinned content is {pinned} tokens, which is more than the context limit of {limit}")]
+    PinnedContextTooLong { pinned: usize, limit: usize },
     #[error("Failed reading input")]
     Io,
+    #[error("Failed to write the response")]
+    WriteOutput,
     #[error("Failed to read image")]
     Image,
     #[error("Failed to access local cache")]
     Cache,
+    #[error("No cached response matches {0}")]
+    CacheEntryNotFound(String),
+    #[error("Failed to import templates")]
+    Import,
+    #[error("Failed to copy the response to the clipboard")]
+    Clipboard,
     #[error(transparent)]
     CmdlineParseFailure(#[from] clap::Error),
+    #[error("Failed to configure HTTP client: {0}")]
+    HttpClient(String),
     #[error("Failed to encode tokens: {0}")]
+    #[cfg_attr(not(feature = "tokenizers"), allow(dead_code))]
     Tokenizer(String),
+    #[error("Unknown fields in configuration")]
+    UnknownFields,
+    #[error("Interrupted")]
+    Interrupted,
+    #[error("Failed to access the usage ledger")]
+    Ledger,
+    #[error("Failed to access history")]
+    History,
+    #[error("History entry {0} not found")]
+    HistoryEntryNotFound(u64),
+    #[error("There are no runs in the history to run again")]
+    NoRunToRepeat,
+    #[error("There are no runs of {0} in the history to continue")]
+    NoRunToContinue(String),
+    #[error("No model meets the template's requirements")]
+    MissingCapabilities,
+    #[error("Stopped judging before every entry was judged")]
+    JudgeStopped,
+    #[error("Failed to write the log file")]
+    LogFile,
+    #[error("Failed to read chat input")]
+    Chat,
+    #[error("Failed to access session")]
+    Session,
+    #[error("Session {0} not found")]
+    SessionNotFound(String),
+    #[error("Run cancelled")]
+    RunCancelled,
+    #[error("The run would go over the budget")]
+    OverBudget,
+    #[error("The template's tools can't run without permission to run shell commands")]
+    ToolsNotAllowed,
+    #[error("Found {0} problems in templates")]
+    ValidationFailed(usize),
+    #[error("{0} checks failed")]
+    DoctorFailed(usize),
+    #[error("{0} templates failed")]
+    MultiRunFailed(usize),
+    #[error("Judge response did not contain a score")]
+    JudgeScoreMissing,
+    #[error("Judge response did not choose one of the responses")]
+    JudgeChoiceMissing,
+    #[error("The response failed a check")]
+    ResponseCheckFailed,
+    #[error("Invalid response schema")]
+    InvalidResponseSchema,
+    #[error("Failed to post-process the response")]
+    PostProcess,
+    #[error("Failed to check content with the moderation classifier")]
+    Moderation,
+    #[error("The {0} was blocked by moderation")]
+    ContentBlocked(ModerationStage),
+    #[error("Invalid stream filter")]
+    StreamFilter,
+    #[error("Failed to fetch {0}")]
+    FetchUrl(String),
+    #[error("Failed to read PDF")]
+    Pdf,
+    #[error("Failed to run git")]
+    Git,
+    #[error("{0} already exists and was not installed by promptbox. Use --force to replace it")]
+    HookExists(String),
 }
@@ -0,0 +1,149 @@
+//! Report each stage of a run as it happens, so that programs wrapping PromptBox can show its
+//! progress instead of only reading the response.
+
+use std::{
+    fs::File,
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use error_stack::{Report, ResultExt};
+use serde::Serialize;
+
+use crate::{error::Error, usage::Usage};
+
+/// Something that happened during a run
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RunEvent<'a> {
+    /// The template was rendered and the prompt is ready to send
+    PromptRendered {
+        template: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        system: Option<&'a str>,
+        prompt: &'a str,
+    },
+    /// A request was sent to a model. This is sent again for each fallback model that is tried.
+    RequestStarted { model: &'a str, host: &'a str },
+    /// Part of the response was written
+    Chunk { text: &'a str },
+    /// The model called a tool, with these arguments as a string of JSON
+    ToolCall { name: &'a str, arguments: &'a str },
+    /// The tokens that the run used and their cost
+    Usage(Usage),
+    /// The run finished and the response was written
+    Completed {
+        response: &'a str,
+        duration_ms: u64,
+        cached: bool,
+    },
+    /// The run failed
+    Error { message: String },
+}
+
+type EventCallback = Arc<dyn Fn(&RunEvent) + Send + Sync>;
+
+/// The callbacks to call with each event of a run
+#[derive(Clone, Default)]
+pub struct RunEvents {
+    callbacks: Vec<EventCallback>,
+}
+
+static NO_EVENTS: RunEvents = RunEvents {
+    callbacks: Vec::new(),
+};
+
+impl std::fmt::Debug for RunEvents {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RunEvents")
+            .field("callbacks", &self.callbacks.len())
+            .finish()
+    }
+}
+
+impl RunEvents {
+    /// Events for requests that nothing is listening to, such as judging a dataset
+    pub fn none() -> &'static Self {
+        &NO_EVENTS
+    }
+
+    /// Write each event to the file at `path` as a line of JSON, or to stderr if the path is `-`.
+    pub fn json_lines(path: &Path) -> Result<Self, Report<Error>> {
+        let writer: Box<dyn Write + Send> = if path == Path::new("-") {
+            Box::new(std::io::stderr())
+        } else {
+            let file = File::create(path)
+                .change_context(Error::Io)
+                .attach_printable_lazy(|| format!("Creating events file {}", path.display()))?;
+            Box::new(file)
+        };
+
+        let writer = Mutex::new(writer);
+        let mut events = Self::default();
+        events.on(move |event| {
+            let Ok(line) = serde_json::to_string(event) else {
+                return;
+            };
+            let mut writer = writer.lock().unwrap();
+            // Events are only a report of the run, so failing to write one isn't worth stopping it.
+            writeln!(writer, "{line}").and_then(|_| writer.flush()).ok();
+        });
+        Ok(events)
+    }
+
+    /// Call `callback` with each event.
+    pub fn on(&mut self, callback: impl Fn(&RunEvent) + Send + Sync + 'static) {
+        self.callbacks.push(Arc::new(callback));
+    }
+
+    pub fn emit(&self, event: RunEvent) {
+        for callback in &self.callbacks {
+            callback(&event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::history::TokenCounts;
+
+    #[test]
+    fn emit_to_callbacks() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut events = RunEvents::default();
+        let seen_by_callback = seen.clone();
+        events.on(move |event| {
+            seen_by_callback
+                .lock()
+                .unwrap()
+                .push(serde_json::to_string(event).unwrap());
+        });
+
+        events.emit(RunEvent::Chunk { text: "Hello" });
+        events.emit(RunEvent::Usage(Usage {
+            tokens: TokenCounts {
+                prompt: 10,
+                response: 2,
+            },
+            estimated: false,
+            cost: None,
+        }));
+        events.emit(RunEvent::Error {
+            message: "It failed".to_string(),
+        });
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                r#"{"event":"chunk","text":"Hello"}"#,
+                r#"{"event":"usage","tokens":{"prompt":10,"response":2}}"#,
+                r#"{"event":"error","message":"It failed"}"#,
+            ]
+        );
+
+        // Emitting without any callbacks does nothing.
+        RunEvents::none().emit(RunEvent::Chunk { text: "Hello" });
+    }
+}
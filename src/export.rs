@@ -0,0 +1,277 @@
+//! Export a template in the formats used to set up hosted assistants, so that the template library
+//! can be the source of truth for bots built on them.
+
+use std::fmt::Write;
+
+use clap::ValueEnum;
+use error_stack::Report;
+use serde::Serialize;
+
+use crate::{config::Config, error::Error, template::ParsedTemplate};
+
+/// The most conversation starters that a GPT can have
+const MAX_CONVERSATION_STARTERS: usize = 4;
+/// The longest instructions that a GPT accepts
+const MAX_GPT_INSTRUCTIONS: usize = 8000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AssistantFormat {
+    /// The fields of an OpenAI GPT configuration, with the options as conversation starters
+    GptConfig,
+    /// Claude Project instructions, with the options as variables
+    ClaudeProject,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ModelSettings {
+    pub model: String,
+    pub temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Variable {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub variable_type: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    pub required: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct GptConfig {
+    pub name: String,
+    pub description: String,
+    pub instructions: String,
+    pub conversation_starters: Vec<String>,
+    pub model_settings: ModelSettings,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ClaudeProject {
+    pub name: String,
+    pub description: String,
+    pub instructions: String,
+    pub variables: Vec<Variable>,
+    pub model_settings: ModelSettings,
+}
+
+fn variables(template: &ParsedTemplate) -> Vec<Variable> {
+    let mut options = template.input.options.iter().collect::<Vec<_>>();
+    options.sort_by(|a, b| a.0.cmp(b.0));
+    options
+        .into_iter()
+        .map(|(name, option)| {
+            let mut variable_type = option.option_type.to_string();
+            if option.array {
+                variable_type.push_str("[]");
+            }
+
+            Variable {
+                name: name.clone(),
+                variable_type,
+                description: option.description.clone(),
+                required: option.required(),
+                default: option.default.clone(),
+            }
+        })
+        .collect()
+}
+
+/// Combine the system prompt and the template into instructions for an assistant, which fills in
+/// the template's options from the conversation.
+fn instructions(template: &ParsedTemplate, variables: &[Variable]) -> String {
+    let mut output = String::new();
+    if let Some((_, system)) = template.system.as_ref() {
+        writeln!(output, "{}\n", system.trim()).ok();
+    }
+
+    writeln!(
+        output,
+        "Respond to each request by following the prompt below. Values in {{{{ }}}} come from \
+        the conversation. Ask for any required value that the user hasn't given."
+    )
+    .ok();
+    let uses_extra = regex::Regex::new(r"\{\{-?\s*extra\b").unwrap();
+    if uses_extra.is_match(&template.template) {
+        writeln!(output, "`extra` is the text of the user's message.").ok();
+    }
+    writeln!(
+        output,
+        "\n<prompt>\n{}\n</prompt>",
+        template.template.trim()
+    )
+    .ok();
+
+    if !variables.is_empty() {
+        writeln!(output, "\nValues:").ok();
+        for variable in variables {
+            let mut details = vec![variable.variable_type.clone()];
+            if variable.required {
+                details.push("required".to_string());
+            }
+            if let Some(default) = variable.default.as_ref() {
+                details.push(format!("default: {default}"));
+            }
+
+            write!(output, "- {} ({})", variable.name, details.join(", ")).ok();
+            if !variable.description.is_empty() {
+                write!(output, ": {}", variable.description).ok();
+            }
+            writeln!(output).ok();
+        }
+    }
+
+    output.trim_end().to_string()
+}
+
+/// GPTs start conversations with a few suggested messages, which come from the template's examples
+/// or, failing that, from its options.
+fn conversation_starters(template: &ParsedTemplate, variables: &[Variable]) -> Vec<String> {
+    let starters = template
+        .input
+        .examples
+        .iter()
+        .filter(|example| !example.description.is_empty())
+        .map(|example| example.description.clone())
+        .collect::<Vec<_>>();
+
+    let starters = if starters.is_empty() {
+        let described = variables
+            .iter()
+            .filter(|v| !v.description.is_empty())
+            .map(|v| v.description.to_lowercase())
+            .collect::<Vec<_>>();
+        match (template.input.description.is_empty(), described.is_empty()) {
+            (true, _) => Vec::new(),
+            (false, true) => vec![template.input.description.clone()],
+            (false, false) => vec![format!(
+                "{} ({})",
+                template.input.description,
+                described.join(", ")
+            )],
+        }
+    } else {
+        starters
+    };
+
+    starters
+        .into_iter()
+        .take(MAX_CONVERSATION_STARTERS)
+        .collect()
+}
+
+fn model_settings(config: &Config, template: &ParsedTemplate) -> ModelSettings {
+    let mut options = config.model.clone();
+    options.update_from_model_input(&template.input.model);
+    ModelSettings {
+        model: options.full_model_spec().model_name().to_string(),
+        temperature: options.temperature,
+        top_p: options.top_p,
+        max_tokens: options.max_tokens,
+    }
+}
+
+/// Export the template that `promptbox run` would use for `name` as pretty-printed JSON.
+pub fn export_template(
+    config: &Config,
+    name: &str,
+    format: AssistantFormat,
+) -> Result<String, Report<Error>> {
+    let template = config.find_template(name)?;
+    let variables = variables(&template);
+    let instructions = instructions(&template, &variables);
+    let model_settings = model_settings(config, &template);
+
+    let json = match format {
+        AssistantFormat::GptConfig => {
+            if instructions.chars().count() > MAX_GPT_INSTRUCTIONS {
+                eprintln!(
+                    "Warning: The instructions are longer than the {MAX_GPT_INSTRUCTIONS} \
+                    characters that a GPT accepts"
+                );
+            }
+
+            serde_json::to_string_pretty(&GptConfig {
+                name: template.name.clone(),
+                description: template.input.description.clone(),
+                conversation_starters: conversation_starters(&template, &variables),
+                instructions,
+                model_settings,
+            })
+        }
+        AssistantFormat::ClaudeProject => serde_json::to_string_pretty(&ClaudeProject {
+            name: template.name.clone(),
+            description: template.input.description.clone(),
+            instructions,
+            variables,
+            model_settings,
+        }),
+    };
+
+    Ok(json.expect("export types always serialize"))
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+    use crate::tests::base_dir;
+
+    fn export(name: &str, format: AssistantFormat) -> serde_json::Value {
+        let config = Config::from_directory(base_dir("")).unwrap();
+        serde_json::from_str(&export_template(&config, name, format).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn gpt_config() {
+        let exported = export("help_text", AssistantFormat::GptConfig);
+        assert_eq!(exported["name"], "help_text");
+        assert_eq!(exported["description"], "Summarize some text");
+        assert_eq!(
+            exported["conversation_starters"],
+            json!(["An excited summary"])
+        );
+        assert_eq!(
+            exported["model_settings"],
+            json!({ "model": "gpt-3.5-turbo", "temperature": 0.4, "top_p": 2.0 })
+        );
+
+        let instructions = exported["instructions"].as_str().unwrap();
+        assert!(instructions.contains("<prompt>\nSummarize this in a {{style}} style\n</prompt>"));
+        assert!(instructions.ends_with(
+            "Values:\n- style (string, default: \"concise\"): The style of the summary"
+        ));
+    }
+
+    #[test]
+    fn claude_project() {
+        let exported = export("system_prompt", AssistantFormat::ClaudeProject);
+        assert_eq!(
+            exported["variables"],
+            json!([{ "name": "type", "type": "string", "required": true }])
+        );
+
+        let instructions = exported["instructions"].as_str().unwrap();
+        assert!(
+            instructions.starts_with("a system prompt for {{type}}\n\nRespond to each request"),
+            "{instructions}"
+        );
+        assert!(!instructions.contains("`extra`"));
+    }
+
+    #[test]
+    fn missing_template() {
+        let config = Config::from_directory(base_dir("")).unwrap();
+        let err =
+            export_template(&config, "does_not_exist", AssistantFormat::GptConfig).unwrap_err();
+        assert!(matches!(err.current_context(), Error::TemplateNotFound));
+    }
+}
@@ -0,0 +1,382 @@
+//! Read the files given to file options. Directories and glob patterns expand into the files they
+//! contain, skipping files that git ignores, and the files are kept within the option's limits.
+
+use std::{
+    io::Read,
+    path::{Component, Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use error_stack::{Report, ResultExt};
+use ignore::{overrides::OverrideBuilder, WalkBuilder};
+
+use crate::{
+    error::Error,
+    option_groups::option_value_mut,
+    pdf,
+    template::{BinaryFiles, PromptOption},
+    tokenizer::Tokenizer,
+};
+
+/// How much of a file to check for NUL bytes when deciding if it is binary, the same as git.
+const BINARY_CHECK_LEN: usize = 8000;
+
+/// Files larger than this are skipped unless the option sets `max_file_size`.
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 1_000_000;
+
+fn is_glob(text: &str) -> bool {
+    text.contains(['*', '?', '[', '{'])
+}
+
+/// The files in a directory, or that match a glob pattern, relative to `base_dir` when they are
+/// inside it. Binary files are left out unless the option includes them. Returns `None` when
+/// `value` is a single file.
+pub fn expand(
+    base_dir: &Path,
+    value: &Path,
+    option: &PromptOption,
+) -> Result<Option<Vec<PathBuf>>, Report<Error>> {
+    let full_path = base_dir.join(value);
+    let (root, pattern) = if full_path.is_dir() {
+        (full_path, None)
+    } else if !full_path.exists() && is_glob(&value.to_string_lossy()) {
+        // Walk from the part of the pattern before the first wildcard.
+        let mut root = base_dir.to_path_buf();
+        let mut pattern = Vec::new();
+        for component in value.components() {
+            let text = component.as_os_str().to_string_lossy();
+            if pattern.is_empty() && !is_glob(&text) {
+                root.push(component);
+            } else if matches!(component, Component::Normal(_)) {
+                pattern.push(text.to_string());
+            }
+        }
+        (root, Some(pattern.join("/")))
+    } else {
+        return Ok(None);
+    };
+
+    let mut walker = WalkBuilder::new(&root);
+    if let Some(pattern) = pattern.as_deref() {
+        // The leading slash anchors the pattern to the root, as in a .gitignore file.
+        let overrides = OverrideBuilder::new(&root)
+            .add(&format!("/{pattern}"))
+            .and_then(|builder| builder.build())
+            .change_context(Error::ArgParseFailure)
+            .attach_printable_lazy(|| format!("Invalid glob pattern {}", value.display()))?;
+        walker.overrides(overrides);
+    }
+
+    let mut files = Vec::new();
+    for entry in walker.build() {
+        let entry = entry
+            .change_context(Error::Io)
+            .attach_printable_lazy(|| format!("Listing files in {}", value.display()))?;
+        if !entry.file_type().is_some_and(|t| t.is_file())
+            || (option.binary == BinaryFiles::Skip && is_binary(entry.path()))
+        {
+            continue;
+        }
+
+        let path = entry.path();
+        files.push(path.strip_prefix(base_dir).unwrap_or(path).to_path_buf());
+    }
+
+    if files.is_empty() && pattern.is_some() {
+        return Err(Report::new(Error::ArgParseFailure))
+            .attach_printable(format!("No files match {}", value.display()));
+    }
+
+    files.sort();
+    Ok(Some(files))
+}
+
+/// The first `len` bytes of the file, or fewer if it is shorter.
+fn read_start(path: &Path, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut start = Vec::with_capacity(len);
+    std::fs::File::open(path)?
+        .take(len as u64)
+        .read_to_end(&mut start)?;
+    Ok(start)
+}
+
+/// Returns true for binary files other than PDFs, which can't be used as text in the prompt.
+fn is_binary(path: &Path) -> bool {
+    read_start(path, BINARY_CHECK_LEN).is_ok_and(|start| !pdf::is_pdf(&start) && start.contains(&0))
+}
+
+/// Read a file for the file option `name`. A PDF file is replaced by its text, and its page count
+/// is added as `pages`.
+///
+/// A file that is too large, or binary when the option doesn't include binary files, is an error
+/// when it was named on the command line. When it was `expanded` from a directory or glob
+/// pattern, it is skipped with a warning and this returns `None`.
+pub fn read_file(
+    base_dir: &Path,
+    path: &Path,
+    name: &str,
+    option: &PromptOption,
+    expanded: bool,
+) -> Result<Option<serde_json::Value>, Report<Error>> {
+    let full_path = base_dir
+        .join(path)
+        .canonicalize()
+        .change_context(Error::Io)
+        .attach_printable_lazy(|| format!("Could not read file: {}", path.display()))?;
+    let max_size = option.max_file_size.unwrap_or(DEFAULT_MAX_FILE_SIZE);
+    let too_large = |size: u64| {
+        if !expanded {
+            return Err(Report::new(Error::ArgParseFailure).attach_printable(format!(
+                "--{name}: {} is {size} bytes, over the limit of {max_size}. Set `max_file_size` on the option to allow it.",
+                path.display()
+            )));
+        }
+
+        eprintln!(
+            "Warning: Skipping {}, which is {size} bytes, over the limit of {max_size}",
+            path.display()
+        );
+        Ok(None)
+    };
+
+    // A PDF is limited by the size of its text instead, so check before reading the whole file.
+    let size = std::fs::metadata(&full_path)
+        .change_context(Error::Io)
+        .attach_printable_lazy(|| format!("Could not read file: {}", path.display()))?
+        .len();
+    if size > max_size && !read_start(&full_path, 5).is_ok_and(|start| pdf::is_pdf(&start)) {
+        return too_large(size);
+    }
+
+    let contents = std::fs::read(&full_path)
+        .change_context(Error::Io)
+        .attach_printable_lazy(|| format!("Could not read file: {}", path.display()))?;
+
+    let mut obj = serde_json::json!({
+        "filename": path.file_name().map(|s| s.to_string_lossy()).unwrap_or_default(),
+        "path": path.to_string_lossy(),
+    });
+
+    if pdf::is_pdf(&contents) {
+        let pages = pdf::extract_pages(&contents)
+            .attach_printable_lazy(|| format!("Could not read PDF: {}", path.display()))?;
+        let text = pages.join("\n\n");
+        if text.len() as u64 > max_size {
+            return too_large(text.len() as u64);
+        }
+        obj["pages"] = serde_json::Value::from(pages.len());
+        obj["contents"] = serde_json::Value::from(text);
+    } else {
+        match String::from_utf8(contents) {
+            Ok(text) => obj["contents"] = serde_json::Value::from(text),
+            Err(e) => match option.binary {
+                BinaryFiles::Skip if !expanded => {
+                    return Err(Report::new(Error::ArgParseFailure).attach_printable(format!(
+                        "--{name}: {} is not a text file. Set `binary = \"base64\"` on the option to include it.",
+                        path.display()
+                    )));
+                }
+                BinaryFiles::Skip => {
+                    eprintln!("Warning: Skipping {}, which is not text", path.display());
+                    return Ok(None);
+                }
+                BinaryFiles::Base64 => {
+                    obj["contents"] = serde_json::Value::from(STANDARD.encode(e.into_bytes()));
+                    obj["encoding"] = serde_json::Value::from("base64");
+                }
+            },
+        }
+    }
+
+    Ok(Some(obj))
+}
+
+/// Keep the first `max_files` files for an option.
+pub fn limit_files<T>(name: &str, option: &PromptOption, files: &mut Vec<T>) {
+    let Some(max_files) = option.max_files else {
+        return;
+    };
+
+    if files.len() > max_files {
+        eprintln!(
+            "Warning: --{name} has {} files, so only the first {max_files} will be used",
+            files.len()
+        );
+        files.truncate(max_files);
+    }
+}
+
+/// Drop the files of each file option after the ones that fit in its `max_tokens`.
+pub fn limit_tokens<'a>(
+    options: impl IntoIterator<Item = (&'a String, &'a PromptOption)>,
+    context: &mut serde_json::Value,
+    tokenizer: Option<&str>,
+) -> Result<(), Report<Error>> {
+    for (name, option) in options {
+        let Some(max_tokens) = option.max_tokens else {
+            continue;
+        };
+
+        let Some(serde_json::Value::Array(files)) = option_value_mut(context, name) else {
+            continue;
+        };
+
+        let tokenizer = Tokenizer::get(tokenizer).map_err(Report::new)?;
+        let mut total = 0;
+        let mut keep = 0;
+        for file in files.iter() {
+            let contents = file
+                .get("contents")
+                .and_then(|c| c.as_str())
+                .unwrap_or_default();
+            total += tokenizer.encode(contents).map_err(Report::new)?.len();
+            if total > max_tokens {
+                break;
+            }
+            keep += 1;
+        }
+
+        if keep < files.len() {
+            eprintln!(
+                "Warning: --{name} is over its limit of {max_tokens} tokens, so only the first {keep} of {} files will be used",
+                files.len()
+            );
+            files.truncate(keep);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn file_option(json: &str) -> PromptOption {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn relative(files: Option<Vec<PathBuf>>) -> Vec<String> {
+        files
+            .unwrap()
+            .iter()
+            .map(|path| path.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn expand_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        std::fs::create_dir_all(base.join(".git")).unwrap();
+        std::fs::create_dir_all(base.join("src/nested")).unwrap();
+        std::fs::create_dir_all(base.join("target")).unwrap();
+        std::fs::write(base.join(".gitignore"), "target/\n").unwrap();
+        std::fs::write(base.join("src/main.rs"), "fn main() {}").unwrap();
+        std::fs::write(base.join("src/lib.md"), "# Lib").unwrap();
+        std::fs::write(base.join("src/nested/deep.rs"), "mod deep;").unwrap();
+        std::fs::write(base.join("src/data.bin"), [1, 0, 2]).unwrap();
+        std::fs::write(base.join("target/out.rs"), "ignored").unwrap();
+
+        let option = file_option(r#"{ "type": "file" }"#);
+        assert_eq!(
+            expand(base, Path::new("src/main.rs"), &option).unwrap(),
+            None
+        );
+        assert_eq!(
+            relative(expand(base, Path::new("src"), &option).unwrap()),
+            ["src/lib.md", "src/main.rs", "src/nested/deep.rs"]
+        );
+        assert_eq!(
+            relative(expand(base, Path::new("src/*.rs"), &option).unwrap()),
+            ["src/main.rs"]
+        );
+        assert_eq!(
+            relative(expand(base, Path::new("**/*.rs"), &option).unwrap()),
+            ["src/main.rs", "src/nested/deep.rs"]
+        );
+
+        let with_binary = file_option(r#"{ "type": "file", "binary": "base64" }"#);
+        assert_eq!(
+            relative(expand(base, Path::new("src/*.*"), &with_binary).unwrap()),
+            ["src/data.bin", "src/lib.md", "src/main.rs"]
+        );
+
+        let err = expand(base, Path::new("src/*.py"), &option).unwrap_err();
+        assert!(format!("{err:?}").contains("No files match src/*.py"));
+    }
+
+    #[test]
+    fn limits() {
+        let option = PromptOption {
+            max_files: Some(2),
+            max_tokens: Some(5),
+            ..file_option(r#"{ "type": "file", "array": true }"#)
+        };
+
+        let mut files = vec![PathBuf::from("a"), PathBuf::from("b"), PathBuf::from("c")];
+        limit_files("src", &option, &mut files);
+        assert_eq!(files, [PathBuf::from("a"), PathBuf::from("b")]);
+
+        let tokenizer = crate::tests::base_dir("whitespace_tokenizer.json");
+        let options = [("src".to_string(), option)];
+        let mut context = serde_json::json!({
+            "src": [
+                { "filename": "a", "contents": "one two" },
+                { "filename": "b", "contents": "three" },
+                { "filename": "c", "contents": "four five six" },
+            ]
+        });
+        limit_tokens(
+            options.iter().map(|(n, o)| (n, o)),
+            &mut context,
+            Some(&tokenizer.to_string_lossy()),
+        )
+        .unwrap();
+        let kept = context["src"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| file["filename"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(kept, ["a", "b"]);
+    }
+
+    #[test]
+    fn read_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        std::fs::write(base.join("text.txt"), "some text").unwrap();
+        std::fs::write(base.join("data.bin"), [0xff, 0, 1]).unwrap();
+
+        let read = |path: &str, option: &PromptOption, expanded: bool| {
+            read_file(base, Path::new(path), "file", option, expanded)
+        };
+
+        let option = file_option(r#"{ "type": "file" }"#);
+        let text = read("text.txt", &option, false).unwrap().unwrap();
+        assert_eq!(text["contents"], "some text");
+        assert_eq!(read("data.bin", &option, true).unwrap(), None);
+        let err = read("data.bin", &option, false).unwrap_err();
+        assert!(
+            format!("{err:?}").contains("--file: data.bin is not a text file"),
+            "{err:?}"
+        );
+
+        let with_binary = file_option(r#"{ "type": "file", "binary": "base64" }"#);
+        let data = read("data.bin", &with_binary, false).unwrap().unwrap();
+        assert_eq!(data["contents"], "/wAB");
+        assert_eq!(data["encoding"], "base64");
+
+        let small = file_option(r#"{ "type": "file", "max_file_size": 4 }"#);
+        assert_eq!(read("text.txt", &small, true).unwrap(), None);
+        let err = read("text.txt", &small, false).unwrap_err();
+        assert!(
+            format!("{err:?}").contains("--file: text.txt is 9 bytes, over the limit of 4"),
+            "{err:?}"
+        );
+
+        let err = read("missing.txt", &option, false).unwrap_err();
+        assert!(matches!(err.current_context(), Error::Io));
+    }
+}
@@ -0,0 +1,503 @@
+//! Filters and functions for writing prompts, added to Tera's built-ins when a template is
+//! rendered.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use tera::{Tera, Value};
+
+use crate::{
+    context::{truncate_at, OverflowKeep},
+    githooks::shell_quote,
+    provenance::{ReadSources, SourceKind},
+    tokenizer::Tokenizer,
+    tools::shell_command,
+};
+
+/// The characters that `escape_markdown` escapes
+const MARKDOWN_SPECIAL: &str = "\\`*_{}[]()#+-.!|<>~";
+
+/// Whether the `shell` function can run commands
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ShellAccess {
+    /// Fail with an error that explains how to allow commands
+    #[default]
+    Denied,
+    Allowed,
+    /// Give a placeholder instead of running the command, to check a template without running it
+    Placeholder,
+}
+
+impl ShellAccess {
+    /// Commands only run when both the template and the run allow them, so that running a
+    /// template from somewhere else can't run commands without asking.
+    pub fn for_run(template_allows: bool, run_allows: bool) -> Self {
+        if template_allows && run_allows {
+            Self::Allowed
+        } else {
+            Self::Denied
+        }
+    }
+}
+
+/// The filters that depend on the template's settings
+#[derive(Debug, Clone, Default)]
+pub struct PromptFilters {
+    /// The tokenizer for the token-aware filters, the same one used to fit the prompt in the
+    /// context
+    tokenizer: Option<String>,
+    shell: ShellAccess,
+    /// The output of each command that has run, so that rendering the prompt again to fit the
+    /// context doesn't run the commands again.
+    shell_output: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl PromptFilters {
+    pub fn new(tokenizer: Option<&str>) -> Self {
+        Self {
+            tokenizer: tokenizer.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_shell(mut self, shell: ShellAccess) -> Self {
+        self.shell = shell;
+        self
+    }
+
+    /// Register the filters, and the `file` function which reads files relative to the directory
+    /// of `template_path` and adds them to `sources`.
+    pub fn register(&self, tera: &mut Tera, template_path: &Path, sources: &ReadSources) {
+        tera.register_filter("truncate_tokens", TruncateTokens(self.tokenizer.clone()));
+        tera.register_filter("token_count", TokenCount(self.tokenizer.clone()));
+        tera.register_filter("to_json", to_json);
+        tera.register_filter("from_json", from_json);
+        tera.register_filter("dedent", dedent);
+        tera.register_filter(
+            "indent",
+            Indent {
+                builtins: Tera::default(),
+            },
+        );
+        tera.register_filter("escape_markdown", escape_markdown);
+        tera.register_filter(
+            "shell_quote",
+            |value: &Value, _: &HashMap<String, Value>| {
+                Ok(shell_quote(&string_arg("shell_quote", value)?).into())
+            },
+        );
+        tera.register_function(
+            "file",
+            ReadFile {
+                template_dir: template_path.parent().map(Path::to_path_buf),
+                tokenizer: self.tokenizer.clone(),
+                sources: sources.clone(),
+            },
+        );
+        tera.register_function(
+            "shell",
+            RunShell {
+                access: self.shell,
+                output: self.shell_output.clone(),
+            },
+        );
+    }
+}
+
+/// The text of a filter's input. Strings are used as-is and other values are converted to JSON,
+/// as they would be printed in a template.
+fn string_arg(filter: &str, value: &Value) -> tera::Result<String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Null => Err(tera::Error::msg(format!(
+            "Filter `{filter}` received a null value"
+        ))),
+        value => Ok(value.to_string()),
+    }
+}
+
+fn load_tokenizer(name: &str, spec: Option<&str>) -> tera::Result<std::sync::Arc<Tokenizer>> {
+    Tokenizer::get(spec).map_err(|e| tera::Error::msg(format!("`{name}`: {e}")))
+}
+
+/// `truncate_tokens(count=500)` keeps the first `count` tokens, or the last ones with
+/// `keep="end"`.
+struct TruncateTokens(Option<String>);
+
+impl tera::Filter for TruncateTokens {
+    fn filter(&self, value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let count = args.get("count").and_then(Value::as_u64).ok_or_else(|| {
+            tera::Error::msg("Filter `truncate_tokens` expected a number argument `count`")
+        })? as usize;
+        let keep = match args.get("keep") {
+            Some(keep) => serde_json::from_value::<OverflowKeep>(keep.clone()).map_err(|_| {
+                tera::Error::msg(format!(
+                    "Filter `truncate_tokens` received keep={keep} but `keep` can only be \"start\" or \"end\""
+                ))
+            })?,
+            None => OverflowKeep::Start,
+        };
+
+        let text = string_arg("truncate_tokens", value)?;
+        if count == 0 {
+            return Ok(String::new().into());
+        }
+
+        let tokenizer = load_tokenizer("truncate_tokens", self.0.as_deref())?;
+        let encoding = tokenizer
+            .encode(&text)
+            .map_err(|e| tera::Error::msg(format!("Filter `truncate_tokens`: {e}")))?;
+        Ok(truncate_at(count, keep, &text, &encoding).into())
+    }
+}
+
+/// `file(path="notes/style.md")` gives the contents of a file, found relative to the template's
+/// directory or else the current directory. `max_tokens` keeps only the start of the file.
+struct ReadFile {
+    template_dir: Option<PathBuf>,
+    tokenizer: Option<String>,
+    sources: ReadSources,
+}
+
+impl tera::Function for ReadFile {
+    fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let path = args
+            .get("path")
+            .and_then(Value::as_str)
+            .ok_or_else(|| tera::Error::msg("Function `file` expected a string argument `path`"))?;
+        let max_tokens = match args.get("max_tokens") {
+            Some(max_tokens) => Some(max_tokens.as_u64().ok_or_else(|| {
+                tera::Error::msg(format!(
+                    "Function `file` received max_tokens={max_tokens} but `max_tokens` can only be a number"
+                ))
+            })? as usize),
+            None => None,
+        };
+
+        let candidates = self
+            .template_dir
+            .iter()
+            .map(|dir| dir.join(path))
+            .chain(std::iter::once(PathBuf::from(path)));
+        let found = candidates.into_iter().find(|p| p.is_file()).ok_or_else(|| {
+            tera::Error::msg(format!(
+                "Function `file` could not find {path} in the template's directory or the current directory"
+            ))
+        })?;
+        let contents = std::fs::read_to_string(&found).map_err(|e| {
+            tera::Error::msg(format!(
+                "Function `file` could not read {}: {e}",
+                found.display()
+            ))
+        })?;
+        self.sources.record(SourceKind::File, &found, &contents);
+
+        let Some(max_tokens) = max_tokens else {
+            return Ok(contents.into());
+        };
+        if max_tokens == 0 {
+            return Ok(String::new().into());
+        }
+
+        let tokenizer = load_tokenizer("file", self.tokenizer.as_deref())?;
+        let encoding = tokenizer
+            .encode(&contents)
+            .map_err(|e| tera::Error::msg(format!("Function `file`: {e}")))?;
+        Ok(truncate_at(max_tokens, OverflowKeep::Start, &contents, &encoding).into())
+    }
+}
+
+/// `shell(command="git diff --staged")` gives the output of a command, run in the current
+/// directory.
+struct RunShell {
+    access: ShellAccess,
+    output: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl tera::Function for RunShell {
+    fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let command = args.get("command").and_then(Value::as_str).ok_or_else(|| {
+            tera::Error::msg("Function `shell` expected a string argument `command`")
+        })?;
+
+        match self.access {
+            ShellAccess::Denied => {
+                return Err(tera::Error::msg(format!(
+                    "Function `shell` can't run `{command}` unless the template sets `allow_shell = true` and --allow-shell is given"
+                )));
+            }
+            ShellAccess::Placeholder => return Ok(format!("output of {command}").into()),
+            ShellAccess::Allowed => {}
+        }
+
+        if let Some(output) = self.output.lock().unwrap().get(command) {
+            return Ok(output.clone().into());
+        }
+
+        let output = shell_command(command)
+            .stdin(std::process::Stdio::null())
+            .output()
+            .map_err(|e| {
+                tera::Error::msg(format!("Function `shell` could not run `{command}`: {e}"))
+            })?;
+        if !output.status.success() {
+            return Err(tera::Error::msg(format!(
+                "Function `shell` command `{command}` failed with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        self.output
+            .lock()
+            .unwrap()
+            .insert(command.to_string(), stdout.clone());
+        Ok(stdout.into())
+    }
+}
+
+/// `token_count` gives the number of tokens in the text.
+struct TokenCount(Option<String>);
+
+impl tera::Filter for TokenCount {
+    fn filter(&self, value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+        let text = string_arg("token_count", value)?;
+        let tokenizer = load_tokenizer("token_count", self.0.as_deref())?;
+        let encoding = tokenizer
+            .encode(&text)
+            .map_err(|e| tera::Error::msg(format!("Filter `token_count`: {e}")))?;
+        Ok(encoding.len().into())
+    }
+}
+
+/// `to_json` converts a value to JSON, and `to_json(pretty=true)` spreads it over several lines.
+fn to_json(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let pretty = args.get("pretty").and_then(Value::as_bool).unwrap_or(false);
+    let json = if pretty {
+        serde_json::to_string_pretty(value)
+    } else {
+        serde_json::to_string(value)
+    }
+    .map_err(tera::Error::json)?;
+    Ok(json.into())
+}
+
+/// `from_json` parses a string of JSON so that its fields can be used in the template.
+fn from_json(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    let text = value
+        .as_str()
+        .ok_or_else(|| tera::Error::msg("Filter `from_json` expected a string"))?;
+    serde_json::from_str(text)
+        .map_err(|e| tera::Error::msg(format!("Filter `from_json` could not parse JSON: {e}")))
+}
+
+/// `dedent` removes the indentation that every line has in common.
+fn dedent(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    let text = string_arg("dedent", value)?;
+    let indent = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+
+    let dedented = text
+        .split('\n')
+        .map(|line| line.get(indent..).unwrap_or_else(|| line.trim_start()))
+        .collect::<Vec<_>>()
+        .join("\n");
+    Ok(dedented.into())
+}
+
+/// Tera's `indent` filter, which also takes `width` as the number of spaces to indent by, as in
+/// `indent(width=4)`.
+struct Indent {
+    builtins: Tera,
+}
+
+impl tera::Filter for Indent {
+    fn filter(&self, value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let mut args = args.clone();
+        if let Some(width) = args.remove("width") {
+            let width = width.as_u64().ok_or_else(|| {
+                tera::Error::msg(format!(
+                    "Filter `indent` received width={width} but `width` can only be a number"
+                ))
+            })?;
+            args.insert("prefix".to_string(), " ".repeat(width as usize).into());
+        }
+
+        self.builtins.get_filter("indent")?.filter(value, &args)
+    }
+}
+
+/// `escape_markdown` puts a backslash before the characters that Markdown treats as formatting.
+fn escape_markdown(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    let text = string_arg("escape_markdown", value)?;
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if MARKDOWN_SPECIAL.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    Ok(escaped.into())
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{provenance::SourceFile, tests::base_dir};
+
+    fn render(template: &str, args: serde_json::Value) -> String {
+        let tokenizer = base_dir("whitespace_tokenizer.json");
+        let mut tera = Tera::default();
+        PromptFilters::new(tokenizer.to_str()).register(
+            &mut tera,
+            &base_dir("test.pb.toml"),
+            &ReadSources::default(),
+        );
+        let context = tera::Context::from_value(args).unwrap();
+        tera.render_str(template, &context).unwrap()
+    }
+
+    #[test]
+    fn tokens() {
+        let args = json!({ "text": "one two three four five" });
+        assert_eq!(render("{{ text | token_count }}", args.clone()), "5");
+        assert_eq!(
+            render("{{ text | truncate_tokens(count=2) }}", args.clone()),
+            "one two"
+        );
+        assert_eq!(
+            render(r#"{{ text | truncate_tokens(count=2, keep="end") }}"#, args),
+            "four five"
+        );
+    }
+
+    #[test]
+    fn read_file() {
+        // Paths are found relative to the template's directory, and then the current directory.
+        assert_eq!(
+            render(r#"{{ file(path="test1.txt") }}"#, json!({})),
+            "test1\n"
+        );
+        assert_eq!(
+            render(r#"{{ file(path="test_data/test2.txt") }}"#, json!({})),
+            "it's test2\n"
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("notes.md"), "one two three four").unwrap();
+        let sources = ReadSources::default();
+        let mut tera = Tera::default();
+        PromptFilters::new(base_dir("whitespace_tokenizer.json").to_str()).register(
+            &mut tera,
+            &dir.path().join("t.pb.toml"),
+            &sources,
+        );
+        let rendered = tera
+            .render_str(
+                r#"{{ file(path="notes.md", max_tokens=2) }}"#,
+                &tera::Context::new(),
+            )
+            .unwrap();
+        assert_eq!(rendered, "one two");
+        assert_eq!(
+            sources.files(),
+            [SourceFile::new(
+                SourceKind::File,
+                dir.path().join("notes.md"),
+                "one two three four"
+            )]
+        );
+        assert!(tera
+            .render_str(r#"{{ file(path="missing.md") }}"#, &tera::Context::new())
+            .is_err());
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn shell() {
+        let template = r#"{{ shell(command="echo $((1 + 2))") }}"#;
+        let render_with = |filters: &PromptFilters| {
+            let mut tera = Tera::default();
+            filters.register(
+                &mut tera,
+                &base_dir("test.pb.toml"),
+                &ReadSources::default(),
+            );
+            tera.render_str(template, &tera::Context::new())
+        };
+
+        let err = render_with(&PromptFilters::default()).unwrap_err();
+        assert!(format!("{err:?}").contains("allow_shell"), "{err:?}");
+
+        let placeholder = PromptFilters::default().with_shell(ShellAccess::Placeholder);
+        assert_eq!(
+            render_with(&placeholder).unwrap(),
+            "output of echo $((1 + 2))"
+        );
+
+        for (template_allows, run_allows) in [(true, false), (false, true)] {
+            let one_flag = PromptFilters::default()
+                .with_shell(ShellAccess::for_run(template_allows, run_allows));
+            assert!(render_with(&one_flag).is_err());
+        }
+
+        let allowed = PromptFilters::default().with_shell(ShellAccess::for_run(true, true));
+        assert_eq!(render_with(&allowed).unwrap(), "3\n");
+
+        let failing = r#"{{ shell(command="exit 3") }}"#;
+        let mut tera = Tera::default();
+        allowed.register(
+            &mut tera,
+            &base_dir("test.pb.toml"),
+            &ReadSources::default(),
+        );
+        assert!(tera.render_str(failing, &tera::Context::new()).is_err());
+    }
+
+    #[test]
+    fn json() {
+        let args = json!({ "data": { "a": [1, 2] }, "text": r#"{"name": "Sam"}"# });
+        assert_eq!(
+            render("{{ data | to_json }}", args.clone()),
+            r#"{"a":[1,2]}"#
+        );
+        assert_eq!(
+            render("{% set parsed = text | from_json %}{{ parsed.name }}", args),
+            "Sam"
+        );
+    }
+
+    #[test]
+    fn text() {
+        let args = json!({
+            "code": "    fn main() {\n        run();\n    }",
+            "name": "it's *here*",
+        });
+        assert_eq!(
+            render("{{ code | dedent }}", args.clone()),
+            "fn main() {\n    run();\n}"
+        );
+        assert_eq!(
+            render("{{ code | dedent | indent(width=2) }}", args.clone()),
+            "fn main() {\n      run();\n  }"
+        );
+        assert_eq!(
+            render("{{ name | escape_markdown }}", args.clone()),
+            r"it's \*here\*"
+        );
+        assert_eq!(
+            render("{{ name | shell_quote }}", args),
+            r#"'it'\''s *here*'"#
+        );
+    }
+}
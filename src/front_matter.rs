@@ -0,0 +1,70 @@
+//! Split the front matter from the body of a Markdown file. YAML front matter is fenced by `---`
+//! lines, and TOML front matter by `+++` lines.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrontMatter<'a> {
+    Yaml(&'a str),
+    Toml(&'a str),
+}
+
+/// Split the front matter from the body. Files without front matter are all body.
+pub fn split_front_matter(contents: &str) -> (Option<FrontMatter<'_>>, &str) {
+    if let Some((yaml, body)) = split_fenced(contents, "---") {
+        return (Some(FrontMatter::Yaml(yaml)), body);
+    }
+    if let Some((toml, body)) = split_fenced(contents, "+++") {
+        return (Some(FrontMatter::Toml(toml)), body);
+    }
+    (None, contents)
+}
+
+fn split_fenced<'a>(contents: &'a str, fence: &str) -> Option<(&'a str, &'a str)> {
+    let rest = contents.strip_prefix(fence)?;
+    let rest = rest
+        .strip_prefix('\n')
+        .or_else(|| rest.strip_prefix("\r\n"))?;
+
+    // Front matter with nothing in it has the closing fence right away.
+    if let Some(body) = rest.strip_prefix(fence) {
+        return Some(("", after_line(body)));
+    }
+
+    let end = rest.find(&format!("\n{fence}"))?;
+    let body = &rest[end + 1 + fence.len()..];
+    Some((&rest[..end], after_line(body)))
+}
+
+/// Skip the rest of the line that the closing fence is on.
+fn after_line(text: &str) -> &str {
+    text.split_once('\n').map(|(_, rest)| rest).unwrap_or("")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn split() {
+        assert_eq!(
+            split_front_matter("---\nmodel: gpt-4o\n---\nHello\n"),
+            (Some(FrontMatter::Yaml("model: gpt-4o")), "Hello\n")
+        );
+        assert_eq!(
+            split_front_matter("+++\r\n[model]\r\nmodel = \"gpt-4o\"\r\n+++\r\nHello"),
+            (
+                Some(FrontMatter::Toml("[model]\r\nmodel = \"gpt-4o\"\r")),
+                "Hello"
+            )
+        );
+        assert_eq!(
+            split_front_matter("---\n---\nHello"),
+            (Some(FrontMatter::Yaml("")), "Hello")
+        );
+        // Without a closing fence, it isn't front matter.
+        assert_eq!(
+            split_front_matter("---\nJust a line\n"),
+            (None, "---\nJust a line\n")
+        );
+        assert_eq!(split_front_matter("Hello"), (None, "Hello"));
+    }
+}
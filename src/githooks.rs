@@ -0,0 +1,423 @@
+//! Install git hooks which run templates to write commit messages and pull request descriptions.
+
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use error_stack::{Report, ResultExt};
+
+use crate::{
+    config::{Config, SearchOptions},
+    error::Error,
+};
+
+/// Marks hooks written by promptbox, so that they can be replaced without `--force`.
+const HOOK_MARKER: &str = "# Installed by promptbox githooks";
+
+const COMMIT_MSG_TEMPLATE: (&str, &str) = (
+    "githooks/commit-msg",
+    include_str!("../prompts/githooks/commit-msg.pb.toml"),
+);
+
+const PR_DESCRIPTION_TEMPLATE: (&str, &str) = (
+    "githooks/pr-description",
+    include_str!("../prompts/githooks/pr-description.pb.toml"),
+);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HookKind {
+    /// Write the commit message from the staged diff when the message is left empty
+    CommitMsg,
+    /// Write the commit message from the staged diff before the editor opens
+    PrepareCommitMsg,
+    /// Write a description of the pushed commits to .git/PR_DESCRIPTION.md on each push
+    PrDescription,
+}
+
+impl HookKind {
+    /// The name of the git hook that runs the template
+    pub fn hook_name(&self) -> &'static str {
+        match self {
+            HookKind::CommitMsg => "commit-msg",
+            HookKind::PrepareCommitMsg => "prepare-commit-msg",
+            HookKind::PrDescription => "pre-push",
+        }
+    }
+
+    /// The name and contents of the template that is used if no other template is given.
+    fn bundled_template(&self) -> (&'static str, &'static str) {
+        match self {
+            HookKind::CommitMsg | HookKind::PrepareCommitMsg => COMMIT_MSG_TEMPLATE,
+            HookKind::PrDescription => PR_DESCRIPTION_TEMPLATE,
+        }
+    }
+
+    /// Generate the hook script. The hooks never fail, so a problem running the template never
+    /// blocks a commit or push.
+    fn script(&self, template: &str) -> String {
+        let run = format!(r#""$PROMPTBOX" run {}"#, shell_quote(template));
+        let body = match self {
+            HookKind::CommitMsg => format!(
+                r#"msg_file="$1"
+
+# Leave messages written by the author alone.
+if grep -v -e '^#' -e '^[[:space:]]*$' "$msg_file" | grep -q .; then
+  exit 0
+fi
+
+git diff --cached --quiet && exit 0
+message=$(git diff --cached | {run}) || exit 0
+[ -n "$message" ] || exit 0
+printf '%s\n' "$message" > "$msg_file"
+"#
+            ),
+            HookKind::PrepareCommitMsg => format!(
+                r#"msg_file="$1"
+
+# Only write a message for a plain `git commit`, and not for -m, merges, amends, etc.
+[ -z "$2" ] || exit 0
+
+git diff --cached --quiet && exit 0
+message=$(git diff --cached | {run}) || exit 0
+[ -n "$message" ] || exit 0
+{{ printf '%s\n' "$message"; cat "$msg_file"; }} > "$msg_file.promptbox" &&
+  mv "$msg_file.promptbox" "$msg_file"
+"#
+            ),
+            HookKind::PrDescription => format!(
+                r#"out_file=$(git rev-parse --git-path PR_DESCRIPTION.md)
+
+while read -r local_ref local_sha remote_ref remote_sha; do
+  # Skip deleted branches
+  case "$local_sha" in *[!0]*) ;; *) continue ;; esac
+
+  case "$remote_sha" in
+    *[!0]*)
+      base="$remote_sha"
+      range="$remote_sha..$local_sha"
+      ;;
+    *)
+      # A new branch, so describe the commits which aren't on any remote yet.
+      first=$(git rev-list "$local_sha" --not --remotes | tail -n 1)
+      [ -n "$first" ] || continue
+      if git rev-parse --quiet --verify "$first^" > /dev/null; then
+        base="$first^"
+        range="$first^..$local_sha"
+      else
+        # The branch starts with a root commit, so compare against an empty tree.
+        base=$(git hash-object -t tree /dev/null)
+        range="$local_sha"
+      fi
+      ;;
+  esac
+
+  # The remote branch may have commits that were never fetched.
+  git rev-parse --quiet --verify "$base^{{tree}}" > /dev/null || continue
+  if {{ git log --reverse --format='%s%n%n%b' "$range" &&
+    git diff "$base" "$local_sha"; }} | {run} > "$out_file"; then
+    echo "Wrote a description of $local_ref to $out_file" >&2
+  fi
+done
+
+exit 0
+"#
+            ),
+        };
+
+        format!(
+            "#!/bin/sh\n{HOOK_MARKER} {kind}. Runs the template `{template}`.\n\
+            PROMPTBOX=\"${{PROMPTBOX:-promptbox}}\"\n\n{body}",
+            kind = self
+                .to_possible_value()
+                .expect("no skipped variants")
+                .get_name(),
+        )
+    }
+}
+
+/// Quote a string so that the shell passes it through unchanged.
+pub(crate) fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r#"'\''"#))
+}
+
+/// Run a git command in a directory and return its output.
+fn git(dir: &Path, args: &[&str]) -> Result<String, Report<Error>> {
+    let output = std::process::Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .change_context(Error::Git)
+        .attach_printable_lazy(|| format!("git {}", args.join(" ")))?;
+
+    if !output.status.success() {
+        return Err(Report::new(Error::Git))
+            .attach_printable(format!("git {}", args.join(" ")))
+            .attach_printable(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+pub struct InstallOptions<'a> {
+    pub kind: HookKind,
+    /// The template to run. If `None`, the bundled template for the hook is used.
+    pub template: Option<&'a str>,
+    /// Replace an existing hook that was not installed by promptbox
+    pub force: bool,
+}
+
+#[derive(Debug)]
+pub struct InstalledHook {
+    pub hook: PathBuf,
+    pub template: String,
+    /// The bundled template file, if it was written because the template didn't exist yet.
+    pub template_file: Option<PathBuf>,
+}
+
+/// Write the bundled template into a template directory within the repository, unless a template
+/// with the same name already exists.
+fn install_bundled_template(
+    dir: &Path,
+    config: &Config,
+    (name, contents): (&str, &str),
+) -> Result<Option<PathBuf>, Report<Error>> {
+    if config.find_template_file(name).is_some() {
+        return Ok(None);
+    }
+
+    let root = PathBuf::from(git(dir, &["rev-parse", "--show-toplevel"])?);
+    let template_dir = config
+        .template_dirs
+        .iter()
+        .find(|d| d.starts_with(&root))
+        .cloned()
+        .unwrap_or_else(|| root.join("promptbox"));
+
+    let path = template_dir.join(format!("{name}.pb.toml"));
+    let parent = path.parent().expect("template path has a directory");
+    std::fs::create_dir_all(parent)
+        .and_then(|_| std::fs::write(&path, contents))
+        .change_context(Error::Io)
+        .attach_printable_lazy(|| path.display().to_string())?;
+
+    Ok(Some(path))
+}
+
+/// Install a hook into the git repository containing `dir`.
+pub fn install_hook(
+    dir: &Path,
+    search: &SearchOptions,
+    options: &InstallOptions,
+) -> Result<InstalledHook, Report<Error>> {
+    let hooks_dir = dir.join(git(dir, &["rev-parse", "--git-path", "hooks"])?);
+    let hook = hooks_dir.join(options.kind.hook_name());
+
+    if let Ok(existing) = std::fs::read_to_string(&hook) {
+        if !existing.contains(HOOK_MARKER) && !options.force {
+            return Err(Report::new(Error::HookExists(hook.display().to_string())));
+        }
+    }
+
+    let config = Config::search(dir.to_path_buf(), search)?;
+    let (template, template_file) = match options.template {
+        Some(template) => {
+            config
+                .find_template_file(template)
+                .ok_or(Error::TemplateNotFound)
+                .attach_printable_lazy(|| template.to_string())?;
+            (template.to_string(), None)
+        }
+        None => {
+            let bundled = options.kind.bundled_template();
+            let file = install_bundled_template(dir, &config, bundled)?;
+            (bundled.0.to_string(), file)
+        }
+    };
+
+    std::fs::create_dir_all(&hooks_dir)
+        .and_then(|_| std::fs::write(&hook, options.kind.script(&template)))
+        .change_context(Error::Io)
+        .attach_printable_lazy(|| hook.display().to_string())?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755))
+            .change_context(Error::Io)
+            .attach_printable_lazy(|| hook.display().to_string())?;
+    }
+
+    Ok(InstalledHook {
+        hook,
+        template,
+        template_file,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn repo() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        git(dir.path(), &["init", "-q"]).unwrap();
+        std::fs::write(
+            dir.path().join("promptbox.toml"),
+            "top_level = true\nuse_global_config = false\n",
+        )
+        .unwrap();
+        dir
+    }
+
+    fn install(dir: &Path, kind: HookKind, template: Option<&str>, force: bool) -> InstalledHook {
+        install_hook(
+            dir,
+            &SearchOptions::default(),
+            &InstallOptions {
+                kind,
+                template,
+                force,
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn bundled_templates_parse() {
+        for (name, contents) in [COMMIT_MSG_TEMPLATE, PR_DESCRIPTION_TEMPLATE] {
+            let template = toml::from_str::<crate::template::PromptTemplate>(contents)
+                .unwrap_or_else(|e| panic!("{name}: {e}"));
+            assert!(template.template.unwrap().contains("{{ extra }}"));
+        }
+    }
+
+    #[test]
+    fn install_bundled() {
+        let dir = repo();
+        let installed = install(dir.path(), HookKind::PrepareCommitMsg, None, false);
+
+        assert_eq!(installed.template, "githooks/commit-msg");
+        let template_file = installed.template_file.unwrap();
+        assert!(template_file.ends_with("githooks/commit-msg.pb.toml"));
+        assert_eq!(
+            std::fs::read_to_string(template_file).unwrap(),
+            COMMIT_MSG_TEMPLATE.1
+        );
+
+        assert!(installed.hook.ends_with(".git/hooks/prepare-commit-msg"));
+        let script = std::fs::read_to_string(&installed.hook).unwrap();
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script.contains(r#""$PROMPTBOX" run 'githooks/commit-msg'"#));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&installed.hook)
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o111, 0o111);
+        }
+
+        // The commit-msg hook uses the same template, which now exists, so it isn't written again.
+        let installed = install(dir.path(), HookKind::CommitMsg, None, false);
+        assert_eq!(installed.template, "githooks/commit-msg");
+        assert!(installed.template_file.is_none());
+    }
+
+    #[test]
+    fn install_custom_template() {
+        let dir = repo();
+        std::fs::write(dir.path().join("my-pr.pb.toml"), "template = '{{ extra }}'").unwrap();
+
+        let installed = install(dir.path(), HookKind::PrDescription, Some("my-pr"), false);
+        assert!(installed.hook.ends_with(".git/hooks/pre-push"));
+        assert!(installed.template_file.is_none());
+        let script = std::fs::read_to_string(&installed.hook).unwrap();
+        assert!(script.contains(r#""$PROMPTBOX" run 'my-pr'"#));
+
+        let err = install_hook(
+            dir.path(),
+            &SearchOptions::default(),
+            &InstallOptions {
+                kind: HookKind::PrDescription,
+                template: Some("missing"),
+                force: false,
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(err.current_context(), Error::TemplateNotFound));
+    }
+
+    #[test]
+    fn existing_hook() {
+        let dir = repo();
+        let hook = dir.path().join(".git/hooks/commit-msg");
+        std::fs::create_dir_all(hook.parent().unwrap()).unwrap();
+        std::fs::write(&hook, "#!/bin/sh\nexit 0\n").unwrap();
+
+        let err = install_hook(
+            dir.path(),
+            &SearchOptions::default(),
+            &InstallOptions {
+                kind: HookKind::CommitMsg,
+                template: None,
+                force: false,
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(err.current_context(), Error::HookExists(_)));
+        assert_eq!(
+            std::fs::read_to_string(&hook).unwrap(),
+            "#!/bin/sh\nexit 0\n"
+        );
+
+        install(dir.path(), HookKind::CommitMsg, None, true);
+        // Hooks installed by promptbox can be replaced without forcing.
+        install(dir.path(), HookKind::CommitMsg, None, false);
+        assert!(std::fs::read_to_string(&hook)
+            .unwrap()
+            .contains(HOOK_MARKER));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn commit_with_generated_message() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = repo();
+        install(dir.path(), HookKind::CommitMsg, None, false);
+
+        // Stand in for promptbox, and check that it receives the staged diff.
+        let fake = dir.path().join("fake-promptbox");
+        std::fs::write(
+            &fake,
+            "#!/bin/sh\ngrep -q '^+hello' && echo \"Add greeting from $2\"\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&fake, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        std::fs::write(dir.path().join("greeting.txt"), "hello\n").unwrap();
+        git(dir.path(), &["add", "greeting.txt"]).unwrap();
+
+        let status = std::process::Command::new("git")
+            .args([
+                "-c",
+                "user.name=Test",
+                "-c",
+                "user.email=test@example.com",
+                "commit",
+                "-q",
+                "--allow-empty-message",
+                "-m",
+                "",
+            ])
+            .current_dir(dir.path())
+            .env("PROMPTBOX", &fake)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let message = git(dir.path(), &["log", "-1", "--format=%B"]).unwrap();
+        assert_eq!(message, "Add greeting from githooks/commit-msg");
+    }
+}
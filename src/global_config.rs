@@ -3,10 +3,17 @@ use std::path::PathBuf;
 use etcetera::BaseStrategy;
 use itertools::Itertools;
 
+/// The directories which hold the global configuration and personal templates. `XDG_CONFIG_HOME`
+/// is checked on every platform, along with the platform's own configuration directory and
+/// `~/.config`.
 pub fn global_config_dirs() -> Vec<PathBuf> {
     let etc = etcetera::base_strategy::choose_native_strategy().unwrap();
-    vec![etc.config_dir(), etc.home_dir().join(".config")]
+    let xdg_config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute());
+    xdg_config
         .into_iter()
+        .chain([etc.config_dir(), etc.home_dir().join(".config")])
         .unique()
         .map(|p| p.join("promptbox"))
         .filter(|p| p.is_dir())
@@ -0,0 +1,722 @@
+//! A record of past runs, which can be exported as transcripts or training data.
+
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, Write},
+    path::PathBuf,
+};
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use error_stack::{Report, ResultExt};
+use etcetera::BaseStrategy;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{event, Level};
+
+use crate::{
+    error::Error,
+    model::ModelOptions,
+    moderation::ModerationResult,
+    provenance::SourceFile,
+    session::{ChatMessage, Role},
+    tokenizer::Tokenizer,
+    usage::Usage,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub timestamp: DateTime<Utc>,
+    pub template: String,
+    pub model: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    pub prompt: String,
+    pub response: String,
+    /// Ratings of the response, such as from `promptbox history rate`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub scores: BTreeMap<String, f64>,
+    /// How the run was started, for `promptbox run --last`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invocation: Option<Invocation>,
+    /// The parameters sent to the model
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<RunParameters>,
+    /// The size of the prompt and response, counted with the local tokenizer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<TokenCounts>,
+    /// How long the model took to respond, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    /// The tokens used and their cost, as reported by the host when it can
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    /// The template and configuration files that the prompt came from, with hashes of their
+    /// contents
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<SourceFile>,
+    /// The messages sent before the prompt, for runs that continued a conversation
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub earlier_messages: Vec<ChatMessage>,
+    /// The results of checking the prompt and response with the moderation classifier
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub moderation: Vec<ModerationResult>,
+}
+
+/// The sampling parameters of a run
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RunParameters {
+    pub temperature: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub logit_bias: BTreeMap<String, f32>,
+}
+
+impl From<&ModelOptions> for RunParameters {
+    fn from(options: &ModelOptions) -> Self {
+        Self {
+            temperature: options.temperature,
+            top_k: options.top_k,
+            top_p: options.top_p,
+            frequency_penalty: options.frequency_penalty,
+            presence_penalty: options.presence_penalty,
+            max_tokens: options.max_tokens,
+            stop: options.stop.clone(),
+            seed: options.seed,
+            logit_bias: options.logit_bias.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenCounts {
+    /// The tokens in the prompt and system prompt
+    pub prompt: usize,
+    pub response: usize,
+}
+
+impl TokenCounts {
+    /// Count the tokens in a run. Returns `None` if the tokenizer can't be loaded, since the
+    /// counts aren't important enough to fail the run.
+    pub fn count(
+        tokenizer: Option<&str>,
+        system: Option<&str>,
+        prompt: &str,
+        response: &str,
+    ) -> Option<Self> {
+        let count = || -> Result<Self, Error> {
+            let tokenizer = Tokenizer::get(tokenizer)?;
+            let system = system.map(|s| tokenizer.encode(s)).transpose()?;
+            Ok(Self {
+                prompt: tokenizer.encode(prompt)?.len() + system.map_or(0, |s| s.len()),
+                response: tokenizer.encode(response)?.len(),
+            })
+        };
+
+        match count() {
+            Ok(counts) => Some(counts),
+            Err(e) => {
+                event!(Level::WARN, error = %e, "Failed to count tokens for the history");
+                None
+            }
+        }
+    }
+}
+
+/// The command line and input of a run, so that it can be run again.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Invocation {
+    /// The directory that PromptBox ran in
+    pub directory: PathBuf,
+    /// The command line arguments, starting with `run`
+    pub args: Vec<String>,
+    /// The text that was piped in, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stdin: Option<String>,
+}
+
+impl Invocation {
+    /// The name of the template that was run
+    pub fn template(&self) -> &str {
+        self.args.get(1).map(|s| s.as_str()).unwrap_or_default()
+    }
+
+    /// Replace the model argument, or add one if there wasn't one.
+    pub fn with_model(mut self, model: &str) -> Self {
+        // Arguments after `--` are all extra prompt text.
+        let end = self
+            .args
+            .iter()
+            .position(|arg| arg == "--")
+            .unwrap_or(self.args.len());
+        let rest = self.args.split_off(end);
+
+        let mut args = Vec::with_capacity(self.args.len() + rest.len() + 2);
+        let mut options = self.args.into_iter();
+        while let Some(arg) = options.next() {
+            if arg == "--model" || arg == "-m" {
+                // Skip the value too
+                options.next();
+                continue;
+            }
+
+            let has_value = arg.starts_with("--model=") || (arg.starts_with("-m") && arg.len() > 2);
+            if !has_value {
+                args.push(arg);
+            }
+        }
+        args.extend(["--model".to_string(), model.to_string()]);
+        args.extend(rest);
+
+        self.args = args;
+        self
+    }
+}
+
+impl HistoryEntry {
+    /// Create an entry for a run. The ID and timestamp are set when it is added to the history.
+    pub fn new(
+        template: &str,
+        model: &str,
+        system: Option<&str>,
+        prompt: &str,
+        response: &str,
+    ) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            template: template.to_string(),
+            model: model.to_string(),
+            system: system.map(|s| s.to_string()),
+            prompt: prompt.to_string(),
+            response: response.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// The whole conversation of the run, ending with the prompt and response.
+    pub fn conversation(&self) -> Vec<ChatMessage> {
+        let mut messages = self.earlier_messages.clone();
+        messages.push(ChatMessage::user(&self.prompt));
+        messages.push(ChatMessage::assistant(&self.response));
+        messages
+    }
+
+    /// Returns true if the prompt, system prompt, or response contains `text`, ignoring case.
+    pub fn contains_text(&self, text: &str) -> bool {
+        let text = text.to_lowercase();
+        [
+            Some(&self.prompt),
+            self.system.as_ref(),
+            Some(&self.response),
+        ]
+        .into_iter()
+        .flatten()
+        .any(|field| field.to_lowercase().contains(&text))
+    }
+
+    /// A single line describing the entry.
+    pub fn summary(&self) -> String {
+        let prompt = self
+            .prompt
+            .lines()
+            .find(|l| !l.trim().is_empty())
+            .unwrap_or("");
+        let prompt = match prompt.char_indices().nth(60) {
+            Some((index, _)) => format!("{}...", &prompt[..index]),
+            None => prompt.to_string(),
+        };
+
+        format!(
+            "{}\t{}\t{}\t{}\t{}",
+            self.id,
+            self.timestamp.format("%Y-%m-%d %H:%M"),
+            self.template,
+            self.model,
+            prompt.trim()
+        )
+    }
+}
+
+/// The directory for the history and other data that PromptBox saves.
+pub fn data_dir() -> Result<PathBuf, Report<Error>> {
+    let etc = etcetera::base_strategy::choose_native_strategy().unwrap();
+    let dir = etc.data_dir().join("promptbox");
+
+    std::fs::create_dir_all(&dir)
+        .change_context(Error::History)
+        .attach_printable_lazy(|| format!("Creating history directory {}", dir.display()))?;
+
+    Ok(dir)
+}
+
+/// The history file, stored as one JSON object per line.
+#[derive(Debug)]
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    pub fn new() -> Result<Self, Report<Error>> {
+        Ok(Self {
+            path: data_dir()?.join("history.jsonl"),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn at(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Read all the entries in the history, oldest first.
+    pub fn entries(&self) -> Result<Vec<HistoryEntry>, Report<Error>> {
+        let file = match std::fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e)
+                    .change_context(Error::History)
+                    .attach_printable_lazy(|| self.path.display().to_string())
+            }
+        };
+
+        std::io::BufReader::new(file)
+            .lines()
+            .filter(|line| !line.as_ref().is_ok_and(|l| l.trim().is_empty()))
+            .map(|line| {
+                let line = line.change_context(Error::History)?;
+                serde_json::from_str(&line)
+                    .change_context(Error::History)
+                    .attach_printable_lazy(|| self.path.display().to_string())
+            })
+            .collect()
+    }
+
+    pub fn get(&self, id: u64) -> Result<HistoryEntry, Report<Error>> {
+        self.entries()?
+            .into_iter()
+            .find(|e| e.id == id)
+            .ok_or(Report::new(Error::HistoryEntryNotFound(id)))
+    }
+
+    /// The most recent run that can be run again, with its ID.
+    pub fn last_invocation(&self) -> Result<Option<(u64, Invocation)>, Report<Error>> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .rev()
+            .find_map(|e| Some((e.id, e.invocation?))))
+    }
+
+    /// The most recent run of a template.
+    pub fn last_run_of(&self, template: &str) -> Result<Option<HistoryEntry>, Report<Error>> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .rev()
+            .find(|e| e.template == template))
+    }
+
+    /// Add a run to the history and return its ID.
+    pub fn add(&self, mut entry: HistoryEntry) -> Result<u64, Report<Error>> {
+        entry.id = self.entries()?.last().map(|e| e.id + 1).unwrap_or(1);
+        entry.timestamp = Utc::now();
+
+        let mut line = serde_json::to_string(&entry).change_context(Error::History)?;
+        line.push('\n');
+
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .change_context(Error::History)
+            .attach_printable_lazy(|| self.path.display().to_string())?;
+
+        Ok(entry.id)
+    }
+
+    /// Set a score on an entry, replacing any existing score with the same name.
+    pub fn set_score(&self, id: u64, name: &str, score: f64) -> Result<(), Report<Error>> {
+        let mut entries = self.entries()?;
+        let entry = entries
+            .iter_mut()
+            .find(|e| e.id == id)
+            .ok_or(Report::new(Error::HistoryEntryNotFound(id)))?;
+        entry.scores.insert(name.to_string(), score);
+
+        let mut contents = String::new();
+        for entry in &entries {
+            contents.push_str(&serde_json::to_string(entry).change_context(Error::History)?);
+            contents.push('\n');
+        }
+
+        // Write to a temporary file first so that the history isn't lost if writing fails.
+        let temp_path = self.path.with_extension("jsonl.tmp");
+        std::fs::write(&temp_path, contents)
+            .and_then(|_| std::fs::rename(&temp_path, &self.path))
+            .change_context(Error::History)
+            .attach_printable_lazy(|| self.path.display().to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// A readable transcript
+    Markdown,
+    /// One ShareGPT conversation per line
+    Sharegpt,
+    /// One OpenAI chat fine-tuning example per line
+    OpenaiJsonl,
+}
+
+/// Format history entries for sharing or as a training dataset.
+pub fn export(entries: &[HistoryEntry], format: ExportFormat) -> String {
+    match format {
+        ExportFormat::Markdown => entries
+            .iter()
+            .map(markdown_transcript)
+            .collect::<Vec<_>>()
+            .join("\n---\n\n"),
+        ExportFormat::Sharegpt | ExportFormat::OpenaiJsonl => entries
+            .iter()
+            .map(|entry| {
+                let value = if format == ExportFormat::Sharegpt {
+                    sharegpt_conversation(entry)
+                } else {
+                    openai_example(entry)
+                };
+                format!("{value}\n")
+            })
+            .collect(),
+    }
+}
+
+fn markdown_transcript(entry: &HistoryEntry) -> String {
+    let mut output = format!(
+        "# {}\n\n_Run {} with {} at {}_\n\n",
+        entry.template,
+        entry.id,
+        entry.model,
+        entry.timestamp.format("%Y-%m-%d %H:%M:%S UTC")
+    );
+
+    if let Some(system) = entry.system.as_ref() {
+        output.push_str(&format!("## System\n\n{}\n\n", system.trim()));
+    }
+
+    let messages = entry
+        .conversation()
+        .iter()
+        .map(|m| format!("## {}\n\n{}\n", m.role.label(), m.content.trim()))
+        .collect::<Vec<_>>()
+        .join("\n");
+    output.push_str(&messages);
+
+    if !entry.sources.is_empty() {
+        output.push_str("\n## Sources\n\n");
+        for source in &entry.sources {
+            output.push_str(&format!("- {source}\n"));
+        }
+    }
+    output
+}
+
+/// Convert an entry to a conversation in the ShareGPT format.
+pub fn sharegpt_conversation(entry: &HistoryEntry) -> serde_json::Value {
+    let mut conversations = Vec::new();
+    if let Some(system) = entry.system.as_ref() {
+        conversations.push(json!({ "from": "system", "value": system }));
+    }
+    for message in entry.conversation() {
+        let from = match message.role {
+            Role::User => "human",
+            Role::Assistant => "gpt",
+        };
+        conversations.push(json!({ "from": from, "value": message.content }));
+    }
+
+    json!({ "conversations": conversations })
+}
+
+/// Convert an entry to an example for OpenAI chat fine-tuning.
+pub fn openai_example(entry: &HistoryEntry) -> serde_json::Value {
+    let mut messages = Vec::new();
+    if let Some(system) = entry.system.as_ref() {
+        messages.push(json!({ "role": "system", "content": system }));
+    }
+    for message in entry.conversation() {
+        messages.push(json!({ "role": message.role, "content": message.content }));
+    }
+
+    json!({ "messages": messages })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::history_entry;
+
+    fn entry(system: Option<&str>) -> HistoryEntry {
+        HistoryEntry {
+            timestamp: DateTime::parse_from_rfc3339("2024-01-02T03:04:05Z")
+                .unwrap()
+                .into(),
+            system: system.map(|s| s.to_string()),
+            prompt: "Summarize this".to_string(),
+            response: "A summary".to_string(),
+            ..history_entry(3)
+        }
+    }
+
+    #[test]
+    fn add_and_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::at(dir.path().join("history.jsonl"));
+        assert_eq!(history.entries().unwrap(), vec![]);
+
+        let first = history
+            .add(HistoryEntry::new(
+                "summarize",
+                "gpt-4o",
+                Some("Be brief"),
+                "a",
+                "b",
+            ))
+            .unwrap();
+        let second = history
+            .add(HistoryEntry::new("summarize", "gpt-4o", None, "c", "d"))
+            .unwrap();
+        assert_eq!((first, second), (1, 2));
+
+        let entry = history.get(2).unwrap();
+        assert_eq!(entry.prompt, "c");
+        assert_eq!(entry.system, None);
+        assert_eq!(history.get(1).unwrap().system.as_deref(), Some("Be brief"));
+
+        let err = history.get(5).unwrap_err();
+        assert!(matches!(
+            err.current_context(),
+            Error::HistoryEntryNotFound(5)
+        ));
+    }
+
+    #[test]
+    fn set_score() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::at(dir.path().join("history.jsonl"));
+        history
+            .add(HistoryEntry::new("summarize", "gpt-4o", None, "a", "b"))
+            .unwrap();
+        history
+            .add(HistoryEntry::new("summarize", "gpt-4o", None, "c", "d"))
+            .unwrap();
+
+        history.set_score(2, "rating", 4.0).unwrap();
+        history.set_score(2, "rating", 5.0).unwrap();
+
+        let entries = history.entries().unwrap();
+        assert!(entries[0].scores.is_empty());
+        assert_eq!(entries[1].scores.get("rating"), Some(&5.0));
+        assert_eq!(entries[1].response, "d");
+
+        let err = history.set_score(3, "rating", 1.0).unwrap_err();
+        assert!(matches!(
+            err.current_context(),
+            Error::HistoryEntryNotFound(3)
+        ));
+    }
+
+    fn invocation(args: &[&str]) -> Invocation {
+        Invocation {
+            directory: PathBuf::from("/project"),
+            args: args.iter().map(|s| s.to_string()).collect(),
+            stdin: None,
+        }
+    }
+
+    #[test]
+    fn last_invocation() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::at(dir.path().join("history.jsonl"));
+        assert_eq!(history.last_invocation().unwrap(), None);
+
+        let mut first = invocation(&["run", "summarize", "--file", "a.txt"]);
+        first.stdin = Some("piped text".to_string());
+        let mut entry = HistoryEntry::new("summarize", "gpt-4o", None, "a", "b");
+        entry.invocation = Some(first.clone());
+        history.add(entry).unwrap();
+        // Runs without an invocation, such as those saved by older versions, are skipped.
+        history
+            .add(HistoryEntry::new("summarize", "gpt-4o", None, "c", "d"))
+            .unwrap();
+
+        let (id, last) = history.last_invocation().unwrap().unwrap();
+        assert_eq!(id, 1);
+        assert_eq!(last, first);
+        assert_eq!(last.template(), "summarize");
+    }
+
+    #[test]
+    fn replace_model() {
+        let replaced = |args: &[&str]| invocation(args).with_model("llama3").args;
+
+        assert_eq!(
+            replaced(&["run", "summarize", "--model", "gpt-4o", "-v"]),
+            ["run", "summarize", "-v", "--model", "llama3"]
+        );
+        assert_eq!(
+            replaced(&["run", "summarize", "--model=gpt-4o", "-mgpt-4", "-m", "x"]),
+            ["run", "summarize", "--model", "llama3"]
+        );
+        assert_eq!(
+            replaced(&["run", "summarize", "text", "--", "-m", "more"]),
+            [
+                "run",
+                "summarize",
+                "text",
+                "--model",
+                "llama3",
+                "--",
+                "-m",
+                "more"
+            ]
+        );
+    }
+
+    #[test]
+    fn search_text() {
+        let entry = entry(Some("Be brief"));
+        assert!(entry.contains_text("summarize THIS"));
+        assert!(entry.contains_text("brief"));
+        assert!(entry.contains_text("a summary"));
+        assert!(!entry.contains_text("missing"));
+    }
+
+    #[test]
+    fn run_details() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::at(dir.path().join("history.jsonl"));
+
+        // Entries saved before the details were recorded can still be read.
+        std::fs::write(
+            dir.path().join("history.jsonl"),
+            concat!(
+                r#"{"id":1,"timestamp":"2024-01-02T03:04:05Z","template":"t","model":"m","prompt":"p","response":"r"}"#,
+                "\n"
+            ),
+        )
+        .unwrap();
+
+        let mut entry = HistoryEntry::new("summarize", "gpt-4o", None, "a", "b");
+        entry.parameters = Some(RunParameters {
+            temperature: 0.5,
+            top_k: None,
+            top_p: Some(0.9),
+            frequency_penalty: None,
+            presence_penalty: None,
+            max_tokens: None,
+            stop: vec![],
+            seed: Some(7),
+            logit_bias: BTreeMap::from([("50256".to_string(), -100.0)]),
+        });
+        entry.tokens = Some(TokenCounts {
+            prompt: 10,
+            response: 20,
+        });
+        entry.duration_ms = Some(1500);
+        assert_eq!(history.add(entry.clone()).unwrap(), 2);
+
+        let entries = history.entries().unwrap();
+        assert_eq!(entries[0].tokens, None);
+        assert_eq!(entries[1].parameters, entry.parameters);
+        assert_eq!(entries[1].tokens, entry.tokens);
+        assert_eq!(entries[1].duration_ms, Some(1500));
+    }
+
+    #[test]
+    fn continued_conversation() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::at(dir.path().join("history.jsonl"));
+        assert_eq!(history.last_run_of("summarize").unwrap(), None);
+
+        history.add(entry(None)).unwrap();
+        let mut follow_up = HistoryEntry::new("summarize", "gpt-4o", None, "Shorter", "Summary");
+        follow_up.earlier_messages = entry(None).conversation();
+        history.add(follow_up).unwrap();
+        history
+            .add(HistoryEntry::new("title", "gpt-4o", None, "a", "b"))
+            .unwrap();
+
+        let last = history.last_run_of("summarize").unwrap().unwrap();
+        assert_eq!(last.id, 2);
+        assert_eq!(
+            last.conversation(),
+            vec![
+                ChatMessage::user("Summarize this"),
+                ChatMessage::assistant("A summary"),
+                ChatMessage::user("Shorter"),
+                ChatMessage::assistant("Summary"),
+            ]
+        );
+
+        let line = r##"{"messages":[{"content":"Summarize this","role":"user"},{"content":"A summary","role":"assistant"},{"content":"Shorter","role":"user"},{"content":"Summary","role":"assistant"}]}"##;
+        assert_eq!(
+            export(&[last], ExportFormat::OpenaiJsonl),
+            format!("{line}\n")
+        );
+    }
+
+    #[test]
+    fn summary() {
+        let mut entry = entry(None);
+        entry.prompt = format!("\n{}\nmore", "a".repeat(70));
+        assert_eq!(
+            entry.summary(),
+            format!(
+                "3\t2024-01-02 03:04\tsummarize\tgpt-4o\t{}...",
+                "a".repeat(60)
+            )
+        );
+    }
+
+    #[test]
+    fn markdown() {
+        let output = export(&[entry(Some("Be brief"))], ExportFormat::Markdown);
+        assert_eq!(
+            output,
+            "# summarize\n\n_Run 3 with gpt-4o at 2024-01-02 03:04:05 UTC_\n\n## System\n\nBe brief\n\n## User\n\nSummarize this\n\n## Assistant\n\nA summary\n"
+        );
+    }
+
+    #[test]
+    fn sharegpt() {
+        let output = export(&[entry(None), entry(None)], ExportFormat::Sharegpt);
+        let line = r##"{"conversations":[{"from":"human","value":"Summarize this"},{"from":"gpt","value":"A summary"}]}"##;
+        assert_eq!(output, format!("{line}\n{line}\n"));
+    }
+
+    #[test]
+    fn openai_jsonl() {
+        let output = export(&[entry(Some("Be brief"))], ExportFormat::OpenaiJsonl);
+        assert_eq!(
+            output,
+            r##"{"messages":[{"content":"Be brief","role":"system"},{"content":"Summarize this","role":"user"},{"content":"A summary","role":"assistant"}]}"##
+                .to_string()
+                + "\n"
+        );
+    }
+}
@@ -0,0 +1,252 @@
+//! Spread requests across multiple endpoints for the same host.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use error_stack::Report;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::{ModelHost, ModelInput};
+use crate::model::{ModelError, ModelOptions};
+
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How to choose between multiple endpoints for a host.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalance {
+    /// Use each endpoint in turn.
+    #[default]
+    RoundRobin,
+    /// Check the health of each endpoint and use the one with the fewest requests in progress.
+    LeastBusy,
+}
+
+/// The number of requests in progress for each endpoint from this process.
+fn in_flight() -> &'static Mutex<HashMap<String, usize>> {
+    static IN_FLIGHT: OnceLock<Mutex<HashMap<String, usize>>> = OnceLock::new();
+    IN_FLIGHT.get_or_init(Default::default)
+}
+
+/// Choose an endpoint from `endpoints`.
+pub fn choose_endpoint(endpoints: &[String], balance: LoadBalance) -> &str {
+    if endpoints.len() == 1 {
+        return &endpoints[0];
+    }
+
+    let index = match balance {
+        LoadBalance::RoundRobin => next_round_robin(endpoints),
+        LoadBalance::LeastBusy => {
+            let in_flight = {
+                let counts = in_flight().lock().unwrap();
+                endpoints
+                    .iter()
+                    .map(|e| counts.get(e).copied().unwrap_or(0))
+                    .collect::<Vec<_>>()
+            };
+
+            let status = std::thread::scope(|scope| {
+                let checks = endpoints
+                    .iter()
+                    .map(|endpoint| scope.spawn(|| check_endpoint(endpoint)))
+                    .collect::<Vec<_>>();
+                checks
+                    .into_iter()
+                    .map(|check| check.join().unwrap_or(EndpointStatus::Down))
+                    .collect::<Vec<_>>()
+            });
+
+            least_busy(&status, &in_flight)
+        }
+    };
+
+    &endpoints[index]
+}
+
+pub(super) fn next_round_robin(endpoints: &[String]) -> usize {
+    static COUNTERS: OnceLock<Mutex<HashMap<Vec<String>, usize>>> = OnceLock::new();
+    let mut counters = COUNTERS.get_or_init(Default::default).lock().unwrap();
+    // Start at a random endpoint so that separate invocations of PromptBox are spread out too.
+    let counter = counters
+        .entry(endpoints.to_vec())
+        .or_insert_with(|| fastrand::usize(0..endpoints.len()));
+    let index = *counter % endpoints.len();
+    *counter = counter.wrapping_add(1);
+    index
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EndpointStatus {
+    Down,
+    /// The endpoint is up. Some servers, such as llama.cpp, also report how many requests they
+    /// are currently processing.
+    Up {
+        processing: Option<usize>,
+    },
+}
+
+#[derive(Deserialize)]
+struct HealthResponse {
+    slots_processing: Option<usize>,
+}
+
+fn check_endpoint(endpoint: &str) -> EndpointStatus {
+    let agent = ureq::AgentBuilder::new()
+        .timeout(HEALTH_CHECK_TIMEOUT)
+        .build();
+    let url = format!("{}/health", endpoint.trim_end_matches('/'));
+    match agent.get(&url).call() {
+        Ok(response) => EndpointStatus::Up {
+            processing: response
+                .into_json::<HealthResponse>()
+                .ok()
+                .and_then(|r| r.slots_processing),
+        },
+        // Any HTTP response means that the server is up, even if it doesn't have a health endpoint.
+        Err(ureq::Error::Status(_, _)) => EndpointStatus::Up { processing: None },
+        Err(ureq::Error::Transport(_)) => EndpointStatus::Down,
+    }
+}
+
+/// Find the endpoint with the lowest load. If every endpoint appears to be down, just pick
+/// the one with the fewest requests from this process and let the request report the error.
+fn least_busy(status: &[EndpointStatus], in_flight: &[usize]) -> usize {
+    let load = |i: usize| match status[i] {
+        EndpointStatus::Up { processing } => Some(processing.unwrap_or(0).max(in_flight[i])),
+        EndpointStatus::Down => None,
+    };
+
+    (0..status.len())
+        .filter_map(|i| load(i).map(|l| (l, i)))
+        .min()
+        .or_else(|| (0..in_flight.len()).map(|i| (in_flight[i], i)).min())
+        .map(|(_, i)| i)
+        .unwrap_or(0)
+}
+
+/// A [ModelHost] which counts its requests against an endpoint for as long as it exists.
+#[derive(Debug)]
+pub struct BalancedHost {
+    inner: Box<dyn ModelHost>,
+    endpoint: String,
+}
+
+impl BalancedHost {
+    pub fn new(inner: Box<dyn ModelHost>, endpoint: String) -> Self {
+        *in_flight()
+            .lock()
+            .unwrap()
+            .entry(endpoint.clone())
+            .or_default() += 1;
+        Self { inner, endpoint }
+    }
+}
+
+impl Drop for BalancedHost {
+    fn drop(&mut self) {
+        if let Some(count) = in_flight().lock().unwrap().get_mut(&self.endpoint) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+impl ModelHost for BalancedHost {
+    fn send_model_request(
+        &self,
+        options: &ModelOptions,
+        input: &ModelInput,
+        message_tx: flume::Sender<String>,
+    ) -> Result<(), Report<ModelError>> {
+        self.inner.send_model_request(options, input, message_tx)
+    }
+
+    fn send_completions_request(
+        &self,
+        options: &ModelOptions,
+        input: &ModelInput,
+        n: usize,
+    ) -> Result<Vec<String>, Report<ModelError>> {
+        self.inner.send_completions_request(options, input, n)
+    }
+
+    fn model_context_limit(&self, model_name: &str) -> Result<Option<usize>, Report<ModelError>> {
+        self.inner.model_context_limit(model_name)
+    }
+
+    fn list_models(&self) -> Result<Vec<String>, Report<ModelError>> {
+        self.inner.list_models()
+    }
+
+    fn moderate(
+        &self,
+        options: &ModelOptions,
+        model: &str,
+        text: &str,
+    ) -> Result<Vec<String>, Report<ModelError>> {
+        self.inner.moderate(options, model, text)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_robin() {
+        let endpoints = vec![
+            "http://rr-test-a".to_string(),
+            "http://rr-test-b".to_string(),
+            "http://rr-test-c".to_string(),
+        ];
+
+        let first = choose_endpoint(&endpoints, LoadBalance::RoundRobin);
+        let start = endpoints.iter().position(|e| e == first).unwrap();
+        for i in 1..6 {
+            assert_eq!(
+                choose_endpoint(&endpoints, LoadBalance::RoundRobin),
+                endpoints[(start + i) % 3]
+            );
+        }
+    }
+
+    #[test]
+    fn least_busy_skips_down_endpoints() {
+        let status = [
+            EndpointStatus::Down,
+            EndpointStatus::Up {
+                processing: Some(3),
+            },
+            EndpointStatus::Up { processing: None },
+        ];
+        assert_eq!(least_busy(&status, &[0, 0, 1]), 2);
+        assert_eq!(least_busy(&status, &[0, 0, 4]), 1);
+    }
+
+    #[test]
+    fn least_busy_all_down() {
+        let status = [EndpointStatus::Down, EndpointStatus::Down];
+        assert_eq!(least_busy(&status, &[2, 1]), 1);
+    }
+
+    #[test]
+    fn in_flight_count() {
+        let endpoint = "http://in-flight-test".to_string();
+        let count = || in_flight().lock().unwrap().get(&endpoint).copied();
+
+        let host = BalancedHost::new(
+            Box::new(super::super::ollama::OllamaHost::new(
+                ureq::agent(),
+                Some(endpoint.clone()),
+                None,
+            )),
+            endpoint.clone(),
+        );
+        assert_eq!(count(), Some(1));
+        drop(host);
+        assert_eq!(count(), Some(0));
+    }
+}
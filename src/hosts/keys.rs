@@ -0,0 +1,339 @@
+//! Spread requests across multiple API keys for the same host.
+
+use std::{
+    collections::HashSet,
+    sync::{Mutex, OnceLock},
+};
+
+use error_stack::Report;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::{balance::next_round_robin, ModelHost, ModelInput};
+use crate::model::{ModelError, ModelOptions};
+
+/// How to choose between multiple API keys for a host. With either strategy, a key that is
+/// rejected or rate limited is skipped in favor of the next one.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyRotation {
+    /// Use each key in turn.
+    #[default]
+    RoundRobin,
+    /// Use the first key until it is rejected or rate limited, and then move on to the next one.
+    Failover,
+}
+
+/// Keys which were rejected or rate limited by the host during this process, by the name of their
+/// environment variable.
+fn failed_keys() -> &'static Mutex<HashSet<String>> {
+    static FAILED: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+    FAILED.get_or_init(Default::default)
+}
+
+/// Returns true if the error means that another key might succeed.
+fn should_try_next_key(err: &ModelError) -> bool {
+    matches!(err, ModelError::Model(401 | 403 | 429, _))
+}
+
+/// A [ModelHost] which sends each request with one of several API keys, moving on to the next key
+/// when the host rejects a key or rate limits it.
+#[derive(Debug)]
+pub struct RotatingKeyHost {
+    /// The name of the environment variable for each key, and a host which uses that key
+    hosts: Vec<(String, Box<dyn ModelHost>)>,
+    rotation: KeyRotation,
+}
+
+impl RotatingKeyHost {
+    pub fn new(hosts: Vec<(String, Box<dyn ModelHost>)>, rotation: KeyRotation) -> Self {
+        Self { hosts, rotation }
+    }
+
+    /// Each key in order, except that keys which have already failed come last, in case they
+    /// work again.
+    fn failover_order(&self) -> Vec<usize> {
+        let failed = failed_keys().lock().unwrap();
+        let (mut order, failed): (Vec<_>, Vec<_>) =
+            (0..self.hosts.len()).partition(|i| !failed.contains(&self.hosts[*i].0));
+        order.extend(failed);
+        order
+    }
+
+    /// The order in which to try the keys for the next request.
+    fn key_order(&self) -> Vec<usize> {
+        match self.rotation {
+            KeyRotation::RoundRobin => {
+                let names = self
+                    .hosts
+                    .iter()
+                    .map(|(name, _)| name.clone())
+                    .collect::<Vec<_>>();
+                let start = next_round_robin(&names);
+                (0..names.len())
+                    .map(|i| (start + i) % names.len())
+                    .collect()
+            }
+            KeyRotation::Failover => self.failover_order(),
+        }
+    }
+
+    /// Send a request with each key in turn until one of them is accepted.
+    fn with_key<T: Default>(
+        &self,
+        options: &ModelOptions,
+        send: impl Fn(&dyn ModelHost, &ModelOptions) -> Result<T, Report<ModelError>>,
+    ) -> Result<T, Report<ModelError>> {
+        // Move on to the next key right away instead of waiting out a rate limit, unless there
+        // are no keys left to try.
+        let mut next_key_options = options.clone();
+        next_key_options.retry.retry_on.retain(|code| *code != 429);
+
+        let order = self.key_order();
+        for (attempt, index) in order.iter().enumerate() {
+            let (name, host) = &self.hosts[*index];
+            let is_last = attempt == order.len() - 1;
+            let options = if is_last { options } else { &next_key_options };
+
+            let err = match send(host.as_ref(), options) {
+                Ok(result) => return Ok(result),
+                Err(err) => err,
+            };
+
+            if is_last || !should_try_next_key(err.current_context()) {
+                return Err(err);
+            }
+
+            failed_keys().lock().unwrap().insert(name.clone());
+            let next_name = &self.hosts[order[attempt + 1]].0;
+            eprintln!(
+                "Request with the key from {name} failed: {}. Trying the key from {next_name}",
+                err.current_context()
+            );
+        }
+
+        Ok(T::default())
+    }
+}
+
+impl ModelHost for RotatingKeyHost {
+    fn send_model_request(
+        &self,
+        options: &ModelOptions,
+        input: &ModelInput,
+        message_tx: flume::Sender<String>,
+    ) -> Result<(), Report<ModelError>> {
+        self.with_key(options, |host, options| {
+            host.send_model_request(options, input, message_tx.clone())
+        })
+    }
+
+    fn send_completions_request(
+        &self,
+        options: &ModelOptions,
+        input: &ModelInput,
+        n: usize,
+    ) -> Result<Vec<String>, Report<ModelError>> {
+        self.with_key(options, |host, options| {
+            host.send_completions_request(options, input, n)
+        })
+    }
+
+    fn model_context_limit(&self, model_name: &str) -> Result<Option<usize>, Report<ModelError>> {
+        // This doesn't count as a request for round robin, so that requests still alternate.
+        let order = self.failover_order();
+        for (attempt, index) in order.iter().enumerate() {
+            let result = self.hosts[*index].1.model_context_limit(model_name);
+            match result {
+                Err(err)
+                    if attempt < order.len() - 1 && should_try_next_key(err.current_context()) =>
+                {
+                    continue
+                }
+                result => return result,
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn list_models(&self) -> Result<Vec<String>, Report<ModelError>> {
+        let order = self.failover_order();
+        for (attempt, index) in order.iter().enumerate() {
+            match self.hosts[*index].1.list_models() {
+                Err(err)
+                    if attempt < order.len() - 1 && should_try_next_key(err.current_context()) =>
+                {
+                    continue
+                }
+                result => return result,
+            }
+        }
+
+        Ok(Vec::new())
+    }
+
+    fn moderate(
+        &self,
+        options: &ModelOptions,
+        model: &str,
+        text: &str,
+    ) -> Result<Vec<String>, Report<ModelError>> {
+        self.with_key(options, |host, options| host.moderate(options, model, text))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::Arc};
+
+    use crate::{events::RunEvents, shutdown::CancellationToken, usage::UsageMeter};
+
+    use super::*;
+
+    /// The key used for each request, and the status codes that would have been retried
+    type Calls = Arc<Mutex<Vec<(String, Vec<u16>)>>>;
+
+    /// A host which records the keys that were used, and fails with a status code for some keys.
+    #[derive(Debug)]
+    struct FakeHost {
+        key: String,
+        status: Option<u16>,
+        calls: Calls,
+    }
+
+    impl ModelHost for FakeHost {
+        fn send_model_request(
+            &self,
+            options: &ModelOptions,
+            _input: &ModelInput,
+            message_tx: flume::Sender<String>,
+        ) -> Result<(), Report<ModelError>> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push((self.key.clone(), options.retry.retry_on.clone()));
+            match self.status {
+                Some(status) => Err(Report::new(ModelError::Model(status, String::new()))),
+                None => {
+                    message_tx.send(self.key.clone()).unwrap();
+                    Ok(())
+                }
+            }
+        }
+
+        fn model_context_limit(&self, _: &str) -> Result<Option<usize>, Report<ModelError>> {
+            Ok(None)
+        }
+
+        fn list_models(&self) -> Result<Vec<String>, Report<ModelError>> {
+            Ok(vec![self.key.clone()])
+        }
+    }
+
+    fn host(
+        prefix: &str,
+        keys: &[(&str, Option<u16>)],
+        rotation: KeyRotation,
+    ) -> (RotatingKeyHost, Calls) {
+        let calls = Calls::default();
+        let hosts = keys
+            .iter()
+            .map(|(key, status)| {
+                let name = format!("{prefix}_{key}");
+                let host: Box<dyn ModelHost> = Box::new(FakeHost {
+                    key: name.clone(),
+                    status: *status,
+                    calls: calls.clone(),
+                });
+                (name, host)
+            })
+            .collect();
+        (RotatingKeyHost::new(hosts, rotation), calls)
+    }
+
+    fn send(host: &RotatingKeyHost) -> Result<String, Report<ModelError>> {
+        let tools = HashMap::new();
+        let input = ModelInput {
+            prompt: "hi",
+            system: None,
+            history: &[],
+            images: Vec::new(),
+            tools: &tools,
+            cancel: &CancellationToken::new(),
+            usage: &UsageMeter::new(),
+            events: RunEvents::none(),
+        };
+        let (tx, rx) = flume::unbounded();
+        host.send_model_request(&ModelOptions::default(), &input, tx)?;
+        Ok(rx.try_iter().collect())
+    }
+
+    #[test]
+    fn round_robin() {
+        let (host, _) = host(
+            "RR",
+            &[("A", None), ("B", None), ("C", None)],
+            KeyRotation::RoundRobin,
+        );
+
+        let first = send(&host).unwrap();
+        let keys = ["RR_A", "RR_B", "RR_C"];
+        let start = keys.iter().position(|k| *k == first).unwrap();
+        for i in 1..6 {
+            assert_eq!(send(&host).unwrap(), keys[(start + i) % 3]);
+        }
+    }
+
+    #[test]
+    fn failover() {
+        let (host, calls) = host(
+            "FAILOVER",
+            &[("A", Some(429)), ("B", Some(401)), ("C", None)],
+            KeyRotation::Failover,
+        );
+
+        assert_eq!(send(&host).unwrap(), "FAILOVER_C");
+        let used = calls.lock().unwrap().drain(..).collect::<Vec<_>>();
+        assert_eq!(
+            used.iter().map(|(key, _)| key.as_str()).collect::<Vec<_>>(),
+            ["FAILOVER_A", "FAILOVER_B", "FAILOVER_C"]
+        );
+        // Rate limits aren't retried with the same key while there's another one to try.
+        assert!(!used[0].1.contains(&429));
+        assert!(used[0].1.contains(&500));
+
+        // Keys that failed are tried last from now on.
+        assert_eq!(send(&host).unwrap(), "FAILOVER_C");
+        assert_eq!(calls.lock().unwrap()[0].0, "FAILOVER_C");
+    }
+
+    #[test]
+    fn all_keys_fail() {
+        let (host, calls) = host(
+            "ALL_FAIL",
+            &[("A", Some(429)), ("B", Some(429))],
+            KeyRotation::Failover,
+        );
+
+        let err = send(&host).unwrap_err();
+        assert!(matches!(err.current_context(), ModelError::Model(429, _)));
+        let used = calls.lock().unwrap();
+        assert_eq!(used.len(), 2);
+        // The last key waits out the rate limit as usual.
+        assert!(used[1].1.contains(&429));
+    }
+
+    #[test]
+    fn other_errors_do_not_change_keys() {
+        let (host, calls) = host(
+            "OTHER",
+            &[("A", Some(500)), ("B", None)],
+            KeyRotation::Failover,
+        );
+
+        let err = send(&host).unwrap_err();
+        assert!(matches!(err.current_context(), ModelError::Model(500, _)));
+        assert_eq!(calls.lock().unwrap().len(), 1);
+    }
+}
@@ -1,39 +1,229 @@
-use std::collections::HashMap;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
 use error_stack::Report;
+use schemars::JsonSchema;
 use serde::Deserialize;
 
 use crate::{
     error::Error,
+    events::{RunEvent, RunEvents},
     image::ImageData,
-    model::{ModelError, ModelOptions},
+    model::{AgentCache, ModelError, ModelOptions},
     option::{overwrite_from_option, overwrite_option_from_option},
+    requests::{create_agent, expand_header_value, AgentOptions},
+    session::ChatMessage,
+    shutdown::CancellationToken,
+    tools::ToolDefinition,
+    usage::UsageMeter,
 };
 
+pub use balance::LoadBalance;
+pub use keys::KeyRotation;
+pub use providers::ProvidersInput;
+pub use rules::{HostRule, HostRuleInput};
+
+mod balance;
+mod keys;
 pub mod ollama;
 pub mod openai;
+mod providers;
+mod rules;
 mod together;
 
 #[derive(Debug)]
 pub struct ModelInput<'a> {
     pub prompt: &'a str,
     pub system: Option<&'a str>,
+    /// Earlier messages in the conversation, oldest first
+    pub history: &'a [ChatMessage],
     pub images: Vec<ImageData>,
+    /// Tools that the model may call
+    pub tools: &'a HashMap<String, ToolDefinition>,
+    /// Stops the request and any tools it is running when cancelled
+    pub cancel: &'a CancellationToken,
+    /// Collects the token usage that the host reports
+    pub usage: &'a UsageMeter,
+    /// Receives the requests and tool calls made for the prompt
+    pub events: &'a RunEvents,
+}
+
+impl ModelInput<'_> {
+    /// The prompt preceded by a transcript of the earlier messages, for hosts that take a single
+    /// prompt instead of a list of messages.
+    pub fn prompt_with_history(&self) -> Cow<'_, str> {
+        if self.history.is_empty() {
+            return Cow::Borrowed(self.prompt);
+        }
+
+        let mut text = String::new();
+        for message in self.history {
+            text.push_str(&format!(
+                "{}: {}\n\n",
+                message.role.label(),
+                message.content
+            ));
+        }
+        text.push_str(&format!("User: {}", self.prompt));
+        Cow::Owned(text)
+    }
 }
 
 pub trait ModelHost: std::fmt::Debug {
     fn send_model_request(
         &self,
         options: &ModelOptions,
-        input: ModelInput,
+        input: &ModelInput,
         message_tx: flume::Sender<String>,
     ) -> Result<(), Report<ModelError>>;
 
+    /// Get `n` separate responses to the same prompt. By default the request is sent once for
+    /// each response, for hosts that can't ask for several at once.
+    fn send_completions_request(
+        &self,
+        options: &ModelOptions,
+        input: &ModelInput,
+        n: usize,
+    ) -> Result<Vec<String>, Report<ModelError>> {
+        send_sequential_completions(self, options, input, n)
+    }
+
     fn model_context_limit(&self, model_name: &str) -> Result<Option<usize>, Report<ModelError>>;
+
+    /// List the models that the host serves. This is a cheap request, so it also works to check
+    /// that the host is reachable and accepts the API key.
+    fn list_models(&self) -> Result<Vec<String>, Report<ModelError>>;
+
+    /// Check the text against the host's content policy, and return the categories that it was
+    /// flagged for.
+    fn moderate(
+        &self,
+        _options: &ModelOptions,
+        _model: &str,
+        _text: &str,
+    ) -> Result<Vec<String>, Report<ModelError>> {
+        Err(Report::new(ModelError::HostDoesNotSupportModeration))
+    }
+}
+
+/// Send the request `n` times, and return each response.
+pub fn send_sequential_completions(
+    host: &(impl ModelHost + ?Sized),
+    options: &ModelOptions,
+    input: &ModelInput,
+    n: usize,
+) -> Result<Vec<String>, Report<ModelError>> {
+    let mut responses = Vec::with_capacity(n);
+    for _ in 0..n {
+        if input.cancel.is_cancelled() {
+            break;
+        }
+
+        let (message_tx, message_rx) = flume::unbounded();
+        host.send_model_request(options, input, message_tx)?;
+        responses.push(message_rx.drain().collect());
+    }
+
+    Ok(responses)
+}
+
+/// Send a request to the model. If the request fails in a way that another model might not,
+/// move on to the next model in the fallback chain. Once part of the response has been sent on
+/// `message_tx` there is no fallback, since the next model's response would be added to it.
+///
+/// Returns the options of the fallback model that answered, or `None` if the first model did.
+pub fn send_model_request(
+    options: &ModelOptions,
+    input: &ModelInput,
+    message_tx: flume::Sender<String>,
+) -> Result<Option<ModelOptions>, Report<Error>> {
+    let streamed = AtomicBool::new(false);
+    let send = |host: &dyn ModelHost, options: &ModelOptions| {
+        let (attempt_tx, attempt_rx) = flume::unbounded();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for message in attempt_rx.iter() {
+                    streamed.store(true, Ordering::Relaxed);
+                    if message_tx.send(message).is_err() {
+                        break;
+                    }
+                }
+            });
+            host.send_model_request(options, input, attempt_tx)
+        })
+    };
+
+    with_fallback(options, input, || streamed.load(Ordering::Relaxed), send)
+        .map(|((), fallback)| fallback)
+}
+
+/// Get `n` responses to the prompt, moving on through the fallback chain like
+/// [send_model_request], along with the options of the fallback model that answered.
+pub fn send_completions_request(
+    options: &ModelOptions,
+    input: &ModelInput,
+    n: usize,
+) -> Result<(Vec<String>, Option<ModelOptions>), Report<Error>> {
+    // The responses are only returned once they are all done, so the fallback is always possible.
+    with_fallback(
+        options,
+        input,
+        || false,
+        |host, options| host.send_completions_request(options, input, n),
+    )
+}
+
+/// Send the request with each model in the chain until one succeeds. `output_started` returns
+/// true once some of the response has been passed on, after which a failure is returned instead
+/// of trying the next model. Along with the result, this returns the options of the fallback
+/// model that answered, or `None` if the first model did.
+fn with_fallback<T: Default>(
+    options: &ModelOptions,
+    input: &ModelInput,
+    output_started: impl Fn() -> bool,
+    send: impl Fn(&dyn ModelHost, &ModelOptions) -> Result<T, Report<ModelError>>,
+) -> Result<(T, Option<ModelOptions>), Report<Error>> {
+    let mut chain = options.model_chain().into_iter().enumerate().peekable();
+    while let Some((index, options)) = chain.next() {
+        let host = options.api_host()?;
+        input.events.emit(RunEvent::RequestStarted {
+            model: options.full_model_spec().model_name(),
+            host: &options.host_name(),
+        });
+        let err = match send(host.as_ref(), &options) {
+            Ok(result) => return Ok((result, (index > 0).then_some(options))),
+            Err(err) => err,
+        };
+
+        let can_fallback = err.current_context().should_fallback() && !input.cancel.is_cancelled();
+        match chain.peek() {
+            Some(_) if can_fallback && output_started() => {
+                return Err(err.change_context(Error::RunPrompt).attach_printable(
+                    "The response had already started, so the fallback models were not tried",
+                ));
+            }
+            Some((_, next)) if can_fallback => {
+                eprintln!(
+                    "Request to {} failed: {}. Trying {}",
+                    options.full_model_spec().model_name(),
+                    err.current_context(),
+                    next.full_model_spec().model_name()
+                );
+            }
+            _ => return Err(err.change_context(Error::RunPrompt)),
+        }
+    }
+
+    Ok((T::default(), None))
 }
 
 /// An API definition to talk to a host send prompts to it.
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum HostProtocol {
     Ollama,
@@ -58,41 +248,227 @@ impl HostProtocol {
 #[derive(Deserialize, Debug, Clone)]
 pub struct HostDefinition {
     pub endpoint: String,
+    /// Multiple endpoints that serve the same models. If this is not empty, requests are spread
+    /// across these endpoints instead of using `endpoint`.
+    pub endpoints: Vec<String>,
+    /// How to choose an endpoint when there are multiple
+    pub balance: LoadBalance,
     pub protocol: HostProtocol,
     pub limit_context_length: bool,
     /// The environment variable that holds the authentication token for this host
     pub api_key: Option<String>,
+    /// Multiple environment variables that hold authentication tokens. If this is not empty,
+    /// requests are spread across these keys instead of using `api_key`.
+    pub api_keys: Vec<String>,
+    /// How to choose a key when there are multiple
+    pub key_rotation: KeyRotation,
     /// If true, send "promptbox" in the `user` field of the request. Some hosts
     /// reject the request if this field exists, so it can be disabled by setting this
     /// to false.
     pub send_app_id: bool,
+    /// A proxy to use for this host, instead of the one from the environment. Set to an empty string
+    /// to disable the proxy.
+    pub proxy: Option<String>,
+    /// A PEM file with extra CA certificates to trust when connecting to this host.
+    pub ca_bundle: Option<PathBuf>,
+    /// Skip TLS certificate verification. Useful for self-hosted endpoints with self-signed
+    /// certificates, but otherwise a bad idea.
+    pub accept_invalid_certs: bool,
+    /// Extra HTTP headers to send to this host. Values can reference environment variables
+    /// as `${VAR}`.
+    pub headers: HashMap<String, String>,
+    /// The maximum number of idle connections to keep open for reuse.
+    pub max_idle_connections: Option<usize>,
+    /// The maximum number of idle connections to keep open to each endpoint.
+    pub max_idle_connections_per_host: Option<usize>,
+}
+
+/// A host that uses one particular endpoint and API key, from [HostDefinition::connections].
+#[derive(Debug)]
+pub struct HostConnection {
+    pub endpoint: String,
+    /// The environment variable that holds the API key, if the host uses one
+    pub key_name: Option<String>,
+    pub host: Box<dyn ModelHost>,
 }
 
 impl HostDefinition {
     /// Create a ModelHost from this HostDefinition
-    pub fn into_model_host(&self) -> Box<dyn ModelHost> {
-        let key = self
-            .api_key
-            .as_ref()
-            .and_then(|var_name| std::env::var(var_name).ok());
-        let endpoint = self.endpoint.clone();
+    pub fn into_model_host(
+        &self,
+        name: &str,
+        agents: &AgentCache,
+        timeout: Option<Duration>,
+        connect_timeout: Option<Duration>,
+    ) -> Result<Box<dyn ModelHost>, Error> {
+        let endpoint = if self.endpoints.is_empty() {
+            self.endpoint.clone()
+        } else {
+            balance::choose_endpoint(&self.endpoints, self.balance).to_string()
+        };
+
+        let agent = self.agent(name, agents, &endpoint, timeout, connect_timeout)?;
+
+        let keys = self.keys(|var| std::env::var(var).ok());
+        let host = if keys.len() > 1 {
+            let hosts = keys
+                .into_iter()
+                .map(|(name, key)| (name, self.host_with_key(&agent, &endpoint, Some(key))))
+                .collect();
+            Box::new(keys::RotatingKeyHost::new(hosts, self.key_rotation))
+        } else {
+            self.host_with_key(
+                &agent,
+                &endpoint,
+                keys.into_iter().next().map(|(_, key)| key),
+            )
+        };
+
+        if self.endpoints.len() > 1 {
+            Ok(Box::new(balance::BalancedHost::new(host, endpoint)))
+        } else {
+            Ok(host)
+        }
+    }
+
+    /// A separate ModelHost for every combination of endpoint and API key, so that each one can be
+    /// checked on its own. The keys are read with `get_env`, and keys whose environment variables
+    /// are not set are skipped.
+    pub fn connections(
+        &self,
+        name: &str,
+        agents: &AgentCache,
+        timeout: Option<Duration>,
+        get_env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Vec<HostConnection>, Error> {
+        let endpoints = if self.endpoints.is_empty() {
+            std::slice::from_ref(&self.endpoint)
+        } else {
+            self.endpoints.as_slice()
+        };
+
+        let keys = self.keys(get_env);
+        let mut connections = Vec::new();
+        for endpoint in endpoints {
+            let agent = self.agent(name, agents, endpoint, timeout, timeout)?;
+            if keys.is_empty() {
+                connections.push(HostConnection {
+                    endpoint: endpoint.clone(),
+                    key_name: None,
+                    host: self.host_with_key(&agent, endpoint, None),
+                });
+            }
+
+            for (key_name, key) in &keys {
+                connections.push(HostConnection {
+                    endpoint: endpoint.clone(),
+                    key_name: Some(key_name.clone()),
+                    host: self.host_with_key(&agent, endpoint, Some(key.clone())),
+                });
+            }
+        }
+
+        Ok(connections)
+    }
+
+    fn agent(
+        &self,
+        name: &str,
+        agents: &AgentCache,
+        endpoint: &str,
+        timeout: Option<Duration>,
+        connect_timeout: Option<Duration>,
+    ) -> Result<ureq::Agent, Error> {
+        agents.get(name, endpoint, timeout, connect_timeout, || {
+            create_agent(
+                endpoint,
+                &AgentOptions {
+                    timeout,
+                    connect_timeout,
+                    proxy: self.proxy.as_deref(),
+                    ca_bundle: self.ca_bundle.as_deref(),
+                    accept_invalid_certs: self.accept_invalid_certs,
+                    headers: self
+                        .headers
+                        .iter()
+                        .map(|(name, value)| {
+                            let value = expand_header_value(value, |var| std::env::var(var).ok())?;
+                            Ok((name.clone(), value))
+                        })
+                        .collect::<Result<_, Error>>()?,
+                    max_idle_connections: self.max_idle_connections,
+                    max_idle_connections_per_host: self.max_idle_connections_per_host,
+                },
+            )
+        })
+    }
+
+    fn host_with_key(
+        &self,
+        agent: &ureq::Agent,
+        endpoint: &str,
+        key: Option<String>,
+    ) -> Box<dyn ModelHost> {
+        let agent = agent.clone();
         match self.protocol {
-            HostProtocol::Ollama => Box::new(ollama::OllamaHost::new(Some(endpoint), key)),
+            HostProtocol::Ollama => Box::new(ollama::OllamaHost::new(
+                agent,
+                Some(endpoint.to_string()),
+                key,
+            )),
             HostProtocol::OpenAi => Box::new(openai::OpenAiHost::new(
-                Some(endpoint),
+                agent,
+                Some(endpoint.to_string()),
                 key,
                 self.limit_context_length,
                 self.send_app_id,
             )),
-            HostProtocol::Together => Box::new(together::TogetherHost::new(endpoint, key)),
+            HostProtocol::Together => Box::new(together::TogetherHost::new(
+                agent,
+                endpoint.to_string(),
+                key,
+            )),
+        }
+    }
+
+    /// The names of the environment variables that hold the API keys for this host.
+    pub fn key_names(&self) -> &[String] {
+        if self.api_keys.is_empty() {
+            self.api_key.as_slice()
+        } else {
+            &self.api_keys
         }
     }
 
+    /// The API keys to use for this host, as the name of each environment variable and its value.
+    /// Variables which are not set are skipped.
+    fn keys(&self, get_env: impl Fn(&str) -> Option<String>) -> Vec<(String, String)> {
+        self.key_names()
+            .iter()
+            .filter_map(|name| get_env(name).map(|key| (name.clone(), key)))
+            .collect()
+    }
+
     pub fn merge_from_input(&mut self, other: &HostDefinitionInput) {
         overwrite_from_option(&mut self.endpoint, &other.endpoint);
+        overwrite_from_option(&mut self.endpoints, &other.endpoints);
+        overwrite_from_option(&mut self.balance, &other.balance);
         overwrite_from_option(&mut self.protocol, &other.protocol);
         overwrite_option_from_option(&mut self.api_key, &other.api_key);
+        overwrite_from_option(&mut self.api_keys, &other.api_keys);
+        overwrite_from_option(&mut self.key_rotation, &other.key_rotation);
         overwrite_from_option(&mut self.limit_context_length, &other.limit_context_length);
+        overwrite_option_from_option(&mut self.proxy, &other.proxy);
+        overwrite_option_from_option(&mut self.ca_bundle, &other.ca_bundle);
+        overwrite_from_option(&mut self.accept_invalid_certs, &other.accept_invalid_certs);
+        if let Some(headers) = other.headers.as_ref() {
+            self.headers.extend(headers.clone());
+        }
+        overwrite_option_from_option(&mut self.max_idle_connections, &other.max_idle_connections);
+        overwrite_option_from_option(
+            &mut self.max_idle_connections_per_host,
+            &other.max_idle_connections_per_host,
+        );
     }
 
     pub fn default_host() -> &'static str {
@@ -102,84 +478,184 @@ impl HostDefinition {
     /// A set of built-in providers
     pub fn builtin() -> HashMap<String, HostDefinition> {
         [
+            (
+                "anthropic".to_string(),
+                HostDefinition {
+                    endpoint: "https://api.anthropic.com/v1".to_string(),
+                    endpoints: Vec::new(),
+                    balance: LoadBalance::RoundRobin,
+                    protocol: HostProtocol::OpenAi,
+                    limit_context_length: false,
+                    api_key: Some("ANTHROPIC_API_KEY".to_string()),
+                    api_keys: Vec::new(),
+                    key_rotation: KeyRotation::RoundRobin,
+                    send_app_id: false,
+                    proxy: None,
+                    ca_bundle: None,
+                    accept_invalid_certs: false,
+                    headers: HashMap::new(),
+                    max_idle_connections: None,
+                    max_idle_connections_per_host: None,
+                },
+            ),
             (
                 "anyscale".to_string(),
                 HostDefinition {
                     endpoint: "https://api.endpoints.anyscale.com/v1".to_string(),
+                    endpoints: Vec::new(),
+                    balance: LoadBalance::RoundRobin,
                     protocol: HostProtocol::OpenAi,
                     limit_context_length: false,
                     api_key: Some("ANYSCALE_API_KEY".to_string()),
+                    api_keys: Vec::new(),
+                    key_rotation: KeyRotation::RoundRobin,
                     send_app_id: true,
+                    proxy: None,
+                    ca_bundle: None,
+                    accept_invalid_certs: false,
+                    headers: HashMap::new(),
+                    max_idle_connections: None,
+                    max_idle_connections_per_host: None,
                 },
             ),
             (
                 "deepinfra".to_string(),
                 HostDefinition {
                     endpoint: "https://api.deepinfra.com/v1/openai".to_string(),
+                    endpoints: Vec::new(),
+                    balance: LoadBalance::RoundRobin,
                     protocol: HostProtocol::OpenAi,
                     limit_context_length: false,
                     api_key: Some("DEEPINFRA_API_KEY".to_string()),
+                    api_keys: Vec::new(),
+                    key_rotation: KeyRotation::RoundRobin,
                     send_app_id: true,
+                    proxy: None,
+                    ca_bundle: None,
+                    accept_invalid_certs: false,
+                    headers: HashMap::new(),
+                    max_idle_connections: None,
+                    max_idle_connections_per_host: None,
                 },
             ),
             (
                 "fireworks".to_string(),
                 HostDefinition {
                     endpoint: "https://api.fireworks.ai/inference/v1".to_string(),
+                    endpoints: Vec::new(),
+                    balance: LoadBalance::RoundRobin,
                     protocol: HostProtocol::OpenAi,
                     limit_context_length: false,
                     api_key: Some("FIREWORKS_API_KEY".to_string()),
+                    api_keys: Vec::new(),
+                    key_rotation: KeyRotation::RoundRobin,
                     send_app_id: false,
+                    proxy: None,
+                    ca_bundle: None,
+                    accept_invalid_certs: false,
+                    headers: HashMap::new(),
+                    max_idle_connections: None,
+                    max_idle_connections_per_host: None,
                 },
             ),
             (
                 "lm-studio".to_string(),
                 HostDefinition {
                     endpoint: "http://localhost:1234".to_string(),
+                    endpoints: Vec::new(),
+                    balance: LoadBalance::RoundRobin,
                     protocol: HostProtocol::OpenAi,
                     limit_context_length: false,
                     api_key: None,
+                    api_keys: Vec::new(),
+                    key_rotation: KeyRotation::RoundRobin,
                     send_app_id: true,
+                    proxy: None,
+                    ca_bundle: None,
+                    accept_invalid_certs: false,
+                    headers: HashMap::new(),
+                    max_idle_connections: None,
+                    max_idle_connections_per_host: None,
                 },
             ),
             (
                 "ollama".to_string(),
                 HostDefinition {
                     endpoint: ollama::DEFAULT_HOST.to_string(),
+                    endpoints: Vec::new(),
+                    balance: LoadBalance::RoundRobin,
                     protocol: HostProtocol::Ollama,
                     limit_context_length: true,
                     api_key: None,
+                    api_keys: Vec::new(),
+                    key_rotation: KeyRotation::RoundRobin,
                     send_app_id: true,
+                    proxy: None,
+                    ca_bundle: None,
+                    accept_invalid_certs: false,
+                    headers: HashMap::new(),
+                    max_idle_connections: None,
+                    max_idle_connections_per_host: None,
                 },
             ),
             (
                 "openai".to_string(),
                 HostDefinition {
                     endpoint: openai::OPENAI_HOST.to_string(),
+                    endpoints: Vec::new(),
+                    balance: LoadBalance::RoundRobin,
                     protocol: HostProtocol::OpenAi,
                     limit_context_length: true,
                     api_key: Some("OPENAI_API_KEY".to_string()),
+                    api_keys: Vec::new(),
+                    key_rotation: KeyRotation::RoundRobin,
                     send_app_id: true,
+                    proxy: None,
+                    ca_bundle: None,
+                    accept_invalid_certs: false,
+                    headers: HashMap::new(),
+                    max_idle_connections: None,
+                    max_idle_connections_per_host: None,
                 },
             ),
             (
                 "openrouter".to_string(),
                 HostDefinition {
                     endpoint: "https://openrouter.ai/api".to_string(),
+                    endpoints: Vec::new(),
+                    balance: LoadBalance::RoundRobin,
                     protocol: HostProtocol::OpenAi,
                     limit_context_length: false,
                     api_key: Some("OPENROUTER_API_KEY".to_string()),
+                    api_keys: Vec::new(),
+                    key_rotation: KeyRotation::RoundRobin,
                     send_app_id: true,
+                    proxy: None,
+                    ca_bundle: None,
+                    accept_invalid_certs: false,
+                    headers: HashMap::new(),
+                    max_idle_connections: None,
+                    max_idle_connections_per_host: None,
                 },
             ),
             (
                 "together".to_string(),
                 HostDefinition {
                     endpoint: together::DEFAULT_HOST.to_string(),
+                    endpoints: Vec::new(),
+                    balance: LoadBalance::RoundRobin,
                     protocol: HostProtocol::Together,
                     limit_context_length: true,
                     api_key: Some("TOGETHER_API_KEY".to_string()),
+                    api_keys: Vec::new(),
+                    key_rotation: KeyRotation::RoundRobin,
                     send_app_id: true,
+                    proxy: None,
+                    ca_bundle: None,
+                    accept_invalid_certs: false,
+                    headers: HashMap::new(),
+                    max_idle_connections: None,
+                    max_idle_connections_per_host: None,
                 },
             ),
         ]
@@ -194,36 +670,91 @@ impl TryFrom<HostDefinitionInput> for HostDefinition {
     /// Create a HostDefinition from a HostDefinitionInput. If there is an existing HostDefinition
     /// with the same name, use [merge_from_input] instead.
     fn try_from(value: HostDefinitionInput) -> Result<Self, Self::Error> {
-        let endpoint = value.endpoint.ok_or(Error::MissingField("endpoint"))?;
+        let endpoints = value.endpoints.unwrap_or_default();
+        let endpoint = value
+            .endpoint
+            .or_else(|| endpoints.first().cloned())
+            .ok_or(Error::MissingField("endpoint"))?;
         let protocol = value.protocol.ok_or(Error::MissingField("protocol"))?;
         Ok(Self {
             endpoint,
+            endpoints,
+            balance: value.balance.unwrap_or_default(),
             limit_context_length: value
                 .limit_context_length
                 .unwrap_or_else(|| protocol.default_context_length_option()),
             protocol,
             api_key: value.api_key,
+            api_keys: value.api_keys.unwrap_or_default(),
+            key_rotation: value.key_rotation.unwrap_or_default(),
             send_app_id: value.send_app_id.unwrap_or(true),
+            proxy: value.proxy,
+            ca_bundle: value.ca_bundle,
+            accept_invalid_certs: value.accept_invalid_certs.unwrap_or(false),
+            headers: value.headers.unwrap_or_default(),
+            max_idle_connections: value.max_idle_connections,
+            max_idle_connections_per_host: value.max_idle_connections_per_host,
         })
     }
 }
 
-#[derive(Deserialize, Default, Debug, Clone)]
+#[derive(Deserialize, Default, Debug, Clone, JsonSchema)]
 pub struct HostDefinitionInput {
     pub endpoint: Option<String>,
+    pub endpoints: Option<Vec<String>>,
+    pub balance: Option<LoadBalance>,
     pub api_key: Option<String>,
+    /// Multiple environment variables that hold API keys for the host. Requests are spread across
+    /// the keys, and a key which is rejected or rate limited is skipped in favor of the next one.
+    pub api_keys: Option<Vec<String>>,
+    /// How to choose between multiple API keys: "round_robin" (the default) or "failover".
+    pub key_rotation: Option<KeyRotation>,
     pub protocol: Option<HostProtocol>,
     pub limit_context_length: Option<bool>,
     pub send_app_id: Option<bool>,
+    pub proxy: Option<String>,
+    pub ca_bundle: Option<PathBuf>,
+    pub accept_invalid_certs: Option<bool>,
+    pub headers: Option<HashMap<String, String>>,
+    /// The maximum number of idle connections to keep open for reuse.
+    pub max_idle_connections: Option<usize>,
+    /// The maximum number of idle connections to keep open to each endpoint.
+    pub max_idle_connections_per_host: Option<usize>,
 }
 
 impl HostDefinitionInput {
+    /// Make a relative CA bundle path relative to `base_dir`, the directory of the file that declared it.
+    pub fn resolve_ca_bundle_path(&mut self, base_dir: &Path) {
+        if let Some(ca_bundle) = self.ca_bundle.as_mut() {
+            if ca_bundle.is_relative() {
+                *ca_bundle = base_dir.join(&ca_bundle);
+            }
+        }
+    }
+
     pub fn merge_from_input(&mut self, other: &HostDefinitionInput) {
         overwrite_option_from_option(&mut self.endpoint, &other.endpoint);
+        overwrite_option_from_option(&mut self.endpoints, &other.endpoints);
+        overwrite_option_from_option(&mut self.balance, &other.balance);
         overwrite_option_from_option(&mut self.protocol, &other.protocol);
         overwrite_option_from_option(&mut self.api_key, &other.api_key);
+        overwrite_option_from_option(&mut self.api_keys, &other.api_keys);
+        overwrite_option_from_option(&mut self.key_rotation, &other.key_rotation);
         overwrite_option_from_option(&mut self.limit_context_length, &other.limit_context_length);
         overwrite_option_from_option(&mut self.send_app_id, &other.send_app_id);
+        overwrite_option_from_option(&mut self.proxy, &other.proxy);
+        overwrite_option_from_option(&mut self.ca_bundle, &other.ca_bundle);
+        overwrite_option_from_option(&mut self.accept_invalid_certs, &other.accept_invalid_certs);
+        if let Some(other_headers) = other.headers.as_ref() {
+            self.headers
+                .get_or_insert_with(HashMap::new)
+                .extend(other_headers.clone());
+        }
+        overwrite_option_from_option(&mut self.max_idle_connections, &other.max_idle_connections);
+        overwrite_option_from_option(
+            &mut self.max_idle_connections_per_host,
+            &other.max_idle_connections_per_host,
+        );
     }
 }
 
@@ -236,4 +767,139 @@ mod test {
         let builtin = super::HostDefinition::builtin();
         assert!(builtin.contains_key(HostDefinition::default_host()));
     }
+
+    #[test]
+    fn prompt_with_history() {
+        use crate::{session::ChatMessage, shutdown::CancellationToken, usage::UsageMeter};
+
+        let tools = std::collections::HashMap::new();
+        let history = [ChatMessage::user("hi"), ChatMessage::assistant("hello")];
+        let mut input = super::ModelInput {
+            prompt: "how are you?",
+            system: None,
+            history: &[],
+            images: Vec::new(),
+            tools: &tools,
+            cancel: &CancellationToken::new(),
+            usage: &UsageMeter::new(),
+            events: super::RunEvents::none(),
+        };
+        assert_eq!(input.prompt_with_history(), "how are you?");
+
+        input.history = &history;
+        assert_eq!(
+            input.prompt_with_history(),
+            "User: hi\n\nAssistant: hello\n\nUser: how are you?"
+        );
+    }
+
+    #[test]
+    fn with_fallback_returns_answering_model() {
+        use crate::{
+            model::{ModelChainEntry, ModelChoice, ModelError, ModelOptions, ModelSpec},
+            shutdown::CancellationToken,
+            usage::UsageMeter,
+        };
+
+        let tools = std::collections::HashMap::new();
+        let input = super::ModelInput {
+            prompt: "hi",
+            system: None,
+            history: &[],
+            images: Vec::new(),
+            tools: &tools,
+            cancel: &CancellationToken::new(),
+            usage: &UsageMeter::new(),
+            events: super::RunEvents::none(),
+        };
+        let mut options = ModelOptions::new(
+            Default::default(),
+            HostDefinition::builtin(),
+            "ollama".into(),
+        );
+        options.set_model_choice(ModelChoice::Chain(
+            ["llama3.1", "mistral"]
+                .into_iter()
+                .map(|model| ModelChainEntry {
+                    model: ModelSpec::Plain(model.to_string()),
+                    overrides: Default::default(),
+                })
+                .collect(),
+        ));
+
+        let send = |fail: &'static str| {
+            move |_: &dyn super::ModelHost, options: &ModelOptions| {
+                if options.full_model_spec().model_name() == fail {
+                    Err(error_stack::Report::new(ModelError::Model(
+                        503,
+                        String::new(),
+                    )))
+                } else {
+                    Ok("response")
+                }
+            }
+        };
+
+        let (response, fallback) =
+            super::with_fallback(&options, &input, || false, send("none")).unwrap();
+        assert_eq!(response, "response");
+        assert!(fallback.is_none(), "The first model answered");
+
+        let (_, fallback) =
+            super::with_fallback(&options, &input, || false, send("llama3.1")).unwrap();
+        assert_eq!(fallback.unwrap().full_model_spec().model_name(), "mistral");
+    }
+
+    #[test]
+    fn endpoints_without_endpoint() {
+        let input = super::HostDefinitionInput {
+            endpoints: Some(vec![
+                "http://gpu1:11434".to_string(),
+                "http://gpu2:11434".to_string(),
+            ]),
+            protocol: Some(super::HostProtocol::Ollama),
+            balance: Some(super::LoadBalance::LeastBusy),
+            ..Default::default()
+        };
+
+        let host = HostDefinition::try_from(input).unwrap();
+        assert_eq!(host.endpoint, "http://gpu1:11434");
+        assert_eq!(host.endpoints.len(), 2);
+        assert_eq!(host.balance, super::LoadBalance::LeastBusy);
+    }
+
+    #[test]
+    fn multiple_api_keys() {
+        let env = |var: &str| match var {
+            "KEY_A" => Some("a".to_string()),
+            "KEY_C" => Some("c".to_string()),
+            _ => None,
+        };
+
+        let mut host = HostDefinition::builtin().remove("openai").unwrap();
+        assert_eq!(host.keys(env), Vec::<(String, String)>::new());
+
+        host.merge_from_input(&super::HostDefinitionInput {
+            api_keys: Some(vec![
+                "KEY_A".to_string(),
+                "KEY_B".to_string(),
+                "KEY_C".to_string(),
+            ]),
+            key_rotation: Some(super::KeyRotation::Failover),
+            ..Default::default()
+        });
+        assert_eq!(host.key_rotation, super::KeyRotation::Failover);
+        assert_eq!(
+            host.keys(env),
+            vec![
+                ("KEY_A".to_string(), "a".to_string()),
+                ("KEY_C".to_string(), "c".to_string())
+            ]
+        );
+
+        // Without `api_keys`, the single `api_key` is used.
+        host.api_keys.clear();
+        host.api_key = Some("KEY_C".to_string());
+        assert_eq!(host.keys(env), vec![("KEY_C".to_string(), "c".to_string())]);
+    }
 }
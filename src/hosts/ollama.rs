@@ -7,12 +7,18 @@ use tracing::{event, instrument, Level};
 use ureq::Response;
 
 use super::{ModelHost, ModelInput};
-use crate::model::{map_model_response_err, ModelError, ModelOptions, OutputFormat};
+use crate::{
+    interrupt,
+    model::{map_model_response_err, ModelError, ModelOptions},
+    requests::request_with_retry,
+    shutdown::CancellationToken,
+};
 
 pub const DEFAULT_HOST: &str = "http://localhost:11434";
 
 #[derive(Debug)]
 pub struct OllamaHost {
+    agent: ureq::Agent,
     pub host: Option<String>,
     // Ollama doesn't use an API key, but if someone puts it behind a reverse proxy this could be
     // useful.
@@ -20,13 +26,25 @@ pub struct OllamaHost {
 }
 
 impl OllamaHost {
-    pub fn new(host: Option<String>, api_key: Option<String>) -> Self {
-        Self { host, api_key }
+    pub fn new(agent: ureq::Agent, host: Option<String>, api_key: Option<String>) -> Self {
+        Self {
+            agent,
+            host,
+            api_key,
+        }
     }
 
     fn host(&self) -> &str {
         self.host.as_deref().unwrap_or(DEFAULT_HOST)
     }
+
+    fn authorize(&self, request: ureq::Request) -> ureq::Request {
+        if let Some(key) = self.api_key.as_ref() {
+            request.set("Authorization", &format!("Bearer {}", key))
+        } else {
+            request
+        }
+    }
 }
 
 impl ModelHost for OllamaHost {
@@ -34,17 +52,16 @@ impl ModelHost for OllamaHost {
     fn send_model_request(
         &self,
         options: &ModelOptions,
-        input: ModelInput,
+        input: &ModelInput,
         message_tx: flume::Sender<String>,
     ) -> Result<(), Report<ModelError>> {
+        if !input.tools.is_empty() {
+            return Err(Report::new(ModelError::HostDoesNotSupportTools));
+        }
+
         let url = format!("{}/api/generate", self.host());
 
-        let request = ureq::post(&url);
-        let request = if let Some(key) = self.api_key.as_ref() {
-            request.set("Authorization", &format!("Bearer {}", key))
-        } else {
-            request
-        };
+        let request = self.authorize(self.agent.post(&url));
 
         let images = input
             .images
@@ -52,45 +69,47 @@ impl ModelHost for OllamaHost {
             .map(|i| i.as_base64())
             .collect::<Vec<_>>();
 
+        let prompt = input.prompt_with_history();
         let spec = options.full_model_spec();
         let body = OllamaRequest {
             model: &spec.model_name(),
-            prompt: input.prompt,
+            prompt: &prompt,
             system: input.system,
             images,
-            format: options.format,
+            // Ollama takes a schema in place of "json".
+            format: match options.response_schema.as_ref() {
+                Some(schema) => Some(schema.as_value().clone()),
+                None => options.format.map(|format| json!(format)),
+            },
             options: OllamaModelOptions {
                 temperature: options.temperature,
                 top_p: options.top_p,
                 top_k: options.top_k,
                 repeat_penalty: options.frequency_penalty,
+                presence_penalty: options.presence_penalty,
                 stop: options.stop.clone(),
                 num_predict: options.max_tokens,
+                seed: options.seed,
             },
             stream: true,
         };
 
         event!(Level::INFO, body = ?body, "Sending request");
 
-        let response: Response = request
-            .send_json(body)
+        let response: Response = request_with_retry(request, body, &options.retry)
             .map_err(map_model_response_err)
             .attach_printable(url)?;
 
+        let _streaming = interrupt::start_streaming();
         let reader = std::io::BufReader::new(response.into_reader());
-        for line in reader.lines() {
-            let line = line.change_context(ModelError::Raw)?;
-            let chunk = serde_json::from_str::<OllamaResponse>(&line)
-                .change_context(ModelError::Deserialize)?;
-            message_tx.send(chunk.response).ok();
-        }
-
-        Ok(())
+        read_stream(reader, &message_tx, input.cancel)
     }
 
     fn model_context_limit(&self, model: &str) -> Result<Option<usize>, Report<ModelError>> {
         let url = format!("{}/api/show", self.host());
-        let response: ModelInfo = ureq::post(&url)
+        let response: ModelInfo = self
+            .agent
+            .post(&url)
             .send_json(json!({
                 "name": model
             }))
@@ -117,7 +136,45 @@ impl ModelHost for OllamaHost {
 
         Ok(Some(context_size))
     }
+
+    fn list_models(&self) -> Result<Vec<String>, Report<ModelError>> {
+        let url = format!("{}/api/tags", self.host());
+        let response: ModelList = self
+            .authorize(self.agent.get(&url))
+            .call()
+            .map_err(map_model_response_err)
+            .attach_printable(url)?
+            .into_json()
+            .change_context(ModelError::Deserialize)?;
+
+        Ok(response.models.into_iter().map(|m| m.name).collect())
+    }
+}
+
+/// Send each chunk of the response to `message_tx` until the response is done, `cancel` is
+/// cancelled, or nothing is receiving the chunks anymore. Returning early drops the reader, which
+/// closes the connection.
+fn read_stream(
+    reader: impl BufRead,
+    message_tx: &flume::Sender<String>,
+    cancel: &CancellationToken,
+) -> Result<(), Report<ModelError>> {
+    for line in reader.lines() {
+        if cancel.is_cancelled() {
+            break;
+        }
+
+        let line = line.change_context(ModelError::Raw)?;
+        let chunk = serde_json::from_str::<OllamaResponse>(&line)
+            .change_context(ModelError::Deserialize)?;
+        if message_tx.send(chunk.response).is_err() {
+            break;
+        }
+    }
+
+    Ok(())
 }
+
 #[derive(Debug, Serialize)]
 pub struct OllamaRequest<'a> {
     pub model: &'a str,
@@ -125,7 +182,7 @@ pub struct OllamaRequest<'a> {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub images: Vec<String>,
     pub system: Option<&'a str>,
-    pub format: Option<OutputFormat>,
+    pub format: Option<serde_json::Value>,
     pub stream: bool,
     pub options: OllamaModelOptions,
 }
@@ -136,8 +193,10 @@ pub struct OllamaModelOptions {
     top_p: Option<f32>,
     top_k: Option<u32>,
     repeat_penalty: Option<f32>,
+    presence_penalty: Option<f32>,
     num_predict: Option<u32>,
     stop: Vec<String>,
+    seed: Option<u64>,
 }
 
 #[derive(Deserialize)]
@@ -147,6 +206,16 @@ struct OllamaResponse {
     // TODO Add response stats
 }
 
+#[derive(Deserialize, Debug)]
+struct ModelList {
+    models: Vec<ModelListEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ModelListEntry {
+    name: String,
+}
+
 #[derive(Deserialize, Debug)]
 struct ModelInfo {
     modelfile: String,
@@ -154,6 +223,47 @@ struct ModelInfo {
     template: String,
 }
 
+#[cfg(test)]
+mod stream_test {
+    use super::*;
+
+    const STREAM: &str = r##"{"response":"Hello","done":false}
+{"response":" there","done":false}
+{"response":"","done":true}
+"##;
+
+    #[test]
+    fn read_full_stream() {
+        let (tx, rx) = flume::unbounded();
+        read_stream(STREAM.as_bytes(), &tx, &CancellationToken::new()).unwrap();
+        drop(tx);
+        assert_eq!(rx.iter().collect::<String>(), "Hello there");
+    }
+
+    #[test]
+    fn stop_stream() {
+        let (tx, rx) = flume::unbounded();
+        let cancel = CancellationToken::new();
+        let mut reader = STREAM.as_bytes();
+        // Read the first line, then stop as if the user pressed Ctrl-C.
+        let mut first = String::new();
+        reader.read_line(&mut first).unwrap();
+        read_stream(first.as_bytes(), &tx, &cancel).unwrap();
+        cancel.cancel();
+        read_stream(reader, &tx, &cancel).unwrap();
+        drop(tx);
+        assert_eq!(rx.iter().collect::<String>(), "Hello");
+    }
+
+    #[test]
+    fn stop_without_receiver() {
+        let (tx, rx) = flume::bounded(1);
+        drop(rx);
+        // Nothing is reading the output anymore, so this returns instead of reading every chunk.
+        read_stream(STREAM.as_bytes(), &tx, &CancellationToken::new()).unwrap();
+    }
+}
+
 #[cfg(all(test, feature = "test-ollama"))]
 mod test {
     // Note that for these tests to work, you must be running ollama and already have pulled the models
@@ -165,7 +275,7 @@ mod test {
     #[test]
     /// Get the context size for a model that specifies it in the modelfile.
     fn model_context_with_info() {
-        let host = super::OllamaHost::new(None);
+        let host = super::OllamaHost::new(ureq::agent(), None, None);
         let limit = host
             .model_context_limit("yarn-mistral:7b-128k-q5_K_M")
             .expect("Fetching context");
@@ -175,7 +285,7 @@ mod test {
     #[test]
     /// Get the context size for a model that doesn't specify it in the modelfile.
     fn model_context_without_info() {
-        let host = super::OllamaHost::new(None);
+        let host = super::OllamaHost::new(ureq::agent(), None, None);
         let limit = host
             .model_context_limit("mistral:7b-instruct-q5_K_M")
             .expect("Fetching context");
@@ -1,19 +1,34 @@
-use std::time::Duration;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::Duration,
+};
 
 use error_stack::{Report, ResultExt};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
+use tracing::{event, Level};
 
-use super::{ModelHost, ModelInput};
+use super::{send_sequential_completions, ModelHost, ModelInput};
 use crate::{
+    events::RunEvent,
+    history::TokenCounts,
     model::{map_model_response_err, ModelError, ModelOptions},
     requests::request_with_retry,
+    shutdown::CancellationToken,
+    tools::{ToolDefinition, ToolError},
 };
 
 pub const OPENAI_HOST: &str = "https://api.openai.com/v1";
 
+/// The timeout for the whole request, when no other timeout is configured.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Stop if the model is still calling tools after this many rounds.
+const MAX_TOOL_ROUNDS: usize = 10;
+
 #[derive(Debug)]
 pub struct OpenAiHost {
+    agent: ureq::Agent,
     pub api_key: Option<String>,
     pub host: Option<String>,
     /// Whether or not to check and enforce a context length limit. Usually this is true, but some
@@ -24,12 +39,14 @@ pub struct OpenAiHost {
 
 impl OpenAiHost {
     pub fn new(
+        agent: ureq::Agent,
         host: Option<String>,
         api_key: Option<String>,
         do_context_limit: bool,
         send_user: bool,
     ) -> Self {
         Self {
+            agent,
             api_key,
             host,
             do_context_limit,
@@ -41,32 +58,30 @@ impl OpenAiHost {
         self.host.as_deref().unwrap_or(OPENAI_HOST)
     }
 
-    fn create_base_request(&self, path: &str) -> ureq::Request {
-        let url = format!("{}/{path}", self.host());
-
-        let request = ureq::post(&url);
+    fn authorize(&self, request: ureq::Request) -> ureq::Request {
         if let Some(key) = self.api_key.as_ref() {
             request.set("Authorization", &format!("Bearer {}", key))
         } else {
             request
         }
     }
-}
 
-impl ModelHost for OpenAiHost {
-    fn send_model_request(
+    fn create_base_request(&self, path: &str) -> ureq::Request {
+        let url = format!("{}/{path}", self.host());
+        self.authorize(self.agent.post(&url))
+    }
+
+    /// The messages and the body of a chat completion request for the prompt.
+    fn request_body(
         &self,
         options: &ModelOptions,
-        input: ModelInput,
-        message_tx: flume::Sender<String>,
-    ) -> Result<(), Report<ModelError>> {
-        let user_content = if input.images.is_empty() {
+        input: &ModelInput,
+    ) -> (Vec<serde_json::Value>, serde_json::Value) {
+        let cache = options.prompt_cache;
+        let user_content = if input.images.is_empty() && !cache.prompt {
             json!(input.prompt)
         } else {
-            let mut messages = vec![json!({
-                "type": "text",
-                "text": input.prompt
-            })];
+            let mut messages = vec![text_content(input.prompt, cache.prompt)];
 
             for image in &input.images {
                 messages.push(json!({
@@ -80,33 +95,40 @@ impl ModelHost for OpenAiHost {
             json!(messages)
         };
 
-        let messages = if let Some(system) = input.system {
-            json!([
-                {
-                    "role": "system",
-                    "content": system,
-                },
-                {
-                    "role": "user",
-                    "content": user_content,
-                }
-            ])
-        } else {
-            json!([
-                {
-                    "role": "user",
-                    "content": user_content,
-                }
-            ])
-        };
+        let mut messages = vec![];
+        if let Some(system) = input.system {
+            let content = if cache.system {
+                json!([text_content(system, true)])
+            } else {
+                json!(system)
+            };
+
+            messages.push(json!({
+                "role": "system",
+                "content": content,
+            }));
+        }
+
+        for message in input.history {
+            messages.push(json!({
+                "role": message.role,
+                "content": message.content,
+            }));
+        }
+
+        messages.push(json!({
+            "role": "user",
+            "content": user_content,
+        }));
 
         let mut body = json!({
             "model": options.full_model_spec().model_name(),
             "temperature": options.temperature,
-            "messages": messages
         });
 
-        if self.send_user {
+        if let Some(user) = options.user.as_ref() {
+            body["user"] = json!(user);
+        } else if self.send_user {
             body["user"] = json!("promptbox");
         }
 
@@ -114,6 +136,16 @@ impl ModelHost for OpenAiHost {
             body["format"] = json!(val);
         }
 
+        if let Some(schema) = options.response_schema.as_ref() {
+            body["response_format"] = json!({
+                "type": "json_schema",
+                "json_schema": {
+                    "name": "response",
+                    "schema": schema.as_value(),
+                },
+            });
+        }
+
         if let Some(val) = options.presence_penalty.as_ref() {
             body["presence_penalty"] = json!(val);
         }
@@ -126,6 +158,14 @@ impl ModelHost for OpenAiHost {
             body["top_p"] = json!(tp);
         }
 
+        if let Some(seed) = options.seed {
+            body["seed"] = json!(seed);
+        }
+
+        if !options.logit_bias.is_empty() {
+            body["logit_bias"] = json!(options.logit_bias);
+        }
+
         if !options.stop.is_empty() {
             body["stop"] = json!(options.stop);
         }
@@ -134,24 +174,131 @@ impl ModelHost for OpenAiHost {
             body["max_tokens"] = json!(max_tokens);
         }
 
-        let mut response: ChatCompletion = request_with_retry(
-            self.create_base_request("chat/completions")
-                .timeout(Duration::from_secs(30)),
-            body,
-        )
-        .map_err(map_model_response_err)?
-        .into_json()
-        .change_context(ModelError::Deserialize)?;
+        if !input.tools.is_empty() {
+            body["tools"] = input
+                .tools
+                .iter()
+                .map(|(name, tool)| tool.openai_definition(name))
+                .collect();
+        }
+
+        (messages, body)
+    }
+
+    fn send_chat_request(
+        &self,
+        options: &ModelOptions,
+        input: &ModelInput,
+        body: &serde_json::Value,
+    ) -> Result<ChatCompletion, Report<ModelError>> {
+        let mut request = self.create_base_request("chat/completions");
+        if options.timeout.is_none() {
+            request = request.timeout(DEFAULT_TIMEOUT);
+        }
+
+        let response: ChatCompletion = request_with_retry(request, body, &options.retry)
+            .map_err(map_model_response_err)?
+            .into_json()
+            .change_context(ModelError::Deserialize)?;
+
+        if let Some(usage) = &response.usage {
+            input.usage.add(TokenCounts {
+                prompt: usage.prompt_tokens,
+                response: usage.completion_tokens,
+            });
+        }
+
+        Ok(response)
+    }
+}
+
+impl ModelHost for OpenAiHost {
+    fn send_model_request(
+        &self,
+        options: &ModelOptions,
+        input: &ModelInput,
+        message_tx: flume::Sender<String>,
+    ) -> Result<(), Report<ModelError>> {
+        let (mut messages, mut body) = self.request_body(options, input);
+
+        for _ in 0..MAX_TOOL_ROUNDS {
+            if input.cancel.is_cancelled() {
+                return Ok(());
+            }
+
+            body["messages"] = json!(messages);
+            let mut response = self.send_chat_request(options, input, &body)?;
+
+            let Some(message) = response.choices.get_mut(0).map(|c| &mut c.message) else {
+                return Ok(());
+            };
+
+            if message.tool_calls.is_empty() {
+                // TODO streaming
+                let result = message.content.take().unwrap_or_default();
+                message_tx.send(result).ok();
+                // Generated images are sent as data URLs, which the output can save to files.
+                for image in &message.images {
+                    message_tx.send(format!("\n{}\n", image.image_url.url)).ok();
+                }
+                return Ok(());
+            }
+
+            messages.push(json!({
+                "role": "assistant",
+                "content": message.content,
+                "tool_calls": message.tool_calls,
+            }));
+
+            for call in &message.tool_calls {
+                input.events.emit(RunEvent::ToolCall {
+                    name: &call.function.name,
+                    arguments: &call.function.arguments,
+                });
+                let result = run_tool_call(input.tools, call, input.cancel)?;
+                messages.push(json!({
+                    "role": "tool",
+                    "tool_call_id": call.id,
+                    "content": result,
+                }));
+            }
+        }
+
+        Err(Report::new(ModelError::TooManyToolCalls(MAX_TOOL_ROUNDS)))
+    }
+
+    fn send_completions_request(
+        &self,
+        options: &ModelOptions,
+        input: &ModelInput,
+        n: usize,
+    ) -> Result<Vec<String>, Report<ModelError>> {
+        // Each response may go through its own rounds of tool calls, so those are sent one at a
+        // time.
+        if n <= 1 || !input.tools.is_empty() {
+            return send_sequential_completions(self, options, input, n);
+        }
 
-        // TODO streaming
-        let result = response
+        let (messages, mut body) = self.request_body(options, input);
+        body["messages"] = json!(messages);
+        body["n"] = json!(n);
+        let mut response = self.send_chat_request(options, input, &body)?;
+
+        response.choices.sort_by_key(|c| c.index);
+        let mut responses = response
             .choices
-            .get_mut(0)
-            .map(|m| m.message.content.take().unwrap_or_default())
-            .unwrap_or_default();
+            .into_iter()
+            .map(|c| c.message.content.unwrap_or_default())
+            .take(n)
+            .collect::<Vec<_>>();
+
+        // Some OpenAI-compatible hosts ignore `n` and return one choice.
+        if responses.len() < n {
+            let rest = send_sequential_completions(self, options, input, n - responses.len())?;
+            responses.extend(rest);
+        }
 
-        message_tx.send(result).ok();
-        Ok(())
+        Ok(responses)
     }
 
     fn model_context_limit(&self, model_name: &str) -> Result<Option<usize>, Report<ModelError>> {
@@ -161,12 +308,108 @@ impl ModelHost for OpenAiHost {
             Ok(None)
         }
     }
+
+    fn list_models(&self) -> Result<Vec<String>, Report<ModelError>> {
+        let url = format!("{}/models", self.host());
+        let response: ModelList = self
+            .authorize(self.agent.get(&url))
+            .call()
+            .map_err(map_model_response_err)
+            .attach_printable(url)?
+            .into_json()
+            .change_context(ModelError::Deserialize)?;
+
+        Ok(response.data.into_iter().map(|m| m.id).collect())
+    }
+
+    fn moderate(
+        &self,
+        options: &ModelOptions,
+        model: &str,
+        text: &str,
+    ) -> Result<Vec<String>, Report<ModelError>> {
+        let body = json!({
+            "model": model,
+            "input": text,
+        });
+        let mut request = self.create_base_request("moderations");
+        if options.timeout.is_none() {
+            request = request.timeout(DEFAULT_TIMEOUT);
+        }
+
+        let response: Moderation = request_with_retry(request, body, &options.retry)
+            .map_err(map_model_response_err)?
+            .into_json()
+            .change_context(ModelError::Deserialize)?;
+
+        Ok(response.flagged_categories())
+    }
+}
+
+/// A text content block, optionally marked as cacheable. This uses the `cache_control` format from
+/// Anthropic, which OpenRouter passes through.
+fn text_content(text: &str, cache: bool) -> serde_json::Value {
+    let mut content = json!({
+        "type": "text",
+        "text": text,
+    });
+
+    if cache {
+        content["cache_control"] = json!({ "type": "ephemeral" });
+    }
+
+    content
+}
+
+fn run_tool_call(
+    tools: &HashMap<String, ToolDefinition>,
+    call: &ToolCall,
+    cancel: &CancellationToken,
+) -> Result<String, Report<ModelError>> {
+    let name = &call.function.name;
+    let tool = tools
+        .get(name)
+        .ok_or_else(|| ToolError::UnknownTool(name.clone()))
+        .change_context(ModelError::Tool)?;
+
+    event!(Level::INFO, tool = %name, arguments = %call.function.arguments, "Running tool");
+    tool.run(name, &call.function.arguments, cancel)
+        .change_context(ModelError::Tool)
 }
 
 #[derive(Debug, Deserialize)]
 struct ChatCompletionMessage {
     role: String,
     content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCall>,
+    /// Images generated by the model, as returned by OpenRouter
+    #[serde(default)]
+    images: Vec<ImageOutput>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageOutput {
+    image_url: ImageOutputUrl,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageOutputUrl {
+    url: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ToolCall {
+    id: String,
+    #[serde(rename = "type")]
+    typ: String,
+    function: ToolCallFunction,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ToolCallFunction {
+    name: String,
+    arguments: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -181,7 +424,57 @@ struct ChatCompletion {
     id: String,
     choices: Vec<ChatCompletionChoice>,
     created: i64,
-    // usage: Usage,
+    #[serde(default)]
+    usage: Option<CompletionUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionUsage {
+    prompt_tokens: usize,
+    completion_tokens: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelList {
+    data: Vec<ModelListEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelListEntry {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Moderation {
+    results: Vec<ModerationResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModerationResult {
+    flagged: bool,
+    #[serde(default)]
+    categories: BTreeMap<String, bool>,
+}
+
+impl Moderation {
+    /// The categories that any of the results were flagged for
+    fn flagged_categories(self) -> Vec<String> {
+        let mut categories = BTreeSet::new();
+        for result in self.results.into_iter().filter(|r| r.flagged) {
+            let flagged = result
+                .categories
+                .into_iter()
+                .filter(|(_, flagged)| *flagged)
+                .map(|(category, _)| category)
+                .collect::<Vec<_>>();
+            if flagged.is_empty() {
+                categories.insert("flagged".to_string());
+            }
+            categories.extend(flagged);
+        }
+
+        categories.into_iter().collect()
+    }
 }
 
 fn send_completion_request(options: &ModelOptions, prompt: &str) -> Result<(), ureq::Error> {
@@ -223,7 +516,32 @@ fn model_context_limit(model_name: &str) -> usize {
 
 #[cfg(test)]
 mod test {
-    use super::model_context_limit;
+    use super::{model_context_limit, text_content, Moderation};
+
+    #[test]
+    fn cacheable_text_content() {
+        let content = text_content("abc", true);
+        assert_eq!(content["text"], "abc");
+        assert_eq!(content["cache_control"]["type"], "ephemeral");
+
+        let content = text_content("abc", false);
+        assert!(content.get("cache_control").is_none());
+    }
+
+    #[test]
+    fn moderation_categories() {
+        let response: Moderation = serde_json::from_str(
+            r#"{
+                "results": [
+                    { "flagged": false, "categories": { "hate": false, "violence": false } },
+                    { "flagged": true, "categories": { "hate": false, "violence": true } },
+                    { "flagged": true, "categories": {} }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(response.flagged_categories(), vec!["flagged", "violence"]);
+    }
 
     /// Check against a bunch of real models to make sure the logic is right
     #[test]
@@ -0,0 +1,442 @@
+//! Typed configuration sections for each kind of model provider. Each section becomes a host
+//! definition when the configuration file is loaded, and is checked for mistakes at that point
+//! so that the error can point to the setting instead of failing on the first request.
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use error_stack::Report;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::{HostDefinitionInput, HostProtocol, KeyRotation, LoadBalance};
+use crate::error::Error;
+
+/// Names of the typed sections, which custom providers can not reuse.
+const TYPED_PROVIDERS: [&str; 3] = ["ollama", "openai", "anthropic"];
+
+#[derive(Deserialize, Debug, Clone, Default, JsonSchema)]
+pub struct ProvidersInput {
+    /// An Ollama server
+    pub ollama: Option<OllamaProviderInput>,
+    /// The OpenAI API
+    pub openai: Option<ApiProviderInput>,
+    /// The Anthropic API, through its OpenAI-compatible endpoint
+    pub anthropic: Option<ApiProviderInput>,
+    /// Other hosts, each with a name that models can refer to
+    #[serde(default)]
+    pub custom: Vec<CustomProviderInput>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, JsonSchema)]
+pub struct OllamaProviderInput {
+    /// The URL of the server. Defaults to http://localhost:11434
+    pub endpoint: Option<String>,
+    /// Multiple servers that serve the same models
+    pub endpoints: Option<Vec<String>>,
+    /// How to choose between multiple endpoints
+    pub balance: Option<LoadBalance>,
+    /// Whether to limit the prompt to the model's context length. Defaults to true.
+    pub limit_context_length: Option<bool>,
+    /// A proxy to use instead of the one from the environment. An empty string disables the proxy.
+    pub proxy: Option<String>,
+    /// A PEM file with extra CA certificates to trust, relative to the configuration file
+    pub ca_bundle: Option<PathBuf>,
+    /// Skip TLS certificate verification
+    pub accept_invalid_certs: Option<bool>,
+    /// Extra HTTP headers to send with each request
+    pub headers: Option<HashMap<String, String>>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, JsonSchema)]
+pub struct ApiProviderInput {
+    /// The base URL of the API, to use a gateway or proxy in front of the provider
+    pub endpoint: Option<String>,
+    /// The environment variable that holds the API key
+    pub api_key: Option<String>,
+    /// Multiple environment variables that hold API keys
+    pub api_keys: Option<Vec<String>>,
+    /// How to choose between multiple API keys
+    pub key_rotation: Option<KeyRotation>,
+    /// A proxy to use instead of the one from the environment. An empty string disables the proxy.
+    pub proxy: Option<String>,
+    /// A PEM file with extra CA certificates to trust, relative to the configuration file
+    pub ca_bundle: Option<PathBuf>,
+    /// Extra HTTP headers to send with each request
+    pub headers: Option<HashMap<String, String>>,
+    /// The maximum number of idle connections to keep open for reuse.
+    pub max_idle_connections: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+pub struct CustomProviderInput {
+    /// The name that models use to refer to this provider
+    pub name: String,
+    /// The API that the provider serves
+    pub protocol: HostProtocol,
+    /// The base URL of the API
+    pub endpoint: Option<String>,
+    /// Multiple endpoints that serve the same models
+    pub endpoints: Option<Vec<String>>,
+    /// How to choose between multiple endpoints
+    pub balance: Option<LoadBalance>,
+    /// The environment variable that holds the API key
+    pub api_key: Option<String>,
+    /// Multiple environment variables that hold API keys
+    pub api_keys: Option<Vec<String>>,
+    /// How to choose between multiple API keys
+    pub key_rotation: Option<KeyRotation>,
+    /// Whether to limit the prompt to the model's context length
+    pub limit_context_length: Option<bool>,
+    /// Send "promptbox" in the `user` field of requests. Defaults to true.
+    pub send_app_id: Option<bool>,
+    /// A proxy to use instead of the one from the environment. An empty string disables the proxy.
+    pub proxy: Option<String>,
+    /// A PEM file with extra CA certificates to trust, relative to the configuration file
+    pub ca_bundle: Option<PathBuf>,
+    /// Skip TLS certificate verification
+    pub accept_invalid_certs: Option<bool>,
+    /// Extra HTTP headers to send with each request
+    pub headers: Option<HashMap<String, String>>,
+    /// The maximum number of idle connections to keep open for reuse.
+    pub max_idle_connections: Option<usize>,
+    /// The maximum number of idle connections to keep open to each endpoint.
+    pub max_idle_connections_per_host: Option<usize>,
+}
+
+impl From<OllamaProviderInput> for HostDefinitionInput {
+    fn from(value: OllamaProviderInput) -> Self {
+        Self {
+            endpoint: value.endpoint,
+            endpoints: value.endpoints,
+            balance: value.balance,
+            protocol: Some(HostProtocol::Ollama),
+            limit_context_length: value.limit_context_length,
+            proxy: value.proxy,
+            ca_bundle: value.ca_bundle,
+            accept_invalid_certs: value.accept_invalid_certs,
+            headers: value.headers,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<ApiProviderInput> for HostDefinitionInput {
+    fn from(value: ApiProviderInput) -> Self {
+        Self {
+            endpoint: value.endpoint,
+            protocol: Some(HostProtocol::OpenAi),
+            api_key: value.api_key,
+            api_keys: value.api_keys,
+            key_rotation: value.key_rotation,
+            proxy: value.proxy,
+            ca_bundle: value.ca_bundle,
+            headers: value.headers,
+            max_idle_connections: value.max_idle_connections,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<CustomProviderInput> for HostDefinitionInput {
+    fn from(value: CustomProviderInput) -> Self {
+        Self {
+            endpoint: value.endpoint,
+            endpoints: value.endpoints,
+            balance: value.balance,
+            api_key: value.api_key,
+            api_keys: value.api_keys,
+            key_rotation: value.key_rotation,
+            protocol: Some(value.protocol),
+            limit_context_length: value.limit_context_length,
+            send_app_id: value.send_app_id,
+            proxy: value.proxy,
+            ca_bundle: value.ca_bundle,
+            accept_invalid_certs: value.accept_invalid_certs,
+            headers: value.headers,
+            max_idle_connections: value.max_idle_connections,
+            max_idle_connections_per_host: value.max_idle_connections_per_host,
+        }
+    }
+}
+
+/// A mistake in a provider section
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProviderProblem {
+    pub file: PathBuf,
+    /// The path to the setting, such as `providers.custom[1].endpoint`
+    pub location: String,
+    pub message: String,
+}
+
+impl Display for ProviderProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: `{}` {}",
+            self.file.display(),
+            self.location,
+            self.message
+        )
+    }
+}
+
+/// Collects the problems found while checking the providers in one file.
+struct Checker<'a> {
+    file: &'a Path,
+    problems: Vec<ProviderProblem>,
+}
+
+impl Checker<'_> {
+    fn problem(&mut self, location: String, message: impl Into<String>) {
+        self.problems.push(ProviderProblem {
+            file: self.file.to_path_buf(),
+            location,
+            message: message.into(),
+        });
+    }
+
+    fn endpoints(
+        &mut self,
+        section: &str,
+        endpoint: Option<&String>,
+        endpoints: Option<&[String]>,
+    ) {
+        let endpoint = endpoint.map(|e| (format!("{section}.endpoint"), e));
+        let endpoints = endpoints
+            .unwrap_or_default()
+            .iter()
+            .enumerate()
+            .map(|(i, e)| (format!("{section}.endpoints[{i}]"), e));
+        for (location, url) in endpoint.into_iter().chain(endpoints) {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                self.problem(
+                    location,
+                    format!("should be an http or https URL, not `{url}`"),
+                );
+            }
+        }
+    }
+
+    fn api_keys(&mut self, section: &str, api_key: Option<&String>, api_keys: Option<&[String]>) {
+        let api_key = api_key.map(|k| (format!("{section}.api_key"), k));
+        let api_keys = api_keys
+            .unwrap_or_default()
+            .iter()
+            .enumerate()
+            .map(|(i, k)| (format!("{section}.api_keys[{i}]"), k));
+        for (location, name) in api_key.into_iter().chain(api_keys) {
+            let is_env_name =
+                !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !is_env_name {
+                self.problem(
+                    location,
+                    "should be the name of an environment variable that holds the key, not the key itself",
+                );
+            }
+        }
+    }
+}
+
+impl ProvidersInput {
+    /// Check the providers from the configuration file at `file`, and return every problem found
+    /// so that they can all be fixed at once.
+    pub fn check(&self, file: &Path) -> Vec<ProviderProblem> {
+        let mut checker = Checker {
+            file,
+            problems: Vec::new(),
+        };
+
+        if let Some(ollama) = &self.ollama {
+            checker.endpoints(
+                "providers.ollama",
+                ollama.endpoint.as_ref(),
+                ollama.endpoints.as_deref(),
+            );
+        }
+
+        for (section, provider) in [("openai", &self.openai), ("anthropic", &self.anthropic)] {
+            let Some(provider) = provider else {
+                continue;
+            };
+            let section = format!("providers.{section}");
+            checker.endpoints(&section, provider.endpoint.as_ref(), None);
+            checker.api_keys(
+                &section,
+                provider.api_key.as_ref(),
+                provider.api_keys.as_deref(),
+            );
+        }
+
+        let mut names = HashMap::new();
+        for (i, provider) in self.custom.iter().enumerate() {
+            let section = format!("providers.custom[{i}]");
+            let name = provider.name.as_str();
+            if name.is_empty() {
+                checker.problem(format!("{section}.name"), "should not be empty");
+            } else if TYPED_PROVIDERS.contains(&name) {
+                checker.problem(
+                    format!("{section}.name"),
+                    format!("can not be `{name}`. Use the `providers.{name}` section instead"),
+                );
+            } else if let Some(first) = names.insert(name, i) {
+                checker.problem(
+                    format!("{section}.name"),
+                    format!("`{name}` is already used by `providers.custom[{first}]`"),
+                );
+            }
+
+            let has_endpoints = !provider.endpoints.as_deref().unwrap_or_default().is_empty();
+            if provider.endpoint.is_none() && !has_endpoints {
+                checker.problem(section.clone(), "needs an `endpoint` or `endpoints`");
+            }
+            checker.endpoints(
+                &section,
+                provider.endpoint.as_ref(),
+                provider.endpoints.as_deref(),
+            );
+            checker.api_keys(
+                &section,
+                provider.api_key.as_ref(),
+                provider.api_keys.as_deref(),
+            );
+        }
+
+        checker.problems
+    }
+
+    /// Check the providers, and return an error listing the problems if there are any.
+    pub fn validate(&self, file: &Path) -> Result<(), Report<Error>> {
+        let problems = self.check(file);
+        if problems.is_empty() {
+            return Ok(());
+        }
+
+        let mut report = Report::new(Error::InvalidProviders(problems.len()));
+        for problem in problems {
+            report = report.attach_printable(problem.to_string());
+        }
+        Err(report)
+    }
+
+    /// Convert the providers into host definitions, keyed by the name that models use for them.
+    pub fn into_hosts(self) -> Vec<(String, HostDefinitionInput)> {
+        let typed = [
+            ("ollama", self.ollama.map(HostDefinitionInput::from)),
+            ("openai", self.openai.map(HostDefinitionInput::from)),
+            ("anthropic", self.anthropic.map(HostDefinitionInput::from)),
+        ];
+
+        typed
+            .into_iter()
+            .filter_map(|(name, host)| Some((name.to_string(), host?)))
+            .chain(
+                self.custom
+                    .into_iter()
+                    .map(|provider| (provider.name.clone(), HostDefinitionInput::from(provider))),
+            )
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(contents: &str) -> ProvidersInput {
+        toml::from_str(contents).unwrap()
+    }
+
+    #[test]
+    fn into_hosts() {
+        let providers = parse(
+            r##"
+            [ollama]
+            endpoints = ["http://gpu1:11434", "http://gpu2:11434"]
+
+            [anthropic]
+            api_key = "WORK_ANTHROPIC_KEY"
+
+            [[custom]]
+            name = "vllm"
+            protocol = "openai"
+            endpoint = "http://localhost:8000/v1"
+            "##,
+        );
+        assert!(providers.check(Path::new("promptbox.toml")).is_empty());
+
+        let hosts = providers
+            .into_hosts()
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        assert_eq!(hosts.len(), 3);
+        assert_eq!(hosts["ollama"].protocol, Some(HostProtocol::Ollama));
+        assert_eq!(hosts["ollama"].endpoints.as_ref().unwrap().len(), 2);
+        assert_eq!(hosts["anthropic"].protocol, Some(HostProtocol::OpenAi));
+        assert_eq!(
+            hosts["anthropic"].api_key.as_deref(),
+            Some("WORK_ANTHROPIC_KEY")
+        );
+        assert_eq!(
+            hosts["vllm"].endpoint.as_deref(),
+            Some("http://localhost:8000/v1")
+        );
+    }
+
+    #[test]
+    fn problems() {
+        let providers = parse(
+            r##"
+            [openai]
+            endpoint = "api.openai.com"
+            api_key = "sk-abc123"
+
+            [[custom]]
+            name = "local"
+            protocol = "ollama"
+
+            [[custom]]
+            name = "local"
+            protocol = "openai"
+            endpoint = "http://localhost:8000"
+
+            [[custom]]
+            name = "openai"
+            protocol = "openai"
+            endpoints = ["http://localhost:8001"]
+            "##,
+        );
+
+        let problems = providers
+            .check(Path::new("promptbox.toml"))
+            .into_iter()
+            .map(|p| p.location)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            [
+                "providers.openai.endpoint",
+                "providers.openai.api_key",
+                "providers.custom[0]",
+                "providers.custom[1].name",
+                "providers.custom[2].name",
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_protocol() {
+        let err = toml::from_str::<ProvidersInput>(
+            r##"
+            [[custom]]
+            name = "local"
+            endpoint = "http://localhost:8000"
+            "##,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("missing field `protocol`"));
+    }
+}
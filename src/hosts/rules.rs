@@ -0,0 +1,96 @@
+//! Choose the host for models by their names, so that a whole family of models can be pointed at a
+//! different host from one place in the configuration.
+
+use std::collections::HashMap;
+
+use error_stack::Report;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::HostDefinition;
+use crate::error::Error;
+
+/// A rule from the configuration's `host_rules`
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct HostRuleInput {
+    /// A regular expression to match against the model name, after resolving aliases
+    pub model: String,
+    /// The host to send requests for matching models to
+    pub host: String,
+}
+
+/// A compiled host rule
+#[derive(Debug, Clone)]
+pub struct HostRule {
+    pub model: Regex,
+    pub host: String,
+}
+
+impl HostRule {
+    /// Compile the rules, checking that each one refers to a host that exists.
+    pub fn compile(
+        rules: &[HostRuleInput],
+        hosts: &HashMap<String, HostDefinition>,
+    ) -> Result<Vec<Self>, Report<Error>> {
+        rules
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                let model = Regex::new(&rule.model).map_err(|e| {
+                    Report::new(Error::ParseConfig)
+                        .attach_printable(format!("Host rule {}: {e}", i + 1))
+                })?;
+                if !hosts.contains_key(&rule.host) {
+                    return Err(Report::new(Error::UnknownModelHost(rule.host.clone()))
+                        .attach_printable(format!("Used by host rule {}", i + 1))
+                        .change_context(Error::ParseConfig));
+                }
+
+                Ok(Self {
+                    model,
+                    host: rule.host.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// The host from the first rule that matches the model name.
+    pub fn find_host<'a>(rules: &'a [Self], model_name: &str) -> Option<&'a str> {
+        rules
+            .iter()
+            .find(|rule| rule.model.is_match(model_name))
+            .map(|rule| rule.host.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rule(model: &str, host: &str) -> HostRuleInput {
+        HostRuleInput {
+            model: model.to_string(),
+            host: host.to_string(),
+        }
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let rules = HostRule::compile(
+            &[rule("^llama", "together"), rule("llama|mistral", "ollama")],
+            &HostDefinition::builtin(),
+        )
+        .unwrap();
+        assert_eq!(HostRule::find_host(&rules, "llama3.1"), Some("together"));
+        assert_eq!(HostRule::find_host(&rules, "codellama"), Some("ollama"));
+        assert_eq!(HostRule::find_host(&rules, "gpt-4o"), None);
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let hosts = HostDefinition::builtin();
+        assert!(HostRule::compile(&[rule("(", "ollama")], &hosts).is_err());
+        assert!(HostRule::compile(&[rule("llama", "olama")], &hosts).is_err());
+    }
+}
@@ -16,6 +16,7 @@ pub const DEFAULT_HOST: &str = "https://api.together.xyz";
 
 #[derive(Debug)]
 pub struct TogetherHost {
+    agent: ureq::Agent,
     pub host: String,
     pub api_key: Option<String>,
 
@@ -25,8 +26,9 @@ pub struct TogetherHost {
 }
 
 impl TogetherHost {
-    pub fn new(host: String, api_key: Option<String>) -> Self {
+    pub fn new(agent: ureq::Agent, host: String, api_key: Option<String>) -> Self {
         Self {
+            agent,
             host,
             api_key,
             cache: Cache::new().ok(),
@@ -40,7 +42,7 @@ impl TogetherHost {
 
     fn fetch_all_model_info(&self) -> Result<Vec<ModelInfo>, Report<ModelError>> {
         let url = format!("{}/models/info", self.host());
-        add_bearer_token(ureq::get(&url), &self.api_key)
+        add_bearer_token(self.agent.get(&url), &self.api_key)
             .call()
             .map_err(map_model_response_err)
             .attach_printable(url)?
@@ -150,19 +152,27 @@ impl ModelHost for TogetherHost {
     fn send_model_request(
         &self,
         options: &ModelOptions,
-        input: ModelInput,
+        input: &ModelInput,
         message_tx: flume::Sender<String>,
     ) -> Result<(), Report<ModelError>> {
         if !input.images.is_empty() {
             return Err(Report::new(ModelError::HostDoesNotSupportImages));
         }
 
+        if !input.tools.is_empty() {
+            return Err(Report::new(ModelError::HostDoesNotSupportTools));
+        }
+
         let full_spec = options.full_model_spec();
         let model_name = full_spec.model_name();
         let model_info = self.get_model_info(model_name)?;
 
         let prompt = self
-            .format_prompt(&model_info.config, input.prompt, input.system)
+            .format_prompt(
+                &model_info.config,
+                &input.prompt_with_history(),
+                input.system,
+            )
             .change_context(ModelError::FormatPrompt)?;
 
         let mut stop = options.stop.clone();
@@ -174,10 +184,11 @@ impl ModelHost for TogetherHost {
             model: model_name,
             prompt: &prompt,
             response_format: Some(TogetherRequestFormat {
-                typ: match options.format {
-                    Some(OutputFormat::JSON) => "json_object",
+                typ: match (options.format, &options.response_schema) {
+                    (Some(OutputFormat::JSON), _) | (_, Some(_)) => "json_object",
                     _ => "text",
                 },
+                schema: options.response_schema.as_ref().map(|s| s.as_value()),
             }),
             temperature: options.temperature,
             top_p: options.top_p,
@@ -191,8 +202,8 @@ impl ModelHost for TogetherHost {
         event!(Level::INFO, prompt = %prompt, body=?body, "Sending request");
 
         let url = format!("{}/inference", self.host());
-        let request = add_bearer_token(ureq::post(&url), &self.api_key);
-        let mut response = request_with_retry(request, body)
+        let request = add_bearer_token(self.agent.post(&url), &self.api_key);
+        let mut response = request_with_retry(request, body, &options.retry)
             .map_err(map_model_response_err)
             .attach_printable_lazy(|| url.clone())?
             .into_json::<TogetherResponse>()
@@ -217,13 +228,20 @@ impl ModelHost for TogetherHost {
         let context_size = model_info.context_length.unwrap_or(2048);
         Ok(Some(context_size as usize))
     }
+
+    fn list_models(&self) -> Result<Vec<String>, Report<ModelError>> {
+        // Skip the cache, since this is used to check that the host still works.
+        let model_info = self.fetch_all_model_info()?;
+        Ok(model_info.into_iter().map(|m| m.name).collect())
+    }
 }
+
 #[derive(Debug, Serialize)]
 struct TogetherRequest<'a> {
     pub model: &'a str,
     pub prompt: &'a str,
     pub stream: bool,
-    pub response_format: Option<TogetherRequestFormat>,
+    pub response_format: Option<TogetherRequestFormat<'a>>,
     pub temperature: f32,
     pub top_p: Option<f32>,
     pub top_k: Option<u32>,
@@ -233,9 +251,11 @@ struct TogetherRequest<'a> {
 }
 
 #[derive(Debug, Serialize)]
-struct TogetherRequestFormat {
+struct TogetherRequestFormat<'a> {
     #[serde(rename = "type")]
     typ: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schema: Option<&'a serde_json::Value>,
 }
 
 #[derive(Deserialize)]
@@ -0,0 +1,342 @@
+//! Extract the readable part of a web page and convert it to Markdown, for `url` options.
+
+use regex::{Regex, RegexBuilder};
+
+/// Elements that hold page furniture instead of the content, and are removed along with everything
+/// inside them.
+const SKIPPED_ELEMENTS: [&str; 11] = [
+    "script", "style", "noscript", "svg", "nav", "header", "footer", "aside", "form", "iframe",
+    "template",
+];
+
+/// Elements whose content is the main part of the page, in order of preference
+const CONTENT_ELEMENTS: [&str; 3] = ["article", "main", "body"];
+
+fn case_insensitive(pattern: &str) -> Regex {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .dot_matches_new_line(true)
+        .build()
+        .unwrap()
+}
+
+/// The page's title, from the `title` element or else the first `h1`.
+pub fn title(html: &str) -> Option<String> {
+    ["title", "h1"].iter().find_map(|tag| {
+        let regex = case_insensitive(&format!(r"<{tag}\b[^>]*>(.*?)</{tag}\s*>"));
+        let text = regex.captures(html)?.get(1)?.as_str();
+        let text = collapse_whitespace(&decode_entities(&strip_tags(text)));
+        let text = text.trim();
+        (!text.is_empty()).then(|| text.to_string())
+    })
+}
+
+/// The readable content of the page as Markdown, without the navigation, scripts, and other parts
+/// that aren't the content.
+pub fn to_markdown(html: &str) -> String {
+    let mut html = case_insensitive(r"<!--.*?-->")
+        .replace_all(html, "")
+        .to_string();
+    for tag in SKIPPED_ELEMENTS {
+        html = case_insensitive(&format!(r"<{tag}\b.*?</{tag}\s*>"))
+            .replace_all(&html, "")
+            .to_string();
+    }
+
+    let content = CONTENT_ELEMENTS
+        .iter()
+        .find_map(|tag| {
+            let regex = case_insensitive(&format!(r"<{tag}\b[^>]*>(.*)</{tag}\s*>"));
+            Some(regex.captures(&html)?.get(1)?.as_str().to_string())
+        })
+        .unwrap_or(html);
+
+    let mut writer = MarkdownWriter::default();
+    let tag_regex = Regex::new(r"<(/?)([a-zA-Z][a-zA-Z0-9]*)([^>]*)>").unwrap();
+    let mut last = 0;
+    for captures in tag_regex.captures_iter(&content) {
+        let whole = captures.get(0).unwrap();
+        writer.text(&content[last..whole.start()]);
+        last = whole.end();
+
+        let closing = !captures[1].is_empty();
+        let name = captures[2].to_ascii_lowercase();
+        writer.tag(&name, closing, &captures[3]);
+    }
+    writer.text(&content[last..]);
+
+    writer.finish()
+}
+
+#[derive(Default)]
+struct MarkdownWriter {
+    output: String,
+    /// For each list being written, the number of the next item, or `None` for unordered lists
+    lists: Vec<Option<usize>>,
+    /// The output position and target of each link being written
+    links: Vec<(usize, String)>,
+    in_pre: bool,
+}
+
+impl MarkdownWriter {
+    fn text(&mut self, text: &str) {
+        if self.in_pre {
+            self.output.push_str(&decode_entities(text));
+            return;
+        }
+
+        let text = collapse_whitespace(&decode_entities(text));
+        // Whitespace at the start of a line doesn't show up in the page.
+        if self.output.is_empty() || self.output.ends_with('\n') || self.output.ends_with(' ') {
+            self.output.push_str(text.trim_start());
+        } else {
+            self.output.push_str(&text);
+        }
+    }
+
+    fn block(&mut self) {
+        let trimmed = self.output.trim_end_matches(' ').len();
+        self.output.truncate(trimmed);
+        if !self.output.is_empty() && !self.output.ends_with("\n\n") {
+            self.output.push_str(if self.output.ends_with('\n') {
+                "\n"
+            } else {
+                "\n\n"
+            });
+        }
+    }
+
+    fn line(&mut self) {
+        let trimmed = self.output.trim_end_matches(' ').len();
+        self.output.truncate(trimmed);
+        if !self.output.is_empty() && !self.output.ends_with('\n') {
+            self.output.push('\n');
+        }
+    }
+
+    fn tag(&mut self, name: &str, closing: bool, attributes: &str) {
+        match (name, closing) {
+            ("h1" | "h2" | "h3" | "h4" | "h5" | "h6", false) => {
+                self.block();
+                let level = name[1..].parse::<usize>().unwrap_or(1);
+                self.output.push_str(&"#".repeat(level));
+                self.output.push(' ');
+            }
+            ("pre", false) => {
+                self.block();
+                self.output.push_str("```\n");
+                self.in_pre = true;
+            }
+            ("pre", true) => {
+                self.in_pre = false;
+                self.line();
+                self.output.push_str("```");
+                self.block();
+            }
+            ("p" | "div" | "section" | "blockquote" | "table" | "tr" | "figure", _) => self.block(),
+            ("ul", false) => {
+                self.block();
+                self.lists.push(None);
+            }
+            ("ol", false) => {
+                self.block();
+                self.lists.push(Some(1));
+            }
+            ("ul" | "ol", true) => {
+                self.lists.pop();
+                self.block();
+            }
+            ("li", false) => {
+                self.line();
+                let depth = self.lists.len().saturating_sub(1);
+                self.output.push_str(&"  ".repeat(depth));
+                match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        self.output.push_str(&format!("{number}. "));
+                        *number += 1;
+                    }
+                    _ => self.output.push_str("- "),
+                }
+            }
+            ("h1" | "h2" | "h3" | "h4" | "h5" | "h6", true) => self.block(),
+            ("br", _) => self.line(),
+            ("hr", _) => {
+                self.block();
+                self.output.push_str("---");
+                self.block();
+            }
+            ("strong" | "b", _) => self.output.push_str("**"),
+            ("em" | "i", _) => self.output.push('*'),
+            ("code", _) if !self.in_pre => self.output.push('`'),
+            // Cells after the first in a row
+            ("td" | "th", false) if !self.output.is_empty() && !self.output.ends_with('\n') => {
+                self.output.push_str(" | ")
+            }
+            ("a", false) => {
+                let href = attribute(attributes, "href").unwrap_or_default();
+                self.links.push((self.output.len(), href));
+            }
+            ("a", true) => {
+                let Some((start, href)) = self.links.pop() else {
+                    return;
+                };
+                let text = self.output[start..].trim().to_string();
+                if text.is_empty()
+                    || href.is_empty()
+                    || href.starts_with('#')
+                    || href.starts_with("javascript:")
+                {
+                    return;
+                }
+                self.output.truncate(start);
+                self.output.push_str(&format!("[{text}]({href})"));
+            }
+            ("img", _) => {
+                let alt = attribute(attributes, "alt").unwrap_or_default();
+                let src = attribute(attributes, "src").unwrap_or_default();
+                if !alt.is_empty() && !src.is_empty() {
+                    self.output.push_str(&format!("![{alt}]({src})"));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn finish(self) -> String {
+        let text = self
+            .output
+            .lines()
+            .map(|line| line.trim_end())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let text = Regex::new(r"\n{3,}").unwrap().replace_all(&text, "\n\n");
+        text.trim().to_string()
+    }
+}
+
+/// The value of an attribute in the text of a tag after its name.
+fn attribute(attributes: &str, name: &str) -> Option<String> {
+    let regex = case_insensitive(&format!(
+        r#"(?:^|\s){name}\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s>]+))"#
+    ));
+    let captures = regex.captures(attributes)?;
+    let value = captures
+        .get(1)
+        .or_else(|| captures.get(2))
+        .or_else(|| captures.get(3))?;
+    Some(decode_entities(value.as_str()))
+}
+
+fn strip_tags(html: &str) -> String {
+    Regex::new(r"<[^>]*>")
+        .unwrap()
+        .replace_all(html, "")
+        .to_string()
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    Regex::new(r"\s+")
+        .unwrap()
+        .replace_all(text, " ")
+        .to_string()
+}
+
+/// Replace the common named entities and all numeric entities with their characters.
+fn decode_entities(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+
+    let entity_regex = Regex::new(r"&(#[0-9]+|#[xX][0-9a-fA-F]+|[a-zA-Z]+);").unwrap();
+    entity_regex
+        .replace_all(text, |captures: &regex::Captures| {
+            let entity = &captures[1];
+            let decoded = if let Some(hex) = entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+            {
+                u32::from_str_radix(hex, 16).ok().and_then(char::from_u32)
+            } else if let Some(decimal) = entity.strip_prefix('#') {
+                decimal.parse::<u32>().ok().and_then(char::from_u32)
+            } else {
+                match entity {
+                    "amp" => Some('&'),
+                    "lt" => Some('<'),
+                    "gt" => Some('>'),
+                    "quot" => Some('"'),
+                    "apos" => Some('\''),
+                    "nbsp" => Some(' '),
+                    "ndash" => Some('–'),
+                    "mdash" => Some('—'),
+                    "hellip" => Some('…'),
+                    "lsquo" => Some('‘'),
+                    "rsquo" => Some('’'),
+                    "ldquo" => Some('“'),
+                    "rdquo" => Some('”'),
+                    "copy" => Some('©'),
+                    _ => None,
+                }
+            };
+
+            decoded
+                .map(String::from)
+                .unwrap_or_else(|| captures[0].to_string())
+        })
+        .to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+  <title>Rust &amp; Prompts</title>
+  <style>body { color: red; }</style>
+</head>
+<body>
+  <nav><a href="/">Home</a> <a href="/blog">Blog</a></nav>
+  <article>
+    <h1>Writing   prompts</h1>
+    <p>Prompts are <strong>templates</strong> with
+       <a href="https://example.com/docs">docs</a>.</p>
+    <!-- a comment -->
+    <ul>
+      <li>First</li>
+      <li>Second &lt;item&gt;</li>
+    </ul>
+    <ol><li>One</li><li>Two</li></ol>
+    <pre><code>fn main() {
+    run();
+}</code></pre>
+    <script>alert("hi")</script>
+  </article>
+  <footer>Copyright</footer>
+</body>
+</html>"#;
+
+    #[test]
+    fn page_title() {
+        assert_eq!(title(PAGE).as_deref(), Some("Rust & Prompts"));
+        assert_eq!(
+            title("<body><h1>Only <em>a</em> heading</h1></body>").as_deref(),
+            Some("Only a heading")
+        );
+        assert_eq!(title("<p>Nothing</p>"), None);
+    }
+
+    #[test]
+    fn markdown() {
+        assert_eq!(
+            to_markdown(PAGE),
+            "# Writing prompts\n\n\
+             Prompts are **templates** with [docs](https://example.com/docs).\n\n\
+             - First\n\
+             - Second <item>\n\n\
+             1. One\n\
+             2. Two\n\n\
+             ```\nfn main() {\n    run();\n}\n```"
+        );
+    }
+}
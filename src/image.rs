@@ -1,10 +1,89 @@
-use std::path::Path;
+use std::{ffi::OsString, io::Read, path::Path, process::Command};
 
 use base64::{display::Base64Display, engine::general_purpose::STANDARD};
 use error_stack::{Report, ResultExt};
+use schemars::JsonSchema;
+use serde::Deserialize;
 
-use crate::error::Error;
+use crate::{error::Error, option::update_if_none};
 
+/// A command that converts the image file in its first argument to the PNG file in its second.
+struct Converter {
+    program: &'static str,
+    args: fn(input: &Path, output: &Path) -> Vec<OsString>,
+}
+
+/// The commands to try for converting HEIC and AVIF images, which can't be decoded here. `sips`
+/// comes with macOS, `magick` is ImageMagick, and `heif-convert` comes with libheif.
+const CONVERTERS: [Converter; 3] = [
+    Converter {
+        program: "sips",
+        args: |input, output| {
+            vec![
+                "-s".into(),
+                "format".into(),
+                "png".into(),
+                input.into(),
+                "--out".into(),
+                output.into(),
+            ]
+        },
+    },
+    Converter {
+        program: "magick",
+        args: |input, output| vec![input.into(), output.into()],
+    },
+    Converter {
+        program: "heif-convert",
+        args: |input, output| vec![input.into(), output.into()],
+    },
+];
+
+#[derive(Deserialize, Debug, Clone, Default, JsonSchema)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct ImagePolicyInput {
+    /// Remove EXIF, GPS, and other metadata from JPEG and PNG images before sending them.
+    /// Defaults to true.
+    pub strip_metadata: Option<bool>,
+    /// Shrink images so that neither side is longer than this many pixels. This must be greater
+    /// than 0.
+    pub max_dimension: Option<u32>,
+}
+
+impl ImagePolicyInput {
+    /// For any members that are `None` in this `ImagePolicyInput`, use the value from `other`
+    pub fn merge_defaults(&mut self, other: &ImagePolicyInput) {
+        update_if_none(&mut self.strip_metadata, &other.strip_metadata);
+        update_if_none(&mut self.max_dimension, &other.max_dimension);
+    }
+}
+
+/// How to change images before they are sent to the model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImagePolicy {
+    pub strip_metadata: bool,
+    pub max_dimension: Option<u32>,
+}
+
+impl Default for ImagePolicy {
+    fn default() -> Self {
+        Self {
+            strip_metadata: true,
+            max_dimension: None,
+        }
+    }
+}
+
+impl From<ImagePolicyInput> for ImagePolicy {
+    fn from(value: ImagePolicyInput) -> Self {
+        Self {
+            strip_metadata: value.strip_metadata.unwrap_or(true),
+            max_dimension: value.max_dimension,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct ImageData {
     pub mimetype: String,
     pub contents: Vec<u8>,
@@ -21,10 +100,180 @@ impl std::fmt::Debug for ImageData {
 impl ImageData {
     pub fn new(filename: &Path) -> Result<Self, Report<Error>> {
         let contents = std::fs::read(filename).change_context(Error::Image)?;
-        let info = imageinfo::ImageInfo::from_raw_data(&contents).change_context(Error::Image)?;
+        Self::from_bytes(contents, None)
+    }
+
+    /// Read an image from `reader`, such as piped input. The MIME type is detected from the data
+    /// if it isn't given.
+    pub fn from_reader(
+        mut reader: impl Read,
+        mimetype: Option<&str>,
+    ) -> Result<Self, Report<Error>> {
+        let mut contents = Vec::new();
+        reader
+            .read_to_end(&mut contents)
+            .change_context(Error::Image)?;
+        if contents.is_empty() {
+            return Err(Report::new(Error::Image)).attach_printable("No image data was given");
+        }
+
+        Self::from_bytes(contents, mimetype)
+    }
+
+    fn from_bytes(contents: Vec<u8>, mimetype: Option<&str>) -> Result<Self, Report<Error>> {
+        let mimetype = match mimetype {
+            Some(mimetype) => mimetype.to_string(),
+            None => imageinfo::ImageInfo::from_raw_data(&contents)
+                .change_context(Error::Image)?
+                .mimetype
+                .to_string(),
+        };
+
+        let image = ImageData { mimetype, contents };
+        match image.mimetype.as_str() {
+            "image/webp" => image.transcode_to_png(),
+            "image/heic" | "image/heif" | "image/avif" => image.convert_to_png(&CONVERTERS),
+            _ => Ok(image),
+        }
+    }
+
+    /// Convert a format that hosts don't all accept to PNG.
+    #[cfg(feature = "images")]
+    fn transcode_to_png(self) -> Result<Self, Report<Error>> {
+        let image = image::load_from_memory(&self.contents)
+            .change_context(Error::Image)
+            .attach_printable_lazy(|| format!("Decoding {} image", self.mimetype))?;
+        let mut contents = std::io::Cursor::new(Vec::new());
+        image
+            .write_to(&mut contents, image::ImageFormat::Png)
+            .change_context(Error::Image)
+            .attach_printable("Converting image to PNG")?;
 
         Ok(ImageData {
-            mimetype: info.mimetype.to_string(),
+            mimetype: "image/png".to_string(),
+            contents: contents.into_inner(),
+        })
+    }
+
+    /// Without the `images` feature there's no decoder built in, so use an external converter.
+    #[cfg(not(feature = "images"))]
+    fn transcode_to_png(self) -> Result<Self, Report<Error>> {
+        self.convert_to_png(&CONVERTERS)
+    }
+
+    /// Convert the image to PNG with the first of the `converters` that is installed and works.
+    fn convert_to_png(self, converters: &[Converter]) -> Result<Self, Report<Error>> {
+        let extension = self.mimetype.trim_start_matches("image/");
+        let dir = std::env::temp_dir();
+        let id = fastrand::u64(..);
+        let input = dir.join(format!("promptbox-image-{id}.{extension}"));
+        let output = dir.join(format!("promptbox-image-{id}.png"));
+
+        let result = std::fs::write(&input, &self.contents)
+            .change_context(Error::Image)
+            .and_then(|_| run_converters(converters, &input, &output));
+        std::fs::remove_file(&input).ok();
+        std::fs::remove_file(&output).ok();
+
+        let contents = result.attach_printable_lazy(|| {
+            format!(
+                "Converting {} image to PNG. Install ImageMagick or libheif to read this format.",
+                self.mimetype
+            )
+        })?;
+        Ok(ImageData {
+            mimetype: "image/png".to_string(),
+            contents,
+        })
+    }
+
+    /// Shrink the image and remove its metadata, as the policy requires.
+    pub fn apply_policy(self, policy: &ImagePolicy) -> Result<Self, Report<Error>> {
+        let image = match policy.max_dimension {
+            Some(max_dimension) => self.downscale(max_dimension)?,
+            None => self,
+        };
+
+        if policy.strip_metadata {
+            image.strip_metadata()
+        } else {
+            Ok(image)
+        }
+    }
+
+    /// Shrink the image so that neither side is longer than `max_dimension`. JPEG images stay
+    /// JPEGs, and other formats are converted to PNG.
+    fn downscale(self, max_dimension: u32) -> Result<Self, Report<Error>> {
+        let info =
+            imageinfo::ImageInfo::from_raw_data(&self.contents).change_context(Error::Image)?;
+        let max_dimension = i64::from(max_dimension);
+        if info.size.width <= max_dimension && info.size.height <= max_dimension {
+            return Ok(self);
+        }
+
+        self.resize(max_dimension as u32)
+    }
+
+    #[cfg(not(feature = "images"))]
+    fn resize(self, _max_dimension: u32) -> Result<Self, Report<Error>> {
+        Err(Report::new(Error::Image).attach_printable(
+            "This build of PromptBox can't resize images. Rebuild it with the `images` feature, or remove `max_dimension` from the configuration.",
+        ))
+    }
+
+    #[cfg(feature = "images")]
+    fn resize(self, max_dimension: u32) -> Result<Self, Report<Error>> {
+        let image = image::load_from_memory(&self.contents)
+            .change_context(Error::Image)
+            .attach_printable("Decoding image to resize it")?;
+        // The encoded image doesn't keep the EXIF data, so turn the pixels the way that the
+        // Orientation tag says to display them.
+        let image = match jpeg_orientation(&self.contents).unwrap_or(1) {
+            2 => image.fliph(),
+            3 => image.rotate180(),
+            4 => image.flipv(),
+            5 => image.rotate90().fliph(),
+            6 => image.rotate90(),
+            7 => image.rotate270().fliph(),
+            8 => image.rotate270(),
+            _ => image,
+        };
+        let resized = image.resize(
+            max_dimension,
+            max_dimension,
+            image::imageops::FilterType::Lanczos3,
+        );
+
+        let (format, mimetype) = if self.mimetype == "image/jpeg" {
+            (image::ImageFormat::Jpeg, "image/jpeg")
+        } else {
+            (image::ImageFormat::Png, "image/png")
+        };
+        let mut contents = std::io::Cursor::new(Vec::new());
+        resized
+            .write_to(&mut contents, format)
+            .change_context(Error::Image)
+            .attach_printable("Encoding resized image")?;
+
+        Ok(ImageData {
+            mimetype: mimetype.to_string(),
+            contents: contents.into_inner(),
+        })
+    }
+
+    /// Remove the metadata from JPEG and PNG images. Other formats are left as they are.
+    fn strip_metadata(self) -> Result<Self, Report<Error>> {
+        let contents = match self.mimetype.as_str() {
+            "image/jpeg" => strip_jpeg_metadata(&self.contents),
+            "image/png" => strip_png_metadata(&self.contents),
+            _ => return Ok(self),
+        };
+
+        let contents = contents
+            .ok_or(Error::Image)
+            .attach_printable("Could not read the image to remove its metadata")?;
+        Ok(ImageData {
+            mimetype: self.mimetype,
             contents,
         })
     }
@@ -41,3 +290,361 @@ impl ImageData {
         )
     }
 }
+
+/// Run each converter in turn until one of them writes the output file, and return its contents.
+fn run_converters(
+    converters: &[Converter],
+    input: &Path,
+    output: &Path,
+) -> Result<Vec<u8>, Report<Error>> {
+    let mut report = Report::new(Error::Image);
+    for converter in converters {
+        let result = Command::new(converter.program)
+            .args((converter.args)(input, output))
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::piped())
+            .output();
+        match result {
+            Ok(result) if result.status.success() && output.is_file() => {
+                return std::fs::read(output).change_context(Error::Image);
+            }
+            Ok(result) => {
+                report = report.attach_printable(format!(
+                    "{} failed: {}",
+                    converter.program,
+                    String::from_utf8_lossy(&result.stderr).trim()
+                ));
+            }
+            // The converter isn't installed.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                report = report.attach_printable(format!("{} failed: {e}", converter.program));
+            }
+        }
+    }
+
+    Err(report)
+}
+
+const JPEG_APP1: u8 = 0xe1;
+/// The EXIF tag for how the image should be rotated or flipped to display it
+const EXIF_ORIENTATION: u16 = 0x0112;
+
+/// The marker of a JPEG segment, and the whole segment including the marker
+type JpegSegment<'a> = (u8, &'a [u8]);
+
+/// Split a JPEG into the segments before the image data, and the rest of the file. Returns `None`
+/// if the data is not a valid JPEG.
+fn jpeg_segments(data: &[u8]) -> Option<(Vec<JpegSegment<'_>>, &[u8])> {
+    const SOI: u8 = 0xd8;
+    const SOS: u8 = 0xda;
+
+    if data.get(..2)? != [0xff, SOI] {
+        return None;
+    }
+
+    let mut segments = Vec::new();
+    let mut pos = 2;
+    loop {
+        if *data.get(pos)? != 0xff {
+            return None;
+        }
+        let marker = *data.get(pos + 1)?;
+        if marker == SOS {
+            return Some((segments, &data[pos..]));
+        }
+
+        let length = u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]) as usize;
+        let end = pos + 2 + length;
+        segments.push((marker, data.get(pos..end)?));
+        pos = end;
+    }
+}
+
+/// The EXIF Orientation of a JPEG, from 1 to 8, if it has one.
+#[cfg(any(test, feature = "images"))]
+fn jpeg_orientation(data: &[u8]) -> Option<u16> {
+    let (segments, _) = jpeg_segments(data)?;
+    segments
+        .iter()
+        .filter(|(marker, _)| *marker == JPEG_APP1)
+        .find_map(|(_, segment)| exif_orientation(segment))
+}
+
+/// Read the Orientation tag from the first IFD of an APP1 EXIF segment.
+fn exif_orientation(segment: &[u8]) -> Option<u16> {
+    let tiff = segment.get(4..)?.strip_prefix(b"Exif\0\0")?;
+    let big_endian = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let read_u16 = |pos: usize| {
+        let bytes = [*tiff.get(pos)?, *tiff.get(pos + 1)?];
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let read_u32 = |pos: usize| {
+        let bytes = tiff.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+
+    let ifd = read_u32(4)? as usize;
+    (0..read_u16(ifd)? as usize)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|entry| read_u16(*entry) == Some(EXIF_ORIENTATION))
+        .and_then(|entry| read_u16(entry + 8))
+        .filter(|orientation| (1..=8).contains(orientation))
+}
+
+/// An APP1 EXIF segment that holds only the Orientation tag.
+fn orientation_segment(orientation: u16) -> Vec<u8> {
+    let mut segment = vec![0xff, JPEG_APP1, 0, 34];
+    segment.extend_from_slice(b"Exif\0\0");
+    // A big-endian TIFF header, and its first IFD right after it
+    segment.extend_from_slice(b"MM\0\x2a\0\0\0\x08");
+    segment.extend_from_slice(&1u16.to_be_bytes());
+    // The tag, its type (SHORT), how many values it has, and the value padded to 4 bytes
+    segment.extend_from_slice(&EXIF_ORIENTATION.to_be_bytes());
+    segment.extend_from_slice(&[0, 3, 0, 0, 0, 1]);
+    segment.extend_from_slice(&orientation.to_be_bytes());
+    segment.extend_from_slice(&[0, 0]);
+    // No more IFDs
+    segment.extend_from_slice(&[0, 0, 0, 0]);
+    segment
+}
+
+/// Copy a JPEG without its EXIF, XMP, IPTC, and comment segments. The EXIF Orientation tag is
+/// kept, since without it the image may be displayed sideways. Returns `None` if the data is not a
+/// valid JPEG.
+fn strip_jpeg_metadata(data: &[u8]) -> Option<Vec<u8>> {
+    const APP13: u8 = 0xed;
+    const COM: u8 = 0xfe;
+
+    let (segments, image_data) = jpeg_segments(data)?;
+    let mut output = Vec::with_capacity(data.len());
+    output.extend_from_slice(&data[..2]);
+    let mut kept_orientation = false;
+    for (marker, segment) in segments {
+        match marker {
+            JPEG_APP1 if !kept_orientation => {
+                if let Some(orientation) = exif_orientation(segment).filter(|o| *o != 1) {
+                    output.extend_from_slice(&orientation_segment(orientation));
+                    kept_orientation = true;
+                }
+            }
+            JPEG_APP1 | APP13 | COM => {}
+            _ => output.extend_from_slice(segment),
+        }
+    }
+    output.extend_from_slice(image_data);
+
+    Some(output)
+}
+
+/// Copy a PNG without its EXIF, text, and timestamp chunks. Returns `None` if the data is not a
+/// valid PNG.
+fn strip_png_metadata(data: &[u8]) -> Option<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    const METADATA_CHUNKS: [&[u8]; 5] = [b"eXIf", b"tEXt", b"zTXt", b"iTXt", b"tIME"];
+
+    if !data.starts_with(SIGNATURE) {
+        return None;
+    }
+
+    let mut output = Vec::with_capacity(data.len());
+    output.extend_from_slice(SIGNATURE);
+    let mut pos = SIGNATURE.len();
+    while pos < data.len() {
+        let length = u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let chunk_type = data.get(pos + 4..pos + 8)?;
+        // Length, type, data, and CRC
+        let end = pos + 12 + length;
+        if !METADATA_CHUNKS.contains(&chunk_type) {
+            output.extend_from_slice(data.get(pos..end)?);
+        }
+        pos = end;
+    }
+
+    Some(output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::base_dir;
+
+    #[test]
+    fn from_reader() {
+        let contents = std::fs::read(base_dir("test.jpg")).unwrap();
+
+        let image = ImageData::from_reader(contents.as_slice(), None).unwrap();
+        assert_eq!(image.mimetype, "image/jpeg");
+        assert_eq!(image.contents, contents);
+
+        let image = ImageData::from_reader(contents.as_slice(), Some("image/x-custom")).unwrap();
+        assert_eq!(image.mimetype, "image/x-custom");
+
+        assert!(ImageData::from_reader(&b"not an image"[..], None).is_err());
+        assert!(ImageData::from_reader(&b""[..], Some("image/png")).is_err());
+    }
+
+    fn read_image(name: &str) -> ImageData {
+        ImageData::new(&base_dir(name)).unwrap()
+    }
+
+    /// Whether the JPEG has a segment with this marker before the image data
+    fn has_jpeg_segment(data: &[u8], marker: u8) -> bool {
+        let mut pos = 2;
+        while data[pos + 1] != 0xda {
+            if data[pos + 1] == marker {
+                return true;
+            }
+            pos += 2 + u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        }
+        false
+    }
+
+    #[test]
+    fn strip_jpeg() {
+        let original = read_image("test.jpg");
+        assert!(has_jpeg_segment(&original.contents, 0xe1));
+        assert!(has_jpeg_segment(&original.contents, 0xed));
+
+        let stripped = original
+            .clone()
+            .apply_policy(&ImagePolicy::default())
+            .unwrap();
+        assert_eq!(stripped.mimetype, "image/jpeg");
+        assert!(!has_jpeg_segment(&stripped.contents, 0xe1));
+        assert!(!has_jpeg_segment(&stripped.contents, 0xed));
+        // The JFIF header and the image itself are unchanged.
+        assert!(has_jpeg_segment(&stripped.contents, 0xe0));
+        assert!(original.contents.ends_with(&stripped.contents[200..]));
+    }
+
+    #[test]
+    fn keep_orientation() {
+        let original = read_image("test.jpg");
+        assert_eq!(jpeg_orientation(&original.contents), None);
+
+        let mut rotated = original.contents[..2].to_vec();
+        rotated.extend_from_slice(&orientation_segment(6));
+        rotated.extend_from_slice(&original.contents[2..]);
+        assert_eq!(jpeg_orientation(&rotated), Some(6));
+
+        let stripped = strip_jpeg_metadata(&rotated).unwrap();
+        assert_eq!(jpeg_orientation(&stripped), Some(6));
+        assert!(!has_jpeg_segment(&stripped, 0xed));
+        // The orientation replaces the original EXIF segment, which is also removed.
+        assert_eq!(
+            stripped.len(),
+            strip_jpeg_metadata(&original.contents).unwrap().len() + 34 + 2
+        );
+
+        let mut upright = original.contents[..2].to_vec();
+        upright.extend_from_slice(&orientation_segment(1));
+        upright.extend_from_slice(&original.contents[2..]);
+        let stripped = strip_jpeg_metadata(&upright).unwrap();
+        assert!(!has_jpeg_segment(&stripped, 0xe1));
+    }
+
+    #[test]
+    fn strip_png() {
+        let original = read_image("tsunami.png");
+        let contains = |data: &[u8], chunk: &[u8]| data.windows(4).any(|w| w == chunk);
+        assert!(contains(&original.contents, b"tEXt"));
+
+        let stripped = original
+            .clone()
+            .apply_policy(&ImagePolicy::default())
+            .unwrap();
+        assert_eq!(stripped.mimetype, "image/png");
+        assert!(!contains(&stripped.contents, b"tEXt"));
+        assert!(contains(&stripped.contents, b"IDAT"));
+        assert!(contains(&stripped.contents, b"IEND"));
+        // Only the 25 bytes of text and the 12 bytes around them were removed.
+        assert_eq!(stripped.contents.len(), original.contents.len() - 37);
+    }
+
+    #[test]
+    fn keep_metadata() {
+        let original = read_image("test.jpg");
+        let policy = ImagePolicy {
+            strip_metadata: false,
+            max_dimension: None,
+        };
+        let image = original.clone().apply_policy(&policy).unwrap();
+        assert_eq!(image.contents, original.contents);
+    }
+
+    #[cfg(feature = "images")]
+    #[test]
+    fn downscale() {
+        let policy = ImagePolicy {
+            strip_metadata: false,
+            max_dimension: Some(500),
+        };
+        let small = read_image("tsunami.png");
+        let image = small.clone().apply_policy(&policy).unwrap();
+        assert_eq!(image.contents, small.contents);
+
+        // test.jpg is 890x1334
+        let resized = read_image("test.jpg").apply_policy(&policy).unwrap();
+        assert_eq!(resized.mimetype, "image/jpeg");
+        let info = imageinfo::ImageInfo::from_raw_data(&resized.contents).unwrap();
+        assert_eq!(info.size.height, 500);
+        assert!(info.size.width < 500);
+    }
+
+    /// A converter that copies the file as it is, or fails
+    fn copy_converter(program: &'static str) -> Converter {
+        Converter {
+            program,
+            args: |input, output| vec![input.into(), output.into()],
+        }
+    }
+
+    #[test]
+    fn convert_with_first_working_converter() {
+        let image = ImageData {
+            mimetype: "image/heic".to_string(),
+            contents: b"image data".to_vec(),
+        };
+        let converters = [
+            copy_converter("promptbox-nonexistent-converter"),
+            copy_converter("false"),
+            copy_converter("cp"),
+        ];
+
+        let converted = image.convert_to_png(&converters).unwrap();
+        assert_eq!(converted.mimetype, "image/png");
+        assert_eq!(converted.contents, b"image data");
+    }
+
+    #[test]
+    fn no_working_converter() {
+        let image = ImageData {
+            mimetype: "image/avif".to_string(),
+            contents: b"image data".to_vec(),
+        };
+        let converters = [copy_converter("promptbox-nonexistent-converter")];
+        assert!(image.convert_to_png(&converters).is_err());
+    }
+
+    #[test]
+    fn invalid_image_data() {
+        let image = ImageData {
+            mimetype: "image/jpeg".to_string(),
+            contents: b"\xff\xd8\xff\xe1\x00".to_vec(),
+        };
+        assert!(image.apply_policy(&ImagePolicy::default()).is_err());
+    }
+}
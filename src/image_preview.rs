@@ -0,0 +1,293 @@
+//! Draw thumbnails of the images in a prompt on terminals that support graphics, so that it's easy
+//! to check that the right images are attached before sending them.
+
+use std::{fmt::Write, io::IsTerminal};
+
+use crate::image::ImageData;
+
+/// How wide to draw a thumbnail on terminals that scale images to a number of columns
+const PREVIEW_COLUMNS: u32 = 32;
+/// The largest side of a decoded thumbnail, in pixels
+#[cfg(feature = "images")]
+const THUMBNAIL_SIZE: u32 = 256;
+
+/// Base64 data is sent to kitty in chunks of at most this many bytes.
+const KITTY_CHUNK_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphicsProtocol {
+    /// The kitty graphics protocol, also supported by Ghostty
+    Kitty,
+    /// iTerm2 inline images, also supported by WezTerm
+    Iterm2,
+    Sixel,
+}
+
+impl GraphicsProtocol {
+    /// Guess the protocol from the environment variables that terminals set.
+    pub fn detect(get_env: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        let term = get_env("TERM").unwrap_or_default();
+        let term_program = get_env("TERM_PROGRAM").unwrap_or_default();
+
+        if get_env("KITTY_WINDOW_ID").is_some()
+            || term == "xterm-kitty"
+            || term_program == "ghostty"
+        {
+            Some(Self::Kitty)
+        } else if matches!(term_program.as_str(), "iTerm.app" | "WezTerm")
+            || get_env("LC_TERMINAL").as_deref() == Some("iTerm2")
+        {
+            Some(Self::Iterm2)
+        } else if term.contains("sixel")
+            || matches!(term.as_str(), "foot" | "foot-extra" | "mlterm")
+        {
+            Some(Self::Sixel)
+        } else {
+            None
+        }
+    }
+}
+
+/// Describe the images, with a thumbnail of each when stderr is a terminal that can draw them.
+pub fn print_previews(images: &[ImageData]) {
+    let protocol = if std::io::stderr().is_terminal() {
+        GraphicsProtocol::detect(|var| std::env::var(var).ok())
+    } else {
+        None
+    };
+
+    for (i, image) in images.iter().enumerate() {
+        eprintln!(
+            "== Image {} of {}: {}, {} KB",
+            i + 1,
+            images.len(),
+            image.mimetype,
+            image.contents.len().div_ceil(1024)
+        );
+        if let Some(preview) = protocol.and_then(|protocol| preview(protocol, image)) {
+            eprintln!("{preview}");
+        }
+        eprintln!();
+    }
+}
+
+/// The escape codes to draw a thumbnail of the image, or `None` if it can't be drawn.
+#[cfg(feature = "images")]
+fn preview(protocol: GraphicsProtocol, image: &ImageData) -> Option<String> {
+    let thumbnail = image::load_from_memory(&image.contents)
+        .ok()?
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+
+    match protocol {
+        GraphicsProtocol::Sixel => {
+            let rgb = thumbnail.to_rgb8();
+            let (width, height) = (rgb.width() as usize, rgb.height() as usize);
+            Some(encode_sixel(width, height, &rgb.into_raw()))
+        }
+        GraphicsProtocol::Kitty | GraphicsProtocol::Iterm2 => {
+            let mut contents = std::io::Cursor::new(Vec::new());
+            thumbnail
+                .write_to(&mut contents, image::ImageFormat::Png)
+                .ok()?;
+            let png = ImageData {
+                mimetype: "image/png".to_string(),
+                contents: contents.into_inner(),
+            };
+            Some(match protocol {
+                GraphicsProtocol::Kitty => encode_kitty(&png),
+                _ => encode_iterm2(&png),
+            })
+        }
+    }
+}
+
+/// Without the `images` feature the image can't be decoded, so send it to terminals that decode
+/// it themselves.
+#[cfg(not(feature = "images"))]
+fn preview(protocol: GraphicsProtocol, image: &ImageData) -> Option<String> {
+    match protocol {
+        // kitty only decodes PNG itself.
+        GraphicsProtocol::Kitty if image.mimetype == "image/png" => Some(encode_kitty(image)),
+        GraphicsProtocol::Iterm2 => Some(encode_iterm2(image)),
+        _ => None,
+    }
+}
+
+/// Escape codes for kitty to draw a PNG image, scaled to the preview width
+fn encode_kitty(png: &ImageData) -> String {
+    let data = png.as_base64();
+    let chunks = data.as_bytes().chunks(KITTY_CHUNK_SIZE).collect::<Vec<_>>();
+    let mut output = String::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = u8::from(i + 1 < chunks.len());
+        // Base64 is ASCII, so every chunk is valid UTF-8.
+        let chunk = std::str::from_utf8(chunk).unwrap_or_default();
+        if i == 0 {
+            write!(
+                output,
+                "\x1b_Ga=T,f=100,c={PREVIEW_COLUMNS},m={more};{chunk}\x1b\\"
+            )
+        } else {
+            write!(output, "\x1b_Gm={more};{chunk}\x1b\\")
+        }
+        .ok();
+    }
+    output
+}
+
+/// The escape code for iTerm2 to draw an image in any format that it can decode
+fn encode_iterm2(image: &ImageData) -> String {
+    format!(
+        "\x1b]1337;File=inline=1;size={};width={PREVIEW_COLUMNS};preserveAspectRatio=1:{}\x07",
+        image.contents.len(),
+        image.as_base64()
+    )
+}
+
+/// Encode RGB pixels as sixels, with the colors reduced to a 6x6x6 cube.
+#[cfg(feature = "images")]
+fn encode_sixel(width: usize, height: usize, rgb: &[u8]) -> String {
+    let level = |value: u8| (usize::from(value) * 5 + 127) / 255;
+    let colors = rgb
+        .chunks_exact(3)
+        .map(|pixel| level(pixel[0]) * 36 + level(pixel[1]) * 6 + level(pixel[2]))
+        .collect::<Vec<_>>();
+
+    let mut output = format!("\x1bPq\"1;1;{width};{height}");
+    let mut used = colors.clone();
+    used.sort_unstable();
+    used.dedup();
+    for color in &used {
+        let percent = |level: usize| level * 100 / 5;
+        write!(
+            output,
+            "#{color};2;{};{};{}",
+            percent(color / 36),
+            percent(color / 6 % 6),
+            percent(color % 6)
+        )
+        .ok();
+    }
+
+    for band in (0..height).step_by(6) {
+        let rows = band..(band + 6).min(height);
+        let mut band_colors = rows
+            .clone()
+            .flat_map(|y| &colors[y * width..(y + 1) * width])
+            .copied()
+            .collect::<Vec<_>>();
+        band_colors.sort_unstable();
+        band_colors.dedup();
+
+        for (i, color) in band_colors.iter().enumerate() {
+            if i > 0 {
+                // Go back to the start of the band to draw the next color.
+                output.push('$');
+            }
+            write!(output, "#{color}").ok();
+
+            let sixels = (0..width).map(|x| {
+                rows.clone()
+                    .enumerate()
+                    .filter(|(_, y)| colors[y * width + x] == *color)
+                    .fold(0u8, |bits, (bit, _)| bits | (1 << bit))
+            });
+            push_runs(&mut output, sixels);
+        }
+        output.push('-');
+    }
+
+    output.push_str("\x1b\\");
+    output
+}
+
+/// Write the sixels, replacing runs of the same sixel with a repeat count.
+#[cfg(feature = "images")]
+fn push_runs(output: &mut String, sixels: impl Iterator<Item = u8>) {
+    let flush = |output: &mut String, sixel: u8, count: usize| {
+        let c = char::from(63 + sixel);
+        if count > 3 {
+            write!(output, "!{count}{c}").ok();
+        } else {
+            for _ in 0..count {
+                output.push(c);
+            }
+        }
+    };
+
+    let mut run: Option<(u8, usize)> = None;
+    for sixel in sixels {
+        run = match run {
+            Some((current, count)) if current == sixel => Some((current, count + 1)),
+            Some((current, count)) => {
+                flush(output, current, count);
+                Some((sixel, 1))
+            }
+            None => Some((sixel, 1)),
+        };
+    }
+    if let Some((sixel, count)) = run {
+        flush(output, sixel, count);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn detect(vars: &[(&str, &str)]) -> Option<GraphicsProtocol> {
+        let vars = vars.iter().copied().collect::<HashMap<_, _>>();
+        GraphicsProtocol::detect(|var| vars.get(var).map(|v| v.to_string()))
+    }
+
+    #[test]
+    fn detect_protocol() {
+        assert_eq!(
+            detect(&[("TERM", "xterm-kitty")]),
+            Some(GraphicsProtocol::Kitty)
+        );
+        assert_eq!(
+            detect(&[("TERM", "xterm-256color"), ("TERM_PROGRAM", "iTerm.app")]),
+            Some(GraphicsProtocol::Iterm2)
+        );
+        assert_eq!(detect(&[("TERM", "foot")]), Some(GraphicsProtocol::Sixel));
+        assert_eq!(detect(&[("TERM", "xterm-256color")]), None);
+        assert_eq!(detect(&[]), None);
+    }
+
+    #[test]
+    fn kitty_chunks() {
+        let image = ImageData {
+            mimetype: "image/png".to_string(),
+            contents: vec![0; 4000],
+        };
+        let encoded = encode_kitty(&image);
+        let chunks = encoded
+            .split("\x1b\\")
+            .filter(|c| !c.is_empty())
+            .collect::<Vec<_>>();
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].starts_with("\x1b_Ga=T,f=100,c=32,m=1;"));
+        assert!(chunks[1].starts_with("\x1b_Gm=0;"));
+    }
+
+    #[cfg(feature = "images")]
+    #[test]
+    fn sixel() {
+        // A red pixel above a blue one
+        let rgb = [255, 0, 0, 0, 0, 255];
+        assert_eq!(
+            encode_sixel(1, 2, &rgb),
+            "\x1bPq\"1;1;1;2#5;2;0;0;100#180;2;100;0;0#5A$#180@-\x1b\\"
+        );
+
+        // Runs of the same sixel are shortened.
+        let white = [255; 5 * 6 * 3];
+        assert_eq!(
+            encode_sixel(5, 6, &white),
+            "\x1bPq\"1;1;5;6#215;2;100;100;100#215!5~-\x1b\\"
+        );
+    }
+}
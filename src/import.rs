@@ -0,0 +1,828 @@
+//! Convert prompts from other tools into PromptBox templates, so that an existing library doesn't
+//! have to be rewritten by hand.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use error_stack::{Report, ResultExt};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    front_matter::{split_front_matter, FrontMatter},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ImportSource {
+    /// A Fabric patterns directory, with a `system.md` in each pattern's directory
+    Fabric,
+    /// AIChat roles, either a directory of Markdown files or a `roles.yaml` file
+    Aichat,
+    /// A directory of `llm` YAML templates, or a single template file
+    Llm,
+}
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct ImportedModel {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+}
+
+impl ImportedModel {
+    fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ImportedOption {
+    #[serde(rename = "type")]
+    pub option_type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+}
+
+/// The contents of the `.pb.toml` file for an imported prompt
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TemplateFile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    pub template: String,
+    #[serde(skip_serializing_if = "ImportedModel::is_empty")]
+    pub model: ImportedModel,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub options: BTreeMap<String, ImportedOption>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportedTemplate {
+    pub name: String,
+    pub file: TemplateFile,
+}
+
+/// What happened to each template when writing them out
+#[derive(Debug, PartialEq)]
+pub enum ImportOutcome {
+    Written(PathBuf),
+    /// A template with the same name already exists, and `--force` wasn't given
+    Exists(PathBuf),
+    /// The prompt's name has no characters that can be used in a file name
+    Unnamed,
+}
+
+/// Whether `location` is a git repository to clone rather than a local path.
+fn is_repo_url(location: &str) -> bool {
+    !Path::new(location).exists()
+        && (location.contains("://") || location.starts_with("git@") || location.ends_with(".git"))
+}
+
+/// Read the prompts at `location`, which may be a local path or the URL of a git repository. A
+/// repository is cloned into a temporary directory which is removed afterward.
+pub fn read_templates_from(
+    source: ImportSource,
+    location: &str,
+) -> Result<Vec<ImportedTemplate>, Report<Error>> {
+    if !is_repo_url(location) {
+        return read_templates(source, Path::new(location));
+    }
+
+    let dir = tempfile::Builder::new()
+        .prefix("promptbox-import-")
+        .tempdir()
+        .change_context(Error::Import)?;
+    clone_repo(location, dir.path())
+        .and_then(|_| read_templates(source, dir.path()))
+        .attach_printable_lazy(|| location.to_string())
+}
+
+fn clone_repo(url: &str, dir: &Path) -> Result<(), Report<Error>> {
+    let output = std::process::Command::new("git")
+        // The URL comes from the user, so keep git from reading it as an option.
+        .args(["clone", "--quiet", "--depth", "1", "--", url])
+        .arg(dir)
+        .output()
+        .change_context(Error::Git)
+        .attach_printable_lazy(|| format!("git clone {url}"))?;
+
+    if !output.status.success() {
+        return Err(Report::new(Error::Git))
+            .attach_printable(format!("git clone {url}"))
+            .attach_printable(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+
+    Ok(())
+}
+
+/// Read the prompts at `path` and convert them into templates.
+pub fn read_templates(
+    source: ImportSource,
+    path: &Path,
+) -> Result<Vec<ImportedTemplate>, Report<Error>> {
+    let mut templates = match source {
+        ImportSource::Fabric => read_fabric(path),
+        ImportSource::Aichat => read_aichat(path),
+        ImportSource::Llm => read_llm(path),
+    }
+    .attach_printable_lazy(|| path.display().to_string())?;
+
+    templates.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(templates)
+}
+
+/// Write the templates into `dir`, leaving existing templates alone unless `force` is set.
+pub fn write_templates(
+    templates: &[ImportedTemplate],
+    dir: &Path,
+    force: bool,
+) -> Result<Vec<(String, ImportOutcome)>, Report<Error>> {
+    std::fs::create_dir_all(dir)
+        .change_context(Error::Import)
+        .attach_printable_lazy(|| dir.display().to_string())?;
+
+    templates
+        .iter()
+        .map(|template| {
+            if template.name.is_empty() {
+                return Ok((template.name.clone(), ImportOutcome::Unnamed));
+            }
+
+            let path = dir.join(format!("{}.pb.toml", template.name));
+            if path.exists() && !force {
+                return Ok((template.name.clone(), ImportOutcome::Exists(path)));
+            }
+
+            let contents = toml::to_string_pretty(&template.file)
+                .change_context(Error::Import)
+                .attach_printable_lazy(|| template.name.clone())?;
+            std::fs::write(&path, contents)
+                .change_context(Error::Import)
+                .attach_printable_lazy(|| path.display().to_string())?;
+            Ok((template.name.clone(), ImportOutcome::Written(path)))
+        })
+        .collect()
+}
+
+fn read_file(path: &Path) -> Result<String, Report<Error>> {
+    std::fs::read_to_string(path)
+        .change_context(Error::Import)
+        .attach_printable_lazy(|| path.display().to_string())
+}
+
+/// The files in a directory with the given extension, or just `path` if it is a file.
+fn files_with_extension(path: &Path, extensions: &[&str]) -> Result<Vec<PathBuf>, Report<Error>> {
+    if path.is_file() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let entries = std::fs::read_dir(path)
+        .change_context(Error::Import)
+        .attach_printable_lazy(|| path.display().to_string())?;
+    Ok(entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| extensions.contains(&ext))
+        })
+        .collect())
+}
+
+/// Turn a name from another tool into one that works as a template file name.
+fn template_name(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect::<String>();
+    name.trim_matches('-').to_string()
+}
+
+/// Text from another tool may contain Tera syntax by accident, so keep it from being rendered.
+fn literal(text: &str) -> String {
+    if text.contains("{{") || text.contains("{%") || text.contains("{#") {
+        format!("{{% raw %}}{text}{{% endraw %}}")
+    } else {
+        text.to_string()
+    }
+}
+
+/// Replace each occurrence of `marker` in `text` with the extra input.
+fn replace_input_marker(text: &str, marker: &str) -> String {
+    text.split(marker)
+        .map(literal)
+        .collect::<Vec<_>>()
+        .join("{{ extra }}")
+}
+
+/// A short description from the first line of a prompt that isn't a heading.
+fn describe(prompt: &str) -> Option<String> {
+    let line = prompt
+        .lines()
+        .map(|line| line.trim())
+        .find(|line| !line.is_empty() && !line.starts_with('#'))?;
+    let sentence = match line.find(". ") {
+        Some(end) => &line[..=end],
+        None => line,
+    };
+    let sentence = match sentence.char_indices().nth(120) {
+        Some((index, _)) => format!("{}...", &sentence[..index]),
+        None => sentence.to_string(),
+    };
+    Some(sentence)
+}
+
+/// The descriptions that the Fabric repository keeps for its patterns
+#[derive(Deserialize, Debug)]
+struct FabricDescriptions {
+    patterns: Vec<FabricDescription>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct FabricDescription {
+    pattern_name: String,
+    description: String,
+}
+
+/// Read the pattern descriptions from the first of `paths` that exists.
+fn read_fabric_descriptions(paths: &[PathBuf]) -> Result<HashMap<String, String>, Report<Error>> {
+    let Some(path) = paths.iter().find(|path| path.is_file()) else {
+        return Ok(HashMap::new());
+    };
+
+    let descriptions: FabricDescriptions = serde_json::from_str(&read_file(path)?)
+        .change_context(Error::Import)
+        .attach_printable_lazy(|| path.display().to_string())?;
+    Ok(descriptions
+        .patterns
+        .into_iter()
+        .map(|p| (p.pattern_name, p.description))
+        .collect())
+}
+
+/// Fabric keeps each pattern in its own directory, with the instructions in `system.md` and
+/// sometimes a `user.md` that goes before the input. `path` may also be a checkout of the Fabric
+/// repository, which keeps the patterns in `data/patterns`, or `patterns` in older versions.
+fn read_fabric(path: &Path) -> Result<Vec<ImportedTemplate>, Report<Error>> {
+    let patterns_dir = if path.join("system.md").is_file() {
+        path.to_path_buf()
+    } else {
+        ["data/patterns", "patterns"]
+            .iter()
+            .map(|sub| path.join(sub))
+            .find(|dir| dir.is_dir())
+            .unwrap_or_else(|| path.to_path_buf())
+    };
+
+    let pattern_dirs = if patterns_dir.join("system.md").is_file() {
+        vec![patterns_dir.clone()]
+    } else {
+        std::fs::read_dir(&patterns_dir)
+            .change_context(Error::Import)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|dir| dir.join("system.md").is_file())
+            .collect()
+    };
+
+    let descriptions = read_fabric_descriptions(&[
+        patterns_dir.join("pattern_descriptions.json"),
+        path.join("scripts/pattern_descriptions/pattern_descriptions.json"),
+    ])?;
+
+    pattern_dirs
+        .into_iter()
+        .map(|dir| {
+            let name = dir
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let system = read_file(&dir.join("system.md"))?;
+            let user = std::fs::read_to_string(dir.join("user.md")).unwrap_or_default();
+            // Prefer the description that Fabric lists for the pattern, then its README.
+            let description = descriptions.get(&name).cloned().or_else(|| {
+                std::fs::read_to_string(dir.join("README.md"))
+                    .ok()
+                    .and_then(|readme| describe(&readme))
+            });
+            let template = if user.trim().is_empty() {
+                "{{ extra }}".to_string()
+            } else {
+                format!("{}\n\n{{{{ extra }}}}", literal(user.trim()))
+            };
+
+            Ok(ImportedTemplate {
+                name: template_name(&name),
+                file: TemplateFile {
+                    description: description.or_else(|| describe(&system)),
+                    system_prompt: Some(literal(system.trim())),
+                    template,
+                    model: ImportedModel::default(),
+                    options: BTreeMap::new(),
+                },
+            })
+        })
+        .collect()
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct AichatRole {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    prompt: String,
+    description: Option<String>,
+    model: Option<String>,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+}
+
+impl AichatRole {
+    /// A role with `__INPUT__` in its prompt is a template for the input. Otherwise the prompt is
+    /// the system prompt.
+    fn into_template(self) -> ImportedTemplate {
+        const INPUT_MARKER: &str = "__INPUT__";
+        let prompt = self.prompt.trim();
+        let (system_prompt, template) = if prompt.contains(INPUT_MARKER) {
+            (None, replace_input_marker(prompt, INPUT_MARKER))
+        } else {
+            (Some(literal(prompt)), "{{ extra }}".to_string())
+        };
+
+        ImportedTemplate {
+            name: template_name(&self.name),
+            file: TemplateFile {
+                description: self.description.or_else(|| describe(prompt)),
+                system_prompt,
+                template,
+                model: ImportedModel {
+                    // AIChat models are named as `client:model`, and the clients are set up
+                    // separately, so only the model name carries over.
+                    model: self
+                        .model
+                        .map(|m| m.split_once(':').map(|(_, m)| m.to_string()).unwrap_or(m)),
+                    temperature: self.temperature,
+                    top_p: self.top_p,
+                },
+                options: BTreeMap::new(),
+            },
+        }
+    }
+}
+
+/// AIChat roles are either Markdown files with the settings in the front matter, or entries in an
+/// older `roles.yaml` file.
+fn read_aichat(path: &Path) -> Result<Vec<ImportedTemplate>, Report<Error>> {
+    let files = files_with_extension(path, &["md", "yaml", "yml"])?;
+    let mut templates = Vec::new();
+    for file in files {
+        let contents = read_file(&file)?;
+        let is_yaml = file
+            .extension()
+            .is_some_and(|ext| ext == "yaml" || ext == "yml");
+
+        if is_yaml {
+            let roles = serde_yaml::from_str::<Vec<AichatRole>>(&contents)
+                .change_context(Error::Import)
+                .attach_printable_lazy(|| file.display().to_string())?;
+            templates.extend(roles.into_iter().map(AichatRole::into_template));
+        } else {
+            // AIChat only uses YAML front matter.
+            let (front_matter, body) = match split_front_matter(&contents) {
+                (Some(FrontMatter::Yaml(yaml)), body) => (Some(yaml), body),
+                _ => (None, contents.as_str()),
+            };
+            let mut role = match front_matter {
+                Some(yaml) => serde_yaml::from_str::<Option<AichatRole>>(yaml)
+                    .change_context(Error::Import)
+                    .attach_printable_lazy(|| file.display().to_string())?
+                    .unwrap_or_default(),
+                None => AichatRole::default(),
+            };
+            role.name = file
+                .file_stem()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default();
+            role.prompt = body.to_string();
+            templates.push(role.into_template());
+        }
+    }
+
+    Ok(templates)
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct LlmTemplate {
+    prompt: Option<String>,
+    system: Option<String>,
+    model: Option<String>,
+    description: Option<String>,
+    #[serde(default)]
+    defaults: BTreeMap<String, serde_yaml::Value>,
+    #[serde(default)]
+    options: BTreeMap<String, serde_yaml::Value>,
+}
+
+/// Convert `$name` and `${name}` variables into Tera, with `$input` as the extra input. The names of
+/// the other variables are added to `variables`.
+fn convert_llm_variables(text: &str, variables: &mut Vec<String>) -> String {
+    let re = Regex::new(r"\$\$|\$\{([_A-Za-z][_A-Za-z0-9]*)\}|\$([_A-Za-z][_A-Za-z0-9]*)").unwrap();
+    let mut output = String::new();
+    let mut literal_text = String::new();
+    let mut last = 0;
+    for captures in re.captures_iter(text) {
+        let whole = captures.get(0).unwrap();
+        literal_text.push_str(&text[last..whole.start()]);
+        last = whole.end();
+
+        let Some(name) = captures.get(1).or_else(|| captures.get(2)) else {
+            literal_text.push('$');
+            continue;
+        };
+
+        output.push_str(&literal(&literal_text));
+        literal_text.clear();
+        if name.as_str() == "input" {
+            output.push_str("{{ extra }}");
+        } else {
+            output.push_str(&format!("{{{{ {} }}}}", name.as_str()));
+            if !variables.iter().any(|v| v == name.as_str()) {
+                variables.push(name.as_str().to_string());
+            }
+        }
+    }
+    literal_text.push_str(&text[last..]);
+    output.push_str(&literal(&literal_text));
+    output
+}
+
+fn yaml_to_string(value: &serde_yaml::Value) -> Option<String> {
+    match value {
+        serde_yaml::Value::String(s) => Some(s.clone()),
+        serde_yaml::Value::Number(n) => Some(n.to_string()),
+        serde_yaml::Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// `llm` templates are YAML files with a prompt and system prompt that use `$variables`, or just a
+/// string with the prompt.
+fn read_llm(path: &Path) -> Result<Vec<ImportedTemplate>, Report<Error>> {
+    files_with_extension(path, &["yaml", "yml"])?
+        .into_iter()
+        .map(|file| {
+            let contents = read_file(&file)?;
+            let value = serde_yaml::from_str::<serde_yaml::Value>(&contents)
+                .change_context(Error::Import)
+                .attach_printable_lazy(|| file.display().to_string())?;
+            let llm = match value {
+                serde_yaml::Value::String(prompt) => LlmTemplate {
+                    prompt: Some(prompt),
+                    ..Default::default()
+                },
+                value => serde_yaml::from_value::<LlmTemplate>(value)
+                    .change_context(Error::Import)
+                    .attach_printable_lazy(|| file.display().to_string())?,
+            };
+
+            let mut variables = Vec::new();
+            let system_prompt = llm
+                .system
+                .as_deref()
+                .map(|s| convert_llm_variables(s.trim(), &mut variables));
+            let template = match llm.prompt.as_deref() {
+                Some(prompt) => {
+                    let template = convert_llm_variables(prompt.trim(), &mut variables);
+                    // llm appends the input to a prompt that doesn't use it.
+                    if prompt.contains("$input") || prompt.contains("${input}") {
+                        template
+                    } else {
+                        format!("{template}\n\n{{{{ extra }}}}")
+                    }
+                }
+                None => "{{ extra }}".to_string(),
+            };
+
+            let options = variables
+                .into_iter()
+                .map(|name| {
+                    let default = llm.defaults.get(&name).and_then(yaml_to_string);
+                    (
+                        name,
+                        ImportedOption {
+                            option_type: "string",
+                            default,
+                        },
+                    )
+                })
+                .collect();
+
+            let option_f32 = |name: &str| {
+                llm.options
+                    .get(name)
+                    .and_then(|v| v.as_f64())
+                    .map(|v| v as f32)
+            };
+
+            let name = file
+                .file_stem()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default();
+            Ok(ImportedTemplate {
+                name: template_name(&name),
+                file: TemplateFile {
+                    description: llm
+                        .description
+                        .or_else(|| describe(llm.system.as_deref().or(llm.prompt.as_deref())?)),
+                    system_prompt,
+                    template,
+                    model: ImportedModel {
+                        model: llm.model,
+                        temperature: option_f32("temperature"),
+                        top_p: option_f32("top_p"),
+                    },
+                    options,
+                },
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::template::PromptTemplate;
+
+    fn write(dir: &Path, path: &str, contents: &str) {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    /// Make sure that the template can be loaded, and return it.
+    fn round_trip(template: &ImportedTemplate) -> PromptTemplate {
+        let contents = toml::to_string_pretty(&template.file).unwrap();
+        toml::from_str::<PromptTemplate>(&contents).unwrap()
+    }
+
+    #[test]
+    fn fabric() {
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            dir.path(),
+            "summarize/system.md",
+            "# IDENTITY and PURPOSE\n\nYou summarize content. You are concise.\n\n# OUTPUT\n\nUse {{bullets}}.\n",
+        );
+        write(dir.path(), "summarize/user.md", "\n");
+        write(dir.path(), "not_a_pattern/README.md", "Nothing here");
+
+        let templates = read_templates(ImportSource::Fabric, dir.path()).unwrap();
+        assert_eq!(templates.len(), 1);
+        let template = &templates[0];
+        assert_eq!(template.name, "summarize");
+        assert_eq!(
+            template.file.description.as_deref(),
+            Some("You summarize content.")
+        );
+        assert_eq!(template.file.template, "{{ extra }}");
+        let system = template.file.system_prompt.as_deref().unwrap();
+        assert!(system.starts_with("{% raw %}# IDENTITY"), "{system}");
+
+        let parsed = round_trip(template);
+        assert_eq!(parsed.description, "You summarize content.");
+    }
+
+    #[test]
+    fn fabric_repo() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().join("fabric");
+        write(
+            &repo,
+            "data/patterns/summarize/system.md",
+            "You summarize.\n",
+        );
+        write(
+            &repo,
+            "data/patterns/extract_wisdom/system.md",
+            "You extract wisdom.\n",
+        );
+        write(
+            &repo,
+            "data/patterns/extract_wisdom/README.md",
+            "# extract_wisdom\n\nPulls the insights out of a talk.\n",
+        );
+        write(
+            &repo,
+            "data/patterns/explain_code/system.md",
+            "You explain.\n",
+        );
+        write(
+            &repo,
+            "scripts/pattern_descriptions/pattern_descriptions.json",
+            r#"{"patterns": [{"patternName": "summarize", "description": "Summarize content.", "tags": ["WRITING"]}]}"#,
+        );
+
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .args(args)
+                .current_dir(&repo)
+                .output()
+                .unwrap()
+                .status;
+            assert!(status.success(), "git {args:?}");
+        };
+        git(&["init", "--quiet"]);
+        git(&["add", "."]);
+        git(&[
+            "-c",
+            "user.name=test",
+            "-c",
+            "user.email=test@example.com",
+            "commit",
+            "--quiet",
+            "-m",
+            "patterns",
+        ]);
+
+        let url = format!("file://{}", repo.display());
+        let templates = read_templates_from(ImportSource::Fabric, &url).unwrap();
+        let descriptions = templates
+            .iter()
+            .map(|t| (t.name.as_str(), t.file.description.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            descriptions,
+            vec![
+                ("explain_code", Some("You explain.")),
+                ("extract_wisdom", Some("Pulls the insights out of a talk.")),
+                ("summarize", Some("Summarize content.")),
+            ]
+        );
+    }
+
+    #[test]
+    fn aichat_markdown() {
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            dir.path(),
+            "roles/shell.md",
+            "---\nmodel: openai:gpt-4o\ntemperature: 0.2\n---\nConvert the request into a shell command.\n",
+        );
+        write(
+            dir.path(),
+            "roles/translate.md",
+            "Translate this into French:\n__INPUT__\n",
+        );
+
+        let templates = read_templates(ImportSource::Aichat, &dir.path().join("roles")).unwrap();
+        assert_eq!(templates.len(), 2);
+
+        let shell = &templates[0];
+        assert_eq!(shell.name, "shell");
+        assert_eq!(
+            shell.file.system_prompt.as_deref(),
+            Some("Convert the request into a shell command.")
+        );
+        assert_eq!(shell.file.template, "{{ extra }}");
+        assert_eq!(shell.file.model.model.as_deref(), Some("gpt-4o"));
+        assert_eq!(shell.file.model.temperature, Some(0.2));
+
+        let translate = &templates[1];
+        assert_eq!(translate.file.system_prompt, None);
+        assert_eq!(
+            translate.file.template,
+            "Translate this into French:\n{{ extra }}"
+        );
+
+        for template in &templates {
+            round_trip(template);
+        }
+    }
+
+    #[test]
+    fn aichat_yaml() {
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            dir.path(),
+            "roles.yaml",
+            "- name: emoji\n  prompt: Reply only with emojis\n  temperature: 0.9\n",
+        );
+
+        let templates =
+            read_templates(ImportSource::Aichat, &dir.path().join("roles.yaml")).unwrap();
+        assert_eq!(templates.len(), 1);
+        assert_eq!(templates[0].name, "emoji");
+        assert_eq!(templates[0].file.model.temperature, Some(0.9));
+        round_trip(&templates[0]);
+    }
+
+    #[test]
+    fn llm() {
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            dir.path(),
+            "recipe.yaml",
+            "system: You are a ${cuisine} chef who charges $$5.\nprompt: Make a recipe using $input for $people people\nmodel: gpt-4o-mini\ndefaults:\n  people: 4\noptions:\n  temperature: 0.5\n",
+        );
+        write(dir.path(), "brief.yml", "'Summarize this briefly'");
+
+        let templates = read_templates(ImportSource::Llm, dir.path()).unwrap();
+        assert_eq!(templates.len(), 2);
+
+        let brief = &templates[0];
+        assert_eq!(brief.name, "brief");
+        assert_eq!(brief.file.template, "Summarize this briefly\n\n{{ extra }}");
+
+        let recipe = &templates[1];
+        assert_eq!(
+            recipe.file.system_prompt.as_deref(),
+            Some("You are a {{ cuisine }} chef who charges $5.")
+        );
+        assert_eq!(
+            recipe.file.template,
+            "Make a recipe using {{ extra }} for {{ people }} people"
+        );
+        assert_eq!(recipe.file.model.model.as_deref(), Some("gpt-4o-mini"));
+        assert_eq!(recipe.file.model.temperature, Some(0.5));
+        assert_eq!(
+            recipe.file.options.get("people"),
+            Some(&ImportedOption {
+                option_type: "string",
+                default: Some("4".to_string())
+            })
+        );
+        assert_eq!(recipe.file.options["cuisine"].default, None);
+
+        let parsed = round_trip(recipe);
+        assert!(parsed.options["cuisine"].required());
+        assert!(!parsed.options["people"].required());
+    }
+
+    #[test]
+    fn write_skips_existing() {
+        let dir = tempfile::tempdir().unwrap();
+        let template = ImportedTemplate {
+            name: "summarize".to_string(),
+            file: TemplateFile {
+                description: None,
+                system_prompt: None,
+                template: "Summarize this\n\n{{ extra }}".to_string(),
+                model: ImportedModel::default(),
+                options: BTreeMap::new(),
+            },
+        };
+        let path = dir.path().join("summarize.pb.toml");
+        std::fs::write(&path, "template = 'mine'").unwrap();
+
+        let outcomes = write_templates(std::slice::from_ref(&template), dir.path(), false).unwrap();
+        assert_eq!(
+            outcomes,
+            vec![("summarize".to_string(), ImportOutcome::Exists(path.clone()))]
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "template = 'mine'");
+
+        let outcomes = write_templates(&[template], dir.path(), true).unwrap();
+        assert_eq!(
+            outcomes,
+            vec![(
+                "summarize".to_string(),
+                ImportOutcome::Written(path.clone())
+            )]
+        );
+        let written = std::fs::read_to_string(&path).unwrap();
+        let parsed = toml::from_str::<PromptTemplate>(&written).unwrap();
+        assert_eq!(
+            parsed.template.as_deref(),
+            Some("Summarize this\n\n{{ extra }}")
+        );
+    }
+    #[test]
+    fn write_skips_unnamed() {
+        let dir = tempfile::tempdir().unwrap();
+        let template = AichatRole {
+            name: "???".to_string(),
+            prompt: "Be brief".to_string(),
+            ..Default::default()
+        }
+        .into_template();
+        assert_eq!(template.name, "");
+
+        let outcomes = write_templates(&[template], dir.path(), false).unwrap();
+        assert_eq!(outcomes, vec![(String::new(), ImportOutcome::Unnamed)]);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,60 @@
+//! Handle Ctrl-C so that a response which is streaming can be stopped without losing the text
+//! that was already received.
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Once,
+};
+
+use tracing::{event, Level};
+
+/// The exit code to use after an interruption, following the shell convention for SIGINT.
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// The number of responses that are streaming
+static STREAMING: AtomicUsize = AtomicUsize::new(0);
+static INSTALL: Once = Once::new();
+
+/// Install the Ctrl-C handler. While a response is streaming, the first Ctrl-C stops the stream
+/// and lets the output finish normally. Otherwise there is nothing to preserve, so it exits
+/// immediately. A second Ctrl-C always exits immediately. Only the first call has any effect.
+pub fn install_handler() {
+    INSTALL.call_once(|| {
+        let result = ctrlc::set_handler(|| {
+            if STREAMING.load(Ordering::SeqCst) == 0 || INTERRUPTED.swap(true, Ordering::SeqCst) {
+                std::process::exit(INTERRUPTED_EXIT_CODE);
+            }
+        });
+
+        if let Err(e) = result {
+            event!(Level::WARN, error = %e, "Failed to install Ctrl-C handler");
+        }
+    });
+}
+
+/// Returns true if the user stopped the response.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Clear the flag after a response was stopped, so that the next response in an interactive chat
+/// can stream.
+pub fn reset() {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+}
+
+/// Marks that a response is streaming for as long as it exists.
+pub struct StreamingGuard(());
+
+/// Mark that a response is streaming, so that Ctrl-C will stop it instead of exiting.
+pub fn start_streaming() -> StreamingGuard {
+    STREAMING.fetch_add(1, Ordering::SeqCst);
+    StreamingGuard(())
+}
+
+impl Drop for StreamingGuard {
+    fn drop(&mut self) {
+        STREAMING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
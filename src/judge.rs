@@ -0,0 +1,613 @@
+//! Score history entries with a judge template, so that datasets can be filtered on the verdicts,
+//! or choose the best of several responses to a prompt.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use error_stack::{Report, ResultExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    confirm::RunEstimate,
+    error::Error,
+    events::RunEvents,
+    filters::{PromptFilters, ShellAccess},
+    history::HistoryEntry,
+    hosts::{self, ModelInput},
+    model::{ModelChoice, ModelOptions, ModelSpec},
+    response_cache::sha256_hex,
+    shutdown::CancellationToken,
+    template::{render_template, ParsedTemplate, RenderEnv},
+    usage::{PriceTable, UsageMeter},
+};
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Verdict {
+    judge: String,
+    /// The version of the judge that gave the verdict, from [TemplateJudge::version]
+    #[serde(default)]
+    version: String,
+    id: u64,
+    score: f64,
+}
+
+/// Verdicts from previous judge runs, stored as one JSON object per line. Each verdict is written
+/// as soon as it is received, so an interrupted run picks up where it left off.
+#[derive(Debug)]
+pub struct VerdictCache {
+    path: PathBuf,
+    write_lock: Mutex<()>,
+}
+
+impl VerdictCache {
+    pub fn new() -> Result<Self, Report<Error>> {
+        Ok(Self::at(
+            crate::history::data_dir()?.join("judge_verdicts.jsonl"),
+        ))
+    }
+
+    pub fn at(path: PathBuf) -> Self {
+        Self {
+            path,
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    /// Load the verdicts from this version of `judge`, keyed by history entry ID.
+    pub fn load(&self, judge: &str, version: &str) -> Result<HashMap<u64, f64>, Report<Error>> {
+        let file = match std::fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => {
+                return Err(e)
+                    .change_context(Error::Cache)
+                    .attach_printable_lazy(|| self.path.display().to_string())
+            }
+        };
+
+        let mut verdicts = HashMap::new();
+        for line in std::io::BufReader::new(file).lines() {
+            let line = line.change_context(Error::Cache)?;
+            // A line may be cut off if the previous run was killed while writing it.
+            let Ok(verdict) = serde_json::from_str::<Verdict>(&line) else {
+                continue;
+            };
+
+            if verdict.judge == judge && verdict.version == version {
+                verdicts.insert(verdict.id, verdict.score);
+            }
+        }
+
+        Ok(verdicts)
+    }
+
+    pub fn append(
+        &self,
+        judge: &str,
+        version: &str,
+        id: u64,
+        score: f64,
+    ) -> Result<(), Report<Error>> {
+        let verdict = Verdict {
+            judge: judge.to_string(),
+            version: version.to_string(),
+            id,
+            score,
+        };
+        let mut line = serde_json::to_string(&verdict).change_context(Error::Cache)?;
+        line.push('\n');
+
+        let _lock = self.write_lock.lock().unwrap();
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .change_context(Error::Cache)
+            .attach_printable_lazy(|| self.path.display().to_string())
+    }
+}
+
+#[derive(Debug)]
+pub struct JudgeOptions<'a> {
+    /// The name of the judge, used to look up cached verdicts
+    pub judge: &'a str,
+    /// The version of the judge. Cached verdicts from other versions are ignored.
+    pub version: &'a str,
+    /// The score that the verdict is stored under on each entry
+    pub score_name: &'a str,
+    /// The number of entries to judge at once
+    pub concurrency: usize,
+    /// Ignore cached verdicts and judge every entry again
+    pub rejudge: bool,
+    /// Stops the workers from starting on more entries when cancelled
+    pub cancel: CancellationToken,
+}
+
+/// Set the judge's score on each entry, calling `judge_fn` for entries without a cached verdict.
+/// Entries which fail to be judged are left without a score, so score filters will skip them.
+pub fn judge_entries(
+    entries: &mut [HistoryEntry],
+    cache: &VerdictCache,
+    options: &JudgeOptions,
+    judge_fn: impl Fn(&HistoryEntry) -> Result<f64, Report<Error>> + Sync,
+) -> Result<(), Report<Error>> {
+    let mut verdicts = if options.rejudge {
+        HashMap::new()
+    } else {
+        cache.load(options.judge, options.version)?
+    };
+
+    let pending = entries
+        .iter()
+        .filter(|e| !verdicts.contains_key(&e.id))
+        .collect::<Vec<_>>();
+    let total = pending.len();
+
+    if total > 0 {
+        eprintln!(
+            "Judging {total} entries with {} ({} cached)",
+            options.judge,
+            entries.len() - total
+        );
+    }
+
+    let (work_tx, work_rx) = flume::unbounded();
+    for entry in pending {
+        work_tx.send(entry).ok();
+    }
+    drop(work_tx);
+
+    let (result_tx, result_rx) = flume::unbounded();
+    std::thread::scope(|scope| {
+        let work_rx = work_rx;
+        for _ in 0..options.concurrency.max(1) {
+            let work_rx = work_rx.clone();
+            let result_tx = result_tx.clone();
+            let judge_fn = &judge_fn;
+            scope.spawn(move || {
+                for entry in work_rx.iter() {
+                    if options.cancel.is_cancelled() {
+                        break;
+                    }
+
+                    let result = judge_fn(entry).and_then(|score| {
+                        // A verdict that can't be saved would be lost, so stop judging instead of
+                        // paying for more of them.
+                        options.cancel.cancel_on_error(|| {
+                            cache.append(options.judge, options.version, entry.id, score)
+                        })?;
+                        Ok(score)
+                    });
+                    if result_tx.send((entry.id, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(result_tx);
+
+        for (done, (id, result)) in result_rx.iter().enumerate() {
+            match result {
+                Ok(score) => {
+                    verdicts.insert(id, score);
+                }
+                Err(e) => eprintln!("Warning: Failed to judge entry {id}: {e:?}"),
+            }
+            eprint!("\rJudged {}/{total}", done + 1);
+        }
+    });
+
+    if total > 0 {
+        eprintln!();
+    }
+
+    for entry in entries.iter_mut() {
+        if let Some(score) = verdicts.get(&entry.id) {
+            entry.scores.insert(options.score_name.to_string(), *score);
+        }
+    }
+
+    if options.cancel.is_cancelled() {
+        return Err(Report::new(Error::JudgeStopped));
+    }
+
+    Ok(())
+}
+
+/// Find the answer in a judge's response: the last number labelled with one of `labels`, such as
+/// "Score: 4", or else the last number in the text, since judges tend to explain their reasoning
+/// before giving a verdict. The top of a scale, as in "4/5" or "4 out of 5", is never the answer.
+fn parse_verdict(response: &str, labels: &str) -> Option<f64> {
+    let number_regex = regex::Regex::new(r"-?\d+(\.\d+)?").unwrap();
+    let label_regex =
+        regex::Regex::new(&format!(r"(?i)\b(?:{labels})\s*(?:is|of)?\s*[:=]?\s*$")).unwrap();
+    let scale_regex = regex::Regex::new(r"(?i)(?:/|\bout of)\s*$").unwrap();
+
+    let numbers = number_regex
+        .find_iter(response)
+        .filter_map(|m| {
+            let before = &response[..m.start()];
+            let mut number = m.as_str();
+            // A dash right after a number is a range, as in "1-10", and not a minus sign.
+            if before.ends_with(|c: char| c.is_ascii_digit()) {
+                number = number.trim_start_matches('-');
+            }
+            if scale_regex.is_match(before) {
+                return None;
+            }
+            Some((number.parse::<f64>().ok()?, label_regex.is_match(before)))
+        })
+        .collect::<Vec<_>>();
+
+    numbers
+        .iter()
+        .rev()
+        .find(|(_, labelled)| *labelled)
+        .or(numbers.last())
+        .map(|(number, _)| *number)
+}
+
+/// Find the score in a judge's response.
+pub fn parse_score(response: &str) -> Option<f64> {
+    parse_verdict(response, "score|rating")
+}
+
+/// Find the response chosen by a judge. The judge numbers the responses from 1, and this returns
+/// the index of the chosen one.
+pub fn parse_choice(response: &str, count: usize) -> Option<usize> {
+    let choice = parse_verdict(response, "choice|answer|best|winner")?;
+    (choice.fract() == 0.0 && (1.0..=count as f64).contains(&choice)).then(|| choice as usize - 1)
+}
+
+/// A template which is sent each history entry and replies with a score, or is sent several
+/// responses and replies with the number of the best one.
+#[derive(Debug)]
+pub struct TemplateJudge {
+    pub template: ParsedTemplate,
+    pub model_options: ModelOptions,
+    pub render_env: RenderEnv,
+}
+
+impl TemplateJudge {
+    /// Load the judge template, using `model` instead of the template's model when it is given.
+    /// `allow_shell` is whether the run allows the judge template to run shell commands.
+    pub fn new(
+        config: &Config,
+        name: &str,
+        model: Option<&str>,
+        allow_shell: bool,
+    ) -> Result<Self, Report<Error>> {
+        let template = config
+            .find_template(name)
+            .attach_printable_lazy(|| format!("Judge template {name}"))?;
+        let mut model_options = config.model.clone();
+        model_options.update_from_model_input(&template.input.model);
+        if let Some(model) = model {
+            model_options.set_model_choice(ModelChoice::Single(ModelSpec::Full {
+                model: model.to_string(),
+                host: None,
+            }));
+        }
+
+        let render_env = RenderEnv {
+            partials: config.partials(),
+            filters: PromptFilters::new(model_options.tokenizer.as_deref()).with_shell(
+                ShellAccess::for_run(template.input.allow_shell, allow_shell),
+            ),
+            ..Default::default()
+        };
+
+        Ok(Self {
+            template,
+            model_options,
+            render_env,
+        })
+    }
+
+    /// A hash of the judge's prompt and model, so that verdicts are given again after either of
+    /// them changes.
+    pub fn version(&self) -> String {
+        let system = self
+            .template
+            .system
+            .as_ref()
+            .map(|(_, system)| system.as_str());
+        let model = self.model_options.full_model_spec();
+        let key = serde_json::json!({
+            "template": self.template.template,
+            "system": system,
+            "host": self.model_options.host_name(),
+            "model": model.model_name(),
+        });
+        sha256_hex(key.to_string().as_bytes())
+    }
+
+    pub fn score(
+        &self,
+        entry: &HistoryEntry,
+        cancel: &CancellationToken,
+    ) -> Result<f64, Report<Error>> {
+        let mut context = tera::Context::new();
+        context.insert("prompt", &entry.prompt);
+        context.insert("response", &entry.response);
+        context.insert("system", &entry.system.as_deref().unwrap_or_default());
+        context.insert("template", &entry.template);
+        context.insert("model", &entry.model);
+
+        let response = self.ask(&context, cancel, &UsageMeter::new())?;
+        parse_score(&response).ok_or_else(|| {
+            Report::new(Error::JudgeScoreMissing).attach_printable(format!("Response: {response}"))
+        })
+    }
+
+    /// Choose the best of several responses to the run's `input`, and return its index. The tokens
+    /// that the judge uses are added to the run's usage.
+    pub fn choose(
+        &self,
+        template: &str,
+        model: &str,
+        input: &ModelInput,
+        responses: &[String],
+    ) -> Result<usize, Report<Error>> {
+        let mut context = tera::Context::new();
+        context.insert("prompt", input.prompt);
+        context.insert("responses", responses);
+        context.insert("system", input.system.unwrap_or_default());
+        context.insert("template", template);
+        context.insert("model", model);
+
+        let response = self.ask(&context, input.cancel, input.usage)?;
+        parse_choice(&response, responses.len()).ok_or_else(|| {
+            Report::new(Error::JudgeChoiceMissing).attach_printable(format!("Response: {response}"))
+        })
+    }
+
+    /// Estimate the request that [TemplateJudge::choose] sends to pick from `n` responses. The
+    /// judge template isn't rendered until the responses arrive, so this counts its source, the
+    /// run's prompt, and each response at `response_tokens`.
+    pub fn estimate(
+        &self,
+        prices: &PriceTable,
+        prompt: &str,
+        system: &str,
+        n: usize,
+        response_tokens: usize,
+    ) -> Result<RunEstimate, Report<Error>> {
+        let mut templates = self.template.template.clone();
+        if let Some((_, system)) = &self.template.system {
+            templates.push('\n');
+            templates.push_str(system);
+        }
+
+        // The run's prompt and system prompt are filled into the judge's templates.
+        let run = format!("{system}\n{prompt}");
+        let estimate = RunEstimate::new(prices, &self.model_options, &run, &templates, &[])?;
+        Ok(RunEstimate::from_tokens(
+            prices,
+            &self.model_options,
+            estimate.tokens + n * response_tokens,
+        ))
+    }
+
+    /// Render the judge template and send it to the judge's model.
+    fn ask(
+        &self,
+        context: &tera::Context,
+        cancel: &CancellationToken,
+        usage: &UsageMeter,
+    ) -> Result<String, Report<Error>> {
+        let prompt = render_template(
+            &self.template.path,
+            &self.template.template,
+            context,
+            &self.render_env,
+        )?;
+        let system = self
+            .template
+            .system
+            .as_ref()
+            .map(|(path, template)| render_template(path, template, context, &self.render_env))
+            .transpose()?;
+
+        let input = ModelInput {
+            prompt: &prompt,
+            system: system.as_deref(),
+            history: &[],
+            images: Vec::new(),
+            tools: &HashMap::new(),
+            cancel,
+            usage,
+            events: RunEvents::none(),
+        };
+
+        let (message_tx, message_rx) = flume::unbounded();
+        hosts::send_model_request(&self.model_options, &input, message_tx)?;
+        Ok(message_rx.drain().collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::tests::history_entry;
+
+    fn entries(count: u64) -> Vec<HistoryEntry> {
+        (1..=count).map(history_entry).collect()
+    }
+
+    fn options(concurrency: usize) -> JudgeOptions<'static> {
+        JudgeOptions {
+            judge: "quality",
+            version: "1",
+            score_name: "judge",
+            concurrency,
+            rejudge: false,
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_score("4"), Some(4.0));
+        assert_eq!(parse_score("Score: 3.5/5"), Some(3.5));
+        assert_eq!(parse_score("This one is bad. -1"), Some(-1.0));
+        assert_eq!(parse_score("On a scale of 1-10 I give 8"), Some(8.0));
+        assert_eq!(parse_score("I'd give it 7 out of 10."), Some(7.0));
+        assert_eq!(
+            parse_score("Score: 6. Point 2 could be clearer, and 3 is missing."),
+            Some(6.0)
+        );
+        assert_eq!(parse_score("No idea"), None);
+    }
+
+    #[test]
+    fn parse_choices() {
+        assert_eq!(parse_choice("2", 3), Some(1));
+        assert_eq!(parse_choice("Response 3 is the clearest.", 3), Some(2));
+        assert_eq!(
+            parse_choice("Of the 3 responses, 2 is the clearest.", 3),
+            Some(1)
+        );
+        assert_eq!(parse_choice("Best: 1. Response 3 is close.", 3), Some(0));
+        assert_eq!(parse_choice("4", 3), None);
+        assert_eq!(parse_choice("0", 3), None);
+        assert_eq!(parse_choice("1.5", 3), None);
+        assert_eq!(parse_choice("They're all fine", 3), None);
+    }
+
+    #[test]
+    fn uses_cached_verdicts() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = VerdictCache::at(dir.path().join("verdicts.jsonl"));
+        let calls = AtomicUsize::new(0);
+        let judge = |entry: &HistoryEntry| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Ok(entry.id as f64)
+        };
+
+        let mut first = entries(10);
+        judge_entries(&mut first, &cache, &options(3), judge).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 10);
+        for entry in &first {
+            assert_eq!(entry.scores.get("judge"), Some(&(entry.id as f64)));
+        }
+
+        let mut second = entries(12);
+        judge_entries(&mut second, &cache, &options(3), judge).unwrap();
+        assert_eq!(
+            calls.load(Ordering::SeqCst),
+            12,
+            "Only new entries are judged"
+        );
+        assert_eq!(second[11].scores.get("judge"), Some(&12.0));
+
+        // Verdicts from other judges are kept separate
+        let mut other = entries(2);
+        let other_options = JudgeOptions {
+            judge: "other",
+            ..options(1)
+        };
+        judge_entries(&mut other, &cache, &other_options, |_| Ok(0.0)).unwrap();
+        assert_eq!(other[0].scores.get("judge"), Some(&0.0));
+        assert_eq!(cache.load("quality", "1").unwrap().get(&1), Some(&1.0));
+
+        // A new version of the judge gives its own verdicts.
+        let new_version = JudgeOptions {
+            version: "2",
+            ..options(1)
+        };
+        let mut changed = entries(2);
+        judge_entries(&mut changed, &cache, &new_version, |_| Ok(3.0)).unwrap();
+        assert_eq!(changed[0].scores.get("judge"), Some(&3.0));
+        assert_eq!(calls.load(Ordering::SeqCst), 12);
+
+        let rejudge = JudgeOptions {
+            rejudge: true,
+            ..options(2)
+        };
+        judge_entries(&mut second, &cache, &rejudge, |_| Ok(5.0)).unwrap();
+        assert_eq!(second[0].scores.get("judge"), Some(&5.0));
+        assert_eq!(cache.load("quality", "1").unwrap().get(&1), Some(&5.0));
+    }
+
+    #[test]
+    fn resume_after_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = VerdictCache::at(dir.path().join("verdicts.jsonl"));
+
+        let mut first = entries(6);
+        judge_entries(&mut first, &cache, &options(2), |entry| {
+            if entry.id % 2 == 0 {
+                Err(Report::new(Error::JudgeScoreMissing))
+            } else {
+                Ok(1.0)
+            }
+        })
+        .unwrap();
+        assert_eq!(first[0].scores.get("judge"), Some(&1.0));
+        assert_eq!(first[1].scores.get("judge"), None);
+
+        let retried = Mutex::new(Vec::new());
+        let mut second = entries(6);
+        judge_entries(&mut second, &cache, &options(2), |entry| {
+            retried.lock().unwrap().push(entry.id);
+            Ok(2.0)
+        })
+        .unwrap();
+
+        let mut retried = retried.into_inner().unwrap();
+        retried.sort();
+        assert_eq!(retried, vec![2, 4, 6]);
+        assert_eq!(second[0].scores.get("judge"), Some(&1.0));
+        assert_eq!(second[1].scores.get("judge"), Some(&2.0));
+    }
+
+    #[test]
+    fn respects_concurrency() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = VerdictCache::at(dir.path().join("verdicts.jsonl"));
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+
+        let mut entries = entries(20);
+        judge_entries(&mut entries, &cache, &options(3), |_| {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(now, Ordering::SeqCst);
+            std::thread::sleep(std::time::Duration::from_millis(5));
+            running.fetch_sub(1, Ordering::SeqCst);
+            Ok(1.0)
+        })
+        .unwrap();
+
+        assert!(max_running.load(Ordering::SeqCst) <= 3);
+        assert!(entries.iter().all(|e| e.scores.contains_key("judge")));
+    }
+
+    #[test]
+    fn stops_when_cancelled() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = VerdictCache::at(dir.path().join("verdicts.jsonl"));
+        let options = options(1);
+
+        let mut entries = entries(5);
+        let err = judge_entries(&mut entries, &cache, &options, |entry| {
+            options.cancel.cancel();
+            Ok(entry.id as f64)
+        })
+        .unwrap_err();
+        assert!(matches!(err.current_context(), Error::JudgeStopped));
+
+        // The entry that was being judged keeps its verdict, and the rest are left for later.
+        let judged = entries.iter().filter(|e| e.scores.contains_key("judge"));
+        assert_eq!(judged.count(), 1);
+        assert_eq!(cache.load("quality", "1").unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,298 @@
+//! Guess the language of the extra input, so that templates can respond in the same language or
+//! handle code differently without needing an option for it.
+//!
+//! This is a quick heuristic instead of a trained model. Code is recognized by patterns that are
+//! distinctive for each programming language, and natural languages by the same detector that
+//! checks the language of responses.
+
+use std::sync::OnceLock;
+
+use regex::Regex;
+use serde::Serialize;
+
+use crate::checks;
+
+/// Only this much of the input is looked at, which is plenty to tell the language.
+const SAMPLE_LENGTH: usize = 20_000;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LanguageKind {
+    /// A natural language
+    Text,
+    /// A programming or data language
+    Code,
+}
+
+/// The `extra_meta` template variable
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ExtraMeta {
+    /// The name of the language, such as `English` or `Rust`
+    pub language: Option<&'static str>,
+    /// From 0 to 1, how sure the guess is
+    pub lang_confidence: f32,
+    pub kind: Option<LanguageKind>,
+}
+
+impl ExtraMeta {
+    pub fn detect(text: &str) -> Self {
+        match detect_language(text) {
+            Some((language, kind, confidence)) => Self {
+                language: Some(language),
+                // Two decimal places is more than enough for a heuristic.
+                lang_confidence: (confidence * 100.0).round() / 100.0,
+                kind: Some(kind),
+            },
+            None => Self {
+                language: None,
+                lang_confidence: 0.0,
+                kind: None,
+            },
+        }
+    }
+}
+
+fn detect_language(text: &str) -> Option<(&'static str, LanguageKind, f32)> {
+    let end = text
+        .char_indices()
+        .nth(SAMPLE_LENGTH)
+        .map(|(i, _)| i)
+        .unwrap_or(text.len());
+    let text = text[..end].trim();
+    if text.is_empty() {
+        return None;
+    }
+
+    if let Some((language, confidence)) = detect_code(text) {
+        return Some((language, LanguageKind::Code, confidence));
+    }
+
+    checks::detect_language(text)
+        .map(|(language, confidence)| (language.name(), LanguageKind::Text, confidence))
+}
+
+/// Patterns which are common in each programming language and rare in prose
+const CODE_PATTERNS: &[(&str, &[&str])] = &[
+    (
+        "Rust",
+        &[
+            r"\bfn\s+\w+\s*[(<]",
+            r"\blet\s+mut\b",
+            r"\bimpl\b.*\{",
+            r"\bpub\s+(fn|struct|enum|mod)\b",
+            r"&(mut\s+)?self\b",
+            r"(?m)^\s*use\s+\w+::",
+        ],
+    ),
+    (
+        "Python",
+        &[
+            r"(?m)^\s*def\s+\w+\(.*\)\s*(->.*)?:\s*$",
+            r"(?m)^\s*(import\s+\w+|from\s+[\w.]+\s+import)\b",
+            r"\bself\.\w+",
+            r"(?m)^\s*elif\b",
+            r"(?m)^\s*class\s+\w+.*:\s*$",
+        ],
+    ),
+    (
+        "JavaScript",
+        &[
+            r"\bfunction\s*\w*\s*\(",
+            r"(?m)^\s*(const|let|var)\s+\w+\s*=",
+            r"=>",
+            r"\bconsole\.log\(",
+            r"\brequire\(",
+            r"(?m)^\s*export\s+(default|const|function)\b",
+        ],
+    ),
+    (
+        "TypeScript",
+        &[
+            r"\binterface\s+\w+\s*\{",
+            r"\w\s*:\s*(string|number|boolean)\b",
+            r"(?m)^\s*(type\s+\w+\s*=|export\s+type\b)",
+        ],
+    ),
+    (
+        "Go",
+        &[
+            r"(?m)^package\s+\w+\s*$",
+            r"\bfunc\s+(\(\w+\s+\*?\w+\)\s*)?\w+\(",
+            r"\w\s*:=",
+            r"\bfmt\.\w+\(",
+        ],
+    ),
+    (
+        "C",
+        &[
+            r"(?m)^#include\s*[<\x22]",
+            r"\bint\s+main\s*\(",
+            r"\bprintf\(",
+            r"\w->\w",
+        ],
+    ),
+    (
+        "Java",
+        &[
+            r"\bpublic\s+(static\s+)?(final\s+)?(class|void|interface)\b",
+            r"\bSystem\.out\.",
+            r"(?m)^import\s+java\.",
+            r"@Override\b",
+        ],
+    ),
+    (
+        "Shell",
+        &[
+            r"(?m)^#!.*\b(ba|z)?sh\b",
+            r"(?m)^\s*(fi|done|esac)\s*$",
+            r"(?m)^\s*(if|while)\s+\[",
+            r"\$\{\w+\}",
+            r"(?m)^\s*echo\s",
+        ],
+    ),
+    (
+        "SQL",
+        &[
+            r"(?i)\bselect\b[\s\S]+?\bfrom\b",
+            r"(?i)\binsert\s+into\b",
+            r"(?i)\bcreate\s+(table|index|view)\b",
+            r"(?im)^\s*where\b",
+            r"(?i)\b(inner|left|right)\s+join\b",
+        ],
+    ),
+    (
+        "HTML",
+        &[
+            r"(?i)<!doctype\s+html",
+            r"(?i)<(html|head|body|div|span|p|ul|li|a)(\s[^>]*)?>",
+            r"</\w+>",
+        ],
+    ),
+];
+
+fn code_patterns() -> &'static [(&'static str, Vec<Regex>)] {
+    static PATTERNS: OnceLock<Vec<(&'static str, Vec<Regex>)>> = OnceLock::new();
+    PATTERNS.get_or_init(|| {
+        CODE_PATTERNS
+            .iter()
+            .map(|(language, patterns)| {
+                let patterns = patterns.iter().map(|p| Regex::new(p).unwrap()).collect();
+                (*language, patterns)
+            })
+            .collect()
+    })
+}
+
+fn detect_code(text: &str) -> Option<(&'static str, f32)> {
+    let starts_like_json = text.starts_with('{') || text.starts_with('[');
+    if starts_like_json && serde_json::from_str::<serde_json::Value>(text).is_ok() {
+        return Some(("JSON", 1.0));
+    }
+
+    // Each pattern counts at most a few times, so that one common pattern doesn't outweigh
+    // several different ones.
+    let scores = code_patterns()
+        .iter()
+        .map(|(language, patterns)| {
+            let score = patterns
+                .iter()
+                .map(|pattern| pattern.find_iter(text).take(3).count())
+                .sum::<usize>();
+            (*language, score)
+        })
+        .collect::<Vec<_>>();
+
+    let total = scores.iter().map(|(_, score)| score).sum::<usize>();
+    let (language, best) = scores
+        .into_iter()
+        .max_by_key(|(_, score)| *score)
+        .filter(|(_, score)| *score >= 3)?;
+
+    // Both how much the best language stands out and how much evidence there is for it.
+    let confidence = (best as f32 / total as f32) * (best as f32 / 8.0).min(1.0);
+    Some((language, confidence))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn detect(text: &str) -> (Option<&'static str>, Option<LanguageKind>) {
+        let meta = ExtraMeta::detect(text);
+        (meta.language, meta.kind)
+    }
+
+    #[test]
+    fn natural_languages() {
+        assert_eq!(
+            detect("The quick brown fox jumps over the lazy dog, and it is not sorry about that."),
+            (Some("English"), Some(LanguageKind::Text))
+        );
+        assert_eq!(
+            detect("El perro come la comida y los gatos duermen en el sofá porque es muy tarde."),
+            (Some("Spanish"), Some(LanguageKind::Text))
+        );
+        assert_eq!(
+            detect("Le chat est sur la table et les enfants sont dans le jardin avec nous."),
+            (Some("French"), Some(LanguageKind::Text))
+        );
+        assert_eq!(
+            detect("Der Hund ist nicht im Haus, und die Katze schläft auf dem Sofa."),
+            (Some("German"), Some(LanguageKind::Text))
+        );
+        assert_eq!(
+            detect("今日はとても良い天気ですね。散歩に行きましょう。"),
+            (Some("Japanese"), Some(LanguageKind::Text))
+        );
+        assert_eq!(
+            detect("Привет, как у тебя дела сегодня?"),
+            (Some("Russian"), Some(LanguageKind::Text))
+        );
+    }
+
+    #[test]
+    fn code() {
+        let rust = r#"
+use std::collections::HashMap;
+
+pub fn count(words: &[&str]) -> HashMap<&str, usize> {
+    let mut counts = HashMap::new();
+    for word in words {
+        *counts.entry(*word).or_default() += 1;
+    }
+    counts
+}
+"#;
+        assert_eq!(detect(rust), (Some("Rust"), Some(LanguageKind::Code)));
+
+        let python = r#"
+import os
+
+class Walker:
+    def __init__(self, root):
+        self.root = root
+
+    def files(self):
+        for dirpath, _, names in os.walk(self.root):
+            yield from names
+"#;
+        assert_eq!(detect(python), (Some("Python"), Some(LanguageKind::Code)));
+
+        let meta = ExtraMeta::detect(r#"{"name": "promptbox", "tags": ["cli"]}"#);
+        assert_eq!(meta.language, Some("JSON"));
+        assert_eq!(meta.lang_confidence, 1.0);
+    }
+
+    #[test]
+    fn nothing_to_detect() {
+        assert_eq!(
+            ExtraMeta::detect("  \n"),
+            ExtraMeta {
+                language: None,
+                lang_confidence: 0.0,
+                kind: None,
+            }
+        );
+        assert_eq!(detect("12345 67890"), (None, None));
+    }
+}
@@ -0,0 +1,390 @@
+//! A record of the tokens and cost of every run, kept apart from the history so that it counts runs
+//! that aren't saved there. There is one file for each calendar month, and each file is locked
+//! while it is used so that runs in parallel don't lose each other's usage.
+
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufRead, Read, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use chrono::{DateTime, Datelike, Utc};
+use error_stack::{Report, ResultExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    budget::BudgetPolicy,
+    error::Error,
+    history::{data_dir, History, HistoryEntry, TokenCounts},
+    model::ModelOptions,
+    usage::{PriceTable, Usage},
+};
+
+/// A calendar month, written as `2025-01`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Month {
+    year: i32,
+    month: u32,
+}
+
+impl Month {
+    pub fn of(timestamp: DateTime<Utc>) -> Self {
+        Self {
+            year: timestamp.year(),
+            month: timestamp.month(),
+        }
+    }
+
+    pub fn current() -> Self {
+        Self::of(Utc::now())
+    }
+}
+
+impl FromStr for Month {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{s} is not a month in the form YYYY-MM");
+        let (year, month) = s.split_once('-').ok_or_else(invalid)?;
+        let year = year.parse().map_err(|_| invalid())?;
+        let month = month.parse().map_err(|_| invalid())?;
+        if !(1..=12).contains(&month) {
+            return Err(invalid());
+        }
+
+        Ok(Self { year, month })
+    }
+}
+
+impl Display for Month {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04}-{:02}", self.year, self.month)
+    }
+}
+
+/// The usage of one run
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LedgerEntry {
+    pub timestamp: DateTime<Utc>,
+    /// The host that the run was sent to. This is `None` for runs copied from the history, which
+    /// doesn't record it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    pub model: String,
+    pub template: String,
+    pub tokens: Option<TokenCounts>,
+    /// True if the tokens were counted locally instead of reported by the host
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub estimated: bool,
+    /// The cost in dollars, if the model has a price
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+}
+
+impl LedgerEntry {
+    pub fn new(template: &str, model_options: &ModelOptions, usage: Usage) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            host: Some(model_options.host_name()),
+            model: model_options.full_model_spec().model_name().to_string(),
+            template: template.to_string(),
+            tokens: Some(usage.tokens),
+            estimated: usage.estimated,
+            cost: usage.cost,
+        }
+    }
+
+    /// Copy a run from the history. Runs saved before costs were recorded are priced with the
+    /// current price table.
+    pub fn from_history(entry: &HistoryEntry, prices: &PriceTable) -> Self {
+        let tokens = entry.usage.map(|u| u.tokens).or(entry.tokens);
+        let cost = entry
+            .usage
+            .and_then(|u| u.cost)
+            .or_else(|| tokens.and_then(|tokens| prices.cost(&entry.model, &tokens)));
+
+        Self {
+            timestamp: entry.timestamp,
+            host: None,
+            model: entry.model.clone(),
+            template: entry.template.clone(),
+            tokens,
+            estimated: entry.usage.is_some_and(|u| u.estimated),
+            cost,
+        }
+    }
+}
+
+/// The total cost of some runs, leaving out those without a known cost.
+pub fn total_cost(entries: &[LedgerEntry]) -> f64 {
+    entries.iter().filter_map(|e| e.cost).sum()
+}
+
+#[derive(Debug)]
+pub struct Ledger {
+    dir: PathBuf,
+}
+
+impl Ledger {
+    /// Open the ledger in the data directory. The first time, it starts with the runs in the
+    /// history, so that usage from before the ledger existed is still counted.
+    pub fn new(prices: &PriceTable) -> Result<Self, Report<Error>> {
+        let dir = data_dir()?.join("usage");
+        match std::fs::create_dir(&dir) {
+            Ok(()) => {
+                let ledger = Self { dir };
+                ledger.add_history(&History::new()?.entries()?, prices)?;
+                Ok(ledger)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(Self { dir }),
+            Err(e) => Err(e)
+                .change_context(Error::Ledger)
+                .attach_printable_lazy(|| format!("Creating directory {}", dir.display())),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn at(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn path(&self, month: Month) -> PathBuf {
+        self.dir.join(format!("{month}.jsonl"))
+    }
+
+    fn add_history(
+        &self,
+        entries: &[HistoryEntry],
+        prices: &PriceTable,
+    ) -> Result<(), Report<Error>> {
+        for entry in entries {
+            self.record(&LedgerEntry::from_history(entry, prices))?;
+        }
+        Ok(())
+    }
+
+    /// Add a run to the file for its month, and return the cost of the month's runs before it.
+    pub fn record(&self, entry: &LedgerEntry) -> Result<f64, Report<Error>> {
+        let path = self.path(Month::of(entry.timestamp));
+        let mut line = serde_json::to_string(entry).change_context(Error::Ledger)?;
+        line.push('\n');
+
+        let record = || -> Result<f64, Report<Error>> {
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .open(&path)
+                .change_context(Error::Ledger)?;
+            // Hold the lock from reading the month's total until the run is written, so that
+            // another run can't slip in between.
+            file.lock().change_context(Error::Ledger)?;
+
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)
+                .change_context(Error::Ledger)?;
+            let spent = total_cost(&parse_entries(contents.as_bytes())?);
+
+            file.write_all(line.as_bytes())
+                .change_context(Error::Ledger)?;
+            Ok(spent)
+        };
+
+        record().attach_printable_lazy(|| path.display().to_string())
+    }
+
+    /// The runs in a month, oldest first.
+    pub fn month(&self, month: Month) -> Result<Vec<LedgerEntry>, Report<Error>> {
+        let path = self.path(month);
+        let read = || -> Result<Vec<LedgerEntry>, Report<Error>> {
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(e).change_context(Error::Ledger),
+            };
+            file.lock_shared().change_context(Error::Ledger)?;
+            parse_entries(std::io::BufReader::new(file))
+        };
+
+        read().attach_printable_lazy(|| path.display().to_string())
+    }
+
+    /// The months that have runs, oldest first.
+    pub fn months(&self) -> Result<Vec<Month>, Report<Error>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e)
+                    .change_context(Error::Ledger)
+                    .attach_printable_lazy(|| self.dir.display().to_string())
+            }
+        };
+
+        let mut months = entries
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name().into_string().ok()?;
+                name.strip_suffix(".jsonl")?.parse().ok()
+            })
+            .collect::<Vec<Month>>();
+        months.sort();
+        Ok(months)
+    }
+
+    /// Every run in the ledger, oldest first.
+    pub fn entries(&self) -> Result<Vec<LedgerEntry>, Report<Error>> {
+        let mut entries = Vec::new();
+        for month in self.months()? {
+            entries.extend(self.month(month)?);
+        }
+        Ok(entries)
+    }
+}
+
+/// Add a finished run to the ledger, and warn if it took the month over an amount in the budget.
+/// Failures are only warnings, since the run itself succeeded.
+pub fn record_run(
+    template: &str,
+    model_options: &ModelOptions,
+    usage: Usage,
+    prices: &PriceTable,
+    budget: &BudgetPolicy,
+) {
+    let entry = LedgerEntry::new(template, model_options, usage);
+    match Ledger::new(prices).and_then(|ledger| ledger.record(&entry)) {
+        Ok(spent) => {
+            if let Some(warning) = budget.crossed(entry.cost.unwrap_or_default(), spent) {
+                eprintln!("Warning: {warning}");
+            }
+        }
+        Err(e) => eprintln!("Warning: Failed to record the usage of the run: {e:?}"),
+    }
+}
+
+fn parse_entries(reader: impl BufRead) -> Result<Vec<LedgerEntry>, Report<Error>> {
+    reader
+        .lines()
+        .filter(|line| !line.as_ref().is_ok_and(|l| l.trim().is_empty()))
+        .map(|line| {
+            let line = line.change_context(Error::Ledger)?;
+            serde_json::from_str(&line).change_context(Error::Ledger)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::usage::ModelPriceInput;
+
+    fn entry(month: u32, template: &str, cost: Option<f64>) -> LedgerEntry {
+        LedgerEntry {
+            timestamp: Utc.with_ymd_and_hms(2025, month, 3, 12, 0, 0).unwrap(),
+            host: Some("openai".to_string()),
+            model: "gpt-4o".to_string(),
+            template: template.to_string(),
+            tokens: Some(TokenCounts {
+                prompt: 100,
+                response: 10,
+            }),
+            estimated: false,
+            cost,
+        }
+    }
+
+    #[test]
+    fn month() {
+        let month = "2025-01".parse::<Month>().unwrap();
+        assert_eq!(month.to_string(), "2025-01");
+        assert_eq!(
+            month,
+            Month::of(Utc.with_ymd_and_hms(2025, 1, 31, 23, 0, 0).unwrap())
+        );
+        assert!("2025-13".parse::<Month>().is_err());
+        assert!("January".parse::<Month>().is_err());
+    }
+
+    #[test]
+    fn monthly_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = Ledger::at(dir.path().to_path_buf());
+
+        assert_eq!(ledger.record(&entry(1, "a", Some(0.5))).unwrap(), 0.0);
+        assert_eq!(ledger.record(&entry(1, "b", None)).unwrap(), 0.5);
+        assert_eq!(ledger.record(&entry(1, "a", Some(0.25))).unwrap(), 0.5);
+        assert_eq!(ledger.record(&entry(2, "a", Some(1.0))).unwrap(), 0.0);
+
+        let january = ledger.month("2025-01".parse().unwrap()).unwrap();
+        assert_eq!(january.len(), 3);
+        assert_eq!(total_cost(&january), 0.75);
+        assert_eq!(
+            ledger
+                .months()
+                .unwrap()
+                .iter()
+                .map(|m| m.to_string())
+                .collect::<Vec<_>>(),
+            ["2025-01", "2025-02"]
+        );
+        assert_eq!(ledger.entries().unwrap().len(), 4);
+        assert!(ledger.month("2024-12".parse().unwrap()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn concurrent_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    let ledger = Ledger::at(dir.path().to_path_buf());
+                    for _ in 0..25 {
+                        ledger.record(&entry(3, "a", Some(1.0))).unwrap();
+                    }
+                });
+            }
+        });
+
+        let ledger = Ledger::at(dir.path().to_path_buf());
+        let march = ledger.month("2025-03".parse().unwrap()).unwrap();
+        assert_eq!(march.len(), 200);
+        assert_eq!(total_cost(&march), 200.0);
+    }
+
+    #[test]
+    fn from_history() {
+        let prices = PriceTable::from(HashMap::from([(
+            "gpt-4o".to_string(),
+            ModelPriceInput {
+                input: Some(5.0),
+                output: Some(15.0),
+            },
+        )]));
+        let mut old = HistoryEntry::new("summarize", "gpt-4o", None, "prompt", "response");
+        old.tokens = Some(TokenCounts {
+            prompt: 1_000_000,
+            response: 0,
+        });
+        let mut new = old.clone();
+        new.usage = Some(Usage {
+            tokens: TokenCounts {
+                prompt: 10,
+                response: 10,
+            },
+            estimated: true,
+            cost: Some(0.25),
+        });
+
+        let old = LedgerEntry::from_history(&old, &prices);
+        assert_eq!(old.cost, Some(5.0));
+        assert_eq!(old.host, None);
+        let new = LedgerEntry::from_history(&new, &prices);
+        assert_eq!(new.cost, Some(0.25));
+        assert!(new.estimated);
+    }
+}
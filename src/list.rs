@@ -0,0 +1,153 @@
+//! Find all the templates available from a directory.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use error_stack::Report;
+use serde::Serialize;
+
+use crate::{
+    config::Config,
+    error::Error,
+    template::{ParsedTemplate, TEMPLATE_EXTENSIONS},
+};
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TemplateSummary {
+    pub name: String,
+    /// The template directory that the template was found in
+    pub directory: PathBuf,
+    /// The namespace of the template directory, for running the template as `namespace/name`
+    pub namespace: String,
+    pub description: String,
+    /// The model that the template will use, after applying the configuration
+    pub model: String,
+}
+
+/// Find the names of the templates in `dir` and its subdirectories, along with their paths.
+pub fn find_template_files(dir: &Path) -> Vec<(String, PathBuf)> {
+    let mut found = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(&current) else {
+            continue;
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            if file_name.starts_with('.') {
+                continue;
+            }
+
+            if path.is_dir() {
+                pending.push(path);
+            } else if let Some((precedence, name)) = TEMPLATE_EXTENSIONS
+                .iter()
+                .enumerate()
+                .find_map(|(i, extension)| Some((i, file_name.strip_suffix(extension)?)))
+            {
+                let relative_dir = current.strip_prefix(dir).unwrap_or(Path::new(""));
+                let name = relative_dir.join(name).to_string_lossy().replace('\\', "/");
+                found.push((name, precedence, path));
+            }
+        }
+    }
+
+    // When a directory has both kinds of file with the same name, the one that is run comes first.
+    found.sort();
+    found
+        .into_iter()
+        .map(|(name, _, path)| (name, path))
+        .collect()
+}
+
+#[derive(Debug, Default)]
+pub struct TemplateList {
+    pub templates: Vec<TemplateSummary>,
+    /// Template files which could not be read
+    pub errors: Vec<(PathBuf, Report<Error>)>,
+}
+
+/// List the templates in the configured template directories. When the same name appears in
+/// multiple directories, only the one that `promptbox run` would use is returned.
+pub fn list_templates(config: &Config) -> TemplateList {
+    let mut seen = HashSet::new();
+    let mut list = TemplateList::default();
+
+    for template_dir in &config.template_dirs {
+        for (name, path) in find_template_files(template_dir) {
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+
+            match ParsedTemplate::from_file(&name, &path) {
+                Ok(Some(template)) => {
+                    let mut model_options = config.model.clone();
+                    model_options.update_from_model_input(&template.input.model);
+                    list.templates.push(TemplateSummary {
+                        name,
+                        directory: template_dir.clone(),
+                        namespace: config.namespace(template_dir),
+                        description: template.input.description,
+                        model: model_options.full_model_spec().model_name().to_string(),
+                    });
+                }
+                Ok(None) => {}
+                Err(e) => list.errors.push((path, e)),
+            };
+        }
+    }
+
+    list
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::base_dir;
+
+    #[test]
+    fn override_precedence() {
+        let config = Config::from_directory(base_dir("override_template/override")).unwrap();
+        let list = list_templates(&config);
+
+        let tmp = list
+            .templates
+            .iter()
+            .filter(|t| t.name == "tmp")
+            .collect::<Vec<_>>();
+        assert_eq!(tmp.len(), 1, "Only one template named tmp");
+        assert_eq!(tmp[0].directory, base_dir("override_template/override"));
+        assert_eq!(tmp[0].namespace, "override");
+    }
+
+    #[test]
+    fn summary() {
+        let config = Config::from_directory(base_dir("")).unwrap();
+        let list = list_templates(&config);
+
+        let help_text = list
+            .templates
+            .iter()
+            .find(|t| t.name == "help_text")
+            .expect("finding help_text template");
+        assert!(!help_text.description.is_empty());
+        assert_eq!(help_text.model, "gpt-3.5-turbo");
+
+        assert!(list
+            .templates
+            .iter()
+            .any(|t| t.name == "subdir_without_config/indir"));
+
+        assert!(
+            list.errors
+                .iter()
+                .any(|(path, _)| path.ends_with("malformed_template.pb.toml")),
+            "The malformed template should be reported"
+        );
+    }
+}
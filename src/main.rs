@@ -1,113 +1,794 @@
-use std::{ffi::OsString, path::PathBuf};
+use std::{
+    borrow::Cow, collections::HashMap, ffi::OsString, io::IsTerminal, path::PathBuf, time::Instant,
+};
 
-use args::{parse_main_args, parse_template_args, FoundCommand, GlobalRunArgs};
-use config::Config;
+use args::{
+    parse_main_args, parse_template_args, CacheCommand, DatasetCommand, FoundCommand,
+    GithooksCommand, GlobalRunArgs, HistoryCommand, MainCommand, MultiCommand, SearchArgs,
+    SessionCommand,
+};
+use assembly_trace::{AssemblyStepKind, AssemblyTrace};
+use budget::BudgetPolicy;
+use cache::Cache;
+use capabilities::{CapabilityRegistry, Requirement};
+use checks::ResponseChecks;
+use config::{Config, SearchOptions};
+use confirm::{ConfirmPolicy, RunEstimate};
+use dates::DateSettings;
 use error::Error;
 use error_stack::{Report, ResultExt};
+use events::{RunEvent, RunEvents};
+use filters::{PromptFilters, ShellAccess};
 use global_config::load_dotenv;
+use history::{History, HistoryEntry, Invocation, RunParameters, TokenCounts};
 use hosts::ModelInput;
 use image::ImageData;
-use model::ModelOptions;
-use template::{assemble_template, render_template, ParsedTemplate};
+use import::{ImportOutcome, ImportSource};
+use judge::TemplateJudge;
+use ledger::{Ledger, Month};
+use model::{ModelError, ModelOptions};
+use moderation::{ModerationPolicy, ModerationResult, ModerationStage};
+use output::{OutputOptions, RunResult};
+use postprocess::PostProcess;
+use progress::{RunStats, Spinner};
+use provenance::SourceFile;
+use response_cache::{CachedResponse, ResponseCachePolicy};
+use routing::RoutingMode;
+use segments::{PromptSegment, PromptSegments};
+use session::{ChatMessage, SessionStore};
+use shutdown::CancellationToken;
+use stream_filter::StreamFilters;
+use template::{assemble_template, render_template, ParsedTemplate, RenderEnv};
+use tools::ToolDefinition;
+use transcript_log::LogEntry;
+use usage::{PriceTable, Usage, UsageGroup, UsageMeter};
 
 mod args;
+mod assembly_trace;
+mod budget;
 mod cache;
+mod capabilities;
+mod chat;
 mod chat_template;
+mod checks;
+mod clipboard;
+mod completions;
 mod config;
+mod confirm;
 mod context;
+mod dataset;
+mod dates;
+mod doctor;
+mod edit;
 mod error;
+mod events;
+mod export;
+mod file_options;
+mod filters;
+mod front_matter;
+mod githooks;
 mod global_config;
+mod history;
 mod hosts;
+mod html;
 mod image;
+mod image_preview;
+mod import;
+mod interrupt;
+mod judge;
+mod language;
+mod ledger;
+mod list;
 mod model;
+mod model_rules;
+mod moderation;
+mod multi;
 mod option;
+mod option_groups;
+mod output;
+mod partials;
+mod pdf;
+mod picker;
+mod pin;
+mod postprocess;
+mod progress;
+mod project_context;
+mod provenance;
+mod render;
 mod requests;
+mod response_cache;
+mod response_schema;
+mod routing;
+mod schema;
+mod segments;
+mod session;
+mod show;
+mod shutdown;
+mod stream_filter;
+mod structured;
+mod target_length;
 mod template;
+mod template_alias;
 #[cfg(test)]
 mod tests;
+mod tokenizer;
+mod tools;
 mod tracing;
+mod transcript_log;
+mod unknown_fields;
+mod usage;
+mod validate;
 
+#[derive(Debug)]
+struct GeneratedTemplate {
+    template_name: String,
+    args: GlobalRunArgs,
+    model_options: ModelOptions,
+    output_options: OutputOptions,
+    prompt: String,
+    system: String,
+    /// The prompt and system prompt, split into their parts
+    segments: PromptSegments,
+    images: Vec<ImageData>,
+    tools: HashMap<String, ToolDefinition>,
+    /// Earlier messages from the `--session` conversation that fit in the context
+    session_history: Vec<ChatMessage>,
+    assembly_trace: AssemblyTrace,
+    save_history: bool,
+    cache_policy: ResponseCachePolicy,
+    /// Append the run to this file
+    log_file: Option<PathBuf>,
+    confirm: ConfirmPolicy,
+    budget: BudgetPolicy,
+    moderation: ModerationPolicy,
+    /// The price of each model, for reporting the cost of the run
+    prices: PriceTable,
+    checks: ResponseChecks,
+    post_process: PostProcess,
+    /// The template and configuration files that the prompt came from
+    sources: Vec<SourceFile>,
+    /// The template and its arguments, to render the prompt again if the host says it is too long
+    template_path: PathBuf,
+    template: String,
+    template_context: tera::Context,
+    render_env: RenderEnv,
+    /// The template that chooses the best of several responses, for `--judge`
+    judge: Option<TemplateJudge>,
+}
+
+/// How many times to shrink the prompt and try again when the host says that it is too long.
+const MAX_CONTEXT_RETRIES: usize = 2;
+
+/// Generate the prompt, reading any piped input from stdin.
+#[cfg(test)]
 fn generate_template(
     base_dir: PathBuf,
     template: String,
     cmdline: Vec<OsString>,
-) -> Result<(GlobalRunArgs, ModelOptions, String, String, Vec<ImageData>), Report<Error>> {
+) -> Result<GeneratedTemplate, Report<Error>> {
     let config = Config::from_directory(base_dir.clone())?;
+    generate_template_with_stdin(config, base_dir, template, cmdline, None)
+}
+
+/// Skip the models in the chain which can't handle the template, and say so.
+fn select_capable_models(
+    registry: &CapabilityRegistry,
+    model_options: &mut ModelOptions,
+    requires: &[Requirement],
+) -> Result<(), Report<Error>> {
+    let message = capabilities::select_capable_models(registry, model_options, requires)
+        .attach_printable("Checking the template's model requirements")?;
+    if let Some(message) = message {
+        eprintln!("{message}");
+    }
+    Ok(())
+}
 
+/// Generate the prompt. If `stdin` is given, it is used in place of any piped input.
+fn generate_template_with_stdin(
+    config: Config,
+    base_dir: PathBuf,
+    template: String,
+    cmdline: Vec<OsString>,
+    stdin: Option<String>,
+) -> Result<GeneratedTemplate, Report<Error>> {
     let ParsedTemplate {
+        name: template_name,
         template,
         path: template_path,
-        input,
+        mut input,
         system,
+        response_schema,
+        sources: template_sources,
+        unknown_fields: template_unknown_fields,
         ..
     } = config.find_template(&template)?;
+    let partials = config.partials();
+    let mut sources = template_sources
+        .into_iter()
+        .chain(config.sources.iter().cloned())
+        .collect::<Vec<_>>();
+
+    let (mut args, mut template_context, mut images) =
+        parse_template_args(cmdline, &base_dir, &template_name, &input)?;
+    if args.last {
+        return Err(Report::new(Error::ArgParseFailure))
+            .attach_printable("--last can't be used with a template name");
+    }
+    if stdin.is_some() {
+        args.stdin = stdin;
+    }
+    args.stdin_format = args.stdin_format.or(input.stdin_format);
+
+    if let Some(mimetype) = args.image_stdin.as_deref() {
+        let stdin = std::io::stdin();
+        if stdin.is_terminal() {
+            return Err(Report::new(Error::ArgParseFailure))
+                .attach_printable("--image-stdin needs an image piped in on stdin");
+        }
+
+        let mimetype = Some(mimetype).filter(|m| !m.is_empty());
+        let image = ImageData::from_reader(stdin.lock(), mimetype)
+            .attach_printable("Reading image from stdin")?;
+        images.push(image);
+    }
+
+    if !args.keep_metadata {
+        images = images
+            .into_iter()
+            .map(|image| image.apply_policy(&config.images))
+            .collect::<Result<_, _>>()?;
+    }
+
+    let unknown_fields = config
+        .unknown_fields
+        .iter()
+        .chain(template_unknown_fields.iter())
+        .cloned()
+        .collect::<Vec<_>>();
+    unknown_fields::check_unknown_fields(&unknown_fields, args.strict_config)?;
+
+    // Tools are shell commands too, so they need the same permission as the `shell` function.
+    let shell_access = ShellAccess::for_run(input.allow_shell, args.allow_shell);
+    if !input.tools.is_empty() && shell_access != ShellAccess::Allowed {
+        return Err(Report::new(Error::ToolsNotAllowed)).attach_printable(
+            "The template must set `allow_shell = true` and the run must pass --allow-shell",
+        );
+    }
+
+    if args.judge.is_some() && args.n.unwrap_or(1) < 2 {
+        return Err(Report::new(Error::ArgParseFailure))
+            .attach_printable("--judge needs an --n of 2 or more responses to choose from");
+    }
 
-    let (mut args, mut template_context, images) = parse_template_args(cmdline, &base_dir, &input)?;
+    // Load the judge first, so that a missing judge template doesn't waste the responses.
+    let judge = args
+        .judge
+        .as_deref()
+        .map(|name| {
+            TemplateJudge::new(&config, name, args.judge_model.as_deref(), args.allow_shell)
+        })
+        .transpose()?;
 
     let mut model_options = config.model;
     model_options.update_from_model_input(&input.model);
+    let rule = model_rules::apply_model_rules(
+        &input.model_rules,
+        input.options.keys(),
+        &template_context,
+        &mut model_options,
+    )?;
+    if let Some(rule) = rule {
+        if args.verbose {
+            eprintln!("Using the model options for `{}`", rule.when);
+        }
+    }
+    let rule_chose_model = rule.is_some_and(|rule| rule.options.model.is_some());
     model_options.update_from_args(&args);
+    model_options.learned_context_limits = context::load_learned_limits();
+    let render_env = RenderEnv {
+        partials,
+        filters: PromptFilters::new(model_options.tokenizer.as_deref()).with_shell(shell_access),
+        ..Default::default()
+    };
+    file_options::limit_tokens(
+        &input.options,
+        &mut template_context,
+        model_options.tokenizer.as_deref(),
+    )?;
+    model_options.context.pinned_args = input
+        .options
+        .iter()
+        .filter(|(_, option)| option.pin)
+        .map(|(name, _)| name.clone())
+        .collect();
+
+    let mut output_options = OutputOptions::new(&input.output, &args, &template_name);
+    output_options.stream_filters = StreamFilters::new(&input.output.stream_filters)
+        .attach_printable_lazy(|| template_path.display().to_string())?;
+    let mut checks = ResponseChecks::from(&input.checks);
+    checks.schema = response_schema.clone();
+    let post_process = PostProcess::new(input.output.post_process.clone())
+        .attach_printable_lazy(|| template_path.display().to_string())?;
+    model_options.response_schema = response_schema;
+    let moderation = ModerationPolicy::for_template(&config.moderation, &input.moderation);
+
+    let target_length = args.target_length.or(input.target_length);
+    if let Some(target_length) = target_length {
+        if model_options.max_tokens.is_none() {
+            model_options.max_tokens = Some(target_length.max_tokens());
+        }
+        if args.enforce_length || input.checks.enforce_length.unwrap_or(false) {
+            checks.length = Some(target_length);
+        }
+    }
 
-    let template = assemble_template(&mut args, &mut template_context, template)?;
+    if let Some(message) = args.follow_up.clone() {
+        // Send the message after the conversation from the last run instead of rendering the
+        // template.
+        let previous = History::new()?
+            .last_run_of(&template_name)?
+            .ok_or_else(|| Error::NoRunToContinue(template_name.clone()))?;
+        select_capable_models(&config.capabilities, &mut model_options, &input.requires)?;
+        let system = previous.system.clone().unwrap_or_default();
+        let message = match target_length {
+            Some(target_length) => target_length.add_instruction(message),
+            None => message,
+        };
+        let session_history = context::fit_session_history(
+            &model_options,
+            &message,
+            &system,
+            previous.conversation(),
+        )?;
+
+        return Ok(GeneratedTemplate {
+            save_history: config.history && !args.no_history,
+            cache_policy: config.response_cache,
+            log_file: args.log.clone().or(config.log_file),
+            confirm: config.confirm,
+            budget: config.budget,
+            moderation,
+            prices: config.prices,
+            checks,
+            post_process,
+            sources,
+            judge,
+            args,
+            model_options,
+            output_options,
+            segments: PromptSegments::split(&system, &message, Vec::new()),
+            prompt: message,
+            system,
+            images,
+            tools: std::mem::take(&mut input.tools),
+            session_history,
+            assembly_trace: AssemblyTrace::disabled(),
+            template_name,
+            template_path,
+            template,
+            template_context: tera::Context::new(),
+            render_env,
+        });
+    }
+
+    let mut assembly_trace = if args.trace_assembly {
+        AssemblyTrace::new(model_options.tokenizer.as_deref())?
+    } else {
+        AssemblyTrace::disabled()
+    };
+
+    let (template, extra) = assemble_template(
+        &mut args,
+        &mut template_context,
+        template,
+        &mut assembly_trace,
+    )?;
+
+    let mut segment_parts = segments::file_segments(&input.options, &template_context);
+
+    if input.project_context && template_context["project_context"].is_null() {
+        let project_context = project_context::load_project_context(&base_dir)?;
+        let contents = match project_context {
+            Some(context) => {
+                segment_parts.push(PromptSegment::Context {
+                    file: context.path.display().to_string(),
+                    text: context.contents.clone(),
+                });
+                context.contents
+            }
+            None => String::new(),
+        };
+        template_context["project_context"] = contents.into();
+    }
+
+    segment_parts.push(PromptSegment::Extra { text: extra });
+
+    // Options with the same names take precedence over the template's date settings.
+    for (name, setting) in [("timezone", &input.timezone), ("locale", &input.locale)] {
+        if let Some(setting) = setting.as_ref() {
+            if template_context[name].is_null() {
+                template_context[name] = setting.clone().into();
+            }
+        }
+    }
+    let dates = DateSettings::new(
+        template_context["timezone"].as_str(),
+        template_context["locale"].as_str(),
+    )
+    .attach_printable_lazy(|| template_path.display().to_string())?;
+    if template_context["today"].is_null() {
+        template_context["today"] = dates.today().into();
+    }
 
     let template_context =
         tera::Context::from_value(template_context).change_context(Error::PreparePrompt)?;
 
-    let prompt = render_template(&template_path, &template, &template_context)
+    let prompt = render_template(&template_path, &template, &template_context, &render_env)
         .attach_printable("Rendering template")
         .attach_printable_lazy(|| template_path.display().to_string())?;
+    assembly_trace.record(AssemblyStepKind::Render, "", &template, &prompt)?;
+
     let system_prompt = if let Some((system_path, system_template)) = system {
-        render_template(&system_path, &system_template, &template_context)
-            .attach_printable("Rendering system template")
-            .attach_printable_lazy(|| system_path.display().to_string())?
+        render_template(
+            &system_path,
+            &system_template,
+            &template_context,
+            &render_env,
+        )
+        .attach_printable("Rendering system template")
+        .attach_printable_lazy(|| system_path.display().to_string())?
     } else {
         String::new()
     };
 
+    // A model from the command line or a model rule always takes precedence.
+    if input.routing == RoutingMode::Auto && args.model.is_none() && !rule_chose_model {
+        let reason =
+            routing::route_model(&config.routing, &mut model_options, &prompt, &system_prompt)?;
+        if args.verbose {
+            eprintln!("{reason}");
+        }
+    }
+
+    select_capable_models(&config.capabilities, &mut model_options, &input.requires)?;
+
+    let rendered_prompt = args.trace_assembly.then(|| prompt.clone());
     let prompt = context::enforce_context_limit(
         &model_options,
         &template_path,
         &template,
-        template_context,
+        &render_env,
+        template_context.clone(),
         prompt,
     )?;
+    if let Some(rendered_prompt) = rendered_prompt {
+        assembly_trace.record(
+            AssemblyStepKind::Truncate,
+            model_options.context.trim_args.join(", "),
+            &rendered_prompt,
+            &prompt,
+        )?;
+    }
+
+    // Added after trimming the prompt, so that the instruction is never trimmed away.
+    let prompt = match target_length {
+        Some(target_length) => {
+            let with_instruction = target_length.add_instruction(prompt.clone());
+            assembly_trace.record(
+                AssemblyStepKind::TargetLength,
+                target_length.to_string(),
+                &prompt,
+                &with_instruction,
+            )?;
+            with_instruction
+        }
+        None => prompt,
+    };
+
+    let session_history = match args.session.as_deref() {
+        Some(name) => context::fit_session_history(
+            &model_options,
+            &prompt,
+            &system_prompt,
+            SessionStore::new()?.load(name)?.messages,
+        )?,
+        None => Vec::new(),
+    };
+
+    let segments = PromptSegments::split(&system_prompt, &prompt, segment_parts);
+    assembly_trace.record_segments(&segments)?;
+    sources.extend(render_env.sources.files());
 
-    Ok((args, model_options, prompt, system_prompt, images))
+    Ok(GeneratedTemplate {
+        save_history: config.history && !args.no_history,
+        cache_policy: config.response_cache,
+        log_file: args.log.clone().or(config.log_file),
+        confirm: config.confirm,
+        budget: config.budget,
+        moderation,
+        prices: config.prices,
+        checks,
+        post_process,
+        sources,
+        judge,
+        args,
+        model_options,
+        output_options,
+        prompt,
+        system: system_prompt,
+        segments,
+        images,
+        tools: std::mem::take(&mut input.tools),
+        session_history,
+        assembly_trace,
+        template_name,
+        template_path,
+        template,
+        template_context,
+        render_env,
+    })
 }
 
 fn run_template(
+    config: Config,
     base_dir: PathBuf,
     template: String,
     args: Vec<OsString>,
-    mut output: impl std::io::Write + Send + 'static,
+    stdin: Option<String>,
+    output: impl std::io::Write + Send + 'static,
 ) -> Result<(), Report<Error>> {
-    let (args, model_options, prompt, system, images) =
-        generate_template(base_dir, template, args)?;
+    let directory = base_dir.clone();
+    let cmdline = args
+        .iter()
+        .skip(1)
+        .map(|arg| arg.to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+
+    let generated = generate_template_with_stdin(config, base_dir, template, args, stdin)?;
+    if !prepare_run(&generated)? {
+        return Ok(());
+    }
+
+    let (output, output_file): (Box<dyn std::io::Write + Send>, _) =
+        match generated.args.output.as_deref() {
+            Some(path) => {
+                let (file, output_file) = output::open_output_file(
+                    path,
+                    &generated.template_context,
+                    generated.args.append_output,
+                )?;
+                (Box::new(file), Some(output_file))
+            }
+            None => (Box::new(output), None),
+        };
+
+    let invocation = Invocation {
+        directory,
+        args: cmdline,
+        stdin: generated.args.stdin.clone(),
+    };
+    send_prompt(generated, invocation, output)?;
+
+    // Only replace the output file once the whole response has been written.
+    if let Some(output_file) = output_file {
+        output_file.finish()?;
+    }
+    Ok(())
+}
+
+/// Print the prompt and ask for confirmation, as the arguments request. Returns false if the
+/// prompt should not be sent.
+fn prepare_run(generated: &GeneratedTemplate) -> Result<bool, Report<Error>> {
+    let GeneratedTemplate {
+        args,
+        model_options,
+        prompt,
+        system,
+        segments,
+        images,
+        session_history,
+        assembly_trace,
+        confirm,
+        budget,
+        prices,
+        ..
+    } = generated;
+
+    if args.trace_assembly {
+        assembly_trace.print();
+    }
 
     if args.verbose {
         eprintln!("{model_options:?}");
+        eprintln!("Prompt segments: {}", segments.describe());
+    }
+
+    if let Some(session) = args.session.as_deref() {
+        if args.verbose || args.dry_run {
+            eprintln!(
+                "Sending {} earlier messages from session {session}",
+                session_history.len()
+            );
+        }
     }
 
     if args.print_prompt || args.verbose || args.dry_run {
         if !system.is_empty() {
             eprintln!("== System:\n{system}\n");
         }
-        eprintln!("== Prompt:\n{prompt}\n\n== Result:");
+        eprintln!("== Prompt:\n{prompt}\n");
+        if args.verbose {
+            image_preview::print_previews(images);
+        }
+        eprintln!("== Result:");
     }
 
     if args.dry_run {
-        return Ok(());
+        return Ok(false);
+    }
+
+    let check_budget = budget.is_enabled() && !args.force;
+    let ask = !args.yes && std::io::stderr().is_terminal();
+    if !check_budget && !ask {
+        return Ok(true);
+    }
+
+    let estimate = estimate_run(generated)?;
+    if check_budget {
+        budget::enforce_budget(budget, prices, &estimate)?;
+    }
+
+    if ask {
+        confirm::confirm_run(confirm, &estimate)?;
+    }
+
+    Ok(true)
+}
+
+/// Estimate everything that the run sends: each of the `--n` requests, and the request to the
+/// judge that chooses between them.
+fn estimate_run(generated: &GeneratedTemplate) -> Result<RunEstimate, Report<Error>> {
+    let GeneratedTemplate {
+        args,
+        model_options,
+        prompt,
+        system,
+        session_history,
+        prices,
+        judge,
+        ..
+    } = generated;
+
+    let estimate = RunEstimate::new(prices, model_options, prompt, system, session_history)?;
+    let Some(n) = args.n.map(|n| n as usize).filter(|n| *n > 1) else {
+        return Ok(estimate);
+    };
+
+    let estimate = estimate.times(n);
+    match judge {
+        Some(judge) => {
+            let response_tokens = model_options.max_tokens.unwrap_or_default() as usize;
+            let judge_estimate = judge.estimate(prices, prompt, system, n, response_tokens)?;
+            Ok(estimate.add(&judge_estimate))
+        }
+        None => Ok(estimate),
+    }
+}
+
+/// Send the prompt to the model, write the response to `output`, and save the run to the history.
+fn send_prompt(
+    generated: GeneratedTemplate,
+    invocation: Invocation,
+    output: impl std::io::Write + Send + 'static,
+) -> Result<(), Report<Error>> {
+    let events = match generated.args.events.as_deref() {
+        Some(path) => RunEvents::json_lines(path)?,
+        None => RunEvents::default(),
+    };
+    events.emit(RunEvent::PromptRendered {
+        template: &generated.template_name,
+        system: Some(generated.system.as_str()).filter(|s| !s.is_empty()),
+        prompt: &generated.prompt,
+    });
+
+    let result = moderate_prompt(&generated).and_then(|moderation| {
+        match generated.args.n.filter(|n| *n > 1) {
+            Some(n) => completions::send_completions(
+                generated, invocation, output, n as usize, moderation, &events,
+            ),
+            None => send_single_prompt(generated, invocation, output, moderation, &events),
+        }
+    });
+    if let Err(e) = &result {
+        events.emit(RunEvent::Error {
+            message: e.current_context().to_string(),
+        });
     }
+    result
+}
+
+/// Check the prompt with the template's moderation policy, before anything is sent.
+fn moderate_prompt(generated: &GeneratedTemplate) -> Result<Vec<ModerationResult>, Report<Error>> {
+    let text = if generated.system.is_empty() {
+        Cow::Borrowed(generated.prompt.as_str())
+    } else {
+        Cow::Owned(format!("{}\n\n{}", generated.system, generated.prompt))
+    };
+    let result =
+        generated
+            .moderation
+            .check(ModerationStage::Input, &text, &generated.model_options)?;
+    Ok(result.into_iter().collect())
+}
 
+fn send_single_prompt(
+    generated: GeneratedTemplate,
+    invocation: Invocation,
+    mut output: impl std::io::Write + Send + 'static,
+    mut moderation_results: Vec<ModerationResult>,
+    events: &RunEvents,
+) -> Result<(), Report<Error>> {
+    let GeneratedTemplate {
+        template_name,
+        args,
+        mut model_options,
+        output_options,
+        mut prompt,
+        system,
+        images,
+        tools,
+        session_history,
+        save_history,
+        cache_policy,
+        log_file,
+        budget,
+        moderation,
+        prices,
+        checks,
+        post_process,
+        sources,
+        template_path,
+        template,
+        template_context,
+        render_env,
+        ..
+    } = generated;
+
+    interrupt::install_handler();
+
+    // If the request fails the output stops, and if writing the output fails the request stops.
+    let cancel = CancellationToken::new();
+    let usage = UsageMeter::new();
     let (message_tx, message_rx) = flume::bounded(32);
+    let print_cancel = cancel.clone();
+    let print_options = output_options.clone();
+    let print_events = events.clone();
+    let waiting = format!(
+        "Waiting for {}",
+        model_options.full_model_spec().model_name()
+    );
     let print_thread = std::thread::spawn(move || {
-        for message in message_rx {
-            write!(output, "{}", message)?;
-            output.flush()?;
-        }
+        // Long prompts can take a while to be answered, so show that the request is working
+        // until the response starts.
+        let mut spinner = Spinner::start(waiting);
+        let first_message = message_rx.recv().ok();
+        spinner.stop();
+        let first_message_at = Instant::now();
 
-        writeln!(output, "")?;
-        Ok::<(), std::io::Error>(())
+        // What arrived is kept separately, since there is no full response if writing it fails.
+        let mut received = String::new();
+        let messages = first_message
+            .into_iter()
+            .chain(message_rx)
+            .inspect(|text: &String| {
+                received.push_str(text);
+                print_events.emit(RunEvent::Chunk { text });
+            });
+        let written = print_cancel
+            .cancel_on_error(|| output::write_output(&print_options, messages, &mut output));
+        // Keep the output so that the JSON result can be written once the run is done.
+        (written, received, output, first_message_at)
     });
 
     let system = if system.is_empty() {
@@ -116,31 +797,775 @@ fn run_template(
         Some(system)
     };
 
-    let host = model_options.api_host()?;
-    let input = ModelInput {
-        prompt: &prompt,
-        system: system.as_deref(),
-        images,
+    let cache_key = (cache_policy.enabled && !args.no_cache)
+        .then(|| {
+            let input = ModelInput {
+                prompt: &prompt,
+                system: system.as_deref(),
+                history: &session_history,
+                images: images.clone(),
+                tools: &tools,
+                cancel: &cancel,
+                usage: &usage,
+                events,
+            };
+            response_cache::cache_key(&model_options, &input)
+        })
+        .flatten();
+    let cache = cache_key.as_ref().and_then(|_| Cache::new().ok());
+    let cached = match (&cache, &cache_key) {
+        (Some(cache), Some(key)) if !args.refresh => {
+            let max_age = args.max_age.unwrap_or(cache_policy.ttl);
+            response_cache::read(cache, key, max_age, &model_options).unwrap_or_else(|e| {
+                eprintln!("Warning: Failed to read the response cache: {e:?}");
+                None
+            })
+        }
+        _ => None,
     };
 
-    host.send_model_request(&model_options, input, message_tx)
-        .change_context(Error::RunPrompt)?;
+    let started = Instant::now();
+    let from_cache = cached.is_some();
+    let mut fallback_options = None;
+    // The cache holds responses from before post-processing, so that changing the steps doesn't
+    // require a new request. This returns that response when it differs from the one written.
+    let result = cancel.cancel_on_error(|| -> Result<Option<String>, Report<Error>> {
+        if let Some(response) = cached {
+            if args.verbose {
+                eprintln!("Using the cached response");
+            }
+            moderation_results.extend(moderation.check(
+                ModerationStage::Output,
+                &response,
+                &model_options,
+            )?);
+            if post_process.is_empty() {
+                message_tx.send(response).ok();
+                return Ok(None);
+            }
+            message_tx.send(post_process.apply(response.clone())?).ok();
+            return Ok(Some(response));
+        }
+
+        // Responses that need to be checked, moderated, or post-processed are collected here
+        // instead of being written as they arrive, since they may change or be blocked before they
+        // are written.
+        let collect_response = !checks.is_empty() || !post_process.is_empty() || moderation.output;
+        let (checked_tx, checked_rx) = flume::unbounded();
+        let response_tx = if !collect_response {
+            message_tx.clone()
+        } else {
+            checked_tx
+        };
+
+        let mut retries = 0;
+        loop {
+            let input = ModelInput {
+                prompt: &prompt,
+                system: system.as_deref(),
+                history: &session_history,
+                images: images.clone(),
+                tools: &tools,
+                cancel: &cancel,
+                usage: &usage,
+                events,
+            };
 
-    print_thread.join().unwrap().ok();
+            let err = match hosts::send_model_request(&model_options, &input, response_tx.clone()) {
+                Ok(fallback) => {
+                    fallback_options = fallback;
+                    break;
+                }
+                Err(err) => err,
+            };
+
+            let reported_limit = match err.downcast_ref::<ModelError>() {
+                Some(ModelError::ContextLengthExceeded { limit, .. })
+                    if retries < MAX_CONTEXT_RETRIES =>
+                {
+                    *limit
+                }
+                _ => return Err(err),
+            };
+
+            retries += 1;
+            let (limit, new_prompt) = context::shrink_after_context_error(
+                &mut model_options,
+                reported_limit,
+                &template_path,
+                &template,
+                &render_env,
+                &template_context,
+                &prompt,
+            )?;
+            prompt = new_prompt;
+
+            let model = model_options.full_model_spec().model_name().to_string();
+            eprintln!(
+                "The prompt was too long for {model}. Retrying with a context limit of {limit} tokens"
+            );
+            if let Err(e) = context::save_learned_limit(&model, limit) {
+                eprintln!("Warning: Failed to save the context limit: {e:?}");
+            }
+        }
+        drop(response_tx);
+
+        if !collect_response {
+            return Ok(None);
+        }
+
+        let response = checked_rx.try_iter().collect::<String>();
+        let response = if cancel.is_cancelled() || checks.is_empty() {
+            response
+        } else {
+            let input = ModelInput {
+                prompt: &prompt,
+                system: system.as_deref(),
+                history: &session_history,
+                images,
+                tools: &tools,
+                cancel: &cancel,
+                usage: &usage,
+                events,
+            };
+            checks::repair_response(&checks, &model_options, &input, response)?
+        };
+        moderation_results.extend(moderation.check(
+            ModerationStage::Output,
+            &response,
+            &model_options,
+        )?);
+
+        if cancel.is_cancelled() || post_process.is_empty() {
+            message_tx.send(response).ok();
+            return Ok(None);
+        }
+
+        message_tx.send(post_process.apply(response.clone())?).ok();
+        Ok(Some(response))
+    });
+    drop(message_tx);
+
+    let (written, received, mut output, first_message_at) = print_thread.join().unwrap();
+    let duration = started.elapsed();
+    let unprocessed = result?;
+    // The usage and history go under the model that answered, which is a fallback model when the
+    // earlier ones failed.
+    let answered_options = fallback_options.as_ref().unwrap_or(&model_options);
+    let (response, output_closed) = match written.change_context(Error::WriteOutput) {
+        Ok(response) => (response, false),
+        // The request was stopped when the output closed, and there's no full response to save,
+        // but what was received still counts toward the usage.
+        Err(e) if output::is_broken_pipe(&e) => (received, true),
+        Err(e) => return Err(e),
+    };
+
+    // Cached responses didn't use any tokens.
+    let run_usage = if from_cache {
+        None
+    } else {
+        let input = ModelInput {
+            prompt: &prompt,
+            system: system.as_deref(),
+            history: &session_history,
+            images: Vec::new(),
+            tools: &tools,
+            cancel: &cancel,
+            usage: &usage,
+            events,
+        };
+        Usage::measure(answered_options, &prices, &input, &response)
+    };
+    if let Some(run_usage) = run_usage {
+        ledger::record_run(
+            &template_name,
+            answered_options,
+            run_usage,
+            &prices,
+            &budget,
+        );
+        events.emit(RunEvent::Usage(run_usage));
+    }
+    if output_closed {
+        return Ok(());
+    }
+    if args.usage {
+        match run_usage {
+            Some(run_usage) => eprintln!("Usage: {run_usage}"),
+            None if from_cache => eprintln!("Usage: none, since the response was cached"),
+            None => eprintln!("Usage: unknown, since the tokenizer could not be loaded"),
+        }
+    }
+    if args.stats {
+        let stats = RunStats {
+            first_token: first_message_at.saturating_duration_since(started),
+            total: duration,
+            response_tokens: run_usage.map(|u| u.tokens.response),
+        };
+        eprintln!("Stats: {stats}");
+    }
+    if args.provenance {
+        eprint!("{}", provenance::footnotes(&sources));
+    }
+
+    let model = answered_options.full_model_spec();
+    let run_result = RunResult {
+        response: &output_options.stream_filters.apply(&response),
+        model: model.model_name(),
+        prompt_tokens: run_usage.map(|u| u.tokens.prompt),
+        completion_tokens: run_usage.map(|u| u.tokens.response),
+        duration_ms: duration.as_millis() as u64,
+    };
+    match output::write_result(&output_options, &run_result, &mut output)
+        .change_context(Error::WriteOutput)
+    {
+        Ok(()) => {}
+        Err(e) if output::is_broken_pipe(&e) => return Ok(()),
+        Err(e) => return Err(e),
+    }
+
+    // The response has already been written, so failing to copy it isn't worth failing the run.
+    if args.copy && !response.is_empty() {
+        if let Err(e) = clipboard::copy(&response) {
+            eprintln!("Warning: {e:?}");
+        }
+    }
+
+    // The request was sent even if it was interrupted, so log whatever came back.
+    if let Some(path) = log_file.as_deref() {
+        let model = answered_options.full_model_spec();
+        let entry = LogEntry {
+            timestamp: chrono::Utc::now(),
+            template: &template_name,
+            model: model.model_name(),
+            system: system.as_deref(),
+            history: &session_history,
+            prompt: &prompt,
+            response: &response,
+            cached: from_cache,
+        };
+        if let Err(e) = transcript_log::append(path, &entry) {
+            eprintln!("Warning: Failed to write the log file: {e:?}");
+        }
+    }
+
+    if interrupt::interrupted() {
+        return Err(Report::new(Error::Interrupted));
+    }
+
+    events.emit(RunEvent::Completed {
+        response: &response,
+        duration_ms: duration.as_millis() as u64,
+        cached: from_cache,
+    });
+
+    if let (Some(cache), Some(key)) = (&cache, &cache_key) {
+        // A fallback model's response isn't cached, since it would be served for the first model.
+        if !from_cache && fallback_options.is_none() {
+            let response = unprocessed.as_deref().unwrap_or(&response);
+            let entry = CachedResponse::new(&template_name, &model_options, response);
+            if let Err(e) = response_cache::write(cache, key, &entry) {
+                eprintln!("Warning: Failed to save the response to the cache: {e:?}");
+            }
+        }
+    }
+
+    if let Some(session) = args.session.as_deref() {
+        SessionStore::new()?.append(
+            session,
+            [
+                ChatMessage::user(&prompt),
+                ChatMessage::assistant(&response),
+            ],
+        )?;
+    }
+
+    if save_history {
+        let mut entry = HistoryEntry::new(
+            &template_name,
+            answered_options.full_model_spec().model_name(),
+            system.as_deref(),
+            &prompt,
+            &response,
+        );
+        entry.invocation = Some(invocation);
+        entry.parameters = Some(RunParameters::from(answered_options));
+        entry.tokens = TokenCounts::count(
+            answered_options.tokenizer.as_deref(),
+            system.as_deref(),
+            &prompt,
+            &response,
+        );
+        entry.duration_ms = Some(duration.as_millis() as u64);
+        entry.usage = run_usage;
+        entry.sources = sources;
+        entry.earlier_messages = session_history;
+        entry.moderation = moderation_results;
+        History::new()?.add(entry)?;
+    }
+
+    Ok(())
+}
+
+/// Run the most recent run in the history again, optionally with a different model.
+fn run_last_command(
+    program: OsString,
+    model: Option<&str>,
+    search: &SearchOptions,
+) -> Result<(), Report<Error>> {
+    let (_, mut invocation) = History::new()?
+        .last_invocation()?
+        .ok_or(Error::NoRunToRepeat)?;
+    if let Some(model) = model {
+        invocation = invocation.with_model(model);
+    }
+
+    let template = invocation.template().to_string();
+    let cmdline = std::iter::once(program)
+        .chain(invocation.args.into_iter().map(OsString::from))
+        .collect();
+    let config = Config::search(invocation.directory.clone(), search)?;
+    run_template(
+        config,
+        invocation.directory,
+        template,
+        cmdline,
+        invocation.stdin,
+        std::io::stdout(),
+    )
+}
+
+/// Print the usage of past runs from the ledger, grouped by model, template, or provider.
+fn run_usage_command(
+    config: &Config,
+    since: Option<chrono::NaiveDate>,
+    month: Option<Month>,
+    template: Option<&str>,
+    by: UsageGroup,
+) -> Result<(), Report<Error>> {
+    let ledger = Ledger::new(&config.prices)?;
+    let mut entries = match month {
+        Some(month) => ledger.month(month)?,
+        None => ledger.entries()?,
+    };
+    if let Some(since) = since {
+        entries.retain(|e| e.timestamp.date_naive() >= since);
+    }
+    if let Some(template) = template {
+        entries.retain(|e| e.template == template);
+    }
+
+    print!("{}", usage::summarize(&entries, by));
+    Ok(())
+}
+
+fn run_session_command(command: SessionCommand) -> Result<(), Report<Error>> {
+    let store = SessionStore::new()?;
+    match command {
+        SessionCommand::List => {
+            for (name, messages) in store.list()? {
+                println!("{name}  {messages} messages");
+            }
+        }
+        SessionCommand::Show { name } => {
+            let session = store.load(&name)?;
+            if session.messages.is_empty() {
+                return Err(Report::new(Error::SessionNotFound(name)));
+            }
+            print!("{}", session.transcript());
+        }
+        SessionCommand::Clear { name } => store.clear(&name)?,
+    }
+
+    Ok(())
+}
+
+fn run_import_command(
+    base_dir: PathBuf,
+    search: &SearchOptions,
+    source: ImportSource,
+    path: &str,
+    dir: Option<PathBuf>,
+    force: bool,
+) -> Result<(), Report<Error>> {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => {
+            let config = Config::search(base_dir.clone(), search)?;
+            config.template_dirs.into_iter().next().unwrap_or(base_dir)
+        }
+    };
+
+    let templates = import::read_templates_from(source, path)?;
+    let mut skipped = 0;
+    for (name, outcome) in import::write_templates(&templates, &dir, force)? {
+        match outcome {
+            ImportOutcome::Written(path) => println!("{name}\t{}", path.display()),
+            ImportOutcome::Exists(path) => {
+                skipped += 1;
+                eprintln!("Skipping {name}: {} already exists", path.display());
+            }
+            ImportOutcome::Unnamed => {
+                eprintln!("Skipping a prompt whose name can't be used as a file name");
+            }
+        }
+    }
+
+    if skipped > 0 {
+        eprintln!("Pass --force to replace the {skipped} existing templates");
+    }
+
+    Ok(())
+}
+
+fn run_cache_command(
+    base_dir: PathBuf,
+    search: &SearchOptions,
+    command: CacheCommand,
+) -> Result<(), Report<Error>> {
+    let cache = Cache::new()?;
+    match command {
+        CacheCommand::Ls => {
+            let config = Config::search(base_dir, search)?;
+            for (key, entry) in response_cache::entries(&cache)? {
+                println!("{}", entry.summary(&key, config.response_cache.ttl));
+            }
+        }
+        CacheCommand::Show { key } => {
+            let (key, entry) = response_cache::find(&cache, &key)?;
+            println!("Key: {key}");
+            println!("Saved: {}", entry.timestamp.format("%Y-%m-%d %H:%M:%S"));
+            println!("Template: {}", entry.template);
+            println!("Model: {}", entry.model);
+            println!("Temperature: {}", entry.temperature);
+            println!();
+            println!("{}", entry.response);
+        }
+    }
+
+    Ok(())
+}
+
+fn run_list_command(config: &Config, json: bool) -> Result<(), Report<Error>> {
+    let list = list::list_templates(config);
+
+    for (path, error) in &list.errors {
+        eprintln!("Warning: Skipping {}: {error}", path.display());
+    }
+
+    if json {
+        let output =
+            serde_json::to_string_pretty(&list.templates).change_context(Error::PreparePrompt)?;
+        println!("{output}");
+        return Ok(());
+    }
+
+    let name_width = list.templates.iter().map(|t| t.name.len()).max();
+    let namespace_width = list.templates.iter().map(|t| t.namespace.len()).max();
+    let model_width = list.templates.iter().map(|t| t.model.len()).max();
+    for template in &list.templates {
+        let line = format!(
+            "{:name_width$}  {:namespace_width$}  {:model_width$}  {}  {}",
+            template.name,
+            template.namespace,
+            template.model,
+            template.directory.display(),
+            template.description,
+            name_width = name_width.unwrap_or(0),
+            namespace_width = namespace_width.unwrap_or(0),
+            model_width = model_width.unwrap_or(0),
+        );
+        println!("{}", line.trim_end());
+    }
+
+    Ok(())
+}
+
+fn run_validate_command(config: &Config) -> Result<(), Report<Error>> {
+    let result = validate::validate_templates(config);
+
+    for problem in &result.problems {
+        println!("{problem}");
+    }
+
+    if result.problems.is_empty() {
+        eprintln!("Checked {} templates with no problems", result.checked);
+        Ok(())
+    } else {
+        Err(Report::new(Error::ValidationFailed(result.problems.len())))
+    }
+}
+
+fn run_doctor_command(
+    base_dir: PathBuf,
+    search: &SearchOptions,
+    hosts: &[String],
+) -> Result<(), Report<Error>> {
+    let checks = doctor::run_checks(base_dir, search, hosts);
+    for check in &checks {
+        println!("{check}");
+    }
+
+    let failed = checks
+        .iter()
+        .filter(|c| c.status == doctor::Status::Failed)
+        .count();
+    if failed == 0 {
+        Ok(())
+    } else {
+        Err(Report::new(Error::DoctorFailed(failed)))
+    }
+}
+
+fn run_githooks_command(
+    base_dir: PathBuf,
+    search: &SearchOptions,
+    command: GithooksCommand,
+) -> Result<(), Report<Error>> {
+    match command {
+        GithooksCommand::Install {
+            kind,
+            template,
+            force,
+        } => {
+            let installed = githooks::install_hook(
+                &base_dir,
+                search,
+                &githooks::InstallOptions {
+                    kind,
+                    template: template.as_deref(),
+                    force,
+                },
+            )?;
+
+            if let Some(template_file) = installed.template_file {
+                eprintln!(
+                    "Wrote the template {} to {}",
+                    installed.template,
+                    template_file.display()
+                );
+            }
+            eprintln!(
+                "Installed {} to run {}",
+                installed.hook.display(),
+                installed.template
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn run_history_command(command: HistoryCommand) -> Result<(), Report<Error>> {
+    let history = History::new()?;
+    match command {
+        HistoryCommand::List(args) => {
+            let entries = history.entries()?;
+            let entries = entries
+                .iter()
+                .rev()
+                .filter(|e| args.template.iter().all(|t| &e.template == t))
+                .filter(|e| args.grep.iter().all(|text| e.contains_text(text)))
+                .take(args.limit)
+                .collect::<Vec<_>>();
+
+            if args.json {
+                let json = serde_json::to_string_pretty(&entries).change_context(Error::History)?;
+                println!("{json}");
+            } else {
+                for entry in entries {
+                    println!("{}", entry.summary());
+                }
+            }
+        }
+        HistoryCommand::Show { ids, format } => {
+            let entries = ids
+                .into_iter()
+                .map(|id| history.get(id))
+                .collect::<Result<Vec<_>, _>>()?;
+            print!("{}", history::export(&entries, format));
+        }
+        HistoryCommand::Rate { id, score, name } => history.set_score(id, &name, score)?,
+    }
 
     Ok(())
 }
 
-fn run(base_dir: PathBuf, cmdline: Vec<OsString>) -> Result<(), Report<Error>> {
-    let args = parse_main_args(cmdline).map_err(Error::CmdlineParseFailure)?;
+fn run_dataset_command(
+    base_dir: PathBuf,
+    search: &SearchOptions,
+    command: DatasetCommand,
+) -> Result<(), Report<Error>> {
+    match command {
+        DatasetCommand::Build(args) => {
+            let mut entries = History::new()?.entries()?;
+            // Filter by template first so that runs which won't be used aren't judged.
+            if let Some(template) = args.template.as_deref() {
+                entries.retain(|e| e.template == template);
+            }
+
+            if let Some(judge_name) = args.judge.as_deref() {
+                let config = Config::search(base_dir, search)?;
+                let judge = TemplateJudge::new(&config, judge_name, None, args.allow_shell)?;
+
+                let cancel = CancellationToken::new();
+                judge::judge_entries(
+                    &mut entries,
+                    &judge::VerdictCache::new()?,
+                    &judge::JudgeOptions {
+                        judge: judge_name,
+                        version: &judge.version(),
+                        score_name: &args.judge_score_name,
+                        concurrency: args.concurrency,
+                        rejudge: args.rejudge,
+                        cancel: cancel.clone(),
+                    },
+                    |entry| judge.score(entry, &cancel),
+                )?;
+            }
+
+            let dataset = dataset::build(
+                entries,
+                &dataset::DatasetOptions {
+                    template: args.template.as_deref(),
+                    filters: &args.filter,
+                    dedup: args.dedup,
+                    val_split: args.val_split,
+                    seed: args.seed,
+                },
+            );
+
+            dataset::write_entries(&dataset.train, args.format, args.output.as_deref())?;
+            if let Some(val_output) = args.val_output.as_deref() {
+                dataset::write_entries(&dataset.val, args.format, Some(val_output))?;
+            }
+
+            eprintln!(
+                "Wrote {} training and {} validation examples",
+                dataset.train.len(),
+                dataset.val.len()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn run(base_dir: PathBuf, mut cmdline: Vec<OsString>) -> Result<(), Report<Error>> {
+    let runs_template = matches!(
+        cmdline.get(1).and_then(|arg| arg.to_str()),
+        Some("run" | "chat")
+    );
+
+    // Aliases come from the configuration, which can't be read until the search settings are
+    // known. They are expanded before parsing so that the template they stand for is found
+    // the same way as one given directly.
+    let mut config = None;
+    let mut search = SearchArgs::default();
+    if runs_template {
+        search = SearchArgs::take_from(&mut cmdline).map_err(Error::CmdlineParseFailure)?;
+        let loaded = Config::search(base_dir.clone(), &search.options(&base_dir)?)?;
+        template_alias::expand_alias(&mut cmdline, &loaded)?;
+        config = Some(loaded);
+    }
 
-    match args {
+    let args = parse_main_args(cmdline.clone()).map_err(Error::CmdlineParseFailure)?;
+    let cli = match args {
         FoundCommand::Run { template, args } => {
+            let config = config.expect("config is loaded for templates");
             let stdout = std::io::stdout();
-            run_template(base_dir, template, args, stdout)?;
+            return run_template(config, base_dir, template, args, None, stdout);
         }
-        FoundCommand::Other(_cli) => {
-            todo!()
+        FoundCommand::Chat { template, args } => {
+            let config = config.expect("config is loaded for templates");
+            return chat::run_chat(config, base_dir, template, args);
+        }
+        FoundCommand::Other(mut cli) => {
+            if !runs_template {
+                search = std::mem::take(&mut cli.search);
+            }
+            cli
+        }
+    };
+
+    let search = search.options(&base_dir)?;
+    let mut load_config = || match config.take() {
+        Some(config) => Ok(config),
+        None => Config::search(base_dir.clone(), &search),
+    };
+
+    match cli.command {
+        MainCommand::List { json } => run_list_command(&load_config()?, json)?,
+        MainCommand::Validate => run_validate_command(&load_config()?)?,
+        MainCommand::Doctor { hosts } => run_doctor_command(base_dir, &search, &hosts)?,
+        MainCommand::Edit { template } => {
+            edit::edit_template(&load_config()?, &template)?;
+        }
+        MainCommand::Show { template } => {
+            print!("{}", show::describe_template(&load_config()?, &template)?);
+        }
+        MainCommand::Export { template, format } => {
+            let config = load_config()?;
+            println!("{}", export::export_template(&config, &template, format)?);
+        }
+        MainCommand::Schema { kind } => {
+            print!("{}", schema::schema_json(kind));
+        }
+        MainCommand::History { command, list } => {
+            run_history_command(command.unwrap_or(HistoryCommand::List(list)))?
+        }
+        MainCommand::Dataset { command } => run_dataset_command(base_dir, &search, command)?,
+        MainCommand::Githooks { command } => run_githooks_command(base_dir, &search, command)?,
+        MainCommand::Multi {
+            command:
+                MultiCommand::Run {
+                    templates,
+                    json,
+                    args,
+                },
+        } => {
+            let program = cmdline.into_iter().next().unwrap_or_default();
+            multi::run_templates(load_config()?, base_dir, program, &templates, args, json)?;
+        }
+        MainCommand::Session { command } => run_session_command(command)?,
+        MainCommand::Cache { command } => run_cache_command(base_dir, &search, command)?,
+        MainCommand::Import {
+            source,
+            path,
+            dir,
+            force,
+        } => run_import_command(base_dir, &search, source, &path, dir, force)?,
+        MainCommand::Usage {
+            since,
+            month,
+            template,
+            by,
+        } => run_usage_command(&load_config()?, since, month, template.as_deref(), by)?,
+        MainCommand::Chat { template } => {
+            let program = cmdline.into_iter().next().unwrap_or_default();
+            let args = vec![program, OsString::from("run"), OsString::from(&template)];
+            chat::run_chat(load_config()?, base_dir, template, args)?;
+        }
+        MainCommand::Run(run_args) if run_args.last => {
+            let program = cmdline.into_iter().next().unwrap_or_default();
+            run_last_command(program, run_args.model.as_deref(), &search)?;
+        }
+        MainCommand::Run(run_args) if run_args.template.is_empty() => {
+            // No template was given, so let the user choose one.
+            let config = load_config()?;
+            let mut cmdline = cmdline.into_iter();
+            let program = cmdline.next().unwrap_or_default();
+            let cmdline =
+                picker::pick_template_command(&config, program, cmdline.skip(1).collect())?;
+            let template = cmdline[2].to_string_lossy().to_string();
+            run_template(config, base_dir, template, cmdline, None, std::io::stdout())?;
+        }
+        MainCommand::Run(run_args) => {
+            // parse_main_args finds the template name itself, so this only happens if it
+            // was given in a form that it doesn't recognize.
+            let template = run_args.template.clone();
+            let config = load_config()?;
+            run_template(config, base_dir, template, cmdline, None, std::io::stdout())?;
         }
     }
 
@@ -155,8 +1580,33 @@ fn main() -> Result<(), Report<Error>> {
     error_stack::Report::install_debug_hook::<std::panic::Location>(|_, _| {});
 
     load_dotenv();
-    run(
+    let result = run(
         std::env::current_dir().unwrap(),
         std::env::args().into_iter().map(OsString::from).collect(),
-    )
+    );
+
+    if let Err(err) = result.as_ref() {
+        if let Error::CmdlineParseFailure(e) = err.current_context() {
+            // Let clap print help and usage errors in its own format.
+            e.exit();
+        }
+
+        if let Error::Interrupted = err.current_context() {
+            // The partial output has already been written, so just exit.
+            eprintln!("Interrupted");
+            std::process::exit(interrupt::INTERRUPTED_EXIT_CODE);
+        }
+
+        if let Error::RunCancelled
+        | Error::ValidationFailed(_)
+        | Error::DoctorFailed(_)
+        | Error::MultiRunFailed(_) = err.current_context()
+        {
+            // The details have already been printed.
+            eprintln!("{}", err.current_context());
+            std::process::exit(1);
+        }
+    }
+
+    result
 }
@@ -1,6 +1,13 @@
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use error_stack::{Report, ResultExt};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
@@ -8,22 +15,36 @@ use crate::{
     args::GlobalRunArgs,
     context::{ContextOptions, ContextOptionsInput},
     error::Error,
-    hosts::{HostDefinition, ModelHost},
+    hosts::{HostDefinition, HostRule, ModelHost},
     option::{overwrite_from_option, overwrite_option_from_option, update_if_none},
+    requests::{RetryOptions, RetryOptionsInput},
+    response_schema::ResponseSchema,
 };
 
 #[derive(Debug, Clone)]
 pub struct ModelOptions {
     pub model: ModelSpec,
+    /// Options that apply only when using `model`
+    pub model_overrides: ModelOverrides,
+    /// Models to try, in order, if the request to `model` fails.
+    pub fallback: Vec<ModelChainEntry>,
     pub openai_key: Option<String>,
     pub temperature: f32,
     pub format: Option<OutputFormat>,
+    /// The JSON Schema that the response should match, for hosts that support it
+    pub response_schema: Option<ResponseSchema>,
     pub top_k: Option<u32>,
     pub top_p: Option<f32>,
     pub frequency_penalty: Option<f32>,
     pub presence_penalty: Option<f32>,
     pub stop: Vec<String>,
     pub max_tokens: Option<u32>,
+    /// Ask the host to sample deterministically, so that a run can be reproduced
+    pub seed: Option<u64>,
+    /// Change the likelihood of tokens, by token ID
+    pub logit_bias: BTreeMap<String, f32>,
+    /// An identifier for the person using PromptBox, to help the host detect abuse
+    pub user: Option<String>,
     /// Alias of short model names to full names, useful for ollama, for example
     pub alias: HashMap<String, ModelSpec>,
 
@@ -31,8 +52,24 @@ pub struct ModelOptions {
     pub host: HashMap<String, HostDefinition>,
     /// The default host to use for non-OpenAI models, when no other host is specified.
     pub default_host: String,
+    /// Rules which choose the host from the model name, for models without an explicit host
+    pub host_rules: Vec<HostRule>,
 
     pub context: ContextOptions,
+    pub retry: RetryOptions,
+    /// The tokenizer to use when calculating the context length. This can be the name of a
+    /// built-in tokenizer, a path to a `tokenizer.json` file, or a Hugging Face repository.
+    pub tokenizer: Option<String>,
+    /// Give up on a request if the host doesn't send any data for this long.
+    pub timeout: Option<Duration>,
+    /// Give up on a request if connecting to the host takes longer than this.
+    pub connect_timeout: Option<Duration>,
+    /// Which parts of the prompt the host should cache
+    pub prompt_cache: PromptCacheOptions,
+    /// HTTP agents shared by all requests made with these options
+    pub agents: AgentCache,
+    /// Context limits learned from hosts which rejected a prompt as too long, by model name
+    pub learned_context_limits: HashMap<String, usize>,
 }
 
 const DEFAULT_MODEL: &str = "gpt-3.5-turbo";
@@ -42,19 +79,33 @@ impl Default for ModelOptions {
     fn default() -> Self {
         Self {
             model: ModelSpec::default(),
+            model_overrides: ModelOverrides::default(),
+            fallback: Vec::new(),
             openai_key: None,
             temperature: DEFAULT_TEMPERATURE,
             format: None,
+            response_schema: None,
             top_k: None,
             top_p: None,
             frequency_penalty: None,
             presence_penalty: None,
             stop: Vec::new(),
             max_tokens: None,
+            seed: None,
+            logit_bias: BTreeMap::new(),
+            user: None,
             context: ContextOptions::default(),
+            retry: RetryOptions::default(),
+            tokenizer: None,
+            timeout: None,
+            connect_timeout: None,
+            prompt_cache: PromptCacheOptions::default(),
+            agents: AgentCache::default(),
+            learned_context_limits: HashMap::new(),
             alias: HashMap::new(),
             host: HostDefinition::builtin(),
             default_host: HostDefinition::default_host().to_string().to_string(),
+            host_rules: Vec::new(),
         }
     }
 }
@@ -65,25 +116,90 @@ impl ModelOptions {
         host: HashMap<String, HostDefinition>,
         default_host: String,
     ) -> Self {
-        Self {
-            model: value.model.unwrap_or_default(),
+        let mut options = Self {
+            model: ModelSpec::default(),
+            model_overrides: ModelOverrides::default(),
+            fallback: Vec::new(),
             // For security, don't allow setting openAI key in normal config or template files.
             openai_key: None,
             temperature: value.temperature.unwrap_or(DEFAULT_TEMPERATURE),
             format: value.format,
+            response_schema: None,
             top_p: value.top_p,
             top_k: value.top_k,
             frequency_penalty: value.frequency_penalty,
             presence_penalty: value.presence_penalty,
             stop: value.stop.unwrap_or_default(),
             max_tokens: value.max_tokens,
+            seed: value.seed,
+            logit_bias: value.logit_bias,
+            user: value.user,
             alias: value.alias,
             context: value.context.into(),
+            retry: value.retry.into(),
+            tokenizer: value.tokenizer,
+            timeout: value.timeout.map(Duration::from_secs),
+            connect_timeout: value.connect_timeout.map(Duration::from_secs),
+            prompt_cache: value.prompt_cache.into(),
+            agents: AgentCache::default(),
+            learned_context_limits: HashMap::new(),
             host,
             default_host,
+            host_rules: Vec::new(),
+        };
+
+        if let Some(model) = value.model {
+            options.set_model_choice(model);
+        }
+
+        options
+    }
+
+    /// Set the model, or chain of models, to use.
+    pub fn set_model_choice(&mut self, choice: ModelChoice) {
+        match choice {
+            ModelChoice::Single(spec) => {
+                self.model = spec;
+                self.model_overrides = ModelOverrides::default();
+                self.fallback = Vec::new();
+            }
+            ModelChoice::Chain(mut chain) => {
+                if chain.is_empty() {
+                    return;
+                }
+
+                let first = chain.remove(0);
+                self.model = first.model;
+                self.model_overrides = first.overrides;
+                self.fallback = chain;
+            }
         }
     }
 
+    /// Return the options to use for each model in the fallback chain, in order.
+    pub fn model_chain(&self) -> Vec<ModelOptions> {
+        let base = ModelOptions {
+            model_overrides: ModelOverrides::default(),
+            fallback: Vec::new(),
+            ..self.clone()
+        };
+
+        let mut first = base.clone();
+        self.model_overrides.apply(&mut first);
+
+        let mut chain = vec![first];
+        for entry in &self.fallback {
+            let mut options = ModelOptions {
+                model: entry.model.clone(),
+                ..base.clone()
+            };
+            entry.overrides.apply(&mut options);
+            chain.push(options);
+        }
+
+        chain
+    }
+
     pub fn update_from_args(&mut self, args: &GlobalRunArgs) {
         let model_spec = match (&args.model, &args.model_host) {
             (Some(model), host) => Some(ModelSpec::Full {
@@ -93,8 +209,22 @@ impl ModelOptions {
             (_, _) => None,
         };
 
-        overwrite_from_option(&mut self.model, &model_spec);
+        if let Some(model_spec) = model_spec {
+            // A model given on the command line replaces the entire chain.
+            self.set_model_choice(ModelChoice::Single(model_spec));
+        }
         overwrite_from_option(&mut self.temperature, &args.temperature);
+        overwrite_option_from_option(&mut self.top_p, &args.top_p);
+        overwrite_option_from_option(&mut self.top_k, &args.top_k);
+        overwrite_option_from_option(&mut self.max_tokens, &args.max_tokens);
+        if !args.stop.is_empty() {
+            self.stop = args.stop.clone();
+        }
+        overwrite_option_from_option(&mut self.seed, &args.seed);
+        overwrite_option_from_option(&mut self.frequency_penalty, &args.frequency_penalty);
+        overwrite_option_from_option(&mut self.presence_penalty, &args.presence_penalty);
+        overwrite_option_from_option(&mut self.user, &args.user);
+        self.logit_bias.extend(args.logit_bias.iter().cloned());
         overwrite_option_from_option(&mut self.format, &args.format);
         overwrite_from_option(&mut self.context.keep, &args.overflow_keep);
         overwrite_option_from_option(&mut self.context.limit, &args.context_limit);
@@ -103,6 +233,17 @@ impl ModelOptions {
             &args.reserve_output_context,
         );
 
+        if let Some(timeout) = args.timeout {
+            self.timeout = Some(Duration::from_secs(timeout));
+        }
+        if let Some(timeout) = args.connect_timeout {
+            self.connect_timeout = Some(Duration::from_secs(timeout));
+        }
+
+        if args.no_prompt_cache {
+            self.prompt_cache = PromptCacheOptions::default();
+        }
+
         // Always overwrite this since there's no other way to set the key.
         self.openai_key = args.openai_key.clone();
     }
@@ -114,30 +255,49 @@ impl ModelOptions {
             .unwrap_or_else(|| self.model.clone())
     }
 
-    pub fn api_host(&self) -> Result<Box<dyn ModelHost>, Error> {
+    /// The name of the host that requests for the model will be sent to.
+    pub fn host_name(&self) -> String {
         let model_spec = self.full_model_spec();
-        let host_name = match model_spec.host_name() {
-            Some(host) => host,
+        match model_spec.host_name() {
+            Some(host) => host.to_string(),
             None => {
                 let model = model_spec.model_name();
-                if model.starts_with("gpt-4") || model.starts_with("gpt-3.5-") {
-                    "openai"
+                if let Some(host) = HostRule::find_host(&self.host_rules, model) {
+                    host.to_string()
+                } else if model.starts_with("gpt-4") || model.starts_with("gpt-3.5-") {
+                    "openai".to_string()
+                } else if model.starts_with("claude-") && self.routes_claude_to_anthropic() {
+                    "anthropic".to_string()
                 } else if model == "lm-studio" {
-                    "lm-studio"
+                    "lm-studio".to_string()
                 } else {
-                    &self.default_host
+                    self.default_host.clone()
                 }
             }
-        };
+        }
+    }
+
+    /// Claude models go to the built-in Anthropic host only when the configuration doesn't choose
+    /// hosts itself, since setups from before that host existed may reach Claude through a proxy
+    /// that is set as the default host.
+    fn routes_claude_to_anthropic(&self) -> bool {
+        self.host_rules.is_empty() && self.default_host == HostDefinition::default_host()
+    }
 
+    pub fn api_host(&self) -> Result<Box<dyn ModelHost>, Error> {
+        let host_name = self.host_name();
         self.host
-            .get(host_name)
-            .ok_or(Error::UnknownModelHost(host_name.to_string()))
-            .map(|host| host.into_model_host())
+            .get(&host_name)
+            .ok_or(Error::UnknownModelHost(host_name.clone()))
+            .and_then(|host| {
+                host.into_model_host(&host_name, &self.agents, self.timeout, self.connect_timeout)
+            })
     }
 
     pub fn update_from_model_input(&mut self, other: &ModelOptionsInput) {
-        overwrite_from_option(&mut self.model, &other.model);
+        if let Some(model) = other.model.as_ref() {
+            self.set_model_choice(model.clone());
+        }
         overwrite_from_option(&mut self.temperature, &other.temperature);
         overwrite_option_from_option(&mut self.format, &other.format);
         overwrite_option_from_option(&mut self.top_p, &other.top_p);
@@ -146,6 +306,23 @@ impl ModelOptions {
         overwrite_option_from_option(&mut self.presence_penalty, &other.presence_penalty);
         overwrite_from_option(&mut self.stop, &other.stop);
         overwrite_option_from_option(&mut self.max_tokens, &other.max_tokens);
+        overwrite_option_from_option(&mut self.seed, &other.seed);
+        overwrite_option_from_option(&mut self.user, &other.user);
+        self.logit_bias.extend(
+            other
+                .logit_bias
+                .iter()
+                .map(|(token, bias)| (token.clone(), *bias)),
+        );
+        overwrite_option_from_option(&mut self.tokenizer, &other.tokenizer);
+        if let Some(timeout) = other.timeout {
+            self.timeout = Some(Duration::from_secs(timeout));
+        }
+        if let Some(timeout) = other.connect_timeout {
+            self.connect_timeout = Some(Duration::from_secs(timeout));
+        }
+        self.retry.update_from_input(&other.retry);
+        self.prompt_cache.update_from_input(&other.prompt_cache);
 
         for (key, value) in &other.alias {
             if !self.alias.contains_key(key) {
@@ -162,11 +339,12 @@ impl ModelOptions {
         let model_name = model.model_name();
 
         let comms = self.api_host()?;
-        let limit = comms
+        let host_limit = comms
             .model_context_limit(model_name)
             .change_context(Error::ContextLimit)?;
+        let learned_limit = self.learned_context_limits.get(model_name).copied();
 
-        let Some(limit) = limit else {
+        let Some(limit) = host_limit.into_iter().chain(learned_limit).min() else {
             return Ok(None);
         };
 
@@ -182,7 +360,49 @@ impl ModelOptions {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct AgentKey {
+    host: String,
+    endpoint: String,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+}
+
+/// HTTP agents for each host, so that multiple requests to the same host, such as looking up the
+/// context limit and then sending the prompt, can reuse connections. Clones share the same agents.
+#[derive(Debug, Clone, Default)]
+pub struct AgentCache(Arc<Mutex<HashMap<AgentKey, ureq::Agent>>>);
+
+impl AgentCache {
+    /// Get the agent for `endpoint` on the host named `host`, calling `create` to make one if
+    /// it doesn't exist yet.
+    pub fn get(
+        &self,
+        host: &str,
+        endpoint: &str,
+        timeout: Option<Duration>,
+        connect_timeout: Option<Duration>,
+        create: impl FnOnce() -> Result<ureq::Agent, Error>,
+    ) -> Result<ureq::Agent, Error> {
+        let key = AgentKey {
+            host: host.to_string(),
+            endpoint: endpoint.to_string(),
+            timeout,
+            connect_timeout,
+        };
+
+        let mut agents = self.0.lock().unwrap();
+        if let Some(agent) = agents.get(&key) {
+            return Ok(agent.clone());
+        }
+
+        let agent = create()?;
+        agents.insert(key, agent.clone());
+        Ok(agent)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum OutputFormat {
     JSON,
@@ -199,7 +419,7 @@ impl FromStr for OutputFormat {
     }
 }
 
-#[derive(Deserialize, Debug, Eq, Clone)]
+#[derive(Deserialize, Debug, Eq, Clone, JsonSchema)]
 #[serde(untagged)]
 pub enum ModelSpec {
     Plain(String),
@@ -267,10 +487,135 @@ impl From<String> for ModelSpec {
     }
 }
 
-#[derive(Deserialize, Debug, Default, Clone)]
+/// Either a single model, or a list of models to try in order.
+#[derive(Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+#[serde(untagged)]
+pub enum ModelChoice {
+    Single(ModelSpec),
+    Chain(Vec<ModelChainEntry>),
+}
+
+impl From<String> for ModelChoice {
+    fn from(value: String) -> Self {
+        Self::Single(ModelSpec::Plain(value))
+    }
+}
+
+/// Mark parts of the prompt as cacheable, for hosts which support prompt caching such as
+/// Anthropic models through OpenRouter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PromptCacheOptions {
+    /// Cache the system prompt
+    pub system: bool,
+    /// Cache the prompt
+    pub prompt: bool,
+}
+
+impl PromptCacheOptions {
+    pub fn update_from_input(&mut self, input: &PromptCacheOptionsInput) {
+        overwrite_from_option(&mut self.system, &input.system);
+        overwrite_from_option(&mut self.prompt, &input.prompt);
+    }
+}
+
+impl From<PromptCacheOptionsInput> for PromptCacheOptions {
+    fn from(value: PromptCacheOptionsInput) -> Self {
+        let mut options = Self::default();
+        options.update_from_input(&value);
+        options
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default, JsonSchema)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
+pub struct PromptCacheOptionsInput {
+    /// Cache the system prompt
+    pub system: Option<bool>,
+    /// Cache the prompt. This is useful for templates that include large files.
+    pub prompt: Option<bool>,
+}
+
+impl PromptCacheOptionsInput {
+    pub fn merge_defaults(&mut self, other: &PromptCacheOptionsInput) {
+        update_if_none(&mut self.system, &other.system);
+        update_if_none(&mut self.prompt, &other.prompt);
+    }
+}
+
+/// Model options which can be set for a single model in a fallback chain.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, JsonSchema)]
+pub struct ModelOverrides {
+    pub temperature: Option<f32>,
+    pub format: Option<OutputFormat>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<u32>,
+    pub max_tokens: Option<u32>,
+}
+
+impl ModelOverrides {
+    pub fn apply(&self, options: &mut ModelOptions) {
+        overwrite_from_option(&mut options.temperature, &self.temperature);
+        overwrite_option_from_option(&mut options.format, &self.format);
+        overwrite_option_from_option(&mut options.top_p, &self.top_p);
+        overwrite_option_from_option(&mut options.top_k, &self.top_k);
+        overwrite_option_from_option(&mut options.max_tokens, &self.max_tokens);
+    }
+}
+
+/// A model in a fallback chain
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(from = "ModelChainEntryInput")]
+pub struct ModelChainEntry {
+    pub model: ModelSpec,
+    pub overrides: ModelOverrides,
+}
+
+// The schema for a chain entry is the schema of the input format that it is deserialized from.
+impl JsonSchema for ModelChainEntry {
+    fn schema_name() -> String {
+        "ModelChainEntry".to_string()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        ModelChainEntryInput::json_schema(gen)
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum ModelChainEntryInput {
+    Plain(String),
+    Full {
+        model: String,
+        host: Option<String>,
+        #[serde(flatten)]
+        overrides: ModelOverrides,
+    },
+}
+
+impl From<ModelChainEntryInput> for ModelChainEntry {
+    fn from(value: ModelChainEntryInput) -> Self {
+        match value {
+            ModelChainEntryInput::Plain(model) => Self {
+                model: ModelSpec::Plain(model),
+                overrides: ModelOverrides::default(),
+            },
+            ModelChainEntryInput::Full {
+                model,
+                host,
+                overrides,
+            } => Self {
+                model: ModelSpec::Full { model, host },
+                overrides,
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, JsonSchema)]
 #[cfg_attr(test, derive(PartialEq))]
 pub struct ModelOptionsInput {
-    pub model: Option<ModelSpec>,
+    pub model: Option<ModelChoice>,
     pub lm_studio_host: Option<String>,
     pub ollama_host: Option<String>,
     pub temperature: Option<f32>,
@@ -281,15 +626,51 @@ pub struct ModelOptionsInput {
     pub presence_penalty: Option<f32>,
     pub stop: Option<Vec<String>>,
     pub max_tokens: Option<u32>,
+    /// Ask the host to sample deterministically, so that the same prompt gives the same response.
+    /// Supported by OpenAI-compatible hosts and Ollama.
+    pub seed: Option<u64>,
+    /// Make tokens more or less likely, from -100 to 100, keyed by token ID. Supported by
+    /// OpenAI-compatible hosts.
+    #[serde(default)]
+    pub logit_bias: BTreeMap<String, f32>,
+    /// An identifier for the person using PromptBox, sent to OpenAI-compatible hosts to help them
+    /// detect abuse.
+    pub user: Option<String>,
+    /// The tokenizer to use when calculating the context length. This can be the name of a
+    /// built-in tokenizer, a path to a `tokenizer.json` file, or a Hugging Face repository.
+    pub tokenizer: Option<String>,
+    /// Give up on a request if the host doesn't send any data for this many seconds.
+    pub timeout: Option<u64>,
+    /// Give up on a request if connecting to the host takes longer than this many seconds.
+    pub connect_timeout: Option<u64>,
     /// Alias of short model names to full names, useful for ollama, for example
     #[serde(default)]
     pub alias: HashMap<String, ModelSpec>,
 
     #[serde(default)]
     pub context: ContextOptionsInput,
+
+    /// Control how failed requests are retried
+    #[serde(default)]
+    pub retry: RetryOptionsInput,
+
+    /// Mark parts of the prompt as cacheable, for hosts that support it
+    #[serde(default)]
+    pub prompt_cache: PromptCacheOptionsInput,
 }
 
 impl ModelOptionsInput {
+    /// Make a tokenizer path relative to `base_dir`, the directory of the file that declared it.
+    pub fn resolve_tokenizer_path(&mut self, base_dir: &Path) {
+        let Some(tokenizer) = self.tokenizer.as_ref() else {
+            return;
+        };
+
+        if tokenizer.ends_with(".json") && Path::new(tokenizer).is_relative() {
+            self.tokenizer = Some(base_dir.join(tokenizer).to_string_lossy().to_string());
+        }
+    }
+
     /// For any members that are `None` in this `ModelOptions`, use the value from `other`
     pub fn merge_defaults(&mut self, other: &ModelOptionsInput) {
         update_if_none(&mut self.model, &other.model);
@@ -303,14 +684,25 @@ impl ModelOptionsInput {
         update_if_none(&mut self.presence_penalty, &other.presence_penalty);
         update_if_none(&mut self.stop, &other.stop);
         update_if_none(&mut self.max_tokens, &other.max_tokens);
+        update_if_none(&mut self.seed, &other.seed);
+        update_if_none(&mut self.user, &other.user);
+        update_if_none(&mut self.tokenizer, &other.tokenizer);
+        update_if_none(&mut self.timeout, &other.timeout);
+        update_if_none(&mut self.connect_timeout, &other.connect_timeout);
 
         self.context.merge_defaults(&other.context);
+        self.retry.merge_defaults(&other.retry);
+        self.prompt_cache.merge_defaults(&other.prompt_cache);
 
         for (key, value) in &other.alias {
             if !self.alias.contains_key(key) {
                 self.alias.insert(key.clone(), value.clone());
             }
         }
+
+        for (token, bias) in &other.logit_bias {
+            self.logit_bias.entry(token.clone()).or_insert(*bias);
+        }
     }
 }
 
@@ -328,6 +720,31 @@ pub enum ModelError {
     FormatPrompt,
     #[error("Host does not support images")]
     HostDoesNotSupportImages,
+    #[error("Host does not support tools")]
+    HostDoesNotSupportTools,
+    #[error("Host does not support moderation")]
+    HostDoesNotSupportModeration,
+    #[error("Failed running a tool")]
+    Tool,
+    #[error("Model did not finish after {0} rounds of tool calls")]
+    TooManyToolCalls(usize),
+    #[error("Prompt is too long for the model: {message}")]
+    ContextLengthExceeded {
+        /// The context size reported by the host, if it included one
+        limit: Option<usize>,
+        message: String,
+    },
+}
+
+impl ModelError {
+    /// Whether it makes sense to retry the request with the next model in the fallback chain.
+    pub fn should_fallback(&self) -> bool {
+        match self {
+            ModelError::Raw | ModelError::ModelNotFound(_) => true,
+            ModelError::Model(code, _) => matches!(code, 404 | 408 | 429 | 500..=599),
+            _ => false,
+        }
+    }
 }
 
 pub fn map_model_response_err(err: ureq::Error) -> Report<ModelError> {
@@ -335,14 +752,46 @@ pub fn map_model_response_err(err: ureq::Error) -> Report<ModelError> {
         err @ ureq::Error::Transport(_) => Report::new(err).change_context(ModelError::Raw),
         ureq::Error::Status(code, response) => {
             let message = response.into_string().unwrap();
-            Report::new(ModelError::Model(code, message))
+            Report::new(status_error(code, message))
         }
     }
 }
 
+/// Classify an error response from a host, detecting prompts which were too long for the model.
+fn status_error(code: u16, message: String) -> ModelError {
+    let lower = message.to_lowercase();
+    let is_context_error = matches!(code, 400 | 413 | 422)
+        && [
+            "context_length_exceeded",
+            "maximum context length",
+            "context window",
+            "prompt is too long",
+            "too many tokens",
+        ]
+        .iter()
+        .any(|pattern| lower.contains(pattern));
+
+    if !is_context_error {
+        return ModelError::Model(code, message);
+    }
+
+    // OpenAI: "This model's maximum context length is 8192 tokens..."
+    // Anthropic: "prompt is too long: 210000 tokens > 200000 maximum"
+    let limit_regex =
+        regex::Regex::new(r"maximum context length is (\d+)|> (\d+) maximum").unwrap();
+    let limit = limit_regex.captures(&lower).and_then(|c| {
+        c.get(1)
+            .or_else(|| c.get(2))
+            .and_then(|m| m.as_str().parse().ok())
+    });
+
+    ModelError::ContextLengthExceeded { limit, message }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::hosts::HostDefinitionInput;
 
     mod host {
         use super::*;
@@ -373,6 +822,19 @@ mod test {
             assert!(matches!(err, Error::UnknownModelHost(_)));
         }
 
+        #[test]
+        fn claude_models() {
+            let mut options = ModelOptions {
+                model: ModelSpec::Plain("claude-3-5-sonnet".to_string()),
+                ..Default::default()
+            };
+            assert_eq!(options.host_name(), "anthropic");
+
+            // A configured default host may be a proxy that also serves Claude.
+            options.default_host = "openrouter".to_string();
+            assert_eq!(options.host_name(), "openrouter");
+        }
+
         #[test]
         fn other_default_host() {
             let options = ModelOptions {
@@ -388,6 +850,147 @@ mod test {
         }
     }
 
+    mod agents {
+        use super::*;
+
+        #[test]
+        fn reuse_agent() {
+            let agents = AgentCache::default();
+            let created = std::cell::Cell::new(0);
+            let create = || {
+                created.set(created.get() + 1);
+                Ok(ureq::agent())
+            };
+
+            agents
+                .get("ollama", "http://a", None, None, create)
+                .unwrap();
+            agents
+                .get("ollama", "http://a", None, None, create)
+                .unwrap();
+            // Clones of the options share the agents.
+            agents
+                .clone()
+                .get("ollama", "http://a", None, None, create)
+                .unwrap();
+            assert_eq!(created.get(), 1);
+
+            agents
+                .get("ollama", "http://b", None, None, create)
+                .unwrap();
+            agents
+                .get(
+                    "ollama",
+                    "http://a",
+                    Some(Duration::from_secs(5)),
+                    None,
+                    create,
+                )
+                .unwrap();
+            assert_eq!(created.get(), 3);
+        }
+
+        #[test]
+        fn pool_settings() {
+            let input: HostDefinitionInput = toml::from_str(
+                r##"
+                endpoint = "http://localhost:8080"
+                protocol = "openai"
+                max_idle_connections = 10
+                max_idle_connections_per_host = 2
+                "##,
+            )
+            .unwrap();
+
+            let host = HostDefinition::try_from(input).unwrap();
+            assert_eq!(host.max_idle_connections, Some(10));
+            assert_eq!(host.max_idle_connections_per_host, Some(2));
+        }
+    }
+
+    mod timeout {
+        use super::*;
+
+        #[test]
+        fn args_override_config() {
+            let input: ModelOptionsInput = toml::from_str(
+                r##"
+                timeout = 60
+                connect_timeout = 5
+                "##,
+            )
+            .unwrap();
+
+            let mut options = ModelOptions::new(input, HostDefinition::builtin(), "ollama".into());
+            assert_eq!(options.timeout, Some(Duration::from_secs(60)));
+            assert_eq!(options.connect_timeout, Some(Duration::from_secs(5)));
+
+            options.update_from_args(&GlobalRunArgs {
+                timeout: Some(10),
+                ..Default::default()
+            });
+            assert_eq!(options.timeout, Some(Duration::from_secs(10)));
+            assert_eq!(options.connect_timeout, Some(Duration::from_secs(5)));
+        }
+    }
+
+    mod sampling {
+        use super::*;
+
+        #[test]
+        fn args_override_template() {
+            let input: ModelOptionsInput = toml::from_str(
+                r##"
+                top_p = 0.5
+                top_k = 40
+                max_tokens = 100
+                stop = ["END"]
+                "##,
+            )
+            .unwrap();
+
+            let mut options = ModelOptions::new(input, HostDefinition::builtin(), "ollama".into());
+            options.update_from_args(&GlobalRunArgs {
+                top_p: Some(0.9),
+                max_tokens: Some(500),
+                stop: vec!["###".to_string(), "\n\n".to_string()],
+                ..Default::default()
+            });
+
+            assert_eq!(options.top_p, Some(0.9));
+            assert_eq!(options.top_k, Some(40));
+            assert_eq!(options.max_tokens, Some(500));
+            assert_eq!(options.stop, vec!["###".to_string(), "\n\n".to_string()]);
+
+            options.update_from_args(&GlobalRunArgs::default());
+            assert_eq!(options.stop.len(), 2);
+        }
+    }
+
+    mod prompt_cache {
+        use super::*;
+
+        #[test]
+        fn no_prompt_cache_arg() {
+            let input: ModelOptionsInput = toml::from_str(
+                r##"
+                prompt_cache = { system = true }
+                "##,
+            )
+            .unwrap();
+
+            let mut options = ModelOptions::new(input, HostDefinition::builtin(), "ollama".into());
+            assert!(options.prompt_cache.system);
+            assert!(!options.prompt_cache.prompt);
+
+            options.update_from_args(&GlobalRunArgs {
+                no_prompt_cache: true,
+                ..Default::default()
+            });
+            assert_eq!(options.prompt_cache, PromptCacheOptions::default());
+        }
+    }
+
     mod context_length {
         use super::*;
 
@@ -415,6 +1018,20 @@ mod test {
             assert_eq!(options.context_limit().unwrap(), Some(16385 - 5));
         }
 
+        #[test]
+        fn learned_limit() {
+            let mut options = create_options(None, 5);
+            options
+                .learned_context_limits
+                .insert("gpt-3.5-turbo-16k".to_string(), 1000);
+            assert_eq!(options.context_limit().unwrap(), Some(995));
+
+            options
+                .learned_context_limits
+                .insert("gpt-3.5-turbo-16k".to_string(), 100000);
+            assert_eq!(options.context_limit().unwrap(), Some(16385 - 5));
+        }
+
         #[test]
         fn not_enough_reserved_output() {
             let options = create_options(Some(20), 20);
@@ -423,6 +1040,119 @@ mod test {
         }
     }
 
+    mod model_chain {
+        use super::*;
+
+        #[test]
+        fn parse_chain() {
+            let input: ModelOptionsInput = toml::from_str(
+                r##"
+                temperature = 0.5
+                model = [
+                    { model = "gpt-4o", temperature = 0.2 },
+                    { model = "llama3.1", host = "ollama" },
+                    "mistral"
+                ]
+                "##,
+            )
+            .unwrap();
+
+            let options = ModelOptions::new(input, HostDefinition::builtin(), "ollama".into());
+            let chain = options.model_chain();
+            assert_eq!(chain.len(), 3);
+
+            assert_eq!(chain[0].model, ModelSpec::Plain("gpt-4o".to_string()));
+            assert_eq!(chain[0].temperature, 0.2);
+
+            assert_eq!(
+                chain[1].model,
+                ModelSpec::Full {
+                    model: "llama3.1".to_string(),
+                    host: Some("ollama".to_string())
+                }
+            );
+            assert_eq!(
+                chain[1].temperature, 0.5,
+                "Overrides from other entries should not apply"
+            );
+
+            assert_eq!(chain[2].model, ModelSpec::Plain("mistral".to_string()));
+            assert!(chain.iter().all(|o| o.fallback.is_empty()));
+        }
+
+        #[test]
+        fn single_model_replaces_chain() {
+            let mut options = ModelOptions::default();
+            options.set_model_choice(ModelChoice::Chain(vec![
+                ModelChainEntry {
+                    model: ModelSpec::Plain("a".to_string()),
+                    overrides: ModelOverrides::default(),
+                },
+                ModelChainEntry {
+                    model: ModelSpec::Plain("b".to_string()),
+                    overrides: ModelOverrides::default(),
+                },
+            ]));
+            assert_eq!(options.model_chain().len(), 2);
+
+            options.update_from_args(&GlobalRunArgs {
+                model: Some("c".to_string()),
+                ..Default::default()
+            });
+
+            let chain = options.model_chain();
+            assert_eq!(chain.len(), 1);
+            assert_eq!(chain[0].model.model_name(), "c");
+        }
+
+        #[test]
+        fn context_length_errors() {
+            let err = status_error(
+                400,
+                r#"{"error":{"message":"This model's maximum context length is 8192 tokens. However, your messages resulted in 9120 tokens.","code":"context_length_exceeded"}}"#.to_string(),
+            );
+            assert!(matches!(
+                err,
+                ModelError::ContextLengthExceeded {
+                    limit: Some(8192),
+                    ..
+                }
+            ));
+
+            let err = status_error(
+                400,
+                "prompt is too long: 210000 tokens > 200000 maximum".to_string(),
+            );
+            assert!(matches!(
+                err,
+                ModelError::ContextLengthExceeded {
+                    limit: Some(200000),
+                    ..
+                }
+            ));
+
+            let err = status_error(413, "Too many tokens in request".to_string());
+            assert!(matches!(
+                err,
+                ModelError::ContextLengthExceeded { limit: None, .. }
+            ));
+
+            let err = status_error(500, "maximum context length is 8192".to_string());
+            assert!(matches!(err, ModelError::Model(500, _)));
+            let err = status_error(400, "Invalid temperature".to_string());
+            assert!(matches!(err, ModelError::Model(400, _)));
+        }
+
+        #[test]
+        fn should_fallback() {
+            assert!(ModelError::Model(429, String::new()).should_fallback());
+            assert!(ModelError::Model(503, String::new()).should_fallback());
+            assert!(!ModelError::Model(400, String::new()).should_fallback());
+            assert!(ModelError::ModelNotFound("a".to_string()).should_fallback());
+            assert!(!ModelError::Deserialize.should_fallback());
+        }
+    }
+
     mod model_spec {
         use crate::model::ModelSpec;
 
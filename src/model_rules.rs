@@ -0,0 +1,146 @@
+//! Choose the model based on the values of a template's options.
+
+use error_stack::{Report, ResultExt};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tera::Tera;
+
+use crate::{
+    error::Error,
+    model::{ModelOptions, ModelOptionsInput},
+};
+
+/// Model options to use when the template's options match a condition
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct ModelRule {
+    /// A Tera expression using the template's options, such as `language == "rust"`.
+    pub when: String,
+    /// The model options to use when the condition is true. These take the place of the
+    /// template's own model options, but options from the command line still take precedence.
+    #[serde(flatten)]
+    pub options: ModelOptionsInput,
+}
+
+impl ModelRule {
+    /// Evaluate the condition with the template's arguments.
+    pub fn matches(&self, context: &tera::Context) -> Result<bool, Report<Error>> {
+        let template = format!("{{% if {} %}}true{{% endif %}}", self.when);
+        let result = Tera::one_off(&template, context, false)
+            .change_context(Error::ParseTemplate)
+            .attach_printable_lazy(|| format!("Evaluating the model rule `{}`", self.when))?;
+        Ok(result == "true")
+    }
+}
+
+/// Apply the options from the first rule whose condition matches the template's arguments.
+/// `option_names` are the template's options, which are `null` in the conditions when they weren't
+/// given. Returns the rule that was applied, if any.
+pub fn apply_model_rules<'a, 'b>(
+    rules: &'a [ModelRule],
+    option_names: impl IntoIterator<Item = &'b String>,
+    args: &serde_json::Value,
+    model_options: &mut ModelOptions,
+) -> Result<Option<&'a ModelRule>, Report<Error>> {
+    if rules.is_empty() {
+        return Ok(None);
+    }
+
+    let mut context =
+        tera::Context::from_value(args.clone()).change_context(Error::PreparePrompt)?;
+    for name in option_names {
+        if !context.contains_key(name) {
+            context.insert(name, &serde_json::Value::Null);
+        }
+    }
+    for rule in rules {
+        if rule.matches(&context)? {
+            model_options.update_from_model_input(&rule.options);
+            return Ok(Some(rule));
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+    use crate::model::ModelSpec;
+
+    #[derive(Deserialize)]
+    struct Rules {
+        model_rules: Vec<ModelRule>,
+    }
+
+    fn rules() -> Vec<ModelRule> {
+        toml::from_str::<Rules>(
+            r##"
+            [[model_rules]]
+            when = 'language == "rust"'
+            model = "codellama"
+            temperature = 0.2
+
+            [[model_rules]]
+            when = 'language in ["python", "ruby"] or detailed'
+            model = { model = "gpt-4o", host = "openai" }
+            "##,
+        )
+        .unwrap()
+        .model_rules
+    }
+
+    fn apply(args: serde_json::Value) -> ModelOptions {
+        let mut options = ModelOptions {
+            model: ModelSpec::Plain("llama3.1".to_string()),
+            ..Default::default()
+        };
+        let names = ["language".to_string(), "detailed".to_string()];
+        apply_model_rules(&rules(), &names, &args, &mut options).unwrap();
+        options
+    }
+
+    #[test]
+    fn first_match() {
+        let options = apply(json!({ "language": "rust", "detailed": true }));
+        assert_eq!(options.model, ModelSpec::Plain("codellama".to_string()));
+        assert_eq!(options.temperature, 0.2);
+
+        let options = apply(json!({ "language": "ruby", "detailed": false }));
+        assert_eq!(
+            options.model,
+            ModelSpec::Full {
+                model: "gpt-4o".to_string(),
+                host: Some("openai".to_string())
+            }
+        );
+        assert_eq!(options.temperature, 0.0);
+    }
+
+    #[test]
+    fn no_match() {
+        let options = apply(json!({ "language": "go", "detailed": false }));
+        assert_eq!(options.model, ModelSpec::Plain("llama3.1".to_string()));
+
+        let options = apply(json!({ "detailed": false }));
+        assert_eq!(options.model, ModelSpec::Plain("llama3.1".to_string()));
+    }
+
+    #[test]
+    fn invalid_condition() {
+        let rule = ModelRule {
+            when: "language ==".to_string(),
+            options: ModelOptionsInput::default(),
+        };
+        let err = apply_model_rules(
+            &[rule],
+            &[],
+            &json!({ "language": "rust" }),
+            &mut ModelOptions::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(err.current_context(), Error::ParseTemplate));
+    }
+}
@@ -0,0 +1,359 @@
+//! Check prompts and responses against a content policy, using OpenAI's moderation endpoint or a
+//! local classifier, before templates are shared with people who might misuse them.
+
+use std::{
+    fmt::Display,
+    io::{Read, Write},
+    process::Stdio,
+};
+
+use error_stack::{Report, ResultExt};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    model::ModelOptions,
+    option::{overwrite_from_option, update_if_none},
+    tools::shell_command,
+};
+
+const DEFAULT_MODEL: &str = "omni-moderation-latest";
+
+/// What to do when moderation flags the prompt or response
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    /// Stop the run with an error, without sending the prompt or writing the response.
+    #[default]
+    Block,
+    /// Print a warning and continue.
+    Flag,
+    /// Continue without a warning. The result is only recorded in the history.
+    Annotate,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, JsonSchema)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct ModerationInput {
+    /// Check the prompt and system prompt before they are sent.
+    pub input: Option<bool>,
+    /// Check the response before it is written. This waits for the whole response instead of
+    /// streaming it.
+    pub output: Option<bool>,
+    /// What to do with flagged content: "block" (the default), "flag", or "annotate"
+    pub action: Option<ModerationAction>,
+    /// The model for OpenAI's moderation endpoint. Defaults to `omni-moderation-latest`.
+    pub model: Option<String>,
+    /// A shell command to use as the classifier instead of OpenAI. It reads the text on stdin and
+    /// prints JSON such as `{"flagged": true, "categories": ["violence"]}`.
+    pub command: Option<String>,
+}
+
+impl ModerationInput {
+    /// For any members that are `None` in this `ModerationInput`, use the value from `other`
+    pub fn merge_defaults(&mut self, other: &ModerationInput) {
+        update_if_none(&mut self.input, &other.input);
+        update_if_none(&mut self.output, &other.output);
+        update_if_none(&mut self.action, &other.action);
+        update_if_none(&mut self.model, &other.model);
+        update_if_none(&mut self.command, &other.command);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Classifier {
+    OpenAi { model: String },
+    Command(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModerationPolicy {
+    pub input: bool,
+    pub output: bool,
+    pub action: ModerationAction,
+    pub classifier: Classifier,
+}
+
+impl Default for ModerationPolicy {
+    fn default() -> Self {
+        Self {
+            input: false,
+            output: false,
+            action: ModerationAction::default(),
+            classifier: Classifier::OpenAi {
+                model: DEFAULT_MODEL.to_string(),
+            },
+        }
+    }
+}
+
+impl From<ModerationInput> for ModerationPolicy {
+    fn from(value: ModerationInput) -> Self {
+        let mut policy = Self::default();
+        overwrite_from_option(&mut policy.input, &value.input);
+        overwrite_from_option(&mut policy.output, &value.output);
+        overwrite_from_option(&mut policy.action, &value.action);
+        policy.classifier = match value.command {
+            Some(command) => Classifier::Command(command),
+            None => Classifier::OpenAi {
+                model: value.model.unwrap_or_else(|| DEFAULT_MODEL.to_string()),
+            },
+        };
+        policy
+    }
+}
+
+/// Which part of the run was checked
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationStage {
+    Input,
+    Output,
+}
+
+impl Display for ModerationStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Input => f.write_str("prompt"),
+            Self::Output => f.write_str("response"),
+        }
+    }
+}
+
+/// The result of checking part of a run, as saved in the history
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ModerationResult {
+    pub stage: ModerationStage,
+    pub flagged: bool,
+    /// The categories that the content was flagged for
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub categories: Vec<String>,
+}
+
+/// The output of a classifier command
+#[derive(Deserialize)]
+struct ClassifierOutput {
+    flagged: bool,
+    #[serde(default)]
+    categories: Vec<String>,
+}
+
+impl ModerationPolicy {
+    /// The policy for a template, which can add checks to the configuration's policy but not
+    /// weaken it. The template's action and classifier are only used when the configuration
+    /// doesn't turn on any checks, so that a template can't swap out the classifier that the
+    /// configuration relies on.
+    pub fn for_template(config: &ModerationInput, template: &ModerationInput) -> Self {
+        let config_checks = config.input == Some(true) || config.output == Some(true);
+        let mut merged = config.clone();
+        if !config_checks {
+            merged.merge_defaults(template);
+        }
+        merged.input = Some(config.input.unwrap_or(false) || template.input.unwrap_or(false));
+        merged.output = Some(config.output.unwrap_or(false) || template.output.unwrap_or(false));
+        Self::from(merged)
+    }
+
+    /// Check the text if the policy covers `stage`. Flagged content returns an error when the
+    /// action is to block it.
+    pub fn check(
+        &self,
+        stage: ModerationStage,
+        text: &str,
+        model_options: &ModelOptions,
+    ) -> Result<Option<ModerationResult>, Report<Error>> {
+        let enabled = match stage {
+            ModerationStage::Input => self.input,
+            ModerationStage::Output => self.output,
+        };
+        if !enabled {
+            return Ok(None);
+        }
+
+        let categories = match &self.classifier {
+            Classifier::OpenAi { model } => {
+                let host = model_options
+                    .host
+                    .get("openai")
+                    .ok_or_else(|| Error::UnknownModelHost("openai".to_string()))
+                    .and_then(|host| {
+                        host.into_model_host(
+                            "openai",
+                            &model_options.agents,
+                            model_options.timeout,
+                            model_options.connect_timeout,
+                        )
+                    })
+                    .change_context(Error::Moderation)?;
+                host.moderate(model_options, model, text)
+                    .change_context(Error::Moderation)?
+            }
+            Classifier::Command(command) => {
+                let output = run_classifier(command, text)?;
+                match (output.flagged, output.categories.is_empty()) {
+                    (true, true) => vec!["flagged".to_string()],
+                    (true, false) => output.categories,
+                    (false, _) => Vec::new(),
+                }
+            }
+        };
+
+        let result = ModerationResult {
+            stage,
+            flagged: !categories.is_empty(),
+            categories,
+        };
+        if result.flagged {
+            let categories = result.categories.join(", ");
+            match self.action {
+                ModerationAction::Block => {
+                    return Err(Report::new(Error::ContentBlocked(stage))
+                        .attach_printable(format!("Flagged for {categories}")));
+                }
+                ModerationAction::Flag => {
+                    eprintln!("Warning: Moderation flagged the {stage} for {categories}");
+                }
+                ModerationAction::Annotate => {}
+            }
+        }
+
+        Ok(Some(result))
+    }
+}
+
+fn run_classifier(command: &str, text: &str) -> Result<ClassifierOutput, Report<Error>> {
+    let mut child = shell_command(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .change_context(Error::Moderation)
+        .attach_printable_lazy(|| command.to_string())?;
+
+    // Write on another thread so that a classifier which writes before reading all of its input
+    // doesn't block on a full pipe.
+    let mut stdin = child.stdin.take();
+    let text = text.to_string();
+    let writer = std::thread::spawn(move || {
+        if let Some(stdin) = stdin.as_mut() {
+            stdin.write_all(text.as_bytes()).ok();
+        }
+    });
+
+    let mut stdout = String::new();
+    if let Some(mut pipe) = child.stdout.take() {
+        pipe.read_to_string(&mut stdout)
+            .change_context(Error::Moderation)?;
+    }
+    writer.join().ok();
+
+    let status = child.wait().change_context(Error::Moderation)?;
+    if !status.success() {
+        return Err(Report::new(Error::Moderation)
+            .attach_printable(format!("Classifier failed with {status}"))
+            .attach_printable(command.to_string()));
+    }
+
+    serde_json::from_str(&stdout)
+        .change_context(Error::Moderation)
+        .attach_printable_lazy(|| format!("Classifier output: {}", stdout.trim()))
+}
+
+#[cfg(all(test, not(windows)))]
+mod test {
+    use super::*;
+
+    fn policy(action: ModerationAction, output: &str) -> ModerationPolicy {
+        ModerationPolicy {
+            input: true,
+            output: false,
+            action,
+            classifier: Classifier::Command(format!("cat > /dev/null; echo '{output}'")),
+        }
+    }
+
+    #[test]
+    fn command_classifier() {
+        let options = ModelOptions::default();
+        let flagged = r#"{"flagged": true, "categories": ["violence"]}"#;
+
+        let err = policy(ModerationAction::Block, flagged)
+            .check(ModerationStage::Input, "text", &options)
+            .unwrap_err();
+        assert!(matches!(
+            err.current_context(),
+            Error::ContentBlocked(ModerationStage::Input)
+        ));
+
+        let result = policy(ModerationAction::Annotate, flagged)
+            .check(ModerationStage::Input, "text", &options)
+            .unwrap();
+        assert_eq!(
+            result,
+            Some(ModerationResult {
+                stage: ModerationStage::Input,
+                flagged: true,
+                categories: vec!["violence".to_string()],
+            })
+        );
+
+        let result = policy(ModerationAction::Block, r#"{"flagged": false}"#)
+            .check(ModerationStage::Input, "text", &options)
+            .unwrap();
+        assert_eq!(result.map(|r| r.flagged), Some(false));
+
+        // The response isn't checked unless the policy says so.
+        let result = policy(ModerationAction::Block, flagged)
+            .check(ModerationStage::Output, "text", &options)
+            .unwrap();
+        assert_eq!(result, None);
+    }
+
+    #[test]
+    fn template_policy() {
+        let config = ModerationInput {
+            input: Some(true),
+            command: Some("classify".to_string()),
+            ..Default::default()
+        };
+        let template = ModerationInput {
+            input: Some(false),
+            output: Some(true),
+            action: Some(ModerationAction::Annotate),
+            command: Some("echo '{\"flagged\": false}'".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            ModerationPolicy::for_template(&config, &template),
+            ModerationPolicy {
+                input: true,
+                output: true,
+                action: ModerationAction::Block,
+                classifier: Classifier::Command("classify".to_string()),
+            }
+        );
+
+        // Without checks in the configuration, the template sets up its own.
+        let config = ModerationInput {
+            action: Some(ModerationAction::Flag),
+            ..Default::default()
+        };
+        assert_eq!(
+            ModerationPolicy::for_template(&config, &template),
+            ModerationPolicy {
+                input: false,
+                output: true,
+                action: ModerationAction::Flag,
+                classifier: Classifier::Command("echo '{\"flagged\": false}'".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn bad_classifier_output() {
+        let err = policy(ModerationAction::Block, "not json")
+            .check(ModerationStage::Input, "text", &ModelOptions::default())
+            .unwrap_err();
+        assert!(matches!(err.current_context(), Error::Moderation));
+    }
+}
@@ -0,0 +1,283 @@
+//! Run several templates over the same arguments and input at once, such as a summary, action
+//! items, and a title for one document.
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    io::{IsTerminal, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use error_stack::{Report, ResultExt};
+
+use crate::{
+    config::Config,
+    error::Error,
+    generate_template_with_stdin,
+    history::Invocation,
+    interrupt, output, prepare_run, send_prompt,
+    template::{OptionType, PromptOption},
+};
+
+/// Collects the output of a template so that it can be printed with the others.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Remove the options that only other templates have from `args`, so that the arguments can
+/// include the options of every template. `foreign_options` maps the name of each of these options
+/// to whether it takes a value.
+fn args_for_template(args: &[OsString], foreign_options: &HashMap<&str, bool>) -> Vec<OsString> {
+    let mut output = Vec::with_capacity(args.len());
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let arg_str = arg.to_string_lossy();
+        if arg_str == "--" {
+            // Everything after this is extra prompt text.
+            output.push(arg.clone());
+            output.extend(args.cloned());
+            break;
+        }
+
+        // Whether this is an option to remove, and if so whether its value is the next argument
+        let foreign = arg_str
+            .strip_prefix("--")
+            .and_then(|option| match option.split_once('=') {
+                Some((name, _)) => foreign_options.get(name).map(|_| false),
+                None => foreign_options.get(option).copied(),
+            });
+        match foreign {
+            Some(true) => {
+                args.next();
+            }
+            Some(false) => {}
+            None => output.push(arg.clone()),
+        }
+    }
+
+    output
+}
+
+/// Format the outputs as a section for each template, labeled with the template name.
+pub fn format_sections(outputs: &[(String, String)]) -> String {
+    outputs
+        .iter()
+        .map(|(name, output)| {
+            let newline = if output.ends_with('\n') { "" } else { "\n" };
+            format!("== {name}\n{output}{newline}")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Format the outputs as a JSON object from template name to output.
+fn format_json(outputs: &[(String, String)]) -> String {
+    let map = outputs
+        .iter()
+        .map(|(name, output)| (name.clone(), output.trim_end().into()))
+        .collect::<serde_json::Map<_, _>>();
+    serde_json::to_string_pretty(&map).unwrap()
+}
+
+/// Run each template with the same `args` and stdin, and print the outputs together once they
+/// have all finished.
+pub fn run_templates(
+    config: Config,
+    base_dir: PathBuf,
+    program: OsString,
+    templates: &[String],
+    args: Vec<OsString>,
+    json: bool,
+) -> Result<(), Report<Error>> {
+    // Read stdin once, since every template needs it.
+    let stdin = if std::io::stdin().is_terminal() {
+        None
+    } else {
+        Some(
+            std::io::read_to_string(std::io::stdin())
+                .attach_printable("Reading stdin")
+                .change_context(Error::Io)?,
+        )
+        .filter(|s| !s.is_empty())
+    };
+
+    let parsed = templates
+        .iter()
+        .map(|name| {
+            config
+                .find_template(name)
+                .attach_printable_lazy(|| format!("Template {name}"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let takes_value =
+        |option: &PromptOption| option.array || option.option_type != OptionType::Bool;
+    let all_options = parsed
+        .iter()
+        .flat_map(|template| template.input.options.iter())
+        .map(|(name, option)| (name.as_str(), takes_value(option)))
+        .collect::<HashMap<_, _>>();
+
+    // Generate all the prompts before sending any of them, so that a problem with one template
+    // doesn't leave the others half done, and confirmations aren't asked at the same time.
+    let mut runs = Vec::with_capacity(templates.len());
+    for (name, template) in templates.iter().zip(&parsed) {
+        let mut foreign_options = all_options.clone();
+        foreign_options.retain(|option, _| !template.input.options.contains_key(*option));
+
+        let cmdline = [program.clone(), OsString::from("run"), OsString::from(name)]
+            .into_iter()
+            .chain(args_for_template(&args, &foreign_options))
+            .collect::<Vec<_>>();
+        let invocation_args = cmdline
+            .iter()
+            .skip(1)
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect();
+
+        let generated = generate_template_with_stdin(
+            config.clone(),
+            base_dir.clone(),
+            name.clone(),
+            cmdline,
+            stdin.clone(),
+        )
+        .attach_printable_lazy(|| format!("Template {name}"))?;
+        if !prepare_run(&generated)? {
+            continue;
+        }
+
+        let invocation = Invocation {
+            directory: base_dir.clone(),
+            args: invocation_args,
+            stdin: generated.args.stdin.clone(),
+        };
+        runs.push((name.clone(), generated, invocation));
+    }
+
+    interrupt::install_handler();
+    let handles = runs
+        .into_iter()
+        .map(|(name, generated, invocation)| {
+            let buffer = SharedBuffer::default();
+            let output = buffer.clone();
+            let handle = std::thread::spawn(move || send_prompt(generated, invocation, output));
+            (name, buffer, handle)
+        })
+        .collect::<Vec<_>>();
+
+    let mut outputs = Vec::with_capacity(handles.len());
+    let mut failed = 0;
+    for (name, buffer, handle) in handles {
+        match handle.join().unwrap() {
+            Ok(()) => outputs.push((name, buffer.contents())),
+            Err(e) if matches!(e.current_context(), Error::Interrupted) => {
+                outputs.push((name, buffer.contents()))
+            }
+            Err(e) => {
+                eprintln!("Template {name} failed: {e:?}");
+                failed += 1;
+            }
+        }
+    }
+
+    let formatted = if json {
+        format!("{}\n", format_json(&outputs))
+    } else {
+        format_sections(&outputs)
+    };
+    let mut stdout = std::io::stdout().lock();
+    let written = stdout
+        .write_all(formatted.as_bytes())
+        .and_then(|_| stdout.flush())
+        .change_context(Error::WriteOutput);
+    match written {
+        Ok(()) => {}
+        // Whatever was reading the output has all that it wants.
+        Err(e) if output::is_broken_pipe(&e) => {}
+        Err(e) => return Err(e),
+    }
+
+    if interrupt::interrupted() {
+        Err(Report::new(Error::Interrupted))
+    } else if failed > 0 {
+        Err(Report::new(Error::MultiRunFailed(failed)))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn outputs() -> Vec<(String, String)> {
+        vec![
+            ("summarize".to_string(), "A summary\n".to_string()),
+            ("title".to_string(), "A title".to_string()),
+        ]
+    }
+
+    #[test]
+    fn template_args() {
+        let foreign_options = HashMap::from([("name", true), ("loud", false)]);
+        let args = [
+            "--topic",
+            "fruit",
+            "--name",
+            "Bob",
+            "--loud",
+            "--name=Al",
+            "extra",
+            "--",
+            "--name",
+        ]
+        .map(OsString::from);
+
+        assert_eq!(
+            args_for_template(&args, &foreign_options),
+            ["--topic", "fruit", "extra", "--", "--name"].map(OsString::from)
+        );
+    }
+
+    #[test]
+    fn sections() {
+        assert_eq!(
+            format_sections(&outputs()),
+            "== summarize\nA summary\n\n== title\nA title\n"
+        );
+    }
+
+    #[test]
+    fn json() {
+        let value: serde_json::Value = serde_json::from_str(&format_json(&outputs())).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({ "summarize": "A summary", "title": "A title" })
+        );
+    }
+
+    #[test]
+    fn shared_buffer() {
+        let buffer = SharedBuffer::default();
+        let mut output = buffer.clone();
+        write!(output, "some ").unwrap();
+        write!(output, "text").unwrap();
+        assert_eq!(buffer.contents(), "some text");
+    }
+}
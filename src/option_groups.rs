@@ -0,0 +1,189 @@
+//! Option groups bundle related options into one object in the template context, such as
+//! `reviewer.name` and `reviewer.focus`, so that large templates don't need a long flat list of
+//! option names.
+//!
+//! The options in a group are added to the template's options under names like `reviewer.name`,
+//! so the command line, help text, and everything else which lists options sees them like any
+//! other option. The values are only nested into an object once the arguments are parsed.
+
+use std::collections::HashMap;
+
+use error_stack::Report;
+
+use crate::{error::Error, template::PromptOption};
+
+/// Add the options from each group to `options`, named `group.option`.
+pub fn flatten_option_groups(
+    groups: HashMap<String, HashMap<String, PromptOption>>,
+    options: &mut HashMap<String, PromptOption>,
+) -> Result<(), Report<Error>> {
+    for (group, group_options) in groups {
+        if options.contains_key(&group) {
+            return Err(Report::new(Error::ParseTemplate).attach_printable(format!(
+                "Option group `{group}` has the same name as an option"
+            )));
+        }
+
+        for (name, option) in group_options {
+            options.insert(format!("{group}.{name}"), option);
+        }
+    }
+
+    Ok(())
+}
+
+/// Move the values of grouped options, such as `reviewer.name`, into an object for each group.
+pub fn nest_option_groups(context: &mut serde_json::Value) {
+    let Some(values) = context.as_object_mut() else {
+        return;
+    };
+
+    let grouped = values
+        .keys()
+        .filter(|key| key.contains('.'))
+        .cloned()
+        .collect::<Vec<_>>();
+    for key in grouped {
+        let Some((group, name)) = key.split_once('.') else {
+            continue;
+        };
+        let value = values.remove(&key).unwrap_or_default();
+        let group = values
+            .entry(group)
+            .or_insert_with(|| serde_json::Value::Object(Default::default()));
+        group[name] = value;
+    }
+}
+
+/// A template context, either before or after it becomes a [tera::Context].
+pub trait TemplateValues {
+    fn value(&self, key: &str) -> Option<&serde_json::Value>;
+}
+
+impl TemplateValues for tera::Context {
+    fn value(&self, key: &str) -> Option<&serde_json::Value> {
+        self.get(key)
+    }
+}
+
+impl TemplateValues for serde_json::Value {
+    fn value(&self, key: &str) -> Option<&serde_json::Value> {
+        self.get(key)
+    }
+}
+
+/// Get the value of an option, looking inside its group for grouped options.
+pub fn option_value<'a>(
+    context: &'a impl TemplateValues,
+    name: &str,
+) -> Option<&'a serde_json::Value> {
+    match name.split_once('.') {
+        Some((group, name)) => context.value(group)?.get(name),
+        None => context.value(name),
+    }
+}
+
+/// Like [option_value], but for changing the value in a context that is still JSON.
+pub fn option_value_mut<'a>(
+    context: &'a mut serde_json::Value,
+    name: &str,
+) -> Option<&'a mut serde_json::Value> {
+    match name.split_once('.') {
+        Some((group, name)) => context.get_mut(group)?.get_mut(name),
+        None => context.get_mut(name),
+    }
+}
+
+/// Call `update` with the value of an option, if it's in the context.
+pub fn update_option_value<T>(
+    context: &mut tera::Context,
+    name: &str,
+    update: impl FnOnce(&mut serde_json::Value) -> T,
+) -> Option<T> {
+    let (key, field) = match name.split_once('.') {
+        Some((group, field)) => (group, Some(field)),
+        None => (name, None),
+    };
+
+    let mut value = context.remove(key)?;
+    let result = match field {
+        Some(field) => value.get_mut(field).map(update),
+        None => Some(update(&mut value)),
+    };
+    context.insert(key, &value);
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn nest() {
+        let mut context = json!({
+            "topic": "tests",
+            "reviewer.name": "Sam",
+            "reviewer.focus": ["naming", "errors"],
+            "author.name": null,
+        });
+        nest_option_groups(&mut context);
+        assert_eq!(
+            context,
+            json!({
+                "topic": "tests",
+                "reviewer": { "name": "Sam", "focus": ["naming", "errors"] },
+                "author": { "name": null },
+            })
+        );
+    }
+
+    #[test]
+    fn group_conflicts_with_option() {
+        let option = || toml::from_str::<PromptOption>("").unwrap();
+        let mut options = HashMap::from([("reviewer".to_string(), option())]);
+        let groups = HashMap::from([(
+            "reviewer".to_string(),
+            HashMap::from([("name".to_string(), option())]),
+        )]);
+        assert!(flatten_option_groups(groups, &mut options).is_err());
+    }
+
+    #[test]
+    fn json_values() {
+        let mut context = json!({ "topic": "tests", "reviewer": { "name": "Sam" } });
+        assert_eq!(option_value(&context, "topic"), Some(&json!("tests")));
+        assert_eq!(option_value(&context, "reviewer.name"), Some(&json!("Sam")));
+
+        *option_value_mut(&mut context, "reviewer.name").unwrap() = json!("Alex");
+        assert_eq!(context["reviewer"]["name"], "Alex");
+        assert_eq!(option_value_mut(&mut context, "author.name"), None);
+    }
+
+    #[test]
+    fn update_grouped_value() {
+        let mut context = tera::Context::from_value(json!({
+            "topic": "tests",
+            "reviewer": { "name": "Sam" },
+        }))
+        .unwrap();
+
+        assert_eq!(option_value(&context, "reviewer.name"), Some(&json!("Sam")));
+        assert_eq!(option_value(&context, "reviewer.focus"), None);
+
+        update_option_value(&mut context, "reviewer.name", |value| {
+            *value = json!("Alex");
+        });
+        update_option_value(&mut context, "topic", |value| {
+            *value = json!("more tests");
+        });
+        assert_eq!(
+            context.into_json(),
+            json!({
+                "topic": "more tests",
+                "reviewer": { "name": "Alex" },
+            })
+        );
+    }
+}
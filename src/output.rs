@@ -0,0 +1,754 @@
+use std::{
+    io::{ErrorKind, IsTerminal, Write},
+    path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::ValueEnum;
+use error_stack::{Report, ResultExt};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    args::GlobalRunArgs,
+    error::Error,
+    option::overwrite_from_option,
+    postprocess::PostProcessStep,
+    render::{MarkdownRenderer, RenderMode},
+    stream_filter::{StreamFilterRule, StreamFilters},
+};
+
+/// Output settings from a template's `output` section.
+#[derive(Deserialize, Debug, Default, Clone, JsonSchema)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct OutputOptionsInput {
+    /// Write a newline after the response. Defaults to true.
+    pub trailing_newline: Option<bool>,
+    /// Treat the response as base64 and write the decoded bytes instead of the text.
+    pub raw_bytes: Option<bool>,
+    /// Save images in the response to this directory, and replace them with the paths to the
+    /// saved files.
+    pub image_dir: Option<PathBuf>,
+    /// A template for the names of saved images. It can use `template`, `index`, `ext`, and
+    /// `timestamp`. Defaults to `{{template}}-{{timestamp}}-{{index}}.{{ext}}`.
+    pub image_filename: Option<String>,
+    /// Steps that change the response before it is written, applied in order: "trim",
+    /// "strip_markdown", `{ regex = "..." }`, or `{ jq = "..." }`.
+    #[serde(default)]
+    pub post_process: Vec<PostProcessStep>,
+    /// Regular expression replacements applied to each line of the text output as it streams,
+    /// such as `{ pattern = "[“”]", replace = '"' }`.
+    #[serde(default)]
+    pub stream_filters: Vec<StreamFilterRule>,
+}
+
+/// How the result of a run is written to stdout
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ResultFormat {
+    /// Just the response
+    #[default]
+    Text,
+    /// A JSON object with the response, the model, the token counts, and the duration
+    Json,
+    /// A JSON line for each chunk of the response as it arrives, and then the same object as `json`
+    Jsonl,
+}
+
+#[derive(Debug, Clone)]
+pub struct OutputOptions {
+    pub format: ResultFormat,
+    pub trailing_newline: bool,
+    pub raw_bytes: bool,
+    /// Style the response as Markdown for the terminal
+    pub render_markdown: bool,
+    /// Replacements made in the text output as it is written
+    pub stream_filters: StreamFilters,
+    /// Where to save images from the response. If not set, images are written as they are received.
+    pub image_dir: Option<PathBuf>,
+    pub image_filename: String,
+    /// The name of the template being run, for use in image file names
+    pub template_name: String,
+}
+
+const DEFAULT_IMAGE_FILENAME: &str = "{{template}}-{{timestamp}}-{{index}}.{{ext}}";
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        Self {
+            format: ResultFormat::Text,
+            trailing_newline: true,
+            raw_bytes: false,
+            render_markdown: false,
+            stream_filters: StreamFilters::default(),
+            image_dir: None,
+            image_filename: DEFAULT_IMAGE_FILENAME.to_string(),
+            template_name: String::new(),
+        }
+    }
+}
+
+impl OutputOptions {
+    pub fn new(input: &OutputOptionsInput, args: &GlobalRunArgs, template_name: &str) -> Self {
+        let mut options = Self {
+            format: args.output_format,
+            template_name: template_name.to_string(),
+            ..Default::default()
+        };
+        overwrite_from_option(&mut options.trailing_newline, &input.trailing_newline);
+        overwrite_from_option(&mut options.raw_bytes, &input.raw_bytes);
+        overwrite_from_option(&mut options.image_filename, &input.image_filename);
+        options.image_dir = args.image_dir.clone().or_else(|| input.image_dir.clone());
+
+        if args.no_trailing_newline {
+            options.trailing_newline = false;
+        }
+
+        if args.raw_bytes {
+            options.raw_bytes = true;
+        }
+
+        options.render_markdown = match args.render {
+            RenderMode::Always => true,
+            RenderMode::Never => false,
+            RenderMode::Auto => {
+                args.output.is_none()
+                    && std::env::var_os("NO_COLOR").is_none()
+                    && std::io::stdout().is_terminal()
+            }
+        };
+
+        options
+    }
+}
+
+/// The result of a run, written as JSON when the result format isn't `text`
+#[derive(Serialize, Debug)]
+pub struct RunResult<'a> {
+    pub response: &'a str,
+    pub model: &'a str,
+    /// The token counts are `None` for cached responses, which didn't use any tokens.
+    pub prompt_tokens: Option<usize>,
+    pub completion_tokens: Option<usize>,
+    pub duration_ms: u64,
+}
+
+/// The result of a run with several responses, written as JSON when the result format isn't `text`
+#[derive(Serialize, Debug)]
+pub struct CompletionsResult<'a> {
+    /// The response chosen by `--judge`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<&'a str>,
+    /// The number of the chosen response, counting from 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chosen: Option<usize>,
+    pub responses: &'a [String],
+    pub model: &'a str,
+    pub prompt_tokens: Option<usize>,
+    pub completion_tokens: Option<usize>,
+    pub duration_ms: u64,
+}
+
+/// Write the result of a run as a line of JSON. Text results are written by [write_output]
+/// instead, so this does nothing for them.
+pub fn write_result(
+    options: &OutputOptions,
+    result: &impl Serialize,
+    output: &mut impl Write,
+) -> Result<(), std::io::Error> {
+    if options.format == ResultFormat::Text {
+        return Ok(());
+    }
+
+    serde_json::to_writer(&mut *output, result)?;
+    writeln!(output)?;
+    output.flush()
+}
+
+/// Write a JSON line for each message as it arrives, or nothing for `json`, which is written all
+/// at once by [write_result]. Raw bytes can't be stored in a JSON string, so the response is left
+/// as base64. The chunks go through the stream filters, but the returned response does not.
+fn write_json_chunks(
+    options: &OutputOptions,
+    messages: impl IntoIterator<Item = String>,
+    output: &mut impl Write,
+) -> Result<String, std::io::Error> {
+    let mut write_chunk = |chunk: &str| -> Result<(), std::io::Error> {
+        if options.format == ResultFormat::Jsonl {
+            serde_json::to_writer(&mut *output, &serde_json::json!({ "chunk": chunk }))?;
+            writeln!(output)?;
+            output.flush()?;
+        }
+        Ok(())
+    };
+
+    if let Some(image_dir) = options.image_dir.as_deref() {
+        // Images can't be found until the whole data URL has arrived, so wait for the whole thing.
+        let response = messages.into_iter().collect::<String>();
+        let response = save_images(options, image_dir, &response)?;
+        write_chunk(&options.stream_filters.apply(&response))?;
+        return Ok(response);
+    }
+
+    let mut filter = (!options.stream_filters.is_empty()).then(|| options.stream_filters.stream());
+    let mut response = String::new();
+    for message in messages {
+        match filter.as_mut() {
+            // A filter holds back the text until the end of its line.
+            Some(filter) => {
+                let text = filter.push(&message);
+                if !text.is_empty() {
+                    write_chunk(&text)?;
+                }
+            }
+            None => write_chunk(&message)?,
+        }
+        response.push_str(&message);
+    }
+    if let Some(rest) = filter.as_mut().map(|f| f.finish()) {
+        if !rest.is_empty() {
+            write_chunk(&rest)?;
+        }
+    }
+
+    Ok(response)
+}
+
+/// Write the messages from the model to `output` as they arrive. Returns the full response.
+pub fn write_output(
+    options: &OutputOptions,
+    messages: impl IntoIterator<Item = String>,
+    output: &mut impl Write,
+) -> Result<String, std::io::Error> {
+    if options.format != ResultFormat::Text {
+        return write_json_chunks(options, messages, output);
+    }
+
+    if options.raw_bytes {
+        // Base64 can't be decoded reliably in arbitrary chunks, so wait for the whole thing.
+        let response = messages.into_iter().collect::<String>();
+        let bytes = decode_raw_bytes(&response)?;
+        // The filters only apply when the bytes turn out to be text.
+        match std::str::from_utf8(&bytes) {
+            Ok(text) if !options.stream_filters.is_empty() => {
+                write!(output, "{}", options.stream_filters.apply(text))?
+            }
+            _ => output.write_all(&bytes)?,
+        }
+        output.flush()?;
+        return Ok(response);
+    }
+
+    if let Some(image_dir) = options.image_dir.as_deref() {
+        // Images can't be found until the whole data URL has arrived, so wait for the whole thing.
+        let response = messages.into_iter().collect::<String>();
+        let response = save_images(options, image_dir, &response)?;
+        write!(output, "{}", options.stream_filters.apply(&response))?;
+        if options.trailing_newline {
+            writeln!(output)?;
+        }
+        output.flush()?;
+        return Ok(response);
+    }
+
+    let mut filter = (!options.stream_filters.is_empty()).then(|| options.stream_filters.stream());
+    let mut renderer = options.render_markdown.then(MarkdownRenderer::new);
+    let mut response = String::new();
+    for message in messages {
+        response.push_str(&message);
+        let text = match filter.as_mut() {
+            Some(filter) => filter.push(&message),
+            None => message,
+        };
+        let text = match renderer.as_mut() {
+            Some(renderer) => renderer.push(&text),
+            None => text,
+        };
+        write!(output, "{}", text)?;
+        output.flush()?;
+    }
+
+    let mut rest = filter.as_mut().map(|f| f.finish()).unwrap_or_default();
+    if let Some(renderer) = renderer.as_mut() {
+        rest = renderer.push(&rest) + &renderer.finish();
+    }
+    write!(output, "{}", rest)?;
+
+    if options.trailing_newline {
+        writeln!(output)?;
+    }
+
+    Ok(response)
+}
+
+/// Returns true if the error came from writing to a pipe whose reader has gone away, such as when
+/// the output is piped into `head`. This isn't a failure, since the reader already has all the
+/// output that it wants.
+pub fn is_broken_pipe(err: &Report<Error>) -> bool {
+    err.downcast_ref::<std::io::Error>()
+        .map(|e| e.kind() == ErrorKind::BrokenPipe)
+        .unwrap_or(false)
+}
+
+fn image_extension(mime_subtype: &str) -> &str {
+    match mime_subtype {
+        "jpeg" => "jpg",
+        "svg+xml" => "svg",
+        other => other,
+    }
+}
+
+/// Save each image data URL in the response to `image_dir`, and return the response with the
+/// data URLs replaced by the paths of the saved files.
+fn save_images(
+    options: &OutputOptions,
+    image_dir: &Path,
+    response: &str,
+) -> Result<String, std::io::Error> {
+    let image_regex =
+        regex::Regex::new(r"data:image/([a-zA-Z0-9.+-]+);base64,([A-Za-z0-9+/]+=*)").unwrap();
+    let timestamp = chrono::Local::now().format("%Y%m%d-%H%M%S").to_string();
+
+    let mut output = String::with_capacity(response.len());
+    let mut last_end = 0;
+    for (index, captures) in image_regex.captures_iter(response).enumerate() {
+        let whole = captures.get(0).unwrap();
+        let bytes = STANDARD
+            .decode(&captures[2])
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        let mut context = tera::Context::new();
+        context.insert("template", &options.template_name);
+        context.insert("index", &(index + 1));
+        context.insert("ext", image_extension(&captures[1]));
+        context.insert("timestamp", &timestamp);
+        let filename = tera::Tera::one_off(&options.image_filename, &context, false)
+            .map_err(std::io::Error::other)?;
+
+        std::fs::create_dir_all(image_dir)?;
+        let path = image_dir.join(filename);
+        std::fs::write(&path, bytes)?;
+
+        output.push_str(&response[last_end..whole.start()]);
+        output.push_str(&path.to_string_lossy());
+        last_end = whole.end();
+    }
+
+    output.push_str(&response[last_end..]);
+    Ok(output)
+}
+
+/// The file given with `--output`. When replacing a regular file, the output is written to a
+/// temporary file next to it that only replaces it once the run succeeds. Appends and special
+/// files such as `/dev/stdout` are written in place.
+#[derive(Debug)]
+pub struct OutputFile {
+    temp_path: Option<tempfile::TempPath>,
+    path: PathBuf,
+}
+
+impl OutputFile {
+    /// Replace the output file with what was written. If this isn't called, such as when the run
+    /// fails, the temporary file is removed and the output file is left as it was.
+    pub fn finish(self) -> Result<(), Report<Error>> {
+        let Some(temp_path) = self.temp_path else {
+            return Ok(());
+        };
+
+        let path = self.path;
+        temp_path
+            .persist(&path)
+            .change_context(Error::WriteOutput)
+            .attach_printable_lazy(|| path.display().to_string())
+    }
+}
+
+/// Open the file given with `--output`. The path is rendered as a template with the template's
+/// options, as in `notes/{{topic}}.md`, and its directory is created if it doesn't exist.
+///
+/// This returns the file to write to and the [OutputFile] to finish once the run succeeds.
+pub fn open_output_file(
+    path: &str,
+    context: &tera::Context,
+    append: bool,
+) -> Result<(std::fs::File, OutputFile), Report<Error>> {
+    let rendered = tera::Tera::one_off(path, context, false)
+        .change_context(Error::WriteOutput)
+        .attach_printable_lazy(|| format!("Rendering the output path {path}"))?;
+    let path = PathBuf::from(rendered.trim());
+
+    let parent = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    std::fs::create_dir_all(parent)
+        .change_context(Error::WriteOutput)
+        .attach_printable_lazy(|| format!("Creating directory {}", parent.display()))?;
+
+    let metadata = match std::fs::metadata(&path) {
+        Ok(metadata) => Some(metadata),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            return Err(Report::new(e)
+                .change_context(Error::WriteOutput)
+                .attach_printable(path.display().to_string()))
+        }
+    };
+
+    let in_place = append || metadata.as_ref().is_some_and(|m| !m.is_file());
+    if in_place {
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .append(append)
+            .truncate(!append)
+            .open(&path)
+            .change_context(Error::WriteOutput)
+            .attach_printable_lazy(|| path.display().to_string())?;
+        return Ok((
+            file,
+            OutputFile {
+                temp_path: None,
+                path,
+            },
+        ));
+    }
+
+    // Replace the file a symlink points to rather than the symlink itself.
+    let path = match metadata {
+        Some(_) => std::fs::canonicalize(&path)
+            .change_context(Error::WriteOutput)
+            .attach_printable_lazy(|| path.display().to_string())?,
+        None => path,
+    };
+    let parent = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+
+    // Keep the temporary file in the same directory so that it can be renamed into place.
+    let file = tempfile::Builder::new()
+        .prefix(".promptbox-output-")
+        .tempfile_in(parent)
+        .change_context(Error::WriteOutput)
+        .attach_printable_lazy(|| path.display().to_string())?;
+
+    // Keep the permissions of the file being replaced.
+    if let Some(metadata) = metadata {
+        std::fs::set_permissions(file.path(), metadata.permissions())
+            .change_context(Error::WriteOutput)
+            .attach_printable_lazy(|| path.display().to_string())?;
+    }
+
+    let (file, temp_path) = file.into_parts();
+    Ok((
+        file,
+        OutputFile {
+            temp_path: Some(temp_path),
+            path,
+        },
+    ))
+}
+
+/// Decode a base64 response, which may also be formatted as a data URL.
+fn decode_raw_bytes(response: &str) -> Result<Vec<u8>, std::io::Error> {
+    let response = response.trim();
+    let encoded = match response.split_once(";base64,") {
+        Some((prefix, data)) if prefix.starts_with("data:") => data,
+        _ => response,
+    };
+
+    let encoded = encoded
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>();
+
+    STANDARD
+        .decode(encoded)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run_output(options: &OutputOptions, messages: &[&str]) -> Result<Vec<u8>, std::io::Error> {
+        let (tx, rx) = flume::unbounded();
+        for message in messages {
+            tx.send(message.to_string()).unwrap();
+        }
+        drop(tx);
+
+        let mut output = Vec::new();
+        write_output(options, rx, &mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn trailing_newline() {
+        let output = run_output(&OutputOptions::default(), &["abc", "def"]).unwrap();
+        assert_eq!(output, b"abcdef\n");
+    }
+
+    #[test]
+    fn stream_filters() {
+        let options = OutputOptions {
+            stream_filters: StreamFilters::new(&[StreamFilterRule {
+                pattern: "’".to_string(),
+                replace: "'".to_string(),
+            }])
+            .unwrap(),
+            ..Default::default()
+        };
+        let output = run_output(&options, &["It’", "s\nwhat’s ", "next"]).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "It's\nwhat's next\n");
+
+        let jsonl = OutputOptions {
+            format: ResultFormat::Jsonl,
+            ..options.clone()
+        };
+        let output = run_output(&jsonl, &["It’", "s\nwhat’s ", "next"]).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"chunk\":\"It's\\n\"}\n{\"chunk\":\"what's next\"}\n"
+        );
+
+        let raw = OutputOptions {
+            raw_bytes: true,
+            ..options
+        };
+        let output = run_output(&raw, &[&STANDARD.encode("It’s")]).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "It's");
+    }
+
+    /// A writer whose reader has gone away
+    struct ClosedPipe;
+
+    impl Write for ClosedPipe {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn broken_pipe() {
+        let (tx, rx) = flume::unbounded();
+        tx.send("abc".to_string()).unwrap();
+        drop(tx);
+
+        let err = write_output(&OutputOptions::default(), rx, &mut ClosedPipe).unwrap_err();
+        let report = Report::new(err).change_context(Error::WriteOutput);
+        assert!(is_broken_pipe(&report));
+
+        let other = Report::new(std::io::Error::from(ErrorKind::StorageFull))
+            .change_context(Error::WriteOutput);
+        assert!(!is_broken_pipe(&other));
+    }
+
+    #[test]
+    fn no_trailing_newline() {
+        let options = OutputOptions {
+            trailing_newline: false,
+            ..Default::default()
+        };
+        let output = run_output(&options, &["abc", "def"]).unwrap();
+        assert_eq!(output, b"abcdef");
+    }
+
+    #[test]
+    fn raw_bytes() {
+        let options = OutputOptions {
+            raw_bytes: true,
+            ..Default::default()
+        };
+        let output = run_output(&options, &["aGVsbG8g", "d29y\nbGQ=\n"]).unwrap();
+        assert_eq!(output, b"hello world");
+    }
+
+    #[test]
+    fn raw_bytes_data_url() {
+        let options = OutputOptions {
+            raw_bytes: true,
+            ..Default::default()
+        };
+        let output = run_output(&options, &["data:image/png;base64,aGVsbG8="]).unwrap();
+        assert_eq!(output, b"hello");
+    }
+
+    #[test]
+    fn save_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = OutputOptions {
+            image_dir: Some(dir.path().join("images")),
+            image_filename: "{{template}}-{{index}}.{{ext}}".to_string(),
+            template_name: "draw".to_string(),
+            ..Default::default()
+        };
+
+        let output = run_output(
+            &options,
+            &[
+                "Here you go: data:image/png;base64,aGVs",
+                "bG8=\nand data:image/jpeg;base64,d29ybGQ=",
+            ],
+        )
+        .unwrap();
+
+        let first = dir.path().join("images/draw-1.png");
+        let second = dir.path().join("images/draw-2.jpg");
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!(
+                "Here you go: {}\nand {}\n",
+                first.display(),
+                second.display()
+            )
+        );
+        assert_eq!(std::fs::read(first).unwrap(), b"hello");
+        assert_eq!(std::fs::read(second).unwrap(), b"world");
+    }
+
+    #[test]
+    fn save_images_in_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = OutputOptions {
+            image_dir: Some(dir.path().to_path_buf()),
+            image_filename: "image.{{ext}}".to_string(),
+            trailing_newline: false,
+            ..Default::default()
+        };
+
+        let output = run_output(
+            &options,
+            &[r#"{"image":"data:image/webp;base64,aGVsbG8="}"#],
+        )
+        .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        let path = dir.path().join("image.webp");
+        assert_eq!(value["image"], path.to_string_lossy().as_ref());
+        assert_eq!(std::fs::read(path).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn json_result() {
+        let result = RunResult {
+            response: "abcdef",
+            model: "gpt-4o",
+            prompt_tokens: Some(10),
+            completion_tokens: Some(2),
+            duration_ms: 150,
+        };
+        let expected = r#"{"response":"abcdef","model":"gpt-4o","prompt_tokens":10,"completion_tokens":2,"duration_ms":150}"#;
+
+        let options = OutputOptions {
+            format: ResultFormat::Json,
+            ..Default::default()
+        };
+        let mut output = run_output(&options, &["abc", "def"]).unwrap();
+        assert!(output.is_empty());
+        write_result(&options, &result, &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), format!("{expected}\n"));
+
+        let options = OutputOptions {
+            format: ResultFormat::Jsonl,
+            ..Default::default()
+        };
+        let mut output = run_output(&options, &["abc", "def\n"]).unwrap();
+        write_result(&options, &result, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!("{{\"chunk\":\"abc\"}}\n{{\"chunk\":\"def\\n\"}}\n{expected}\n")
+        );
+    }
+
+    #[test]
+    fn output_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut context = tera::Context::new();
+        context.insert("topic", "rust");
+        let path = format!("{}/notes/{{{{topic}}}}.md", dir.path().display());
+        let written = dir.path().join("notes/rust.md");
+
+        let write = |text: &[u8], append: bool| {
+            let (mut file, output) = open_output_file(&path, &context, append).unwrap();
+            file.write_all(text).unwrap();
+            output
+        };
+
+        write(b"first\n", false).finish().unwrap();
+        write(b"second\n", true).finish().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&written).unwrap(),
+            "first\nsecond\n"
+        );
+
+        // A run that doesn't finish leaves the file as it was, without the temporary file.
+        let failed = write(b"partial\n", false);
+        assert_eq!(
+            std::fs::read_to_string(&written).unwrap(),
+            "first\nsecond\n"
+        );
+        drop(failed);
+        assert_eq!(
+            std::fs::read_dir(written.parent().unwrap())
+                .unwrap()
+                .count(),
+            1
+        );
+
+        write(b"third\n", false).finish().unwrap();
+        assert_eq!(std::fs::read_to_string(&written).unwrap(), "third\n");
+
+        let err = open_output_file("{{ missing }}.md", &context, false).unwrap_err();
+        assert!(matches!(err.current_context(), Error::WriteOutput));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn output_file_symlink() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target.md");
+        let link = dir.path().join("link.md");
+        std::fs::write(&target, "first\n").unwrap();
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o644)).unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        let context = tera::Context::new();
+        let link_path = link.to_string_lossy();
+
+        // Appending writes to the existing file without replacing it.
+        let (mut file, output) = open_output_file(&link_path, &context, true).unwrap();
+        file.write_all(b"second\n").unwrap();
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "first\nsecond\n");
+        output.finish().unwrap();
+
+        // Replacing the file keeps the symlink and the file's permissions.
+        let (mut file, output) = open_output_file(&link_path, &context, false).unwrap();
+        file.write_all(b"third\n").unwrap();
+        output.finish().unwrap();
+        assert!(std::fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "third\n");
+        assert_eq!(
+            std::fs::metadata(&target).unwrap().permissions().mode() & 0o777,
+            0o644
+        );
+    }
+
+    #[test]
+    fn raw_bytes_invalid() {
+        let options = OutputOptions {
+            raw_bytes: true,
+            ..Default::default()
+        };
+        let err = run_output(&options, &["not base64!"]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,260 @@
+//! Find the partials that templates include with `{% include "snippets/style" %}`. Each template
+//! directory can have a `partials` folder, and they are searched in the same order as the
+//! template directories, so a project can override a partial from a shared directory.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use error_stack::{Report, ResultExt};
+use regex::Regex;
+use tera::Tera;
+
+use crate::{
+    error::Error,
+    pin::remove_pin_tags,
+    provenance::{ReadSources, SourceKind},
+};
+
+/// The name of the folder in each template directory which holds the partials
+const PARTIALS_DIR: &str = "partials";
+
+/// Extensions to try after the name itself, so that `{% include "style" %}` can find `style.md`.
+const PARTIAL_EXTENSIONS: [&str; 5] = ["", ".md", ".txt", ".tera", ".liquid"];
+
+#[derive(Debug, Default)]
+pub struct Partials {
+    dirs: Vec<PathBuf>,
+    /// Partials which have already been looked up, since a prompt may be rendered several times.
+    cache: Mutex<HashMap<String, Option<(PathBuf, String)>>>,
+}
+
+impl Partials {
+    /// Look for partials in the `partials` folder of each directory, in order.
+    pub fn new(template_dirs: &[PathBuf]) -> Self {
+        Self {
+            dirs: template_dirs
+                .iter()
+                .map(|dir| dir.join(PARTIALS_DIR))
+                .collect(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Find the partial with this name in the first directory that has it.
+    fn find(&self, name: &str) -> Result<Option<(PathBuf, String)>, Report<Error>> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(partial) = cache.get(name) {
+            return Ok(partial.clone());
+        }
+
+        let path = self
+            .dirs
+            .iter()
+            .flat_map(|dir| {
+                PARTIAL_EXTENSIONS
+                    .iter()
+                    .map(move |extension| dir.join(format!("{name}{extension}")))
+            })
+            .find(|path| path.is_file());
+        let partial = match path {
+            Some(path) => {
+                let contents = std::fs::read_to_string(&path)
+                    .change_context(Error::ParseTemplate)
+                    .attach_printable_lazy(|| path.display().to_string())?;
+                Some((path, contents))
+            }
+            None => None,
+        };
+
+        cache.insert(name.to_string(), partial.clone());
+        Ok(partial)
+    }
+
+    /// Add the partials that `template` includes to `tera`, along with the partials that those
+    /// include. Each partial's file is added to `sources`.
+    pub fn register(
+        &self,
+        tera: &mut Tera,
+        template: &str,
+        sources: &ReadSources,
+    ) -> Result<(), Report<Error>> {
+        let mut seen = HashSet::new();
+        self.register_includes(tera, template, sources, &mut seen)
+    }
+
+    fn register_includes(
+        &self,
+        tera: &mut Tera,
+        template: &str,
+        sources: &ReadSources,
+        seen: &mut HashSet<String>,
+    ) -> Result<(), Report<Error>> {
+        for include in includes(template) {
+            if !seen.insert(include.name.clone()) {
+                continue;
+            }
+
+            let Some((path, contents)) = self.find(&include.name)? else {
+                if include.ignore_missing {
+                    continue;
+                }
+
+                return Err(Report::new(Error::PartialNotFound(include.name.clone())))
+                    .attach_printable(self.searched());
+            };
+
+            tera.add_raw_template(&include.name, &remove_pin_tags(&contents))
+                .change_context(Error::ParseTemplate)
+                .attach_printable_lazy(|| path.display().to_string())?;
+            sources.record(SourceKind::Partial, &path, &contents);
+            self.register_includes(tera, &contents, sources, seen)
+                .attach_printable_lazy(|| format!("Included from {}", path.display()))?;
+        }
+
+        Ok(())
+    }
+
+    fn searched(&self) -> String {
+        if self.dirs.is_empty() {
+            return "No template directories to search for partials".to_string();
+        }
+
+        let dirs = self
+            .dirs
+            .iter()
+            .map(|dir| dir.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!("Searched {dirs}")
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Include {
+    name: String,
+    ignore_missing: bool,
+}
+
+/// The partials that a template includes. Names given as variables can't be known before the
+/// template is rendered, so they are skipped.
+fn includes(template: &str) -> Vec<Include> {
+    let include_regex = Regex::new(
+        r#"\{%-?\s*include\s+(?:"([^"]+)"|'([^']+)'|\[([^\]]*)\])(\s+ignore\s+missing)?\s*-?%\}"#,
+    )
+    .unwrap();
+    let name_regex = Regex::new(r#""([^"]+)"|'([^']+)'"#).unwrap();
+
+    include_regex
+        .captures_iter(template)
+        .flat_map(|captures| {
+            let ignore_missing = captures.get(4).is_some();
+            let names = match captures.get(1).or_else(|| captures.get(2)) {
+                Some(name) => vec![name.as_str().to_string()],
+                // A list of names includes the first one that exists.
+                None => name_regex
+                    .captures_iter(captures.get(3).map(|m| m.as_str()).unwrap_or_default())
+                    .filter_map(|c| c.get(1).or_else(|| c.get(2)))
+                    .map(|m| m.as_str().to_string())
+                    .collect(),
+            };
+            let is_list = names.len() > 1;
+
+            names.into_iter().map(move |name| Include {
+                name,
+                ignore_missing: ignore_missing || is_list,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn find_includes() {
+        assert_eq!(
+            includes(
+                r#"{% include "snippets/style" %} {%- include 'a' ignore missing -%}
+                {% include ["b", "c"] %} {% include name %}"#
+            ),
+            vec![
+                Include {
+                    name: "snippets/style".to_string(),
+                    ignore_missing: false,
+                },
+                Include {
+                    name: "a".to_string(),
+                    ignore_missing: true,
+                },
+                Include {
+                    name: "b".to_string(),
+                    ignore_missing: true,
+                },
+                Include {
+                    name: "c".to_string(),
+                    ignore_missing: true,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn precedence_and_missing() {
+        let project = tempfile::tempdir().unwrap();
+        let shared = tempfile::tempdir().unwrap();
+        let write = |dir: &Path, name: &str, contents: &str| {
+            let path = dir.join(PARTIALS_DIR).join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        write(project.path(), "snippets/style.md", "Be brief.");
+        write(shared.path(), "snippets/style.md", "Be thorough.");
+        write(
+            shared.path(),
+            "signoff",
+            r#"{% include "snippets/style" %} Thanks, {{ name }}."#,
+        );
+
+        let partials = Partials::new(&[project.path().to_path_buf(), shared.path().to_path_buf()]);
+        let sources = ReadSources::default();
+        let mut tera = Tera::default();
+        partials
+            .register(&mut tera, r#"Hi. {% include "signoff" %}"#, &sources)
+            .unwrap();
+        let context = tera::Context::from_value(serde_json::json!({ "name": "Sam" })).unwrap();
+        let rendered = tera
+            .render_str(r#"Hi. {% include "signoff" %}"#, &context)
+            .unwrap();
+        assert_eq!(rendered, "Hi. Be brief. Thanks, Sam.");
+        let paths = sources
+            .files()
+            .into_iter()
+            .map(|source| source.path)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                shared.path().join(PARTIALS_DIR).join("signoff"),
+                project.path().join(PARTIALS_DIR).join("snippets/style.md"),
+            ]
+        );
+
+        let err = partials
+            .register(&mut Tera::default(), r#"{% include "nope" %}"#, &sources)
+            .unwrap_err();
+        assert!(matches!(err.current_context(), Error::PartialNotFound(name) if name == "nope"));
+        partials
+            .register(
+                &mut Tera::default(),
+                r#"{% include "nope" ignore missing %}"#,
+                &sources,
+            )
+            .unwrap();
+    }
+}
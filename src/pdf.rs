@@ -0,0 +1,62 @@
+//! Extract the text of PDF files passed to file options.
+
+use error_stack::Report;
+
+use crate::error::Error;
+
+/// Returns true if the file contents look like a PDF.
+pub fn is_pdf(contents: &[u8]) -> bool {
+    contents.starts_with(b"%PDF-")
+}
+
+/// The text of each page in the PDF.
+#[cfg(feature = "pdf")]
+pub fn extract_pages(contents: &[u8]) -> Result<Vec<String>, Report<Error>> {
+    use error_stack::ResultExt;
+
+    // The PDF parser panics on some malformed files, so catch that and report it like any other
+    // error.
+    let result = std::panic::catch_unwind(|| pdf_extract::extract_text_from_mem_by_pages(contents));
+
+    let pages = match result {
+        Ok(pages) => pages.change_context(Error::Pdf)?,
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "Unsupported PDF".to_string());
+            return Err(Report::new(Error::Pdf).attach_printable(message));
+        }
+    };
+
+    Ok(pages.iter().map(|page| page.trim().to_string()).collect())
+}
+
+#[cfg(not(feature = "pdf"))]
+pub fn extract_pages(_contents: &[u8]) -> Result<Vec<String>, Report<Error>> {
+    Err(Report::new(Error::Pdf).attach_printable(
+        "This build of PromptBox can't read PDF files. Rebuild it with the `pdf` feature.",
+    ))
+}
+
+#[cfg(all(test, feature = "pdf"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pages() {
+        let contents = std::fs::read("test_data/two_pages.pdf").unwrap();
+        assert!(is_pdf(&contents));
+        assert_eq!(
+            extract_pages(&contents).unwrap(),
+            vec!["First page".to_string(), "Second page".to_string()]
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        let err = extract_pages(b"%PDF-1.4\nnot really a pdf").unwrap_err();
+        assert!(matches!(err.current_context(), Error::Pdf));
+    }
+}
@@ -0,0 +1,339 @@
+//! Choose a template interactively when `promptbox run` is given no template name, and then ask
+//! for the values of its required options.
+
+use std::{
+    ffi::OsString,
+    io::{BufRead, IsTerminal, Write},
+};
+
+use error_stack::{Report, ResultExt};
+
+use crate::{
+    config::Config,
+    error::Error,
+    list::{list_templates, TemplateSummary},
+    template::{OptionType, PromptOption},
+};
+
+/// The most templates to show at once. Searching narrows down the list.
+const MAX_SHOWN: usize = 20;
+
+/// Score how well `query` matches `text`, where lower is better. Each character of the query must
+/// appear in the text in order, and matches which are closer together and nearer the start score
+/// better. Returns `None` if the text doesn't match.
+fn fuzzy_score(query: &str, text: &str) -> Option<usize> {
+    let text = text.to_lowercase().chars().collect::<Vec<_>>();
+    let mut score = 0;
+    let mut pos = 0;
+    let mut first = true;
+    for q in query.to_lowercase().chars().filter(|c| !c.is_whitespace()) {
+        let found = text[pos..].iter().position(|c| *c == q)?;
+        // The gap before the first match counts less than gaps between matches.
+        score += if first { found.min(10) } else { found * 2 };
+        first = false;
+        pos += found + 1;
+    }
+
+    Some(score)
+}
+
+/// The templates matching the query, best first. Matches in the name rank above matches in the
+/// description.
+fn matching_templates<'a>(
+    query: &str,
+    templates: &'a [TemplateSummary],
+) -> Vec<&'a TemplateSummary> {
+    let mut scored = templates
+        .iter()
+        .filter_map(|t| {
+            let score = fuzzy_score(query, &t.name).or_else(|| {
+                fuzzy_score(query, &format!("{} {}", t.name, t.description))
+                    .map(|score| score + 1000)
+            })?;
+            Some((score, t))
+        })
+        .collect::<Vec<_>>();
+    scored.sort_by(|(a_score, a), (b_score, b)| a_score.cmp(b_score).then(a.name.cmp(&b.name)));
+    scored.into_iter().map(|(_, t)| t).collect()
+}
+
+/// Read a line, returning `None` at the end of the input.
+fn read_line(input: &mut impl BufRead) -> std::io::Result<Option<String>> {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(line.trim().to_string()))
+}
+
+/// Show the templates and let the user search them or choose one by number. Returns `None` if the
+/// input ends before a template is chosen.
+fn pick_template(
+    templates: &[TemplateSummary],
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> std::io::Result<Option<String>> {
+    let mut query = String::new();
+    loop {
+        let matches = matching_templates(&query, templates);
+        if matches.is_empty() {
+            writeln!(output, "No templates match `{query}`")?;
+        } else {
+            let shown = &matches[..matches.len().min(MAX_SHOWN)];
+            let name_width = shown.iter().map(|t| t.name.len()).max().unwrap_or(0);
+            let number_width = shown.len().to_string().len();
+            for (i, template) in shown.iter().enumerate() {
+                let line = format!(
+                    "{:>number_width$}. {:name_width$}  {}",
+                    i + 1,
+                    template.name,
+                    template.description
+                );
+                writeln!(output, "{}", line.trim_end())?;
+            }
+            if matches.len() > shown.len() {
+                writeln!(output, "... and {} more", matches.len() - shown.len())?;
+            }
+        }
+
+        write!(
+            output,
+            "Search, or choose a template by number (Enter picks the first): "
+        )?;
+        output.flush()?;
+
+        let Some(line) = read_line(input)? else {
+            return Ok(None);
+        };
+
+        if line.is_empty() {
+            if let Some(first) = matches.first() {
+                return Ok(Some(first.name.clone()));
+            }
+        } else if let Ok(number) = line.parse::<usize>() {
+            if (1..=matches.len().min(MAX_SHOWN)).contains(&number) {
+                return Ok(Some(matches[number - 1].name.clone()));
+            }
+            writeln!(output, "There is no template number {number}")?;
+        } else {
+            query = line;
+        }
+    }
+}
+
+/// Ask for the value of each required option, and return them as command line arguments. Returns
+/// `None` if the input ends first.
+fn ask_options<'a>(
+    options: impl IntoIterator<Item = (&'a String, &'a PromptOption)>,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> std::io::Result<Option<Vec<OsString>>> {
+    let mut options = options
+        .into_iter()
+        .filter(|(_, option)| option.required())
+        .collect::<Vec<_>>();
+    options.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut args = Vec::new();
+    for (name, option) in options {
+        let mut label = name.clone();
+        if !option.description.is_empty() {
+            label = format!("{label} ({})", option.description);
+        } else if matches!(option.option_type, OptionType::File | OptionType::Image) {
+            label = format!("{label} (path)");
+        } else if option.option_type == OptionType::Url {
+            label = format!("{label} (URL)");
+        } else if matches!(
+            option.option_type,
+            OptionType::Json | OptionType::Yaml | OptionType::Csv
+        ) {
+            label = format!("{label} (path or {})", option.option_type);
+        }
+        if option.array {
+            label = format!("{label}, one per line and an empty line to finish");
+        }
+
+        let mut values = Vec::new();
+        loop {
+            write!(output, "{label}: ")?;
+            output.flush()?;
+            let Some(value) = read_line(input)? else {
+                return Ok(None);
+            };
+
+            if value.is_empty() {
+                if !values.is_empty() {
+                    break;
+                }
+                // Required options need at least one value.
+                continue;
+            }
+
+            values.push(value);
+            if !option.array {
+                break;
+            }
+        }
+
+        for value in values {
+            args.push(OsString::from(format!("--{name}")));
+            args.push(OsString::from(value));
+        }
+    }
+
+    Ok(Some(args))
+}
+
+/// Choose a template and the values of its required options interactively, and return the command
+/// line to run it with. `args` are the arguments that were given after `run`. This only works
+/// when both stdin and stderr are terminals.
+pub fn pick_template_command(
+    config: &Config,
+    program: OsString,
+    args: Vec<OsString>,
+) -> Result<Vec<OsString>, Report<Error>> {
+    if !std::io::stdin().is_terminal() || !std::io::stderr().is_terminal() {
+        return Err(Report::new(Error::MissingTemplateName));
+    }
+
+    let list = list_templates(config);
+    if list.templates.is_empty() {
+        return Err(Report::new(Error::TemplateNotFound))
+            .attach_printable("No templates were found in the template directories");
+    }
+
+    let mut input = std::io::stdin().lock();
+    let mut output = std::io::stderr();
+    let name = pick_template(&list.templates, &mut input, &mut output)
+        .change_context(Error::Io)?
+        .ok_or(Error::RunCancelled)?;
+
+    let template = config.find_template(&name)?;
+    let option_args = ask_options(&template.input.options, &mut input, &mut output)
+        .change_context(Error::Io)?
+        .ok_or(Error::RunCancelled)?;
+
+    Ok([program, OsString::from("run"), OsString::from(name)]
+        .into_iter()
+        .chain(args)
+        .chain(option_args)
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, io::Cursor, path::PathBuf};
+
+    use super::*;
+
+    fn summary(name: &str, description: &str) -> TemplateSummary {
+        TemplateSummary {
+            name: name.to_string(),
+            directory: PathBuf::new(),
+            namespace: String::new(),
+            description: description.to_string(),
+            model: String::new(),
+        }
+    }
+
+    fn templates() -> Vec<TemplateSummary> {
+        vec![
+            summary("summarize", "Summarize a document"),
+            summary("code/review", "Review a diff"),
+            summary("commit-msg", "Write a commit message"),
+            summary("translate", "Translate text into another language"),
+        ]
+    }
+
+    fn names(query: &str, templates: &[TemplateSummary]) -> Vec<String> {
+        matching_templates(query, templates)
+            .into_iter()
+            .map(|t| t.name.clone())
+            .collect()
+    }
+
+    fn pick(input: &str) -> (Option<String>, String) {
+        let mut output = Vec::new();
+        let picked = pick_template(&templates(), &mut Cursor::new(input), &mut output).unwrap();
+        (picked, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn fuzzy_matching() {
+        assert_eq!(fuzzy_score("smz", "summarize"), Some(10));
+        assert_eq!(fuzzy_score("sum", "summarize"), Some(0));
+        assert_eq!(fuzzy_score("zs", "summarize"), None);
+        assert_eq!(fuzzy_score("", "summarize"), Some(0));
+
+        let templates = templates();
+        assert_eq!(names("cm", &templates), ["commit-msg", "summarize"]);
+        // Descriptions are searched too, after the names.
+        assert_eq!(names("diff", &templates), ["code/review"]);
+        assert_eq!(names("t", &templates)[0], "translate");
+        assert_eq!(names("nothing", &templates), Vec::<String>::new());
+    }
+
+    #[test]
+    fn pick_by_number() {
+        let (picked, output) = pick("3\n");
+        assert_eq!(picked.as_deref(), Some("summarize"));
+        assert!(output.contains("1. code/review  Review a diff"), "{output}");
+    }
+
+    #[test]
+    fn search_then_pick() {
+        let (picked, output) = pick("review\n\n");
+        assert_eq!(picked.as_deref(), Some("code/review"));
+        assert_eq!(output.matches("Search, or choose").count(), 2);
+
+        let (picked, output) = pick("nothing\n9\ntrans\n1\n");
+        assert_eq!(picked.as_deref(), Some("translate"));
+        assert!(output.contains("No templates match `nothing`"));
+        assert!(output.contains("There is no template number 9"));
+    }
+
+    #[test]
+    fn cancel_picking() {
+        let (picked, _) = pick("sum");
+        assert_eq!(picked, None);
+    }
+
+    #[test]
+    fn ask_required_options() {
+        let options: HashMap<String, PromptOption> = toml::from_str(
+            r##"
+            topic = { description = "What to write about" }
+            files = { type = "file", array = true }
+            style = { default = "concise" }
+            verbose = { type = "bool" }
+            "##,
+        )
+        .unwrap();
+
+        let mut output = Vec::new();
+        let args = ask_options(
+            &options,
+            &mut Cursor::new("a.txt\nb.txt\n\n\nrust\n"),
+            &mut output,
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(
+            args,
+            ["--files", "a.txt", "--files", "b.txt", "--topic", "rust"]
+                .map(OsString::from)
+                .to_vec()
+        );
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("files (path), one per line"), "{output}");
+        // The empty answer for topic asks again.
+        assert_eq!(output.matches("topic (What to write about): ").count(), 2);
+        assert!(!output.contains("style"));
+
+        let cancelled =
+            ask_options(&options, &mut Cursor::new("a.txt\n"), &mut Vec::new()).unwrap();
+        assert_eq!(cancelled, None);
+    }
+}
@@ -0,0 +1,310 @@
+//! Pinned content is never removed when trimming a prompt to fit the context. Content is pinned by
+//! setting `pin = true` on an option, or by wrapping part of a template in
+//! `{% pin %}...{% endpin %}`.
+//!
+//! To find the pinned content in a rendered prompt, the template is rendered again with markers
+//! around each pinned value and block.
+
+use std::{borrow::Cow, collections::BTreeMap, ops::Range, sync::OnceLock};
+
+use regex::Regex;
+use tera::Tera;
+use tracing::{event, Level};
+
+use crate::option_groups::{option_value, update_option_value};
+
+/// These characters are in the Unicode private use area, so they won't appear in normal text.
+const START: char = '\u{E000}';
+const LABEL_END: char = '\u{E001}';
+const END: char = '\u{E002}';
+
+fn pin_tag_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| Regex::new(r"\{%(-?)\s*(end)?pin\s*(-?)%\}").unwrap())
+}
+
+/// Replace the pin tags with tags that Tera understands, so that the template renders as if they
+/// weren't there.
+pub fn remove_pin_tags(template: &str) -> Cow<'_, str> {
+    pin_tag_regex().replace_all(template, |caps: &regex::Captures| {
+        let tag = if caps.get(2).is_some() {
+            "endif"
+        } else {
+            "if true"
+        };
+        format!("{{%{} {tag} {}%}}", &caps[1], &caps[3])
+    })
+}
+
+/// Replace the pin tags with markers around the content of each block. The markers use the same
+/// whitespace control as the tags, so the output is the same once the markers are removed.
+fn mark_pin_tags(template: &str) -> String {
+    let mut count = 0;
+    pin_tag_regex()
+        .replace_all(template, |caps: &regex::Captures| {
+            let (left, right) = (&caps[1], &caps[3]);
+            if caps.get(2).is_some() {
+                format!("{{{{{left} \"{END}\" {left}}}}}{{%{left} endif {right}%}}")
+            } else {
+                count += 1;
+                format!(
+                    "{{%{left} if true {right}%}}{{{{{right} \"{START}pin block {count}{LABEL_END}\" {right}}}}}"
+                )
+            }
+        })
+        .into_owned()
+}
+
+fn mark_text(label: &str, text: &str) -> String {
+    format!("{START}{label}{LABEL_END}{text}{END}")
+}
+
+/// Add markers around the text of an option value. For files, only the contents are marked.
+fn mark_value(label: &str, value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::String(s) => *s = mark_text(label, s),
+        serde_json::Value::Array(values) => {
+            for value in values {
+                mark_value(label, value);
+            }
+        }
+        serde_json::Value::Object(object) => {
+            if let Some(contents) = object.get_mut("contents") {
+                mark_value(label, contents);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// A piece of pinned content in the prompt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pin {
+    /// What pinned the content, such as "option `file`" or "pin block 1"
+    pub label: String,
+    /// The location of the content in the prompt
+    pub range: Range<usize>,
+}
+
+/// Remove the markers from rendered text, and return the text with the location of each pin.
+/// Pins inside other pins are included in the outer pin.
+fn find_pins(marked: &str) -> (String, Vec<Pin>) {
+    let mut text = String::with_capacity(marked.len());
+    let mut pins = Vec::new();
+    let mut depth = 0;
+    let mut current: Option<(String, usize)> = None;
+
+    let mut chars = marked.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            START => {
+                let label = chars.by_ref().take_while(|c| *c != LABEL_END).collect();
+                if depth == 0 {
+                    current = Some((label, text.len()));
+                }
+                depth += 1;
+            }
+            END => {
+                depth -= 1;
+                if depth == 0 {
+                    if let Some((label, start)) = current.take() {
+                        pins.push(Pin {
+                            label,
+                            range: start..text.len(),
+                        });
+                    }
+                }
+            }
+            c => text.push(c),
+        }
+    }
+
+    (text, pins)
+}
+
+/// Find the pinned content in `rendered`, the output of rendering `template` with `args`.
+pub fn locate_pins(
+    template: &str,
+    args: &tera::Context,
+    pinned_args: &[String],
+    rendered: &str,
+) -> Vec<Pin> {
+    let has_tags = pin_tag_regex().is_match(template);
+    let pinned_args = pinned_args
+        .iter()
+        .filter(|arg| option_value(args, arg).is_some())
+        .collect::<Vec<_>>();
+    if !has_tags && pinned_args.is_empty() {
+        return Vec::new();
+    }
+
+    let template = mark_pin_tags(template);
+    let render_marked = |args: &tera::Context| {
+        let marked = Tera::one_off(&template, args, false).ok()?;
+        let (text, pins) = find_pins(&marked);
+        (text == rendered).then_some(pins)
+    };
+
+    let mut marked_args = args.clone();
+    for arg in &pinned_args {
+        update_option_value(&mut marked_args, arg, |value| {
+            mark_value(&format!("option `{arg}`"), value)
+        });
+    }
+
+    // Markers in the values change the output of templates that inspect the values, such as
+    // with `length`. Then only the pin blocks can be found.
+    let pins = render_marked(&marked_args).or_else(|| {
+        event!(
+            Level::WARN,
+            "Could not find pinned options in the rendered template"
+        );
+        render_marked(args)
+    });
+
+    pins.unwrap_or_else(|| {
+        event!(
+            Level::WARN,
+            "Could not find pinned content in the rendered template"
+        );
+        Vec::new()
+    })
+}
+
+/// The number of tokens pinned by each option or block, in the order they first appear.
+pub fn pin_sizes(
+    pins: &[Pin],
+    rendered: &str,
+    count_tokens: impl Fn(&str) -> Result<usize, crate::Error>,
+) -> Result<Vec<(String, usize)>, crate::Error> {
+    let mut order = Vec::new();
+    let mut sizes = BTreeMap::new();
+    for pin in pins {
+        let tokens = count_tokens(&rendered[pin.range.clone()])?;
+        let size = sizes.entry(pin.label.clone()).or_insert_with(|| {
+            order.push(pin.label.clone());
+            0
+        });
+        *size += tokens;
+    }
+
+    Ok(order
+        .into_iter()
+        .map(|label| {
+            let size = sizes[&label];
+            (label, size)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn render(template: &str, args: &serde_json::Value) -> String {
+        let args = tera::Context::from_value(args.clone()).unwrap();
+        Tera::one_off(&remove_pin_tags(template), &args, false).unwrap()
+    }
+
+    fn pinned_text(template: &str, args: serde_json::Value, pinned: &[&str]) -> Vec<String> {
+        let rendered = render(template, &args);
+        let context = tera::Context::from_value(args).unwrap();
+        let pinned = pinned.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        locate_pins(template, &context, &pinned, &rendered)
+            .into_iter()
+            .map(|pin| format!("{}: {}", pin.label, &rendered[pin.range]))
+            .collect()
+    }
+
+    #[test]
+    fn remove_tags() {
+        assert_eq!(render("a {% pin %}b{% endpin %} c", &json!({})), "a b c");
+        assert_eq!(
+            render("a\n{%- pin -%}\n  b\n{%- endpin %} c", &json!({})),
+            "ab c"
+        );
+    }
+
+    #[test]
+    fn pin_blocks() {
+        let template = "Intro {% pin %}Keep {{ topic }}{% endpin %}\n\
+            {%- for item in items %}\n{%- pin -%}  {{ item }}  {%- endpin -%}{% endfor %} end";
+        assert_eq!(
+            pinned_text(
+                template,
+                json!({ "topic": "this", "items": ["x", "y"] }),
+                &[]
+            ),
+            vec!["pin block 1: Keep this", "pin block 2: x", "pin block 2: y"]
+        );
+    }
+
+    #[test]
+    fn pinned_options() {
+        let template = "{{ a }} and {{ b }} {% for f in files %}{{ f.filename }}: {{ f.contents }} {% endfor %}";
+        let args = json!({
+            "a": "first",
+            "b": "second",
+            "files": [
+                { "filename": "x.txt", "contents": "x contents" },
+                { "filename": "y.txt", "contents": "y contents" },
+            ]
+        });
+        assert_eq!(
+            pinned_text(template, args, &["b", "files", "missing"]),
+            vec![
+                "option `b`: second",
+                "option `files`: x contents",
+                "option `files`: y contents"
+            ]
+        );
+    }
+
+    #[test]
+    fn nested_pins() {
+        let template = "{% pin %}Topic: {{ topic }}{% endpin %}";
+        assert_eq!(
+            pinned_text(template, json!({ "topic": "fruit" }), &["topic"]),
+            vec!["pin block 1: Topic: fruit"]
+        );
+    }
+
+    #[test]
+    fn values_changed_by_markers() {
+        // The pinned option can't be found, but the pin block still can.
+        let template = "{{ a | length }} {% pin %}b{% endpin %}";
+        assert_eq!(
+            pinned_text(template, json!({ "a": "abc" }), &["a"]),
+            vec!["pin block 1: b"]
+        );
+    }
+
+    #[test]
+    fn sizes() {
+        let rendered = "aa bb cc dd";
+        let pins = vec![
+            Pin {
+                label: "option `x`".to_string(),
+                range: 0..5,
+            },
+            Pin {
+                label: "pin block 1".to_string(),
+                range: 6..8,
+            },
+            Pin {
+                label: "option `x`".to_string(),
+                range: 9..11,
+            },
+        ];
+        let sizes = pin_sizes(&pins, rendered, |s| Ok(s.split_whitespace().count())).unwrap();
+        assert_eq!(
+            sizes,
+            vec![
+                ("option `x`".to_string(), 3),
+                ("pin block 1".to_string(), 1)
+            ]
+        );
+    }
+}
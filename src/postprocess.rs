@@ -0,0 +1,246 @@
+//! Reshape the response before it is written, so that a template can guarantee the shape of its
+//! output for other tools.
+
+use error_stack::Report;
+use jaq_core::{
+    load::{Arena, File, Loader},
+    Compiler, Ctx, Native, RcIter,
+};
+use jaq_json::Val;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::error::Error;
+
+/// A change to make to the response
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PostProcessStep {
+    /// Remove whitespace from the start and end of the response
+    Trim,
+    /// Remove Markdown formatting, such as code fences, headings, emphasis, and links, leaving the
+    /// text
+    StripMarkdown,
+    /// Replace the response with the first match of this regular expression. If the expression
+    /// has a capture group, only the text of the first group is kept.
+    Regex(String),
+    /// Run this jq filter on the response, which must be JSON. Strings in the output are written
+    /// without quotes, and each output goes on its own line.
+    Jq(String),
+}
+
+fn compile_jq(filter: &str) -> Result<jaq_core::Filter<Native<Val>>, String> {
+    let arena = Arena::default();
+    let loader = Loader::new(jaq_std::defs().chain(jaq_json::defs()));
+    let modules = loader
+        .load(
+            &arena,
+            File {
+                code: filter,
+                path: (),
+            },
+        )
+        .map_err(|errors| {
+            let errors = errors.into_iter().map(|(_, e)| e).collect::<Vec<_>>();
+            format!("{errors:?}")
+        })?;
+
+    Compiler::default()
+        .with_funs(jaq_std::funs().chain(jaq_json::funs()))
+        .compile(modules)
+        .map_err(|errors| {
+            let errors = errors.into_iter().map(|(_, e)| e).collect::<Vec<_>>();
+            format!("{errors:?}")
+        })
+}
+
+fn run_jq(filter: &str, response: &str) -> Result<String, String> {
+    let filter = compile_jq(filter)?;
+    let input = serde_json::from_str::<serde_json::Value>(response.trim())
+        .map_err(|e| format!("The response was not valid JSON ({e})"))?;
+
+    let inputs = RcIter::new(core::iter::empty());
+    let mut lines = Vec::new();
+    for value in filter.run((Ctx::new([], &inputs), Val::from(input))) {
+        let value = value.map_err(|e| e.to_string())?;
+        match serde_json::Value::from(value) {
+            serde_json::Value::String(s) => lines.push(s),
+            value => lines.push(value.to_string()),
+        }
+    }
+
+    Ok(lines.join("\n"))
+}
+
+fn strip_markdown(response: &str) -> String {
+    let fence = Regex::new(r"(?m)^[ \t]*(```|~~~).*$\n?").unwrap();
+    let heading = Regex::new(r"(?m)^#{1,6}\s+").unwrap();
+    let quote = Regex::new(r"(?m)^>\s?").unwrap();
+    let image_or_link = Regex::new(r"!?\[([^\]]*)\]\([^)]*\)").unwrap();
+    let emphasis = Regex::new(r"(\*\*|__)(.+?)(\*\*|__)|\*([^*\s][^*]*)\*|`([^`]*)`").unwrap();
+
+    let text = fence.replace_all(response, "");
+    let text = heading.replace_all(&text, "");
+    let text = quote.replace_all(&text, "");
+    let text = image_or_link.replace_all(&text, "$1");
+    let text = emphasis.replace_all(&text, |c: &regex::Captures| {
+        c.get(2)
+            .or_else(|| c.get(4))
+            .or_else(|| c.get(5))
+            .map(|m| m.as_str().to_string())
+            .unwrap_or_default()
+    });
+    text.into_owned()
+}
+
+impl PostProcessStep {
+    /// Make sure that the regular expression or jq filter compiles.
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            Self::Regex(pattern) => Regex::new(pattern).map(|_| ()).map_err(|e| e.to_string()),
+            Self::Jq(filter) => compile_jq(filter).map(|_| ()),
+            Self::Trim | Self::StripMarkdown => Ok(()),
+        }
+    }
+
+    fn apply(&self, response: &str) -> Result<String, String> {
+        match self {
+            Self::Trim => Ok(response.trim().to_string()),
+            Self::StripMarkdown => Ok(strip_markdown(response)),
+            Self::Regex(pattern) => {
+                let regex = Regex::new(pattern).map_err(|e| e.to_string())?;
+                let captures = regex
+                    .captures(response)
+                    .ok_or_else(|| format!("The response did not match `{pattern}`"))?;
+                let matched = captures.get(1).or_else(|| captures.get(0)).unwrap();
+                Ok(matched.as_str().to_string())
+            }
+            Self::Jq(filter) => run_jq(filter, response),
+        }
+    }
+}
+
+/// The steps from a template's `output.post_process`, applied in order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PostProcess(Vec<PostProcessStep>);
+
+impl PostProcess {
+    /// Check each step, so that mistakes in a template are found before the prompt is sent.
+    pub fn new(steps: Vec<PostProcessStep>) -> Result<Self, Report<Error>> {
+        for (i, step) in steps.iter().enumerate() {
+            if let Err(e) = step.validate() {
+                return Err(Report::new(Error::PostProcess)
+                    .attach_printable(format!("Step {}: {e}", i + 1)));
+            }
+        }
+
+        Ok(Self(steps))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Run the response through each step.
+    pub fn apply(&self, response: String) -> Result<String, Report<Error>> {
+        self.0
+            .iter()
+            .enumerate()
+            .try_fold(response, |response, (i, step)| {
+                step.apply(&response).map_err(|e| {
+                    Report::new(Error::PostProcess).attach_printable(format!("Step {}: {e}", i + 1))
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Steps {
+        post_process: Vec<PostProcessStep>,
+    }
+
+    fn post_process(toml: &str) -> PostProcess {
+        PostProcess::new(toml::from_str::<Steps>(toml).unwrap().post_process).unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let steps = toml::from_str::<Steps>(
+            r##"post_process = ["trim", "strip_markdown", { regex = "a(b)" }, { jq = ".a" }]"##,
+        )
+        .unwrap()
+        .post_process;
+        assert_eq!(
+            steps,
+            vec![
+                PostProcessStep::Trim,
+                PostProcessStep::StripMarkdown,
+                PostProcessStep::Regex("a(b)".to_string()),
+                PostProcessStep::Jq(".a".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn regex_and_trim() {
+        let steps = post_process(r##"post_process = [{ regex = "Answer:(.*)" }, "trim"]"##);
+        assert_eq!(
+            steps
+                .apply("Thinking...\nAnswer:  42 \n".to_string())
+                .unwrap(),
+            "42"
+        );
+
+        let err = steps.apply("No answer".to_string()).unwrap_err();
+        assert!(matches!(err.current_context(), Error::PostProcess));
+    }
+
+    #[test]
+    fn jq() {
+        let steps = post_process(r##"post_process = [{ jq = ".items[] | .name" }]"##);
+        assert_eq!(
+            steps
+                .apply(r#"{"items": [{"name": "a"}, {"name": "b"}]}"#.to_string())
+                .unwrap(),
+            "a\nb"
+        );
+
+        let steps = post_process(r##"post_process = [{ jq = "{count: .items | length}" }]"##);
+        assert_eq!(
+            steps.apply(r#"{"items": [1, 2]}"#.to_string()).unwrap(),
+            r#"{"count":2}"#
+        );
+        assert!(steps.apply("not json".to_string()).is_err());
+    }
+
+    #[test]
+    fn markdown() {
+        let steps = post_process(r##"post_process = ["strip_markdown", "trim"]"##);
+        let response = "# Title\n\nSome **bold** and *italic* text with `code` and a \
+            [link](https://example.com).\n\n> A quote\n\n```rust\nlet x = 1;\n```\n";
+        assert_eq!(
+            steps.apply(response.to_string()).unwrap(),
+            "Title\n\nSome bold and italic text with code and a link.\n\nA quote\n\nlet x = 1;"
+        );
+    }
+
+    #[test]
+    fn invalid_steps() {
+        for toml in [
+            r##"post_process = [{ regex = "(" }]"##,
+            r##"post_process = [{ jq = ".items[" }]"##,
+        ] {
+            let steps = toml::from_str::<Steps>(toml).unwrap().post_process;
+            let err = PostProcess::new(steps).unwrap_err();
+            assert!(
+                matches!(err.current_context(), Error::PostProcess),
+                "{toml}"
+            );
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Show that a request is in progress, and how quickly it was answered.
+
+use std::{
+    fmt::Display,
+    io::IsTerminal,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+const FRAMES: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+const FRAME_INTERVAL: Duration = Duration::from_millis(80);
+
+/// A spinner on stderr, shown until the spinner is stopped or dropped. Nothing is shown when
+/// stderr isn't a terminal, or if the spinner stops before the first frame.
+pub struct Spinner {
+    stop_tx: Option<flume::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Spinner {
+    pub fn start(message: String) -> Self {
+        if !std::io::stderr().is_terminal() {
+            return Self {
+                stop_tx: None,
+                thread: None,
+            };
+        }
+
+        let (stop_tx, stop_rx) = flume::bounded::<()>(1);
+        let thread = std::thread::spawn(move || {
+            let started = Instant::now();
+            let mut drawn = false;
+            for frame in FRAMES.iter().cycle() {
+                // The sender is dropped to stop the spinner.
+                if stop_rx.recv_timeout(FRAME_INTERVAL) != Err(flume::RecvTimeoutError::Timeout) {
+                    break;
+                }
+
+                eprint!("\r{frame} {message} ({}s)", started.elapsed().as_secs());
+                drawn = true;
+            }
+
+            if drawn {
+                eprint!("\r\x1b[K");
+            }
+        });
+
+        Self {
+            stop_tx: Some(stop_tx),
+            thread: Some(thread),
+        }
+    }
+
+    /// Stop the spinner and wait for it to be erased.
+    pub fn stop(&mut self) {
+        self.stop_tx.take();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// How long a run took, for `--stats`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RunStats {
+    /// The time until the first part of the response was written
+    pub first_token: Duration,
+    pub total: Duration,
+    /// The tokens in the response, if they could be counted
+    pub response_tokens: Option<usize>,
+}
+
+impl RunStats {
+    /// The response tokens per second while the response was streaming.
+    pub fn tokens_per_second(&self) -> Option<f64> {
+        let streaming = self.total.saturating_sub(self.first_token).as_secs_f64();
+        let tokens = self.response_tokens?;
+        (streaming > 0.0 && tokens > 0).then(|| tokens as f64 / streaming)
+    }
+}
+
+impl Display for RunStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "first token after {:.2}s, done after {:.2}s",
+            self.first_token.as_secs_f64(),
+            self.total.as_secs_f64()
+        )?;
+        if let Some(rate) = self.tokens_per_second() {
+            write!(f, ", {rate:.1} tokens/s")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stats() {
+        let stats = RunStats {
+            first_token: Duration::from_millis(1500),
+            total: Duration::from_millis(3500),
+            response_tokens: Some(100),
+        };
+        assert_eq!(stats.tokens_per_second(), Some(50.0));
+        assert_eq!(
+            stats.to_string(),
+            "first token after 1.50s, done after 3.50s, 50.0 tokens/s"
+        );
+
+        // A response that arrives all at once has no streaming rate.
+        let collected = RunStats {
+            first_token: Duration::from_secs(2),
+            total: Duration::from_secs(2),
+            response_tokens: Some(100),
+        };
+        assert_eq!(
+            collected.to_string(),
+            "first token after 2.00s, done after 2.00s"
+        );
+    }
+
+    #[test]
+    fn spinner_stops_before_drawing() {
+        // Stopping before the first frame shouldn't draw anything or block.
+        let mut spinner = Spinner::start("Waiting".to_string());
+        spinner.stop();
+        assert!(spinner.thread.is_none());
+    }
+}
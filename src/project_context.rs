@@ -0,0 +1,75 @@
+//! Project conventions that apply to every template. Templates with `project_context = true` get
+//! the contents of a `PROMPTBOX.md` or `.promptbox/context.md` file as the `project_context`
+//! variable.
+
+use std::path::{Path, PathBuf};
+
+use error_stack::{Report, ResultExt};
+
+use crate::error::Error;
+
+/// The files to look for in each directory, in order of preference.
+const CONTEXT_FILES: [&str; 2] = ["PROMPTBOX.md", ".promptbox/context.md"];
+
+#[derive(Debug)]
+pub struct ProjectContext {
+    pub path: PathBuf,
+    pub contents: String,
+}
+
+/// Find the project context file in `start_dir` or the closest parent directory that has one.
+pub fn find_project_context(start_dir: &Path) -> Option<PathBuf> {
+    start_dir.ancestors().find_map(|dir| {
+        CONTEXT_FILES
+            .iter()
+            .map(|file| dir.join(file))
+            .find(|path| path.is_file())
+    })
+}
+
+/// Read the project context file for `start_dir`, if there is one.
+pub fn load_project_context(start_dir: &Path) -> Result<Option<ProjectContext>, Report<Error>> {
+    let Some(path) = find_project_context(start_dir) else {
+        return Ok(None);
+    };
+
+    let contents = std::fs::read_to_string(&path)
+        .change_context(Error::Io)
+        .attach_printable_lazy(|| format!("Reading project context {}", path.display()))?;
+    Ok(Some(ProjectContext { path, contents }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn closest_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("src/module");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::create_dir_all(dir.path().join(".promptbox")).unwrap();
+        std::fs::write(dir.path().join(".promptbox/context.md"), "root rules").unwrap();
+
+        let context = load_project_context(&nested).unwrap().unwrap();
+        assert_eq!(context.path, dir.path().join(".promptbox/context.md"));
+        assert_eq!(context.contents, "root rules");
+
+        // A file closer to the start directory wins, and PROMPTBOX.md is preferred.
+        let src = dir.path().join("src");
+        std::fs::create_dir_all(src.join(".promptbox")).unwrap();
+        std::fs::write(src.join(".promptbox/context.md"), "src context").unwrap();
+        std::fs::write(src.join("PROMPTBOX.md"), "src rules").unwrap();
+        let context = load_project_context(&nested).unwrap().unwrap();
+        assert_eq!(context.contents, "src rules");
+    }
+
+    #[test]
+    fn no_file() {
+        let dir = tempfile::tempdir().unwrap();
+        // Don't pick up a file from a parent of the temporary directory.
+        if find_project_context(dir.path().parent().unwrap()).is_none() {
+            assert!(load_project_context(dir.path()).unwrap().is_none());
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Record which files a run's prompt came from, so that a response can be traced back to the
+//! exact template and configuration that produced it.
+
+use std::{
+    fmt::Display,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::response_cache::sha256_hex;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceKind {
+    /// A `.pb.toml` or `.pb.md` template file
+    Template,
+    /// A file read through the template's `template_path`
+    TemplateText,
+    /// A file read through the template's `system_prompt_path`
+    SystemPrompt,
+    /// A file read through the template's `response_schema_path`
+    ResponseSchema,
+    /// A `promptbox.toml` configuration file
+    Config,
+    /// A partial that the template includes
+    Partial,
+    /// A file read with the `file` function in the template
+    File,
+}
+
+impl Display for SourceKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Template => "template",
+            Self::TemplateText => "template text",
+            Self::SystemPrompt => "system prompt",
+            Self::ResponseSchema => "response schema",
+            Self::Config => "config",
+            Self::Partial => "partial",
+            Self::File => "file",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A file that was read to make the prompt, and the hash of its contents at the time
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SourceFile {
+    pub kind: SourceKind,
+    pub path: PathBuf,
+    pub sha256: String,
+}
+
+impl SourceFile {
+    pub fn new(kind: SourceKind, path: impl Into<PathBuf>, contents: &str) -> Self {
+        Self {
+            kind,
+            path: path.into(),
+            sha256: sha256_hex(contents.as_bytes()),
+        }
+    }
+}
+
+impl Display for SourceFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} (sha256:{})",
+            self.kind,
+            self.path.display(),
+            self.sha256
+        )
+    }
+}
+
+/// The files that were read while rendering the prompt, such as partials. Clones share the same
+/// list, so the files read by each render end up in one place.
+#[derive(Debug, Clone, Default)]
+pub struct ReadSources(Arc<Mutex<Vec<SourceFile>>>);
+
+impl ReadSources {
+    /// Add a file, unless a file with the same path and kind was already added.
+    pub fn record(&self, kind: SourceKind, path: impl Into<PathBuf>, contents: &str) {
+        let path = path.into();
+        let mut sources = self.0.lock().unwrap();
+        if !sources.iter().any(|s| s.kind == kind && s.path == path) {
+            sources.push(SourceFile::new(kind, path, contents));
+        }
+    }
+
+    pub fn files(&self) -> Vec<SourceFile> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Format the sources as numbered footnotes, one per line.
+pub fn footnotes(sources: &[SourceFile]) -> String {
+    sources
+        .iter()
+        .enumerate()
+        .map(|(i, source)| format!("[{}] {source}\n", i + 1))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn format_footnotes() {
+        let sources = [
+            SourceFile::new(
+                SourceKind::Template,
+                "/work/summarize.pb.toml",
+                "template = 'hi'",
+            ),
+            SourceFile::new(SourceKind::Config, "/work/promptbox.toml", ""),
+        ];
+        assert_eq!(sources[0].sha256.len(), 64);
+        assert_ne!(sources[0].sha256, sources[1].sha256);
+        assert_eq!(
+            footnotes(&sources),
+            format!(
+                "[1] template: /work/summarize.pb.toml (sha256:{})\n\
+                [2] config: /work/promptbox.toml (sha256:{})\n",
+                sources[0].sha256, sources[1].sha256
+            )
+        );
+    }
+}
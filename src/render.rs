@@ -0,0 +1,293 @@
+//! Style Markdown responses for the terminal as they stream in.
+
+use clap::ValueEnum;
+use regex::{Captures, Regex};
+
+const RESET_BOLD: &str = "\x1b[22m";
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const ITALIC: &str = "\x1b[3m";
+const RESET_ITALIC: &str = "\x1b[23m";
+const UNDERLINE: &str = "\x1b[4m";
+const RESET_UNDERLINE: &str = "\x1b[24m";
+const CYAN: &str = "\x1b[36m";
+const MAGENTA: &str = "\x1b[35m";
+const RESET_COLOR: &str = "\x1b[39m";
+const RESET: &str = "\x1b[0m";
+
+/// When to render the response as Markdown
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RenderMode {
+    /// Render when writing text to a terminal, unless `NO_COLOR` is set
+    #[default]
+    Auto,
+    /// Always render, even when the output is piped
+    Always,
+    /// Never render
+    Never,
+}
+
+#[cfg(feature = "highlight")]
+mod highlight {
+    use std::sync::OnceLock;
+
+    use syntect::{
+        easy::HighlightLines,
+        highlighting::{Theme, ThemeSet},
+        parsing::SyntaxSet,
+    };
+
+    fn syntaxes() -> &'static SyntaxSet {
+        static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+        SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+    }
+
+    fn theme() -> &'static Theme {
+        static THEME: OnceLock<Theme> = OnceLock::new();
+        THEME.get_or_init(|| {
+            let mut themes = ThemeSet::load_defaults().themes;
+            themes
+                .remove("base16-ocean.dark")
+                .expect("the default themes include base16-ocean.dark")
+        })
+    }
+
+    pub struct CodeHighlighter(HighlightLines<'static>);
+
+    impl CodeHighlighter {
+        /// Create a highlighter for the language from a code fence, if it's one that we know.
+        pub fn new(language: &str) -> Option<Self> {
+            let syntax = syntaxes().find_syntax_by_token(language)?;
+            Some(Self(HighlightLines::new(syntax, theme())))
+        }
+
+        pub fn highlight(&mut self, line: &str) -> Option<String> {
+            let with_newline = format!("{line}\n");
+            let ranges = self.0.highlight_line(&with_newline, syntaxes()).ok()?;
+            let escaped = syntect::util::as_24_bit_terminal_escaped(&ranges, false);
+            Some(format!(
+                "{}{}",
+                escaped.trim_end_matches('\n'),
+                super::RESET
+            ))
+        }
+    }
+}
+
+#[cfg(not(feature = "highlight"))]
+mod highlight {
+    /// Without the `highlight` feature, code is written without colors.
+    pub struct CodeHighlighter;
+
+    impl CodeHighlighter {
+        pub fn new(_language: &str) -> Option<Self> {
+            None
+        }
+
+        pub fn highlight(&mut self, _line: &str) -> Option<String> {
+            None
+        }
+    }
+}
+
+use highlight::CodeHighlighter;
+
+struct CodeBlock {
+    fence: String,
+    highlighter: Option<CodeHighlighter>,
+}
+
+/// Styles Markdown a line at a time. Text is held back until the end of its line, since the
+/// style of a line isn't known until it is complete.
+pub struct MarkdownRenderer {
+    pending: String,
+    code_block: Option<CodeBlock>,
+    fence: Regex,
+    heading: Regex,
+    bullet: Regex,
+    rule: Regex,
+    inline: Regex,
+}
+
+impl Default for MarkdownRenderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MarkdownRenderer {
+    pub fn new() -> Self {
+        Self {
+            pending: String::new(),
+            code_block: None,
+            fence: Regex::new(r"^\s*(```+|~~~+)\s*([\w+#.-]*)").unwrap(),
+            heading: Regex::new(r"^(#{1,6})\s+(.*)$").unwrap(),
+            bullet: Regex::new(r"^(\s*)[-*+]\s+").unwrap(),
+            rule: Regex::new(r"^\s*(\*\s*){3,}$|^\s*(-\s*){3,}$|^\s*(_\s*){3,}$").unwrap(),
+            inline: Regex::new(
+                r"`([^`]+)`|\*\*(.+?)\*\*|__(.+?)__|\*([^*\s][^*]*?)\*|\[([^\]]+)\]\(([^)\s]+)\)",
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Add text from the response, and return the styled text for any lines that it completed.
+    pub fn push(&mut self, text: &str) -> String {
+        self.pending.push_str(text);
+        let Some(end) = self.pending.rfind('\n') else {
+            return String::new();
+        };
+
+        let complete = self.pending[..end].to_string();
+        self.pending.drain(..=end);
+        complete
+            .split('\n')
+            .map(|line| format!("{}\n", self.render_line(line)))
+            .collect()
+    }
+
+    /// Return the styled text for the rest of the response.
+    pub fn finish(&mut self) -> String {
+        let rest = std::mem::take(&mut self.pending);
+        if rest.is_empty() {
+            String::new()
+        } else {
+            self.render_line(&rest)
+        }
+    }
+
+    fn render_line(&mut self, line: &str) -> String {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+
+        if let Some(captures) = self.fence.captures(line) {
+            let fence = &captures[1];
+            match self.code_block.as_ref() {
+                Some(block) if fence.starts_with(&block.fence) && captures[2].is_empty() => {
+                    self.code_block = None;
+                }
+                Some(_) => {}
+                None => {
+                    self.code_block = Some(CodeBlock {
+                        fence: fence.to_string(),
+                        highlighter: CodeHighlighter::new(&captures[2]),
+                    });
+                }
+            }
+            return format!("{DIM}{line}{RESET_BOLD}");
+        }
+
+        if let Some(block) = self.code_block.as_mut() {
+            return block
+                .highlighter
+                .as_mut()
+                .and_then(|h| h.highlight(line))
+                .unwrap_or_else(|| line.to_string());
+        }
+
+        if let Some(captures) = self.heading.captures(line) {
+            let color = if captures[1].len() <= 2 { MAGENTA } else { "" };
+            let text = self.render_inline(&captures[2]);
+            return format!("{BOLD}{color}{text}{RESET}");
+        }
+
+        if self.rule.is_match(line) {
+            return format!("{DIM}{}{RESET_BOLD}", "─".repeat(40));
+        }
+
+        if let Some(quoted) = line.strip_prefix('>') {
+            let text = self.render_inline(quoted.trim_start());
+            return format!("{DIM}│{RESET_BOLD} {ITALIC}{text}{RESET_ITALIC}");
+        }
+
+        let line = self.bullet.replace(line, "$1• ");
+        self.render_inline(&line)
+    }
+
+    fn render_inline(&self, text: &str) -> String {
+        self.inline
+            .replace_all(text, |c: &Captures| {
+                if let Some(code) = c.get(1) {
+                    format!("{CYAN}{}{RESET_COLOR}", code.as_str())
+                } else if let Some(bold) = c.get(2).or_else(|| c.get(3)) {
+                    format!("{BOLD}{}{RESET_BOLD}", bold.as_str())
+                } else if let Some(italic) = c.get(4) {
+                    format!("{ITALIC}{}{RESET_ITALIC}", italic.as_str())
+                } else {
+                    format!(
+                        "{UNDERLINE}{}{RESET_UNDERLINE} {DIM}({}){RESET_BOLD}",
+                        &c[5], &c[6]
+                    )
+                }
+            })
+            .into_owned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn strip_ansi(text: &str) -> String {
+        Regex::new("\x1b\\[[0-9;]*m")
+            .unwrap()
+            .replace_all(text, "")
+            .into_owned()
+    }
+
+    fn render(text: &str) -> String {
+        let mut renderer = MarkdownRenderer::new();
+        let mut output = renderer.push(text);
+        output.push_str(&renderer.finish());
+        output
+    }
+
+    #[test]
+    fn styles() {
+        assert_eq!(
+            render("# Title\n"),
+            format!("{BOLD}{MAGENTA}Title{RESET}\n")
+        );
+        assert_eq!(
+            render("Some **bold** and `code`"),
+            format!("Some {BOLD}bold{RESET_BOLD} and {CYAN}code{RESET_COLOR}")
+        );
+        assert_eq!(
+            strip_ansi(&render(
+                "- an *item* with a [link](https://example.com)\n> quoted\n---\n"
+            )),
+            format!(
+                "• an item with a link (https://example.com)\n│ quoted\n{}\n",
+                "─".repeat(40)
+            )
+        );
+    }
+
+    #[test]
+    fn code_blocks() {
+        let text = "```rust\nlet x = **y**;\n```\nafter **bold**\n";
+        let output = render(text);
+        assert_eq!(
+            strip_ansi(&output),
+            "```rust\nlet x = **y**;\n```\nafter bold\n"
+        );
+
+        let unknown = render("```not-a-language\n# not a heading\n```\n");
+        assert_eq!(
+            unknown,
+            format!("{DIM}```not-a-language{RESET_BOLD}\n# not a heading\n{DIM}```{RESET_BOLD}\n")
+        );
+    }
+
+    #[test]
+    fn streamed_in_pieces() {
+        let text = "# Heading\n\nSome **bold** text\n```python\nprint('hi')\n```\nThe end";
+        let mut renderer = MarkdownRenderer::new();
+        let mut output = String::new();
+        for piece in text.as_bytes().chunks(3) {
+            output.push_str(&renderer.push(std::str::from_utf8(piece).unwrap()));
+        }
+        output.push_str(&renderer.finish());
+        assert_eq!(output, render(text));
+        assert!(output.ends_with("The end"));
+    }
+}
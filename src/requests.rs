@@ -1,33 +1,371 @@
-use serde::Serialize;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    option::{overwrite_from_option, update_if_none},
+};
+
+const DEFAULT_RETRY_STATUS_CODES: [u16; 5] = [429, 500, 502, 503, 504];
+
+/// Control how failed requests are retried.
+#[derive(Debug, Clone)]
+pub struct RetryOptions {
+    /// The maximum number of times to retry a request.
+    pub attempts: u32,
+    /// The delay before the first retry. This doubles with each retry.
+    pub initial_delay: Duration,
+    /// The longest time to wait between retries
+    pub max_delay: Duration,
+    /// HTTP status codes which should be retried.
+    pub retry_on: Vec<u16>,
+}
+
+impl Default for RetryOptions {
+    fn default() -> Self {
+        Self {
+            attempts: 4,
+            initial_delay: Duration::from_millis(1000),
+            max_delay: Duration::from_millis(30000),
+            retry_on: DEFAULT_RETRY_STATUS_CODES.to_vec(),
+        }
+    }
+}
+
+impl From<RetryOptionsInput> for RetryOptions {
+    fn from(value: RetryOptionsInput) -> Self {
+        let mut options = Self::default();
+        options.update_from_input(&value);
+        options
+    }
+}
+
+impl RetryOptions {
+    pub fn update_from_input(&mut self, input: &RetryOptionsInput) {
+        overwrite_from_option(&mut self.attempts, &input.attempts);
+        overwrite_from_option(&mut self.retry_on, &input.retry_on);
+        if let Some(delay) = input.initial_delay_ms {
+            self.initial_delay = Duration::from_millis(delay);
+        }
+        if let Some(delay) = input.max_delay_ms {
+            self.max_delay = Duration::from_millis(delay);
+        }
+    }
+
+    /// How long to wait before retry number `try_num`, starting from 0.
+    fn delay(&self, try_num: u32) -> Duration {
+        let multiplier = 2u32.saturating_pow(try_num);
+        let delay = self.initial_delay.saturating_mul(multiplier);
+        std::cmp::min(delay, self.max_delay)
+    }
+
+    /// How long to wait before retry number `try_num`. A server's `retry_after` is followed, but
+    /// never past `max_delay`, so a misbehaving server can't stall the run.
+    fn retry_delay(&self, try_num: u32, retry_after: Option<Duration>) -> Duration {
+        match retry_after {
+            Some(delay) => delay.min(self.max_delay),
+            None => {
+                let perturb = Duration::from_millis(fastrand::u64(0..200));
+                self.delay(try_num) + perturb
+            }
+        }
+    }
+
+    fn should_retry(&self, err: &ureq::Error) -> bool {
+        match err {
+            ureq::Error::Status(code, _) => self.retry_on.contains(code),
+            // Timeouts and dropped connections. Failing to connect at all usually means that the
+            // host is not running, so that is not retried.
+            ureq::Error::Transport(t) => t.kind() == ureq::ErrorKind::Io,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, JsonSchema)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
+pub struct RetryOptionsInput {
+    /// The maximum number of times to retry a request.
+    pub attempts: Option<u32>,
+    /// The delay before the first retry, in milliseconds. This doubles with each retry.
+    pub initial_delay_ms: Option<u64>,
+    /// The longest time to wait between retries, in milliseconds
+    pub max_delay_ms: Option<u64>,
+    /// HTTP status codes which should be retried.
+    pub retry_on: Option<Vec<u16>>,
+}
+
+impl RetryOptionsInput {
+    pub fn merge_defaults(&mut self, other: &RetryOptionsInput) {
+        update_if_none(&mut self.attempts, &other.attempts);
+        update_if_none(&mut self.initial_delay_ms, &other.initial_delay_ms);
+        update_if_none(&mut self.max_delay_ms, &other.max_delay_ms);
+        update_if_none(&mut self.retry_on, &other.retry_on);
+    }
+}
 
 pub fn request_with_retry(
     req: ureq::Request,
     body: impl Serialize,
+    retry: &RetryOptions,
 ) -> Result<ureq::Response, ureq::Error> {
-    const MAX_TRIES: u32 = 4;
     let mut try_num = 0;
-    let delay = 1000;
     loop {
         let response = req.clone().send_json(&body);
-        match response {
+        let err = match response {
             Ok(res) => return Ok(res),
-            Err(ureq::Error::Status(code, response)) => {
-                if code != 429 || try_num > MAX_TRIES {
-                    return Err(ureq::Error::Status(code, response));
-                }
+            Err(e) => e,
+        };
 
-                // This is potentially retryable. We don't do anything smart right now, just a
-                // random exponential backoff.
+        if try_num >= retry.attempts || !retry.should_retry(&err) {
+            return Err(err);
+        }
 
-                let perturb = fastrand::i32(-100..100);
-                let this_delay = 2i32.pow(try_num) * delay + perturb;
+        // Respect the server's request if there is one, otherwise do a random exponential backoff.
+        let retry_after = match &err {
+            ureq::Error::Status(_, response) => response
+                .header("retry-after")
+                .and_then(|s| s.parse::<u64>().ok())
+                .map(Duration::from_secs),
+            _ => None,
+        };
 
-                eprintln!("Rate limited... waiting {this_delay}ms to retry");
-                std::thread::sleep(std::time::Duration::from_millis(this_delay as u64));
-                try_num += 1;
-            }
-            e @ Err(_) => return e,
+        let this_delay = retry.retry_delay(try_num, retry_after);
+
+        let reason = match &err {
+            ureq::Error::Status(code, _) => format!("Request failed with status {code}"),
+            ureq::Error::Transport(t) => format!("Request failed: {t}"),
+        };
+
+        eprintln!(
+            "{reason}... waiting {}ms to retry ({}/{})",
+            this_delay.as_millis(),
+            try_num + 1,
+            retry.attempts
+        );
+        std::thread::sleep(this_delay);
+        try_num += 1;
+    }
+}
+
+/// Settings for the HTTP agent used to talk to a host.
+#[derive(Debug, Clone, Default)]
+pub struct AgentOptions<'a> {
+    /// Give up if the host doesn't send any data for this long.
+    pub timeout: Option<Duration>,
+    /// Give up if connecting to the host takes longer than this.
+    pub connect_timeout: Option<Duration>,
+    /// The proxy to use. If not set, the standard proxy environment variables are used.
+    /// An empty string disables the proxy.
+    pub proxy: Option<&'a str>,
+    /// Trust the certificates in this PEM file, in addition to the built-in root certificates.
+    /// Defaults to the `SSL_CERT_FILE` environment variable.
+    pub ca_bundle: Option<&'a Path>,
+    /// Skip verification of TLS certificates.
+    pub accept_invalid_certs: bool,
+    /// Extra headers to add to every request. These replace any headers of the same name
+    /// that the host would otherwise send.
+    pub headers: Vec<(String, String)>,
+    /// The maximum number of idle connections to keep open for reuse.
+    pub max_idle_connections: Option<usize>,
+    /// The maximum number of idle connections to keep open to each host.
+    pub max_idle_connections_per_host: Option<usize>,
+}
+
+/// Create an HTTP agent for talking to `endpoint`. Every host should create its requests through
+/// an agent from this function so that timeouts, proxies, and TLS settings are applied consistently.
+pub fn create_agent(endpoint: &str, options: &AgentOptions) -> Result<ureq::Agent, Error> {
+    let mut builder = ureq::AgentBuilder::new();
+    if let Some(timeout) = options.timeout {
+        // This applies to each read rather than the whole request, so that a long streaming
+        // response isn't cut off as long as it keeps sending data.
+        builder = builder.timeout_read(timeout).timeout_write(timeout);
+    }
+    if let Some(timeout) = options.connect_timeout {
+        builder = builder.timeout_connect(timeout);
+    }
+    if let Some(max) = options.max_idle_connections {
+        builder = builder.max_idle_connections(max);
+    }
+    if let Some(max) = options.max_idle_connections_per_host {
+        builder = builder.max_idle_connections_per_host(max);
+    }
+
+    let proxy = match options.proxy {
+        Some(proxy) => Some(proxy.to_string()).filter(|p| !p.is_empty()),
+        None => proxy_from_env(endpoint, |var| std::env::var(var).ok()),
+    };
+    if let Some(proxy) = proxy {
+        let proxy = ureq::Proxy::new(&proxy)
+            .map_err(|e| Error::HttpClient(format!("Invalid proxy {proxy}: {e}")))?;
+        builder = builder.proxy(proxy);
+    }
+
+    let env_ca_bundle = std::env::var_os("SSL_CERT_FILE").map(PathBuf::from);
+    let ca_bundle = options.ca_bundle.or(env_ca_bundle.as_deref());
+    if ca_bundle.is_some() || options.accept_invalid_certs {
+        builder = builder.tls_config(tls_config(ca_bundle, options.accept_invalid_certs)?);
+    }
+
+    if !options.headers.is_empty() {
+        builder = builder.middleware(HeaderMiddleware(options.headers.clone()));
+    }
+
+    Ok(builder.build())
+}
+
+/// Add custom headers to every request from an agent.
+struct HeaderMiddleware(Vec<(String, String)>);
+
+impl ureq::Middleware for HeaderMiddleware {
+    fn handle(
+        &self,
+        mut request: ureq::Request,
+        next: ureq::MiddlewareNext,
+    ) -> Result<ureq::Response, ureq::Error> {
+        for (name, value) in &self.0 {
+            request = request.set(name, value);
         }
+        next.handle(request)
+    }
+}
+
+/// Replace `${VAR}` in a header value with the value of the environment variable `VAR`, so that
+/// secrets don't have to be written in the configuration file.
+pub fn expand_header_value(
+    value: &str,
+    get_env: impl Fn(&str) -> Option<String>,
+) -> Result<String, Error> {
+    let mut output = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| Error::HttpClient(format!("Unclosed ${{ in header value {value}")))?;
+        let var = &after[..end];
+        let var_value = get_env(var)
+            .ok_or_else(|| Error::HttpClient(format!("Environment variable {var} is not set")))?;
+        output.push_str(&var_value);
+        rest = &after[end + 1..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Find the proxy for `endpoint` from the standard environment variables, respecting `NO_PROXY`.
+fn proxy_from_env(endpoint: &str, get_env: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let get_env = |names: &[&str]| {
+        names
+            .iter()
+            .find_map(|name| get_env(name))
+            .filter(|v| !v.is_empty())
+    };
+
+    let host = endpoint_host(endpoint);
+    // Local servers like Ollama should never go through the proxy.
+    if matches!(host, "localhost" | "127.0.0.1" | "::1") {
+        return None;
+    }
+
+    if let Some(no_proxy) = get_env(&["NO_PROXY", "no_proxy"]) {
+        let excluded = no_proxy.split(',').map(|s| s.trim()).any(|entry| {
+            let entry = entry.trim_start_matches('.');
+            entry == "*"
+                || (!entry.is_empty() && (host == entry || host.ends_with(&format!(".{entry}"))))
+        });
+        if excluded {
+            return None;
+        }
+    }
+
+    if endpoint.starts_with("https://") {
+        get_env(&["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"])
+    } else {
+        get_env(&["HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy"])
+    }
+}
+
+/// Get the host name from an endpoint URL.
+fn endpoint_host(endpoint: &str) -> &str {
+    let without_scheme = endpoint
+        .split_once("://")
+        .map(|(_, rest)| rest)
+        .unwrap_or(endpoint);
+    let authority = without_scheme.split('/').next().unwrap_or_default();
+    let authority = authority
+        .rsplit_once('@')
+        .map(|(_, host)| host)
+        .unwrap_or(authority);
+
+    if let Some(ipv6) = authority.strip_prefix('[') {
+        return ipv6.split(']').next().unwrap_or_default();
+    }
+
+    authority.split(':').next().unwrap_or_default()
+}
+
+fn tls_config(
+    ca_bundle: Option<&Path>,
+    accept_invalid_certs: bool,
+) -> Result<Arc<rustls::ClientConfig>, Error> {
+    let builder = rustls::ClientConfig::builder().with_safe_defaults();
+
+    if accept_invalid_certs {
+        let config = builder
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate))
+            .with_no_client_auth();
+        return Ok(Arc::new(config));
+    }
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+
+    if let Some(path) = ca_bundle {
+        let read_error =
+            |e: std::io::Error| Error::HttpClient(format!("Reading {}: {e}", path.display()));
+        let file = std::fs::File::open(path).map_err(read_error)?;
+        let certs =
+            rustls_pemfile::certs(&mut std::io::BufReader::new(file)).map_err(read_error)?;
+        let (added, _) = roots.add_parsable_certificates(&certs);
+        if added == 0 {
+            return Err(Error::HttpClient(format!(
+                "No certificates found in {}",
+                path.display()
+            )));
+        }
+    }
+
+    let config = builder.with_root_certificates(roots).with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// A certificate verifier for self-hosted endpoints which don't have a valid certificate.
+struct AcceptAnyCertificate;
+
+impl rustls::client::ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
     }
 }
 
@@ -38,3 +376,183 @@ pub fn add_bearer_token(req: ureq::Request, token: &Option<String>) -> ureq::Req
         req
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn delay_backoff() {
+        let options = RetryOptions {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            ..Default::default()
+        };
+
+        assert_eq!(options.delay(0), Duration::from_millis(100));
+        assert_eq!(options.delay(1), Duration::from_millis(200));
+        assert_eq!(options.delay(3), Duration::from_millis(800));
+        assert_eq!(options.delay(4), Duration::from_millis(1000));
+        assert_eq!(options.delay(40), Duration::from_millis(1000));
+
+        let retry_after = |secs| options.retry_delay(0, Some(Duration::from_secs(secs)));
+        assert_eq!(retry_after(0), Duration::ZERO);
+        assert_eq!(retry_after(3600), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn update_from_input() {
+        let mut options = RetryOptions::default();
+        options.update_from_input(&RetryOptionsInput {
+            attempts: Some(2),
+            initial_delay_ms: Some(50),
+            max_delay_ms: None,
+            retry_on: Some(vec![503]),
+        });
+
+        assert_eq!(options.attempts, 2);
+        assert_eq!(options.initial_delay, Duration::from_millis(50));
+        assert_eq!(options.max_delay, RetryOptions::default().max_delay);
+        assert_eq!(options.retry_on, vec![503]);
+    }
+
+    #[test]
+    fn endpoint_hosts() {
+        assert_eq!(endpoint_host("https://api.openai.com/v1"), "api.openai.com");
+        assert_eq!(endpoint_host("http://localhost:11434"), "localhost");
+        assert_eq!(endpoint_host("http://user:pw@gpu1:8080/v1"), "gpu1");
+        assert_eq!(endpoint_host("http://[::1]:8080"), "::1");
+    }
+
+    #[test]
+    fn expand_header() {
+        let env = |name: &str| (name == "MY_KEY").then(|| "secret".to_string());
+        assert_eq!(
+            expand_header_value("Token ${MY_KEY}", env).unwrap(),
+            "Token secret"
+        );
+        assert_eq!(
+            expand_header_value("https://example.com", env).unwrap(),
+            "https://example.com"
+        );
+        expand_header_value("${OTHER_KEY}", env).expect_err("unset variable");
+        expand_header_value("${MY_KEY", env).expect_err("unclosed");
+    }
+
+    #[test]
+    fn custom_headers() {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let reader = BufReader::new(stream.try_clone().unwrap());
+            let headers = reader
+                .lines()
+                .map(|line| line.unwrap())
+                .take_while(|line| !line.is_empty())
+                .collect::<Vec<_>>();
+            (&stream)
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            headers
+        });
+
+        let agent = create_agent(
+            &endpoint,
+            &AgentOptions {
+                headers: vec![
+                    ("Authorization".to_string(), "Token abc".to_string()),
+                    ("X-Title".to_string(), "promptbox".to_string()),
+                ],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        add_bearer_token(agent.get(&endpoint), &Some("default".to_string()))
+            .call()
+            .unwrap();
+
+        let headers = server.join().unwrap();
+        assert!(headers.contains(&"Authorization: Token abc".to_string()));
+        assert!(!headers.contains(&"Authorization: Bearer default".to_string()));
+        assert!(headers.contains(&"X-Title: promptbox".to_string()));
+    }
+
+    mod proxy {
+        use super::super::proxy_from_env;
+
+        fn env<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<String> + 'a {
+            |name| {
+                vars.iter()
+                    .find(|(k, _)| *k == name)
+                    .map(|(_, v)| v.to_string())
+            }
+        }
+
+        #[test]
+        fn https_proxy() {
+            let vars = [
+                ("HTTPS_PROXY", "http://proxy:3128"),
+                ("HTTP_PROXY", "http://other:3128"),
+            ];
+            assert_eq!(
+                proxy_from_env("https://api.openai.com/v1", env(&vars)),
+                Some("http://proxy:3128".to_string())
+            );
+            assert_eq!(
+                proxy_from_env("http://gpu1:11434", env(&vars)),
+                Some("http://other:3128".to_string())
+            );
+        }
+
+        #[test]
+        fn no_proxy() {
+            let vars = [
+                ("HTTPS_PROXY", "http://proxy:3128"),
+                ("NO_PROXY", "internal.example.com, .corp"),
+            ];
+            assert_eq!(
+                proxy_from_env("https://llm.internal.example.com", env(&vars)),
+                None
+            );
+            assert_eq!(proxy_from_env("https://gpu.corp/v1", env(&vars)), None);
+            assert_eq!(
+                proxy_from_env("https://api.openai.com/v1", env(&vars)),
+                Some("http://proxy:3128".to_string())
+            );
+        }
+
+        #[test]
+        fn localhost_skips_proxy() {
+            let vars = [("ALL_PROXY", "http://proxy:3128")];
+            assert_eq!(proxy_from_env("http://localhost:11434", env(&vars)), None);
+        }
+    }
+
+    mod tls {
+        use super::super::tls_config;
+
+        #[test]
+        fn accept_invalid_certs() {
+            tls_config(None, true).expect("creating config");
+        }
+
+        #[test]
+        fn missing_ca_bundle() {
+            let path = std::path::Path::new("/nonexistent/ca.pem");
+            tls_config(Some(path), false).expect_err("should fail");
+        }
+
+        #[test]
+        fn ca_bundle_without_certs() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("ca.pem");
+            std::fs::write(&path, "not a certificate").unwrap();
+            tls_config(Some(&path), false).expect_err("should fail");
+        }
+    }
+}
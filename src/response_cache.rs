@@ -0,0 +1,414 @@
+//! Reuse the response to an identical deterministic request, so that running the same prompt again
+//! while working on a pipeline doesn't wait for the model or pay for it again.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use error_stack::Report;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    cache::Cache,
+    error::Error,
+    history::RunParameters,
+    hosts::ModelInput,
+    model::{ModelOptions, OutputFormat},
+    option::update_if_none,
+    session::ChatMessage,
+};
+
+/// How long cached responses are used when no TTL is configured.
+const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Deserialize, Debug, Clone, Default, JsonSchema)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct ResponseCacheInput {
+    /// Reuse the responses to identical requests with a temperature of 0. Defaults to false.
+    pub enabled: Option<bool>,
+    /// How long to use a cached response for, in seconds. Defaults to one day.
+    pub ttl_seconds: Option<u64>,
+}
+
+impl ResponseCacheInput {
+    /// For any members that are `None` in this `ResponseCacheInput`, use the value from `other`
+    pub fn merge_defaults(&mut self, other: &ResponseCacheInput) {
+        update_if_none(&mut self.enabled, &other.enabled);
+        update_if_none(&mut self.ttl_seconds, &other.ttl_seconds);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseCachePolicy {
+    pub enabled: bool,
+    pub ttl: Duration,
+}
+
+impl Default for ResponseCachePolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl: DEFAULT_TTL,
+        }
+    }
+}
+
+impl From<ResponseCacheInput> for ResponseCachePolicy {
+    fn from(value: ResponseCacheInput) -> Self {
+        Self {
+            enabled: value.enabled.unwrap_or(false),
+            ttl: value
+                .ttl_seconds
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_TTL),
+        }
+    }
+}
+
+/// Everything about a request that can change the response
+#[derive(Serialize)]
+struct CacheKey<'a> {
+    /// The host and the endpoints it sends requests to, since the same model name can be a
+    /// different model on another host.
+    host: String,
+    endpoints: Vec<&'a str>,
+    model: &'a str,
+    parameters: RunParameters,
+    format: Option<OutputFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_schema: Option<&'a serde_json::Value>,
+    system: Option<&'a str>,
+    history: &'a [ChatMessage],
+    prompt: &'a str,
+    /// Hashes of the images
+    images: Vec<String>,
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// The cache key for a request, or `None` if its response shouldn't be cached. Only requests with
+/// a temperature of 0 are cached, since other requests are expected to give different responses
+/// each time. Requests with tools are never cached, since calling the tools may have side effects.
+pub fn cache_key(options: &ModelOptions, input: &ModelInput) -> Option<String> {
+    if options.temperature != 0.0 || !input.tools.is_empty() {
+        return None;
+    }
+
+    let spec = options.full_model_spec();
+    let host = options.host_name();
+    let endpoints = match options.host.get(&host) {
+        Some(definition) if definition.endpoints.is_empty() => vec![definition.endpoint.as_str()],
+        Some(definition) => definition.endpoints.iter().map(String::as_str).collect(),
+        None => Vec::new(),
+    };
+    let key = CacheKey {
+        host,
+        endpoints,
+        model: spec.model_name(),
+        parameters: RunParameters::from(options),
+        format: options.format,
+        response_schema: options.response_schema.as_ref().map(|s| s.as_value()),
+        system: input.system,
+        history: input.history,
+        prompt: input.prompt,
+        images: input
+            .images
+            .iter()
+            .map(|image| sha256_hex(&image.contents))
+            .collect(),
+    };
+
+    let key = serde_json::to_vec(&key).ok()?;
+    Some(sha256_hex(&key))
+}
+
+fn filename(key: &str) -> String {
+    format!("responses/{key}.json")
+}
+
+/// A saved response, along with the request details needed to decide whether it can be reused
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CachedResponse {
+    pub timestamp: DateTime<Utc>,
+    pub template: String,
+    pub model: String,
+    pub temperature: f32,
+    pub response: String,
+}
+
+impl CachedResponse {
+    pub fn new(template: &str, options: &ModelOptions, response: &str) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            template: template.to_string(),
+            model: options.full_model_spec().model_name().to_string(),
+            temperature: options.temperature,
+            response: response.to_string(),
+        }
+    }
+
+    /// Whether this response can be used for a request with `options`. The model is already part
+    /// of the key, but this makes sure that a response is never reused for a different model or
+    /// from a request that wasn't deterministic.
+    pub fn reusable_for(&self, options: &ModelOptions) -> bool {
+        self.temperature == 0.0
+            && options.temperature == 0.0
+            && self.model == options.full_model_spec().model_name()
+    }
+
+    /// A single line describing the entry, for `cache ls`
+    pub fn summary(&self, key: &str, max_age: Duration) -> String {
+        let response = self
+            .response
+            .lines()
+            .find(|l| !l.trim().is_empty())
+            .unwrap_or("");
+        let response = match response.char_indices().nth(60) {
+            Some((index, _)) => format!("{}...", &response[..index]),
+            None => response.to_string(),
+        };
+        let expired = if self.is_expired(max_age) {
+            " (expired)"
+        } else {
+            ""
+        };
+
+        format!(
+            "{}\t{}{expired}\t{}\t{}\t{}",
+            &key[..key.len().min(12)],
+            self.timestamp.format("%Y-%m-%d %H:%M"),
+            self.template,
+            self.model,
+            response.trim()
+        )
+    }
+
+    pub fn is_expired(&self, max_age: Duration) -> bool {
+        let age = (Utc::now() - self.timestamp).to_std().unwrap_or_default();
+        age > max_age
+    }
+}
+
+/// Read the cached response for `key`, if there is one no older than `max_age` that can be used
+/// for a request with `options`.
+pub fn read(
+    cache: &Cache,
+    key: &str,
+    max_age: Duration,
+    options: &ModelOptions,
+) -> Result<Option<String>, Report<Error>> {
+    let entry = cache.read_cache::<serde_json::Value>(&filename(key), max_age)?;
+    // Entries saved by older versions only had the response, and are treated as missing.
+    let entry = entry.and_then(|entry| serde_json::from_value::<CachedResponse>(entry).ok());
+    Ok(entry
+        .filter(|entry| entry.reusable_for(options))
+        .map(|entry| entry.response))
+}
+
+pub fn write(cache: &Cache, key: &str, entry: &CachedResponse) -> Result<(), Report<Error>> {
+    cache.write_cache(&filename(key), entry)
+}
+
+/// Every cached response, newest first
+pub fn entries(cache: &Cache) -> Result<Vec<(String, CachedResponse)>, Report<Error>> {
+    let mut entries = cache
+        .list("responses")?
+        .into_iter()
+        .filter_map(|name| {
+            let key = name.strip_suffix(".json")?.to_string();
+            let entry = cache
+                .read_cache::<serde_json::Value>(&filename(&key), Duration::MAX)
+                .ok()??;
+            let entry = serde_json::from_value::<CachedResponse>(entry).ok()?;
+            Some((key, entry))
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.timestamp));
+    Ok(entries)
+}
+
+/// Find the cached response whose key starts with `prefix`.
+pub fn find(cache: &Cache, prefix: &str) -> Result<(String, CachedResponse), Report<Error>> {
+    let mut matches = entries(cache)?
+        .into_iter()
+        .filter(|(key, _)| key.starts_with(prefix))
+        .collect::<Vec<_>>();
+    match matches.len() {
+        0 => Err(Report::new(Error::CacheEntryNotFound(prefix.to_string()))),
+        1 => Ok(matches.remove(0)),
+        n => Err(
+            Report::new(Error::CacheEntryNotFound(prefix.to_string())).attach_printable(format!(
+                "{n} entries start with {prefix}, so give more of the key"
+            )),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{
+        events::RunEvents, model::ModelSpec, shutdown::CancellationToken, usage::UsageMeter,
+    };
+
+    #[test]
+    fn key_changes_with_request() {
+        let tools = HashMap::new();
+        let input = ModelInput {
+            prompt: "Summarize this",
+            system: Some("Be brief"),
+            history: &[],
+            images: Vec::new(),
+            tools: &tools,
+            cancel: &CancellationToken::new(),
+            usage: &UsageMeter::new(),
+            events: RunEvents::none(),
+        };
+        let options = ModelOptions::default();
+        let key = cache_key(&options, &input).unwrap();
+        assert_eq!(cache_key(&options, &input), Some(key.clone()));
+
+        let other_prompt = ModelInput {
+            prompt: "Summarize that",
+            images: Vec::new(),
+            ..input
+        };
+        assert_ne!(cache_key(&options, &other_prompt).unwrap(), key);
+
+        let other_options = ModelOptions {
+            max_tokens: Some(100),
+            ..Default::default()
+        };
+        assert_ne!(cache_key(&other_options, &input).unwrap(), key);
+
+        let mut other_endpoint = ModelOptions::default();
+        let host = other_endpoint.host_name();
+        other_endpoint.host.get_mut(&host).unwrap().endpoint = "http://localhost:9999".into();
+        assert_ne!(cache_key(&other_endpoint, &input).unwrap(), key);
+
+        let other_host = ModelOptions {
+            model: ModelSpec::Full {
+                model: options.full_model_spec().model_name().to_string(),
+                host: Some("ollama".to_string()),
+            },
+            ..Default::default()
+        };
+        assert_ne!(cache_key(&other_host, &input).unwrap(), key);
+    }
+
+    #[test]
+    fn only_deterministic_requests() {
+        let tools = HashMap::new();
+        let input = ModelInput {
+            prompt: "Write a poem",
+            system: None,
+            history: &[],
+            images: Vec::new(),
+            tools: &tools,
+            cancel: &CancellationToken::new(),
+            usage: &UsageMeter::new(),
+            events: RunEvents::none(),
+        };
+        let options = ModelOptions {
+            temperature: 0.7,
+            ..Default::default()
+        };
+        assert_eq!(cache_key(&options, &input), None);
+    }
+
+    #[test]
+    fn read_and_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::at(dir.path().to_path_buf());
+        let options = ModelOptions::default();
+        assert_eq!(read(&cache, "abc", DEFAULT_TTL, &options).unwrap(), None);
+
+        let entry = CachedResponse::new("summarize", &options, "A response");
+        write(&cache, "abc", &entry).unwrap();
+        assert_eq!(
+            read(&cache, "abc", DEFAULT_TTL, &options).unwrap(),
+            Some("A response".to_string())
+        );
+        assert_eq!(read(&cache, "abc", Duration::ZERO, &options).unwrap(), None);
+    }
+
+    #[test]
+    fn only_reused_for_same_model() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::at(dir.path().to_path_buf());
+        let options = ModelOptions::default();
+        write(
+            &cache,
+            "abc",
+            &CachedResponse::new("summarize", &options, "A response"),
+        )
+        .unwrap();
+
+        let other_model = ModelOptions {
+            model: ModelSpec::Plain("another-model".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            read(&cache, "abc", DEFAULT_TTL, &other_model).unwrap(),
+            None
+        );
+
+        let warm = ModelOptions {
+            temperature: 0.5,
+            ..Default::default()
+        };
+        assert_eq!(read(&cache, "abc", DEFAULT_TTL, &warm).unwrap(), None);
+
+        // Entries from older versions held only the response.
+        cache.write_cache(&filename("old"), "A response").unwrap();
+        assert_eq!(read(&cache, "old", DEFAULT_TTL, &options).unwrap(), None);
+    }
+
+    #[test]
+    fn list_and_find() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::at(dir.path().to_path_buf());
+        let options = ModelOptions::default();
+        let mut first = CachedResponse::new("summarize", &options, "First");
+        first.timestamp -= chrono::Duration::hours(2);
+        write(&cache, "abc123", &first).unwrap();
+        write(
+            &cache,
+            "abd456",
+            &CachedResponse::new("summarize", &options, "Second"),
+        )
+        .unwrap();
+
+        let entries = entries(&cache).unwrap();
+        let keys = entries
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec!["abd456", "abc123"]);
+        assert!(entries[1].1.is_expired(Duration::from_secs(60 * 60)));
+        assert!(!entries[0].1.is_expired(Duration::from_secs(60 * 60)));
+
+        let (key, entry) = find(&cache, "abc").unwrap();
+        assert_eq!(key, "abc123");
+        assert_eq!(entry.response, "First");
+
+        let err = find(&cache, "ab").unwrap_err();
+        assert!(matches!(
+            err.current_context(),
+            Error::CacheEntryNotFound(_)
+        ));
+        let err = find(&cache, "xyz").unwrap_err();
+        assert!(matches!(
+            err.current_context(),
+            Error::CacheEntryNotFound(_)
+        ));
+    }
+}
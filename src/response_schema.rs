@@ -0,0 +1,124 @@
+//! Ask the model for JSON that matches a schema, and check that the response does.
+
+use std::path::Path;
+
+use error_stack::{Report, ResultExt};
+use serde_json::Value;
+
+use crate::error::Error;
+
+/// A JSON Schema that the response must match
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseSchema(Value);
+
+impl ResponseSchema {
+    /// Make sure that `schema` is a valid JSON Schema.
+    pub fn new(schema: Value) -> Result<Self, Report<Error>> {
+        if let Err(e) = jsonschema::validator_for(&schema) {
+            return Err(Report::new(Error::InvalidResponseSchema)).attach_printable(e.to_string());
+        }
+
+        Ok(Self(schema))
+    }
+
+    /// Load the schema from a template's `response_schema`, which can be a table or a string of
+    /// JSON, such as the contents of the file from `response_schema_path`. `source` is the file
+    /// that the schema came from.
+    pub fn load(schema: Option<Value>, source: &Path) -> Result<Option<Self>, Report<Error>> {
+        let schema = match schema {
+            Some(Value::String(json)) => serde_json::from_str(&json)
+                .change_context(Error::InvalidResponseSchema)
+                .attach_printable_lazy(|| source.display().to_string())?,
+            Some(schema) => schema,
+            None => return Ok(None),
+        };
+
+        Self::new(schema)
+            .attach_printable_lazy(|| source.display().to_string())
+            .map(Some)
+    }
+
+    pub fn as_value(&self) -> &Value {
+        &self.0
+    }
+
+    /// Describe how `response` fails to match the schema, or return `None` if it matches.
+    pub fn validate(&self, response: &Value) -> Option<String> {
+        // The schema was checked when it was created, so this always succeeds.
+        let validator = jsonschema::validator_for(&self.0).ok()?;
+        let errors = validator
+            .iter_errors(response)
+            .map(|e| {
+                let path = e.instance_path.to_string();
+                if path.is_empty() {
+                    e.to_string()
+                } else {
+                    format!("{path}: {e}")
+                }
+            })
+            .collect::<Vec<_>>();
+
+        (!errors.is_empty()).then(|| errors.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn schema() -> ResponseSchema {
+        ResponseSchema::new(json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "tags": { "type": "array", "items": { "type": "string" } }
+            },
+            "required": ["name"]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn validate() {
+        let schema = schema();
+        assert_eq!(
+            schema.validate(&json!({ "name": "a", "tags": ["b"] })),
+            None
+        );
+
+        let problem = schema.validate(&json!({ "tags": ["b", 3] })).unwrap();
+        assert!(
+            problem.contains("\"name\" is a required property"),
+            "{problem}"
+        );
+        assert!(
+            problem.contains("/tags/1: 3 is not of type \"string\""),
+            "{problem}"
+        );
+    }
+
+    #[test]
+    fn load() {
+        let source = Path::new("extract.pb.toml");
+        let json = serde_json::to_string(schema().as_value()).unwrap();
+        let from_string = ResponseSchema::load(Some(Value::String(json)), source).unwrap();
+        assert_eq!(from_string, Some(schema()));
+
+        let table = ResponseSchema::load(Some(schema().as_value().clone()), source).unwrap();
+        assert_eq!(table, Some(schema()));
+
+        assert_eq!(ResponseSchema::load(None, source).unwrap(), None);
+        let err = ResponseSchema::load(Some(json!({ "type": 5 })), source).unwrap_err();
+        assert!(matches!(
+            err.current_context(),
+            Error::InvalidResponseSchema
+        ));
+        let err = ResponseSchema::load(Some(json!("{ not json")), source).unwrap_err();
+        assert!(matches!(
+            err.current_context(),
+            Error::InvalidResponseSchema
+        ));
+    }
+}
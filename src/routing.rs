@@ -0,0 +1,187 @@
+//! Choose a model based on the size of the prompt.
+
+use error_stack::{Report, ResultExt};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::{
+    error::Error,
+    model::{ModelChoice, ModelOptions},
+    option::{overwrite_from_option, update_if_none},
+    tokenizer::Tokenizer,
+};
+
+/// Whether a template should use the routing policy from the configuration.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RoutingMode {
+    /// Always use the model from the template or configuration.
+    #[default]
+    Off,
+    /// Choose the model based on the size of the prompt.
+    Auto,
+}
+
+const DEFAULT_THRESHOLD: usize = 4096;
+
+#[derive(Debug, Clone)]
+pub struct RoutingPolicy {
+    /// Prompts with fewer tokens than this use `small_model`.
+    pub threshold: usize,
+    /// A fast or cheap model for small prompts.
+    pub small_model: ModelChoice,
+    /// A model with a large context for everything else.
+    pub large_model: ModelChoice,
+}
+
+impl TryFrom<RoutingPolicyInput> for RoutingPolicy {
+    type Error = Error;
+
+    fn try_from(value: RoutingPolicyInput) -> Result<Self, Self::Error> {
+        let mut threshold = DEFAULT_THRESHOLD;
+        overwrite_from_option(&mut threshold, &value.threshold);
+
+        Ok(Self {
+            threshold,
+            small_model: value
+                .small_model
+                .ok_or(Error::MissingField("routing.small_model"))?,
+            large_model: value
+                .large_model
+                .ok_or(Error::MissingField("routing.large_model"))?,
+        })
+    }
+}
+
+impl RoutingPolicy {
+    /// Choose the model to use for a prompt with `token_count` tokens, along with a
+    /// description of why it was chosen.
+    pub fn choose(&self, token_count: usize) -> (&ModelChoice, String) {
+        if token_count < self.threshold {
+            (
+                &self.small_model,
+                format!(
+                    "Using small model since the prompt has {token_count} tokens, under the threshold of {}",
+                    self.threshold
+                ),
+            )
+        } else {
+            (
+                &self.large_model,
+                format!(
+                    "Using large model since the prompt has {token_count} tokens, at or above the threshold of {}",
+                    self.threshold
+                ),
+            )
+        }
+    }
+}
+
+/// Set the model in `model_options` according to the routing policy and the size of the prompt.
+/// Returns a description of which model was chosen and why.
+pub fn route_model(
+    policy: &RoutingPolicyInput,
+    model_options: &mut ModelOptions,
+    prompt: &str,
+    system: &str,
+) -> Result<String, Report<Error>> {
+    let policy = RoutingPolicy::try_from(policy.clone())
+        .change_context(Error::PreparePrompt)
+        .attach_printable(
+            "Templates with `routing = \"auto\"` need a `[routing]` section in the configuration",
+        )?;
+
+    let tokenizer =
+        Tokenizer::get(model_options.tokenizer.as_deref()).change_context(Error::PreparePrompt)?;
+    let mut token_count = tokenizer
+        .encode(prompt)
+        .change_context(Error::PreparePrompt)?
+        .len();
+    if !system.is_empty() {
+        token_count += tokenizer
+            .encode(system)
+            .change_context(Error::PreparePrompt)?
+            .len();
+    }
+
+    let (choice, reason) = policy.choose(token_count);
+    model_options.set_model_choice(choice.clone());
+
+    Ok(format!(
+        "{reason}: {}",
+        model_options.full_model_spec().model_name()
+    ))
+}
+
+#[derive(Deserialize, Debug, Clone, Default, JsonSchema)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct RoutingPolicyInput {
+    /// Prompts with fewer tokens than this use `small_model`.
+    pub threshold: Option<usize>,
+    /// A fast or cheap model for small prompts.
+    pub small_model: Option<ModelChoice>,
+    /// A model with a large context for everything else.
+    pub large_model: Option<ModelChoice>,
+}
+
+impl RoutingPolicyInput {
+    /// For any members that are `None` in this `RoutingPolicyInput`, use the value from `other`
+    pub fn merge_defaults(&mut self, other: &RoutingPolicyInput) {
+        update_if_none(&mut self.threshold, &other.threshold);
+        update_if_none(&mut self.small_model, &other.small_model);
+        update_if_none(&mut self.large_model, &other.large_model);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::ModelSpec;
+
+    fn policy() -> RoutingPolicy {
+        let input: RoutingPolicyInput = toml::from_str(
+            r##"
+            threshold = 100
+            small_model = "llama3.1"
+            large_model = { model = "gpt-4o", host = "openai" }
+            "##,
+        )
+        .unwrap();
+        RoutingPolicy::try_from(input).unwrap()
+    }
+
+    #[test]
+    fn small_prompt() {
+        let policy = policy();
+        let (choice, _) = policy.choose(99);
+        assert_eq!(
+            choice,
+            &ModelChoice::Single(ModelSpec::Plain("llama3.1".to_string()))
+        );
+    }
+
+    #[test]
+    fn large_prompt() {
+        let policy = policy();
+        let (choice, reason) = policy.choose(100);
+        assert_eq!(
+            choice,
+            &ModelChoice::Single(ModelSpec::Full {
+                model: "gpt-4o".to_string(),
+                host: Some("openai".to_string())
+            })
+        );
+        assert!(reason.contains("100 tokens"));
+    }
+
+    #[test]
+    fn requires_models() {
+        let input = RoutingPolicyInput {
+            threshold: Some(10),
+            small_model: Some(ModelChoice::from("a".to_string())),
+            large_model: None,
+        };
+        let err = RoutingPolicy::try_from(input).unwrap_err();
+        assert!(matches!(err, Error::MissingField("routing.large_model")));
+    }
+}
@@ -0,0 +1,56 @@
+//! JSON Schemas for the files that PromptBox reads, for editor autocomplete and validation.
+
+use schemars::{schema::RootSchema, schema_for};
+
+use crate::{args::SchemaKind, config::ConfigInput, template::PromptTemplate};
+
+pub fn schema(kind: SchemaKind) -> RootSchema {
+    match kind {
+        SchemaKind::Template => schema_for!(PromptTemplate),
+        SchemaKind::Config => schema_for!(ConfigInput),
+    }
+}
+
+pub fn schema_json(kind: SchemaKind) -> String {
+    // Serializing a schema can't fail.
+    serde_json::to_string_pretty(&schema(kind)).unwrap() + "\n"
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn template_schema() {
+        let schema = schema(SchemaKind::Template);
+        let properties = &schema.schema.object.as_ref().unwrap().properties;
+        assert!(properties.contains_key("template"));
+        assert!(properties.contains_key("model"));
+        assert!(properties.contains_key("options"));
+    }
+
+    #[test]
+    fn config_schema() {
+        let schema = schema(SchemaKind::Config);
+        let properties = &schema.schema.object.as_ref().unwrap().properties;
+        assert!(properties.contains_key("templates"));
+        assert!(properties.contains_key("host"));
+    }
+
+    /// Make sure that the published schemas are regenerated when the file formats change.
+    #[test]
+    fn published_schemas_are_current() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("schema");
+        for (kind, filename) in [
+            (SchemaKind::Template, "template.schema.json"),
+            (SchemaKind::Config, "config.schema.json"),
+        ] {
+            let published = std::fs::read_to_string(dir.join(filename)).unwrap_or_default();
+            assert!(
+                published == schema_json(kind),
+                "schema/{filename} is out of date. Update it with `promptbox schema {}`",
+                filename.split('.').next().unwrap()
+            );
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! The assembled prompt as typed segments instead of a single string, so that each part can be
+//! inspected and accounted for on its own.
+
+use crate::{
+    option_groups::option_value,
+    template::{OptionType, PromptOption},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PromptSegment {
+    /// The rendered system prompt
+    System { text: String },
+    /// Text from the template itself, including `--pre`, `--post`, and option values other than
+    /// files
+    Instructions { text: String },
+    /// The contents of a file passed to a file option
+    Context { file: String, text: String },
+    /// Extra arguments and stdin
+    Extra { text: String },
+}
+
+impl PromptSegment {
+    pub fn text(&self) -> &str {
+        match self {
+            PromptSegment::System { text }
+            | PromptSegment::Instructions { text }
+            | PromptSegment::Context { text, .. }
+            | PromptSegment::Extra { text } => text,
+        }
+    }
+
+    /// The name of the segment type, such as `context`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PromptSegment::System { .. } => "system",
+            PromptSegment::Instructions { .. } => "instructions",
+            PromptSegment::Context { .. } => "context",
+            PromptSegment::Extra { .. } => "extra",
+        }
+    }
+}
+
+/// The segments of a prompt, in order. The system prompt comes first if there is one, and the
+/// rest of the segments joined together are exactly the prompt.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PromptSegments(pub Vec<PromptSegment>);
+
+impl PromptSegments {
+    /// Split a rendered prompt into segments by finding each of `parts` in it. The text between
+    /// the parts becomes [PromptSegment::Instructions]. Parts that can't be found, for example
+    /// because they were truncated to fit the context, stay part of the instructions.
+    pub fn split(system: &str, prompt: &str, parts: Vec<PromptSegment>) -> Self {
+        let mut parts = parts
+            .into_iter()
+            .filter(|part| !part.text().is_empty())
+            .filter_map(|part| prompt.find(part.text()).map(|start| (start, part)))
+            .collect::<Vec<_>>();
+        parts.sort_by_key(|(start, _)| *start);
+
+        let mut segments = Vec::new();
+        if !system.is_empty() {
+            segments.push(PromptSegment::System {
+                text: system.to_string(),
+            });
+        }
+
+        let mut pos = 0;
+        for (_, part) in parts {
+            // Search again from the current position, since an earlier part may have contained
+            // the first occurrence.
+            let Some(start) = prompt[pos..].find(part.text()).map(|start| start + pos) else {
+                continue;
+            };
+
+            if start > pos {
+                segments.push(PromptSegment::Instructions {
+                    text: prompt[pos..start].to_string(),
+                });
+            }
+            pos = start + part.text().len();
+            segments.push(part);
+        }
+
+        if pos < prompt.len() {
+            segments.push(PromptSegment::Instructions {
+                text: prompt[pos..].to_string(),
+            });
+        }
+
+        Self(segments)
+    }
+
+    /// A short description of each segment and its size, such as `context a.txt (120 bytes)`
+    pub fn describe(&self) -> String {
+        self.0
+            .iter()
+            .map(|segment| match segment {
+                PromptSegment::Context { file, text } => {
+                    format!("context {file} ({} bytes)", text.len())
+                }
+                segment => format!("{} ({} bytes)", segment.kind(), segment.text().len()),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// The contents of each file passed to a file option, or page passed to a url option, from the
+/// template context.
+pub fn file_segments<'a>(
+    options: impl IntoIterator<Item = (&'a String, &'a PromptOption)>,
+    context: &serde_json::Value,
+) -> Vec<PromptSegment> {
+    let file_segment = |value: &serde_json::Value| {
+        Some(PromptSegment::Context {
+            file: value
+                .get("filename")
+                .or_else(|| value.get("url"))?
+                .as_str()?
+                .to_string(),
+            text: value.get("contents")?.as_str()?.to_string(),
+        })
+    };
+
+    options
+        .into_iter()
+        .filter(|(_, option)| matches!(option.option_type, OptionType::File | OptionType::Url))
+        .filter_map(|(name, _)| option_value(context, name))
+        .flat_map(|value| match value {
+            serde_json::Value::Array(values) => values.iter().filter_map(file_segment).collect(),
+            value => file_segment(value).into_iter().collect::<Vec<_>>(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn context(file: &str, text: &str) -> PromptSegment {
+        PromptSegment::Context {
+            file: file.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    fn instructions(text: &str) -> PromptSegment {
+        PromptSegment::Instructions {
+            text: text.to_string(),
+        }
+    }
+
+    fn extra(text: &str) -> PromptSegment {
+        PromptSegment::Extra {
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn split_prompt() {
+        let prompt = "Summarize these\n\nfile b\n\nfile a\n\nPlease";
+        let segments = PromptSegments::split(
+            "Be brief",
+            prompt,
+            vec![
+                context("a.txt", "file a"),
+                context("b.txt", "file b"),
+                extra("Please"),
+                context("missing.txt", "not in the prompt"),
+                extra(""),
+            ],
+        );
+
+        assert_eq!(
+            segments.0,
+            vec![
+                PromptSegment::System {
+                    text: "Be brief".to_string()
+                },
+                instructions("Summarize these\n\n"),
+                context("b.txt", "file b"),
+                instructions("\n\n"),
+                context("a.txt", "file a"),
+                instructions("\n\n"),
+                extra("Please"),
+            ]
+        );
+
+        assert_eq!(
+            segments.describe(),
+            "system (8 bytes), instructions (17 bytes), context b.txt (6 bytes), \
+            instructions (2 bytes), context a.txt (6 bytes), instructions (2 bytes), extra (6 bytes)"
+        );
+
+        let joined = segments.0[1..].iter().map(|s| s.text()).collect::<String>();
+        assert_eq!(joined, prompt);
+    }
+
+    #[test]
+    fn repeated_text() {
+        // The same contents twice each get their own occurrence.
+        let segments = PromptSegments::split(
+            "",
+            "same same",
+            vec![context("a.txt", "same"), context("b.txt", "same")],
+        );
+        assert_eq!(
+            segments.0,
+            vec![
+                context("a.txt", "same"),
+                instructions(" "),
+                context("b.txt", "same")
+            ]
+        );
+    }
+
+    #[test]
+    fn files_from_context() {
+        let options: HashMap<String, PromptOption> = toml::from_str(
+            r##"
+            file = { type = "file" }
+            files = { type = "file", array = true }
+            missing = { type = "file", optional = true }
+            topic = { type = "string" }
+            "##,
+        )
+        .unwrap();
+        let values = json!({
+            "file": { "filename": "a.txt", "contents": "A" },
+            "files": [
+                { "filename": "b.txt", "contents": "B" },
+                { "filename": "c.txt", "contents": "C" },
+            ],
+            "missing": null,
+            "topic": "fruit",
+        });
+
+        let mut segments = file_segments(&options, &values);
+        segments.sort_by(|a, b| a.text().cmp(b.text()));
+        assert_eq!(
+            segments,
+            vec![
+                context("a.txt", "A"),
+                context("b.txt", "B"),
+                context("c.txt", "C")
+            ]
+        );
+    }
+}
@@ -0,0 +1,268 @@
+//! Named conversations. Runs with `--session` send the earlier messages of the session along with
+//! the prompt, and then add the prompt and response to it.
+
+use std::path::PathBuf;
+
+use error_stack::{Report, ResultExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Error, history::data_dir};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+impl Role {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Role::User => "User",
+            Role::Assistant => "Assistant",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn user(content: impl Into<String>) -> Self {
+        Self {
+            role: Role::User,
+            content: content.into(),
+        }
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self {
+            role: Role::Assistant,
+            content: content.into(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct Session {
+    pub messages: Vec<ChatMessage>,
+}
+
+impl Session {
+    /// The messages as a readable transcript
+    pub fn transcript(&self) -> String {
+        self.messages
+            .iter()
+            .map(|m| format!("## {}\n\n{}\n", m.role.label(), m.content.trim_end()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// The directory of saved sessions, with one JSON file per session.
+#[derive(Debug)]
+pub struct SessionStore {
+    dir: PathBuf,
+}
+
+impl SessionStore {
+    pub fn new() -> Result<Self, Report<Error>> {
+        Ok(Self {
+            dir: data_dir()?.join("sessions"),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn at(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, Report<Error>> {
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'))
+            && !name.starts_with('.');
+        if !valid {
+            return Err(Report::new(Error::Session)).attach_printable(format!(
+                "Session names can only contain letters, numbers, `-`, `_`, and `.`: {name}"
+            ));
+        }
+
+        Ok(self.dir.join(format!("{name}.json")))
+    }
+
+    /// Read a session. A session which doesn't exist yet is empty.
+    pub fn load(&self, name: &str) -> Result<Session, Report<Error>> {
+        let path = self.path(name)?;
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Session::default()),
+            Err(e) => {
+                return Err(e)
+                    .change_context(Error::Session)
+                    .attach_printable_lazy(|| path.display().to_string())
+            }
+        };
+
+        serde_json::from_str(&contents)
+            .change_context(Error::Session)
+            .attach_printable_lazy(|| path.display().to_string())
+    }
+
+    /// Add messages to the end of a session, creating it if needed.
+    pub fn append(
+        &self,
+        name: &str,
+        messages: impl IntoIterator<Item = ChatMessage>,
+    ) -> Result<(), Report<Error>> {
+        let mut session = self.load(name)?;
+        session.messages.extend(messages);
+
+        let path = self.path(name)?;
+        let contents = serde_json::to_string_pretty(&session).change_context(Error::Session)?;
+        std::fs::create_dir_all(&self.dir)
+            .and_then(|_| std::fs::write(&path, contents))
+            .change_context(Error::Session)
+            .attach_printable_lazy(|| path.display().to_string())
+    }
+
+    /// The names of the saved sessions, with the number of messages in each.
+    pub fn list(&self) -> Result<Vec<(String, usize)>, Report<Error>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e)
+                    .change_context(Error::Session)
+                    .attach_printable_lazy(|| self.dir.display().to_string())
+            }
+        };
+
+        let mut names = entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let name = path.file_name()?.to_str()?.strip_suffix(".json")?;
+                Some(name.to_string())
+            })
+            .collect::<Vec<_>>();
+        names.sort();
+
+        names
+            .into_iter()
+            .map(|name| {
+                let count = self.load(&name)?.messages.len();
+                Ok((name, count))
+            })
+            .collect()
+    }
+
+    /// Delete a session.
+    pub fn clear(&self, name: &str) -> Result<(), Report<Error>> {
+        let path = self.path(name)?;
+        match std::fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(Report::new(Error::SessionNotFound(name.to_string())))
+            }
+            Err(e) => Err(e)
+                .change_context(Error::Session)
+                .attach_printable_lazy(|| path.display().to_string()),
+        }
+    }
+}
+
+/// Drop the oldest messages until the rest fit in `budget` tokens. The remaining messages always
+/// start with a user message.
+pub fn fit_to_budget(
+    mut messages: Vec<ChatMessage>,
+    budget: usize,
+    count_tokens: impl Fn(&str) -> Result<usize, Error>,
+) -> Result<Vec<ChatMessage>, Error> {
+    let sizes = messages
+        .iter()
+        .map(|m| count_tokens(&m.content))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut total = sizes.iter().sum::<usize>();
+    let mut start = 0;
+    while start < messages.len() && (total > budget || messages[start].role != Role::User) {
+        total -= sizes[start];
+        start += 1;
+    }
+
+    messages.drain(..start);
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn conversation() -> Vec<ChatMessage> {
+        vec![
+            ChatMessage::user("one two"),
+            ChatMessage::assistant("three"),
+            ChatMessage::user("four five six"),
+            ChatMessage::assistant("seven"),
+        ]
+    }
+
+    fn fit(budget: usize) -> Vec<ChatMessage> {
+        fit_to_budget(conversation(), budget, |s| Ok(s.split_whitespace().count())).unwrap()
+    }
+
+    #[test]
+    fn fit_messages() {
+        assert_eq!(fit(100), conversation());
+        assert_eq!(fit(7), conversation());
+        // Dropping the first user message also drops the reply to it.
+        assert_eq!(fit(6), conversation()[2..]);
+        assert_eq!(fit(4), conversation()[2..]);
+        assert_eq!(fit(3), Vec::<ChatMessage>::new());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SessionStore::at(dir.path().join("sessions"));
+        assert_eq!(store.load("proj").unwrap(), Session::default());
+        assert_eq!(store.list().unwrap(), vec![]);
+
+        store
+            .append(
+                "proj",
+                [ChatMessage::user("hi"), ChatMessage::assistant("hello")],
+            )
+            .unwrap();
+        store.append("proj", [ChatMessage::user("more")]).unwrap();
+        store.append("other", [ChatMessage::user("x")]).unwrap();
+
+        let session = store.load("proj").unwrap();
+        assert_eq!(session.messages.len(), 3);
+        assert_eq!(
+            session.transcript(),
+            "## User\n\nhi\n\n## Assistant\n\nhello\n\n## User\n\nmore\n"
+        );
+        assert_eq!(
+            store.list().unwrap(),
+            vec![("other".to_string(), 1), ("proj".to_string(), 3)]
+        );
+
+        store.clear("proj").unwrap();
+        assert_eq!(store.load("proj").unwrap(), Session::default());
+        let err = store.clear("proj").unwrap_err();
+        assert!(matches!(err.current_context(), Error::SessionNotFound(_)));
+    }
+
+    #[test]
+    fn invalid_names() {
+        let store = SessionStore::at(PathBuf::from("/nonexistent"));
+        for name in ["", "../x", "a/b", ".hidden"] {
+            assert!(store.load(name).is_err(), "{name}");
+        }
+    }
+}
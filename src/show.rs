@@ -0,0 +1,198 @@
+//! Describe a template after the configuration has been applied.
+
+use std::fmt::Write;
+
+use error_stack::Report;
+
+use crate::{config::Config, error::Error, routing::RoutingMode, template::ParsedTemplate};
+
+fn model_lines(config: &Config, template: &ParsedTemplate) -> Vec<(&'static str, String)> {
+    let mut options = config.model.clone();
+    options.update_from_model_input(&template.input.model);
+
+    let mut lines = vec![
+        ("model", options.full_model_spec().model_name().to_string()),
+        ("host", options.host_name()),
+        ("temperature", options.temperature.to_string()),
+    ];
+
+    let optional = [
+        ("top_p", options.top_p.map(|v| v.to_string())),
+        ("top_k", options.top_k.map(|v| v.to_string())),
+        (
+            "frequency_penalty",
+            options.frequency_penalty.map(|v| v.to_string()),
+        ),
+        (
+            "presence_penalty",
+            options.presence_penalty.map(|v| v.to_string()),
+        ),
+        ("max_tokens", options.max_tokens.map(|v| v.to_string())),
+        ("seed", options.seed.map(|v| v.to_string())),
+        (
+            "logit_bias",
+            (!options.logit_bias.is_empty()).then(|| format!("{:?}", options.logit_bias)),
+        ),
+        ("user", options.user.clone()),
+        ("format", options.format.map(|_| "json".to_string())),
+        (
+            "stop",
+            (!options.stop.is_empty()).then(|| format!("{:?}", options.stop)),
+        ),
+        (
+            "fallback",
+            (!options.fallback.is_empty()).then(|| {
+                options
+                    .fallback
+                    .iter()
+                    .map(|f| f.model.model_name())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            }),
+        ),
+        ("tokenizer", options.tokenizer.clone()),
+        (
+            "context_limit",
+            options.context.limit.map(|v| v.to_string()),
+        ),
+        (
+            "routing",
+            (template.input.routing == RoutingMode::Auto).then(|| "auto".to_string()),
+        ),
+    ];
+
+    lines.extend(
+        optional
+            .into_iter()
+            .filter_map(|(name, value)| value.map(|v| (name, v))),
+    );
+    lines
+}
+
+/// Describe the template that `promptbox run` would use for `name`: where it was found, the
+/// model options after merging the configuration, its options, and the raw template text.
+pub fn describe_template(config: &Config, name: &str) -> Result<String, Report<Error>> {
+    let template = config.find_template(name)?;
+    let mut output = String::new();
+
+    writeln!(output, "{}", template.name).ok();
+    if !template.input.description.is_empty() {
+        writeln!(output, "{}", template.input.description).ok();
+    }
+    writeln!(output, "\nPath: {}", template.source.display()).ok();
+    if template.path != template.source {
+        writeln!(output, "Template path: {}", template.path.display()).ok();
+    }
+    if let Some((system_path, _)) = template
+        .system
+        .as_ref()
+        .filter(|(path, _)| *path != template.source)
+    {
+        writeln!(output, "System prompt path: {}", system_path.display()).ok();
+    }
+
+    writeln!(output, "\nModel:").ok();
+    for (name, value) in model_lines(config, &template) {
+        writeln!(output, "  {name}: {value}").ok();
+    }
+
+    writeln!(output, "\nOptions:").ok();
+    let mut options = template.input.options.iter().collect::<Vec<_>>();
+    options.sort_by(|a, b| a.0.cmp(b.0));
+    if options.is_empty() {
+        writeln!(output, "  (none)").ok();
+    }
+    for (name, option) in options {
+        let mut option_type = option.option_type.to_string();
+        if option.array {
+            option_type.push_str("[]");
+        }
+
+        let mut details = Vec::new();
+        if option.required() {
+            details.push("required".to_string());
+        }
+        if let Some(default) = option.default.as_ref() {
+            details.push(format!("default: {default}"));
+        }
+
+        let mut line = format!("  --{name} <{option_type}>");
+        if !details.is_empty() {
+            write!(line, " ({})", details.join(", ")).ok();
+        }
+        if !option.description.is_empty() {
+            write!(line, "  {}", option.description).ok();
+        }
+        writeln!(output, "{line}").ok();
+    }
+
+    if let Some((_, system)) = template.system.as_ref() {
+        writeln!(output, "\nSystem prompt:\n{}", system.trim_end()).ok();
+    }
+    writeln!(output, "\nTemplate:\n{}", template.template.trim_end()).ok();
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::base_dir;
+
+    #[test]
+    fn describe() {
+        let config = Config::from_directory(base_dir("")).unwrap();
+        let output = describe_template(&config, "help_text").unwrap();
+
+        let expected_path = base_dir("help_text.pb.toml");
+        assert_eq!(
+            output,
+            format!(
+                r##"help_text
+Summarize some text
+
+Path: {}
+
+Model:
+  model: gpt-3.5-turbo
+  host: openai
+  temperature: 0.4
+  top_p: 2
+
+Options:
+  --style <string> (default: "concise")  The style of the summary
+
+Template:
+Summarize this in a {{{{style}}}} style
+"##,
+                expected_path.display()
+            )
+        );
+    }
+
+    #[test]
+    fn required_options_and_template_path() {
+        let config = Config::from_directory(base_dir("")).unwrap();
+        let output = describe_template(&config, "normal").unwrap();
+        let lines = output.lines().collect::<Vec<_>>();
+        for expected in [
+            "  --arrayopt <string[]>  this is an array",
+            "  --boolopt <bool>",
+            "  --defaultvalue <number> (default: 10)",
+            "  --fileopt <file> (required)",
+            "  --stringopt <string> (required)",
+        ] {
+            assert!(lines.contains(&expected), "Missing line {expected}");
+        }
+
+        let output = describe_template(&config, "template_path").unwrap();
+
+        assert!(output.contains(&format!(
+            "Template path: {}",
+            base_dir("template_path.liquid").display()
+        )));
+
+        let err = describe_template(&config, "does_not_exist").unwrap_err();
+        assert!(matches!(err.current_context(), Error::TemplateNotFound));
+    }
+}
@@ -0,0 +1,64 @@
+//! Stop all the work for a run together. The threads writing output, the requests to the model,
+//! the tools that the model calls, and batch workers share a [CancellationToken], so that an error
+//! in one of them or a Ctrl-C from the user winds down the rest instead of leaving them running.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crate::interrupt;
+
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask everything that shares this token to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true if the token was cancelled or the user stopped the response with Ctrl-C.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst) || interrupt::interrupted()
+    }
+
+    /// Run `f` and cancel the token if it fails, so that the work which depends on it stops early.
+    pub fn cancel_on_error<T, E>(&self, f: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+        let result = f();
+        if result.is_err() {
+            self.cancel();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shared_between_clones() {
+        let token = CancellationToken::new();
+        let other = token.clone();
+        assert!(!other.is_cancelled());
+
+        token.cancel();
+        assert!(other.is_cancelled());
+        assert!(!CancellationToken::new().is_cancelled());
+    }
+
+    #[test]
+    fn cancel_on_error() {
+        let token = CancellationToken::new();
+        token.cancel_on_error(|| Ok::<_, ()>(())).unwrap();
+        assert!(!token.is_cancelled());
+
+        token.cancel_on_error(|| Err::<(), _>(())).unwrap_err();
+        assert!(token.is_cancelled());
+    }
+}
@@ -0,0 +1,154 @@
+//! Rewrite the response as it streams out, for small fixes that would otherwise need the output to
+//! be piped through `sed`.
+
+use error_stack::Report;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::error::Error;
+
+/// A rule from a template's `output.stream_filters`
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct StreamFilterRule {
+    /// A regular expression to find in each line of the response
+    pub pattern: String,
+    /// The text to replace each match with. This can refer to capture groups as `$1` or
+    /// `${name}`. Defaults to removing the match.
+    #[serde(default)]
+    pub replace: String,
+}
+
+/// The compiled rules from a template's `output.stream_filters`, applied in order
+#[derive(Debug, Clone, Default)]
+pub struct StreamFilters(Vec<(Regex, String)>);
+
+impl StreamFilters {
+    /// Compile each rule, so that mistakes in a template are found before the prompt is sent.
+    pub fn new(rules: &[StreamFilterRule]) -> Result<Self, Report<Error>> {
+        let filters = rules
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                Regex::new(&rule.pattern)
+                    .map(|regex| (regex, rule.replace.clone()))
+                    .map_err(|e| {
+                        Report::new(Error::StreamFilter)
+                            .attach_printable(format!("Rule {}: {e}", i + 1))
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self(filters))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Run each rule over some complete text.
+    pub fn apply(&self, text: &str) -> String {
+        text.split_inclusive('\n')
+            .map(|line| self.apply_line(line))
+            .collect()
+    }
+
+    fn apply_line(&self, line: &str) -> String {
+        let (line, newline) = match line.strip_suffix('\n') {
+            Some(line) => (line, "\n"),
+            None => (line, ""),
+        };
+
+        let mut line = line.to_string();
+        for (regex, replace) in &self.0 {
+            line = regex.replace_all(&line, replace.as_str()).into_owned();
+        }
+        line.push_str(newline);
+        line
+    }
+
+    /// Start filtering a streamed response.
+    pub fn stream(&self) -> FilteredStream<'_> {
+        FilteredStream {
+            filters: self,
+            pending: String::new(),
+        }
+    }
+}
+
+/// Filters a response as it arrives. Rules match within a single line, so text is only held back
+/// until the end of its line.
+pub struct FilteredStream<'a> {
+    filters: &'a StreamFilters,
+    pending: String,
+}
+
+impl FilteredStream<'_> {
+    /// Add text from the response, and return the filtered text for any lines that it completed.
+    pub fn push(&mut self, text: &str) -> String {
+        self.pending.push_str(text);
+        let Some(end) = self.pending.rfind('\n') else {
+            return String::new();
+        };
+
+        let complete = self.pending.drain(..=end).collect::<String>();
+        self.filters.apply(&complete)
+    }
+
+    /// Return the filtered text for the rest of the response.
+    pub fn finish(&mut self) -> String {
+        let rest = std::mem::take(&mut self.pending);
+        self.filters.apply(&rest)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Output {
+        stream_filters: Vec<StreamFilterRule>,
+    }
+
+    fn filters(toml: &str) -> StreamFilters {
+        StreamFilters::new(&toml::from_str::<Output>(toml).unwrap().stream_filters).unwrap()
+    }
+
+    #[test]
+    fn rules_in_order() {
+        let filters = filters(
+            r#"stream_filters = [
+                { pattern = "[“”]", replace = '"' },
+                { pattern = "/home/(\\w+)", replace = "~$1" },
+                { pattern = "\\s*🚀" },
+            ]"#,
+        );
+        assert_eq!(
+            filters.apply("“Done” 🚀\nSee /home/alice/notes.md\n"),
+            "\"Done\"\nSee ~alice/notes.md\n"
+        );
+    }
+
+    #[test]
+    fn streamed_in_pieces() {
+        let filters = filters(r#"stream_filters = [{ pattern = "colou?r", replace = "hue" }]"#);
+        let text = "One colour\nand another color\nat the end: colour";
+        let mut stream = filters.stream();
+        let mut output = String::new();
+        for piece in text.as_bytes().chunks(3) {
+            output.push_str(&stream.push(std::str::from_utf8(piece).unwrap()));
+        }
+        output.push_str(&stream.finish());
+        assert_eq!(output, "One hue\nand another hue\nat the end: hue");
+    }
+
+    #[test]
+    fn invalid_rule() {
+        let rules = toml::from_str::<Output>(r#"stream_filters = [{ pattern = "(" }]"#)
+            .unwrap()
+            .stream_filters;
+        let err = StreamFilters::new(&rules).unwrap_err();
+        assert!(matches!(err.current_context(), Error::StreamFilter));
+    }
+}
@@ -0,0 +1,219 @@
+//! Parse the values of the `json`, `yaml`, and `csv` option types, and stdin with
+//! `--stdin-format`, so templates can use the structure instead of the raw text.
+
+use std::path::Path;
+
+use clap::ValueEnum;
+use error_stack::{Report, ResultExt};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::{error::Error, template::OptionType};
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, ValueEnum, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DataFormat {
+    Json,
+    Yaml,
+    /// CSV with a header row, parsed into an array with an object for each row
+    Csv,
+    /// An array of the lines that aren't empty
+    Lines,
+}
+
+impl DataFormat {
+    fn for_option(option_type: OptionType) -> Option<Self> {
+        match option_type {
+            OptionType::Json => Some(Self::Json),
+            OptionType::Yaml => Some(Self::Yaml),
+            OptionType::Csv => Some(Self::Csv),
+            _ => None,
+        }
+    }
+
+    /// The value to use when there is no data: an empty array for the formats that always parse
+    /// into one, and null for the others.
+    pub fn empty_value(self) -> serde_json::Value {
+        match self {
+            Self::Json | Self::Yaml => serde_json::Value::Null,
+            Self::Csv | Self::Lines => serde_json::Value::Array(vec![]),
+        }
+    }
+
+    /// Parse the text, or describe why it doesn't parse.
+    pub fn parse(self, text: &str) -> Result<serde_json::Value, String> {
+        match self {
+            Self::Json => serde_json::from_str(text).map_err(|e| e.to_string()),
+            Self::Yaml => serde_yaml::from_str(text).map_err(|e| e.to_string()),
+            Self::Csv => parse_csv(text),
+            Self::Lines => Ok(text
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .collect::<Vec<_>>()
+                .into()),
+        }
+    }
+}
+
+impl std::fmt::Display for DataFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Json => "json",
+            Self::Yaml => "yaml",
+            Self::Csv => "csv",
+            Self::Lines => "lines",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Parse an option value, which can be the path to a file or the data itself.
+pub fn parse_value(
+    option_type: OptionType,
+    base_dir: &Path,
+    value: &str,
+) -> Result<serde_json::Value, Report<Error>> {
+    let path = base_dir.join(value);
+    let text = if path.is_file() {
+        std::fs::read_to_string(&path)
+            .change_context(Error::Io)
+            .attach_printable_lazy(|| format!("Could not read file: {value}"))?
+    } else {
+        value.to_string()
+    };
+
+    let Some(format) = DataFormat::for_option(option_type) else {
+        return Ok(serde_json::Value::from(text));
+    };
+    format.parse(&text).map_err(|e| {
+        Report::new(Error::ArgParseFailure).attach_printable(format!("Invalid {format}: {e}"))
+    })
+}
+
+/// Parse CSV with a header row into an array with an object for each row, keyed by the column
+/// names. Rows with fewer fields than the header get empty strings for the rest.
+fn parse_csv(text: &str) -> Result<serde_json::Value, String> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    field.push('"');
+                    chars.next();
+                }
+                '"' => in_quotes = false,
+                c => field.push(c),
+            }
+            continue;
+        }
+
+        match c {
+            '"' if field.is_empty() => in_quotes = true,
+            ',' => row.push(std::mem::take(&mut field)),
+            '\r' => {}
+            '\n' => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            c => field.push(c),
+        }
+    }
+
+    if in_quotes {
+        return Err("A quoted field is not closed".to_string());
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+
+    let mut rows = rows
+        .into_iter()
+        .filter(|row| !(row.len() == 1 && row[0].is_empty()));
+    let Some(header) = rows.next() else {
+        return Ok(serde_json::Value::Array(vec![]));
+    };
+
+    rows.enumerate()
+        .map(|(index, row)| {
+            if row.len() > header.len() {
+                return Err(format!(
+                    "Row {} has {} fields, but the header has {}",
+                    index + 1,
+                    row.len(),
+                    header.len()
+                ));
+            }
+
+            let mut row = row.into_iter();
+            let object = header
+                .iter()
+                .map(|column| {
+                    let value = row.next().unwrap_or_default();
+                    (column.clone(), serde_json::Value::from(value))
+                })
+                .collect::<serde_json::Map<_, _>>();
+            Ok(serde_json::Value::Object(object))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(serde_json::Value::Array)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn csv() {
+        let text = "name,notes\r\nSam,\"Likes \"\"tea\"\", coffee\"\n\nAlex,\"Two\nlines\"\nJo\n";
+        assert_eq!(
+            parse_csv(text).unwrap(),
+            json!([
+                { "name": "Sam", "notes": "Likes \"tea\", coffee" },
+                { "name": "Alex", "notes": "Two\nlines" },
+                { "name": "Jo", "notes": "" },
+            ])
+        );
+
+        assert_eq!(parse_csv("").unwrap(), json!([]));
+        assert!(parse_csv("a\n\"open").is_err());
+        assert_eq!(
+            parse_csv("a\n1,2").unwrap_err(),
+            "Row 1 has 2 fields, but the header has 1"
+        );
+    }
+
+    #[test]
+    fn file_or_text() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("data.yaml"), "items:\n  - a\n  - b\n").unwrap();
+
+        assert_eq!(
+            parse_value(OptionType::Yaml, dir.path(), "data.yaml").unwrap(),
+            json!({ "items": ["a", "b"] })
+        );
+        assert_eq!(
+            parse_value(OptionType::Json, dir.path(), r#"{"count": 2}"#).unwrap(),
+            json!({ "count": 2 })
+        );
+
+        let err = parse_value(OptionType::Json, dir.path(), "{").unwrap_err();
+        assert!(format!("{err:?}").contains("Invalid json"));
+    }
+
+    #[test]
+    fn lines() {
+        assert_eq!(
+            DataFormat::Lines.parse("pod-a\n\npod-b\n").unwrap(),
+            json!(["pod-a", "pod-b"])
+        );
+        assert_eq!(DataFormat::Lines.empty_value(), json!([]));
+        assert_eq!(DataFormat::Json.empty_value(), json!(null));
+    }
+}
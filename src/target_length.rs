@@ -0,0 +1,166 @@
+//! Ask for a response of about a certain length, so that templates don't each need their own
+//! wording for it, and check that the response came close.
+
+use std::{fmt::Display, str::FromStr};
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LengthUnit {
+    Words,
+    Characters,
+}
+
+/// How long the response should be, such as `300w` or `2000c`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct TargetLength {
+    pub count: usize,
+    pub unit: LengthUnit,
+}
+
+impl FromStr for TargetLength {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = s.trim();
+        let split = value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len());
+        let (count, unit) = value.split_at(split);
+        let unit = match unit.trim() {
+            "" | "w" | "word" | "words" => Some(LengthUnit::Words),
+            "c" | "char" | "chars" | "characters" => Some(LengthUnit::Characters),
+            _ => None,
+        };
+
+        match (count.parse::<usize>(), unit) {
+            (Ok(count), Some(unit)) if count > 0 => Ok(Self { count, unit }),
+            _ => Err(format!(
+                "`{value}` should be a number of words or characters, such as 300w or 2000c"
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for TargetLength {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for TargetLength {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.unit {
+            LengthUnit::Words => write!(f, "{} words", self.count),
+            LengthUnit::Characters => write!(f, "{} characters", self.count),
+        }
+    }
+}
+
+impl TargetLength {
+    /// Add the instruction to the end of the prompt.
+    pub fn add_instruction(&self, prompt: String) -> String {
+        format!("{prompt}\n\nThe response should be about {self} long.")
+    }
+
+    /// A `max_tokens` with room to go somewhat over the target, so that a response which is a
+    /// little long isn't cut off.
+    pub fn max_tokens(&self) -> u32 {
+        // Words average a bit more than one token, and tokens about four characters.
+        let tokens = match self.unit {
+            LengthUnit::Words => self.count * 2,
+            LengthUnit::Characters => self.count / 2,
+        };
+        u32::try_from(tokens + 64).unwrap_or(u32::MAX)
+    }
+
+    pub fn measure(&self, text: &str) -> usize {
+        match self.unit {
+            LengthUnit::Words => text.split_whitespace().count(),
+            LengthUnit::Characters => text.trim().chars().count(),
+        }
+    }
+
+    /// Returns a description of the problem if the response is less than half or more than one
+    /// and a half times the target.
+    pub fn check(&self, response: &str) -> Result<(), String> {
+        let length = self.measure(response);
+        let unit = match self.unit {
+            LengthUnit::Words => "words",
+            LengthUnit::Characters => "characters",
+        };
+
+        if length * 2 < self.count {
+            Err(format!(
+                "The response was only {length} {unit} long, but should be about {self}. \
+                Expand it"
+            ))
+        } else if length * 2 > self.count * 3 {
+            Err(format!(
+                "The response was {length} {unit} long, but should be about {self}. Shorten it"
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let words = TargetLength {
+            count: 300,
+            unit: LengthUnit::Words,
+        };
+        assert_eq!("300w".parse::<TargetLength>().unwrap(), words);
+        assert_eq!("300 words".parse::<TargetLength>().unwrap(), words);
+        assert_eq!("300".parse::<TargetLength>().unwrap(), words);
+        assert_eq!(
+            "2000c".parse::<TargetLength>().unwrap(),
+            TargetLength {
+                count: 2000,
+                unit: LengthUnit::Characters,
+            }
+        );
+
+        for invalid in ["", "0w", "w", "300 lines", "-5w"] {
+            assert!(invalid.parse::<TargetLength>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn check() {
+        let target = "10w".parse::<TargetLength>().unwrap();
+        assert!(target
+            .check("one two three four five six seven eight")
+            .is_ok());
+        assert!(target
+            .check("one two")
+            .unwrap_err()
+            .starts_with("The response was only 2 words long"));
+        assert!(target
+            .check(&"word ".repeat(16))
+            .unwrap_err()
+            .contains("Shorten it"));
+
+        let target = "20c".parse::<TargetLength>().unwrap();
+        assert!(target.check("  about twenty characters  ").is_ok());
+        assert!(target.check(&"x".repeat(31)).is_err());
+    }
+
+    #[test]
+    fn instruction() {
+        let target = "2000c".parse::<TargetLength>().unwrap();
+        assert_eq!(
+            target.add_instruction("Describe a cat.".to_string()),
+            "Describe a cat.\n\nThe response should be about 2000 characters long."
+        );
+        assert_eq!(target.max_tokens(), 1064);
+    }
+}
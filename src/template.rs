@@ -5,12 +5,37 @@ use std::{
 };
 
 use error_stack::{Report, ResultExt};
+use schemars::JsonSchema;
 use serde::Deserialize;
 use tera::Tera;
 
-use crate::{args::GlobalRunArgs, error::Error, model::ModelOptionsInput};
+use crate::{
+    args::GlobalRunArgs,
+    assembly_trace::{AssemblyStepKind, AssemblyTrace},
+    capabilities::Requirement,
+    checks::ResponseChecksInput,
+    dates::DateSettings,
+    error::Error,
+    filters::PromptFilters,
+    front_matter::{split_front_matter, FrontMatter},
+    language::ExtraMeta,
+    model::ModelOptionsInput,
+    model_rules::ModelRule,
+    moderation::ModerationInput,
+    option_groups::flatten_option_groups,
+    output::OutputOptionsInput,
+    partials::Partials,
+    pin::remove_pin_tags,
+    provenance::{ReadSources, SourceFile, SourceKind},
+    response_schema::ResponseSchema,
+    routing::RoutingMode,
+    structured::DataFormat,
+    target_length::TargetLength,
+    tools::ToolDefinition,
+    unknown_fields::{from_toml_str, from_yaml_str, UnknownField},
+};
 
-#[derive(Deserialize, Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Debug, Default, Copy, Clone, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum OptionType {
     #[default]
@@ -22,9 +47,46 @@ pub enum OptionType {
     Bool,
     File,
     Image,
+    /// A web page, fetched and converted to Markdown
+    Url,
+    /// JSON from a file or the argument itself, parsed so the template can use its structure
+    Json,
+    /// YAML from a file or the argument itself, parsed so the template can use its structure
+    Yaml,
+    /// CSV with a header row, from a file or the argument itself, parsed into an array of rows
+    Csv,
+}
+
+/// What to do with binary files given to a file option
+#[derive(Deserialize, Debug, Default, Copy, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum BinaryFiles {
+    /// Leave the file out, with a warning.
+    #[default]
+    Skip,
+    /// Include the file's contents encoded as base64.
+    Base64,
+}
+
+impl std::fmt::Display for OptionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::String => "string",
+            Self::Number => "number",
+            Self::Integer => "integer",
+            Self::Bool => "bool",
+            Self::File => "file",
+            Self::Image => "image",
+            Self::Url => "url",
+            Self::Json => "json",
+            Self::Yaml => "yaml",
+            Self::Csv => "csv",
+        };
+        f.write_str(name)
+    }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, JsonSchema)]
 pub struct PromptOption {
     #[serde(default)]
     pub description: String,
@@ -38,32 +100,183 @@ pub struct PromptOption {
     /// Set `optional` true to allow omitting the option without providing a default value
     #[serde(default)]
     pub optional: bool,
+    /// Never trim the value of this option to fit the prompt in the context.
+    #[serde(default)]
+    pub pin: bool,
+    /// For `url` options, keep the page's original HTML instead of converting it to Markdown.
+    #[serde(default)]
+    pub raw: bool,
+    /// For `file` options, the most files to use from the directories and glob patterns given.
+    pub max_files: Option<usize>,
+    /// For `file` options, stop adding files once they reach this many tokens in total.
+    pub max_tokens: Option<usize>,
+    /// For `file` options, skip files larger than this many bytes. Defaults to 1000000.
+    pub max_file_size: Option<u64>,
+    /// For `file` options, whether to skip binary files or include them as base64.
+    #[serde(default)]
+    pub binary: BinaryFiles,
+    /// For `number` and `integer` options, the lowest value allowed.
+    pub min: Option<f64>,
+    /// For `number` and `integer` options, the highest value allowed.
+    pub max: Option<f64>,
+    /// For `string` options, a regular expression that the whole value must match.
+    pub pattern: Option<String>,
+}
+
+impl PromptOption {
+    /// Returns true if the option must be given on the command line.
+    pub fn required(&self) -> bool {
+        self.option_type != OptionType::Bool && self.default.is_none() && !self.optional
+    }
+
+    /// The regular expression from `pattern`, which must match the whole value.
+    pub fn pattern_regex(&self) -> Option<Result<regex::Regex, regex::Error>> {
+        self.pattern
+            .as_ref()
+            .map(|pattern| regex::Regex::new(&format!("^(?:{pattern})$")))
+    }
 }
 
-#[derive(Deserialize, Debug)]
+/// An example invocation of a template, shown in the help text.
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct TemplateExample {
+    #[serde(default)]
+    pub description: String,
+    /// The arguments to pass to the template
+    pub args: String,
+}
+
+#[derive(Deserialize, Debug, JsonSchema)]
 pub struct PromptTemplate {
     #[serde(default)]
     pub description: String,
+    /// Example invocations, shown in the help text.
+    #[serde(default)]
+    pub examples: Vec<TemplateExample>,
     #[serde(default)]
     pub model: ModelOptionsInput,
+    /// Set to "auto" to choose the model using the routing policy from the configuration.
+    #[serde(default)]
+    pub routing: RoutingMode,
+    /// Use different model options depending on the values of the template's options. The first
+    /// rule whose `when` condition is true is used.
+    #[serde(default)]
+    pub model_rules: Vec<ModelRule>,
+    /// What the model needs to be able to do for this template, from "vision", "json_mode",
+    /// "tools", and "context>=N". Models in the fallback chain which don't meet the requirements
+    /// are skipped.
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub requires: Vec<Requirement>,
 
     #[serde(default)]
     pub options: HashMap<String, PromptOption>,
 
+    /// Groups of related options, which the template sees as one object. The options in a group
+    /// named `reviewer` are given as `--reviewer.name` and used as `{{ reviewer.name }}`.
+    #[serde(default)]
+    pub option_groups: HashMap<String, HashMap<String, PromptOption>>,
+
+    /// Set the `project_context` variable to the contents of the project's `PROMPTBOX.md` or
+    /// `.promptbox/context.md` file.
+    #[serde(default)]
+    pub project_context: bool,
+
+    /// Let the template run shell commands with the `shell` function, such as
+    /// `{{ shell(command="git diff --staged") }}`, and run the commands of its tools. The run must
+    /// also allow this with `--allow-shell`.
+    #[serde(default)]
+    pub allow_shell: bool,
+
+    /// Parse the text piped in on stdin into the `stdin` variable, instead of adding it to the
+    /// end of the prompt. A run can also set this with `--stdin-format`.
+    pub stdin_format: Option<DataFormat>,
+
+    /// The time zone for the `today` variable, the `now()` function, and the `date` filter, such
+    /// as "America/New_York". Defaults to the machine's time zone.
+    pub timezone: Option<String>,
+    /// The locale for the names of months and days from the `date` filter, such as "de_DE".
+    pub locale: Option<String>,
+
     pub system_prompt: Option<String>,
     pub system_prompt_path: Option<PathBuf>,
 
     pub template: Option<String>,
     pub template_path: Option<PathBuf>,
+
+    /// How long the response should be, as a number of words or characters such as "300w" or
+    /// "2000c". The prompt asks for this length and `max_tokens` is set to leave some room past
+    /// it. Set `checks.enforce_length` to send responses that are far off back to the model.
+    #[schemars(with = "Option<String>")]
+    pub target_length: Option<TargetLength>,
+
+    /// A JSON Schema that the response must match, as a table or a string of JSON. The schema is
+    /// sent to hosts that support it, and responses that don't match it are sent back to the
+    /// model to fix, up to `checks.max_repairs` times.
+    pub response_schema: Option<serde_json::Value>,
+    /// A JSON file containing the response schema, relative to the template file.
+    pub response_schema_path: Option<PathBuf>,
+
+    /// Control how the response is written
+    #[serde(default)]
+    pub output: OutputOptionsInput,
+
+    /// Check the response for common problems, and fix them or ask the model to try again
+    #[serde(default)]
+    pub checks: ResponseChecksInput,
+
+    /// Tools that the model can call while generating the response
+    #[serde(default)]
+    pub tools: HashMap<String, ToolDefinition>,
+
+    /// Check the prompt or response with a moderation classifier. This adds to the
+    /// configuration's `moderation` settings, and can't turn off the checks that it sets.
+    #[serde(default)]
+    pub moderation: ModerationInput,
+}
+
+/// The extensions of template files, in order of precedence: TOML, and Markdown with the settings
+/// in front matter and the template as the body.
+pub const TEMPLATE_EXTENSIONS: [&str; 2] = [".pb.toml", ".pb.md"];
+
+/// Parse a Markdown template. The front matter holds the same settings as a TOML template, and the
+/// body is the template unless the front matter sets `template` or `template_path`.
+fn parse_markdown_template(
+    contents: &str,
+    path: &Path,
+) -> Result<(PromptTemplate, Vec<UnknownField>), Report<Error>> {
+    let (front_matter, body) = split_front_matter(contents);
+    let (mut template, unknown_fields) = match front_matter {
+        Some(FrontMatter::Yaml(yaml)) if !yaml.trim().is_empty() => {
+            from_yaml_str::<PromptTemplate>(yaml, path).change_context(Error::ParseTemplate)?
+        }
+        Some(FrontMatter::Toml(toml)) => {
+            from_toml_str::<PromptTemplate>(toml, path).change_context(Error::ParseTemplate)?
+        }
+        _ => from_toml_str::<PromptTemplate>("", path).change_context(Error::ParseTemplate)?,
+    };
+
+    if template.template.is_none() && template.template_path.is_none() {
+        template.template = Some(body.to_string());
+    }
+
+    Ok((template, unknown_fields))
 }
 
 #[derive(Debug)]
 pub struct ParsedTemplate {
     pub name: String,
+    /// The template file that this was loaded from
+    pub source: PathBuf,
     pub input: PromptTemplate,
     pub path: PathBuf,
     pub template: String,
     pub system: Option<(PathBuf, String)>,
+    pub response_schema: Option<ResponseSchema>,
+    /// The files that the template was read from
+    pub sources: Vec<SourceFile>,
+    /// Fields in the template file which were not recognized
+    pub unknown_fields: Vec<UnknownField>,
 }
 
 impl ParsedTemplate {
@@ -72,14 +285,26 @@ impl ParsedTemplate {
         let Ok(contents) = std::fs::read_to_string(path) else {
             return Ok(None);
         };
+        let mut sources = vec![SourceFile::new(SourceKind::Template, path, &contents)];
+
+        let is_markdown = path.to_string_lossy().ends_with(TEMPLATE_EXTENSIONS[1]);
+        let (mut prompt_template, unknown_fields) = if is_markdown {
+            parse_markdown_template(&contents, path)
+        } else {
+            from_toml_str::<PromptTemplate>(&contents, path).change_context(Error::ParseTemplate)
+        }
+        .attach_printable_lazy(|| path.display().to_string())?;
 
-        let mut prompt_template: PromptTemplate = toml::from_str(&contents)
-            .change_context(Error::ParseTemplate)
-            .attach_printable_lazy(|| path.display().to_string())?;
+        if let Some(dir) = path.parent() {
+            prompt_template.model.resolve_tokenizer_path(dir);
+        }
+
+        flatten_option_groups(
+            std::mem::take(&mut prompt_template.option_groups),
+            &mut prompt_template.options,
+        )
+        .attach_printable_lazy(|| path.display().to_string())?;
 
-        // At some point we should support partials here, but it still needs some design since we
-        // want to allow templates to reference partials in upper directories. For now, we just
-        // do a String.
         let (template_path, template_result) = if let Some(t) = prompt_template.template.take() {
             // Template is embedded in the file
             (path.to_path_buf(), t)
@@ -97,6 +322,11 @@ impl ParsedTemplate {
             let template_contents = std::fs::read_to_string(&template_path)
                 .change_context(Error::TemplateContentsNotFound)
                 .attach_printable_lazy(|| template_path.display().to_string())?;
+            sources.push(SourceFile::new(
+                SourceKind::TemplateText,
+                &template_path,
+                &template_contents,
+            ));
             (template_path, template_contents)
         };
 
@@ -113,28 +343,104 @@ impl ParsedTemplate {
             let template_contents = std::fs::read_to_string(&template_path)
                 .change_context(Error::TemplateContentsNotFound)
                 .attach_printable_lazy(|| template_path.display().to_string())?;
+            sources.push(SourceFile::new(
+                SourceKind::SystemPrompt,
+                &template_path,
+                &template_contents,
+            ));
             Some((template_path, template_contents))
         } else {
             None
         };
 
+        let response_schema = if let Some(schema) = prompt_template.response_schema.take() {
+            ResponseSchema::load(Some(schema), path)?
+        } else if let Some(relative_path) = prompt_template.response_schema_path.as_ref() {
+            let schema_path = path
+                .parent()
+                .ok_or(Error::EmptyTemplate)?
+                .join(relative_path);
+            let contents = std::fs::read_to_string(&schema_path)
+                .change_context(Error::InvalidResponseSchema)
+                .attach_printable_lazy(|| schema_path.display().to_string())?;
+            sources.push(SourceFile::new(
+                SourceKind::ResponseSchema,
+                &schema_path,
+                &contents,
+            ));
+            ResponseSchema::load(Some(serde_json::Value::String(contents)), &schema_path)?
+        } else {
+            None
+        };
+
         Ok(Some(ParsedTemplate {
             name: name.to_string(),
+            source: path.to_path_buf(),
             input: prompt_template,
             path: template_path,
             template: template_result,
             system,
+            response_schema,
+            sources,
+            unknown_fields,
         }))
     }
 }
 
+/// The partials, filters, and functions that templates can use when they are rendered
+#[derive(Debug, Default)]
+pub struct RenderEnv {
+    pub partials: Partials,
+    pub filters: PromptFilters,
+    /// The partials and other files that rendering has read
+    pub sources: ReadSources,
+}
+
+impl RenderEnv {
+    /// Add the filters and functions to `tera`, and the partials that `template` includes.
+    pub fn register(
+        &self,
+        tera: &mut Tera,
+        template_path: &Path,
+        template: &str,
+    ) -> Result<(), Report<Error>> {
+        self.filters.register(tera, template_path, &self.sources);
+        self.partials.register(tera, template, &self.sources)
+    }
+}
+
+/// Join the messages from a Tera error and the errors that caused it.
+pub fn tera_message(error: &tera::Error) -> String {
+    let mut messages = vec![error.to_string()];
+    let mut source = std::error::Error::source(error);
+    while let Some(e) = source {
+        messages.push(e.to_string());
+        source = e.source();
+    }
+
+    messages.join(": ").trim_end().to_string()
+}
+
 pub fn render_template(
     template_path: &Path,
     template: &str,
     context: &tera::Context,
+    env: &RenderEnv,
 ) -> Result<String, Report<Error>> {
-    Tera::one_off(template, context, false)
-        .change_context(Error::ParseTemplate)
+    DateSettings::from_context(context)
+        .and_then(|dates| {
+            let mut tera = Tera::default();
+            env.register(&mut tera, template_path, template)?;
+            dates.register(&mut tera);
+            tera.render_str(&remove_pin_tags(template), context)
+                .map_err(|e| {
+                    // The cause, such as a missing variable, is only in the error's sources.
+                    let message = tera_message(&e);
+                    Report::new(e)
+                        .change_context(Error::ParseTemplate)
+                        .attach_printable(message)
+                })
+        })
         .attach_printable_lazy(|| template_path.display().to_string())
 }
 
@@ -143,53 +449,108 @@ pub fn template_references_extra(template: &str) -> bool {
     extra_regex.is_match(template)
 }
 
+/// Add the extra arguments, stdin, `--pre`, and `--post` to the template. Returns the new template
+/// and the extra text that was added to it.
 pub fn assemble_template(
     args: &mut GlobalRunArgs,
     template_context: &mut serde_json::Value,
     initial_template: String,
-) -> Result<String, Report<Error>> {
+    trace: &mut AssemblyTrace,
+) -> Result<(String, String), Report<Error>> {
+    trace.record(AssemblyStepKind::Template, "", "", &initial_template)?;
+
     let mut template = match args.prepend.as_ref() {
-        Some(pre) => format!("{pre}\n\n{initial_template}"),
+        Some(pre) => {
+            let template = format!("{pre}\n\n{initial_template}");
+            trace.record(
+                AssemblyStepKind::Prepend,
+                "--pre",
+                &initial_template,
+                &template,
+            )?;
+            template
+        }
         None => initial_template,
     };
 
     let mut extra = std::mem::take(&mut args.extra_prompt);
+    let mut sources = Vec::new();
+    if !extra.is_empty() {
+        sources.push(format!("{} extra arguments", extra.len()));
+    }
 
     let stdin = std::io::stdin();
-    if !stdin.is_terminal() {
+    if args.stdin.is_none() && !stdin.is_terminal() {
         // Some text is potentially being piped in, so read it.
         let stdin_value = std::io::read_to_string(stdin)
             .attach_printable("Reading stdin")
             .change_context(Error::Io)?;
-        if !stdin_value.is_empty() {
-            extra.push(stdin_value);
-        }
+        args.stdin = Some(stdin_value).filter(|s| !s.is_empty());
     };
 
+    match (args.stdin.as_ref(), args.stdin_format) {
+        (Some(stdin_value), Some(format)) => {
+            template_context["stdin"] = format.parse(stdin_value).map_err(|e| {
+                Report::new(Error::PreparePrompt)
+                    .attach_printable(format!("Invalid {format} on stdin: {e}"))
+            })?;
+        }
+        (Some(stdin_value), None) => {
+            extra.push(stdin_value.clone());
+            sources.push("stdin".to_string());
+        }
+        // Nothing was piped in, but the template still refers to `stdin`.
+        (None, Some(format)) => template_context["stdin"] = format.empty_value(),
+        (None, None) => {}
+    }
+
     let extra_content = extra.join("\n\n");
+    template_context["extra_meta"] = serde_json::to_value(ExtraMeta::detect(&extra_content))
+        .change_context(Error::PreparePrompt)?;
+    let sources = sources.join(" and ");
     if template_references_extra(&template) {
-        template_context["extra"] = extra_content.into();
+        trace.record_substitution(
+            AssemblyStepKind::SubstituteExtra,
+            sources,
+            &template,
+            &extra_content,
+        )?;
+        template_context["extra"] = extra_content.clone().into();
     } else if !extra_content.is_empty() {
-        template = format!("{template}\n\n{extra_content}");
+        let new_template = format!("{template}\n\n{extra_content}");
+        trace.record(
+            AssemblyStepKind::AppendExtra,
+            sources,
+            &template,
+            &new_template,
+        )?;
+        template = new_template;
     }
 
     let template = match args.append.as_ref() {
-        Some(append) => format!("{template}\n\n{append}"),
+        Some(append) => {
+            let new_template = format!("{template}\n\n{append}");
+            trace.record(AssemblyStepKind::Append, "--post", &template, &new_template)?;
+            new_template
+        }
         None => template,
     };
 
-    Ok(template)
+    Ok((template, extra_content))
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{ffi::OsString, path::PathBuf};
+    use std::{collections::BTreeMap, ffi::OsString, path::PathBuf};
 
     use super::ParsedTemplate;
     use crate::{
+        config::Config,
         error::Error,
-        generate_template,
+        generate_template, generate_template_with_stdin,
+        provenance::{SourceFile, SourceKind},
         tests::{base_dir, BASE_DIR},
+        GeneratedTemplate,
     };
 
     fn to_cmdline_vec(args: Vec<impl Into<OsString>>) -> Vec<OsString> {
@@ -229,7 +590,7 @@ mod tests {
             "optvalue",
         ]);
 
-        let (_args, _options, prompt, system, _) =
+        let GeneratedTemplate { prompt, system, .. } =
             generate_template(PathBuf::from(BASE_DIR), "normal".to_string(), cmdline)
                 .expect("generate_template");
         assert!(system.is_empty());
@@ -247,6 +608,67 @@ optvalue
         );
     }
 
+    #[test]
+    fn option_groups() {
+        let cmdline = to_cmdline_vec(vec![
+            "test",
+            "run",
+            "option_groups",
+            "--topic",
+            "the parser",
+            "--reviewer.name",
+            "Sam",
+            "--reviewer.focus",
+            "naming",
+            "--reviewer.focus",
+            "errors",
+            "--reviewer.notes",
+            "test1.txt",
+        ]);
+
+        let GeneratedTemplate {
+            prompt, segments, ..
+        } = generate_template(
+            PathBuf::from(BASE_DIR),
+            "option_groups".to_string(),
+            cmdline,
+        )
+        .expect("generate_template");
+        assert_eq!(
+            prompt,
+            r##"Review the parser as a senior engineer named Sam.
+Focus on naming.
+Focus on errors.
+test1.txt: test1
+
+"##
+        );
+        // The file in the group is still found as a context segment.
+        assert!(segments.0.iter().any(|segment| matches!(
+            segment,
+            crate::segments::PromptSegment::Context { file, .. } if file == "test1.txt"
+        )));
+    }
+
+    #[test]
+    fn tools_need_allow_shell() {
+        let err = generate_template(
+            PathBuf::from(BASE_DIR),
+            "tools".to_string(),
+            to_cmdline_vec(vec!["test", "run", "tools"]),
+        )
+        .expect_err("tools should need --allow-shell");
+        assert!(matches!(err.current_context(), Error::ToolsNotAllowed));
+
+        let GeneratedTemplate { tools, .. } = generate_template(
+            PathBuf::from(BASE_DIR),
+            "tools".to_string(),
+            to_cmdline_vec(vec!["test", "run", "tools", "--allow-shell"]),
+        )
+        .expect("generate_template");
+        assert!(tools.contains_key("current_weather"));
+    }
+
     #[test]
     fn malformed_template() {
         let cmdline = to_cmdline_vec(vec!["test", "run", "malformed_template"]);
@@ -265,7 +687,7 @@ optvalue
     fn in_parent_dir() {
         let cmdline = to_cmdline_vec(vec!["test", "run", "simple"]);
 
-        let (_, _, prompt, _, _) =
+        let GeneratedTemplate { prompt, .. } =
             generate_template(base_dir("config_in_subdir"), "simple".to_string(), cmdline)
                 .expect("generate_template");
 
@@ -276,7 +698,7 @@ optvalue
     fn override_template() {
         let cmdline = to_cmdline_vec(vec!["test", "run", "tmp"]);
 
-        let (_, _, prompt, _, _) = generate_template(
+        let GeneratedTemplate { prompt, .. } = generate_template(
             base_dir("override_template/override"),
             "tmp".to_string(),
             cmdline,
@@ -286,11 +708,100 @@ optvalue
         assert_eq!(prompt, "overridden");
     }
 
+    #[test]
+    fn namespaced_template() {
+        // The overridden template can still be run through its directory's namespace.
+        for (name, expected) in [("base/tmp", "abc"), ("override/tmp", "overridden")] {
+            let cmdline = to_cmdline_vec(vec!["test", "run", name]);
+            let GeneratedTemplate { prompt, .. } = generate_template(
+                base_dir("override_template/override"),
+                name.to_string(),
+                cmdline,
+            )
+            .expect("generate_template");
+            assert_eq!(prompt, expected, "template {name}");
+        }
+    }
+
+    #[test]
+    fn partials() {
+        // The partial in the closer directory takes precedence, even when it is included from a
+        // partial in another directory.
+        for (dir, expected) in [
+            ("override_template", "Hello. Thanks. Be thorough.\n"),
+            ("override_template/override", "Hello. Thanks. Be brief.\n"),
+        ] {
+            let cmdline = to_cmdline_vec(vec!["test", "run", "with_partial"]);
+            let GeneratedTemplate {
+                prompt, sources, ..
+            } = generate_template(base_dir(dir), "with_partial".to_string(), cmdline)
+                .expect("generate_template");
+            assert_eq!(prompt, expected, "from {dir}");
+            assert!(
+                sources
+                    .iter()
+                    .any(|source| source.kind == SourceKind::Partial
+                        && source.path == base_dir("override_template/partials/signoff.md")),
+                "from {dir}: {sources:?}"
+            );
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("promptbox.toml"),
+            "top_level = true\nuse_global_config = false\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("missing.pb.toml"),
+            "template = '{% include \"nope\" %}'",
+        )
+        .unwrap();
+        let err = generate_template(
+            dir.path().to_path_buf(),
+            "missing".to_string(),
+            to_cmdline_vec(vec!["test", "run", "missing"]),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(err.current_context(), Error::PartialNotFound(name) if name == "nope"),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn markdown_templates() {
+        let cmdline = to_cmdline_vec(vec!["test", "run", "markdown_yaml", "--topic", "rain"]);
+        let GeneratedTemplate {
+            prompt,
+            model_options,
+            ..
+        } = generate_template(
+            PathBuf::from(BASE_DIR),
+            "markdown_yaml".to_string(),
+            cmdline,
+        )
+        .expect("generate_template");
+        assert_eq!(prompt, "Write a haiku about rain.\n");
+        assert_eq!(model_options.full_model_spec().model_name(), "gpt-4o-mini");
+        assert_eq!(model_options.temperature, 0.4);
+
+        let cmdline = to_cmdline_vec(vec!["test", "run", "markdown_toml", "--topic", "rain"]);
+        let GeneratedTemplate { prompt, system, .. } = generate_template(
+            PathBuf::from(BASE_DIR),
+            "markdown_toml".to_string(),
+            cmdline,
+        )
+        .expect("generate_template");
+        assert_eq!(prompt, "Write a sonnet about rain.\n");
+        assert_eq!(system, "You are a poet.");
+    }
+
     #[test]
     fn template_at_path() {
         let cmdline = to_cmdline_vec(vec!["test", "run", "subdir_without_config/indir"]);
 
-        let (_, _, prompt, _, _) = generate_template(
+        let GeneratedTemplate { prompt, .. } = generate_template(
             PathBuf::from(BASE_DIR),
             "subdir_without_config/indir".to_string(),
             cmdline,
@@ -300,6 +811,108 @@ optvalue
         assert_eq!(prompt, "the subdir");
     }
 
+    #[test]
+    fn replayed_stdin() {
+        let cmdline = to_cmdline_vec(vec!["test", "run", "simple", "more text"]);
+
+        let GeneratedTemplate { prompt, args, .. } = generate_template_with_stdin(
+            Config::from_directory(PathBuf::from(BASE_DIR)).unwrap(),
+            PathBuf::from(BASE_DIR),
+            "simple".to_string(),
+            cmdline,
+            Some("piped text".to_string()),
+        )
+        .expect("generate_template");
+
+        assert_eq!(prompt, "a simple prompt\n\nmore text\n\npiped text");
+        assert_eq!(args.stdin.as_deref(), Some("piped text"));
+    }
+
+    #[test]
+    fn structured_stdin() {
+        let cmdline = to_cmdline_vec(vec!["test", "run", "stdin_lines"]);
+        let GeneratedTemplate { prompt, .. } = generate_template_with_stdin(
+            Config::from_directory(PathBuf::from(BASE_DIR)).unwrap(),
+            PathBuf::from(BASE_DIR),
+            "stdin_lines".to_string(),
+            cmdline,
+            Some("a.rs\n\nb.rs\n".to_string()),
+        )
+        .expect("generate_template");
+        assert_eq!(prompt, "Review a.rs, b.rs");
+
+        // The flag overrides the template's format.
+        let cmdline = to_cmdline_vec(vec!["test", "run", "stdin_lines", "--stdin-format", "json"]);
+        let GeneratedTemplate { prompt, .. } = generate_template_with_stdin(
+            Config::from_directory(PathBuf::from(BASE_DIR)).unwrap(),
+            PathBuf::from(BASE_DIR),
+            "stdin_lines".to_string(),
+            cmdline,
+            Some(r#"["c.rs", "d.rs"]"#.to_string()),
+        )
+        .expect("generate_template");
+        assert_eq!(prompt, "Review c.rs, d.rs");
+    }
+
+    #[test]
+    fn project_context() {
+        let cmdline = to_cmdline_vec(vec![
+            "test",
+            "run",
+            "project_context/conventions",
+            "--topic",
+            "colours",
+        ]);
+
+        let GeneratedTemplate { prompt, .. } = generate_template(
+            base_dir("project_context"),
+            "project_context/conventions".to_string(),
+            cmdline,
+        )
+        .expect("generate_template");
+
+        assert_eq!(prompt, "Use British spelling.\n\nWrite about colours.\n");
+    }
+
+    #[test]
+    fn option_constraints() {
+        let run = |args: &[&str]| {
+            let mut cmdline = vec!["test", "run", "constrained"];
+            cmdline.extend_from_slice(args);
+            generate_template(
+                PathBuf::from(BASE_DIR),
+                "constrained".to_string(),
+                to_cmdline_vec(cmdline),
+            )
+        };
+
+        let GeneratedTemplate { prompt, .. } =
+            run(&["--count", "3", "--ticket", "PB-12"]).expect("generate_template");
+        assert_eq!(prompt, "3 0.5 PB-12");
+
+        for (args, message) in [
+            (
+                ["--count", "11", "--ticket", "PB-1"],
+                "invalid value '11' for '--count <count>': must be at most 10",
+            ),
+            (
+                ["--count", "0", "--ticket", "PB-1"],
+                "invalid value '0' for '--count <count>': must be at least 1",
+            ),
+            (
+                ["--ratio", "1.5", "--count", "1"],
+                "invalid value '1.5' for '--ratio <ratio>': must be at most 1",
+            ),
+            (
+                ["--count", "1", "--ticket", "PB-1x"],
+                "invalid value 'PB-1x' for '--ticket <ticket>': must match the pattern `[A-Z]+-[0-9]+`",
+            ),
+        ] {
+            let err = run(&args).expect_err("should fail");
+            assert!(format!("{err:?}").contains(message), "{err:?}");
+        }
+    }
+
     #[test]
     fn nonexistent_file() {
         let cmdline = to_cmdline_vec(vec!["test", "run", "nonexistent_file"]);
@@ -357,6 +970,12 @@ optvalue
         assert_eq!(options.presence_penalty, Some(0.5));
         assert_eq!(options.stop, Some(vec!["a".to_string(), "b".to_string()]));
         assert_eq!(options.max_tokens, Some(30));
+        assert_eq!(options.seed, Some(42));
+        assert_eq!(
+            options.logit_bias,
+            BTreeMap::from([("50256".to_string(), -100.0)])
+        );
+        assert_eq!(options.user, Some("someone".to_string()));
 
         let mut aliases = options.alias.iter().collect::<Vec<_>>();
         aliases.sort_by_key(|x| x.0);
@@ -402,7 +1021,7 @@ optvalue
             "5",
         ]);
 
-        let (_, model_options, _, _, _) = generate_template(
+        let GeneratedTemplate { model_options, .. } = generate_template(
             base_dir("all_model_options"),
             "all_model_options".to_string(),
             cmdline,
@@ -422,7 +1041,10 @@ optvalue
     #[test]
     fn system_prompt() {
         let cmdline = to_cmdline_vec(vec!["test", "run", "system_prompt", "--type", "fruit"]);
-        let (_, _, _, system_prompt, _) = generate_template(
+        let GeneratedTemplate {
+            system: system_prompt,
+            ..
+        } = generate_template(
             PathBuf::from(BASE_DIR),
             "system_prompt".to_string(),
             cmdline,
@@ -441,7 +1063,10 @@ optvalue
             "--type",
             "fruit",
         ]);
-        let (_, _, _, system_prompt, _) = generate_template(
+        let GeneratedTemplate {
+            system: system_prompt,
+            ..
+        } = generate_template(
             PathBuf::from(BASE_DIR),
             "system_prompt_in_file".to_string(),
             cmdline,
@@ -451,6 +1076,49 @@ optvalue
         assert_eq!(system_prompt, "A system prompt for fruit\n");
     }
 
+    #[test]
+    fn sources() {
+        let cmdline = to_cmdline_vec(vec![
+            "test",
+            "run",
+            "system_prompt_in_file",
+            "--type",
+            "fruit",
+        ]);
+        let GeneratedTemplate { sources, .. } = generate_template(
+            PathBuf::from(BASE_DIR),
+            "system_prompt_in_file".to_string(),
+            cmdline,
+        )
+        .expect("generate_template");
+
+        let kinds_and_paths = sources
+            .iter()
+            .map(|s| (s.kind, s.path.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds_and_paths[..3],
+            [
+                (
+                    SourceKind::Template,
+                    base_dir("system_prompt_in_file.pb.toml")
+                ),
+                (SourceKind::SystemPrompt, base_dir("system_prompt.liquid")),
+                (SourceKind::Config, base_dir("promptbox.toml")),
+            ]
+        );
+
+        let contents = std::fs::read_to_string(base_dir("system_prompt.liquid")).unwrap();
+        assert_eq!(
+            sources[1],
+            SourceFile::new(
+                SourceKind::SystemPrompt,
+                base_dir("system_prompt.liquid"),
+                &contents
+            )
+        );
+    }
+
     mod assemble_template {
         use super::*;
 
@@ -466,7 +1134,7 @@ optvalue
                 "Do it best",
             ]);
 
-            let (_, _, prompt, _, _) =
+            let GeneratedTemplate { prompt, .. } =
                 generate_template(PathBuf::from(BASE_DIR), "simple".to_string(), cmdline)
                     .expect("generate_template");
             assert_eq!(
@@ -487,7 +1155,7 @@ optvalue
                 "Do it best",
             ]);
 
-            let (_, _, prompt, _, _) =
+            let GeneratedTemplate { prompt, .. } =
                 generate_template(PathBuf::from(BASE_DIR), "simple".to_string(), cmdline)
                     .expect("generate_template");
             assert_eq!(
@@ -510,7 +1178,7 @@ optvalue
                 "Do it best",
             ]);
 
-            let (_, _, prompt, _, _) =
+            let GeneratedTemplate { prompt, .. } =
                 generate_template(PathBuf::from(BASE_DIR), "simple".to_string(), cmdline)
                     .expect("generate_template");
             assert_eq!(
@@ -533,7 +1201,7 @@ optvalue
                 "Do it best",
             ]);
 
-            let (_, _, prompt, _, _) = generate_template(
+            let GeneratedTemplate { prompt, .. } = generate_template(
                 PathBuf::from(BASE_DIR),
                 "extra_template_arg".to_string(),
                 cmdline,
@@ -546,6 +1214,109 @@ optvalue
         }
     }
 
+    #[test]
+    fn prompt_segments() {
+        use crate::segments::PromptSegment;
+
+        let cmdline = to_cmdline_vec(vec![
+            "test",
+            "run",
+            "normal",
+            "--stringopt",
+            "stringvalue",
+            "--numopt",
+            "5.5",
+            "--intopt",
+            "6",
+            "--defaulttypeopt",
+            "defvalue",
+            "--fileopt",
+            "test2.txt",
+            "--arrayfileopt",
+            "test1.txt",
+            "--post",
+            "Is it done?",
+            "Do it now",
+        ]);
+
+        let GeneratedTemplate {
+            prompt, segments, ..
+        } = generate_template(PathBuf::from(BASE_DIR), "normal".to_string(), cmdline)
+            .expect("generate_template");
+
+        let joined = segments.0.iter().map(|s| s.text()).collect::<String>();
+        assert_eq!(joined, prompt);
+
+        let parts = segments
+            .0
+            .iter()
+            .filter(|s| !matches!(s, PromptSegment::Instructions { .. }))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            parts,
+            vec![
+                PromptSegment::Context {
+                    file: "test2.txt".to_string(),
+                    text: "it's test2\n".to_string(),
+                },
+                PromptSegment::Context {
+                    file: "test1.txt".to_string(),
+                    text: "test1\n".to_string(),
+                },
+                PromptSegment::Extra {
+                    text: "Do it now".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[cfg(feature = "tokenizers")]
+    #[test]
+    fn trace_assembly() {
+        use crate::assembly_trace::{AssemblyStep, AssemblyStepKind};
+
+        let cmdline = to_cmdline_vec(vec![
+            "test",
+            "run",
+            "traced",
+            "--trace-assembly",
+            "--pre",
+            "Do it right",
+            "--post",
+            "Is it done?",
+            "Do it now",
+            "Do it best",
+        ]);
+
+        let GeneratedTemplate { assembly_trace, .. } =
+            generate_template(PathBuf::from(BASE_DIR), "traced".to_string(), cmdline)
+                .expect("generate_template");
+
+        let step = |step, detail: &str, tokens_before, tokens_after| AssemblyStep {
+            step,
+            detail: detail.to_string(),
+            tokens_before,
+            tokens_after,
+        };
+        assert_eq!(
+            assembly_trace.steps,
+            vec![
+                step(AssemblyStepKind::Template, "", 0, 5),
+                step(AssemblyStepKind::Prepend, "--pre", 5, 8),
+                step(
+                    AssemblyStepKind::SubstituteExtra,
+                    "2 extra arguments",
+                    8,
+                    14
+                ),
+                step(AssemblyStepKind::Append, "--post", 8, 12),
+                step(AssemblyStepKind::Render, "", 12, 15),
+                step(AssemblyStepKind::Truncate, "", 15, 15),
+            ]
+        );
+    }
+
     mod args {
         use base64::Engine;
 
@@ -571,7 +1342,7 @@ optvalue
                 "test1.txt",
             ]);
 
-            let (_, _, prompt, _, _) =
+            let GeneratedTemplate { prompt, .. } =
                 generate_template(PathBuf::from(BASE_DIR), "normal".to_string(), cmdline)
                     .expect("generate_template");
             assert_eq!(
@@ -742,6 +1513,25 @@ test1.txt: test1
             ));
         }
 
+        #[test]
+        fn help_text() {
+            let cmdline = to_cmdline_vec(vec!["test", "run", "help_text", "--help"]);
+            let err = generate_template(PathBuf::from(BASE_DIR), "help_text".to_string(), cmdline)
+                .expect_err("help should return an error");
+
+            let Error::CmdlineParseFailure(e) = err.current_context() else {
+                panic!("Expected a clap error, saw {err:?}");
+            };
+
+            assert_eq!(e.kind(), clap::error::ErrorKind::DisplayHelp);
+            let help = e.render().to_string();
+            assert!(help.contains("Summarize some text"));
+            assert!(help.contains("The style of the summary [default: concise]"));
+            assert!(help.contains(
+                "Examples:\n  # An excited summary\n  promptbox run help_text --style excited"
+            ));
+        }
+
         #[test]
         fn image_args() {
             let cmdline = to_cmdline_vec(vec![
@@ -754,7 +1544,7 @@ test1.txt: test1
                 "test.jpg",
             ]);
 
-            let (_, _, _, _, images) =
+            let GeneratedTemplate { images, .. } =
                 generate_template(BASE_DIR.into(), "images".to_string(), cmdline).unwrap();
 
             assert_eq!(images.len(), 2);
@@ -0,0 +1,211 @@
+//! Short names for templates along with arguments that are often used with them, such as
+//! `cm = "commitmsg --style conventional"`.
+
+use std::{collections::HashSet, ffi::OsString};
+
+use error_stack::Report;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::{args, config::Config, error::Error, template::PromptTemplate};
+
+/// The template and arguments that an alias stands for
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(untagged)]
+pub enum TemplateAlias {
+    /// The template name followed by its arguments, separated by spaces
+    Command(String),
+    /// The template name and each of its arguments, for arguments that contain spaces
+    Args(Vec<String>),
+}
+
+impl TemplateAlias {
+    fn args(&self) -> Vec<String> {
+        match self {
+            Self::Command(command) => command.split_whitespace().map(String::from).collect(),
+            Self::Args(args) => args.clone(),
+        }
+    }
+}
+
+/// If the template of a `run` or `chat` command line is an alias, replace it with the template
+/// and arguments that the alias stands for. Flags that the alias presets are dropped when the
+/// command line gives them too, so that the command line can override them. Aliases are only
+/// expanded once, so an alias can have the same name as its template to give it default
+/// arguments.
+pub fn expand_alias(cmdline: &mut Vec<OsString>, config: &Config) -> Result<(), Report<Error>> {
+    let Some(index) = args::template_position(cmdline) else {
+        return Ok(());
+    };
+    let name = cmdline[index].to_string_lossy();
+    let Some(alias) = config.template_aliases.get(name.as_ref()) else {
+        return Ok(());
+    };
+
+    let mut alias_args = alias.args().into_iter();
+    let Some(template) = alias_args.next() else {
+        return Err(Report::new(Error::ParseConfig)
+            .attach_printable(format!("Template alias `{name}` is empty")));
+    };
+
+    // The template's options are needed to know which of its flags take values. If it can't be
+    // read, running it reports the error.
+    let options = config.find_template(&template).ok().map(|t| t.input);
+    let given = cmdline[2..index]
+        .iter()
+        .chain(&cmdline[index + 1..])
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    let given = flag_groups(&given, options.as_ref())
+        .into_iter()
+        .filter_map(|(id, _)| id)
+        .collect::<HashSet<_>>();
+
+    let presets = flag_groups(&alias_args.collect::<Vec<_>>(), options.as_ref())
+        .into_iter()
+        .filter(|(id, _)| !id.as_ref().is_some_and(|id| given.contains(id)))
+        .flat_map(|(_, args)| args)
+        .map(OsString::from);
+
+    let expanded = std::iter::once(OsString::from(template))
+        .chain(presets)
+        .collect::<Vec<_>>();
+    cmdline.splice(index..=index, expanded);
+    Ok(())
+}
+
+/// Split arguments into each flag with its value, and each positional argument by itself. Flags
+/// are identified by their argument name, so the long and short forms match.
+fn flag_groups(
+    args: &[String],
+    template: Option<&PromptTemplate>,
+) -> Vec<(Option<String>, Vec<String>)> {
+    let mut groups = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            groups.extend(
+                std::iter::once(arg)
+                    .chain(args.by_ref())
+                    .map(|a| (None, vec![a.clone()])),
+            );
+            break;
+        }
+        if !arg.starts_with('-') || arg == "-" {
+            groups.push((None, vec![arg.clone()]));
+            continue;
+        }
+
+        let flag = args::find_run_flag(arg, template);
+        let id = flag.as_ref().map_or_else(
+            || {
+                arg.split_once('=')
+                    .map_or(arg.as_str(), |(name, _)| name)
+                    .to_string()
+            },
+            |flag| flag.id.clone(),
+        );
+        let mut group = vec![arg.clone()];
+        if !arg.contains('=') && flag.is_some_and(|flag| flag.takes_value) {
+            group.extend(args.next().cloned());
+        }
+        groups.push((Some(id), group));
+    }
+
+    groups
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use super::*;
+    use crate::tests::BASE_DIR;
+
+    fn cmdline(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    fn config_with(aliases: &[(&str, TemplateAlias)]) -> Config {
+        let mut config = Config::from_directory(PathBuf::from(BASE_DIR)).unwrap();
+        config.template_aliases = aliases
+            .iter()
+            .map(|(name, alias)| (name.to_string(), alias.clone()))
+            .collect::<HashMap<_, _>>();
+        config
+    }
+
+    #[test]
+    fn expand() {
+        let config = config_with(&[
+            (
+                "n",
+                TemplateAlias::Command(
+                    "normal --stringopt preset --boolopt --intopt 1".to_string(),
+                ),
+            ),
+            (
+                "normal",
+                TemplateAlias::Args(vec![
+                    "normal".to_string(),
+                    "--stringopt".to_string(),
+                    "two words".to_string(),
+                ]),
+            ),
+        ]);
+
+        // Flags given on the command line replace the ones from the alias.
+        let mut args = cmdline(&[
+            "promptbox",
+            "run",
+            "--model",
+            "m",
+            "n",
+            "--stringopt",
+            "mine",
+            "--",
+            "extra",
+        ]);
+        expand_alias(&mut args, &config).unwrap();
+        assert_eq!(
+            args,
+            cmdline(&[
+                "promptbox",
+                "run",
+                "--model",
+                "m",
+                "normal",
+                "--boolopt",
+                "--intopt",
+                "1",
+                "--stringopt",
+                "mine",
+                "--",
+                "extra"
+            ])
+        );
+
+        // An alias with the same name as its template is only expanded once.
+        let mut args = cmdline(&["promptbox", "run", "normal", "--boolopt"]);
+        expand_alias(&mut args, &config).unwrap();
+        assert_eq!(
+            args,
+            cmdline(&[
+                "promptbox",
+                "run",
+                "normal",
+                "--stringopt",
+                "two words",
+                "--boolopt"
+            ])
+        );
+
+        let mut args = cmdline(&["promptbox", "run", "simple"]);
+        expand_alias(&mut args, &config).unwrap();
+        assert_eq!(args, cmdline(&["promptbox", "run", "simple"]));
+
+        let empty = config_with(&[("x", TemplateAlias::Command(" ".to_string()))]);
+        let mut args = cmdline(&["promptbox", "run", "x"]);
+        assert!(expand_alias(&mut args, &empty).is_err());
+    }
+}
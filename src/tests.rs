@@ -1,7 +1,21 @@
 use std::path::{Path, PathBuf};
 
+use crate::history::HistoryEntry;
+
 pub const BASE_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data");
 
 pub fn base_dir(subpath: impl AsRef<Path>) -> PathBuf {
     PathBuf::from(BASE_DIR).join(subpath)
 }
+
+/// A history entry for run `id` of the `summarize` template, with the other fields left empty.
+pub fn history_entry(id: u64) -> HistoryEntry {
+    HistoryEntry {
+        id,
+        template: "summarize".to_string(),
+        model: "gpt-4o".to_string(),
+        prompt: format!("prompt {id}"),
+        response: format!("response {id}"),
+        ..Default::default()
+    }
+}
@@ -0,0 +1,223 @@
+//! Count the tokens in text. Builds with the `tokenizers` feature use the model's real tokenizer,
+//! and other builds estimate the count from the words and punctuation in the text.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+#[cfg(feature = "tokenizers")]
+pub use tokenizers::Encoding;
+
+use crate::Error;
+
+/// The tokenizer used when none is specified.
+pub const DEFAULT_TOKENIZER: &str = "llama2";
+
+/// Map the names of well-known tokenizers to a Hugging Face repository that contains them.
+#[cfg(feature = "tokenizers")]
+fn builtin_tokenizer_repo(name: &str) -> Option<&'static str> {
+    match name {
+        "llama2" => Some("TheBloke/Llama-2-70B-fp16"),
+        "llama3" => Some("NousResearch/Meta-Llama-3-8B"),
+        "cl100k_base" => Some("Xenova/gpt-4"),
+        "o200k_base" => Some("Xenova/gpt-4o"),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "tokenizers")]
+pub struct Tokenizer(tokenizers::Tokenizer);
+
+#[cfg(not(feature = "tokenizers"))]
+pub struct Tokenizer;
+
+impl std::fmt::Debug for Tokenizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tokenizer").finish_non_exhaustive()
+    }
+}
+
+impl Tokenizer {
+    #[cfg(all(test, feature = "tokenizers"))]
+    pub fn new() -> Result<Self, Error> {
+        // This isn't accurate for everything but most models are using a similar config.
+        Self::load(DEFAULT_TOKENIZER)
+    }
+
+    /// Load a tokenizer. `spec` can be the name of a built-in tokenizer, the path to a
+    /// `tokenizer.json` file, or a Hugging Face repository that contains a tokenizer.
+    #[cfg(feature = "tokenizers")]
+    pub fn load(spec: &str) -> Result<Self, Error> {
+        use std::path::Path;
+
+        let tokenizer = if let Some(repo) = builtin_tokenizer_repo(spec) {
+            tokenizers::Tokenizer::from_pretrained(repo, None)
+        } else if spec.ends_with(".json") || Path::new(spec).is_file() {
+            tokenizers::Tokenizer::from_file(spec)
+        } else {
+            tokenizers::Tokenizer::from_pretrained(spec, None)
+        }
+        .map_err(|e| Error::Tokenizer(format!("{spec}: {e}")))?;
+
+        Ok(Self(tokenizer))
+    }
+
+    /// Without the `tokenizers` feature every tokenizer is an estimate, so `spec` is ignored.
+    #[cfg(not(feature = "tokenizers"))]
+    pub fn load(_spec: &str) -> Result<Self, Error> {
+        Ok(Self)
+    }
+
+    /// Get a tokenizer, reusing it if it has already been loaded by this process.
+    pub fn get(spec: Option<&str>) -> Result<Arc<Self>, Error> {
+        static REGISTRY: OnceLock<Mutex<HashMap<String, Arc<Tokenizer>>>> = OnceLock::new();
+
+        let spec = spec.unwrap_or(DEFAULT_TOKENIZER);
+        let registry = REGISTRY.get_or_init(Default::default);
+        if let Some(tokenizer) = registry.lock().unwrap().get(spec) {
+            return Ok(tokenizer.clone());
+        }
+
+        // Don't hold the lock while loading since that may take a while.
+        let tokenizer = Arc::new(Self::load(spec)?);
+        registry
+            .lock()
+            .unwrap()
+            .insert(spec.to_string(), tokenizer.clone());
+        Ok(tokenizer)
+    }
+
+    #[cfg(feature = "tokenizers")]
+    pub fn encode(&self, input: &str) -> Result<Encoding, Error> {
+        self.0
+            .encode(input, false)
+            .map_err(|e| Error::Tokenizer(e.to_string()))
+    }
+
+    #[cfg(not(feature = "tokenizers"))]
+    pub fn encode(&self, input: &str) -> Result<Encoding, Error> {
+        Ok(Encoding {
+            offsets: estimate_offsets(input),
+        })
+    }
+
+    #[cfg(all(test, feature = "tokenizers"))]
+    pub fn encode_batch<'s>(
+        &self,
+        input: Vec<impl Into<tokenizers::EncodeInput<'s>> + Send>,
+    ) -> Result<Vec<Encoding>, Error> {
+        self.0
+            .encode_batch(input, false)
+            .map_err(|e| Error::Tokenizer(e.to_string()))
+    }
+}
+
+/// The estimated tokens in some text, for builds without the `tokenizers` feature.
+#[cfg(not(feature = "tokenizers"))]
+#[derive(Debug, Clone)]
+pub struct Encoding {
+    offsets: Vec<(usize, usize)>,
+}
+
+#[cfg(not(feature = "tokenizers"))]
+impl Encoding {
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// The start and end byte of each token
+    pub fn get_offsets(&self) -> &[(usize, usize)] {
+        &self.offsets
+    }
+}
+
+/// Longer words are counted as one token per this many characters, which is about what real
+/// tokenizers do for English text.
+#[cfg(any(test, not(feature = "tokenizers")))]
+const CHARS_PER_TOKEN: usize = 4;
+
+/// Split text into estimated tokens: pieces of words up to [CHARS_PER_TOKEN] characters long, and
+/// each punctuation character. Returns the byte range of each token.
+#[cfg(any(test, not(feature = "tokenizers")))]
+fn estimate_offsets(input: &str) -> Vec<(usize, usize)> {
+    let mut offsets = Vec::new();
+    let mut word: Option<(usize, usize)> = None;
+    for (index, c) in input.char_indices() {
+        let end = index + c.len_utf8();
+        if c.is_alphanumeric() {
+            match word.as_mut() {
+                Some((_, chars)) if *chars < CHARS_PER_TOKEN => *chars += 1,
+                _ => {
+                    offsets.push((index, end));
+                    word = Some((index, 1));
+                    continue;
+                }
+            }
+            offsets.last_mut().expect("word has a token").1 = end;
+        } else {
+            word = None;
+            if !c.is_whitespace() {
+                offsets.push((index, end));
+            }
+        }
+    }
+    offsets
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn estimate() {
+        let input = "Hello, wonderful world!";
+        let tokens = super::estimate_offsets(input)
+            .iter()
+            .map(|(start, end)| &input[*start..*end])
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            ["Hell", "o", ",", "wond", "erfu", "l", "worl", "d", "!"]
+        );
+        assert!(super::estimate_offsets(" \n ").is_empty());
+    }
+
+    #[cfg(feature = "tokenizers")]
+    #[test]
+    fn from_file() {
+        use super::Tokenizer;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokenizer.json");
+        std::fs::write(
+            &path,
+            r##"{
+              "version": "1.0",
+              "truncation": null,
+              "padding": null,
+              "added_tokens": [],
+              "normalizer": null,
+              "pre_tokenizer": { "type": "Whitespace" },
+              "post_processor": null,
+              "decoder": null,
+              "model": { "type": "WordLevel", "vocab": { "[UNK]": 0 }, "unk_token": "[UNK]" }
+            }"##,
+        )
+        .unwrap();
+
+        let path = path.to_string_lossy();
+        let tokenizer = Tokenizer::get(Some(&path)).unwrap();
+        assert_eq!(tokenizer.encode("one two three").unwrap().len(), 3);
+
+        let again = Tokenizer::get(Some(&path)).unwrap();
+        assert!(
+            std::sync::Arc::ptr_eq(&tokenizer, &again),
+            "Tokenizer should be reused"
+        );
+    }
+
+    #[cfg(feature = "tokenizers")]
+    #[test]
+    fn missing_file() {
+        super::Tokenizer::load("/nonexistent/tokenizer.json").expect_err("should fail");
+    }
+}
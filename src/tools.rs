@@ -0,0 +1,222 @@
+use std::{
+    io::{Read, Write},
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use error_stack::{Report, ResultExt};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::json;
+use thiserror::Error;
+
+use crate::shutdown::CancellationToken;
+
+/// How often to check if a running tool should be stopped
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Error, Debug)]
+pub enum ToolError {
+    #[error("Model requested unknown tool {0}")]
+    UnknownTool(String),
+    #[error("Failed to run tool {0}")]
+    Run(String),
+    #[error("Stopped tool {0}")]
+    Cancelled(String),
+}
+
+fn default_parameters() -> serde_json::Value {
+    json!({
+        "type": "object",
+        "properties": {}
+    })
+}
+
+/// A tool that the model can call. When the model calls the tool, `command` is run with the
+/// arguments from the model passed as JSON on stdin and in the `PROMPTBOX_TOOL_ARGS` environment
+/// variable. Whatever the command writes to stdout is returned to the model.
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+pub struct ToolDefinition {
+    #[serde(default)]
+    pub description: String,
+    /// A JSON Schema describing the arguments to the tool.
+    #[serde(default = "default_parameters")]
+    pub parameters: serde_json::Value,
+    /// The shell command to run
+    pub command: String,
+}
+
+impl ToolDefinition {
+    /// The definition of the tool in the format used by the OpenAI API.
+    pub fn openai_definition(&self, name: &str) -> serde_json::Value {
+        json!({
+            "type": "function",
+            "function": {
+                "name": name,
+                "description": self.description,
+                "parameters": self.parameters,
+            }
+        })
+    }
+
+    /// Run the tool with arguments from the model, and return the result to send back. If
+    /// `cancel` is cancelled while the tool runs, the tool is killed.
+    pub fn run(
+        &self,
+        name: &str,
+        arguments: &str,
+        cancel: &CancellationToken,
+    ) -> Result<String, Report<ToolError>> {
+        let mut child = shell_command(&self.command)
+            .env("PROMPTBOX_TOOL_NAME", name)
+            .env("PROMPTBOX_TOOL_ARGS", arguments)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .change_context_lazy(|| ToolError::Run(name.to_string()))
+            .attach_printable_lazy(|| self.command.clone())?;
+
+        // Read the output on other threads so that the tool doesn't block on a full pipe while
+        // this thread waits for it to exit or be cancelled.
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        let stdout = std::thread::spawn(move || read_all(stdout));
+        let stderr = std::thread::spawn(move || read_all(stderr));
+
+        // The arguments are written on their own thread too, since the tool may write its output
+        // before it reads them. The write ends when the tool exits, so this isn't waited on.
+        if let Some(mut stdin) = child.stdin.take() {
+            let arguments = arguments.to_string();
+            std::thread::spawn(move || {
+                // The tool may not read stdin at all, so a write failure here is fine.
+                stdin.write_all(arguments.as_bytes()).ok();
+            });
+        }
+
+        let status = loop {
+            if cancel.is_cancelled() {
+                child.kill().ok();
+                child.wait().ok();
+                // Processes started by the tool may still hold the pipes open, so don't wait for
+                // the output.
+                return Err(Report::new(ToolError::Cancelled(name.to_string())));
+            }
+
+            match child
+                .try_wait()
+                .change_context_lazy(|| ToolError::Run(name.to_string()))?
+            {
+                Some(status) => break status,
+                None => std::thread::sleep(POLL_INTERVAL),
+            }
+        };
+        let stdout = stdout.join().unwrap();
+        let stderr = stderr.join().unwrap();
+
+        let stdout = String::from_utf8_lossy(&stdout);
+        if status.success() {
+            Ok(stdout.trim_end().to_string())
+        } else {
+            // Let the model know that the tool failed instead of aborting the whole request.
+            let stderr = String::from_utf8_lossy(&stderr);
+            Ok(format!(
+                "Tool failed with {}\n{}\n{}",
+                status,
+                stdout.trim_end(),
+                stderr.trim_end()
+            )
+            .trim_end()
+            .to_string())
+        }
+    }
+}
+
+fn read_all(pipe: Option<impl Read>) -> Vec<u8> {
+    let mut output = Vec::new();
+    if let Some(mut pipe) = pipe {
+        pipe.read_to_end(&mut output).ok();
+    }
+    output
+}
+
+#[cfg(not(windows))]
+pub fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(windows)]
+pub fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+#[cfg(all(test, not(windows)))]
+mod test {
+    use super::*;
+
+    fn tool(command: &str) -> ToolDefinition {
+        ToolDefinition {
+            description: String::new(),
+            parameters: default_parameters(),
+            command: command.to_string(),
+        }
+    }
+
+    #[test]
+    fn args_on_stdin() {
+        let result = tool("cat")
+            .run("echo", r##"{"a":1}"##, &CancellationToken::new())
+            .unwrap();
+        assert_eq!(result, r##"{"a":1}"##);
+    }
+
+    #[test]
+    fn output_before_reading_args() {
+        // Both are larger than a pipe's buffer, so neither side can finish writing before the
+        // other reads.
+        let arguments = "a".repeat(100_000);
+        let result = tool("head -c 100000 /dev/zero | tr '\\0' b; wc -c")
+            .run("big", &arguments, &CancellationToken::new())
+            .unwrap();
+        assert!(result.starts_with("bbb"));
+        assert!(result.ends_with("100000"));
+    }
+
+    #[test]
+    fn args_in_env() {
+        let result = tool("echo \"$PROMPTBOX_TOOL_NAME $PROMPTBOX_TOOL_ARGS\"")
+            .run("echo", "{}", &CancellationToken::new())
+            .unwrap();
+        assert_eq!(result, "echo {}");
+    }
+
+    #[test]
+    fn failed_command() {
+        let result = tool("echo oops >&2; exit 3")
+            .run("fail", "{}", &CancellationToken::new())
+            .unwrap();
+        assert!(result.starts_with("Tool failed"));
+        assert!(result.ends_with("oops"));
+    }
+
+    #[test]
+    fn cancelled() {
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let started = std::time::Instant::now();
+        let err = tool("sleep 10").run("slow", "{}", &cancel).unwrap_err();
+        assert!(matches!(err.current_context(), ToolError::Cancelled(_)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn openai_definition() {
+        let def = tool("true").openai_definition("a_tool");
+        assert_eq!(def["function"]["name"], "a_tool");
+        assert_eq!(def["function"]["parameters"]["type"], "object");
+    }
+}
@@ -0,0 +1,154 @@
+//! Append each run to a log file, as a record of exactly what was sent to the model and what came
+//! back.
+
+use std::{io::Write, path::Path};
+
+use chrono::{DateTime, Utc};
+use error_stack::{Report, ResultExt};
+use serde::Serialize;
+
+use crate::{error::Error, session::ChatMessage};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    Markdown,
+    Jsonl,
+}
+
+impl LogFormat {
+    /// Files ending in `.jsonl` get one JSON object per line, and everything else gets Markdown.
+    fn for_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("jsonl") => Self::Jsonl,
+            _ => Self::Markdown,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct LogEntry<'a> {
+    pub timestamp: DateTime<Utc>,
+    pub template: &'a str,
+    pub model: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<&'a str>,
+    /// Messages from earlier in the conversation that were sent before the prompt
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub history: &'a [ChatMessage],
+    pub prompt: &'a str,
+    pub response: &'a str,
+    /// True if the response came from the response cache instead of the model
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub cached: bool,
+}
+
+impl<'a> LogEntry<'a> {
+    fn markdown(&self) -> String {
+        let mut output = format!(
+            "# {}\n\n_{} with {}{}_\n\n",
+            self.template,
+            self.timestamp.format("%Y-%m-%d %H:%M:%S UTC"),
+            self.model,
+            if self.cached { " (cached)" } else { "" }
+        );
+
+        if let Some(system) = self.system {
+            output.push_str(&format!("## System\n\n{}\n\n", system.trim()));
+        }
+
+        let prompt = ChatMessage::user(self.prompt);
+        let response = ChatMessage::assistant(self.response);
+        for message in self.history.iter().chain([&prompt, &response]) {
+            output.push_str(&format!(
+                "## {}\n\n{}\n\n",
+                message.role.label(),
+                message.content.trim()
+            ));
+        }
+
+        output.push_str("---\n\n");
+        output
+    }
+
+    fn format(&self, format: LogFormat) -> Result<String, Report<Error>> {
+        match format {
+            LogFormat::Markdown => Ok(self.markdown()),
+            LogFormat::Jsonl => {
+                let line = serde_json::to_string(self).change_context(Error::LogFile)?;
+                Ok(format!("{line}\n"))
+            }
+        }
+    }
+}
+
+/// Append an entry to the log file at `path`, creating it if needed.
+pub fn append(path: &Path, entry: &LogEntry) -> Result<(), Report<Error>> {
+    let contents = entry.format(LogFormat::for_path(path))?;
+
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)
+            .change_context(Error::LogFile)
+            .attach_printable_lazy(|| dir.display().to_string())?;
+    }
+
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .change_context(Error::LogFile)
+        .attach_printable_lazy(|| path.display().to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry<'a>(history: &'a [ChatMessage]) -> LogEntry<'a> {
+        LogEntry {
+            timestamp: DateTime::parse_from_rfc3339("2024-01-02T03:04:05Z")
+                .unwrap()
+                .with_timezone(&Utc),
+            template: "summarize",
+            model: "gpt-4o",
+            system: Some("Be brief"),
+            history,
+            prompt: "Summarize this",
+            response: "A summary",
+            cached: false,
+        }
+    }
+
+    #[test]
+    fn markdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs/runs.md");
+        append(&path, &entry(&[])).unwrap();
+        append(&path, &entry(&[])).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let expected = "# summarize\n\n_2024-01-02 03:04:05 UTC with gpt-4o_\n\n## System\n\nBe brief\n\n## User\n\nSummarize this\n\n## Assistant\n\nA summary\n\n---\n\n";
+        assert_eq!(contents, expected.repeat(2));
+    }
+
+    #[test]
+    fn jsonl() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("runs.jsonl");
+        let history = [ChatMessage::user("Hi"), ChatMessage::assistant("Hello")];
+        append(&path, &entry(&history)).unwrap();
+        append(&path, &entry(&[])).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines = contents
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["history"][1]["content"], "Hello");
+        assert_eq!(lines[0]["response"], "A summary");
+        assert_eq!(lines[0]["timestamp"], "2024-01-02T03:04:05Z");
+        assert!(lines[1].get("history").is_none());
+        assert!(lines[1].get("cached").is_none());
+    }
+}
@@ -0,0 +1,304 @@
+//! Detect fields in configuration and template files that PromptBox doesn't recognize, which are
+//! usually typos.
+
+use std::path::{Path, PathBuf};
+
+use error_stack::Report;
+use schemars::{
+    schema::{RootSchema, Schema, SchemaObject, SingleOrVec},
+    schema_for, JsonSchema,
+};
+use serde::de::DeserializeOwned;
+
+use crate::error::Error;
+
+/// A field in a file which does not match any known option.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownField {
+    pub file: PathBuf,
+    /// The path to the field, such as `model.temprature`
+    pub field: String,
+    /// A known field with a similar name
+    pub suggestion: Option<String>,
+}
+
+impl std::fmt::Display for UnknownField {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: unknown field `{}`", self.file.display(), self.field)?;
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, ", did you mean `{suggestion}`?")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a TOML file, returning the parsed value along with any fields that were not recognized.
+pub fn from_toml_str<T: DeserializeOwned + JsonSchema>(
+    contents: &str,
+    file: &Path,
+) -> Result<(T, Vec<UnknownField>), toml::de::Error> {
+    deserialize_with_unknown_fields(toml::Deserializer::new(contents), file)
+}
+
+/// Parse YAML, returning the parsed value along with any fields that were not recognized.
+pub fn from_yaml_str<T: DeserializeOwned + JsonSchema>(
+    contents: &str,
+    file: &Path,
+) -> Result<(T, Vec<UnknownField>), serde_yaml::Error> {
+    deserialize_with_unknown_fields(serde_yaml::Deserializer::from_str(contents), file)
+}
+
+fn deserialize_with_unknown_fields<'de, D, T>(
+    deserializer: D,
+    file: &Path,
+) -> Result<(T, Vec<UnknownField>), D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: DeserializeOwned + JsonSchema,
+{
+    let mut ignored = Vec::new();
+    let value = serde_ignored::deserialize(deserializer, |path| {
+        let mut segments = Vec::new();
+        path_segments(&path, &mut segments);
+        ignored.push(segments);
+    })?;
+
+    if ignored.is_empty() {
+        return Ok((value, Vec::new()));
+    }
+
+    let schema = schema_for!(T);
+    let unknown = ignored
+        .into_iter()
+        .map(|segments| {
+            let (name, parents) = segments.split_last().expect("path had no segments");
+            let suggestion = match name {
+                Segment::Key(name) => suggest_field(&schema, parents, name),
+                Segment::Index(_) => None,
+            };
+
+            UnknownField {
+                file: file.to_path_buf(),
+                field: segments
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect::<Vec<_>>()
+                    .join("."),
+                suggestion,
+            }
+        })
+        .collect();
+
+    Ok((value, unknown))
+}
+
+/// Warn about unknown fields, or return an error if `strict` is set.
+pub fn check_unknown_fields(fields: &[UnknownField], strict: bool) -> Result<(), Report<Error>> {
+    if fields.is_empty() {
+        return Ok(());
+    }
+
+    if strict {
+        let mut report = Report::new(Error::UnknownFields);
+        for field in fields {
+            report = report.attach_printable(field.to_string());
+        }
+        return Err(report);
+    }
+
+    for field in fields {
+        eprintln!("Warning: {field}");
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+impl std::fmt::Display for Segment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Segment::Key(key) => write!(f, "{key}"),
+            Segment::Index(index) => write!(f, "{index}"),
+        }
+    }
+}
+
+fn path_segments(path: &serde_ignored::Path, segments: &mut Vec<Segment>) {
+    match path {
+        serde_ignored::Path::Root => {}
+        serde_ignored::Path::Seq { parent, index } => {
+            path_segments(parent, segments);
+            segments.push(Segment::Index(*index));
+        }
+        serde_ignored::Path::Map { parent, key } => {
+            path_segments(parent, segments);
+            segments.push(Segment::Key(key.clone()));
+        }
+        serde_ignored::Path::Some { parent }
+        | serde_ignored::Path::NewtypeStruct { parent }
+        | serde_ignored::Path::NewtypeVariant { parent } => path_segments(parent, segments),
+    }
+}
+
+/// Find the known field most similar to `name` in the object at `parents`.
+fn suggest_field(root: &RootSchema, parents: &[Segment], name: &str) -> Option<String> {
+    let mut objects = vec![&root.schema];
+    for segment in parents {
+        objects = objects
+            .into_iter()
+            .flat_map(|object| child_schemas(root, object, segment))
+            .collect();
+    }
+
+    objects
+        .into_iter()
+        .flat_map(|object| object_variants(root, object))
+        .filter_map(|object| object.object.as_ref())
+        .flat_map(|object| object.properties.keys())
+        .map(|known| (strsim::jaro_winkler(name, known), known))
+        .filter(|(score, _)| *score > 0.8)
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, known)| known.clone())
+}
+
+/// Get the schemas that a value with the path `segment` inside `schema` could match.
+fn child_schemas<'a>(
+    root: &'a RootSchema,
+    schema: &'a SchemaObject,
+    segment: &Segment,
+) -> Vec<&'a SchemaObject> {
+    object_variants(root, schema)
+        .into_iter()
+        .filter_map(|variant| match segment {
+            Segment::Key(key) => variant.object.as_ref().and_then(|object| {
+                object
+                    .properties
+                    .get(key)
+                    .or(object.additional_properties.as_deref())
+            }),
+            Segment::Index(_) => {
+                variant
+                    .array
+                    .as_ref()
+                    .and_then(|array| match array.items.as_ref()? {
+                        SingleOrVec::Single(item) => Some(item.as_ref()),
+                        SingleOrVec::Vec(items) => items.first(),
+                    })
+            }
+        })
+        .filter_map(|schema| match schema {
+            Schema::Object(object) => Some(object),
+            Schema::Bool(_) => None,
+        })
+        .collect()
+}
+
+/// Resolve references and combinators to get the possible shapes of a value.
+fn object_variants<'a>(root: &'a RootSchema, schema: &'a SchemaObject) -> Vec<&'a SchemaObject> {
+    if let Some(reference) = schema.reference.as_deref() {
+        let name = reference.trim_start_matches("#/definitions/");
+        return match root.definitions.get(name) {
+            Some(Schema::Object(object)) => object_variants(root, object),
+            _ => Vec::new(),
+        };
+    }
+
+    let mut variants = vec![schema];
+    if let Some(subschemas) = schema.subschemas.as_ref() {
+        let combined = [&subschemas.all_of, &subschemas.any_of, &subschemas.one_of];
+        for schemas in combined.into_iter().flatten() {
+            for schema in schemas {
+                if let Schema::Object(object) = schema {
+                    variants.extend(object_variants(root, object));
+                }
+            }
+        }
+    }
+
+    variants
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{config::ConfigInput, template::PromptTemplate};
+
+    fn unknown_fields<T: DeserializeOwned + JsonSchema>(contents: &str) -> Vec<UnknownField> {
+        let (_, unknown) = from_toml_str::<T>(contents, Path::new("test.toml")).unwrap();
+        unknown
+    }
+
+    #[test]
+    fn no_unknown_fields() {
+        let unknown = unknown_fields::<PromptTemplate>(
+            r##"
+            template = "abc"
+            [model]
+            temperature = 0.5
+            "##,
+        );
+        assert_eq!(unknown, vec![]);
+    }
+
+    #[test]
+    fn nested_typo() {
+        let unknown = unknown_fields::<PromptTemplate>(
+            r##"
+            template = "abc"
+            [model]
+            temprature = 0.5
+            "##,
+        );
+        assert_eq!(
+            unknown,
+            vec![UnknownField {
+                file: PathBuf::from("test.toml"),
+                field: "model.temprature".to_string(),
+                suggestion: Some("temperature".to_string()),
+            }]
+        );
+        assert_eq!(
+            unknown[0].to_string(),
+            "test.toml: unknown field `model.temprature`, did you mean `temperature`?"
+        );
+    }
+
+    #[test]
+    fn typo_in_map_value() {
+        let unknown = unknown_fields::<PromptTemplate>(
+            r##"
+            template = "abc"
+            [options]
+            name = { type = "string", descripton = "The name" }
+            "##,
+        );
+        assert_eq!(unknown.len(), 1);
+        assert_eq!(unknown[0].field, "options.name.descripton");
+        assert_eq!(unknown[0].suggestion.as_deref(), Some("description"));
+    }
+
+    #[test]
+    fn config_without_suggestion() {
+        let unknown = unknown_fields::<ConfigInput>(
+            r##"
+            something_else = true
+            "##,
+        );
+        assert_eq!(unknown.len(), 1);
+        assert_eq!(unknown[0].field, "something_else");
+        assert_eq!(unknown[0].suggestion, None);
+    }
+
+    #[test]
+    fn strict_mode() {
+        let fields = unknown_fields::<ConfigInput>("top_levl = true");
+        let err = check_unknown_fields(&fields, true).unwrap_err();
+        assert!(matches!(err.current_context(), Error::UnknownFields));
+        check_unknown_fields(&fields, false).expect("warnings only");
+    }
+}
@@ -0,0 +1,379 @@
+//! Count the tokens that each run uses and what they cost. The counts come from the host when it
+//! reports them, and from the local tokenizer otherwise.
+
+use std::{collections::BTreeMap, collections::HashMap, fmt::Display, sync::Mutex};
+
+use clap::ValueEnum;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    capabilities::pattern_matches, history::TokenCounts, hosts::ModelInput, ledger::LedgerEntry,
+    model::ModelOptions, option::update_if_none,
+};
+
+/// Collects the token usage reported by the host across every request of a run, such as tool
+/// calls and repairs.
+#[derive(Debug, Default)]
+pub struct UsageMeter(Mutex<Option<TokenCounts>>);
+
+impl UsageMeter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&self, counts: TokenCounts) {
+        let mut total = self.0.lock().unwrap();
+        let total = total.get_or_insert(TokenCounts {
+            prompt: 0,
+            response: 0,
+        });
+        total.prompt += counts.prompt;
+        total.response += counts.response;
+    }
+
+    /// The total usage, or `None` if the host didn't report any.
+    pub fn total(&self) -> Option<TokenCounts> {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// The price of a model, in dollars per million tokens
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, JsonSchema)]
+pub struct ModelPriceInput {
+    /// The price of a million prompt tokens
+    pub input: Option<f64>,
+    /// The price of a million response tokens
+    pub output: Option<f64>,
+}
+
+impl ModelPriceInput {
+    /// For any members that are `None` in this `ModelPriceInput`, use the value from `other`
+    pub fn merge_defaults(&mut self, other: &ModelPriceInput) {
+        update_if_none(&mut self.input, &other.input);
+        update_if_none(&mut self.output, &other.output);
+    }
+}
+
+/// The price of each model, from the configuration.
+#[derive(Debug, Clone, Default)]
+pub struct PriceTable(HashMap<String, ModelPriceInput>);
+
+impl From<HashMap<String, ModelPriceInput>> for PriceTable {
+    fn from(value: HashMap<String, ModelPriceInput>) -> Self {
+        Self(value)
+    }
+}
+
+impl PriceTable {
+    /// The cost of `tokens` with `model`, or `None` if the model has no price. An exact model name
+    /// takes precedence over a pattern, and longer patterns over shorter ones.
+    pub fn cost(&self, model: &str, tokens: &TokenCounts) -> Option<f64> {
+        let (_, price) = self
+            .0
+            .iter()
+            .filter(|(pattern, _)| pattern_matches(pattern, model))
+            .max_by_key(|(pattern, _)| (!pattern.ends_with('*'), pattern.len()))?;
+
+        if price.input.is_none() && price.output.is_none() {
+            return None;
+        }
+
+        let input = price.input.unwrap_or_default() * tokens.prompt as f64;
+        let output = price.output.unwrap_or_default() * tokens.response as f64;
+        Some((input + output) / 1_000_000.0)
+    }
+}
+
+/// The tokens that a run used and what they cost
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Usage {
+    pub tokens: TokenCounts,
+    /// True if the host didn't report its usage, so the tokens were counted locally
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub estimated: bool,
+    /// The cost in dollars, if the model has a price
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+}
+
+impl Usage {
+    /// Find the usage of a run, using the counts from `input.usage` if the host reported any.
+    pub fn measure(
+        model_options: &ModelOptions,
+        prices: &PriceTable,
+        input: &ModelInput,
+        response: &str,
+    ) -> Option<Self> {
+        let (tokens, estimated) = match input.usage.total() {
+            Some(tokens) => (tokens, false),
+            None => {
+                let tokens = TokenCounts::count(
+                    model_options.tokenizer.as_deref(),
+                    input.system,
+                    &input.prompt_with_history(),
+                    response,
+                )?;
+                (tokens, true)
+            }
+        };
+
+        let spec = model_options.full_model_spec();
+        Some(Self {
+            tokens,
+            estimated,
+            cost: prices.cost(spec.model_name(), &tokens),
+        })
+    }
+}
+
+fn format_cost(cost: Option<f64>) -> String {
+    match cost {
+        Some(cost) => format!("${cost:.4}"),
+        None => "unknown cost".to_string(),
+    }
+}
+
+impl Display for Usage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} prompt + {} response tokens{}, {}",
+            self.tokens.prompt,
+            self.tokens.response,
+            if self.estimated { " (estimated)" } else { "" },
+            format_cost(self.cost)
+        )
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct UsageTotals {
+    pub runs: usize,
+    pub prompt_tokens: usize,
+    pub response_tokens: usize,
+    pub cost: f64,
+    /// Runs whose cost isn't known, and so aren't included in `cost`
+    pub unpriced_runs: usize,
+}
+
+impl UsageTotals {
+    fn add(&mut self, tokens: Option<TokenCounts>, cost: Option<f64>) {
+        self.runs += 1;
+        if let Some(tokens) = tokens {
+            self.prompt_tokens += tokens.prompt;
+            self.response_tokens += tokens.response;
+        }
+        match cost {
+            Some(cost) => self.cost += cost,
+            None => self.unpriced_runs += 1,
+        }
+    }
+}
+
+/// How to group runs when summarizing their usage
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum UsageGroup {
+    #[default]
+    Model,
+    Template,
+    /// The host that the runs were sent to
+    Provider,
+}
+
+impl UsageGroup {
+    fn key(&self, entry: &LedgerEntry) -> String {
+        match self {
+            Self::Model => entry.model.clone(),
+            Self::Template => entry.template.clone(),
+            Self::Provider => entry.host.clone().unwrap_or_else(|| "unknown".to_string()),
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            Self::Model => "Model",
+            Self::Template => "Template",
+            Self::Provider => "Provider",
+        }
+    }
+}
+
+/// The usage of past runs for each model, template, or provider
+#[derive(Debug, Default)]
+pub struct UsageSummary {
+    pub by: UsageGroup,
+    pub groups: BTreeMap<String, UsageTotals>,
+    pub total: UsageTotals,
+}
+
+/// Add up the usage of runs from the ledger.
+pub fn summarize(entries: &[LedgerEntry], by: UsageGroup) -> UsageSummary {
+    let mut summary = UsageSummary {
+        by,
+        ..Default::default()
+    };
+    for entry in entries {
+        summary
+            .groups
+            .entry(by.key(entry))
+            .or_default()
+            .add(entry.tokens, entry.cost);
+        summary.total.add(entry.tokens, entry.cost);
+    }
+
+    summary
+}
+
+impl Display for UsageSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let row = |f: &mut std::fmt::Formatter<'_>, name: &str, totals: &UsageTotals| {
+            let cost = if totals.unpriced_runs == totals.runs {
+                "-".to_string()
+            } else if totals.unpriced_runs > 0 {
+                format!("${:.4} ({} unpriced)", totals.cost, totals.unpriced_runs)
+            } else {
+                format!("${:.4}", totals.cost)
+            };
+            writeln!(
+                f,
+                "{name:<30} {:>6} {:>14} {:>14}  {cost}",
+                totals.runs, totals.prompt_tokens, totals.response_tokens
+            )
+        };
+
+        writeln!(
+            f,
+            "{:<30} {:>6} {:>14} {:>14}  Cost",
+            self.by.title(),
+            "Runs",
+            "Prompt tokens",
+            "Response tokens"
+        )?;
+        for (name, totals) in &self.groups {
+            row(f, name, totals)?;
+        }
+        row(f, "Total", &self.total)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::{events::RunEvents, history::HistoryEntry, shutdown::CancellationToken};
+
+    fn prices() -> PriceTable {
+        PriceTable::from(HashMap::from([
+            (
+                "gpt-4o*".to_string(),
+                ModelPriceInput {
+                    input: Some(5.0),
+                    output: Some(15.0),
+                },
+            ),
+            (
+                "gpt-4o-mini".to_string(),
+                ModelPriceInput {
+                    input: Some(0.15),
+                    output: Some(0.6),
+                },
+            ),
+        ]))
+    }
+
+    fn tokens(prompt: usize, response: usize) -> TokenCounts {
+        TokenCounts { prompt, response }
+    }
+
+    #[test]
+    fn cost() {
+        let prices = prices();
+        assert_eq!(
+            prices.cost("gpt-4o-2024-05-13", &tokens(1_000_000, 100_000)),
+            Some(6.5)
+        );
+        assert_eq!(
+            prices.cost("gpt-4o-mini", &tokens(1_000_000, 1_000_000)),
+            Some(0.75)
+        );
+        assert_eq!(prices.cost("llama3.1", &tokens(100, 100)), None);
+    }
+
+    #[test]
+    fn reported_usage() {
+        let tools = HashMap::new();
+        let meter = UsageMeter::new();
+        let input = ModelInput {
+            prompt: "Summarize this",
+            system: None,
+            history: &[],
+            images: Vec::new(),
+            tools: &tools,
+            cancel: &CancellationToken::new(),
+            usage: &meter,
+            events: RunEvents::none(),
+        };
+        let options = ModelOptions {
+            model: crate::model::ModelSpec::Plain("gpt-4o".to_string()),
+            ..Default::default()
+        };
+
+        meter.add(tokens(1000, 100));
+        meter.add(tokens(1200, 50));
+        let usage = Usage::measure(&options, &prices(), &input, "A summary").unwrap();
+        assert_eq!(usage.tokens, tokens(2200, 150));
+        assert!(!usage.estimated);
+        assert_eq!(usage.cost, Some(0.01325));
+    }
+
+    #[test]
+    fn summary() {
+        let entry = |day: u32, model: &str, usage: Option<Usage>, counts: Option<TokenCounts>| {
+            let mut entry = HistoryEntry::new("summarize", model, None, "prompt", "response");
+            entry.timestamp = Utc.with_ymd_and_hms(2024, 1, day, 12, 0, 0).unwrap();
+            entry.usage = usage;
+            entry.tokens = counts;
+            entry
+        };
+
+        let entries = [
+            entry(1, "gpt-4o", None, Some(tokens(1_000_000, 0))),
+            entry(
+                2,
+                "gpt-4o",
+                Some(Usage {
+                    tokens: tokens(100, 10),
+                    estimated: false,
+                    cost: Some(0.25),
+                }),
+                Some(tokens(90, 10)),
+            ),
+            entry(3, "gpt-4o", None, Some(tokens(1_000_000, 0))),
+            entry(3, "llama3.1", None, Some(tokens(10, 20))),
+        ];
+
+        let since = Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap();
+        let entries = entries
+            .iter()
+            .filter(|e| e.timestamp >= since)
+            .map(|e| LedgerEntry::from_history(e, &prices()))
+            .collect::<Vec<_>>();
+
+        let summary = summarize(&entries, UsageGroup::Model);
+        let gpt = &summary.groups["gpt-4o"];
+        assert_eq!(gpt.runs, 2);
+        assert_eq!(gpt.prompt_tokens, 1_000_100);
+        assert_eq!(gpt.cost, 5.25);
+        assert_eq!(summary.groups["llama3.1"].unpriced_runs, 1);
+        assert_eq!(summary.total.runs, 3);
+        assert_eq!(summary.total.response_tokens, 30);
+
+        let by_template = summarize(&entries, UsageGroup::Template);
+        assert_eq!(by_template.groups["summarize"].runs, 3);
+        assert!(by_template.to_string().starts_with("Template "));
+        let by_provider = summarize(&entries, UsageGroup::Provider);
+        assert_eq!(by_provider.groups["unknown"].runs, 3);
+    }
+}
@@ -0,0 +1,506 @@
+//! Check every template for problems that would otherwise only show up when it runs.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use clap::CommandFactory;
+use error_stack::{AttachmentKind, FrameKind, Report};
+use serde_json::json;
+use tera::Tera;
+
+use crate::{
+    args::GlobalRunArgs,
+    config::Config,
+    error::Error,
+    filters::{PromptFilters, ShellAccess},
+    list::find_template_files,
+    option_groups::nest_option_groups,
+    pin::remove_pin_tags,
+    structured::DataFormat,
+    template::{tera_message, OptionType, ParsedTemplate, PromptOption, RenderEnv},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub path: PathBuf,
+    pub message: String,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.message)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ValidationResult {
+    /// The number of template files that were checked
+    pub checked: usize,
+    pub problems: Vec<Problem>,
+}
+
+/// Join the messages from each level of a report, leaving out the path of the template since it is
+/// already shown with the problem.
+pub(crate) fn report_message(report: &Report<Error>, path: Option<&Path>) -> String {
+    let path = path.map(|p| p.display().to_string());
+    let messages = report
+        .frames()
+        .filter_map(|frame| match frame.kind() {
+            FrameKind::Context(context) => Some(context.to_string()),
+            FrameKind::Attachment(AttachmentKind::Printable(printable)) => {
+                Some(printable.to_string())
+            }
+            _ => None,
+        })
+        .filter(|message| Some(message) != path.as_ref())
+        .collect::<Vec<_>>();
+
+    messages.join(": ").trim_end().to_string()
+}
+
+fn placeholder_value(option: &PromptOption) -> serde_json::Value {
+    let value = match option.option_type {
+        OptionType::String => json!("placeholder"),
+        OptionType::Number => json!(1.0),
+        OptionType::Integer => json!(1),
+        OptionType::Bool => json!(true),
+        OptionType::File => json!({
+            "filename": "placeholder.txt",
+            "path": "placeholder.txt",
+            "contents": "placeholder",
+            "pages": 1
+        }),
+        OptionType::Url => json!({
+            "url": "https://example.com",
+            "title": "placeholder",
+            "contents": "placeholder"
+        }),
+        // The structure of the data isn't known, so use the default when there is one.
+        OptionType::Json | OptionType::Yaml | OptionType::Csv => {
+            if let Some(default) = option.default.clone() {
+                return default;
+            }
+
+            if option.option_type == OptionType::Csv {
+                json!([])
+            } else {
+                json!({})
+            }
+        }
+        // Images are sent alongside the prompt instead of being rendered into it.
+        OptionType::Image => return serde_json::Value::Null,
+    };
+
+    if option.array {
+        json!([value])
+    } else {
+        value
+    }
+}
+
+/// Describe why a default value of an option breaks the option's own constraints, if it does.
+fn default_problem(option: &PromptOption, value: &serde_json::Value) -> Option<String> {
+    if let Some(number) = value
+        .as_f64()
+        .or_else(|| value.as_str().and_then(|s| s.parse().ok()))
+        .filter(|_| matches!(option.option_type, OptionType::Number | OptionType::Integer))
+    {
+        if let Some(min) = option.min.filter(|min| number < *min) {
+            return Some(format!("is less than its min of {min}"));
+        }
+        if let Some(max) = option.max.filter(|max| number > *max) {
+            return Some(format!("is more than its max of {max}"));
+        }
+    }
+
+    if let (Some(Ok(regex)), Some(text)) = (option.pattern_regex(), value.as_str()) {
+        if !regex.is_match(text) {
+            let pattern = option.pattern.as_deref().unwrap_or_default();
+            return Some(format!("does not match its pattern `{pattern}`"));
+        }
+    }
+
+    None
+}
+
+/// Render a template with placeholder values for each of its options, and describe any errors.
+fn check_render(
+    template: &ParsedTemplate,
+    label: &str,
+    path: &Path,
+    text: &str,
+    context: &tera::Context,
+    env: &RenderEnv,
+) -> Option<String> {
+    let mut tera = Tera::default();
+    if let Err(e) = env.register(&mut tera, path, text) {
+        return Some(format!(
+            "{} in {label} of {}",
+            e.current_context(),
+            template.name
+        ));
+    }
+    if let Err(e) = tera.add_raw_template(label, &remove_pin_tags(text)) {
+        return Some(format!("Syntax error in {label}: {}", tera_message(&e)));
+    }
+
+    match tera.render(label, context) {
+        Ok(_) => None,
+        Err(e) => {
+            let message = tera_message(&e);
+            let problem = if message.contains("not found in context") {
+                "Unknown variable"
+            } else {
+                "Failed to render"
+            };
+            Some(format!(
+                "{problem} in {label} of {}: {message}",
+                template.name
+            ))
+        }
+    }
+}
+
+/// Find problems in a template which parsed successfully.
+fn check_template(
+    template: &ParsedTemplate,
+    builtin_args: &HashSet<String>,
+    env: &RenderEnv,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let mut options = template.input.options.iter().collect::<Vec<_>>();
+    options.sort_by(|a, b| a.0.cmp(b.0));
+    for (name, option) in &options {
+        if builtin_args.contains(name.as_str()) {
+            problems.push(format!(
+                "Option `{name}` has the same name as a built-in argument of `promptbox run`"
+            ));
+        }
+        if let Some(Err(e)) = option.pattern_regex() {
+            problems.push(format!("Option `{name}` has an invalid pattern: {e}"));
+        }
+        if let (Some(min), Some(max)) = (option.min, option.max) {
+            if min > max {
+                problems.push(format!(
+                    "Option `{name}` has a min of {min}, which is more than its max of {max}"
+                ));
+            }
+        }
+        let numeric = matches!(option.option_type, OptionType::Number | OptionType::Integer);
+        if (option.min.is_some() || option.max.is_some()) && !numeric {
+            problems.push(format!(
+                "Option `{name}` has a min or max, which only apply to number and integer options"
+            ));
+        }
+        if option.pattern.is_some() && option.option_type != OptionType::String {
+            problems.push(format!(
+                "Option `{name}` has a pattern, which only applies to string options"
+            ));
+        }
+        let defaults = match &option.default {
+            Some(serde_json::Value::Array(values)) if option.array => values.iter().collect(),
+            Some(value) => vec![value],
+            None => Vec::new(),
+        };
+        for value in defaults {
+            if let Some(problem) = default_problem(option, value) {
+                problems.push(format!(
+                    "Option `{name}` has a default of {value}, which {problem}"
+                ));
+            }
+        }
+    }
+
+    let mut context = serde_json::Map::new();
+    for (name, option) in &options {
+        context.insert(name.to_string(), placeholder_value(option));
+    }
+    context.insert("extra".to_string(), json!("placeholder"));
+    context.insert(
+        "extra_meta".to_string(),
+        json!({ "language": "English", "lang_confidence": 1.0, "kind": "text" }),
+    );
+    if template.input.project_context {
+        context.insert("project_context".to_string(), json!("placeholder"));
+    }
+    match template.input.stdin_format {
+        Some(DataFormat::Json | DataFormat::Yaml) => {
+            context.insert("stdin".to_string(), json!({}));
+        }
+        Some(DataFormat::Csv | DataFormat::Lines) => {
+            context.insert("stdin".to_string(), json!([]));
+        }
+        None => {}
+    }
+    let mut context = serde_json::Value::Object(context);
+    nest_option_groups(&mut context);
+    let context = tera::Context::from_value(context).expect("context is an object");
+
+    problems.extend(check_render(
+        template,
+        "template",
+        &template.path,
+        &template.template,
+        &context,
+        env,
+    ));
+    if let Some((path, system)) = template.system.as_ref() {
+        problems.extend(check_render(
+            template,
+            "system prompt",
+            path,
+            system,
+            &context,
+            env,
+        ));
+    }
+
+    problems
+}
+
+fn check_file(
+    name: &str,
+    path: &Path,
+    builtin_args: &HashSet<String>,
+    env: &RenderEnv,
+) -> Result<Vec<String>, String> {
+    let template = match ParsedTemplate::from_file(name, path) {
+        Ok(Some(template)) => template,
+        Ok(None) => return Err("Could not read the file".to_string()),
+        Err(e) => return Err(report_message(&e, Some(path))),
+    };
+
+    Ok(check_template(&template, builtin_args, env))
+}
+
+/// Check every template in every template directory, including those which are hidden by a
+/// template of the same name in another directory.
+pub fn validate_templates(config: &Config) -> ValidationResult {
+    let builtin_args = GlobalRunArgs::command()
+        .get_arguments()
+        .flat_map(|arg| {
+            std::iter::once(arg.get_id().to_string()).chain(arg.get_long().map(|l| l.to_string()))
+        })
+        .chain(["help".to_string()])
+        .collect::<HashSet<_>>();
+
+    // Commands aren't run while checking, and the tokenizer doesn't matter since only the names
+    // of the filters are checked.
+    let env = RenderEnv {
+        partials: config.partials(),
+        filters: PromptFilters::default().with_shell(ShellAccess::Placeholder),
+        ..Default::default()
+    };
+    let mut result = ValidationResult::default();
+    let mut seen_paths = HashSet::new();
+    for template_dir in &config.template_dirs {
+        for (name, path) in find_template_files(template_dir) {
+            // Template directories may overlap, so don't check the same file twice.
+            let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+            if !seen_paths.insert(canonical) {
+                continue;
+            }
+
+            result.checked += 1;
+            let messages =
+                check_file(&name, &path, &builtin_args, &env).unwrap_or_else(|e| vec![e]);
+            result
+                .problems
+                .extend(messages.into_iter().map(|message| Problem {
+                    path: path.clone(),
+                    message,
+                }));
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn validate(files: &[(&str, &str)]) -> ValidationResult {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("promptbox.toml"),
+            "top_level = true\nuse_global_config = false\n",
+        )
+        .unwrap();
+        for (name, contents) in files {
+            std::fs::write(dir.path().join(name), contents).unwrap();
+        }
+
+        let config = Config::from_directory(dir.path().to_path_buf()).unwrap();
+        validate_templates(&config)
+    }
+
+    fn messages(result: &ValidationResult) -> Vec<String> {
+        result
+            .problems
+            .iter()
+            .map(|p| {
+                format!(
+                    "{}: {}",
+                    p.path.file_name().unwrap().to_string_lossy(),
+                    p.message
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn valid_templates() {
+        let result = validate(&[
+            (
+                "files.pb.toml",
+                r##"
+                template = '''
+                {% for f in files %}{{ f.filename }}: {{ f.contents }}{% endfor %}
+                {{ count + 1 }} {% if detailed %}{{ style }}{% endif %} {{ extra }}
+                '''
+                system_prompt = "Write in a {{ style }} style"
+
+                [options]
+                files = { type = "file", array = true }
+                count = { type = "integer" }
+                detailed = { type = "bool" }
+                style = { default = "concise" }
+                picture = { type = "image" }
+                "##,
+            ),
+            ("plain.pb.toml", "template = 'No options here'"),
+        ]);
+
+        assert_eq!(result.checked, 2);
+        assert_eq!(messages(&result), Vec::<String>::new());
+    }
+
+    #[test]
+    fn syntax_error() {
+        let result = validate(&[("broken.pb.toml", "template = 'Hello {{ name'")]);
+        let messages = messages(&result);
+        assert_eq!(messages.len(), 1);
+        assert!(
+            messages[0].starts_with("broken.pb.toml: Syntax error in template"),
+            "{messages:?}"
+        );
+    }
+
+    #[test]
+    fn unknown_variable() {
+        let result = validate(&[(
+            "unknown.pb.toml",
+            r##"
+            template = "{{ name }}"
+            system_prompt = "{{ persona }}"
+
+            [options]
+            nmae = { type = "string" }
+            "##,
+        )]);
+        let messages = messages(&result);
+        assert_eq!(messages.len(), 2, "{messages:?}");
+        assert!(messages[0].contains("Unknown variable in template"));
+        assert!(messages[0].contains("name"));
+        assert!(messages[1].contains("Unknown variable in system prompt"));
+        assert!(messages[1].contains("persona"));
+    }
+
+    #[test]
+    fn missing_template() {
+        let result = validate(&[("empty.pb.toml", "description = 'Nothing to see'")]);
+        let messages = messages(&result);
+        assert_eq!(messages.len(), 1);
+        assert!(
+            messages[0].contains("missing template and template_path"),
+            "{messages:?}"
+        );
+    }
+
+    #[test]
+    fn duplicate_options() {
+        let result = validate(&[
+            (
+                "builtin.pb.toml",
+                r##"
+                template = "{{ model }} {{ verbose }}"
+                [options]
+                model = { type = "string" }
+                verbose = { type = "bool" }
+                "##,
+            ),
+            (
+                "twice.pb.toml",
+                r##"
+                template = "{{ style }}"
+                [options]
+                style = { type = "string" }
+                style = { type = "number" }
+                "##,
+            ),
+        ]);
+
+        let messages = messages(&result);
+        assert_eq!(messages.len(), 3, "{messages:?}");
+        assert!(messages[0].starts_with("builtin.pb.toml: Option `model`"));
+        assert!(messages[1].starts_with("builtin.pb.toml: Option `verbose`"));
+        assert!(messages[2].starts_with("twice.pb.toml:"));
+        assert!(messages[2].contains("duplicate key"), "{messages:?}");
+    }
+
+    #[test]
+    fn option_constraints() {
+        let result = validate(&[(
+            "constraints.pb.toml",
+            r##"
+            template = "{{ count }} {{ ticket }}"
+            [options]
+            count = { type = "integer", min = 10, max = 1 }
+            ticket = { type = "string", pattern = "[A-Z+" }
+            "##,
+        )]);
+
+        let messages = messages(&result);
+        assert_eq!(messages.len(), 2, "{messages:?}");
+        assert_eq!(
+            messages[0],
+            "constraints.pb.toml: Option `count` has a min of 10, which is more than its max of 1"
+        );
+        assert!(
+            messages[1].starts_with("constraints.pb.toml: Option `ticket` has an invalid pattern"),
+            "{messages:?}"
+        );
+    }
+
+    #[test]
+    fn option_defaults() {
+        let result = validate(&[(
+            "defaults.pb.toml",
+            r##"
+            template = "{{ count }} {{ ratio }} {{ ticket }} {{ tags }} {{ name }} {{ flag }}"
+            [options]
+            count = { type = "integer", min = 1, max = 5, default = 8 }
+            ratio = { type = "number", min = 0.5, default = 0.1 }
+            ticket = { type = "string", pattern = "^[A-Z]+-[0-9]+$", default = "AB-12" }
+            tags = { type = "string", array = true, pattern = "^[a-z]+$", default = ["ok", "Bad"] }
+            name = { type = "string", min = 1 }
+            flag = { type = "bool", pattern = "yes" }
+            "##,
+        )]);
+
+        assert_eq!(
+            messages(&result),
+            [
+                "defaults.pb.toml: Option `count` has a default of 8, which is more than its max of 5",
+                "defaults.pb.toml: Option `flag` has a pattern, which only applies to string options",
+                "defaults.pb.toml: Option `name` has a min or max, which only apply to number and integer options",
+                "defaults.pb.toml: Option `ratio` has a default of 0.1, which is less than its min of 0.5",
+                "defaults.pb.toml: Option `tags` has a default of \"Bad\", which does not match its pattern `^[a-z]+$`",
+            ]
+        );
+    }
+}